@@ -0,0 +1,266 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! An overall time budget for delivering a message, from resolving the host to the end of
+//! the SMTP dialogue.
+//!
+//! Every step only gets the time left, so a slow DNS server or a server that stalls
+//! between replies can't hold the caller for longer than the budget.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut deadline = Deadline::new(Duration::from_secs(30));
+//! let addrs = try!(deadline.resolve("mx.rustastic.org:25"));
+//! let mut stream = try!(deadline.connect(addrs.as_ref()));
+//! deadline.enter(SendPhase::Dialogue);
+//! if let Err(err) = send_message(&stream) {
+//!     // Says `QUIT` if the budget ran out during the dialogue.
+//!     deadline.abort(&mut stream);
+//!     return Err(deadline.check(err));
+//! }
+//! ```
+
+use std::borrow::ToOwned;
+use std::error::Error;
+use std::fmt;
+use std::io::{Write, Error as IoError, ErrorKind};
+use std::io::Result as IoResult;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+#[cfg(test)]
+use std::io::Read;
+#[cfg(test)]
+use std::net::TcpListener;
+use super::super::common::stream::is_timeout;
+
+/// A step of a delivery.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum SendPhase {
+    /// Resolving the address of the server.
+    Resolve,
+    /// Opening the TCP connection.
+    Connect,
+    /// Performing the TLS handshake.
+    Tls,
+    /// Sending commands and reading replies.
+    Dialogue
+}
+
+impl fmt::Display for SendPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            SendPhase::Resolve => "DNS resolution",
+            SendPhase::Connect => "connection",
+            SendPhase::Tls => "TLS handshake",
+            SendPhase::Dialogue => "SMTP dialogue"
+        })
+    }
+}
+
+/// Why a delivery ran out of time.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct DeadlineExceeded {
+    /// The phase during which the budget ran out.
+    pub phase: SendPhase,
+    /// How long each phase took, in order.
+    pub spent: Vec<(SendPhase, Duration)>
+}
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "delivery deadline exceeded during {}", self.phase)
+    }
+}
+
+impl Error for DeadlineExceeded {
+    fn description(&self) -> &str {
+        "delivery deadline exceeded"
+    }
+}
+
+/// Keeps track of the time left for a delivery, and of the time spent in each phase.
+pub struct Deadline {
+    end: Instant,
+    phase: SendPhase,
+    phase_start: Instant,
+    spent: Vec<(SendPhase, Duration)>
+}
+
+impl Deadline {
+    /// Starts a budget for a delivery, in the `Resolve` phase.
+    pub fn new(budget: Duration) -> Deadline {
+        let now = Instant::now();
+        Deadline {
+            end: now + budget,
+            phase: SendPhase::Resolve,
+            phase_start: now,
+            spent: Vec::new()
+        }
+    }
+
+    /// Returns the current phase.
+    pub fn phase(&self) -> SendPhase {
+        self.phase
+    }
+
+    /// Ends the current phase and starts another.
+    pub fn enter(&mut self, phase: SendPhase) {
+        let now = Instant::now();
+        self.spent.push((self.phase, now.duration_since(self.phase_start)));
+        self.phase = phase;
+        self.phase_start = now;
+    }
+
+    /// Returns how long each phase took so far, including the current one.
+    pub fn spent(&self) -> Vec<(SendPhase, Duration)> {
+        let mut spent = self.spent.clone();
+        spent.push((self.phase, self.phase_start.elapsed()));
+        spent
+    }
+
+    /// Returns the time left, or why there is none.
+    pub fn remaining(&self) -> Result<Duration, DeadlineExceeded> {
+        let now = Instant::now();
+        match now < self.end {
+            true => Ok(self.end.duration_since(now)),
+            false => Err(self.exceeded())
+        }
+    }
+
+    /// Tells which phase consumed the budget.
+    pub fn exceeded(&self) -> DeadlineExceeded {
+        DeadlineExceeded {
+            phase: self.phase,
+            spent: self.spent()
+        }
+    }
+
+    fn remaining_io(&self) -> IoResult<Duration> {
+        self.remaining().map_err(|err| IoError::new(ErrorKind::TimedOut, err))
+    }
+
+    /// Turns timeouts into a `DeadlineExceeded` error once the budget is over, so the
+    /// caller can tell which phase was too slow. Other errors are returned as they are.
+    pub fn check(&self, err: IoError) -> IoError {
+        match is_timeout(&err) && self.remaining().is_err() {
+            true => IoError::new(ErrorKind::TimedOut, self.exceeded()),
+            false => err
+        }
+    }
+
+    /// Resolves the address of a server in the `Resolve` phase.
+    ///
+    /// The system resolver can't be interrupted, so it runs in its own thread, which is
+    /// left behind if it is too slow.
+    pub fn resolve(&mut self, address: &str) -> IoResult<Vec<SocketAddr>> {
+        self.enter(SendPhase::Resolve);
+        let remaining = try!(self.remaining_io());
+        let (tx, rx) = mpsc::channel();
+        let address = address.to_owned();
+        thread::spawn(move || {
+            let _ = tx.send(address.to_socket_addrs().map(|addrs| addrs.collect::<Vec<SocketAddr>>()));
+        });
+        match rx.recv_timeout(remaining) {
+            Ok(res) => res,
+            Err(_) => Err(IoError::new(ErrorKind::TimedOut, self.exceeded()))
+        }
+    }
+
+    /// Connects to the first address that accepts the connection in the `Connect` phase.
+    ///
+    /// The timeouts of the stream are set to the time left, see `apply`.
+    pub fn connect(&mut self, addrs: &[SocketAddr]) -> IoResult<TcpStream> {
+        self.enter(SendPhase::Connect);
+        let mut last_err = IoError::new(ErrorKind::InvalidInput, "no address to connect to");
+        for addr in addrs.iter() {
+            let remaining = try!(self.remaining_io());
+            match TcpStream::connect_timeout(addr, remaining) {
+                Ok(stream) => {
+                    try!(self.apply(&stream));
+                    return Ok(stream);
+                },
+                Err(err) => last_err = self.check(err)
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Sets the read and write timeouts of the stream to the time left.
+    ///
+    /// This must be called again before each blocking step, ie before each command, so
+    /// that the timeouts shrink as the budget is used.
+    pub fn apply(&self, stream: &TcpStream) -> IoResult<()> {
+        let remaining = try!(self.remaining_io());
+        try!(stream.set_read_timeout(Some(remaining)));
+        stream.set_write_timeout(Some(remaining))
+    }
+
+    /// Ends a delivery that ran out of time as cleanly as possible.
+    ///
+    /// During the dialogue, the server is told with `QUIT`, without waiting for its reply.
+    /// In the other phases, there is nothing to say, and dropping the stream closes it.
+    pub fn abort<W: Write>(&self, output: &mut W) {
+        if self.phase == SendPhase::Dialogue {
+            let _ = output.write_all(b"QUIT\r\n").and_then(|_| output.flush());
+        }
+    }
+}
+
+#[test]
+fn test_deadline() {
+    let mut deadline = Deadline::new(Duration::from_secs(60));
+    assert_eq!(SendPhase::Resolve, deadline.phase());
+    assert!(deadline.remaining().unwrap() <= Duration::from_secs(60));
+    deadline.enter(SendPhase::Connect);
+    deadline.enter(SendPhase::Dialogue);
+    let phases: Vec<SendPhase> = deadline.spent().iter().map(|&(phase, _)| phase).collect();
+    assert_eq!(vec![SendPhase::Resolve, SendPhase::Connect, SendPhase::Dialogue], phases);
+
+    // Only timeouts after the end of the budget are turned into `DeadlineExceeded`.
+    let err = deadline.check(IoError::new(ErrorKind::TimedOut, "timed out"));
+    assert_eq!("timed out", err.to_string());
+
+    let deadline = Deadline::new(Duration::from_secs(0));
+    assert_eq!(SendPhase::Resolve, deadline.remaining().err().unwrap().phase);
+    let err = deadline.check(IoError::new(ErrorKind::WouldBlock, "timed out"));
+    assert_eq!(ErrorKind::TimedOut, err.kind());
+    assert_eq!("delivery deadline exceeded during DNS resolution", err.to_string());
+    let err = deadline.check(IoError::new(ErrorKind::ConnectionRefused, "refused"));
+    assert_eq!(ErrorKind::ConnectionRefused, err.kind());
+}
+
+#[test]
+fn test_deadline_dialogue() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut deadline = Deadline::new(Duration::from_millis(200));
+    let addrs = deadline.resolve(listener.local_addr().unwrap().to_string().as_ref()).unwrap();
+    let mut stream = deadline.connect(addrs.as_ref()).unwrap();
+    deadline.enter(SendPhase::Dialogue);
+
+    // The server never sends its greeting.
+    let mut buf = [0u8; 16];
+    let err = (&stream).read(&mut buf).err().unwrap();
+    let err = deadline.check(err);
+    assert_eq!("delivery deadline exceeded during SMTP dialogue", err.to_string());
+    deadline.abort(&mut stream);
+    let (mut server, _) = listener.accept().unwrap();
+    let mut quit = [0u8; 6];
+    server.read_exact(&mut quit).unwrap();
+    assert_eq!(b"QUIT\r\n", &quit);
+}
@@ -0,0 +1,439 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! DKIM signatures of outgoing messages, as described
+//! [in RFC 6376](http://tools.ietf.org/html/rfc6376).
+//!
+//! Keys are held by a `DkimSigner`, which wraps a crypto library the same way a
+//! `TlsConnector` does, for RSA (`rsa-sha256`) or Ed25519 (`ed25519-sha256`, described
+//! [in RFC 8463](http://tools.ietf.org/html/rfc8463)) keys. Each sending domain gets its
+//! own key and selector, and the client signs messages for the domain of the sender given
+//! to MAIL:
+//!
+//! ```ignore
+//! let mut keys = DkimKeys::new();
+//! keys.add(DkimKey::new("rustastic.org", "mail", rsa_signer));
+//! client.set_dkim_keys(keys);
+//! try!(client.mail(Some(&sender), &[]));
+//! try!(client.rcpt(&recipient, &[]));
+//! // The message goes out with a DKIM-Signature header for rustastic.org.
+//! try!(client.send_message(message));
+//! ```
+//!
+//! Messages must use `<CRLF>` line endings, which is how they are sent anyway.
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::vec::Vec;
+use super::super::common::base64;
+use super::super::common::message::header_fields;
+use super::super::common::sha256;
+#[cfg(test)]
+use super::session::SmtpClient;
+#[cfg(test)]
+use super::testing::{Script, MockServer};
+#[cfg(test)]
+use super::super::common::mailbox::Mailbox;
+
+/// The header fields signed by default, when the message has them.
+pub static DEFAULT_SIGNED_HEADERS: [&'static str; 10] = [
+    "From", "Sender", "Reply-To", "Subject", "Date", "Message-ID", "To", "Cc",
+    "MIME-Version", "Content-Type"
+];
+
+/// A signing algorithm, named as in the `a=` tag.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum DkimAlgorithm {
+    /// RSA with SHA-256, `rsa-sha256`.
+    RsaSha256,
+    /// Ed25519 with SHA-256, `ed25519-sha256`.
+    Ed25519Sha256
+}
+
+impl DkimAlgorithm {
+    /// Returns the name of the algorithm, ie `rsa-sha256`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DkimAlgorithm::RsaSha256 => "rsa-sha256",
+            DkimAlgorithm::Ed25519Sha256 => "ed25519-sha256"
+        }
+    }
+}
+
+/// Signs with a private key.
+pub trait DkimSigner: Send + Sync {
+    /// Returns the algorithm of the key.
+    fn algorithm(&self) -> DkimAlgorithm;
+
+    /// Signs the SHA-256 digest of the signed header fields, ie with RSASSA-PKCS1-v1_5 or
+    /// with Ed25519 over the digest, and returns the signature.
+    fn sign(&self, digest: &[u8; 32]) -> Result<Vec<u8>, String>;
+}
+
+/// How header fields and bodies are canonicalized before they are hashed.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum Canonicalization {
+    /// Nothing may change, except empty lines at the end of the body.
+    Simple,
+    /// Whitespace and the case of header field names may change.
+    Relaxed
+}
+
+impl Canonicalization {
+    /// Returns the name of the canonicalization, as in the `c=` tag.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Canonicalization::Simple => "simple",
+            Canonicalization::Relaxed => "relaxed"
+        }
+    }
+}
+
+fn is_wsp(byte: u8) -> bool {
+    byte == b' ' || byte == b'\t'
+}
+
+// Replaces runs of whitespace with a space, and drops the whitespace at the end.
+fn compress_wsp(data: &[u8], output: &mut Vec<u8>) {
+    let mut in_wsp = false;
+    for &byte in data.iter() {
+        match is_wsp(byte) {
+            true => in_wsp = true,
+            false => {
+                if in_wsp {
+                    output.push(b' ');
+                    in_wsp = false;
+                }
+                output.push(byte);
+            }
+        }
+    }
+}
+
+/// Canonicalizes a header field, given without its final `<CRLF>`, and adds `<CRLF>`.
+pub fn canonicalize_header(field: &[u8], canonicalization: Canonicalization) -> Vec<u8> {
+    let mut output = Vec::with_capacity(field.len() + 2);
+    match canonicalization {
+        Canonicalization::Simple => output.extend_from_slice(field),
+        Canonicalization::Relaxed => {
+            let colon = field.iter().position(|&byte| byte == b':').unwrap_or(field.len());
+            let name: Vec<u8> = field[.. colon].iter().cloned().filter(|&byte| !is_wsp(byte)).collect();
+            output.extend(name.to_ascii_lowercase());
+            output.push(b':');
+            if colon < field.len() {
+                let unfolded: Vec<u8> = field[colon + 1 ..].iter().cloned().filter(|&byte| byte != b'\r' && byte != b'\n').collect();
+                let start = unfolded.iter().position(|&byte| !is_wsp(byte)).unwrap_or(unfolded.len());
+                compress_wsp(&unfolded[start ..], &mut output);
+            }
+        }
+    }
+    output.extend_from_slice(b"\r\n");
+    output
+}
+
+/// Canonicalizes a body.
+///
+/// Empty lines at the end are dropped, and the last line gets a `<CRLF>` if it has none.
+/// An empty body is `<CRLF>` with the simple canonicalization, and stays empty with the
+/// relaxed one.
+pub fn canonicalize_body(body: &[u8], canonicalization: Canonicalization) -> Vec<u8> {
+    let mut lines: Vec<&[u8]> = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i + 1 < body.len() {
+        if body[i] == b'\r' && body[i + 1] == b'\n' {
+            lines.push(&body[start .. i]);
+            start = i + 2;
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    if start < body.len() {
+        lines.push(&body[start ..]);
+    }
+
+    let mut output = Vec::with_capacity(body.len() + 2);
+    for line in lines.iter() {
+        match canonicalization {
+            Canonicalization::Simple => output.extend_from_slice(line),
+            Canonicalization::Relaxed => compress_wsp(line, &mut output)
+        }
+        output.extend_from_slice(b"\r\n");
+    }
+    while output.ends_with(b"\r\n\r\n") {
+        let len = output.len();
+        output.truncate(len - 2);
+    }
+    if output == b"\r\n" && canonicalization == Canonicalization::Relaxed {
+        output.clear();
+    }
+    if output.is_empty() && canonicalization == Canonicalization::Simple {
+        output.extend_from_slice(b"\r\n");
+    }
+    output
+}
+
+/// Represents an error that occured while signing a message.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum DkimError {
+    /// The message has no From header field, which must be signed.
+    NoFrom,
+    /// The signer failed, for the given reason.
+    Signer(String)
+}
+
+impl fmt::Display for DkimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DkimError::NoFrom => f.write_str("message without From header field"),
+            DkimError::Signer(ref reason) => write!(f, "DKIM signer failed: {}", reason)
+        }
+    }
+}
+
+impl Error for DkimError {
+    fn description(&self) -> &str {
+        "message can't be signed"
+    }
+}
+
+/// The key, selector and settings used to sign the messages of a domain.
+#[derive(Clone)]
+pub struct DkimKey {
+    domain: String,
+    selector: String,
+    signer: Arc<DkimSigner>,
+    header_canonicalization: Canonicalization,
+    body_canonicalization: Canonicalization,
+    headers: Vec<String>
+}
+
+impl DkimKey {
+    /// Creates a key for a domain, published under the selector, ie `mail` for
+    /// `mail._domainkey.rustastic.org`.
+    ///
+    /// Headers and bodies are canonicalized with `relaxed`, and the
+    /// `DEFAULT_SIGNED_HEADERS` are signed.
+    pub fn new<S: 'static + DkimSigner>(domain: &str, selector: &str, signer: S) -> DkimKey {
+        DkimKey {
+            domain: domain.to_owned(),
+            selector: selector.to_owned(),
+            signer: Arc::new(signer),
+            header_canonicalization: Canonicalization::Relaxed,
+            body_canonicalization: Canonicalization::Relaxed,
+            headers: DEFAULT_SIGNED_HEADERS.iter().map(|&name| name.to_owned()).collect()
+        }
+    }
+
+    /// Returns the signing domain, as in the `d=` tag.
+    pub fn domain(&self) -> &str {
+        self.domain.as_ref()
+    }
+
+    /// Sets how headers and bodies are canonicalized.
+    pub fn set_canonicalization(&mut self, header: Canonicalization, body: Canonicalization) {
+        self.header_canonicalization = header;
+        self.body_canonicalization = body;
+    }
+
+    /// Sets the names of the header fields to sign. `From` is always signed.
+    pub fn set_signed_headers(&mut self, headers: &[&str]) {
+        self.headers = headers.iter().map(|&name| name.to_owned()).collect();
+        if !self.headers.iter().any(|name| name.eq_ignore_ascii_case("From")) {
+            self.headers.insert(0, "From".to_owned());
+        }
+    }
+
+    /// Returns the DKIM-Signature header field of the message, without its final
+    /// `<CRLF>`.
+    pub fn signature(&self, message: &[u8]) -> Result<String, DkimError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        self.signature_at(message, now.as_secs())
+    }
+
+    // Returns the DKIM-Signature header field, with the given timestamp.
+    fn signature_at(&self, message: &[u8], timestamp: u64) -> Result<String, DkimError> {
+        let mut fields = header_fields(message);
+        let mut present: Vec<(String, &[u8])> = Vec::new();
+        for field in fields.by_ref() {
+            let colon = field.iter().position(|&byte| byte == b':').unwrap_or(field.len());
+            let name = String::from_utf8_lossy(&field[.. colon]).trim().to_ascii_lowercase();
+            present.push((name, field));
+        }
+        let body = &message[fields.position() ..];
+
+        // Fields with the same name are signed from the bottom up, so each name is listed
+        // once per field.
+        let mut listed: Vec<String> = Vec::new();
+        let mut hashed = Vec::new();
+        for name in self.headers.iter() {
+            let name = name.to_ascii_lowercase();
+            if listed.contains(&name) {
+                continue;
+            }
+            for &(ref existing, field) in present.iter().rev() {
+                if *existing == name {
+                    hashed.extend(canonicalize_header(field, self.header_canonicalization));
+                    listed.push(name.clone());
+                }
+            }
+        }
+        if !listed.iter().any(|name| name == "from") {
+            return Err(DkimError::NoFrom);
+        }
+
+        let body_hash = sha256::digest(canonicalize_body(body, self.body_canonicalization).as_ref());
+        let unsigned = format!("DKIM-Signature: v=1; a={}; c={}/{}; d={}; s={};\r\n\tt={}; h={};\r\n\tbh={};\r\n\tb=",
+            self.signer.algorithm().name(), self.header_canonicalization.name(),
+            self.body_canonicalization.name(), self.domain, self.selector, timestamp,
+            listed.join(":"), base64::encode(&body_hash));
+        let canonical = canonicalize_header(unsigned.as_bytes(), self.header_canonicalization);
+        // The signature field is hashed without its final `<CRLF>`.
+        hashed.extend_from_slice(&canonical[.. canonical.len() - 2]);
+        let signature = try!(self.signer.sign(&sha256::digest(hashed.as_ref())).map_err(DkimError::Signer));
+        Ok(unsigned + base64::encode(signature.as_ref()).as_ref())
+    }
+
+    /// Returns the message with its DKIM-Signature header field at the top.
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, DkimError> {
+        let signature = try!(self.signature(message));
+        let mut signed = Vec::with_capacity(signature.len() + 2 + message.len());
+        signed.extend_from_slice(signature.as_bytes());
+        signed.extend_from_slice(b"\r\n");
+        signed.extend_from_slice(message);
+        Ok(signed)
+    }
+}
+
+/// The keys of the sending domains, see `SmtpClient::set_dkim_keys`.
+#[derive(Clone)]
+pub struct DkimKeys {
+    keys: Vec<DkimKey>
+}
+
+impl DkimKeys {
+    /// Creates a set without keys, which signs nothing.
+    pub fn new() -> DkimKeys {
+        DkimKeys {
+            keys: Vec::new()
+        }
+    }
+
+    /// Adds the key of a domain, replacing its previous key.
+    pub fn add(&mut self, key: DkimKey) {
+        self.keys.retain(|existing| !existing.domain.eq_ignore_ascii_case(key.domain.as_ref()));
+        self.keys.push(key);
+    }
+
+    /// Returns the key of a domain, if any.
+    pub fn get(&self, domain: &str) -> Option<&DkimKey> {
+        self.keys.iter().find(|key| key.domain.eq_ignore_ascii_case(domain))
+    }
+}
+
+// Pretends to sign, the signature is the digest.
+#[cfg(test)]
+struct DigestSigner;
+
+#[cfg(test)]
+impl DkimSigner for DigestSigner {
+    fn algorithm(&self) -> DkimAlgorithm {
+        DkimAlgorithm::Ed25519Sha256
+    }
+
+    fn sign(&self, digest: &[u8; 32]) -> Result<Vec<u8>, String> {
+        Ok(digest.to_vec())
+    }
+}
+
+#[test]
+fn test_canonicalize() {
+    // The examples of RFC 6376, section 3.4.5.
+    assert_eq!(b"a:X\r\n".to_vec(), canonicalize_header(b"A: X", Canonicalization::Relaxed));
+    assert_eq!(b"b:Y Z\r\n".to_vec(), canonicalize_header(b"B : Y\t\r\n\tZ  ", Canonicalization::Relaxed));
+    assert_eq!(b"B : Y\t\r\n\tZ  \r\n".to_vec(), canonicalize_header(b"B : Y\t\r\n\tZ  ", Canonicalization::Simple));
+    let body = b" C \r\nD \t E\r\n\r\n\r\n";
+    assert_eq!(b" C\r\nD E\r\n".to_vec(), canonicalize_body(body, Canonicalization::Relaxed));
+    assert_eq!(b" C \r\nD \t E\r\n".to_vec(), canonicalize_body(body, Canonicalization::Simple));
+
+    assert_eq!(b"Hi\r\n".to_vec(), canonicalize_body(b"Hi", Canonicalization::Simple));
+    assert_eq!(b"\r\n".to_vec(), canonicalize_body(b"", Canonicalization::Simple));
+    assert_eq!(b"\r\n".to_vec(), canonicalize_body(b"\r\n\r\n", Canonicalization::Simple));
+    assert_eq!(Vec::<u8>::new(), canonicalize_body(b"\r\n \r\n", Canonicalization::Relaxed));
+}
+
+#[test]
+fn test_signature() {
+    let mut key = DkimKey::new("rustastic.org", "mail", DigestSigner);
+    let message = b"From: rust@rustastic.org\r\nSubject:  Hi\r\nX-Mailer: rsmtp\r\nTo: a@rustastic.org\r\nTo: b@rustastic.org\r\n\r\nHi\r\n\r\n";
+    let signature = key.signature_at(message, 1400000000).unwrap();
+    let (unsigned, b) = signature.split_at(signature.rfind("b=").unwrap() + 2);
+    assert_eq!(format!("DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; d=rustastic.org; s=mail;\r\n\tt=1400000000; h=from:subject:to:to;\r\n\tbh={};\r\n\tb=",
+        base64::encode(&sha256::digest(b"Hi\r\n"))), unsigned);
+    let mut hashed = b"from:rust@rustastic.org\r\nsubject:Hi\r\nto:b@rustastic.org\r\nto:a@rustastic.org\r\n".to_vec();
+    hashed.extend_from_slice(b"dkim-signature:v=1; a=ed25519-sha256; c=relaxed/relaxed; d=rustastic.org; s=mail; t=1400000000; h=from:subject:to:to; bh=");
+    hashed.extend_from_slice(base64::encode(&sha256::digest(b"Hi\r\n")).as_bytes());
+    hashed.extend_from_slice(b"; b=");
+    assert_eq!(base64::encode(&sha256::digest(hashed.as_ref())), b);
+
+    key.set_canonicalization(Canonicalization::Simple, Canonicalization::Simple);
+    key.set_signed_headers(&["Subject"]);
+    let signature = key.signature_at(b"From: rust@rustastic.org\r\n\r\n", 1400000000).unwrap();
+    assert!(signature.contains("c=simple/simple;"));
+    assert!(signature.contains("h=from;"));
+    assert!(signature.contains("bh=frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY=;"));
+    assert_eq!(Err(DkimError::NoFrom), key.signature(b"Subject: Hi\r\n\r\nHi\r\n"));
+
+    let signed = key.sign(b"From: rust@rustastic.org\r\n\r\nHi\r\n").unwrap();
+    assert!(signed.starts_with(b"DKIM-Signature: v=1;"));
+    assert!(signed.ends_with(b"\r\nFrom: rust@rustastic.org\r\n\r\nHi\r\n"));
+}
+
+#[test]
+fn test_keys() {
+    let mut keys = DkimKeys::new();
+    assert!(keys.get("rustastic.org").is_none());
+    keys.add(DkimKey::new("rustastic.org", "mail", DigestSigner));
+    keys.add(DkimKey::new("Rustastic.org", "mail2", DigestSigner));
+    keys.add(DkimKey::new("example.org", "mail", DigestSigner));
+    assert_eq!("mail2", keys.get("RUSTASTIC.ORG").unwrap().selector);
+    assert_eq!("example.org", keys.get("example.org").unwrap().domain());
+    assert!(keys.get("mail.rustastic.org").is_none());
+}
+
+#[test]
+fn test_client_signing() {
+    let server = MockServer::start(&Script::new());
+    let mut keys = DkimKeys::new();
+    keys.add(DkimKey::new("rustastic.org", "mail", DigestSigner));
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    client.set_dkim_keys(keys);
+    client.hello("rustastic.org").unwrap();
+    let message = b"From: rust@rustastic.org\r\n\r\nHi\r\n";
+
+    for sender in ["rust@rustastic.org", "rust@example.org"].iter() {
+        client.mail(Some(&Mailbox::parse(sender).unwrap()), &[]).unwrap();
+        client.rcpt(&Mailbox::parse("smtp@rustastic.org").unwrap(), &[]).unwrap();
+        assert_eq!(250, client.data(message).unwrap().code());
+    }
+    let messages = server.messages();
+    assert!(messages[0].starts_with(b"DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; d=rustastic.org; s=mail;"));
+    assert!(messages[0].ends_with(message));
+    assert_eq!(message.to_vec(), messages[1]);
+}
@@ -0,0 +1,182 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The name a client gives in EHLO or HELO, depending on the address it connects from
+//! and on the domain of the sender.
+//!
+//! Receivers often check that this name resolves to the address of the client, so a
+//! host sending from several addresses needs a name for each of them.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut identity = try!(HeloIdentity::new("mx.rustastic.org"));
+//! try!(identity.map_source_ip(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), "out1.rustastic.org"));
+//! try!(identity.map_sender_domain("brand-a.example", "mail.brand-a.example"));
+//!
+//! client.hello(identity.select(Some(source), sender.as_ref()));
+//! ```
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::net::IpAddr;
+use std::vec::Vec;
+use super::super::common::utils;
+use super::super::common::mailbox::{Mailbox, MailboxForeignPart};
+#[cfg(test)]
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Maximum length of a domain name, as per RFC 5321.
+static MAX_DOMAIN_LEN: usize = 255;
+
+/// Why a name can't be given in EHLO or HELO.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum IdentityError {
+    /// The name is neither a domain nor an address literal, or is too long.
+    Invalid,
+    /// The name is a domain with a single label, while
+    /// [RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.1.1.1) requires a fully
+    /// qualified one.
+    NotFullyQualified,
+    /// The name is an address literal for another address than the one it is used for.
+    LiteralMismatch
+}
+
+/// Checks that a name can be given in EHLO or HELO, ie `mx.rustastic.org` or
+/// `[192.0.2.1]`.
+pub fn check_name(name: &str) -> Result<(), IdentityError> {
+    if let Some((literal, _)) = utils::get_mailbox_ip(name) {
+        return match literal.len() == name.len() {
+            true => Ok(()),
+            false => Err(IdentityError::Invalid)
+        };
+    }
+    match utils::get_domain(name) {
+        Some(domain) if domain.len() == name.len() && name.len() <= MAX_DOMAIN_LEN => {
+            match name.contains('.') {
+                true => Ok(()),
+                false => Err(IdentityError::NotFullyQualified)
+            }
+        },
+        _ => Err(IdentityError::Invalid)
+    }
+}
+
+#[test]
+fn test_check_name() {
+    assert_eq!(Ok(()), check_name("mx.rustastic.org"));
+    assert_eq!(Ok(()), check_name("[192.0.2.1]"));
+    assert_eq!(Ok(()), check_name("[IPv6:2001:db8::1]"));
+    assert_eq!(Err(IdentityError::NotFullyQualified), check_name("localhost"));
+    assert_eq!(Err(IdentityError::Invalid), check_name(""));
+    assert_eq!(Err(IdentityError::Invalid), check_name("mx.rustastic.org."));
+    assert_eq!(Err(IdentityError::Invalid), check_name("mx_1.rustastic.org"));
+    assert_eq!(Err(IdentityError::Invalid), check_name("-mx.rustastic.org"));
+    assert_eq!(Err(IdentityError::Invalid), check_name("[192.0.2.1]x"));
+    let long = format!("{}.org", (0 .. 64).map(|_| "abc").collect::<Vec<&str>>().join("."));
+    assert_eq!(Err(IdentityError::Invalid), check_name(long.as_ref()));
+}
+
+/// Picks the name to give in EHLO or HELO.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct HeloIdentity {
+    default: String,
+    source_ips: Vec<(IpAddr, String)>,
+    domains: Vec<(String, String)>
+}
+
+impl HeloIdentity {
+    /// Creates an identity giving the name for every connection, until more specific
+    /// names are added.
+    pub fn new(default: &str) -> Result<HeloIdentity, IdentityError> {
+        try!(check_name(default));
+        Ok(HeloIdentity {
+            default: default.to_owned(),
+            source_ips: Vec::new(),
+            domains: Vec::new()
+        })
+    }
+
+    /// Gives the name for connections from the source address, or replaces the name it
+    /// had.
+    ///
+    /// This takes precedence over the domain of the sender, because the name should
+    /// resolve to the address the receiver sees. An address literal must be the literal
+    /// of the address.
+    pub fn map_source_ip(&mut self, ip: IpAddr, name: &str) -> Result<(), IdentityError> {
+        try!(check_name(name));
+        if let Some((_, literal)) = utils::get_mailbox_ip(name) {
+            if literal != ip {
+                return Err(IdentityError::LiteralMismatch);
+            }
+        }
+        self.source_ips.retain(|&(existing, _)| existing != ip);
+        self.source_ips.push((ip, name.to_owned()));
+        Ok(())
+    }
+
+    /// Gives the name for the messages of senders in the domain, or replaces the name it
+    /// had.
+    ///
+    /// Domains are compared without regard for case, subdomains don't match.
+    pub fn map_sender_domain(&mut self, domain: &str, name: &str) -> Result<(), IdentityError> {
+        try!(check_name(name));
+        self.domains.retain(|&(ref existing, _)| !existing.eq_ignore_ascii_case(domain));
+        self.domains.push((domain.to_owned(), name.to_owned()));
+        Ok(())
+    }
+
+    /// Returns the name for a connection from the source address, if known, sending mail
+    /// from the sender.
+    pub fn select(&self, source: Option<IpAddr>, sender: Option<&Mailbox>) -> &str {
+        let by_ip = source.and_then(|source| {
+            self.source_ips.iter().find(|&&(existing, _)| existing == source)
+        });
+        let by_domain = sender.and_then(|sender| {
+            match *sender.foreign_part() {
+                MailboxForeignPart::Domain(ref domain) => {
+                    self.domains.iter().find(|&&(ref existing, _)| existing.eq_ignore_ascii_case(domain))
+                },
+                MailboxForeignPart::IpAddr(_) => None
+            }
+        });
+        match by_ip.or(by_domain) {
+            Some(&(_, ref name)) => name.as_ref(),
+            None => self.default.as_ref()
+        }
+    }
+}
+
+#[test]
+fn test_helo_identity() {
+    assert_eq!(Err(IdentityError::NotFullyQualified), HeloIdentity::new("localhost"));
+    let mut identity = HeloIdentity::new("mx.rustastic.org").unwrap();
+    let ip_1 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    let ip_2 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2));
+    let brand = Mailbox::parse("news@Brand-A.example").unwrap();
+
+    assert_eq!("mx.rustastic.org", identity.select(Some(ip_1), Some(&brand)));
+
+    identity.map_source_ip(ip_1, "out1.rustastic.org").unwrap();
+    identity.map_sender_domain("brand-a.example", "mail.brand-a.example").unwrap();
+    assert_eq!(Err(IdentityError::Invalid), identity.map_sender_domain("brand-b.example", "mail brand-b"));
+    assert_eq!(Err(IdentityError::LiteralMismatch), identity.map_source_ip(ip_2, "[192.0.2.1]"));
+    identity.map_source_ip(ip_2, "[IPv6:2001:db8::2]").unwrap();
+
+    assert_eq!("out1.rustastic.org", identity.select(Some(ip_1), Some(&brand)));
+    assert_eq!("[IPv6:2001:db8::2]", identity.select(Some(ip_2), None));
+    assert_eq!("mail.brand-a.example", identity.select(None, Some(&brand)));
+    assert_eq!("mx.rustastic.org", identity.select(None, None));
+    assert_eq!("mx.rustastic.org", identity.select(None, Some(&Mailbox::parse("rust@[127.0.0.1]").unwrap())));
+}
@@ -14,3 +14,284 @@
 
 //! The `client` module contains things needed to build an SMTP client, but useless for
 //! an SMTP server.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Error as IoError, ErrorKind};
+use std::io::Result as IoResult;
+use std::vec::Vec;
+use std::ascii::AsciiExt;
+use self::tls::{TlsReport, TlsLevel};
+use super::common::Reply;
+use super::common::lz;
+use super::common::reply::ReplyParser;
+#[cfg(test)]
+use super::common::reply::ReplyError;
+use super::common::stream::{InputStream, LINE_TOO_LONG};
+
+pub use self::session::{SmtpClient, TlsPolicy, AuthError};
+pub use self::size::SizeError;
+
+/// Sessions with SMTP servers
+pub mod session;
+
+/// TLS for outgoing connections
+pub mod tls;
+
+/// Load balancing and failover across smarthosts
+pub mod smarthost;
+
+/// Sender-dependent routing to smarthosts
+pub mod transport;
+
+/// Recipients that must not get mail
+pub mod suppression;
+
+/// Time budget for deliveries
+pub mod deadline;
+
+/// Names given in EHLO and HELO
+pub mod identity;
+
+/// Measuring the latency of servers
+pub mod probe;
+
+/// Delivery to the mail exchangers of domains
+pub mod mx;
+
+/// Sizes of messages given with MAIL
+pub mod size;
+
+/// Retries after temporary failures
+pub mod retry;
+
+/// DKIM signatures of outgoing messages
+pub mod dkim;
+
+#[cfg(test)]
+mod testing;
+
+/// What happened while delivering a message, kept for auditing.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct DeliveryReport {
+    /// The TLS session of the delivery, `None` if the connection wasn't encrypted.
+    pub tls: Option<TlsReport>
+}
+
+impl DeliveryReport {
+    /// Returns how secure the delivery actually was.
+    pub fn tls_level(&self) -> TlsLevel {
+        TlsLevel::of(self.tls.as_ref())
+    }
+}
+
+/// What a reply means for the delivery of a message.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum ReplyOutcome {
+    /// The command was accepted, or the server expects more input.
+    Accepted,
+    /// The command failed, but may succeed if tried again later.
+    Temporary,
+    /// The command failed and will fail again.
+    Permanent,
+    /// The host or domain accepts no mail at all, as described
+    /// [in RFC 7504](http://tools.ietf.org/html/rfc7504).
+    ///
+    /// This is permanent, and other hosts of the domain must not be tried either.
+    NoMail
+}
+
+/// Tells what a reply code means for the delivery of a message.
+pub fn reply_outcome(code: u16) -> ReplyOutcome {
+    match code {
+        521 | 556 => ReplyOutcome::NoMail,
+        200 ... 399 => ReplyOutcome::Accepted,
+        400 ... 499 => ReplyOutcome::Temporary,
+        _ => ReplyOutcome::Permanent
+    }
+}
+
+#[test]
+fn test_reply_outcome() {
+    assert_eq!(ReplyOutcome::Accepted, reply_outcome(250));
+    assert_eq!(ReplyOutcome::Accepted, reply_outcome(354));
+    assert_eq!(ReplyOutcome::Temporary, reply_outcome(421));
+    assert_eq!(ReplyOutcome::Temporary, reply_outcome(452));
+    assert_eq!(ReplyOutcome::Permanent, reply_outcome(550));
+    assert_eq!(ReplyOutcome::NoMail, reply_outcome(521));
+    assert_eq!(ReplyOutcome::NoMail, reply_outcome(556));
+}
+
+/// The most lines `read_reply` takes in a reply. Replies to EHLO are far shorter, longer
+/// replies come from broken or hostile servers.
+pub static MAX_REPLY_LINES: usize = 100;
+
+/// The longest line `read_reply` takes in a reply, without `<CRLF>`.
+///
+/// [RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.5.3.1.5) allows 510 octets,
+/// this leaves room for servers that don't quite follow it.
+pub static MAX_REPLY_LINE_LEN: usize = 998;
+
+/// Why a reply was refused, given as the inner error of an `InvalidData` error by
+/// `read_reply_limited`.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum ReplyLimitExceeded {
+    /// The reply has more lines than the given limit.
+    TooManyLines(usize),
+    /// A line of the reply is longer than the given limit.
+    LineTooLong(usize)
+}
+
+impl fmt::Display for ReplyLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReplyLimitExceeded::TooManyLines(max) => write!(f, "reply longer than {} lines", max),
+            ReplyLimitExceeded::LineTooLong(max) => write!(f, "reply line longer than {} octets", max)
+        }
+    }
+}
+
+impl Error for ReplyLimitExceeded {
+    fn description(&self) -> &str {
+        "reply too large"
+    }
+}
+
+/// Why an address can't be given to a server, as the inner error of an `InvalidInput`
+/// error by `SmtpClient::mail` and `SmtpClient::rcpt`.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum AddressError {
+    /// The local part isn't ASCII, and the transaction wasn't started with `SMTPUTF8`.
+    Utf8LocalPart,
+    /// The domain can't be converted to A-labels.
+    InvalidDomain
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            AddressError::Utf8LocalPart => "UTF-8 local part without SMTPUTF8",
+            AddressError::InvalidDomain => "domain can't be converted to A-labels"
+        })
+    }
+}
+
+impl Error for AddressError {
+    fn description(&self) -> &str {
+        "address not supported by the server"
+    }
+}
+
+/// Reads a reply from the server, which may span several lines, with the default limits.
+pub fn read_reply<S: Read>(input: &mut InputStream<S>) -> IoResult<Reply> {
+    read_reply_limited(input, MAX_REPLY_LINES, MAX_REPLY_LINE_LEN)
+}
+
+/// Reads a reply from the server with at most `max_lines` lines of at most
+/// `max_line_len` octets, so a hostile server can't make the client use much memory.
+///
+/// Lines are also limited by the buffer of `input`. The rest of a reply that is too
+/// large isn't read, so the session can't go on. A malformed reply fails with an
+/// `InvalidData` error whose inner error is a `ReplyError`, as soon as the faulty line
+/// is read.
+pub fn read_reply_limited<S: Read>(input: &mut InputStream<S>, max_lines: usize, max_line_len: usize) -> IoResult<Reply> {
+    let mut parser = ReplyParser::new();
+    loop {
+        let line = match input.read_line() {
+            Ok(line) if line.len() <= max_line_len => String::from_utf8_lossy(line).into_owned(),
+            Ok(_) => return Err(IoError::new(ErrorKind::InvalidData, ReplyLimitExceeded::LineTooLong(max_line_len))),
+            Err(ref err) if err.description() == LINE_TOO_LONG => {
+                return Err(IoError::new(ErrorKind::InvalidData, ReplyLimitExceeded::LineTooLong(max_line_len)));
+            },
+            Err(err) => return Err(err)
+        };
+        if parser.len() == max_lines {
+            return Err(IoError::new(ErrorKind::InvalidData, ReplyLimitExceeded::TooManyLines(max_lines)));
+        }
+        match parser.push(line.as_ref()) {
+            Ok(Some(reply)) => return Ok(reply),
+            Ok(None) => {},
+            Err(err) => return Err(IoError::new(ErrorKind::InvalidData, err))
+        }
+    }
+}
+
+/// Tells whether the reply to EHLO advertises the private `XZDAT` extension of this
+/// crate, see `server::commands::xzdat`.
+pub fn supports_xzdat(ehlo: &Reply) -> bool {
+    ehlo.lines().iter().skip(1).any(|line| {
+        line.split(' ').next().unwrap_or("").eq_ignore_ascii_case(lz::EXTENSION)
+    })
+}
+
+/// Returns the XZDAT command line for a message, and the octets to send right after it.
+///
+/// Only use this if `supports_xzdat` says so.
+pub fn xzdat_command(message: &[u8]) -> (String, Vec<u8>) {
+    let compressed = lz::compress(message);
+    (format!("XZDAT {} {}", compressed.len(), message.len()), compressed)
+}
+
+#[test]
+fn test_xzdat_command() {
+    let lines = vec!["rustastic.org".to_owned(), "XZDAT".to_owned()];
+    assert!(supports_xzdat(&Reply::multiline(250, None, lines)));
+    assert!(!supports_xzdat(&Reply::new(250, "XZDAT")));
+    assert!(!supports_xzdat(&Reply::multiline(250, None, vec!["rustastic.org".to_owned(), "XZDATA".to_owned()])));
+
+    let message = b"Subject: Hi\r\n\r\nHi hi hi hi hi\r\n";
+    let (line, compressed) = xzdat_command(message);
+    assert_eq!(format!("XZDAT {} {}", compressed.len(), message.len()), line);
+    assert_eq!(Ok(message.to_vec()), lz::decompress(compressed.as_ref(), message.len()));
+}
+
+#[test]
+fn test_read_reply() {
+    let mut input = InputStream::new(&b"250-rustastic.org\r\n250 2.0.0 SIZE 1000\r\n550 5.1.1 No\r\n250 OK\r\nHello\r\n"[..], 1000, false);
+    let reply = read_reply(&mut input).unwrap();
+    assert_eq!(250, reply.code());
+    assert_eq!(2, reply.lines().len());
+    let reply = read_reply(&mut input).unwrap();
+    assert_eq!(vec!["550 5.1.1 No"], reply.to_lines());
+    assert_eq!(vec!["250 OK"], read_reply(&mut input).unwrap().to_lines());
+    assert!(read_reply(&mut input).is_err());
+}
+
+#[test]
+fn test_read_reply_malformed() {
+    let malformed = |input: &[u8]| {
+        let mut input = InputStream::new(input, 1000, false);
+        let err = read_reply(&mut input).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        *err.get_ref().unwrap().downcast_ref::<ReplyError>().unwrap()
+    };
+
+    assert_eq!(ReplyError::InvalidLine, malformed(&b"Hello\r\n"[..]));
+    assert_eq!(ReplyError::InvalidLine, malformed(&b"250-rustastic.org\r\n250_SIZE 1000\r\n"[..]));
+    assert_eq!(ReplyError::InvalidCode, malformed(&b"999 OK\r\n"[..]));
+    // The reply fails at the faulty line, the rest of it is never waited for.
+    assert_eq!(ReplyError::MixedCodes, malformed(&b"250-rustastic.org\r\n550-SIZE 1000\r\n"[..]));
+}
+
+#[test]
+fn test_read_reply_limited() {
+    let limit = |result: IoResult<Reply>| {
+        let err = result.unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        *err.get_ref().unwrap().downcast_ref::<ReplyLimitExceeded>().unwrap()
+    };
+
+    let mut input = InputStream::new(&b"250-rustastic.org\r\n250-SIZE 1000\r\n250 8BITMIME\r\n"[..], 1000, false);
+    assert_eq!(ReplyLimitExceeded::TooManyLines(2), limit(read_reply_limited(&mut input, 2, 100)));
+    let mut input = InputStream::new(&b"250-rustastic.org\r\n250-SIZE 1000\r\n250 8BITMIME\r\n"[..], 1000, false);
+    assert_eq!(3, read_reply_limited(&mut input, 3, 17).unwrap().lines().len());
+    let mut input = InputStream::new(&b"250-rustastic.org\r\n250 SIZE 1000\r\n"[..], 1000, false);
+    assert_eq!(ReplyLimitExceeded::LineTooLong(16), limit(read_reply_limited(&mut input, 3, 16)));
+
+    // Lines longer than the buffer.
+    let mut long = b"250 ".to_vec();
+    long.extend((0 .. 2000).map(|_| b'a'));
+    long.extend(b"\r\n".iter().cloned());
+    let mut input = InputStream::new(&long[..], 1000, false);
+    assert_eq!(ReplyLimitExceeded::LineTooLong(MAX_REPLY_LINE_LEN), limit(read_reply(&mut input)));
+}
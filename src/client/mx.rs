@@ -0,0 +1,242 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Direct delivery to the mail exchangers of a domain, as described
+//! [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-5).
+//!
+//! Hosts are tried by order of preference. A domain without MX records gets its mail at
+//! its own address, and a domain with a null MX, as described
+//! [in RFC 7505](http://tools.ietf.org/html/rfc7505), doesn't get mail at all. When a
+//! host can't be reached or fails temporarily, the next one is tried.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let router = MxRouter::new(try!(DnsClient::from_resolv_conf("/etc/resolv.conf")));
+//! let reply = try!(router.deliver("rustastic.org", |client| {
+//!     try!(client.hello("mx.example.com"));
+//!     ...
+//!     client.data(message)
+//! }));
+//! ```
+
+use std::borrow::ToOwned;
+use std::io::Result as IoResult;
+use std::net::{SocketAddr, TcpStream};
+use std::vec::Vec;
+use super::session::SmtpClient;
+use super::super::common::Reply;
+use super::super::common::dns::{Resolver, DnsError};
+use super::super::common::stream::Transport;
+#[cfg(test)]
+use std::net::{IpAddr, Ipv4Addr};
+#[cfg(test)]
+use super::testing::{Script, Step, MockServer};
+#[cfg(test)]
+use super::super::common::dns::MxRecord;
+#[cfg(test)]
+use super::super::common::mailbox::Mailbox;
+#[cfg(test)]
+use super::super::common::status::EnhancedStatusCode;
+
+/// Represents an error that occured while delivering to the hosts of a domain.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum MxError {
+    /// The MX records of the domain couldn't be looked up.
+    Dns(DnsError),
+    /// The domain has a null MX, it doesn't accept mail.
+    NullMx,
+    /// No host took the message. Each host that was tried is given, with why it failed.
+    Exhausted(Vec<(String, String)>)
+}
+
+/// Delivers to the mail exchangers of domains, see `client::mx`.
+pub struct MxRouter<R> {
+    resolver: R,
+    port: u16
+}
+
+impl<R: Resolver> MxRouter<R> {
+    /// Creates a router looking up hosts with the resolver, and connecting to port 25.
+    pub fn new(resolver: R) -> MxRouter<R> {
+        MxRouter {
+            resolver: resolver,
+            port: 25
+        }
+    }
+
+    /// Sets the port to connect to.
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    /// Returns the hosts to try for a domain, by order of preference.
+    ///
+    /// Hosts with the same preference are kept in the order of the response, which name
+    /// servers usually rotate.
+    pub fn hosts(&self, domain: &str) -> Result<Vec<String>, MxError> {
+        let mut records = try!(self.resolver.lookup_mx(domain).map_err(MxError::Dns));
+        if records.len() == 0 {
+            return Ok(vec![domain.to_owned()]);
+        }
+        if records.len() == 1 && records[0].exchange.len() == 0 {
+            return Err(MxError::NullMx);
+        }
+        records.sort_by(|a, b| a.preference.cmp(&b.preference));
+        Ok(records.into_iter().map(|record| record.exchange).collect())
+    }
+
+    // Connects to the first address of the host that accepts the connection.
+    fn connect(&self, host: &str) -> Result<SmtpClient<Transport>, String> {
+        let ips = match self.resolver.lookup_ip(host) {
+            Ok(ips) => ips,
+            Err(DnsError::Temporary(err)) => return Err(err),
+            Err(err) => return Err(format!("{:?}", err))
+        };
+        let mut last_err = "no address".to_owned();
+        for ip in ips {
+            match TcpStream::connect(SocketAddr::new(ip, self.port)).and_then(SmtpClient::with_tcp) {
+                Ok(client) => return Ok(client),
+                Err(err) => last_err = format!("{}: {}", ip, err)
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Delivers to the hosts of a domain, until one of them doesn't fail temporarily.
+    ///
+    /// `send` is called with a session on each host that accepts it, and returns the
+    /// last reply of the delivery, ie the reply to DATA, or the first failure. The
+    /// session is closed with QUIT afterwards. The next host is tried when the
+    /// connection fails or the reply is `4xx`. A `5xx` reply is returned as is, other
+    /// hosts would refuse the message too.
+    pub fn deliver<F>(&self, domain: &str, mut send: F) -> Result<Reply, MxError>
+        where F: FnMut(&mut SmtpClient<Transport>) -> IoResult<Reply> {
+        let mut failures = Vec::new();
+        for host in try!(self.hosts(domain)) {
+            let mut client = match self.connect(host.as_ref()) {
+                Ok(client) => client,
+                Err(err) => {
+                    failures.push((host, err));
+                    continue;
+                }
+            };
+            // A host refusing the session may be overloaded, others may take the message.
+            if client.banner().code() != 220 {
+                failures.push((host, format!("banner {}", client.banner().code())));
+                let _ = client.quit();
+                continue;
+            }
+            match send(&mut client) {
+                Ok(reply) => {
+                    let _ = client.quit();
+                    match reply.code() {
+                        400 ... 499 => failures.push((host, format!("reply {}", reply.code()))),
+                        _ => return Ok(reply)
+                    }
+                },
+                Err(err) => failures.push((host, err.to_string()))
+            }
+        }
+        Err(MxError::Exhausted(failures))
+    }
+}
+
+#[cfg(test)]
+struct TestResolver;
+
+#[cfg(test)]
+impl Resolver for TestResolver {
+    fn lookup_mx(&self, domain: &str) -> Result<Vec<MxRecord>, DnsError> {
+        let mx = |preference, exchange: &str| MxRecord { preference: preference, exchange: exchange.to_owned() };
+        match domain {
+            "rustastic.org" => Ok(vec![
+                mx(30, "mx3.rustastic.org"),
+                mx(10, "mx1.rustastic.org"),
+                mx(20, "mx2.rustastic.org"),
+                mx(30, "mx4.rustastic.org"),
+                mx(40, "mx5.rustastic.org")
+            ]),
+            "implicit.rustastic.org" => Ok(vec![]),
+            "null.rustastic.org" => Ok(vec![mx(0, "")]),
+            "down.rustastic.org" => Err(DnsError::Temporary("timeout".to_owned())),
+            _ => Err(DnsError::NotFound)
+        }
+    }
+
+    fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>, DnsError> {
+        match host {
+            // Nothing listens there.
+            "mx1.rustastic.org" => Ok(vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))]),
+            "mx2.rustastic.org" => Err(DnsError::Temporary("timeout".to_owned())),
+            _ => Ok(vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))])
+        }
+    }
+}
+
+#[test]
+fn test_hosts() {
+    let router = MxRouter::new(TestResolver);
+    assert_eq!(Ok(vec![
+        "mx1.rustastic.org".to_owned(),
+        "mx2.rustastic.org".to_owned(),
+        "mx3.rustastic.org".to_owned(),
+        "mx4.rustastic.org".to_owned(),
+        "mx5.rustastic.org".to_owned()
+    ]), router.hosts("rustastic.org"));
+    assert_eq!(Ok(vec!["implicit.rustastic.org".to_owned()]), router.hosts("implicit.rustastic.org"));
+    assert_eq!(Err(MxError::NullMx), router.hosts("null.rustastic.org"));
+    assert_eq!(Err(MxError::Dns(DnsError::NotFound)), router.hosts("unknown.rustastic.org"));
+    assert_eq!(Err(MxError::Dns(DnsError::Temporary("timeout".to_owned()))), router.hosts("down.rustastic.org"));
+}
+
+#[test]
+fn test_deliver() {
+    let mut script = Script::new();
+    script.greeting(Step::Reply(Reply::new(421, "Too busy")))
+        .greeting(Step::Reply(Reply::new(220, "rustastic.test ESMTP")))
+        .on("MAIL", Step::Reply(Reply::enhanced(451, EnhancedStatusCode::new(4, 3, 0), "Try again later")))
+        .on("MAIL", Step::Reply(Reply::enhanced(250, EnhancedStatusCode::new(2, 1, 0), "OK")));
+    let server = MockServer::start(&script);
+    let mut router = MxRouter::new(TestResolver);
+    router.set_port(server.addr().port());
+    let sender = Mailbox::parse("rust@rustastic.org").unwrap();
+
+    let reply = router.deliver("rustastic.org", |client| {
+        try!(client.hello("mx.rustastic.org"));
+        client.mail(Some(&sender), &[])
+    }).unwrap();
+    assert_eq!(250, reply.code());
+    assert_eq!(vec![
+        "QUIT".to_owned(),
+        "EHLO mx.rustastic.org".to_owned(),
+        "MAIL FROM:<rust@rustastic.org>".to_owned(),
+        "QUIT".to_owned(),
+        "EHLO mx.rustastic.org".to_owned(),
+        "MAIL FROM:<rust@rustastic.org>".to_owned(),
+        "QUIT".to_owned()
+    ], server.commands());
+
+    // Once every host failed, all the failures are given.
+    let mut router = MxRouter::new(TestResolver);
+    router.set_port(server.addr().port());
+    match router.deliver("implicit.rustastic.org", |client| client.command("NOOP").map(|_| {
+        Reply::enhanced(452, EnhancedStatusCode::new(4, 3, 1), "Insufficient storage")
+    })) {
+        Err(MxError::Exhausted(failures)) => {
+            assert_eq!(vec![("implicit.rustastic.org".to_owned(), "reply 452".to_owned())], failures);
+        },
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Measuring how fast a server answers, without sending mail, ie to monitor the health
+//! of a relay.
+//!
+//! A probe connects, reads the banner, says EHLO, sends NOOP a few times and quits,
+//! timing each step.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let report = try!(SmtpClient::probe("mx.rustastic.org:25", "monitor.rustastic.org", 3, Duration::from_secs(10)));
+//! if report.noop_average().map_or(false, |average| average > Duration::from_millis(500)) {
+//!     alert(report.addr);
+//! }
+//! ```
+
+use std::io::{Error as IoError, ErrorKind};
+use std::io::Result as IoResult;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+use super::session::SmtpClient;
+use super::super::common::stream::Transport;
+#[cfg(test)]
+use std::borrow::ToOwned;
+#[cfg(test)]
+use super::testing::{Script, Step, MockServer};
+#[cfg(test)]
+use super::super::common::Reply;
+#[cfg(test)]
+use super::super::common::status::EnhancedStatusCode;
+
+/// How long each step of a probe took.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct LatencyReport {
+    /// The address that was probed.
+    pub addr: SocketAddr,
+    /// Opening the TCP connection.
+    pub connect: Duration,
+    /// Getting the banner, once connected.
+    pub banner: Duration,
+    /// Getting the reply to EHLO, including the retry with HELO if needed.
+    pub ehlo: Duration,
+    /// Getting the reply to each NOOP.
+    pub noops: Vec<Duration>
+}
+
+impl LatencyReport {
+    /// Returns the fastest NOOP, `None` if none was sent.
+    pub fn noop_min(&self) -> Option<Duration> {
+        self.noops.iter().cloned().min()
+    }
+
+    /// Returns the slowest NOOP, `None` if none was sent.
+    pub fn noop_max(&self) -> Option<Duration> {
+        self.noops.iter().cloned().max()
+    }
+
+    /// Returns the average time of NOOP, `None` if none was sent.
+    pub fn noop_average(&self) -> Option<Duration> {
+        match self.noops.len() {
+            0 => None,
+            len => {
+                let total = self.noops.iter().fold(Duration::from_secs(0), |total, &noop| total + noop);
+                Some(total / len as u32)
+            }
+        }
+    }
+}
+
+// Fails unless the server replied with the expected code.
+fn expect(code: u16, expected: u16, step: &str) -> IoResult<()> {
+    match code == expected {
+        true => Ok(()),
+        false => Err(IoError::new(ErrorKind::Other, format!("{} replied with {}", step, code)))
+    }
+}
+
+impl SmtpClient<Transport> {
+    /// Probes a server, sending NOOP `noops` times, see `client::probe`.
+    ///
+    /// Addresses are tried in order until one accepts the connection. Each reply must
+    /// come within `timeout`. Fails if the connection fails, or if the server refuses
+    /// the session or a command, since the time of a refusal says little about the
+    /// health of a server.
+    pub fn probe<A: ToSocketAddrs>(address: A, hello: &str, noops: usize, timeout: Duration) -> IoResult<LatencyReport> {
+        let mut last_err = IoError::new(ErrorKind::InvalidInput, "no address to probe");
+        let mut connected = None;
+        for addr in try!(address.to_socket_addrs()) {
+            let started = Instant::now();
+            match TcpStream::connect(addr) {
+                Ok(stream) => {
+                    connected = Some((addr, stream, started.elapsed()));
+                    break;
+                },
+                Err(err) => last_err = err
+            }
+        }
+        let (addr, stream, connect) = match connected {
+            Some(connected) => connected,
+            None => return Err(last_err)
+        };
+        try!(stream.set_read_timeout(Some(timeout)));
+        try!(stream.set_write_timeout(Some(timeout)));
+
+        let started = Instant::now();
+        let mut client = try!(SmtpClient::with_tcp(stream));
+        let banner = started.elapsed();
+        try!(expect(client.banner().code(), 220, "banner"));
+
+        let started = Instant::now();
+        let code = try!(client.hello(hello)).code();
+        let ehlo = started.elapsed();
+        try!(expect(code, 250, "EHLO"));
+
+        let mut report = LatencyReport {
+            addr: addr,
+            connect: connect,
+            banner: banner,
+            ehlo: ehlo,
+            noops: Vec::with_capacity(noops)
+        };
+        for _ in 0 .. noops {
+            let started = Instant::now();
+            let code = try!(client.command("NOOP")).code();
+            report.noops.push(started.elapsed());
+            try!(expect(code, 250, "NOOP"));
+        }
+        // The measurements are done, a failure to say goodbye doesn't change them.
+        let _ = client.quit();
+        Ok(report)
+    }
+}
+
+#[test]
+fn test_probe() {
+    let server = MockServer::start(&Script::new());
+    let report = SmtpClient::probe(server.addr(), "rustastic.org", 3, Duration::from_secs(5)).unwrap();
+    assert_eq!(server.addr(), report.addr);
+    assert_eq!(3, report.noops.len());
+    assert!(report.noop_min().unwrap() <= report.noop_average().unwrap());
+    assert!(report.noop_average().unwrap() <= report.noop_max().unwrap());
+    assert_eq!(vec![
+        "EHLO rustastic.org".to_owned(),
+        "NOOP".to_owned(),
+        "NOOP".to_owned(),
+        "NOOP".to_owned(),
+        "QUIT".to_owned()
+    ], server.commands());
+
+    let report = LatencyReport { noops: Vec::new(), ..report };
+    assert_eq!(None, report.noop_average());
+}
+
+#[test]
+fn test_probe_refused() {
+    let mut script = Script::new();
+    script.greeting(Step::Reply(Reply::new(554, "No SMTP service here")))
+        .greeting(Step::Reply(Reply::new(220, "rustastic.test ESMTP")))
+        .on("NOOP", Step::Reply(Reply::enhanced(421, EnhancedStatusCode::new(4, 3, 2), "Shutting down")));
+    let server = MockServer::start(&script);
+
+    let err = SmtpClient::probe(server.addr(), "rustastic.org", 1, Duration::from_secs(5)).unwrap_err();
+    assert_eq!("banner replied with 554", err.to_string());
+    let err = SmtpClient::probe(server.addr(), "rustastic.org", 1, Duration::from_secs(5)).unwrap_err();
+    assert_eq!("NOOP replied with 421", err.to_string());
+}
@@ -0,0 +1,334 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sending a message again after temporary failures, waiting longer each time, until
+//! every recipient got it, was refused for good, or the retries take too long.
+//!
+//! `4xx` replies and connection failures, timeouts included, are temporary. Other
+//! failures are permanent, see `reply_outcome`. Each attempt only sends the message to
+//! the recipients that failed temporarily so far.
+//!
+//! The calling thread sleeps between attempts, so this suits short deadlines, ie for
+//! submission. A queue is better for retrying over hours or days.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut policy = RetryPolicy::new(Duration::from_secs(300));
+//! policy.set_delays(Duration::from_secs(5), Duration::from_secs(60), 2);
+//! let report = policy.send(|| SmtpClient::connect("mx.rustastic.org:25"), "rustastic.org",
+//!     Some(&sender), recipients.as_ref(), message.as_ref());
+//! ```
+
+use std::borrow::ToOwned;
+use std::cmp;
+use std::io::{Read, Write, Error as IoError};
+use std::io::Result as IoResult;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+use super::{reply_outcome, ReplyOutcome};
+use super::session::SmtpClient;
+use super::super::common::Reply;
+use super::super::common::mailbox::Mailbox;
+#[cfg(test)]
+use super::testing::{Script, Step, MockServer};
+#[cfg(test)]
+use super::super::common::status::EnhancedStatusCode;
+
+/// Why an attempt failed temporarily.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum TemporaryFailure {
+    /// The server replied with `4xx`.
+    Reply(Reply),
+    /// The connection failed, or the server doesn't speak SMTP.
+    Connection(String)
+}
+
+/// What happened to the message for a recipient.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum RecipientOutcome {
+    /// The message was delivered, with the given reply to the message.
+    Delivered(Reply),
+    /// The message was refused for good, with the given reply.
+    Refused(Reply),
+    /// The message was still failing temporarily when the retries took too long.
+    Expired(TemporaryFailure)
+}
+
+/// What happened to a message, see `RetryPolicy::send`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct RetryReport {
+    /// How many times the message was sent, including the first time.
+    pub attempts: u32,
+    /// What happened to the message for each recipient, in the order they were given.
+    pub recipients: Vec<(Mailbox, RecipientOutcome)>
+}
+
+/// When and for how long to retry, see `client::retry`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct RetryPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: u32,
+    give_up_after: Duration
+}
+
+// The outcome of an attempt for a recipient, `None` while it isn't known.
+type Attempt = Vec<Option<Result<RecipientOutcome, TemporaryFailure>>>;
+
+impl RetryPolicy {
+    /// Creates a policy retrying for at most `give_up_after` after the first attempt.
+    ///
+    /// By default, the first retry comes after a minute, and each retry waits twice as
+    /// long as the previous one, up to an hour.
+    pub fn new(give_up_after: Duration) -> RetryPolicy {
+        RetryPolicy {
+            initial_delay: Duration::from_secs(60),
+            max_delay: Duration::from_secs(3600),
+            multiplier: 2,
+            give_up_after: give_up_after
+        }
+    }
+
+    /// Sets how long to wait before the first retry, the longest wait between two retries,
+    /// and by how much the wait is multiplied after each retry.
+    pub fn set_delays(&mut self, initial: Duration, max: Duration, multiplier: u32) {
+        self.initial_delay = initial;
+        self.max_delay = cmp::max(initial, max);
+        self.multiplier = cmp::max(multiplier, 1);
+    }
+
+    /// Returns how long after the first attempt retries stop.
+    pub fn give_up_after(&self) -> Duration {
+        self.give_up_after
+    }
+
+    /// Returns how long to wait before the given retry, starting at 0 for the first one.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let mut delay = self.initial_delay;
+        for _ in 0 .. retry {
+            if delay >= self.max_delay {
+                break;
+            }
+            delay = delay * self.multiplier;
+        }
+        cmp::min(delay, self.max_delay)
+    }
+
+    /// Sends a message to the recipients, retrying on temporary failures.
+    ///
+    /// `connect` opens a new session for each attempt. The session is identified with
+    /// `hello`, and closed with QUIT at the end of each attempt. No retry is made once it
+    /// would start after the deadline.
+    pub fn send<S, F>(&self, mut connect: F, hello: &str, sender: Option<&Mailbox>, recipients: &[Mailbox], message: &[u8]) -> RetryReport
+        where S: Read + Write, F: FnMut() -> IoResult<SmtpClient<S>> {
+        let started = Instant::now();
+        let mut outcomes: Vec<Option<RecipientOutcome>> = recipients.iter().map(|_| None).collect();
+        let mut attempts = 0;
+        loop {
+            let pending: Vec<usize> = (0 .. recipients.len()).filter(|&i| outcomes[i].is_none()).collect();
+            let results = match connect() {
+                Ok(mut client) => {
+                    let results = attempt(&mut client, hello, sender, recipients, pending.as_ref(), message);
+                    // The outcome is known, a failure to say goodbye doesn't change it.
+                    let _ = client.quit();
+                    results
+                },
+                Err(err) => pending.iter().map(|_| Some(Err(TemporaryFailure::Connection(err.to_string())))).collect()
+            };
+            attempts += 1;
+
+            let delay = self.delay(attempts - 1);
+            let expired = started.elapsed() + delay > self.give_up_after;
+            for (&i, result) in pending.iter().zip(results.into_iter()) {
+                outcomes[i] = match result {
+                    Some(Ok(outcome)) => Some(outcome),
+                    Some(Err(failure)) if expired => Some(RecipientOutcome::Expired(failure)),
+                    Some(Err(_)) | None => None
+                };
+            }
+            if outcomes.iter().all(|outcome| outcome.is_some()) {
+                break;
+            }
+            thread::sleep(delay);
+        }
+        RetryReport {
+            attempts: attempts,
+            recipients: recipients.iter().cloned().zip(outcomes.into_iter().map(|outcome| outcome.unwrap())).collect()
+        }
+    }
+}
+
+// What a reply means for the recipients it applies to.
+fn classify(reply: &Reply) -> Option<Result<RecipientOutcome, TemporaryFailure>> {
+    match reply_outcome(reply.code()) {
+        ReplyOutcome::Accepted => None,
+        ReplyOutcome::Temporary => Some(Err(TemporaryFailure::Reply(reply.clone()))),
+        ReplyOutcome::Permanent | ReplyOutcome::NoMail => Some(Ok(RecipientOutcome::Refused(reply.clone())))
+    }
+}
+
+/// Sends a message once to the recipients over a session, without retrying, and returns
+/// the outcome for each of them, in order.
+///
+/// Recipients that failed temporarily get a `TemporaryFailure`. The session is identified
+/// with `hello`, and left open.
+pub fn send_once<S: Read + Write>(client: &mut SmtpClient<S>, hello: &str, sender: Option<&Mailbox>, recipients: &[Mailbox], message: &[u8]) -> Vec<Result<RecipientOutcome, TemporaryFailure>> {
+    let all: Vec<usize> = (0 .. recipients.len()).collect();
+    attempt(client, hello, sender, recipients, all.as_ref(), message).into_iter().map(|result| {
+        result.unwrap_or(Err(TemporaryFailure::Connection("no reply".to_owned())))
+    }).collect()
+}
+
+// Sends the message once to the pending recipients, given by their index. Returns the
+// outcome for each of them.
+fn attempt<S: Read + Write>(client: &mut SmtpClient<S>, hello: &str, sender: Option<&Mailbox>, recipients: &[Mailbox], pending: &[usize], message: &[u8]) -> Attempt {
+    let mut results: Attempt = pending.iter().map(|_| None).collect();
+    {
+        // Fills the outcome of the recipients without one yet.
+        let fill = |results: &mut Attempt, outcome: Result<RecipientOutcome, TemporaryFailure>| {
+            for result in results.iter_mut().filter(|result| result.is_none()) {
+                *result = Some(outcome.clone());
+            }
+        };
+        let failure = |err: IoError| Err(TemporaryFailure::Connection(err.to_string()));
+
+        let reply = match client.banner().code() {
+            220 => client.hello(hello),
+            _ => Ok(client.banner().clone())
+        }.and_then(|reply| match classify(&reply) {
+            Some(_) => Ok(reply),
+            None => client.mail(sender, &[])
+        });
+        match reply {
+            Ok(ref reply) if classify(reply).is_some() => fill(&mut results, classify(reply).unwrap()),
+            Ok(_) => {},
+            Err(err) => fill(&mut results, failure(err))
+        }
+
+        let mut accepted = Vec::new();
+        for (j, &i) in pending.iter().enumerate() {
+            if results[j].is_some() {
+                break;
+            }
+            match client.rcpt(&recipients[i], &[]) {
+                Ok(reply) => match classify(&reply) {
+                    Some(outcome) => results[j] = Some(outcome),
+                    None => accepted.push(j)
+                },
+                Err(err) => {
+                    fill(&mut results, failure(err));
+                    break;
+                }
+            }
+        }
+
+        if accepted.len() > 0 {
+            let outcome = match client.send_message(message) {
+                Ok(reply) => classify(&reply).unwrap_or(Ok(RecipientOutcome::Delivered(reply))),
+                Err(err) => failure(err)
+            };
+            fill(&mut results, outcome);
+        }
+    }
+    results
+}
+
+#[test]
+fn test_delay() {
+    let mut policy = RetryPolicy::new(Duration::from_secs(3600));
+    assert_eq!(Duration::from_secs(60), policy.delay(0));
+    assert_eq!(Duration::from_secs(240), policy.delay(2));
+    assert_eq!(Duration::from_secs(3600), policy.delay(100));
+    policy.set_delays(Duration::from_secs(10), Duration::from_secs(100), 3);
+    assert_eq!(Duration::from_secs(30), policy.delay(1));
+    assert_eq!(Duration::from_secs(100), policy.delay(3));
+}
+
+#[cfg(test)]
+fn get_policy(give_up_after: u64) -> RetryPolicy {
+    let mut policy = RetryPolicy::new(Duration::from_millis(give_up_after));
+    policy.set_delays(Duration::from_millis(10), Duration::from_millis(20), 2);
+    policy
+}
+
+#[test]
+fn test_send() {
+    let mut script = Script::new();
+    script.greeting(Step::Reply(Reply::new(421, "Too busy")))
+        .greeting(Step::Reply(Reply::new(220, "rustastic.test ESMTP")))
+        .on("RCPT", Step::Reply(Reply::enhanced(250, EnhancedStatusCode::new(2, 1, 5), "OK")))
+        .on("RCPT", Step::Reply(Reply::enhanced(450, EnhancedStatusCode::new(4, 2, 1), "Mailbox busy")))
+        .on("RCPT", Step::Reply(Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 1), "No such user")))
+        .on("RCPT", Step::Reply(Reply::enhanced(250, EnhancedStatusCode::new(2, 1, 5), "OK")));
+    let server = MockServer::start(&script);
+    let sender = Mailbox::parse("rust@rustastic.org").unwrap();
+    let recipients = vec![
+        Mailbox::parse("a@rustastic.org").unwrap(),
+        Mailbox::parse("b@rustastic.org").unwrap(),
+        Mailbox::parse("c@rustastic.org").unwrap()
+    ];
+
+    let report = get_policy(5000).send(|| SmtpClient::connect(server.addr()), "rustastic.org",
+        Some(&sender), recipients.as_ref(), b"Hello\r\n");
+    assert_eq!(3, report.attempts);
+    let delivered = RecipientOutcome::Delivered(Reply::enhanced(250, EnhancedStatusCode::new(2, 0, 0), "OK"));
+    assert_eq!(vec![
+        (recipients[0].clone(), delivered.clone()),
+        (recipients[1].clone(), delivered),
+        (recipients[2].clone(), RecipientOutcome::Refused(Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 1), "No such user")))
+    ], report.recipients);
+    assert_eq!(vec![
+        // Refused by the banner.
+        "QUIT".to_owned(),
+        "EHLO rustastic.org".to_owned(),
+        "MAIL FROM:<rust@rustastic.org>".to_owned(),
+        "RCPT TO:<a@rustastic.org>".to_owned(),
+        "RCPT TO:<b@rustastic.org>".to_owned(),
+        "RCPT TO:<c@rustastic.org>".to_owned(),
+        "DATA".to_owned(),
+        "QUIT".to_owned(),
+        // Only the recipient that failed temporarily is tried again.
+        "EHLO rustastic.org".to_owned(),
+        "MAIL FROM:<rust@rustastic.org>".to_owned(),
+        "RCPT TO:<b@rustastic.org>".to_owned(),
+        "DATA".to_owned(),
+        "QUIT".to_owned()
+    ], server.commands());
+}
+
+#[test]
+fn test_send_expired() {
+    let mut script = Script::new();
+    script.on("MAIL", Step::Reply(Reply::enhanced(451, EnhancedStatusCode::new(4, 3, 0), "Try again later")));
+    let server = MockServer::start(&script);
+    let recipients = vec![Mailbox::parse("a@rustastic.org").unwrap()];
+
+    let report = get_policy(30).send(|| SmtpClient::connect(server.addr()), "rustastic.org",
+        None, recipients.as_ref(), b"Hello\r\n");
+    assert!(report.attempts >= 2);
+    assert_eq!(vec![(recipients[0].clone(), RecipientOutcome::Expired(TemporaryFailure::Reply(
+        Reply::enhanced(451, EnhancedStatusCode::new(4, 3, 0), "Try again later")
+    )))], report.recipients);
+
+    // Connection failures are temporary too.
+    let report = get_policy(0).send(|| SmtpClient::connect("127.0.0.2:1"), "rustastic.org",
+        None, recipients.as_ref(), b"Hello\r\n");
+    assert_eq!(1, report.attempts);
+    match report.recipients[0].1 {
+        RecipientOutcome::Expired(TemporaryFailure::Connection(_)) => {},
+        ref other => panic!("unexpected outcome: {:?}", other)
+    }
+}
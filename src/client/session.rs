@@ -0,0 +1,1010 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An SMTP session with a server, command by command.
+//!
+//! Every command returns the reply of the server, failures included, so the caller
+//! decides what to do with them, ie with `reply_outcome`. Errors are only returned when
+//! the connection fails or the server doesn't speak SMTP.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use rsmtp::client::SmtpClient;
+//! use rsmtp::common::mailbox::Mailbox;
+//!
+//! let mut client = SmtpClient::connect("127.0.0.1:25").unwrap();
+//! client.hello("rustastic.org").unwrap();
+//! let sender = Mailbox::parse("rust@rustastic.org").unwrap();
+//! let recipient = Mailbox::parse("smtp@rustastic.org").unwrap();
+//! if client.mail(Some(&sender), &[]).unwrap().code() == 250 &&
+//!     client.rcpt(&recipient, &[]).unwrap().code() == 250 {
+//!     client.data(b"Subject: Hi\r\n\r\nHello\r\n").unwrap();
+//! }
+//! client.quit().unwrap();
+//! ```
+//!
+//! # Internationalized addresses
+//!
+//! Addresses with UTF-8 in them are sent as they are when the transaction was started
+//! with the `SMTPUTF8` parameter, as described
+//! [in RFC 6531](http://tools.ietf.org/html/rfc6531). `mail` adds it for a UTF-8 sender
+//! when the server advertises the extension, and `smtputf8_parameter` tells whether to
+//! add it for the recipients. Otherwise, U-label domains are converted to A-labels, ie
+//! `rust@xn--bcher-kva.example` for `rust@bücher.example`, which every server takes.
+//! UTF-8 local parts can't be converted, so they fail with `AddressError`.
+
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::borrow::ToOwned;
+use std::io::{Read, Write, Error as IoError, ErrorKind};
+use std::io::Result as IoResult;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+use super::{read_reply, read_reply_limited, MAX_REPLY_LINES, MAX_REPLY_LINE_LEN, AddressError, DeliveryReport};
+use super::size::{SizeEstimator, SizeError, ExactSize};
+use super::dkim::DkimKeys;
+use super::tls::{TlsConfig, TlsReport, TlsLevel};
+use super::super::common::Reply;
+use super::super::common::MIN_ALLOWED_LINE_SIZE;
+use super::super::common::base64;
+use super::super::common::md5;
+use super::super::common::instrument::InstrumentedStream;
+use super::super::common::mailbox::{Mailbox, MailboxForeignPart};
+use super::super::common::stream::{InputStream, OutputStream, Transport};
+use super::super::server::AuthMechanism;
+#[cfg(test)]
+use super::testing::{Script, Step, MockServer};
+#[cfg(test)]
+use super::tls::PlainConnector;
+#[cfg(test)]
+use super::size::WireSize;
+#[cfg(test)]
+use super::ReplyLimitExceeded;
+#[cfg(test)]
+use super::super::common::status::EnhancedStatusCode;
+
+/// The size of the chunks `SmtpClient::send_message` sends with BDAT.
+pub static DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How secure a session must be, see `SmtpClient::starttls`.
+///
+/// Policies are ordered from the weakest to the strictest, so senders can tighten them
+/// one destination at a time, see `tls::TlsPolicies`.
+#[derive(PartialEq, Eq, Clone, Debug, Copy, PartialOrd, Ord)]
+pub enum TlsPolicy {
+    /// Never encrypts the session.
+    Disabled,
+    /// Encrypts the session if the server offers STARTTLS, whatever its certificate, and
+    /// goes on in plain text otherwise.
+    Opportunistic,
+    /// Fails unless the session is encrypted, whatever the certificate of the server.
+    RequiredUnverified,
+    /// Fails unless the session is encrypted and the certificate of the server is
+    /// accepted by the `TlsConfig`.
+    Required
+}
+
+/// Represents an error that occured while authenticating.
+#[derive(Debug)]
+pub enum AuthError {
+    /// The connection failed, or the server doesn't speak SMTP.
+    Io(IoError),
+    /// The server offers none of the mechanisms the client supports.
+    NoMechanism,
+    /// The server rejected the credentials or the exchange, with the given reply.
+    Rejected(Reply)
+}
+
+/// A session with an SMTP server.
+pub struct SmtpClient<S> {
+    input: InputStream<S>,
+    output: OutputStream<S>,
+    banner: Reply,
+    extensions: Vec<String>,
+    tls: Option<TlsReport>,
+    size_estimator: Box<SizeEstimator>,
+    max_reply_lines: usize,
+    max_reply_line_len: usize,
+    smtputf8: bool,
+    dkim: Option<DkimKeys>,
+    sender_domain: Option<String>
+}
+
+impl SmtpClient<Transport> {
+    /// Connects to a server and reads its banner.
+    pub fn connect<A: ToSocketAddrs>(address: A) -> IoResult<SmtpClient<Transport>> {
+        SmtpClient::with_tcp(try!(TcpStream::connect(address)))
+    }
+
+    /// Starts a session on a TCP connection that is already open, and reads the banner.
+    pub fn with_tcp(stream: TcpStream) -> IoResult<SmtpClient<Transport>> {
+        let stream = InstrumentedStream::new(stream);
+        let input = InstrumentedStream::with_stats(try!(stream.try_clone()), stream.stats().clone());
+        SmtpClient::new(Transport::Tcp(input), Transport::Tcp(stream))
+    }
+
+    /// Connects to a server with implicit TLS, ie on port 465 as described
+    /// [in RFC 8314](http://tools.ietf.org/html/rfc8314#section-3), and reads the
+    /// banner over the encrypted connection.
+    ///
+    /// `domain` is the domain the certificate of the server must match. It is checked as
+    /// with `TlsPolicy::Required`, since nothing can be sent in plain text instead.
+    pub fn connect_tls<A: ToSocketAddrs>(address: A, config: &TlsConfig, domain: &str) -> IoResult<SmtpClient<Transport>> {
+        SmtpClient::with_tls(try!(TcpStream::connect(address)), config, domain)
+    }
+
+    /// Starts a session with implicit TLS on a TCP connection that is already open, see
+    /// `connect_tls`.
+    pub fn with_tls(stream: TcpStream, config: &TlsConfig, domain: &str) -> IoResult<SmtpClient<Transport>> {
+        let socket = Arc::new(try!(stream.try_clone()));
+        let (tls, report) = try!(config.connect(domain, stream));
+        let tls = Arc::new(Mutex::new(InstrumentedStream::new(tls)));
+        let mut client = try!(SmtpClient::new(Transport::Tls(tls.clone(), socket.clone()), Transport::Tls(tls, socket)));
+        client.tls = Some(report);
+        Ok(client)
+    }
+
+    /// Upgrades the session to TLS with STARTTLS, after EHLO, and identifies the client
+    /// again with EHLO over the encrypted connection.
+    ///
+    /// `domain` is the domain the certificate of the server must match, `hello` is the
+    /// domain given in EHLO. Returns whether the session is now encrypted. When the
+    /// server doesn't offer STARTTLS or refuses it, the session goes on in plain text
+    /// with the opportunistic policy, and fails with the required policies. Nothing is
+    /// sent with the disabled policy.
+    ///
+    /// The certificate is only checked with `TlsPolicy::Required`, the other policies
+    /// take any certificate and record how it was verified, see `tls_level`. A failed
+    /// handshake leaves the connection unusable, so it fails with every policy.
+    /// Opportunistic clients usually reconnect and deliver in plain text.
+    ///
+    /// Sessions that are already encrypted, ie over a TLS transport given to `new`, send
+    /// nothing and return `true`.
+    pub fn starttls(&mut self, config: &TlsConfig, domain: &str, hello: &str, policy: TlsPolicy) -> IoResult<bool> {
+        if self.tls.is_some() || self.output.get_ref().is_tls() {
+            return Ok(true);
+        }
+        if policy == TlsPolicy::Disabled {
+            return Ok(false);
+        }
+        let refusal = match self.has_extension("STARTTLS") {
+            true => {
+                let reply = try!(self.command("STARTTLS"));
+                match reply.code() {
+                    220 => None,
+                    code => Some(format!("STARTTLS refused with {}", code))
+                }
+            },
+            false => Some("STARTTLS not offered".to_owned())
+        };
+        if let Some(refusal) = refusal {
+            return match policy >= TlsPolicy::RequiredUnverified {
+                true => Err(IoError::new(ErrorKind::Other, refusal)),
+                false => Ok(false)
+            };
+        }
+
+        let (stream, socket, stats) = match *self.output.get_ref() {
+            Transport::Tcp(ref stream) => (try!(stream.try_clone()), try!(stream.try_clone()), stream.stats().clone()),
+            Transport::Tls(..) => return Ok(true)
+        };
+        let (tls, report) = match policy {
+            TlsPolicy::Required => try!(config.connect(domain, stream)),
+            _ => try!(config.handshake(domain, stream))
+        };
+        let tls = Arc::new(Mutex::new(InstrumentedStream::with_stats(tls, stats)));
+        let socket = Arc::new(socket);
+        self.input.replace_stream(Transport::Tls(tls.clone(), socket.clone()));
+        self.output.replace_stream(Transport::Tls(tls, socket));
+        self.tls = Some(report);
+
+        // What the server advertised in plain text can't be trusted.
+        let reply = try!(self.hello(hello));
+        match reply.code() {
+            250 => Ok(true),
+            code => Err(IoError::new(ErrorKind::Other, format!("EHLO refused with {} over TLS", code)))
+        }
+    }
+}
+
+impl SmtpClient<UnixStream> {
+    /// Connects to a server listening on a Unix socket, ie a local delivery agent or
+    /// filter, and reads its banner.
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> IoResult<SmtpClient<UnixStream>> {
+        let stream = try!(UnixStream::connect(path));
+        SmtpClient::new(try!(stream.try_clone()), stream)
+    }
+}
+
+impl<S: Read + Write> SmtpClient<S> {
+    /// Starts a session on a connection that is already open, and reads the banner.
+    ///
+    /// `input` and `output` are usually two handles to the same connection.
+    pub fn new(input: S, output: S) -> IoResult<SmtpClient<S>> {
+        let mut input = InputStream::new(input, MIN_ALLOWED_LINE_SIZE, false);
+        let banner = try!(read_reply(&mut input));
+        Ok(SmtpClient {
+            input: input,
+            output: OutputStream::new(output, false),
+            banner: banner,
+            extensions: Vec::new(),
+            tls: None,
+            size_estimator: Box::new(ExactSize),
+            max_reply_lines: MAX_REPLY_LINES,
+            max_reply_line_len: MAX_REPLY_LINE_LEN,
+            smtputf8: false,
+            dkim: None,
+            sender_domain: None
+        })
+    }
+
+    /// Sets how many lines, and how long, replies may be, see `read_reply_limited`.
+    ///
+    /// By default, replies are limited to `MAX_REPLY_LINES` lines of `MAX_REPLY_LINE_LEN`
+    /// octets. Longer lines are refused anyway, the input buffer can't take them.
+    pub fn set_reply_limits(&mut self, max_lines: usize, max_line_len: usize) {
+        self.max_reply_lines = max_lines;
+        self.max_reply_line_len = max_line_len;
+    }
+
+    /// Sets how `mail_with_size` estimates the size of messages, `ExactSize` by default.
+    pub fn set_size_estimator<E: SizeEstimator + 'static>(&mut self, estimator: E) {
+        self.size_estimator = Box::new(estimator);
+    }
+
+    /// Signs the messages given to `data` and `send_message` with the key of the domain
+    /// of the sender given to MAIL, see `client::dkim`.
+    ///
+    /// Messages from other domains, and messages sent with `bdat`, are sent as they are.
+    /// `mail_with_size` estimates the size before signing, so give the size of the
+    /// signature to the estimator, see `size::WithHeaders`.
+    pub fn set_dkim_keys(&mut self, keys: DkimKeys) {
+        self.dkim = Some(keys);
+    }
+
+    /// Returns the TLS session, `None` if the session isn't encrypted.
+    pub fn tls_report(&self) -> Option<&TlsReport> {
+        self.tls.as_ref()
+    }
+
+    /// Returns how secure the session actually is.
+    pub fn tls_level(&self) -> TlsLevel {
+        TlsLevel::of(self.tls.as_ref())
+    }
+
+    /// Returns what happened so far in the session, for auditing.
+    pub fn delivery_report(&self) -> DeliveryReport {
+        DeliveryReport {
+            tls: self.tls.clone()
+        }
+    }
+
+    /// Returns the banner of the server, which is `220` when it accepts the session.
+    pub fn banner(&self) -> &Reply {
+        &self.banner
+    }
+
+    fn read_reply(&mut self) -> IoResult<Reply> {
+        read_reply_limited(&mut self.input, self.max_reply_lines, self.max_reply_line_len)
+    }
+
+    /// Sends a command line, without `<CRLF>`, and returns the reply.
+    pub fn command(&mut self, line: &str) -> IoResult<Reply> {
+        try!(self.output.write_line(line));
+        try!(self.output.flush());
+        self.read_reply()
+    }
+
+    /// Identifies the client with EHLO, or HELO if the server doesn't know EHLO.
+    ///
+    /// The extensions advertised in the reply to EHLO are kept, see `extensions`. A reply
+    /// with too many extensions fails with `ReplyLimitExceeded`, see `set_reply_limits`.
+    pub fn hello(&mut self, domain: &str) -> IoResult<Reply> {
+        self.extensions.clear();
+        let reply = try!(self.command(format!("EHLO {}", domain).as_ref()));
+        match reply.code() {
+            250 => {
+                self.extensions = reply.lines().iter().skip(1).map(|line| line.to_owned()).collect();
+                Ok(reply)
+            },
+            // Servers that only know RFC 821 reject EHLO as a syntax error.
+            500 ... 504 => self.command(format!("HELO {}", domain).as_ref()),
+            _ => Ok(reply)
+        }
+    }
+
+    /// Returns the extensions the server advertised in its reply to EHLO, as given, ie
+    /// `SIZE 10485760`.
+    pub fn extensions(&self) -> &[String] {
+        self.extensions.as_ref()
+    }
+
+    /// Returns the parameters of an extension, ie `10485760` for `SIZE 10485760`, or
+    /// `None` if the server doesn't advertise it.
+    ///
+    /// Keywords are compared regardless of case.
+    pub fn extension(&self, keyword: &str) -> Option<&str> {
+        self.extensions.iter().filter_map(|extension| {
+            let mut parts = extension.splitn(2, ' ');
+            match parts.next().unwrap_or("").eq_ignore_ascii_case(keyword) {
+                true => Some(parts.next().unwrap_or("")),
+                false => None
+            }
+        }).next()
+    }
+
+    /// Tells whether the server advertises the extension.
+    pub fn has_extension(&self, keyword: &str) -> bool {
+        self.extension(keyword).is_some()
+    }
+
+    /// Returns the SASL mechanisms the server advertises with AUTH and the client
+    /// supports, the strongest first.
+    ///
+    /// CRAM-MD5 doesn't send the password, PLAIN and LOGIN should only be used over TLS.
+    pub fn auth_mechanisms(&self) -> Vec<AuthMechanism> {
+        let offered: Vec<&str> = match self.extension("AUTH") {
+            Some(mechanisms) => mechanisms.split(' ').collect(),
+            None => Vec::new()
+        };
+        [AuthMechanism::CramMd5, AuthMechanism::Plain, AuthMechanism::Login].iter().filter(|mechanism| {
+            offered.iter().any(|offered| offered.eq_ignore_ascii_case(mechanism.name()))
+        }).cloned().collect()
+    }
+
+    /// Authenticates with the strongest mechanism the server offers, see
+    /// `auth_mechanisms`, and returns the mechanism.
+    pub fn authenticate(&mut self, username: &str, password: &str) -> Result<AuthMechanism, AuthError> {
+        let mechanism = match self.auth_mechanisms().first() {
+            Some(mechanism) => *mechanism,
+            None => return Err(AuthError::NoMechanism)
+        };
+        try!(self.authenticate_with(mechanism, username, password));
+        Ok(mechanism)
+    }
+
+    /// Authenticates with the given mechanism, whether the server offers it or not, and
+    /// returns the final reply, `235` on success.
+    pub fn authenticate_with(&mut self, mechanism: AuthMechanism, username: &str, password: &str) -> Result<Reply, AuthError> {
+        let reply = match mechanism {
+            AuthMechanism::Plain => {
+                let response = format!("\0{}\0{}", username, password);
+                let line = format!("AUTH PLAIN {}", base64::encode(response.as_bytes()));
+                try!(self.command(line.as_ref()).map_err(AuthError::Io))
+            },
+            AuthMechanism::Login => {
+                let reply = try!(self.command("AUTH LOGIN").map_err(AuthError::Io));
+                let reply = try!(self.respond(reply, username.as_bytes()));
+                try!(self.respond(reply, password.as_bytes()))
+            },
+            AuthMechanism::CramMd5 => {
+                let reply = try!(self.command("AUTH CRAM-MD5").map_err(AuthError::Io));
+                let challenge = match reply.code() {
+                    334 => base64::decode(reply.lines().first().map_or("", |line| line.as_ref())),
+                    _ => return Err(AuthError::Rejected(reply))
+                };
+                let challenge = match challenge {
+                    Ok(challenge) => challenge,
+                    Err(_) => {
+                        // Cancel the exchange, the server then fails it.
+                        let reply = try!(self.command("*").map_err(AuthError::Io));
+                        return Err(AuthError::Rejected(reply));
+                    }
+                };
+                let digest = md5::to_hex(&md5::hmac(password.as_bytes(), challenge.as_ref()));
+                let response = format!("{} {}", username, digest);
+                try!(self.respond(reply, response.as_bytes()))
+            }
+        };
+        match reply.code() {
+            235 => Ok(reply),
+            _ => Err(AuthError::Rejected(reply))
+        }
+    }
+
+    // Sends a response to a `334` challenge, and returns the next reply.
+    fn respond(&mut self, reply: Reply, response: &[u8]) -> Result<Reply, AuthError> {
+        match reply.code() {
+            334 => self.command(base64::encode(response).as_ref()).map_err(AuthError::Io),
+            _ => Err(AuthError::Rejected(reply))
+        }
+    }
+
+    /// Returns `SMTPUTF8` if one of the addresses has UTF-8 in it and the server advertises
+    /// the extension, see `client::session`.
+    ///
+    /// Give it to `mail` with the recipients, so their U-label domains are sent as they
+    /// are.
+    pub fn smtputf8_parameter(&self, addresses: &[&Mailbox]) -> Option<&'static str> {
+        match self.has_extension("SMTPUTF8") && addresses.iter().any(|address| !address.to_wire_string().is_ascii()) {
+            true => Some("SMTPUTF8"),
+            false => None
+        }
+    }
+
+    // Returns an address as it can be sent to the server.
+    fn wire_address(&self, mailbox: &Mailbox, smtputf8: bool) -> IoResult<String> {
+        let address = mailbox.to_wire_string();
+        if smtputf8 || address.is_ascii() {
+            return Ok(address);
+        }
+        if !mailbox.local_part().is_ascii() {
+            return Err(IoError::new(ErrorKind::InvalidInput, AddressError::Utf8LocalPart));
+        }
+        match mailbox.to_ascii_domain() {
+            Ok(mailbox) => Ok(mailbox.to_wire_string()),
+            Err(_) => Err(IoError::new(ErrorKind::InvalidInput, AddressError::InvalidDomain))
+        }
+    }
+
+    /// Starts a transaction with MAIL. `None` gives the null sender `<>`.
+    ///
+    /// `parameters` are added after the address, ie `BODY=8BITMIME`. `SMTPUTF8` is added
+    /// for a UTF-8 sender when the server advertises it, see `client::session`.
+    pub fn mail(&mut self, sender: Option<&Mailbox>, parameters: &[&str]) -> IoResult<Reply> {
+        let mut parameters = parameters.to_vec();
+        let mut smtputf8 = parameters.iter().any(|parameter| parameter.eq_ignore_ascii_case("SMTPUTF8"));
+        if let (false, Some(parameter)) = (smtputf8, sender.and_then(|sender| self.smtputf8_parameter(&[sender]))) {
+            parameters.push(parameter);
+            smtputf8 = true;
+        }
+        let path = match sender {
+            Some(sender) => try!(self.wire_address(sender, smtputf8)),
+            None => String::new()
+        };
+        let line = with_parameters(format!("MAIL FROM:<{}>", path), parameters.as_ref());
+        self.smtputf8 = smtputf8;
+        self.sender_domain = sender.and_then(|sender| match *sender.foreign_part() {
+            MailboxForeignPart::Domain(ref domain) => Some(domain.clone()),
+            MailboxForeignPart::IpAddr(_) => None
+        });
+        self.command(line.as_ref())
+    }
+
+    /// Starts a transaction for the message with MAIL, giving its estimated size with the
+    /// SIZE parameter if the server advertises the extension.
+    ///
+    /// Fails without sending anything if the message is larger than the limit the server
+    /// advertises.
+    pub fn mail_with_size(&mut self, sender: Option<&Mailbox>, parameters: &[&str], message: &[u8]) -> Result<Reply, SizeError> {
+        let size = self.size_estimator.estimate(message);
+        let limit = match self.extension("SIZE") {
+            Some(limit) => Some(limit.parse::<usize>().unwrap_or(0)),
+            None => None
+        };
+        let parameter = match limit {
+            // A limit of `0`, or none at all, means that there is no limit.
+            Some(limit) if limit > 0 && size > limit => return Err(SizeError::TooLarge(size, limit)),
+            Some(_) => Some(format!("SIZE={}", size)),
+            None => None
+        };
+        let mut parameters = parameters.to_vec();
+        if let Some(ref parameter) = parameter {
+            parameters.push(parameter.as_ref());
+        }
+        self.mail(sender, parameters.as_ref()).map_err(SizeError::Io)
+    }
+
+    /// Adds a recipient to the transaction with RCPT.
+    ///
+    /// `parameters` are added after the address, ie `NOTIFY=NEVER`. A U-label domain is
+    /// converted to A-labels unless the transaction was started with `SMTPUTF8`.
+    pub fn rcpt(&mut self, recipient: &Mailbox, parameters: &[&str]) -> IoResult<Reply> {
+        let path = try!(self.wire_address(recipient, self.smtputf8));
+        let line = with_parameters(format!("RCPT TO:<{}>", path), parameters);
+        self.command(line.as_ref())
+    }
+
+    /// Sends the message with DATA, and returns the reply to the message.
+    ///
+    /// The message is dot-stuffed, so it is sent as is, but for the DKIM signature, see
+    /// `set_dkim_keys`. If the server rejects DATA, its reply is returned and the message
+    /// isn't sent.
+    pub fn data(&mut self, message: &[u8]) -> IoResult<Reply> {
+        match try!(self.dkim_sign(message)) {
+            Some(signed) => self.send_data(signed.as_ref()),
+            None => self.send_data(message)
+        }
+    }
+
+    // Returns the message signed for the domain of the sender, if there is a key for it.
+    fn dkim_sign(&self, message: &[u8]) -> IoResult<Option<Vec<u8>>> {
+        let key = match (self.dkim.as_ref(), self.sender_domain.as_ref()) {
+            (Some(keys), Some(domain)) => keys.get(domain.as_ref()),
+            _ => None
+        };
+        match key {
+            Some(key) => key.sign(message).map(Some).map_err(|err| IoError::new(ErrorKind::InvalidInput, err)),
+            None => Ok(None)
+        }
+    }
+
+    // Sends the message with DATA, as it is.
+    fn send_data(&mut self, message: &[u8]) -> IoResult<Reply> {
+        let reply = try!(self.command("DATA"));
+        if reply.code() != 354 {
+            return Ok(reply);
+        }
+        try!(self.output.write_bytes(dot_stuff(message).as_ref()));
+        try!(self.output.flush());
+        self.read_reply()
+    }
+
+    /// Sends the message with BDAT, in chunks of at most `chunk_size` octets, as described
+    /// [in RFC 3030](http://tools.ietf.org/html/rfc3030). The server must advertise
+    /// `CHUNKING`.
+    ///
+    /// `length` octets are read from `message` and sent as is, without dot-stuffing, so
+    /// large messages can be streamed. Returns the reply to the last chunk, or to the first
+    /// chunk the server refuses.
+    pub fn bdat<R: Read>(&mut self, message: &mut R, length: usize, chunk_size: usize) -> IoResult<Reply> {
+        let chunk_size = if chunk_size == 0 { DEFAULT_CHUNK_SIZE } else { chunk_size };
+        let mut chunk = Vec::with_capacity(cmp::min(length, chunk_size));
+        let mut remaining = length;
+        loop {
+            let size = cmp::min(remaining, chunk_size);
+            remaining -= size;
+            chunk.resize(size, 0);
+            try!(message.read_exact(chunk.as_mut()));
+            let line = match remaining {
+                0 => format!("BDAT {} LAST", size),
+                _ => format!("BDAT {}", size)
+            };
+            try!(self.output.write_line(line.as_ref()));
+            try!(self.output.write_bytes(chunk.as_ref()));
+            try!(self.output.flush());
+            let reply = try!(self.read_reply());
+            if remaining == 0 || reply.code() != 250 {
+                return Ok(reply);
+            }
+        }
+    }
+
+    /// Sends the message with BDAT if the server advertises `CHUNKING`, and with DATA
+    /// otherwise. The message is signed first, see `set_dkim_keys`.
+    pub fn send_message(&mut self, message: &[u8]) -> IoResult<Reply> {
+        let signed = try!(self.dkim_sign(message));
+        let message = signed.as_ref().map_or(message, |signed| &signed[..]);
+        match self.has_extension("CHUNKING") {
+            true => self.bdat(&mut &message[..], message.len(), DEFAULT_CHUNK_SIZE),
+            false => self.send_data(message)
+        }
+    }
+
+    /// Aborts the transaction with RSET.
+    pub fn rset(&mut self) -> IoResult<Reply> {
+        self.command("RSET")
+    }
+
+    /// Ends the session with QUIT.
+    ///
+    /// The connection is closed when the client is dropped.
+    pub fn quit(mut self) -> IoResult<Reply> {
+        self.command("QUIT")
+    }
+}
+
+// Adds the parameters to a MAIL or RCPT command line.
+fn with_parameters(mut line: String, parameters: &[&str]) -> String {
+    for parameter in parameters.iter() {
+        line.push(' ');
+        line.push_str(parameter);
+    }
+    line
+}
+
+/// Returns the message as sent after DATA, with a `.` added to lines starting with one,
+/// and the final `<CRLF>.<CRLF>`, as described
+/// [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.5.2).
+///
+/// A message that doesn't end with `<CRLF>` gets one.
+pub fn dot_stuff(message: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(message.len() + 5);
+    let mut line_start = true;
+    for (i, &byte) in message.iter().enumerate() {
+        if line_start && byte == b'.' {
+            stuffed.push(b'.');
+        }
+        stuffed.push(byte);
+        line_start = byte == b'\n' && i > 0 && message[i - 1] == b'\r';
+    }
+    if message.len() > 0 && !message.ends_with(b"\r\n") {
+        stuffed.extend(b"\r\n".iter().cloned());
+    }
+    stuffed.extend(b".\r\n".iter().cloned());
+    stuffed
+}
+
+#[test]
+fn test_dot_stuff() {
+    assert_eq!(b".\r\n".to_vec(), dot_stuff(b""));
+    assert_eq!(b"Hi\r\n.\r\n".to_vec(), dot_stuff(b"Hi"));
+    assert_eq!(b"Hi\r\n.\r\n".to_vec(), dot_stuff(b"Hi\r\n"));
+    assert_eq!(b"..Hi\r\n...\r\na.b\r\n..\r\n.\r\n".to_vec(), dot_stuff(b".Hi\r\n..\r\na.b\r\n.\r\n"));
+    assert_eq!(b"a\n.b\r\n.\r\n".to_vec(), dot_stuff(b"a\n.b"));
+}
+
+#[test]
+fn test_with_parameters() {
+    assert_eq!("MAIL FROM:<>", with_parameters("MAIL FROM:<>".to_owned(), &[]));
+    assert_eq!("MAIL FROM:<> BODY=8BITMIME SIZE=10", with_parameters("MAIL FROM:<>".to_owned(), &["BODY=8BITMIME", "SIZE=10"]));
+}
+
+#[test]
+fn test_session() {
+    let mut script = Script::new();
+    let lines = vec!["rustastic.test".to_owned(), "SIZE 1000".to_owned(), "8bitmime".to_owned()];
+    script.on("EHLO", Step::Reply(Reply::multiline(250, None, lines)));
+    script.on("RCPT", Step::Reply(Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 1), "No such user")))
+        .on("RCPT", Step::Reply(Reply::enhanced(250, EnhancedStatusCode::new(2, 1, 5), "OK")));
+    let server = MockServer::start(&script);
+
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    assert_eq!(220, client.banner().code());
+    assert_eq!(250, client.hello("rustastic.org").unwrap().code());
+    assert_eq!(vec!["SIZE 1000".to_owned(), "8bitmime".to_owned()], client.extensions().to_vec());
+    assert_eq!(Some("1000"), client.extension("size"));
+    assert!(client.has_extension("8BITMIME"));
+    assert!(!client.has_extension("SMTPUTF8"));
+
+    let sender = Mailbox::parse("rust@rustastic.org").unwrap();
+    let recipient = Mailbox::parse("smtp@rustastic.org").unwrap();
+    assert_eq!(250, client.mail(Some(&sender), &["BODY=8BITMIME"]).unwrap().code());
+    assert_eq!(550, client.rcpt(&recipient, &[]).unwrap().code());
+    assert_eq!(250, client.rcpt(&recipient, &[]).unwrap().code());
+    assert_eq!(250, client.data(b"Subject: Hi\r\n\r\n.Hello").unwrap().code());
+    assert_eq!(250, client.rset().unwrap().code());
+    assert_eq!(250, client.mail(None, &[]).unwrap().code());
+    assert_eq!(221, client.quit().unwrap().code());
+
+    assert_eq!(vec![
+        "EHLO rustastic.org".to_owned(),
+        "MAIL FROM:<rust@rustastic.org> BODY=8BITMIME".to_owned(),
+        "RCPT TO:<smtp@rustastic.org>".to_owned(),
+        "RCPT TO:<smtp@rustastic.org>".to_owned(),
+        "DATA".to_owned(),
+        "RSET".to_owned(),
+        "MAIL FROM:<>".to_owned(),
+        "QUIT".to_owned()
+    ], server.commands());
+    // The mock server doesn't remove the stuffing.
+    assert_eq!(vec![b"Subject: Hi\r\n\r\n..Hello\r\n".to_vec()], server.messages());
+}
+
+#[test]
+fn test_helo_fallback() {
+    let mut script = Script::new();
+    script.on("EHLO", Step::Reply(Reply::enhanced(502, EnhancedStatusCode::new(5, 5, 1), "Command not implemented")));
+    script.on("DATA", Step::Reply(Reply::enhanced(554, EnhancedStatusCode::new(5, 5, 1), "No valid recipients")));
+    let server = MockServer::start(&script);
+
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    assert_eq!(250, client.hello("rustastic.org").unwrap().code());
+    assert!(client.extensions().is_empty());
+    assert_eq!(554, client.data(b"Hello\r\n").unwrap().code());
+    assert_eq!(vec!["EHLO rustastic.org".to_owned(), "HELO rustastic.org".to_owned(), "DATA".to_owned()], server.commands());
+    assert!(server.messages().is_empty());
+}
+
+#[test]
+fn test_starttls() {
+    let mut script = Script::new();
+    let lines = vec!["rustastic.test".to_owned(), "STARTTLS".to_owned()];
+    script.on("EHLO", Step::Reply(Reply::multiline(250, None, lines)))
+        .on("EHLO", Step::Reply(Reply::multiline(250, None, vec!["rustastic.test".to_owned(), "SIZE 1000".to_owned()])))
+        .on("STARTTLS", Step::Reply(Reply::enhanced(220, EnhancedStatusCode::new(2, 0, 0), "Ready to start TLS")));
+    let server = MockServer::start(&script);
+    let mut config = TlsConfig::new(PlainConnector);
+    config.accept_self_signed("rustastic.test");
+
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    client.hello("rustastic.org").unwrap();
+    assert!(client.tls_report().is_none());
+    assert!(client.starttls(&config, "rustastic.test", "rustastic.org", TlsPolicy::Required).unwrap());
+    assert_eq!("rustastic.test", client.tls_report().unwrap().domain);
+    assert_eq!(vec!["SIZE 1000".to_owned()], client.extensions().to_vec());
+    assert_eq!(221, client.quit().unwrap().code());
+    assert_eq!(vec![
+        "EHLO rustastic.org".to_owned(),
+        "STARTTLS".to_owned(),
+        "EHLO rustastic.org".to_owned(),
+        "QUIT".to_owned()
+    ], server.commands());
+}
+
+#[test]
+fn test_starttls_policy() {
+    let mut script = Script::new();
+    let lines = vec!["rustastic.test".to_owned(), "STARTTLS".to_owned()];
+    script.on("EHLO", Step::Reply(Reply::new(250, "rustastic.test")))
+        .on("EHLO", Step::Reply(Reply::multiline(250, None, lines)))
+        .on("STARTTLS", Step::Reply(Reply::enhanced(454, EnhancedStatusCode::new(4, 7, 0), "TLS not available")));
+    let server = MockServer::start(&script);
+    let config = TlsConfig::new(PlainConnector);
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+
+    // Not offered.
+    client.hello("rustastic.org").unwrap();
+    assert!(!client.starttls(&config, "rustastic.test", "rustastic.org", TlsPolicy::Opportunistic).unwrap());
+    assert!(client.starttls(&config, "rustastic.test", "rustastic.org", TlsPolicy::Required).is_err());
+
+    // Refused.
+    client.hello("rustastic.org").unwrap();
+    assert!(!client.starttls(&config, "rustastic.test", "rustastic.org", TlsPolicy::Opportunistic).unwrap());
+    assert!(client.starttls(&config, "rustastic.test", "rustastic.org", TlsPolicy::Required).is_err());
+    assert!(client.tls_report().is_none());
+    assert_eq!(250, client.rset().unwrap().code());
+    assert_eq!(vec![
+        "EHLO rustastic.org".to_owned(),
+        "EHLO rustastic.org".to_owned(),
+        "STARTTLS".to_owned(),
+        "STARTTLS".to_owned(),
+        "RSET".to_owned()
+    ], server.commands());
+}
+
+#[test]
+fn test_starttls_ladder() {
+    let mut script = Script::new();
+    let lines = vec!["rustastic.test".to_owned(), "STARTTLS".to_owned()];
+    script.on("EHLO", Step::Reply(Reply::multiline(250, None, lines)))
+        .on("STARTTLS", Step::Reply(Reply::enhanced(220, EnhancedStatusCode::new(2, 0, 0), "Ready to start TLS")));
+    let server = MockServer::start(&script);
+    let mut config = TlsConfig::new(PlainConnector);
+
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    client.hello("rustastic.org").unwrap();
+    assert!(!client.starttls(&config, "rustastic.test", "rustastic.org", TlsPolicy::Disabled).unwrap());
+    assert_eq!(TlsLevel::Plain, client.tls_level());
+    // The certificate isn't trusted.
+    assert!(client.starttls(&config, "rustastic.test", "rustastic.org", TlsPolicy::Required).is_err());
+
+    for policy in [TlsPolicy::Opportunistic, TlsPolicy::RequiredUnverified].iter() {
+        let mut client = SmtpClient::connect(server.addr()).unwrap();
+        client.hello("rustastic.org").unwrap();
+        assert!(client.starttls(&config, "rustastic.test", "rustastic.org", *policy).unwrap());
+        assert_eq!(TlsLevel::Unverified, client.tls_level());
+        assert_eq!(TlsLevel::Unverified, client.delivery_report().tls_level());
+    }
+
+    config.accept_self_signed("rustastic.test");
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    client.hello("rustastic.org").unwrap();
+    assert!(client.starttls(&config, "rustastic.test", "rustastic.org", TlsPolicy::Required).unwrap());
+    assert_eq!(TlsLevel::Verified, client.tls_level());
+}
+
+#[test]
+fn test_connect_tls() {
+    let mut script = Script::new();
+    script.on("EHLO", Step::Reply(Reply::multiline(250, None, vec!["rustastic.test".to_owned(), "STARTTLS".to_owned()])));
+    let server = MockServer::start(&script);
+    let mut config = TlsConfig::new(PlainConnector);
+
+    // The certificate isn't trusted, nothing is read nor sent.
+    assert!(SmtpClient::connect_tls(server.addr(), &config, "rustastic.test").is_err());
+
+    config.accept_self_signed("rustastic.test");
+    let mut client = SmtpClient::connect_tls(server.addr(), &config, "rustastic.test").unwrap();
+    assert_eq!(220, client.banner().code());
+    assert_eq!(TlsLevel::Verified, client.tls_level());
+    client.hello("rustastic.org").unwrap();
+    // The session is already encrypted.
+    assert!(client.starttls(&config, "rustastic.test", "rustastic.org", TlsPolicy::Required).unwrap());
+    assert_eq!(221, client.quit().unwrap().code());
+
+    // So are sessions over a TLS transport given to `new`, without a report.
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let socket = Arc::new(stream.try_clone().unwrap());
+    let (tls, _) = config.handshake("rustastic.test", stream).unwrap();
+    let tls = Arc::new(Mutex::new(InstrumentedStream::new(tls)));
+    let mut client = SmtpClient::new(Transport::Tls(tls.clone(), socket.clone()), Transport::Tls(tls, socket)).unwrap();
+    assert!(client.starttls(&config, "rustastic.test", "rustastic.org", TlsPolicy::Required).unwrap());
+    assert_eq!(221, client.quit().unwrap().code());
+    assert_eq!(vec![
+        "EHLO rustastic.org".to_owned(),
+        "QUIT".to_owned(),
+        "QUIT".to_owned()
+    ], server.commands());
+}
+
+#[test]
+fn test_auth_mechanisms() {
+    let mut script = Script::new();
+    let lines = vec!["rustastic.test".to_owned(), "AUTH LOGIN plain".to_owned()];
+    script.on("EHLO", Step::Reply(Reply::multiline(250, None, lines)))
+        .on("EHLO", Step::Reply(Reply::new(250, "rustastic.test")));
+    let server = MockServer::start(&script);
+
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    client.hello("rustastic.org").unwrap();
+    assert_eq!(vec![AuthMechanism::Plain, AuthMechanism::Login], client.auth_mechanisms());
+    client.hello("rustastic.org").unwrap();
+    assert!(client.auth_mechanisms().is_empty());
+    match client.authenticate("rust", "pass") {
+        Err(AuthError::NoMechanism) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
+
+#[test]
+fn test_authenticate() {
+    let mut script = Script::new();
+    let lines = vec!["rustastic.test".to_owned(), "AUTH PLAIN LOGIN CRAM-MD5".to_owned()];
+    let success = Step::Reply(Reply::enhanced(235, EnhancedStatusCode::new(2, 7, 0), "Authentication successful"));
+    script.on("EHLO", Step::Reply(Reply::multiline(250, None, lines)))
+        .on("AUTH", Step::Reply(Reply::new(334, "PDE4OTYuNjk3MTcwOTUyQHBvc3RvZmZpY2UucmVzdG9uLm1jaS5uZXQ+")))
+        .on("AUTH", success.clone())
+        .on("AUTH", Step::Reply(Reply::new(334, "VXNlcm5hbWU6")))
+        .on("AUTH", Step::Reply(Reply::enhanced(535, EnhancedStatusCode::new(5, 7, 8), "Authentication credentials invalid")));
+    // The responses are verbs for the mock server.
+    script.on("dGltIGI5MTNhNjAyYzdlZGE3YTQ5NWI0ZTZlNzMzNGQzODkw", success.clone())
+        .on("cnVzdA==", Step::Reply(Reply::new(334, "UGFzc3dvcmQ6")))
+        .on("cGFzcw==", success);
+    let server = MockServer::start(&script);
+
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    client.hello("rustastic.org").unwrap();
+    // From RFC 2195.
+    assert_eq!(AuthMechanism::CramMd5, client.authenticate("tim", "tanstaaftanstaaf").unwrap());
+    assert_eq!(235, client.authenticate_with(AuthMechanism::Plain, "rust", "pass").unwrap().code());
+    assert_eq!(235, client.authenticate_with(AuthMechanism::Login, "rust", "pass").unwrap().code());
+    match client.authenticate_with(AuthMechanism::Plain, "rust", "wrong") {
+        Err(AuthError::Rejected(reply)) => assert_eq!(535, reply.code()),
+        other => panic!("unexpected result: {:?}", other)
+    }
+    assert_eq!(vec![
+        "EHLO rustastic.org".to_owned(),
+        "AUTH CRAM-MD5".to_owned(),
+        "dGltIGI5MTNhNjAyYzdlZGE3YTQ5NWI0ZTZlNzMzNGQzODkw".to_owned(),
+        "AUTH PLAIN AHJ1c3QAcGFzcw==".to_owned(),
+        "AUTH LOGIN".to_owned(),
+        "cnVzdA==".to_owned(),
+        "cGFzcw==".to_owned(),
+        "AUTH PLAIN AHJ1c3QAd3Jvbmc=".to_owned()
+    ], server.commands());
+}
+
+#[test]
+fn test_bdat() {
+    let mut script = Script::new();
+    let lines = vec!["rustastic.test".to_owned(), "CHUNKING".to_owned()];
+    script.on("EHLO", Step::Reply(Reply::multiline(250, None, lines)))
+        .on("BDAT", Step::Reply(Reply::enhanced(250, EnhancedStatusCode::new(2, 0, 0), "OK")))
+        .on("BDAT", Step::Reply(Reply::enhanced(250, EnhancedStatusCode::new(2, 0, 0), "OK")))
+        .on("BDAT", Step::Reply(Reply::enhanced(250, EnhancedStatusCode::new(2, 0, 0), "OK")))
+        .on("BDAT", Step::Reply(Reply::enhanced(250, EnhancedStatusCode::new(2, 0, 0), "OK")))
+        .on("BDAT", Step::Reply(Reply::enhanced(552, EnhancedStatusCode::new(5, 3, 4), "Message too big")));
+    let server = MockServer::start(&script);
+
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    client.hello("rustastic.org").unwrap();
+    // Lines with a single dot are sent as is.
+    assert_eq!(250, client.bdat(&mut &b"Hello\r\n.\r\n"[..], 10, 4).unwrap().code());
+    assert_eq!(250, client.send_message(b"").unwrap().code());
+    assert_eq!(552, client.bdat(&mut &b"Hello, world"[..], 12, 4).unwrap().code());
+    // The message is shorter than announced.
+    assert!(client.bdat(&mut &b"Hello"[..], 10, 20).is_err());
+    assert_eq!(vec![
+        "EHLO rustastic.org".to_owned(),
+        "BDAT 4".to_owned(),
+        "BDAT 4".to_owned(),
+        "BDAT 2 LAST".to_owned(),
+        "BDAT 0 LAST".to_owned(),
+        "BDAT 4".to_owned()
+    ], server.commands());
+    assert_eq!(vec![b"Hello\r\n.\r\n".to_vec(), b"".to_vec()], server.messages());
+}
+
+#[test]
+fn test_mail_with_size() {
+    let mut script = Script::new();
+    let lines = vec!["rustastic.test".to_owned(), "SIZE 10".to_owned()];
+    script.on("EHLO", Step::Reply(Reply::multiline(250, None, lines)))
+        .on("EHLO", Step::Reply(Reply::new(250, "rustastic.test")));
+    let server = MockServer::start(&script);
+    let sender = Mailbox::parse("rust@rustastic.org").unwrap();
+
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    client.hello("rustastic.org").unwrap();
+    assert_eq!(250, client.mail_with_size(Some(&sender), &["BODY=8BITMIME"], b"Hello").unwrap().code());
+    match client.mail_with_size(Some(&sender), &[], b"Hello, world") {
+        Err(SizeError::TooLarge(14, 10)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+    client.set_size_estimator(WireSize);
+    match client.mail_with_size(Some(&sender), &[], b"Hello!") {
+        Err(SizeError::TooLarge(11, 10)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+    // Without the extension, there is no parameter.
+    client.hello("rustastic.org").unwrap();
+    assert_eq!(250, client.mail_with_size(None, &[], b"Hello, world").unwrap().code());
+    assert_eq!(vec![
+        "EHLO rustastic.org".to_owned(),
+        "MAIL FROM:<rust@rustastic.org> BODY=8BITMIME SIZE=7".to_owned(),
+        "EHLO rustastic.org".to_owned(),
+        "MAIL FROM:<>".to_owned()
+    ], server.commands());
+}
+
+#[test]
+fn test_reply_limits() {
+    let mut script = Script::new();
+    let lines: Vec<String> = (0 .. 150).map(|i| format!("X-EXTENSION-{}", i)).collect();
+    script.on("EHLO", Step::Reply(Reply::multiline(250, None, lines)));
+    let server = MockServer::start(&script);
+
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    client.set_reply_limits(200, 100);
+    assert_eq!(149, client.hello("rustastic.org").unwrap().lines().len() - 1);
+    drop(client);
+
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    let err = client.hello("rustastic.org").unwrap_err();
+    assert_eq!(Some(&ReplyLimitExceeded::TooManyLines(100)), err.get_ref().and_then(|err| err.downcast_ref()));
+    assert!(client.extensions().is_empty());
+}
+
+#[test]
+fn test_international_addresses() {
+    let sender = Mailbox::parse("rust@rustastic.org").unwrap();
+    let international = Mailbox::parse_utf8("rust@bücher.example").unwrap();
+    let utf8_local = Mailbox::parse_utf8("josé@bücher.example").unwrap();
+
+    // Legacy servers get A-labels.
+    let server = MockServer::start(&Script::new());
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    client.hello("rustastic.org").unwrap();
+    assert_eq!(None, client.smtputf8_parameter(&[&international]));
+    client.mail(Some(&international), &[]).unwrap();
+    client.rcpt(&international, &[]).unwrap();
+    let err = client.rcpt(&utf8_local, &[]).unwrap_err();
+    assert_eq!(ErrorKind::InvalidInput, err.kind());
+    assert_eq!(Some(&AddressError::Utf8LocalPart), err.get_ref().and_then(|err| err.downcast_ref()));
+    assert_eq!(vec![
+        "EHLO rustastic.org".to_owned(),
+        "MAIL FROM:<rust@xn--bcher-kva.example>".to_owned(),
+        "RCPT TO:<rust@xn--bcher-kva.example>".to_owned()
+    ], server.commands());
+
+    // Servers advertising SMTPUTF8 get U-labels, once the transaction asks for them.
+    let mut script = Script::new();
+    let lines = vec!["rustastic.test".to_owned(), "SMTPUTF8".to_owned()];
+    script.on("EHLO", Step::Reply(Reply::multiline(250, None, lines)));
+    let server = MockServer::start(&script);
+    let mut client = SmtpClient::connect(server.addr()).unwrap();
+    client.hello("rustastic.org").unwrap();
+    client.mail(Some(&utf8_local), &[]).unwrap();
+    client.rcpt(&international, &[]).unwrap();
+    client.mail(Some(&sender), &[]).unwrap();
+    client.rcpt(&international, &[]).unwrap();
+    assert_eq!(None, client.smtputf8_parameter(&[&sender]));
+    let parameter = client.smtputf8_parameter(&[&sender, &international]).unwrap();
+    client.mail(Some(&sender), &[parameter]).unwrap();
+    client.rcpt(&international, &[]).unwrap();
+    assert_eq!(vec![
+        "EHLO rustastic.org".to_owned(),
+        "MAIL FROM:<josé@bücher.example> SMTPUTF8".to_owned(),
+        "RCPT TO:<rust@bücher.example>".to_owned(),
+        "MAIL FROM:<rust@rustastic.org>".to_owned(),
+        "RCPT TO:<rust@xn--bcher-kva.example>".to_owned(),
+        "MAIL FROM:<rust@rustastic.org> SMTPUTF8".to_owned(),
+        "RCPT TO:<rust@bücher.example>".to_owned()
+    ], server.commands());
+}
@@ -0,0 +1,108 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The size of a message given with the SIZE parameter of MAIL, as described
+//! [in RFC 1870](http://tools.ietf.org/html/rfc1870).
+//!
+//! The size must not be lower than what the server will receive, so a client that adds
+//! headers after MAIL, ie a trace or a signature, must count them too. Servers use it to
+//! refuse messages they can't take before they are sent, see
+//! `SmtpClient::mail_with_size`.
+
+use std::io::Error as IoError;
+#[cfg(test)]
+use super::session::dot_stuff;
+
+/// Represents an error that occured while starting a transaction for a message.
+#[derive(Debug)]
+pub enum SizeError {
+    /// The connection failed, or the server doesn't speak SMTP.
+    Io(IoError),
+    /// The message is larger than the server takes. Gives the estimated size of the
+    /// message and the limit of the server.
+    TooLarge(usize, usize)
+}
+
+/// Estimates the size of messages.
+pub trait SizeEstimator: Send + Sync {
+    /// Returns the size of the message, in octets, never lower than the actual size.
+    fn estimate(&self, message: &[u8]) -> usize;
+}
+
+/// The exact size of the message as the server stores it, the default.
+///
+/// As required by RFC 1870, it includes the `<CRLF>` added to a message that doesn't
+/// end with one, but not the dots added by dot-stuffing nor the final `.`.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct ExactSize;
+
+impl SizeEstimator for ExactSize {
+    fn estimate(&self, message: &[u8]) -> usize {
+        match message.len() > 0 && !message.ends_with(b"\r\n") {
+            true => message.len() + 2,
+            false => message.len()
+        }
+    }
+}
+
+/// The size of the message on the wire after DATA, ie with dot-stuffing and the final
+/// `<CRLF>.<CRLF>`, which is a little more than the exact size.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct WireSize;
+
+impl SizeEstimator for WireSize {
+    fn estimate(&self, message: &[u8]) -> usize {
+        let mut size = ExactSize.estimate(message) + 3;
+        let mut line_start = true;
+        for (i, &byte) in message.iter().enumerate() {
+            if line_start && byte == b'.' {
+                size += 1;
+            }
+            line_start = byte == b'\n' && i > 0 && message[i - 1] == b'\r';
+        }
+        size
+    }
+}
+
+/// Adds a number of octets to the estimate of another estimator, for the headers added
+/// to the message after MAIL.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct WithHeaders<E> {
+    /// The estimator of the message without the headers.
+    pub estimator: E,
+    /// The size of the headers, including their `<CRLF>`.
+    pub headers: usize
+}
+
+impl<E: SizeEstimator> SizeEstimator for WithHeaders<E> {
+    fn estimate(&self, message: &[u8]) -> usize {
+        self.estimator.estimate(message) + self.headers
+    }
+}
+
+#[test]
+fn test_exact_size() {
+    assert_eq!(0, ExactSize.estimate(b""));
+    assert_eq!(4, ExactSize.estimate(b"Hi\r\n"));
+    assert_eq!(4, ExactSize.estimate(b"Hi"));
+    assert_eq!(8, ExactSize.estimate(b".Hi\r\n.\r\n"));
+}
+
+#[test]
+fn test_wire_size() {
+    for message in [&b""[..], b"Hi", b".Hi\r\n..\r\nHo", b"Hi\n.Ho\r\n", b"\r\n.\r\n"].iter() {
+        assert_eq!(dot_stuff(message).len(), WireSize.estimate(message));
+    }
+    assert_eq!(WireSize.estimate(b"Hi") + 50, WithHeaders { estimator: WireSize, headers: 50 }.estimate(b"Hi"));
+}
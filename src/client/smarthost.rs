@@ -0,0 +1,377 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Delivery through a pool of smarthosts, with weighted load balancing and failover.
+//!
+//! Hosts that fail too many times in a row are ejected for a while. Once the ejection
+//! time is over, they get their traffic back gradually, and are ejected again if they keep
+//! failing.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut pool = SmarthostPool::new();
+//! pool.add_host("relay1.example.com:25", 3);
+//! pool.add_host("relay2.example.com:25", 1);
+//! pool.set_probe(send_noop, Duration::from_secs(30));
+//!
+//! if let Some(host) = pool.select(Instant::now()) {
+//!     match deliver(&host, &message) {
+//!         Ok(_) => pool.report_success(&host),
+//!         Err(_) => pool.report_failure(&host, Instant::now())
+//!     }
+//! }
+//! ```
+
+use std::borrow::ToOwned;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+use super::super::server::metrics::Metrics;
+
+/// Checks the health of a host, given its address, ie by connecting to it and sending
+/// NOOP. Returns `true` if the host is healthy.
+pub type ProbeFn = fn(&str) -> bool;
+
+/// The number of successes it takes a host to get its full weight back after an ejection.
+pub static RECOVERY_STEPS: u32 = 4;
+
+/// What happened to a host since it was added to the pool.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct HostStats {
+    /// How many times the host was selected.
+    pub selected: u64,
+    /// How many deliveries or probes succeeded.
+    pub successes: u64,
+    /// How many deliveries or probes failed.
+    pub failures: u64,
+    /// How many times the host was ejected.
+    pub ejections: u64,
+    /// Whether the host is ejected at the time the statistics were taken.
+    pub ejected: bool
+}
+
+struct Smarthost {
+    address: String,
+    weight: u32,
+    // For smooth weighted round robin.
+    current_weight: i64,
+    consecutive_failures: u32,
+    ejected_until: Option<Instant>,
+    // Between 1 and `RECOVERY_STEPS` while recovering, `RECOVERY_STEPS` when healthy.
+    recovery: u32,
+    last_probe: Option<Instant>,
+    stats: HostStats
+}
+
+impl Smarthost {
+    // Once the ejection time is over, a host gets messages again, unless there is a probe,
+    // in which case it waits for a successful probe.
+    fn is_available(&self, now: Instant, probing: bool) -> bool {
+        match self.ejected_until {
+            Some(until) => !probing && now >= until,
+            None => true
+        }
+    }
+
+    fn effective_weight(&self) -> u32 {
+        match self.weight * self.recovery / RECOVERY_STEPS {
+            0 => 1,
+            weight => weight
+        }
+    }
+}
+
+/// A pool of smarthosts to deliver through.
+pub struct SmarthostPool {
+    hosts: Vec<Smarthost>,
+    max_failures: u32,
+    ejection_time: Duration,
+    probe: Option<(ProbeFn, Duration)>,
+    metrics: Option<Arc<Metrics>>
+}
+
+impl SmarthostPool {
+    /// Creates an empty pool.
+    ///
+    /// By default, hosts are ejected for 60 seconds after 3 failures in a row, and there
+    /// is no health checking.
+    pub fn new() -> SmarthostPool {
+        SmarthostPool {
+            hosts: Vec::new(),
+            max_failures: 3,
+            ejection_time: Duration::from_secs(60),
+            probe: None,
+            metrics: None
+        }
+    }
+
+    /// Adds a host, ie `relay.example.com:25`.
+    ///
+    /// A host with a weight of 2 gets twice as many messages as a host with a weight of 1.
+    /// A weight of 0 is treated as 1.
+    pub fn add_host(&mut self, address: &str, weight: u32) {
+        self.hosts.push(Smarthost {
+            address: address.to_owned(),
+            weight: if weight == 0 { 1 } else { weight },
+            current_weight: 0,
+            consecutive_failures: 0,
+            ejected_until: None,
+            recovery: RECOVERY_STEPS,
+            last_probe: None,
+            stats: HostStats {
+                selected: 0,
+                successes: 0,
+                failures: 0,
+                ejections: 0,
+                ejected: false
+            }
+        });
+    }
+
+    /// Sets how many failures in a row get a host ejected.
+    pub fn set_max_failures(&mut self, max_failures: u32) {
+        self.max_failures = max_failures;
+    }
+
+    /// Sets how long a host stays ejected.
+    pub fn set_ejection_time(&mut self, ejection_time: Duration) {
+        self.ejection_time = ejection_time;
+    }
+
+    /// Sets the function used to check the health of the hosts, and how often each host
+    /// is checked by `check_health`.
+    pub fn set_probe(&mut self, probe: ProbeFn, interval: Duration) {
+        self.probe = Some((probe, interval));
+    }
+
+    /// Sets where the events of each host are reported, see `Metrics::increment_host`.
+    pub fn set_metrics<M: 'static + Metrics>(&mut self, metrics: M) {
+        self.metrics = Some(Arc::new(metrics));
+    }
+
+    fn report(&self, counter: &str, index: usize) {
+        if let Some(ref metrics) = self.metrics {
+            metrics.increment_host(counter, self.hosts[index].address.as_ref());
+        }
+    }
+
+    /// Returns the address of the host to deliver the next message to, or `None` if all
+    /// hosts are ejected.
+    ///
+    /// Hosts are picked with a smooth weighted round robin, so that a host with a high
+    /// weight doesn't get all its messages in a row.
+    pub fn select(&mut self, now: Instant) -> Option<String> {
+        let mut total = 0i64;
+        let mut best: Option<usize> = None;
+        let probing = self.probe.is_some();
+        for i in 0 .. self.hosts.len() {
+            if !self.hosts[i].is_available(now, probing) {
+                continue;
+            }
+            let weight = self.hosts[i].effective_weight() as i64;
+            self.hosts[i].current_weight += weight;
+            total += weight;
+            best = match best {
+                Some(j) if self.hosts[j].current_weight >= self.hosts[i].current_weight => Some(j),
+                _ => Some(i)
+            };
+        }
+        best.map(|i| {
+            self.hosts[i].current_weight -= total;
+            self.hosts[i].stats.selected += 1;
+            self.report("smarthost_selected", i);
+            self.hosts[i].address.clone()
+        })
+    }
+
+    fn find(&self, address: &str) -> Option<usize> {
+        self.hosts.iter().position(|host| host.address == address)
+    }
+
+    fn succeed(&mut self, i: usize) {
+        let recovered = {
+            let host = &mut self.hosts[i];
+            host.stats.successes += 1;
+            host.consecutive_failures = 0;
+            host.ejected_until = None;
+            match host.recovery < RECOVERY_STEPS {
+                true => {
+                    host.recovery += 1;
+                    host.recovery == RECOVERY_STEPS
+                },
+                false => false
+            }
+        };
+        if recovered {
+            self.report("smarthost_recoveries", i);
+        }
+    }
+
+    fn fail(&mut self, i: usize, now: Instant) {
+        let ejected = {
+            let host = &mut self.hosts[i];
+            host.stats.failures += 1;
+            host.consecutive_failures += 1;
+            // A host that fails while recovering hasn't recovered, so it goes straight back.
+            match host.consecutive_failures >= self.max_failures || host.recovery < RECOVERY_STEPS {
+                true => {
+                    host.ejected_until = Some(now + self.ejection_time);
+                    host.consecutive_failures = 0;
+                    host.current_weight = 0;
+                    host.recovery = 1;
+                    host.stats.ejections += 1;
+                    true
+                },
+                false => false
+            }
+        };
+        self.report("smarthost_failures", i);
+        if ejected {
+            self.report("smarthost_ejections", i);
+        }
+    }
+
+    /// Records that a message was delivered through a host.
+    pub fn report_success(&mut self, address: &str) {
+        if let Some(i) = self.find(address) {
+            self.succeed(i);
+        }
+    }
+
+    /// Records that a message couldn't be delivered through a host, because it couldn't be
+    /// reached or replied with a temporary failure.
+    pub fn report_failure(&mut self, address: &str, now: Instant) {
+        if let Some(i) = self.find(address) {
+            self.fail(i, now);
+        }
+    }
+
+    /// Probes the hosts that haven't been checked for the interval given to `set_probe`.
+    ///
+    /// Hosts whose ejection time is over are only probed, they don't get messages until the
+    /// probe succeeds. This is meant to be called periodically, ie from a timer thread.
+    pub fn check_health(&mut self, now: Instant) {
+        let (probe, interval) = match self.probe {
+            Some(probe) => probe,
+            None => return
+        };
+        for i in 0 .. self.hosts.len() {
+            let due = match self.hosts[i].last_probe {
+                Some(last) => now.duration_since(last) >= interval,
+                None => true
+            };
+            let ejected = match self.hosts[i].ejected_until {
+                Some(until) => now < until,
+                None => false
+            };
+            if !due || ejected {
+                continue;
+            }
+            self.hosts[i].last_probe = Some(now);
+            match probe(self.hosts[i].address.as_ref()) {
+                true => self.succeed(i),
+                false => self.fail(i, now)
+            }
+        }
+    }
+
+    /// Returns the statistics of every host, by address.
+    pub fn stats(&self, now: Instant) -> Vec<(String, HostStats)> {
+        let probing = self.probe.is_some();
+        self.hosts.iter().map(|host| {
+            let mut stats = host.stats;
+            stats.ejected = !host.is_available(now, probing);
+            (host.address.clone(), stats)
+        }).collect()
+    }
+}
+
+#[test]
+fn test_select() {
+    let now = Instant::now();
+    let mut pool = SmarthostPool::new();
+    assert_eq!(None, pool.select(now));
+
+    pool.add_host("a:25", 3);
+    pool.add_host("b:25", 1);
+    let selected: Vec<String> = (0 .. 8).map(|_| pool.select(now).unwrap()).collect();
+    assert_eq!(vec!["a:25", "a:25", "b:25", "a:25", "a:25", "a:25", "b:25", "a:25"], selected);
+    assert_eq!(6, pool.stats(now)[0].1.selected);
+    assert_eq!(2, pool.stats(now)[1].1.selected);
+}
+
+#[test]
+fn test_ejection() {
+    let now = Instant::now();
+    let mut pool = SmarthostPool::new();
+    pool.add_host("a:25", 4);
+    pool.set_max_failures(2);
+    pool.set_ejection_time(Duration::from_secs(10));
+
+    pool.report_failure("a:25", now);
+    pool.report_success("a:25");
+    pool.report_failure("a:25", now);
+    assert_eq!(Some("a:25".to_owned()), pool.select(now));
+    pool.report_failure("a:25", now);
+    assert_eq!(None, pool.select(now));
+    assert!(pool.stats(now)[0].1.ejected);
+    assert_eq!(1, pool.stats(now)[0].1.ejections);
+
+    // The host comes back with a quarter of its weight, and goes straight back out if it
+    // fails again.
+    let later = now + Duration::from_secs(10);
+    pool.add_host("b:25", 1);
+    assert!(!pool.stats(later)[0].1.ejected);
+    pool.report_failure("a:25", later);
+    assert_eq!(2, pool.stats(later)[0].1.ejections);
+    assert_eq!(Some("b:25".to_owned()), pool.select(later));
+
+    // It gets its full weight back after enough successes.
+    let later = later + Duration::from_secs(10);
+    for _ in 1 .. RECOVERY_STEPS {
+        pool.report_success("a:25");
+    }
+    let selected: Vec<String> = (0 .. 5).map(|_| pool.select(later).unwrap()).collect();
+    assert_eq!(4, selected.iter().filter(|host| *host == "a:25").count());
+    assert_eq!(4, pool.stats(later)[0].1.failures);
+}
+
+#[cfg(test)]
+fn probe(address: &str) -> bool {
+    address != "down:25"
+}
+
+#[test]
+fn test_check_health() {
+    let now = Instant::now();
+    let mut pool = SmarthostPool::new();
+    pool.add_host("up:25", 1);
+    pool.add_host("down:25", 1);
+    pool.set_max_failures(1);
+    pool.set_ejection_time(Duration::from_secs(10));
+    pool.set_probe(probe, Duration::from_secs(5));
+
+    pool.check_health(now);
+    assert_eq!(Some("up:25".to_owned()), pool.select(now));
+    assert_eq!(Some("up:25".to_owned()), pool.select(now));
+
+    // Not probed again before the interval, and kept out until a probe succeeds.
+    pool.check_health(now + Duration::from_secs(1));
+    assert_eq!(1, pool.stats(now)[1].1.failures);
+    pool.check_health(now + Duration::from_secs(10));
+    assert_eq!(2, pool.stats(now)[1].1.failures);
+    assert_eq!(2, pool.stats(now)[0].1.successes);
+    assert!(pool.stats(now + Duration::from_secs(100))[1].1.ejected);
+}
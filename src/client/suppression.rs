@@ -0,0 +1,146 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Recipients that must not get mail anymore, ie because their address bounced or they
+//! complained.
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::io::Result as IoResult;
+use std::sync::Mutex;
+use std::vec::Vec;
+use super::super::common::json::{Json, ImportError, export_lines, import_lines};
+
+/// Why and since when a recipient is suppressed.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Suppression {
+    /// Why the recipient is suppressed, ie `bounce` or `complaint`.
+    pub reason: String,
+    /// When the recipient was suppressed, in seconds.
+    pub since: u64
+}
+
+/// A list of suppressed recipients, shared by all the deliveries.
+///
+/// Addresses are compared without regard for ASCII case, it is safer to suppress too
+/// much than to send to someone who complained.
+pub struct SuppressionList {
+    entries: Mutex<HashMap<String, Suppression>>
+}
+
+impl SuppressionList {
+    /// Creates an empty list.
+    pub fn new() -> SuppressionList {
+        SuppressionList {
+            entries: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Suppresses an address, ie `rust@rustastic.org`, replacing the previous reason.
+    pub fn add(&self, address: &str, reason: &str, since: u64) {
+        self.entries.lock().unwrap().insert(address.to_ascii_lowercase(), Suppression {
+            reason: reason.to_owned(),
+            since: since
+        });
+    }
+
+    /// Stops suppressing an address, returning whether it was suppressed.
+    pub fn remove(&self, address: &str) -> bool {
+        self.entries.lock().unwrap().remove(&address.to_ascii_lowercase()).is_some()
+    }
+
+    /// Returns why an address is suppressed, if it is.
+    pub fn get(&self, address: &str) -> Option<Suppression> {
+        self.entries.lock().unwrap().get(&address.to_ascii_lowercase()).cloned()
+    }
+
+    /// Returns the number of suppressed addresses.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Writes every address as JSON lines, sorted by address, returning the number of
+    /// addresses.
+    ///
+    /// Lines look like `{"address":"rust@rustastic.org","reason":"bounce","since":100}`.
+    pub fn export<W: Write>(&self, output: &mut W) -> IoResult<usize> {
+        let records = {
+            let entries = self.entries.lock().unwrap();
+            let mut addresses: Vec<&String> = entries.keys().collect();
+            addresses.sort();
+            addresses.iter().map(|address| {
+                let suppression = &entries[*address];
+                Json::Object(vec![
+                    ("address".to_owned(), Json::String((*address).clone())),
+                    ("reason".to_owned(), Json::String(suppression.reason.clone())),
+                    ("since".to_owned(), Json::Number(suppression.since))
+                ])
+            }).collect::<Vec<Json>>()
+        };
+        export_lines(output, records.as_ref())
+    }
+
+    /// Adds the addresses written by `export`, returning the number of addresses read.
+    ///
+    /// Addresses that are already suppressed keep the earliest date. Addresses before an
+    /// invalid line are kept.
+    pub fn import<R: BufRead>(&self, input: &mut R) -> Result<usize, ImportError> {
+        import_lines(input, |record| {
+            let address = record.get("address").and_then(|v| v.as_str());
+            let reason = record.get("reason").and_then(|v| v.as_str());
+            let since = record.get("since").and_then(|v| v.as_u64());
+            match (address, reason, since) {
+                (Some(address), Some(reason), Some(since)) => {
+                    match self.get(address) {
+                        Some(ref existing) if existing.since <= since => {},
+                        _ => self.add(address, reason, since)
+                    }
+                    true
+                },
+                _ => false
+            }
+        })
+    }
+}
+
+#[test]
+fn test_suppression_list() {
+    let list = SuppressionList::new();
+    list.add("Rust@Rustastic.org", "bounce", 100);
+    list.add("b@rustastic.org", "complaint", 50);
+    assert_eq!(Some(Suppression { reason: "bounce".to_owned(), since: 100 }), list.get("rust@rustastic.ORG"));
+    assert!(list.remove("B@rustastic.org"));
+    assert!(!list.remove("b@rustastic.org"));
+    list.add("a@rustastic.org", "complaint", 200);
+
+    let mut output = Vec::new();
+    assert_eq!(2, list.export(&mut output).unwrap());
+    assert_eq!(
+        "{\"address\":\"a@rustastic.org\",\"reason\":\"complaint\",\"since\":200}\n{\"address\":\"rust@rustastic.org\",\"reason\":\"bounce\",\"since\":100}\n",
+        String::from_utf8(output.clone()).unwrap()
+    );
+
+    let other = SuppressionList::new();
+    other.add("a@rustastic.org", "bounce", 150);
+    assert_eq!(2, other.import(&mut &output[..]).unwrap());
+    assert_eq!(2, other.len());
+    assert_eq!(Some(Suppression { reason: "bounce".to_owned(), since: 150 }), other.get("a@rustastic.org"));
+    match other.import(&mut &b"{\"address\":\"c@rustastic.org\"}\n"[..]) {
+        Err(ImportError::Invalid(1)) => {},
+        res => panic!("unexpected result: {:?}", res)
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! A scripted SMTP server to test clients against, including when things go wrong.
+//!
+//! # Example
+//!
+//! ```ignore
+//! // Greylist the first attempt, then drop the connection once the message is sent.
+//! let mut script = Script::new();
+//! script.on("MAIL", Step::Reply(Reply::enhanced(451, EnhancedStatusCode::new(4, 7, 1), "Try again later")));
+//! script.on("MAIL", Step::Reply(Reply::enhanced(250, EnhancedStatusCode::new(2, 1, 0), "OK")));
+//! script.on_message(Step::Hangup);
+//! let server = MockServer::start(script);
+//! ```
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::collections::HashMap;
+use std::io::{Read, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::vec::Vec;
+use super::read_reply;
+use super::super::common::Reply;
+use super::super::common::status::EnhancedStatusCode;
+use super::super::common::stream::InputStream;
+
+/// What the server does when it gets a command.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Step {
+    /// Sends the reply and waits for the next command.
+    Reply(Reply),
+    /// Sends the reply and closes the connection.
+    ReplyAndHangup(Reply),
+    /// Closes the connection without replying.
+    Hangup
+}
+
+/// How the server behaves, command by command.
+///
+/// Each time a command is received, the next step given for its verb is used. The
+/// last step of a verb is used again once the others have been used, and commands
+/// without any step get a default reply.
+#[derive(Clone, Debug)]
+pub struct Script {
+    greeting: Vec<Step>,
+    message: Vec<Step>,
+    commands: HashMap<String, Vec<Step>>
+}
+
+impl Script {
+    /// Creates a script accepting everything.
+    pub fn new() -> Script {
+        Script {
+            greeting: Vec::new(),
+            message: Vec::new(),
+            commands: HashMap::new()
+        }
+    }
+
+    /// Adds a step for the greeting, sent when a client connects.
+    ///
+    /// The default is `220 rustastic.test ESMTP`.
+    pub fn greeting(&mut self, step: Step) -> &mut Script {
+        self.greeting.push(step);
+        self
+    }
+
+    /// Adds a step for the command with the given verb, ie `MAIL` or `EHLO`.
+    ///
+    /// The default is `354` for DATA, `221` and closing the connection for QUIT, and
+    /// `250` for other commands.
+    pub fn on(&mut self, verb: &str, step: Step) -> &mut Script {
+        self.commands.entry(verb.to_ascii_uppercase()).or_insert(Vec::new()).push(step);
+        self
+    }
+
+    /// Adds a step for the end of a message sent after DATA.
+    ///
+    /// The default is `250 2.0.0 OK`.
+    pub fn on_message(&mut self, step: Step) -> &mut Script {
+        self.message.push(step);
+        self
+    }
+}
+
+// Returns the step to use the `count`th time, starting at 0.
+fn get_step(steps: &[Step], count: usize) -> Option<&Step> {
+    match steps.len() {
+        0 => None,
+        len => Some(&steps[if count < len { count } else { len - 1 }])
+    }
+}
+
+fn default_step(verb: &str) -> Step {
+    match verb {
+        "" => Step::Reply(Reply::new(220, "rustastic.test ESMTP")),
+        "." => Step::Reply(Reply::enhanced(250, EnhancedStatusCode::new(2, 0, 0), "OK")),
+        "DATA" => Step::Reply(Reply::new(354, "Start mail input; end with <CRLF>.<CRLF>")),
+        "QUIT" => Step::ReplyAndHangup(Reply::enhanced(221, EnhancedStatusCode::new(2, 0, 0), "Bye")),
+        _ => Step::Reply(Reply::enhanced(250, EnhancedStatusCode::new(2, 0, 0), "OK"))
+    }
+}
+
+// The verb of a command line, ie `MAIL` for `MAIL FROM:<a@rustastic.org>`.
+fn get_verb(line: &str) -> String {
+    line.split(|c| c == ' ' || c == ':').next().unwrap_or("").to_ascii_uppercase()
+}
+
+#[test]
+fn test_get_verb() {
+    assert_eq!("MAIL", get_verb("mail FROM:<a@rustastic.org>"));
+    assert_eq!("RCPT", get_verb("RCPT TO:<a@rustastic.org>"));
+    assert_eq!("QUIT", get_verb("QUIT"));
+    assert_eq!("", get_verb(""));
+}
+
+// What the server went through, shared by all the connections.
+struct ScriptState {
+    script: Script,
+    counts: HashMap<String, usize>,
+    commands: Vec<String>,
+    messages: Vec<Vec<u8>>
+}
+
+impl ScriptState {
+    // Returns the step for the verb, `""` being the greeting and `"."` the end of a
+    // message.
+    fn next_step(&mut self, verb: &str) -> Step {
+        let count = {
+            let count = self.counts.entry(verb.to_owned()).or_insert(0);
+            *count += 1;
+            *count - 1
+        };
+        let step = match verb {
+            "" => get_step(self.script.greeting.as_ref(), count),
+            "." => get_step(self.script.message.as_ref(), count),
+            _ => self.script.commands.get(verb).and_then(|steps| get_step(steps.as_ref(), count))
+        };
+        match step {
+            Some(step) => step.clone(),
+            None => default_step(verb)
+        }
+    }
+}
+
+/// A server following a script, on the loopback interface.
+///
+/// Connections are handled one at a time, and the script goes on from one connection
+/// to the next, so retries can be tested.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<ScriptState>>
+}
+
+impl MockServer {
+    /// Starts a server following the script on a free port.
+    pub fn start(script: &Script) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = MockServer {
+            addr: listener.local_addr().unwrap(),
+            state: Arc::new(Mutex::new(ScriptState {
+                script: script.clone(),
+                counts: HashMap::new(),
+                commands: Vec::new(),
+                messages: Vec::new()
+            }))
+        };
+        let state = server.state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => handle_connection(stream, &state),
+                    Err(_) => break
+                }
+            }
+        });
+        server
+    }
+
+    /// Returns the address to connect to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the command lines received so far, without `<CRLF>`.
+    pub fn commands(&self) -> Vec<String> {
+        self.state.lock().unwrap().commands.clone()
+    }
+
+    /// Returns the messages received so far, without the final `.` for DATA, with the
+    /// chunks put together for BDAT.
+    pub fn messages(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().messages.clone()
+    }
+}
+
+// Sends the reply of the step, returning whether the connection goes on and the code
+// that was sent.
+fn run_step(stream: &mut TcpStream, step: Step) -> (bool, Option<u16>) {
+    let (reply, hangup) = match step {
+        Step::Reply(reply) => (reply, false),
+        Step::ReplyAndHangup(reply) => (reply, true),
+        Step::Hangup => return (false, None)
+    };
+    // The reply is written at once, clients may expect whole lines in a single read.
+    let mut bytes = String::new();
+    for line in reply.to_lines().iter() {
+        bytes.push_str(line.as_ref());
+        bytes.push_str("\r\n");
+    }
+    match stream.write_all(bytes.as_bytes()) {
+        Ok(_) => (!hangup, Some(reply.code())),
+        Err(_) => (false, None)
+    }
+}
+
+// Reads the lines of a message, until `.` alone on a line.
+fn read_message(reader: &mut BufReader<TcpStream>) -> Option<Vec<u8>> {
+    let mut message = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {}
+        }
+        if line == b".\r\n" {
+            return Some(message);
+        }
+        message.extend(line.into_iter());
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: &Arc<Mutex<ScriptState>>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return
+    };
+    let mut reader = BufReader::new(stream);
+    let step = state.lock().unwrap().next_step("");
+    if !run_step(&mut writer, step).0 {
+        return;
+    }
+    let mut chunks = Vec::new();
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        let line = line.trim_right_matches("\r\n").to_owned();
+        let verb = get_verb(line.as_ref());
+        // The chunk of BDAT comes right after the command.
+        let last_chunk = match verb.as_ref() {
+            "BDAT" => {
+                let mut parts = line.split(' ').skip(1);
+                let size = match parts.next().and_then(|size| size.parse::<usize>().ok()) {
+                    Some(size) => size,
+                    None => return
+                };
+                let mut chunk = vec![0; size];
+                if reader.read_exact(chunk.as_mut()).is_err() {
+                    return;
+                }
+                chunks.extend(chunk.into_iter());
+                parts.next().map_or(false, |last| last.eq_ignore_ascii_case("LAST"))
+            },
+            _ => false
+        };
+        let step = {
+            let mut state = state.lock().unwrap();
+            state.commands.push(line);
+            if last_chunk {
+                state.messages.push(chunks.split_off(0));
+            }
+            state.next_step(verb.as_ref())
+        };
+        match run_step(&mut writer, step) {
+            (false, _) => return,
+            (true, Some(354)) if verb == "DATA" => {
+                let message = match read_message(&mut reader) {
+                    Some(message) => message,
+                    None => return
+                };
+                let step = {
+                    let mut state = state.lock().unwrap();
+                    state.messages.push(message);
+                    state.next_step(".")
+                };
+                if !run_step(&mut writer, step).0 {
+                    return;
+                }
+            },
+            (true, _) => {}
+        }
+    }
+}
+
+fn send(stream: &mut TcpStream, input: &mut InputStream<TcpStream>, line: &str) -> Option<u16> {
+    stream.write_all(format!("{}\r\n", line).as_bytes()).unwrap();
+    read_reply(input).ok().map(|reply| reply.code())
+}
+
+#[test]
+fn test_mock_server() {
+    let mut script = Script::new();
+    script.on("MAIL", Step::Reply(Reply::enhanced(451, EnhancedStatusCode::new(4, 7, 1), "Try again later")))
+        .on("MAIL", Step::Reply(Reply::enhanced(250, EnhancedStatusCode::new(2, 1, 0), "OK")))
+        .on_message(Step::Hangup);
+    let server = MockServer::start(&script);
+
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    let mut input = InputStream::new(stream.try_clone().unwrap(), 1000, false);
+    assert_eq!(220, read_reply(&mut input).unwrap().code());
+    assert_eq!(Some(250), send(&mut stream, &mut input, "EHLO rustastic.org"));
+    assert_eq!(Some(451), send(&mut stream, &mut input, "MAIL FROM:<a@rustastic.org>"));
+    assert_eq!(Some(250), send(&mut stream, &mut input, "MAIL FROM:<a@rustastic.org>"));
+    assert_eq!(Some(250), send(&mut stream, &mut input, "MAIL FROM:<a@rustastic.org>"));
+    assert_eq!(Some(250), send(&mut stream, &mut input, "RCPT TO:<b@rustastic.org>"));
+    assert_eq!(Some(354), send(&mut stream, &mut input, "DATA"));
+    assert_eq!(None, send(&mut stream, &mut input, "Subject: Hi\r\n\r\nHello\r\n."));
+
+    assert_eq!(vec![b"Subject: Hi\r\n\r\nHello\r\n".to_vec()], server.messages());
+    assert_eq!(6, server.commands().len());
+    assert_eq!("DATA", server.commands()[5]);
+
+    // The script goes on with the next connection.
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    let mut input = InputStream::new(stream.try_clone().unwrap(), 1000, false);
+    assert_eq!(220, read_reply(&mut input).unwrap().code());
+    assert_eq!(Some(250), send(&mut stream, &mut input, "MAIL FROM:<a@rustastic.org>"));
+    assert_eq!(Some(221), send(&mut stream, &mut input, "QUIT"));
+    assert!(read_reply(&mut input).is_err());
+}
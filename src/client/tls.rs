@@ -0,0 +1,348 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS for outgoing connections, and how server certificates are verified.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut config = TlsConfig::new(connector);
+//! // The internal relay has a self-signed certificate.
+//! config.accept_self_signed("relay.internal");
+//! // Only this key is accepted for the partner, whoever signed it.
+//! config.add_pin("mx.partner.example", spki_sha256);
+//!
+//! // Partners must have verified TLS, others get it when they offer it.
+//! let mut policies = TlsPolicies::new(TlsPolicy::Opportunistic);
+//! policies.set("partner.example", TlsPolicy::Required);
+//! try!(client.starttls(&config, domain, "mx.rustastic.org", policies.get(domain)));
+//! record(domain, client.tls_level());
+//! ```
+
+use std::io::{Error as IoError, ErrorKind};
+use std::io::Result as IoResult;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::vec::Vec;
+use std::borrow::ToOwned;
+use std::ascii::AsciiExt;
+#[cfg(test)]
+use std::net::TcpListener;
+use super::super::common::tls::{TlsConnector, TlsInfo, TlsStream};
+use super::super::common::sha256;
+use super::session::TlsPolicy;
+
+/// A verification callback, given the domain and what the handshake negotiated.
+///
+/// It returns why the certificate is rejected, if it is.
+pub type VerifyFn = Fn(&str, &TlsInfo) -> Result<(), String> + Send + Sync;
+
+/// How a server certificate was accepted, or why it wasn't.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum TlsVerification {
+    /// The certificate chains up to a trusted root and matches the domain.
+    Chain,
+    /// The key of the certificate matches a pin of the domain.
+    Pinned,
+    /// The certificate isn't trusted, but the domain accepts self-signed certificates.
+    SelfSigned,
+    /// The verification callback accepted the certificate.
+    Callback,
+    /// The certificate was rejected for the given reason.
+    Failed(String)
+}
+
+/// The TLS session of a delivery, for auditing.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct TlsReport {
+    /// The domain the certificate was checked against.
+    pub domain: String,
+    /// What the handshake negotiated.
+    pub info: TlsInfo,
+    /// How the certificate was verified. It may have failed in log-only mode.
+    pub verification: TlsVerification
+}
+
+/// How secure a session or a delivery actually was, from the weakest to the strongest.
+#[derive(PartialEq, Eq, Clone, Debug, Copy, PartialOrd, Ord)]
+pub enum TlsLevel {
+    /// The session wasn't encrypted.
+    Plain,
+    /// The session was encrypted, but the certificate of the server wasn't accepted.
+    Unverified,
+    /// The session was encrypted, and the certificate of the server was accepted.
+    Verified
+}
+
+impl TlsLevel {
+    /// Returns the level of a session with the given TLS session, `None` if it wasn't
+    /// encrypted.
+    pub fn of(report: Option<&TlsReport>) -> TlsLevel {
+        match report.map(|report| &report.verification) {
+            None => TlsLevel::Plain,
+            Some(&TlsVerification::Failed(_)) => TlsLevel::Unverified,
+            Some(_) => TlsLevel::Verified
+        }
+    }
+}
+
+/// The TLS policy of each destination, ie to require verified TLS for partners while
+/// other domains get opportunistic TLS.
+///
+/// Destinations are domains, which are compared regardless of case. A destination
+/// starting with a dot, ie `.rustastic.org`, is for all the subdomains of the domain.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct TlsPolicies {
+    default: TlsPolicy,
+    destinations: Vec<(String, TlsPolicy)>
+}
+
+impl TlsPolicies {
+    /// Creates policies giving the default policy to every destination.
+    pub fn new(default: TlsPolicy) -> TlsPolicies {
+        TlsPolicies {
+            default: default,
+            destinations: Vec::new()
+        }
+    }
+
+    /// Sets the policy of a destination, replacing the previous one.
+    pub fn set(&mut self, destination: &str, policy: TlsPolicy) {
+        self.destinations.retain(|&(ref other, _)| !other.eq_ignore_ascii_case(destination));
+        self.destinations.push((destination.to_owned(), policy));
+    }
+
+    /// Returns the policy of a domain.
+    ///
+    /// The domain itself comes first, then its closest parent with a policy for its
+    /// subdomains, then the default.
+    pub fn get(&self, domain: &str) -> TlsPolicy {
+        let domain = domain.to_ascii_lowercase();
+        let mut best: Option<(usize, TlsPolicy)> = None;
+        for &(ref destination, policy) in self.destinations.iter() {
+            let destination = destination.to_ascii_lowercase();
+            // The longest match is the most specific one, an exact match beats the
+            // subdomains of the same domain.
+            let (matched, len) = match destination.starts_with(".") {
+                true => (domain.ends_with(destination.as_str()), destination.len()),
+                false => (domain == destination, destination.len() + 1)
+            };
+            if matched && best.map_or(true, |(best_len, _)| len > best_len) {
+                best = Some((len, policy));
+            }
+        }
+        best.map_or(self.default, |(_, policy)| policy)
+    }
+}
+
+/// How the client performs TLS handshakes and verifies server certificates.
+///
+/// By default, certificates must chain up to a trusted root and match the domain.
+#[derive(Clone)]
+pub struct TlsConfig {
+    connector: Arc<TlsConnector>,
+    pins: Vec<(String, [u8; 32])>,
+    self_signed: Vec<String>,
+    verify: Option<Arc<VerifyFn>>,
+    log_only: bool
+}
+
+impl TlsConfig {
+    /// Creates a configuration performing handshakes with the connector.
+    pub fn new<C: 'static + TlsConnector>(connector: C) -> TlsConfig {
+        TlsConfig {
+            connector: Arc::new(connector),
+            pins: Vec::new(),
+            self_signed: Vec::new(),
+            verify: None,
+            log_only: false
+        }
+    }
+
+    /// Pins a key for the domain, given as the SHA-256 digest of its DER encoded
+    /// SubjectPublicKeyInfo.
+    ///
+    /// Once a domain has pins, its certificates are accepted if and only if their key
+    /// matches one of them, whoever signed them.
+    pub fn add_pin(&mut self, domain: &str, spki_sha256: [u8; 32]) {
+        self.pins.push((domain.to_owned(), spki_sha256));
+    }
+
+    /// Accepts certificates that don't chain up to a trusted root for the domain, ie for
+    /// internal relays with self-signed certificates.
+    pub fn accept_self_signed(&mut self, domain: &str) {
+        self.self_signed.push(domain.to_owned());
+    }
+
+    /// Sets a callback that decides whether certificates are acceptable, instead of the
+    /// pins and the other checks.
+    pub fn set_verify<F>(&mut self, verify: F)
+        where F: 'static + Fn(&str, &TlsInfo) -> Result<(), String> + Send + Sync {
+        self.verify = Some(Arc::new(verify));
+    }
+
+    /// Goes on with deliveries when verification fails, only reporting the failure in the
+    /// `verification` of the `TlsReport`, for the caller to log.
+    pub fn log_only(&mut self) {
+        self.log_only = true;
+    }
+
+    /// Tells whether the certificate of a server is acceptable for the domain.
+    pub fn verify(&self, domain: &str, info: &TlsInfo) -> TlsVerification {
+        if let Some(ref verify) = self.verify {
+            return match verify(domain, info) {
+                Ok(_) => TlsVerification::Callback,
+                Err(reason) => TlsVerification::Failed(reason)
+            };
+        }
+
+        let pins: Vec<&[u8; 32]> = self.pins.iter()
+            .filter(|&&(ref pinned, _)| pinned.eq_ignore_ascii_case(domain))
+            .map(|&(_, ref pin)| pin)
+            .collect();
+        if pins.len() > 0 {
+            let digest = sha256::digest(info.peer_spki.as_ref());
+            return match pins.iter().any(|pin| **pin == digest) {
+                true => TlsVerification::Pinned,
+                false => TlsVerification::Failed("key doesn't match the pins of the domain".to_owned())
+            };
+        }
+
+        if info.chain_verified {
+            TlsVerification::Chain
+        } else if self.self_signed.iter().any(|allowed| allowed.eq_ignore_ascii_case(domain)) {
+            TlsVerification::SelfSigned
+        } else {
+            TlsVerification::Failed("certificate not trusted".to_owned())
+        }
+    }
+
+    /// Performs a TLS handshake with a server and verifies its certificate.
+    ///
+    /// This fails if the certificate is not acceptable, unless in log-only mode, see
+    /// `log_only`.
+    pub fn connect(&self, domain: &str, stream: TcpStream) -> IoResult<(Box<TlsStream>, TlsReport)> {
+        let (stream, report) = try!(self.handshake(domain, stream));
+        if let TlsVerification::Failed(ref reason) = report.verification {
+            if !self.log_only {
+                let message = format!("TLS verification failed for {}: {}", domain, reason);
+                return Err(IoError::new(ErrorKind::InvalidData, message));
+            }
+        }
+        Ok((stream, report))
+    }
+
+    /// Performs a TLS handshake with a server, and reports how its certificate was
+    /// verified without failing if it isn't acceptable.
+    pub fn handshake(&self, domain: &str, stream: TcpStream) -> IoResult<(Box<TlsStream>, TlsReport)> {
+        let (stream, info) = try!(self.connector.connect(domain, stream));
+        let verification = self.verify(domain, &info);
+        Ok((stream, TlsReport {
+            domain: domain.to_owned(),
+            info: info,
+            verification: verification
+        }))
+    }
+}
+
+#[cfg(test)]
+fn get_info(chain_verified: bool) -> TlsInfo {
+    TlsInfo {
+        protocol: "TLSv1.2".to_owned(),
+        cipher: "ECDHE-RSA-AES128-GCM-SHA256".to_owned(),
+        peer_spki: b"key".to_vec(),
+        chain_verified: chain_verified
+    }
+}
+
+#[test]
+fn test_verify() {
+    let mut config = TlsConfig::new(PlainConnector);
+    assert_eq!(TlsVerification::Chain, config.verify("rustastic.org", &get_info(true)));
+    assert!(config.verify("rustastic.org", &get_info(false)) != TlsVerification::Chain);
+
+    config.accept_self_signed("relay.internal");
+    assert_eq!(TlsVerification::SelfSigned, config.verify("RELAY.internal", &get_info(false)));
+
+    // Pins replace the other checks for their domain.
+    config.add_pin("rustastic.org", sha256::digest(b"other key"));
+    assert!(config.verify("rustastic.org", &get_info(true)) != TlsVerification::Pinned);
+    config.add_pin("rustastic.org", sha256::digest(b"key"));
+    assert_eq!(TlsVerification::Pinned, config.verify("rustastic.org", &get_info(false)));
+
+    config.set_verify(|domain, _| match domain == "rustastic.org" {
+        true => Ok(()),
+        false => Err("unknown".to_owned())
+    });
+    assert_eq!(TlsVerification::Callback, config.verify("rustastic.org", &get_info(false)));
+    assert_eq!(TlsVerification::Failed("unknown".to_owned()), config.verify("relay.internal", &get_info(true)));
+}
+
+// Pretends to perform handshakes, with an untrusted certificate.
+#[cfg(test)]
+pub struct PlainConnector;
+
+#[cfg(test)]
+impl TlsConnector for PlainConnector {
+    fn connect(&self, _: &str, stream: TcpStream) -> IoResult<(Box<TlsStream>, TlsInfo)> {
+        Ok((Box::new(stream), get_info(false)))
+    }
+}
+
+#[test]
+fn test_connect() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut config = TlsConfig::new(PlainConnector);
+
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let err = config.connect("rustastic.org", stream).err().unwrap();
+    assert_eq!(ErrorKind::InvalidData, err.kind());
+
+    // The failure is only reported in log-only mode.
+    config.log_only();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (_, report) = config.connect("rustastic.org", stream).unwrap();
+    assert_eq!("rustastic.org", report.domain);
+    assert_eq!("TLSv1.2", report.info.protocol);
+    assert_eq!(TlsVerification::Failed("certificate not trusted".to_owned()), report.verification);
+}
+
+#[test]
+fn test_tls_policies() {
+    let mut policies = TlsPolicies::new(TlsPolicy::Opportunistic);
+    policies.set(".rustastic.org", TlsPolicy::RequiredUnverified);
+    policies.set("mx.rustastic.org", TlsPolicy::Required);
+    policies.set("legacy.example", TlsPolicy::Required);
+    policies.set("LEGACY.example", TlsPolicy::Disabled);
+
+    assert_eq!(TlsPolicy::Opportunistic, policies.get("example.com"));
+    assert_eq!(TlsPolicy::Opportunistic, policies.get("rustastic.org"));
+    assert_eq!(TlsPolicy::RequiredUnverified, policies.get("relay.Rustastic.org"));
+    assert_eq!(TlsPolicy::Required, policies.get("MX.rustastic.org"));
+    assert_eq!(TlsPolicy::Disabled, policies.get("legacy.example"));
+    assert!(TlsPolicy::Required > TlsPolicy::RequiredUnverified);
+}
+
+#[test]
+fn test_tls_level() {
+    let report = |verification| TlsReport {
+        domain: "rustastic.org".to_owned(),
+        info: get_info(false),
+        verification: verification
+    };
+    assert_eq!(TlsLevel::Plain, TlsLevel::of(None));
+    assert_eq!(TlsLevel::Unverified, TlsLevel::of(Some(&report(TlsVerification::Failed("certificate not trusted".to_owned())))));
+    assert_eq!(TlsLevel::Verified, TlsLevel::of(Some(&report(TlsVerification::SelfSigned))));
+    assert!(TlsLevel::Verified > TlsLevel::Unverified);
+}
@@ -0,0 +1,210 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Routing of outgoing messages to smarthosts, depending on who sends them.
+//!
+//! A sender with several brands can deliver the mail of each sending domain through its
+//! own provider, with its own credentials.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut map = TransportMap::new();
+//! map.add_route("brand-a", Route::new(pool_a, Some(Credentials::new("a", "secret"))));
+//! map.add_route("brand-b", Route::new(pool_b, None));
+//! map.map_sender_domain("brand-a.example", "brand-a");
+//! map.map_user("newsletter", "brand-b");
+//!
+//! if let Some(route) = map.select(sender.as_ref(), authenticated) {
+//!     let host = route.pool.select(Instant::now());
+//!     ...
+//! }
+//! ```
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::fmt;
+use std::vec::Vec;
+use super::smarthost::SmarthostPool;
+use super::super::common::base64;
+use super::super::common::mailbox::{Mailbox, MailboxForeignPart};
+
+/// What to authenticate with at a smarthost.
+#[derive(PartialEq, Eq, Clone)]
+pub struct Credentials {
+    /// The user name.
+    pub username: String,
+    /// The password.
+    pub password: String
+}
+
+impl Credentials {
+    /// Creates credentials from a user name and a password.
+    pub fn new(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.to_owned(),
+            password: password.to_owned()
+        }
+    }
+
+    /// Returns the AUTH command line authenticating with the PLAIN mechanism, as
+    /// described [in RFC 4616](http://tools.ietf.org/html/rfc4616).
+    pub fn auth_plain(&self) -> String {
+        let mut response = Vec::new();
+        response.push(0u8);
+        response.extend(self.username.as_bytes().iter().cloned());
+        response.push(0u8);
+        response.extend(self.password.as_bytes().iter().cloned());
+        format!("AUTH PLAIN {}", base64::encode(response.as_ref()))
+    }
+}
+
+// The password must not end up in logs.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Credentials {{ username: {:?}, password: \"...\" }}", self.username)
+    }
+}
+
+/// The smarthosts to deliver through, and how to authenticate with them.
+pub struct Route {
+    /// The smarthosts.
+    pub pool: SmarthostPool,
+    /// The credentials for the smarthosts, if they require authentication.
+    pub credentials: Option<Credentials>
+}
+
+impl Route {
+    /// Creates a route through the smarthosts of the pool.
+    pub fn new(pool: SmarthostPool, credentials: Option<Credentials>) -> Route {
+        Route {
+            pool: pool,
+            credentials: credentials
+        }
+    }
+}
+
+/// Picks the route of a message from its sender.
+pub struct TransportMap {
+    routes: Vec<(String, Route)>,
+    users: Vec<(String, String)>,
+    domains: Vec<(String, String)>,
+    default: Option<String>
+}
+
+impl TransportMap {
+    /// Creates a map with no routes.
+    pub fn new() -> TransportMap {
+        TransportMap {
+            routes: Vec::new(),
+            users: Vec::new(),
+            domains: Vec::new(),
+            default: None
+        }
+    }
+
+    /// Adds a route, or replaces the route with the same name.
+    pub fn add_route(&mut self, name: &str, route: Route) {
+        self.routes.retain(|&(ref existing, _)| existing != name);
+        self.routes.push((name.to_owned(), route));
+    }
+
+    /// Sends the messages of senders in the domain through the named route.
+    ///
+    /// Domains are compared without regard for case, subdomains don't match.
+    pub fn map_sender_domain(&mut self, domain: &str, route: &str) {
+        self.domains.retain(|&(ref existing, _)| !existing.eq_ignore_ascii_case(domain));
+        self.domains.push((domain.to_owned(), route.to_owned()));
+    }
+
+    /// Sends the messages of an authenticated user through the named route.
+    ///
+    /// This takes precedence over the domain of the sender.
+    pub fn map_user(&mut self, user: &str, route: &str) {
+        self.users.retain(|&(ref existing, _)| existing != user);
+        self.users.push((user.to_owned(), route.to_owned()));
+    }
+
+    /// Sets the route of the messages that match nothing else.
+    pub fn set_default_route(&mut self, route: &str) {
+        self.default = Some(route.to_owned());
+    }
+
+    /// Returns the name of the route for a sender, given the user it authenticated as.
+    pub fn route_name(&self, sender: Option<&Mailbox>, user: Option<&str>) -> Option<&str> {
+        let by_user = user.and_then(|user| {
+            self.users.iter().find(|&&(ref existing, _)| existing == user)
+        });
+        let by_domain = sender.and_then(|sender| {
+            match *sender.foreign_part() {
+                MailboxForeignPart::Domain(ref domain) => {
+                    self.domains.iter().find(|&&(ref existing, _)| existing.eq_ignore_ascii_case(domain))
+                },
+                MailboxForeignPart::IpAddr(_) => None
+            }
+        });
+        match by_user.or(by_domain) {
+            Some(&(_, ref route)) => Some(route.as_ref()),
+            None => self.default.as_ref().map(|route| route.as_ref())
+        }
+    }
+
+    /// Returns the route for a sender, given the user it authenticated as.
+    ///
+    /// This is `None` if nothing matches and there is no default route, or if the route
+    /// that matches was never added.
+    pub fn select(&mut self, sender: Option<&Mailbox>, user: Option<&str>) -> Option<&mut Route> {
+        let name = match self.route_name(sender, user) {
+            Some(name) => name.to_owned(),
+            None => return None
+        };
+        self.routes.iter_mut()
+            .find(|&&mut (ref existing, _)| *existing == name)
+            .map(|&mut (_, ref mut route)| route)
+    }
+}
+
+#[test]
+fn test_credentials() {
+    let credentials = Credentials::new("rust", "secret");
+    assert_eq!("AUTH PLAIN AHJ1c3QAc2VjcmV0", credentials.auth_plain());
+    assert_eq!("Credentials { username: \"rust\", password: \"...\" }", format!("{:?}", credentials));
+}
+
+#[test]
+fn test_transport_map() {
+    let brand_a = Mailbox::parse("news@Brand-A.example").unwrap();
+    let brand_b = Mailbox::parse("news@brand-b.example").unwrap();
+    let mut map = TransportMap::new();
+    assert_eq!(None, map.route_name(Some(&brand_a), None));
+
+    map.add_route("a", Route::new(SmarthostPool::new(), Some(Credentials::new("a", "secret"))));
+    map.add_route("default", Route::new(SmarthostPool::new(), None));
+    map.map_sender_domain("brand-a.example", "a");
+    map.map_sender_domain("brand-b.example", "b");
+    map.map_user("alice", "default");
+    map.set_default_route("default");
+
+    assert_eq!(Some("a"), map.route_name(Some(&brand_a), None));
+    assert_eq!(Some("b"), map.route_name(Some(&brand_b), Some("bob")));
+    assert_eq!(Some("default"), map.route_name(Some(&brand_a), Some("alice")));
+    assert_eq!(Some("default"), map.route_name(None, None));
+    assert_eq!(Some("default"), map.route_name(Some(&Mailbox::parse("rust@[127.0.0.1]").unwrap()), None));
+
+    assert_eq!(Some("a"), map.select(Some(&brand_a), None).and_then(|route| route.credentials.as_ref()).map(|c| c.username.as_ref()));
+    assert!(map.select(None, None).unwrap().credentials.is_none());
+    // Route b was never added.
+    assert!(map.select(Some(&brand_b), None).is_none());
+}
@@ -0,0 +1,120 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Base64 encoding, as described [in RFC 4648](http://tools.ietf.org/html/rfc4648#section-4).
+//!
+//! This is used by SASL exchanges, which don't allow line breaks in encoded data.
+
+use std::vec::Vec;
+
+static ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes bytes with padding and without line breaks.
+pub fn encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b0 = chunk[0] as usize;
+        let b1 = if chunk.len() > 1 { chunk[1] as usize } else { 0 };
+        let b2 = if chunk.len() > 2 { chunk[2] as usize } else { 0 };
+        out.push(ALPHABET[b0 >> 2] as char);
+        out.push(ALPHABET[((b0 & 0x03) << 4) | (b1 >> 4)] as char);
+        match chunk.len() {
+            1 => out.push_str("=="),
+            2 => {
+                out.push(ALPHABET[(b1 & 0x0f) << 2] as char);
+                out.push('=');
+            },
+            _ => {
+                out.push(ALPHABET[((b1 & 0x0f) << 2) | (b2 >> 6)] as char);
+                out.push(ALPHABET[b2 & 0x3f] as char);
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn test_encode() {
+    assert_eq!("", encode(b""));
+    assert_eq!("Zg==", encode(b"f"));
+    assert_eq!("Zm8=", encode(b"fo"));
+    assert_eq!("Zm9v", encode(b"foo"));
+    assert_eq!("Zm9vYg==", encode(b"foob"));
+    assert_eq!("Zm9vYmE=", encode(b"fooba"));
+    assert_eq!("Zm9vYmFy", encode(b"foobar"));
+    assert_eq!("AHJ1c3QAcGFzcw==", encode(b"\0rust\0pass"));
+}
+
+fn decode_char(c: u8) -> Option<u8> {
+    match c {
+        b'A' ... b'Z' => Some(c - b'A'),
+        b'a' ... b'z' => Some(c - b'a' + 26),
+        b'0' ... b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None
+    }
+}
+
+/// Decodes padded base64 data.
+///
+/// Whitespace, missing padding and characters outside the alphabet are errors.
+pub fn decode(s: &str) -> Result<Vec<u8>, ()> {
+    let bytes = s.as_bytes();
+    if bytes.len() % 4 != 0 {
+        return Err(());
+    }
+
+    let mut out = Vec::with_capacity(bytes.len() / 4 * 3);
+    for (i, chunk) in bytes.chunks(4).enumerate() {
+        let last = (i + 1) * 4 == bytes.len();
+        let padding = match (chunk[2], chunk[3]) {
+            (b'=', b'=') if last => 2,
+            (_, b'=') if last => 1,
+            _ => 0
+        };
+
+        let mut n = 0u32;
+        for &c in chunk[.. 4 - padding].iter() {
+            n = (n << 6) | try!(decode_char(c).ok_or(())) as u32;
+        }
+        n <<= 6 * padding as u32;
+
+        out.push((n >> 16) as u8);
+        if padding < 2 {
+            out.push((n >> 8) as u8);
+        }
+        if padding < 1 {
+            out.push(n as u8);
+        }
+    }
+    Ok(out)
+}
+
+#[test]
+fn test_decode() {
+    assert_eq!(Ok(b"".to_vec()), decode(""));
+    assert_eq!(Ok(b"f".to_vec()), decode("Zg=="));
+    assert_eq!(Ok(b"fo".to_vec()), decode("Zm8="));
+    assert_eq!(Ok(b"foo".to_vec()), decode("Zm9v"));
+    assert_eq!(Ok(b"foobar".to_vec()), decode("Zm9vYmFy"));
+    assert_eq!(Ok(b"\0rust\0pass".to_vec()), decode("AHJ1c3QAcGFzcw=="));
+
+    assert_eq!(Err(()), decode("Zg"));
+    assert_eq!(Err(()), decode("Zg=a"));
+    assert_eq!(Err(()), decode("Z==="));
+    assert_eq!(Err(()), decode("Zg==Zg=="));
+    assert_eq!(Err(()), decode("Zm9 "));
+    assert_eq!(Err(()), decode("Zm9v\r\n"));
+}
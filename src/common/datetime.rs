@@ -0,0 +1,344 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dates and times as written in messages, as described
+//! [in RFC 5322](http://tools.ietf.org/html/rfc5322#section-3.3), ie
+//! `Thu, 01 Jan 2015 00:00:00 +0000`.
+//!
+//! Dates are always written in the current format. When parsing, the obsolete formats of
+//! [section 4.3](http://tools.ietf.org/html/rfc5322#section-4.3) are accepted too: two
+//! digit years, zone names like `EST`, comments and spaces around colons.
+
+use std::ascii::AsciiExt;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::vec::Vec;
+
+static DAYS: [&'static str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+static MONTHS: [&'static str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"
+];
+
+// The zones of obsolete dates, with their offset in minutes.
+static ZONES: [(&'static str, i32); 10] = [
+    ("UT", 0), ("GMT", 0),
+    ("EST", -300), ("EDT", -240),
+    ("CST", -360), ("CDT", -300),
+    ("MST", -420), ("MDT", -360),
+    ("PST", -480), ("PDT", -420)
+];
+
+/// Represents an error that occured while trying to parse a date.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum DateTimeError {
+    /// The date doesn't follow the syntax.
+    Syntax,
+    /// A field is out of range, ie the 31st of April or 25 o'clock.
+    OutOfRange
+}
+
+/// A date and time, in a zone.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct DateTime {
+    /// The year, ie `2015`.
+    pub year: i64,
+    /// The month, from 1 to 12.
+    pub month: u32,
+    /// The day of the month, from 1.
+    pub day: u32,
+    /// The hour, from 0 to 23.
+    pub hour: u32,
+    /// The minute, from 0 to 59.
+    pub minute: u32,
+    /// The second, from 0 to 60 for leap seconds.
+    pub second: u32,
+    /// The offset of the zone from UTC, in minutes, ie `-300` for `-0500`.
+    ///
+    /// Unknown zones, including `-0000`, are `0`.
+    pub offset: i32
+}
+
+fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+// The number of days since the 1st of January 1970, in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The year, month and day of a number of days since the 1st of January 1970.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+    let month = (if month < 10 { month + 3 } else { month - 9 }) as u32;
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+impl DateTime {
+    /// Returns the date of a number of seconds since the Unix epoch, in the zone with the
+    /// given offset in minutes.
+    pub fn from_timestamp(timestamp: i64, offset: i32) -> DateTime {
+        let local = timestamp + offset as i64 * 60;
+        let days = if local >= 0 { local / 86400 } else { (local - 86399) / 86400 };
+        let seconds = (local - days * 86400) as u32;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year,
+            month: month,
+            day: day,
+            hour: seconds / 3600,
+            minute: seconds / 60 % 60,
+            second: seconds % 60,
+            offset: offset
+        }
+    }
+
+    /// Returns the current date, in UTC.
+    pub fn now() -> DateTime {
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs() as i64)
+        };
+        DateTime::from_timestamp(timestamp, 0)
+    }
+
+    /// Returns the number of seconds since the Unix epoch.
+    ///
+    /// A leap second is the same as the second after it.
+    pub fn timestamp(&self) -> i64 {
+        let days = days_from_civil(self.year, self.month, self.day);
+        days * 86400 + (self.hour * 3600 + self.minute * 60 + self.second) as i64 - self.offset as i64 * 60
+    }
+
+    /// Returns the day of the week, from 0 for Sunday to 6 for Saturday.
+    pub fn weekday(&self) -> u32 {
+        // The 1st of January 1970 was a Thursday.
+        let days = days_from_civil(self.year, self.month, self.day);
+        ((days % 7 + 11) % 7) as u32
+    }
+
+    /// Parses a date, in the current or the obsolete format.
+    ///
+    /// The day of the week is optional, and isn't checked against the date.
+    pub fn parse(s: &str) -> Result<DateTime, DateTimeError> {
+        let s = try!(normalize(s));
+        let mut rest = s.as_ref();
+        if let Some(comma) = rest.find(',') {
+            let day = rest[.. comma].trim();
+            if !DAYS.iter().any(|name| name.eq_ignore_ascii_case(day)) {
+                return Err(DateTimeError::Syntax);
+            }
+            rest = &rest[comma + 1 ..];
+        }
+
+        let tokens: Vec<&str> = rest.split(' ').filter(|token| token.len() > 0).collect();
+        if tokens.len() != 5 {
+            return Err(DateTimeError::Syntax);
+        }
+        let day = try!(parse_number(tokens[0], 1, 2));
+        let month = match MONTHS.iter().position(|name| name.eq_ignore_ascii_case(tokens[1])) {
+            Some(index) => index as u32 + 1,
+            None => return Err(DateTimeError::Syntax)
+        };
+        let year = try!(parse_number(tokens[2], 2, 9)) as i64;
+        // Two digit years are between 1950 and 2049, three digit ones are after 1900.
+        let year = match tokens[2].len() {
+            2 if year < 50 => year + 2000,
+            2 | 3 => year + 1900,
+            _ => year
+        };
+
+        let mut time = tokens[3].split(':');
+        let hour = try!(parse_number(time.next().unwrap_or(""), 2, 2));
+        let minute = try!(parse_number(time.next().unwrap_or(""), 2, 2));
+        let second = match time.next() {
+            Some(second) => try!(parse_number(second, 2, 2)),
+            None => 0
+        };
+        if time.next().is_some() {
+            return Err(DateTimeError::Syntax);
+        }
+        let offset = try!(parse_zone(tokens[4]));
+
+        if year < 1900 || day == 0 || day > days_in_month(year, month) || hour > 23 || minute > 59 || second > 60 {
+            return Err(DateTimeError::OutOfRange);
+        }
+        Ok(DateTime {
+            year: year,
+            month: month,
+            day: day,
+            hour: hour,
+            minute: minute,
+            second: second,
+            offset: offset
+        })
+    }
+}
+
+// Removes comments, and the spaces around colons, and turns folding whitespace into
+// spaces.
+fn normalize(s: &str) -> Result<String, DateTimeError> {
+    let mut out = String::with_capacity(s.len());
+    let mut depth = 0;
+    let mut escaped = false;
+    for c in s.chars() {
+        if depth > 0 {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 && !out.ends_with(' ') && !out.ends_with(':') {
+                out.push(' ');
+            }
+            continue;
+        }
+        match c {
+            '(' => depth += 1,
+            ')' => return Err(DateTimeError::Syntax),
+            ' ' | '\t' | '\r' | '\n' => {
+                if !out.ends_with(' ') && !out.ends_with(':') {
+                    out.push(' ');
+                }
+            },
+            ':' => {
+                let len = out.trim_right().len();
+                out.truncate(len);
+                out.push(':');
+            },
+            c => out.push(c)
+        }
+    }
+    match depth {
+        0 => Ok(out),
+        _ => Err(DateTimeError::Syntax)
+    }
+}
+
+// Parses a number written with `min` to `max` digits.
+fn parse_number(s: &str, min: usize, max: usize) -> Result<u32, DateTimeError> {
+    if s.len() < min || s.len() > max || !s.chars().all(|c| c >= '0' && c <= '9') {
+        return Err(DateTimeError::Syntax);
+    }
+    s.parse().map_err(|_| DateTimeError::Syntax)
+}
+
+// Parses a zone, returning its offset in minutes.
+fn parse_zone(s: &str) -> Result<i32, DateTimeError> {
+    match s.chars().next() {
+        Some(sign) if sign == '+' || sign == '-' => {
+            let value = try!(parse_number(&s[1 ..], 4, 4)) as i32;
+            if value % 100 > 59 {
+                return Err(DateTimeError::OutOfRange);
+            }
+            let offset = value / 100 * 60 + value % 100;
+            Ok(if sign == '-' { -offset } else { offset })
+        },
+        _ => {
+            if let Some(&(_, offset)) = ZONES.iter().find(|&&(name, _)| name.eq_ignore_ascii_case(s)) {
+                return Ok(offset);
+            }
+            // Military zones were defined with the wrong sign, and other names are
+            // unknown, so they all mean that the offset is unknown.
+            match s.len() > 0 && s.chars().all(|c| (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z')) {
+                true => Ok(0),
+                false => Err(DateTimeError::Syntax)
+            }
+        }
+    }
+}
+
+/// Writes the date in the current format, ie `Thu, 01 Jan 2015 00:00:00 +0000`.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.offset < 0 { '-' } else { '+' };
+        let offset = self.offset.abs();
+        write!(f, "{}, {:02} {} {:04} {:02}:{:02}:{:02} {}{:02}{:02}",
+            DAYS[self.weekday() as usize], self.day, MONTHS[self.month as usize - 1], self.year,
+            self.hour, self.minute, self.second, sign, offset / 60, offset % 60)
+    }
+}
+
+#[test]
+fn test_from_timestamp() {
+    assert_eq!("Thu, 01 Jan 1970 00:00:00 +0000", DateTime::from_timestamp(0, 0).to_string());
+    assert_eq!("Thu, 01 Jan 2015 00:00:00 +0000", DateTime::from_timestamp(1420070400, 0).to_string());
+    assert_eq!("Wed, 31 Dec 2014 19:00:00 -0500", DateTime::from_timestamp(1420070400, -300).to_string());
+    assert_eq!("Mon, 29 Feb 2016 17:30:00 +0530", DateTime::from_timestamp(1456747200, 330).to_string());
+    assert_eq!("Thu, 13 Feb 1969 23:32:00 -0330", DateTime::from_timestamp(-27723480, -210).to_string());
+
+    for &timestamp in [0, 1, -1, 951782400, 4107542399, -2208988800].iter() {
+        for &offset in [0, -300, 840].iter() {
+            assert_eq!(timestamp, DateTime::from_timestamp(timestamp, offset).timestamp());
+        }
+    }
+}
+
+#[test]
+fn test_parse() {
+    let date = DateTime::parse("Fri, 21 Nov 1997 09:55:06 -0600").unwrap();
+    assert_eq!(DateTime { year: 1997, month: 11, day: 21, hour: 9, minute: 55, second: 6, offset: -360 }, date);
+    assert_eq!(880127706, date.timestamp());
+    assert_eq!("Fri, 21 Nov 1997 09:55:06 -0600", date.to_string());
+
+    assert_eq!(Ok(date), DateTime::parse("21 Nov 1997 09:55:06 -0600"));
+    assert_eq!(Ok(1456747200), DateTime::parse("29 feb 2016 12:00 +0000").map(|date| date.timestamp()));
+    assert_eq!(Ok(60), DateTime::parse("Thu, 01 Jan 1970 00:00:60 +0000").map(|date| date.timestamp()));
+
+    // Obsolete formats.
+    assert_eq!(Ok(date), DateTime::parse("Fri, 21 Nov 97 09:55:06 CST"));
+    assert_eq!(Ok(date), DateTime::parse("Fri , 21 Nov 1997 09 : 55 : 06 (Central \\) Time) -0600 (CST)"));
+    assert_eq!(Ok(date), DateTime::parse("Fri,\r\n 21 Nov\r\n 1997 09:55:06 -0600"));
+    assert_eq!(Ok(1997), DateTime::parse("21 Nov 097 09:55:06 GMT").map(|date| date.year));
+    assert_eq!(Ok(2049), DateTime::parse("21 Nov 49 09:55:06 GMT").map(|date| date.year));
+    assert_eq!(Ok(0), DateTime::parse("21 Nov 1997 09:55:06 Z").map(|date| date.offset));
+    assert_eq!(Ok(0), DateTime::parse("21 Nov 1997 09:55:06 -0000").map(|date| date.offset));
+
+    assert_eq!(Err(DateTimeError::Syntax), DateTime::parse(""));
+    assert_eq!(Err(DateTimeError::Syntax), DateTime::parse("Fry, 21 Nov 1997 09:55:06 -0600"));
+    assert_eq!(Err(DateTimeError::Syntax), DateTime::parse("21 November 1997 09:55:06 -0600"));
+    assert_eq!(Err(DateTimeError::Syntax), DateTime::parse("21 Nov 1997 9:55:06 -0600"));
+    assert_eq!(Err(DateTimeError::Syntax), DateTime::parse("21 Nov 1997 09:55:06"));
+    assert_eq!(Err(DateTimeError::Syntax), DateTime::parse("21 Nov 1997 09:55:06 -06"));
+    assert_eq!(Err(DateTimeError::Syntax), DateTime::parse("21 Nov 1997 09:55:06 -0600 (CST"));
+    assert_eq!(Err(DateTimeError::OutOfRange), DateTime::parse("29 Feb 2015 12:00:00 +0000"));
+    assert_eq!(Err(DateTimeError::OutOfRange), DateTime::parse("31 Apr 2015 12:00:00 +0000"));
+    assert_eq!(Err(DateTimeError::OutOfRange), DateTime::parse("30 Apr 2015 24:00:00 +0000"));
+    assert_eq!(Err(DateTimeError::OutOfRange), DateTime::parse("30 Apr 2015 12:00:00 +0060"));
+}
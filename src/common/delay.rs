@@ -0,0 +1,90 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Artificial delays before replies, ie to slow down suspected spam bots or to study how
+//! clients react to slow servers.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut delays = DelayTable::new();
+//! // Accept recipients slowly.
+//! delays.add_rule(Some("RCPT"), Some(250), Duration::from_secs(5));
+//! server.set_delay_policy(delays);
+//! ```
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::time::Duration;
+use std::vec::Vec;
+
+/// Decides how long to wait before sending a reply.
+pub trait DelayPolicy: Send + Sync {
+    /// Returns how long to wait before sending a reply with the given code.
+    ///
+    /// `command` is the verb of the command being replied to, ie `RCPT`, or `None` for
+    /// the greeting and replies to unrecognized commands.
+    fn delay(&self, command: Option<&str>, code: u16) -> Option<Duration>;
+}
+
+/// A `DelayPolicy` made of rules matching the command and the reply code.
+///
+/// The first matching rule applies.
+pub struct DelayTable {
+    rules: Vec<(Option<String>, Option<u16>, Duration)>
+}
+
+impl DelayTable {
+    /// Creates a table without rules, which never delays replies.
+    pub fn new() -> DelayTable {
+        DelayTable {
+            rules: Vec::new()
+        }
+    }
+
+    /// Adds a rule delaying replies to the command with the code. `None` matches any
+    /// command or any code.
+    pub fn add_rule(&mut self, command: Option<&str>, code: Option<u16>, delay: Duration) {
+        self.rules.push((command.map(|command| command.to_owned()), code, delay));
+    }
+}
+
+impl DelayPolicy for DelayTable {
+    fn delay(&self, command: Option<&str>, code: u16) -> Option<Duration> {
+        self.rules.iter().find(|&&(ref rule_command, rule_code, _)| {
+            let command_matches = match (rule_command.as_ref(), command) {
+                (None, _) => true,
+                (Some(expected), Some(command)) => expected.eq_ignore_ascii_case(command),
+                (Some(_), None) => false
+            };
+            command_matches && rule_code.map_or(true, |expected| expected == code)
+        }).map(|&(_, _, delay)| delay)
+    }
+}
+
+#[test]
+fn test_delay_table() {
+    let mut table = DelayTable::new();
+    assert_eq!(None, table.delay(Some("RCPT"), 250));
+
+    table.add_rule(Some("rcpt"), Some(250), Duration::from_secs(5));
+    table.add_rule(None, Some(550), Duration::from_secs(1));
+    table.add_rule(Some("DATA"), None, Duration::from_secs(2));
+    assert_eq!(Some(Duration::from_secs(5)), table.delay(Some("RCPT"), 250));
+    assert_eq!(None, table.delay(Some("MAIL"), 250));
+    assert_eq!(Some(Duration::from_secs(1)), table.delay(Some("RCPT"), 550));
+    assert_eq!(Some(Duration::from_secs(1)), table.delay(None, 550));
+    assert_eq!(Some(Duration::from_secs(2)), table.delay(Some("DATA"), 354));
+    assert_eq!(None, table.delay(None, 220));
+}
@@ -0,0 +1,499 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! DNS lookups, through a `Resolver`.
+//!
+//! The system resolver of the standard library only knows addresses, so `DnsClient`
+//! asks a recursive name server directly, as described
+//! [in RFC 1035](http://tools.ietf.org/html/rfc1035), over UDP. It doesn't retry over
+//! TCP when a response is truncated, and doesn't check DNSSEC, so it should be pointed
+//! at a trusted resolver, ie one on the same host.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let resolver = try!(DnsClient::from_resolv_conf("/etc/resolv.conf"));
+//! for mx in try!(resolver.lookup_mx("rustastic.org")) {
+//!     println!("{} {}", mx.preference, mx.exchange);
+//! }
+//! ```
+
+use std::borrow::ToOwned;
+use std::fs::File;
+use std::io::{Read, Error as IoError, ErrorKind};
+use std::io::Result as IoResult;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::vec::Vec;
+use super::id::random_u64;
+#[cfg(test)]
+use std::thread;
+
+// The largest response over UDP without EDNS.
+static MAX_UDP_SIZE: usize = 512;
+
+// How many compression pointers are followed in a name, against loops.
+static MAX_POINTERS: usize = 64;
+
+/// Represents an error that occured while looking up a name.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum DnsError {
+    /// The name doesn't exist.
+    NotFound,
+    /// The name can't be looked up, ie because a label is longer than 63 octets.
+    InvalidName,
+    /// The lookup failed, and may succeed later, ie because the server timed out.
+    Temporary(String)
+}
+
+/// A mail exchanger of a domain.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct MxRecord {
+    /// The preference, lower is tried first.
+    pub preference: u16,
+    /// The name of the host, without the final dot.
+    pub exchange: String
+}
+
+/// The types of records that can be looked up.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum RecordType {
+    /// IPv4 addresses.
+    A,
+    /// IPv6 addresses.
+    Aaaa,
+    /// Mail exchangers.
+    Mx,
+    /// Names of addresses, under `in-addr.arpa` and `ip6.arpa`.
+    Ptr
+}
+
+impl RecordType {
+    /// Returns the code of the type on the wire.
+    pub fn code(&self) -> u16 {
+        match *self {
+            RecordType::A => 1,
+            RecordType::Ptr => 12,
+            RecordType::Mx => 15,
+            RecordType::Aaaa => 28
+        }
+    }
+}
+
+/// The data of a record.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum RecordData {
+    /// An IPv4 address.
+    A(Ipv4Addr),
+    /// An IPv6 address.
+    Aaaa(Ipv6Addr),
+    /// A mail exchanger.
+    Mx(MxRecord),
+    /// The name of an address, without the final dot.
+    Ptr(String)
+}
+
+/// Looks up names.
+pub trait Resolver: Send + Sync {
+    /// Returns the MX records of a domain, in no particular order.
+    ///
+    /// An existing domain without MX records has none, rather than `NotFound`.
+    fn lookup_mx(&self, domain: &str) -> Result<Vec<MxRecord>, DnsError>;
+
+    /// Returns the IPv4 and IPv6 addresses of a host.
+    fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>, DnsError>;
+
+    /// Returns the names of an address, from its PTR records.
+    ///
+    /// Resolvers that can't look up names find none.
+    #[allow(unused_variables)]
+    fn lookup_ptr(&self, ip: &IpAddr) -> Result<Vec<String>, DnsError> {
+        Err(DnsError::NotFound)
+    }
+}
+
+/// A shared resolver, ie by a `MxRouter` and the reverse DNS lookups of a server.
+impl<R: Resolver + ?Sized> Resolver for Arc<R> {
+    fn lookup_mx(&self, domain: &str) -> Result<Vec<MxRecord>, DnsError> {
+        (**self).lookup_mx(domain)
+    }
+
+    fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>, DnsError> {
+        (**self).lookup_ip(host)
+    }
+
+    fn lookup_ptr(&self, ip: &IpAddr) -> Result<Vec<String>, DnsError> {
+        (**self).lookup_ptr(ip)
+    }
+}
+
+/// Returns the name of the PTR records of an address, ie `1.2.0.192.in-addr.arpa` for
+/// `192.0.2.1`.
+pub fn reverse_name(ip: &IpAddr) -> String {
+    match *ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", octets[3], octets[2], octets[1], octets[0])
+        },
+        IpAddr::V6(ip) => {
+            let mut name = String::with_capacity(72);
+            for segment in ip.segments().iter().rev() {
+                for shift in [0, 4, 8, 12].iter() {
+                    name.push_str(format!("{:x}.", (segment >> shift) & 0xf).as_ref());
+                }
+            }
+            name + "ip6.arpa"
+        }
+    }
+}
+
+/// Writes a query for the records of a type, with the given id.
+pub fn encode_query(id: u16, name: &str, record_type: RecordType) -> Result<Vec<u8>, DnsError> {
+    let name = name.trim_right_matches('.');
+    if name.len() == 0 || name.len() > 253 {
+        return Err(DnsError::InvalidName);
+    }
+    let mut query = vec![
+        (id >> 8) as u8, id as u8,
+        // A standard query, recursion desired.
+        0x01, 0x00,
+        // One question, no records.
+        0, 1, 0, 0, 0, 0, 0, 0
+    ];
+    for label in name.split('.') {
+        if label.len() == 0 || label.len() > 63 {
+            return Err(DnsError::InvalidName);
+        }
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes().iter().cloned());
+    }
+    let code = record_type.code();
+    // The root, the type, and the Internet class.
+    query.extend([0, (code >> 8) as u8, code as u8, 0, 1].iter().cloned());
+    Ok(query)
+}
+
+fn malformed() -> DnsError {
+    DnsError::Temporary("malformed response".to_owned())
+}
+
+fn read_u16(message: &[u8], pos: usize) -> Result<u16, DnsError> {
+    match message.len() >= pos + 2 {
+        true => Ok((message[pos] as u16) << 8 | message[pos + 1] as u16),
+        false => Err(malformed())
+    }
+}
+
+// Reads a name, following compression pointers, returning it and the position after it.
+fn read_name(message: &[u8], mut pos: usize) -> Result<(String, usize), DnsError> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = match message.get(pos) {
+            Some(&len) => len as usize,
+            None => return Err(malformed())
+        };
+        if len & 0xc0 == 0xc0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return Err(malformed());
+            }
+            let target = try!(read_u16(message, pos)) as usize & 0x3fff;
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            pos = target;
+            continue;
+        }
+        if len == 0 {
+            return Ok((name, end.unwrap_or(pos + 1)));
+        }
+        if message.len() < pos + 1 + len {
+            return Err(malformed());
+        }
+        if name.len() > 0 {
+            name.push('.');
+        }
+        name.push_str(String::from_utf8_lossy(&message[pos + 1 .. pos + 1 + len]).as_ref());
+        pos += 1 + len;
+    }
+}
+
+/// Reads the records of a type in the answer to a query with the given id.
+///
+/// Records of other types, ie the CNAME records leading to the answer, are skipped.
+pub fn decode_response(id: u16, message: &[u8], record_type: RecordType) -> Result<Vec<RecordData>, DnsError> {
+    if message.len() < 12 || try!(read_u16(message, 0)) != id || message[2] & 0x80 == 0 {
+        return Err(malformed());
+    }
+    if message[2] & 0x02 != 0 {
+        return Err(DnsError::Temporary("truncated response".to_owned()));
+    }
+    match message[3] & 0x0f {
+        0 => {},
+        3 => return Err(DnsError::NotFound),
+        rcode => return Err(DnsError::Temporary(format!("server failure, rcode {}", rcode)))
+    }
+    let questions = try!(read_u16(message, 4));
+    let answers = try!(read_u16(message, 6));
+
+    let mut pos = 12;
+    for _ in 0 .. questions {
+        pos = try!(read_name(message, pos)).1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0 .. answers {
+        pos = try!(read_name(message, pos)).1;
+        let code = try!(read_u16(message, pos));
+        let len = try!(read_u16(message, pos + 8)) as usize;
+        let start = pos + 10;
+        if message.len() < start + len {
+            return Err(malformed());
+        }
+        let data = &message[start .. start + len];
+        pos = start + len;
+        if code != record_type.code() {
+            continue;
+        }
+        records.push(match record_type {
+            RecordType::A if len == 4 => RecordData::A(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+            RecordType::Aaaa if len == 16 => {
+                let mut segments = [0u16; 8];
+                for (i, segment) in segments.iter_mut().enumerate() {
+                    *segment = (data[2 * i] as u16) << 8 | data[2 * i + 1] as u16;
+                }
+                RecordData::Aaaa(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                    segments[4], segments[5], segments[6], segments[7]))
+            },
+            RecordType::Mx if len >= 3 => RecordData::Mx(MxRecord {
+                preference: try!(read_u16(message, start)),
+                exchange: try!(read_name(message, start + 2)).0
+            }),
+            RecordType::Ptr if len >= 1 => RecordData::Ptr(try!(read_name(message, start)).0),
+            _ => return Err(malformed())
+        });
+    }
+    Ok(records)
+}
+
+/// Returns the first name server of a `resolv.conf` file.
+pub fn parse_resolv_conf(conf: &str) -> Option<IpAddr> {
+    conf.lines().filter_map(|line| {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("nameserver") => words.next().and_then(|ip| ip.parse().ok()),
+            _ => None
+        }
+    }).next()
+}
+
+/// A `Resolver` asking a recursive name server, see `common::dns`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct DnsClient {
+    server: SocketAddr,
+    timeout: Duration,
+    attempts: u32
+}
+
+impl DnsClient {
+    /// Creates a client asking the server, ie `127.0.0.1:53`.
+    ///
+    /// By default, queries are sent twice, and time out after 5 seconds each time.
+    pub fn new(server: SocketAddr) -> DnsClient {
+        DnsClient {
+            server: server,
+            timeout: Duration::from_secs(5),
+            attempts: 2
+        }
+    }
+
+    /// Creates a client asking the first name server of a `resolv.conf` file, ie
+    /// `/etc/resolv.conf`.
+    pub fn from_resolv_conf<P: AsRef<Path>>(path: P) -> IoResult<DnsClient> {
+        let mut conf = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut conf));
+        match parse_resolv_conf(conf.as_ref()) {
+            Some(ip) => Ok(DnsClient::new(SocketAddr::new(ip, 53))),
+            None => Err(IoError::new(ErrorKind::InvalidData, "no name server"))
+        }
+    }
+
+    /// Sets how long to wait for each attempt, and how many attempts to make.
+    pub fn set_timeout(&mut self, timeout: Duration, attempts: u32) {
+        self.timeout = timeout;
+        self.attempts = if attempts == 0 { 1 } else { attempts };
+    }
+
+    /// Looks up the records of a type.
+    ///
+    /// An existing name without records of the type has none, rather than `NotFound`.
+    pub fn query(&self, name: &str, record_type: RecordType) -> Result<Vec<RecordData>, DnsError> {
+        let id = random_u64() as u16;
+        let query = try!(encode_query(id, name, record_type));
+        let local = match self.server {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0"
+        };
+        let failed = |err: IoError| DnsError::Temporary(err.to_string());
+        let socket = try!(UdpSocket::bind(local).map_err(&failed));
+        try!(socket.set_read_timeout(Some(self.timeout)).map_err(&failed));
+
+        let mut buffer = [0; MAX_UDP_SIZE];
+        for _ in 0 .. self.attempts {
+            try!(socket.send_to(query.as_ref(), self.server).map_err(&failed));
+            loop {
+                match socket.recv_from(&mut buffer) {
+                    // Responses from elsewhere, or to other queries, are spoofed or late.
+                    Ok((len, from)) => {
+                        if from == self.server && len >= 2 && read_u16(&buffer, 0) == Ok(id) {
+                            return decode_response(id, &buffer[.. len], record_type);
+                        }
+                    },
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => break,
+                    Err(err) => return Err(failed(err))
+                }
+            }
+        }
+        Err(DnsError::Temporary(format!("no response from {}", self.server)))
+    }
+}
+
+impl Resolver for DnsClient {
+    fn lookup_mx(&self, domain: &str) -> Result<Vec<MxRecord>, DnsError> {
+        let records = try!(self.query(domain, RecordType::Mx));
+        Ok(records.into_iter().filter_map(|record| match record {
+            RecordData::Mx(mx) => Some(mx),
+            _ => None
+        }).collect())
+    }
+
+    fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>, DnsError> {
+        let mut ips = Vec::new();
+        for record in try!(self.query(host, RecordType::A)).into_iter().chain(try!(self.query(host, RecordType::Aaaa)).into_iter()) {
+            match record {
+                RecordData::A(ip) => ips.push(IpAddr::V4(ip)),
+                RecordData::Aaaa(ip) => ips.push(IpAddr::V6(ip)),
+                _ => {}
+            }
+        }
+        Ok(ips)
+    }
+
+    fn lookup_ptr(&self, ip: &IpAddr) -> Result<Vec<String>, DnsError> {
+        let records = try!(self.query(reverse_name(ip).as_ref(), RecordType::Ptr));
+        Ok(records.into_iter().filter_map(|record| match record {
+            RecordData::Ptr(name) => Some(name),
+            _ => None
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+fn get_response(id: u16, rcode: u8) -> Vec<u8> {
+    let mut response = encode_query(id, "rustastic.org", RecordType::Mx).unwrap();
+    response[2] |= 0x80;
+    response[3] |= rcode;
+    // Two MX records and a CNAME record.
+    response[7] = 3;
+    response.extend([
+        // rustastic.org, through a pointer to the question.
+        0xc0, 12, 0, 15, 0, 1, 0, 0, 0x0e, 0x10, 0, 9,
+        0, 10, 3, b'm', b'x', b'1', 0xc0, 12
+    ].iter().cloned());
+    response.extend([
+        0xc0, 12, 0, 5, 0, 1, 0, 0, 0x0e, 0x10, 0, 2,
+        0xc0, 12
+    ].iter().cloned());
+    response.extend([
+        0xc0, 12, 0, 15, 0, 1, 0, 0, 0x0e, 0x10, 0, 4,
+        0, 20, 0xc0, 45
+    ].iter().cloned());
+    response
+}
+
+#[test]
+fn test_encode_query() {
+    assert_eq!(vec![
+        0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0,
+        9, b'r', b'u', b's', b't', b'a', b's', b't', b'i', b'c', 3, b'o', b'r', b'g', 0,
+        0, 15, 0, 1
+    ], encode_query(0x1234, "rustastic.org.", RecordType::Mx).unwrap());
+    assert_eq!(Err(DnsError::InvalidName), encode_query(1, "", RecordType::A));
+    assert_eq!(Err(DnsError::InvalidName), encode_query(1, "rustastic..org", RecordType::A));
+    let long = (0 .. 64).map(|_| "a").collect::<String>();
+    assert_eq!(Err(DnsError::InvalidName), encode_query(1, long.as_ref(), RecordType::A));
+}
+
+#[test]
+fn test_decode_response() {
+    assert_eq!(Ok(vec![
+        RecordData::Mx(MxRecord { preference: 10, exchange: "mx1.rustastic.org".to_owned() }),
+        RecordData::Mx(MxRecord { preference: 20, exchange: "mx1.rustastic.org".to_owned() })
+    ]), decode_response(7, get_response(7, 0).as_ref(), RecordType::Mx));
+    assert_eq!(Ok(vec![]), decode_response(7, get_response(7, 0).as_ref(), RecordType::A));
+    assert_eq!(Err(DnsError::NotFound), decode_response(7, get_response(7, 3).as_ref(), RecordType::Mx));
+    assert_eq!(Err(DnsError::Temporary("server failure, rcode 2".to_owned())),
+        decode_response(7, get_response(7, 2).as_ref(), RecordType::Mx));
+    assert!(decode_response(8, get_response(7, 0).as_ref(), RecordType::Mx).is_err());
+
+    let response = get_response(7, 0);
+    assert!(decode_response(7, &response[.. response.len() - 1], RecordType::Mx).is_err());
+
+    // A pointer to itself.
+    let mut response = get_response(7, 0);
+    response[31] = 0xc0;
+    response[32] = 31;
+    assert!(decode_response(7, response.as_ref(), RecordType::Mx).is_err());
+}
+
+#[test]
+fn test_reverse_name() {
+    assert_eq!("1.2.0.192.in-addr.arpa", reverse_name(&IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+    assert_eq!("b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa",
+        reverse_name(&IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0x567, 0x89ab))));
+
+    let mut response = encode_query(7, "1.2.0.192.in-addr.arpa", RecordType::Ptr).unwrap();
+    response[2] |= 0x80;
+    response[7] = 1;
+    response.extend([0xc0, 12, 0, 12, 0, 1, 0, 0, 0x0e, 0x10, 0, 5, 2, b'm', b'x', 0xc0, 16].iter().cloned());
+    assert_eq!(Ok(vec![RecordData::Ptr("mx.0.192.in-addr.arpa".to_owned())]), decode_response(7, response.as_ref(), RecordType::Ptr));
+}
+
+#[test]
+fn test_parse_resolv_conf() {
+    let conf = "# Generated\nsearch rustastic.org\nnameserver  ::1\nnameserver 127.0.0.1\n";
+    assert_eq!(Some(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))), parse_resolv_conf(conf));
+    assert_eq!(None, parse_resolv_conf("search rustastic.org\n"));
+}
+
+#[test]
+fn test_dns_client() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut client = DnsClient::new(server.local_addr().unwrap());
+    client.set_timeout(Duration::from_millis(200), 2);
+    thread::spawn(move || {
+        let mut buffer = [0; MAX_UDP_SIZE];
+        // The first query is lost.
+        server.recv_from(&mut buffer).unwrap();
+        let (_, from) = server.recv_from(&mut buffer).unwrap();
+        let id = read_u16(&buffer, 0).unwrap();
+        server.send_to(get_response(id.wrapping_add(1), 0).as_ref(), from).unwrap();
+        server.send_to(get_response(id, 0).as_ref(), from).unwrap();
+    });
+    let records = client.lookup_mx("rustastic.org").unwrap();
+    assert_eq!(vec![10, 20], records.iter().map(|mx| mx.preference).collect::<Vec<u16>>());
+}
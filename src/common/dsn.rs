@@ -0,0 +1,246 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Delivery status notification parameters, as described
+//! [in RFC 3461](http://tools.ietf.org/html/rfc3461).
+//!
+//! `RET` and `ENVID` are given to MAIL, `NOTIFY` and `ORCPT` to RCPT.
+
+use std::ascii::AsciiExt;
+#[cfg(test)]
+use std::iter::repeat;
+use super::utils::get_atom;
+
+/// The maximum length of the `ENVID` parameter.
+pub static MAX_ENVID_SIZE: usize = 100;
+
+/// The maximum length of the address of the `ORCPT` parameter.
+pub static MAX_ORCPT_SIZE: usize = 500;
+
+/// What a failure notification contains, given with `RET`.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum DsnReturn {
+    /// `RET=FULL`, the whole message.
+    Full,
+    /// `RET=HDRS`, only the headers of the message.
+    Headers
+}
+
+/// When notifications are requested for a recipient, given with `NOTIFY`.
+///
+/// `NOTIFY=NEVER` has all of them set to `false`.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct DsnNotify {
+    /// When the message is delivered.
+    pub success: bool,
+    /// When the message can't be delivered.
+    pub failure: bool,
+    /// When the delivery of the message is delayed.
+    pub delay: bool
+}
+
+/// The original recipient of a message, given with `ORCPT`, ie `rfc822;a@b`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct OriginalRecipient {
+    /// The type of the address, ie `rfc822`.
+    pub address_type: String,
+    /// The address, decoded from xtext.
+    pub address: String
+}
+
+/// The DSN parameters of MAIL.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct SenderDsn {
+    /// What failure notifications contain.
+    pub ret: Option<DsnReturn>,
+    /// The envelope identifier, decoded from xtext.
+    pub envid: Option<String>
+}
+
+/// The DSN parameters of RCPT.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct RecipientDsn {
+    /// When notifications are requested.
+    pub notify: Option<DsnNotify>,
+    /// The original recipient.
+    pub orcpt: Option<OriginalRecipient>
+}
+
+fn decode_hex(c: u8) -> Option<u8> {
+    match c {
+        b'0' ... b'9' => Some(c - b'0'),
+        b'A' ... b'F' => Some(c - b'A' + 10),
+        _ => None
+    }
+}
+
+/// Decodes xtext, as described [in RFC 3461](http://tools.ietf.org/html/rfc3461#section-4).
+///
+/// Characters must be printable US-ASCII other than `+` and `=`, or be encoded as `+`
+/// followed by 2 uppercase hexadecimal digits.
+pub fn decode_xtext(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = String::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => {
+                if i + 2 >= bytes.len() {
+                    return None;
+                }
+                let c = match (decode_hex(bytes[i + 1]), decode_hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => (high << 4) | low,
+                    _ => return None
+                };
+                if c > 127 {
+                    return None;
+                }
+                out.push(c as char);
+                i += 3;
+            },
+            b'=' => return None,
+            c if c >= b'!' && c <= b'~' => {
+                out.push(c as char);
+                i += 1;
+            },
+            _ => return None
+        }
+    }
+    Some(out)
+}
+
+/// Encodes US-ASCII text as xtext.
+pub fn encode_xtext(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '!' ... '~' if c != '+' && c != '=' => out.push(c),
+            _ => out.push_str(format!("+{:02X}", c as u32).as_ref())
+        }
+    }
+    out
+}
+
+#[test]
+fn test_xtext() {
+    assert_eq!(Some("".to_owned()), decode_xtext(""));
+    assert_eq!(Some("a+b=c d".to_owned()), decode_xtext("a+2Bb+3Dc+20d"));
+    assert_eq!(None, decode_xtext("a+2bb"));
+    assert_eq!(None, decode_xtext("a+2"));
+    assert_eq!(None, decode_xtext("a+"));
+    assert_eq!(None, decode_xtext("a=b"));
+    assert_eq!(None, decode_xtext("a b"));
+    assert_eq!(None, decode_xtext("+C3+A9"));
+    assert_eq!("a+2Bb+3Dc+20d", encode_xtext("a+b=c d"));
+    assert_eq!(Some("rust@rustastic.org".to_owned()), decode_xtext(encode_xtext("rust@rustastic.org").as_ref()));
+}
+
+/// Parses the value of `RET`, ie `FULL` or `HDRS`.
+pub fn parse_ret(value: &str) -> Option<DsnReturn> {
+    if value.eq_ignore_ascii_case("FULL") {
+        Some(DsnReturn::Full)
+    } else if value.eq_ignore_ascii_case("HDRS") {
+        Some(DsnReturn::Headers)
+    } else {
+        None
+    }
+}
+
+/// Parses the value of `ENVID`, ie `QQ314159`.
+pub fn parse_envid(value: &str) -> Option<String> {
+    match value.len() > 0 && value.len() <= MAX_ENVID_SIZE {
+        true => decode_xtext(value),
+        false => None
+    }
+}
+
+#[test]
+fn test_parse_ret_envid() {
+    assert_eq!(Some(DsnReturn::Full), parse_ret("FULL"));
+    assert_eq!(Some(DsnReturn::Headers), parse_ret("hdrs"));
+    assert_eq!(None, parse_ret("HEADERS"));
+    assert_eq!(Some("QQ314159".to_owned()), parse_envid("QQ314159"));
+    assert_eq!(Some("a b".to_owned()), parse_envid("a+20b"));
+    assert_eq!(None, parse_envid(""));
+    assert_eq!(None, parse_envid(repeat('a').take(MAX_ENVID_SIZE + 1).collect::<String>().as_ref()));
+}
+
+/// Parses the value of `NOTIFY`, ie `NEVER` or `SUCCESS,FAILURE`.
+pub fn parse_notify(value: &str) -> Option<DsnNotify> {
+    let mut notify = DsnNotify {
+        success: false,
+        failure: false,
+        delay: false
+    };
+    if value.eq_ignore_ascii_case("NEVER") {
+        return Some(notify);
+    }
+    for keyword in value.split(',') {
+        if keyword.eq_ignore_ascii_case("SUCCESS") {
+            notify.success = true;
+        } else if keyword.eq_ignore_ascii_case("FAILURE") {
+            notify.failure = true;
+        } else if keyword.eq_ignore_ascii_case("DELAY") {
+            notify.delay = true;
+        } else {
+            return None;
+        }
+    }
+    Some(notify)
+}
+
+#[test]
+fn test_parse_notify() {
+    let never = DsnNotify { success: false, failure: false, delay: false };
+    assert_eq!(Some(never), parse_notify("NEVER"));
+    assert_eq!(Some(DsnNotify { success: true, failure: true, delay: false }), parse_notify("success,FAILURE"));
+    assert_eq!(Some(DsnNotify { success: false, failure: false, delay: true }), parse_notify("DELAY"));
+    assert_eq!(None, parse_notify("NEVER,SUCCESS"));
+    assert_eq!(None, parse_notify("SUCCESS,"));
+    assert_eq!(None, parse_notify(""));
+    assert_eq!(None, parse_notify("ALWAYS"));
+}
+
+/// Parses the value of `ORCPT`, ie `rfc822;rust+40rustastic.org`.
+pub fn parse_orcpt(value: &str) -> Option<OriginalRecipient> {
+    let mut parts = value.splitn(2, ';');
+    let address_type = parts.next().unwrap();
+    let address = match parts.next() {
+        Some(address) if address.len() > 0 && address.len() <= MAX_ORCPT_SIZE => address,
+        _ => return None
+    };
+    if get_atom(address_type) != Some(address_type) {
+        return None;
+    }
+    decode_xtext(address).map(|address| {
+        OriginalRecipient {
+            address_type: address_type.to_owned(),
+            address: address
+        }
+    })
+}
+
+#[test]
+fn test_parse_orcpt() {
+    assert_eq!(Some(OriginalRecipient {
+        address_type: "rfc822".to_owned(),
+        address: "rust@rustastic.org".to_owned()
+    }), parse_orcpt("rfc822;rust@rustastic.org"));
+    assert_eq!(Some("a+b@rustastic.org".to_owned()), parse_orcpt("rfc822;a+2Bb@rustastic.org").map(|o| o.address));
+    assert_eq!(None, parse_orcpt("rfc822"));
+    assert_eq!(None, parse_orcpt("rfc822;"));
+    assert_eq!(None, parse_orcpt(";rust@rustastic.org"));
+    assert_eq!(None, parse_orcpt("rfc 822;rust@rustastic.org"));
+    assert_eq!(None, parse_orcpt("rfc822;a+b@rustastic.org"));
+}
@@ -0,0 +1,282 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Generators of unique ids, for sessions, queued messages and Message-ID headers.
+//!
+//! All the generators put the time first, so ids sort roughly by creation time, and are
+//! monotonic within a process. To get ids that are unique across a fleet, use the
+//! randomness of `Ulid` and `UuidV7`, or give each instance its own node to `Snowflake`.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Mints unique ids.
+pub trait IdGenerator: Send + Sync {
+    /// Returns a new id, made of characters allowed in the local part of a Message-ID.
+    fn generate(&self) -> String;
+}
+
+/// Returns a Message-ID, as described
+/// [in RFC 5322](http://tools.ietf.org/html/rfc5322#section-3.6.4), ie
+/// `<01ARZ3NDEKTSV4RRFFQ69G5FAV@rustastic.org>`.
+pub fn message_id(ids: &IdGenerator, domain: &str) -> String {
+    format!("<{}@{}>", ids.generate(), domain)
+}
+
+// Milliseconds since the UNIX epoch. A clock before the epoch counts as the epoch.
+fn now_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1000000) as u64,
+        Err(_) => 0
+    }
+}
+
+/// Returns 64 random bits, from the keys std uses against hash flooding.
+///
+/// They are good enough to avoid collisions, not for cryptography.
+pub fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(now_millis());
+    hasher.finish()
+}
+
+// A timestamp followed by 80 random bits. Within a millisecond, or if the clock goes
+// back, the random bits are incremented instead, so values never go back.
+struct MonotonicState {
+    millis: u64,
+    high: u16,
+    low: u64
+}
+
+impl MonotonicState {
+    fn new() -> MonotonicState {
+        MonotonicState {
+            millis: 0,
+            high: 0,
+            low: 0
+        }
+    }
+
+    fn next(&mut self, now: u64) -> (u64, u16, u64) {
+        if now > self.millis {
+            self.millis = now;
+            self.high = random_u64() as u16;
+            self.low = random_u64();
+        } else {
+            self.low = self.low.wrapping_add(1);
+            if self.low == 0 {
+                self.high = self.high.wrapping_add(1);
+            }
+        }
+        (self.millis, self.high, self.low)
+    }
+}
+
+static CROCKFORD: &'static [u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+// The 26 characters of a ULID, 48 bits of time and 80 bits of randomness.
+fn encode_ulid(millis: u64, high: u16, low: u64) -> String {
+    let mut bytes = [0u8; 16];
+    for i in 0 .. 6 {
+        bytes[i] = (millis >> (8 * (5 - i))) as u8;
+    }
+    bytes[6] = (high >> 8) as u8;
+    bytes[7] = high as u8;
+    for i in 0 .. 8 {
+        bytes[8 + i] = (low >> (8 * (7 - i))) as u8;
+    }
+
+    // 130 bits in groups of 5, the first two bits being zero.
+    let mut out = String::with_capacity(26);
+    for i in 0 .. 26 {
+        let mut value = 0;
+        for j in 0 .. 5 {
+            let bit = (i * 5 + j) as isize - 2;
+            value <<= 1;
+            if bit >= 0 && bytes[bit as usize / 8] & (0x80 >> (bit as usize % 8)) != 0 {
+                value |= 1;
+            }
+        }
+        out.push(CROCKFORD[value] as char);
+    }
+    out
+}
+
+#[test]
+fn test_encode_ulid() {
+    assert_eq!("00000000000000000000000000", encode_ulid(0, 0, 0));
+    assert_eq!("7ZZZZZZZZZZZZZZZZZZZZZZZZZ", encode_ulid(0xffffffffffff, 0xffff, 0xffffffffffffffff));
+    // The example of the ULID specification.
+    assert_eq!("01ARZ3NDEK", &encode_ulid(1469922850259, 0, 0)[.. 10]);
+    assert_eq!("0000000000000000000000000Z", encode_ulid(0, 0, 31));
+}
+
+/// Generates [ULIDs](https://github.com/ulid/spec), ie `01ARZ3NDEKTSV4RRFFQ69G5FAV`.
+///
+/// This is the default generator of the server.
+pub struct Ulid {
+    state: Mutex<MonotonicState>
+}
+
+impl Ulid {
+    /// Creates a generator.
+    pub fn new() -> Ulid {
+        Ulid {
+            state: Mutex::new(MonotonicState::new())
+        }
+    }
+}
+
+impl IdGenerator for Ulid {
+    fn generate(&self) -> String {
+        let (millis, high, low) = self.state.lock().unwrap().next(now_millis());
+        encode_ulid(millis, high, low)
+    }
+}
+
+// A version 7 UUID, with the low 74 of the 80 random bits.
+fn encode_uuid_v7(millis: u64, high: u16, low: u64) -> String {
+    let rand_a = ((high as u64) << 2 | low >> 62) & 0xfff;
+    let rand_b = low & 0x3fffffffffffffff;
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        (millis >> 16) & 0xffffffff,
+        millis & 0xffff,
+        0x7000 | rand_a,
+        0x8000 | (rand_b >> 48),
+        rand_b & 0xffffffffffff
+    )
+}
+
+#[test]
+fn test_encode_uuid_v7() {
+    assert_eq!("00000000-0000-7000-8000-000000000000", encode_uuid_v7(0, 0, 0));
+    assert_eq!("01563e3a-b5d3-7fff-bfff-ffffffffffff", encode_uuid_v7(1469922850259, 0xffff, 0xffffffffffffffff));
+    assert_eq!("00000000-0000-7001-8000-000000000001", encode_uuid_v7(0, 0, 0x4000000000000001));
+}
+
+/// Generates version 7 UUIDs, as described
+/// [in RFC 9562](http://tools.ietf.org/html/rfc9562#section-5.7), ie
+/// `01563e3a-b5d3-7a3b-8c4d-5e6f70819203`.
+pub struct UuidV7 {
+    state: Mutex<MonotonicState>
+}
+
+impl UuidV7 {
+    /// Creates a generator.
+    pub fn new() -> UuidV7 {
+        UuidV7 {
+            state: Mutex::new(MonotonicState::new())
+        }
+    }
+}
+
+impl IdGenerator for UuidV7 {
+    fn generate(&self) -> String {
+        let (millis, high, low) = self.state.lock().unwrap().next(now_millis());
+        encode_uuid_v7(millis, high, low)
+    }
+}
+
+/// The largest node of a `Snowflake` generator.
+pub static MAX_SNOWFLAKE_NODE: u16 = 1023;
+
+/// Generates 64 bits ids made of 41 bits of time since an epoch, 10 bits of node and 12
+/// bits of sequence, written in decimal.
+///
+/// Ids are unique as long as every instance has its own node. At most 4096 ids are
+/// generated per millisecond, generating more waits for the next millisecond.
+pub struct Snowflake {
+    node: u64,
+    epoch: u64,
+    // The last millisecond since the epoch, and the sequence within it.
+    state: Mutex<(u64, u64)>
+}
+
+impl Snowflake {
+    /// Creates a generator for a node, up to `MAX_SNOWFLAKE_NODE`, with the epoch in
+    /// milliseconds since the UNIX epoch.
+    ///
+    /// Returns `None` if the node is too large.
+    pub fn new(node: u16, epoch: u64) -> Option<Snowflake> {
+        match node <= MAX_SNOWFLAKE_NODE {
+            true => Some(Snowflake {
+                node: node as u64,
+                epoch: epoch,
+                state: Mutex::new((0, 0))
+            }),
+            false => None
+        }
+    }
+
+    fn next(&self, mut now: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let millis = now.saturating_sub(self.epoch);
+            if millis > state.0 {
+                *state = (millis, 0);
+                break;
+            }
+            // Within the same millisecond, or the clock went back.
+            if state.1 < 4095 {
+                state.1 += 1;
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+            now = now_millis();
+        }
+        (state.0 & 0x1ffffffffff) << 22 | self.node << 12 | state.1
+    }
+}
+
+impl IdGenerator for Snowflake {
+    fn generate(&self) -> String {
+        format!("{}", self.next(now_millis()))
+    }
+}
+
+#[test]
+fn test_snowflake() {
+    assert!(Snowflake::new(1024, 0).is_none());
+    let snowflake = Snowflake::new(5, 1000).unwrap();
+    assert_eq!(1 << 22 | 5 << 12, snowflake.next(1001));
+    assert_eq!(1 << 22 | 5 << 12 | 1, snowflake.next(1001));
+    // The clock going back doesn't go back in ids.
+    assert_eq!(1 << 22 | 5 << 12 | 2, snowflake.next(900));
+    assert_eq!(2 << 22 | 5 << 12, snowflake.next(1002));
+}
+
+#[test]
+fn test_generators() {
+    let generators: Vec<Box<IdGenerator>> = vec![Box::new(Ulid::new()), Box::new(UuidV7::new())];
+    for generator in generators.iter() {
+        let ids: Vec<String> = (0 .. 1000).map(|_| generator.generate()).collect();
+        for pair in ids.windows(2) {
+            assert!(pair[0] < pair[1]);
+        }
+    }
+    let snowflake = Snowflake::new(1, 0).unwrap();
+    let ids: Vec<u64> = (0 .. 5000).map(|_| snowflake.generate().parse().unwrap()).collect();
+    for pair in ids.windows(2) {
+        assert!(pair[0] < pair[1]);
+    }
+
+    let id = message_id(&Ulid::new(), "rustastic.org");
+    assert_eq!(42, id.len());
+    assert!(id.starts_with("<") && id.ends_with("@rustastic.org>"));
+}
@@ -0,0 +1,271 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Internationalized domain names, ie `bücher.example`, as described
+//! [in RFC 5890](http://tools.ietf.org/html/rfc5890).
+//!
+//! A label with non-ASCII characters, a U-label, is written in ASCII as an A-label: `xn--`
+//! followed by its Punycode encoding, as described
+//! [in RFC 3492](http://tools.ietf.org/html/rfc3492). Servers that don't advertise
+//! `SMTPUTF8` only take A-labels.
+//!
+//! Labels are lowercased before being encoded, but the other mappings and checks of IDNA,
+//! ie for disallowed characters, are not done.
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::char;
+use std::vec::Vec;
+
+static BASE: u32 = 36;
+static TMIN: u32 = 1;
+static TMAX: u32 = 26;
+static SKEW: u32 = 38;
+static DAMP: u32 = 700;
+static INITIAL_BIAS: u32 = 72;
+static INITIAL_N: u32 = 128;
+
+/// The prefix of A-labels.
+pub static ACE_PREFIX: &'static str = "xn--";
+
+/// The longest label, in octets.
+static MAX_LABEL_LEN: usize = 63;
+
+// Adapts the bias after a code point, see section 6.1 of RFC 3492.
+fn adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = match first {
+        true => delta / DAMP,
+        false => delta / 2
+    };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
+
+// Returns the threshold for the digit at position `k`.
+fn threshold(k: u32, bias: u32) -> u32 {
+    match k <= bias {
+        true => TMIN,
+        false if k >= bias + TMAX => TMAX,
+        false => k - bias
+    }
+}
+
+fn encode_digit(digit: u32) -> char {
+    match digit {
+        0 ... 25 => (b'a' + digit as u8) as char,
+        _ => (b'0' + (digit - 26) as u8) as char
+    }
+}
+
+fn decode_digit(c: char) -> Option<u32> {
+    match c {
+        'a' ... 'z' => Some(c as u32 - 'a' as u32),
+        'A' ... 'Z' => Some(c as u32 - 'A' as u32),
+        '0' ... '9' => Some(c as u32 - '0' as u32 + 26),
+        _ => None
+    }
+}
+
+/// Encodes text with Punycode, ie `bcher-kva` for `bücher`.
+///
+/// Returns `None` if the text is too long to be encoded.
+pub fn encode(input: &str) -> Option<String> {
+    let points: Vec<u32> = input.chars().map(|c| c as u32).collect();
+    let mut output: String = input.chars().filter(|c| c.is_ascii()).collect();
+    let basic = output.len() as u32;
+    let mut handled = basic;
+    if basic > 0 {
+        output.push('-');
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    while (handled as usize) < points.len() {
+        let m = points.iter().cloned().filter(|&point| point >= n).min().unwrap();
+        delta = match (m - n).checked_mul(handled + 1).and_then(|more| delta.checked_add(more)) {
+            Some(delta) => delta,
+            None => return None
+        };
+        n = m;
+        for &point in points.iter() {
+            if point < n {
+                delta = match delta.checked_add(1) {
+                    Some(delta) => delta,
+                    None => return None
+                };
+            }
+            if point == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = threshold(k, bias);
+                    if q < t {
+                        break;
+                    }
+                    output.push(encode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(encode_digit(q));
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta += 1;
+        n += 1;
+    }
+    Some(output)
+}
+
+/// Decodes Punycode, ie `bücher` for `bcher-kva`.
+///
+/// Returns `None` if the input isn't valid Punycode.
+pub fn decode(input: &str) -> Option<String> {
+    if !input.is_ascii() {
+        return None;
+    }
+    let (basic, digits) = match input.rfind('-') {
+        Some(pos) => (&input[.. pos], &input[pos + 1 ..]),
+        None => ("", input)
+    };
+    let mut output: Vec<char> = basic.chars().collect();
+
+    let mut n = INITIAL_N;
+    let mut i: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut digits = digits.chars();
+    let mut next = digits.next();
+    while next.is_some() {
+        let old_i = i;
+        let mut w: u32 = 1;
+        let mut k = BASE;
+        loop {
+            let digit = match next.and_then(decode_digit) {
+                Some(digit) => digit,
+                None => return None
+            };
+            next = digits.next();
+            i = match digit.checked_mul(w).and_then(|more| i.checked_add(more)) {
+                Some(i) => i,
+                None => return None
+            };
+            let t = threshold(k, bias);
+            if digit < t {
+                break;
+            }
+            w = match w.checked_mul(BASE - t) {
+                Some(w) => w,
+                None => return None
+            };
+            k += BASE;
+        }
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - old_i, len, old_i == 0);
+        n = match n.checked_add(i / len) {
+            Some(n) => n,
+            None => return None
+        };
+        i %= len;
+        // Basic code points must be given before the delimiter.
+        match char::from_u32(n) {
+            Some(c) if n >= INITIAL_N => output.insert(i as usize, c),
+            _ => return None
+        }
+        i += 1;
+    }
+    Some(output.into_iter().collect())
+}
+
+/// Converts the U-labels of a domain to A-labels, ie `xn--bcher-kva.example` for
+/// `bücher.example`. ASCII labels are kept as they are.
+///
+/// Returns `None` if a label is empty or too long once converted.
+pub fn to_ascii(domain: &str) -> Option<String> {
+    let mut labels = Vec::new();
+    for label in domain.split('.') {
+        let label = match label.is_ascii() {
+            true => label.to_owned(),
+            false => match encode(label.to_lowercase().as_ref()) {
+                Some(encoded) => format!("{}{}", ACE_PREFIX, encoded),
+                None => return None
+            }
+        };
+        if label.len() == 0 || label.len() > MAX_LABEL_LEN {
+            return None;
+        }
+        labels.push(label);
+    }
+    Some(labels.join("."))
+}
+
+/// Converts the A-labels of a domain to U-labels, ie `bücher.example` for
+/// `xn--bcher-kva.example`. Other labels are kept as they are.
+///
+/// Returns `None` if an A-label isn't valid Punycode.
+pub fn to_unicode(domain: &str) -> Option<String> {
+    let mut labels = Vec::new();
+    for label in domain.split('.') {
+        let is_a_label = label.len() > ACE_PREFIX.len() && label[.. ACE_PREFIX.len()].eq_ignore_ascii_case(ACE_PREFIX);
+        labels.push(match is_a_label {
+            true => match decode(&label[ACE_PREFIX.len() ..]) {
+                Some(decoded) => decoded,
+                None => return None
+            },
+            false => label.to_owned()
+        });
+    }
+    Some(labels.join("."))
+}
+
+#[test]
+fn test_punycode() {
+    for &(decoded, encoded) in [
+        ("bücher", "bcher-kva"),
+        ("münchen", "mnchen-3ya"),
+        ("büc-her", "bc-her-3ya"),
+        ("ü", "tda"),
+        ("他们为什么不说中文", "ihqwcrb4cv8a8dqg056pqjye"),
+        ("rustastic", "rustastic-")
+    ].iter() {
+        assert_eq!(Some(encoded.to_owned()), encode(decoded));
+        assert_eq!(Some(decoded.to_owned()), decode(encoded));
+    }
+    assert_eq!(Some("bücher".to_owned()), decode("bcher-KVA"));
+    assert_eq!(None, decode("bcher-kv!"));
+    assert_eq!(None, decode("bcher-kv"));
+    assert_eq!(None, decode("bücher-kva"));
+    assert_eq!(None, decode("99999999999999"));
+}
+
+#[test]
+fn test_domains() {
+    assert_eq!(Some("xn--bcher-kva.example".to_owned()), to_ascii("bücher.example"));
+    assert_eq!(Some("xn--bcher-kva.example".to_owned()), to_ascii("Bücher.example"));
+    assert_eq!(Some("Rustastic.org".to_owned()), to_ascii("Rustastic.org"));
+    assert_eq!(None, to_ascii("bücher..example"));
+    let long: String = (0 .. 60).map(|_| 'ü').collect();
+    assert_eq!(None, to_ascii(long.as_ref()));
+
+    assert_eq!(Some("bücher.example".to_owned()), to_unicode("xn--bcher-kva.example"));
+    assert_eq!(Some("münchen.bücher.example".to_owned()), to_unicode("XN--mnchen-3ya.xn--bcher-kva.example"));
+    assert_eq!(Some("rustastic.org".to_owned()), to_unicode("rustastic.org"));
+    assert_eq!(None, to_unicode("xn--bcher-kv!.example"));
+}
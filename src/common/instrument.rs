@@ -0,0 +1,178 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Measurements of the traffic of a connection, taken by wrapping its stream.
+//!
+//! The statistics are shared, so the reading and the writing half of a connection can
+//! count into the same place, and keep counting when the stream is replaced, ie by
+//! STARTTLS.
+
+use std::io::{Read, Write};
+use std::io::Result as IoResult;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+#[cfg(test)]
+use std::net::{TcpListener, TcpStream};
+#[cfg(test)]
+use super::stream::InputStream;
+
+/// The traffic of a connection so far.
+pub struct StreamStats {
+    bytes_in: AtomicUsize,
+    bytes_out: AtomicUsize,
+    started: Instant,
+    last_activity: Mutex<Instant>
+}
+
+impl StreamStats {
+    /// Creates statistics for a connection that just opened.
+    pub fn new() -> StreamStats {
+        let now = Instant::now();
+        StreamStats {
+            bytes_in: AtomicUsize::new(0),
+            bytes_out: AtomicUsize::new(0),
+            started: now,
+            last_activity: Mutex::new(now)
+        }
+    }
+
+    /// Returns the number of octets read.
+    pub fn bytes_in(&self) -> usize {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of octets written.
+    pub fn bytes_out(&self) -> usize {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Returns when anything was last read or written, or when the connection opened.
+    pub fn last_activity(&self) -> Instant {
+        *self.last_activity.lock().unwrap()
+    }
+
+    /// Returns how long nothing has been read or written.
+    pub fn idle_time(&self) -> Duration {
+        self.last_activity().elapsed()
+    }
+
+    /// Returns how long the connection has been open.
+    pub fn age(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Returns the average number of octets read and written per second, in that order.
+    pub fn throughput(&self) -> (f64, f64) {
+        let age = self.age();
+        let seconds = age.as_secs() as f64 + age.subsec_nanos() as f64 / 1e9;
+        match seconds > 0.0 {
+            true => (self.bytes_in() as f64 / seconds, self.bytes_out() as f64 / seconds),
+            false => (0.0, 0.0)
+        }
+    }
+
+    fn record(&self, counter: &AtomicUsize, bytes: usize) {
+        if bytes > 0 {
+            counter.fetch_add(bytes, Ordering::Relaxed);
+            *self.last_activity.lock().unwrap() = Instant::now();
+        }
+    }
+}
+
+/// A stream counting what goes through it.
+///
+/// Everything else is reached through `Deref`, ie `stream.peer_addr()`.
+pub struct InstrumentedStream<S> {
+    stream: S,
+    stats: Arc<StreamStats>
+}
+
+impl<S> InstrumentedStream<S> {
+    /// Wraps a stream, with new statistics.
+    pub fn new(stream: S) -> InstrumentedStream<S> {
+        InstrumentedStream::with_stats(stream, Arc::new(StreamStats::new()))
+    }
+
+    /// Wraps a stream, counting into existing statistics.
+    pub fn with_stats(stream: S, stats: Arc<StreamStats>) -> InstrumentedStream<S> {
+        InstrumentedStream {
+            stream: stream,
+            stats: stats
+        }
+    }
+
+    /// Returns the statistics of the stream.
+    pub fn stats(&self) -> &Arc<StreamStats> {
+        &self.stats
+    }
+
+    /// Returns the wrapped stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S> Deref for InstrumentedStream<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S: Read> Read for InstrumentedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let bytes = try!(self.stream.read(buf));
+        self.stats.record(&self.stats.bytes_in, bytes);
+        Ok(bytes)
+    }
+}
+
+impl<S: Write> Write for InstrumentedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let bytes = try!(self.stream.write(buf));
+        self.stats.record(&self.stats.bytes_out, bytes);
+        Ok(bytes)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.stream.flush()
+    }
+}
+
+#[test]
+fn test_instrumented_stream() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+
+    // Both halves of a client count into the same statistics.
+    let mut output = InstrumentedStream::new(client.try_clone().unwrap());
+    let stats = output.stats().clone();
+    let mut input = InputStream::new(InstrumentedStream::with_stats(client, stats.clone()), 1000, false);
+    output.write_all(b"EHLO rustastic.org\r\n").unwrap();
+    server.write_all(b"250 OK\r\n").unwrap();
+    assert_eq!(b"250 OK", input.read_line().unwrap());
+    assert_eq!(20, stats.bytes_out());
+    assert_eq!(8, stats.bytes_in());
+    assert!(stats.idle_time() <= stats.age());
+    assert!(output.peer_addr().is_ok());
+
+    let mut read = [0u8; 20];
+    server.read_exact(&mut read).unwrap();
+    assert_eq!(b"EHLO rustastic.org\r\n", &read);
+}
@@ -0,0 +1,449 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! A small JSON codec, as described [in RFC 7159](http://tools.ietf.org/html/rfc7159),
+//! for the JSON lines files used to import and export state.
+//!
+//! Numbers are limited to non-negative integers, which is all the exported state needs.
+
+use std::fmt;
+use std::io::{BufRead, Write, Error as IoError};
+use std::io::Result as IoResult;
+use std::vec::Vec;
+use std::char;
+use std::borrow::ToOwned;
+
+/// A JSON value.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Json {
+    /// `null`.
+    Null,
+    /// `true` or `false`.
+    Bool(bool),
+    /// A non-negative integer.
+    Number(u64),
+    /// A string.
+    String(String),
+    /// An array.
+    Array(Vec<Json>),
+    /// An object, with its members in order.
+    Object(Vec<(String, Json)>)
+}
+
+/// Represents an error that occured while trying to parse JSON.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum JsonError {
+    /// The text ended in the middle of a value.
+    UnexpectedEnd,
+    /// The character at the given byte offset isn't valid there.
+    UnexpectedChar(usize),
+    /// The number at the given byte offset doesn't fit in 64 bits.
+    NumberTooLarge(usize)
+}
+
+impl Json {
+    /// Parses a JSON text, which must hold exactly one value.
+    pub fn parse(s: &str) -> Result<Json, JsonError> {
+        let mut parser = Parser { s: s.as_bytes(), offset: 0 };
+        let value = try!(parser.value());
+        parser.skip_whitespace();
+        match parser.offset == s.len() {
+            true => Ok(value),
+            false => Err(JsonError::UnexpectedChar(parser.offset))
+        }
+    }
+
+    /// Returns the value of a member, if this is an object that has it.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref members) => {
+                members.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref value)| value)
+            },
+            _ => None
+        }
+    }
+
+    /// Returns the string, if this is one.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s.as_ref()),
+            _ => None
+        }
+    }
+
+    /// Returns the number, if this is one.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Json::Number(n) => Some(n),
+            _ => None
+        }
+    }
+
+    /// Returns the boolean, if this is one.
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None
+        }
+    }
+
+    /// Returns the elements, if this is an array.
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match *self {
+            Json::Array(ref elements) => Some(elements.as_ref()),
+            _ => None
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    try!(f.write_str("\""));
+    for c in s.chars() {
+        match c {
+            '"' => try!(f.write_str("\\\"")),
+            '\\' => try!(f.write_str("\\\\")),
+            '\n' => try!(f.write_str("\\n")),
+            '\r' => try!(f.write_str("\\r")),
+            '\t' => try!(f.write_str("\\t")),
+            c if (c as u32) < 0x20 => try!(write!(f, "\\u{:04x}", c as u32)),
+            c => try!(write!(f, "{}", c))
+        }
+    }
+    f.write_str("\"")
+}
+
+/// Writes the value on a single line, without spaces.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(ref s) => write_string(f, s.as_ref()),
+            Json::Array(ref elements) => {
+                try!(f.write_str("["));
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        try!(f.write_str(","));
+                    }
+                    try!(write!(f, "{}", element));
+                }
+                f.write_str("]")
+            },
+            Json::Object(ref members) => {
+                try!(f.write_str("{"));
+                for (i, &(ref key, ref value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        try!(f.write_str(","));
+                    }
+                    try!(write_string(f, key.as_ref()));
+                    try!(write!(f, ":{}", value));
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    offset: usize
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.offset < self.s.len() && (self.s[self.offset] == b' ' || self.s[self.offset] == b'\t' || self.s[self.offset] == b'\r' || self.s[self.offset] == b'\n') {
+            self.offset += 1;
+        }
+    }
+
+    fn peek(&self) -> Result<u8, JsonError> {
+        match self.offset < self.s.len() {
+            true => Ok(self.s[self.offset]),
+            false => Err(JsonError::UnexpectedEnd)
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), JsonError> {
+        match try!(self.peek()) == c {
+            true => {
+                self.offset += 1;
+                Ok(())
+            },
+            false => Err(JsonError::UnexpectedChar(self.offset))
+        }
+    }
+
+    fn literal(&mut self, literal: &str, value: Json) -> Result<Json, JsonError> {
+        for c in literal.bytes() {
+            try!(self.expect(c));
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.skip_whitespace();
+        match try!(self.peek()) {
+            b'n' => self.literal("null", Json::Null),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'"' => self.string().map(Json::String),
+            b'0' ... b'9' => self.number(),
+            b'[' => self.array(),
+            b'{' => self.object(),
+            _ => Err(JsonError::UnexpectedChar(self.offset))
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.offset;
+        let mut n = 0u64;
+        while self.offset < self.s.len() && self.s[self.offset] >= b'0' && self.s[self.offset] <= b'9' {
+            let digit = (self.s[self.offset] - b'0') as u64;
+            n = match n.checked_mul(10).and_then(|n| n.checked_add(digit)) {
+                Some(n) => n,
+                None => return Err(JsonError::NumberTooLarge(start))
+            };
+            self.offset += 1;
+        }
+        // Leading zeros aren't allowed, and fractions and exponents aren't supported.
+        if self.s[start] == b'0' && self.offset > start + 1 {
+            return Err(JsonError::UnexpectedChar(start + 1));
+        }
+        match self.peek() {
+            Ok(b'.') | Ok(b'e') | Ok(b'E') => Err(JsonError::UnexpectedChar(self.offset)),
+            _ => Ok(Json::Number(n))
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let mut n = 0;
+        for _ in 0 .. 4 {
+            let digit = match (try!(self.peek()) as char).to_digit(16) {
+                Some(digit) => digit,
+                None => return Err(JsonError::UnexpectedChar(self.offset))
+            };
+            n = n * 16 + digit;
+            self.offset += 1;
+        }
+        Ok(n)
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        try!(self.expect(b'"'));
+        let mut bytes = Vec::new();
+        loop {
+            let c = try!(self.peek());
+            match c {
+                b'"' => {
+                    self.offset += 1;
+                    break;
+                },
+                b'\\' => {
+                    self.offset += 1;
+                    let escaped = try!(self.peek());
+                    self.offset += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\x08',
+                        b'f' => '\x0c',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => try!(self.unicode_escape()),
+                        _ => return Err(JsonError::UnexpectedChar(self.offset - 1))
+                    };
+                    let mut buf = String::new();
+                    buf.push(c);
+                    bytes.extend(buf.as_bytes().iter().cloned());
+                },
+                c if c < 0x20 => return Err(JsonError::UnexpectedChar(self.offset)),
+                c => {
+                    bytes.push(c);
+                    self.offset += 1;
+                }
+            }
+        }
+        // The input is a `str` and escapes are pushed as UTF-8, so this can't fail.
+        Ok(String::from_utf8(bytes).unwrap())
+    }
+
+    // Decodes `\uXXXX` after the `u`, including surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let start = self.offset;
+        let high = try!(self.hex4());
+        let code = match high {
+            0xd800 ... 0xdbff => {
+                try!(self.expect(b'\\'));
+                try!(self.expect(b'u'));
+                let low = try!(self.hex4());
+                match low {
+                    0xdc00 ... 0xdfff => 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00),
+                    _ => return Err(JsonError::UnexpectedChar(start))
+                }
+            },
+            code => code
+        };
+        char::from_u32(code).ok_or(JsonError::UnexpectedChar(start))
+    }
+
+    fn array(&mut self) -> Result<Json, JsonError> {
+        try!(self.expect(b'['));
+        let mut elements = Vec::new();
+        self.skip_whitespace();
+        if try!(self.peek()) == b']' {
+            self.offset += 1;
+            return Ok(Json::Array(elements));
+        }
+        loop {
+            elements.push(try!(self.value()));
+            self.skip_whitespace();
+            match try!(self.peek()) {
+                b',' => self.offset += 1,
+                b']' => {
+                    self.offset += 1;
+                    return Ok(Json::Array(elements));
+                },
+                _ => return Err(JsonError::UnexpectedChar(self.offset))
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, JsonError> {
+        try!(self.expect(b'{'));
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if try!(self.peek()) == b'}' {
+            self.offset += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = try!(self.string());
+            self.skip_whitespace();
+            try!(self.expect(b':'));
+            let value = try!(self.value());
+            members.push((key, value));
+            self.skip_whitespace();
+            match try!(self.peek()) {
+                b',' => self.offset += 1,
+                b'}' => {
+                    self.offset += 1;
+                    return Ok(Json::Object(members));
+                },
+                _ => return Err(JsonError::UnexpectedChar(self.offset))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(Ok(Json::Null), Json::parse(" null "));
+    assert_eq!(Ok(Json::Bool(false)), Json::parse("false"));
+    assert_eq!(Ok(Json::Number(18446744073709551615)), Json::parse("18446744073709551615"));
+    assert_eq!(Err(JsonError::NumberTooLarge(0)), Json::parse("18446744073709551616"));
+    assert_eq!(Ok(Json::String("a\"b\\c/\n\u{e9}\u{1f600}".to_owned())), Json::parse("\"a\\\"b\\\\c\\/\\n\\u00e9\\ud83d\\ude00\""));
+    assert_eq!(Ok(Json::String("é".to_owned())), Json::parse("\"é\""));
+    let value = Json::parse("{\"key\": \"a\", \"counts\": [[1, 2], []], \"passed\": true}").unwrap();
+    assert_eq!(Some("a"), value.get("key").and_then(|v| v.as_str()));
+    assert_eq!(Some(true), value.get("passed").and_then(|v| v.as_bool()));
+    assert_eq!(Some(2), value.get("counts").and_then(|v| v.as_array()).map(|a| a.len()));
+    assert_eq!(None, value.get("missing"));
+
+    assert_eq!(Err(JsonError::UnexpectedEnd), Json::parse(""));
+    assert_eq!(Err(JsonError::UnexpectedEnd), Json::parse("[1, 2"));
+    assert_eq!(Err(JsonError::UnexpectedChar(3)), Json::parse("[1 2]"));
+    assert_eq!(Err(JsonError::UnexpectedChar(1)), Json::parse("01"));
+    assert_eq!(Err(JsonError::UnexpectedChar(0)), Json::parse("-1"));
+    assert_eq!(Err(JsonError::UnexpectedChar(1)), Json::parse("1.5"));
+    assert_eq!(Err(JsonError::UnexpectedChar(1)), Json::parse("\"\n\""));
+    assert_eq!(Err(JsonError::UnexpectedChar(7)), Json::parse("\"\\ud800\""));
+    assert_eq!(Err(JsonError::UnexpectedChar(5)), Json::parse("null null"));
+}
+
+#[test]
+fn test_display() {
+    let value = Json::Object(vec![
+        ("key".to_owned(), Json::String("a\"\u{1}é".to_owned())),
+        ("counts".to_owned(), Json::Array(vec![Json::Number(1), Json::Null, Json::Bool(true)])),
+        ("empty".to_owned(), Json::Object(vec![]))
+    ]);
+    let text = format!("{}", value);
+    assert_eq!("{\"key\":\"a\\\"\\u0001é\",\"counts\":[1,null,true],\"empty\":{}}", text);
+    assert_eq!(Ok(value), Json::parse(text.as_ref()));
+}
+
+/// Represents an error that occured while importing JSON lines.
+#[derive(Debug)]
+pub enum ImportError {
+    /// Reading the input failed.
+    Io(IoError),
+    /// The line with the given number, starting at 1, isn't valid JSON.
+    Syntax(usize, JsonError),
+    /// The line with the given number, starting at 1, isn't a valid record.
+    Invalid(usize)
+}
+
+/// Writes records as JSON lines, one per line.
+pub fn export_lines<W: Write>(output: &mut W, records: &[Json]) -> IoResult<usize> {
+    for record in records.iter() {
+        try!(writeln!(output, "{}", record));
+    }
+    Ok(records.len())
+}
+
+/// Reads JSON lines, calling `f` with each record, and returns the number of records.
+///
+/// `f` returns `false` if the record is invalid, which stops the import. Blank lines are
+/// skipped.
+pub fn import_lines<R: BufRead, F: FnMut(&Json) -> bool>(input: &mut R, mut f: F) -> Result<usize, ImportError> {
+    let mut count = 0;
+    for (i, line) in input.lines().enumerate() {
+        let line = try!(line.map_err(ImportError::Io));
+        if line.trim().len() == 0 {
+            continue;
+        }
+        let record = try!(Json::parse(line.as_ref()).map_err(|err| ImportError::Syntax(i + 1, err)));
+        if !f(&record) {
+            return Err(ImportError::Invalid(i + 1));
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[test]
+fn test_lines() {
+    let mut output = Vec::new();
+    assert_eq!(2, export_lines(&mut output, &[Json::Number(1), Json::String("a\nb".to_owned())]).unwrap());
+    assert_eq!(b"1\n\"a\\nb\"\n".to_vec(), output);
+
+    let mut records = Vec::new();
+    let input = b"1\n\n\"a\\nb\"\n";
+    assert_eq!(2, import_lines(&mut &input[..], |record| { records.push(record.clone()); true }).unwrap());
+    assert_eq!(vec![Json::Number(1), Json::String("a\nb".to_owned())], records);
+
+    match import_lines(&mut &b"1\n[\n"[..], |_| true) {
+        Err(ImportError::Syntax(2, JsonError::UnexpectedEnd)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+    match import_lines(&mut &b"1\n2\n"[..], |record| record.as_u64() == Some(1)) {
+        Err(ImportError::Invalid(2)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
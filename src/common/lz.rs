@@ -0,0 +1,170 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! A small LZ77 compression format, used by the private `XZDAT` extension to send
+//! messages between instances of this crate.
+//!
+//! The compressed data is a sequence of tokens:
+//!
+//! - a byte `n` below `0x80`, followed by `n + 1` literal octets;
+//! - a byte `0x80 | (len - 3)`, followed by a big-endian 16-bit distance, which copies
+//!   `len` octets starting `distance` octets before the end of the output.
+//!
+//! This is not a standard format, other implementations won't understand it.
+
+use std::vec::Vec;
+#[cfg(test)]
+use std::iter::repeat;
+
+/// The keyword of the extension, advertised in the reply to EHLO.
+///
+/// It starts with `X` as it is private, as described
+/// [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.1.5).
+pub static EXTENSION: &'static str = "XZDAT";
+
+static MIN_MATCH: usize = 3;
+static MAX_MATCH: usize = 130;
+static MAX_LITERALS: usize = 128;
+static MAX_DISTANCE: usize = 65535;
+static HASH_SIZE: usize = 4096;
+
+/// An error that occurs when decompressing data.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum DecompressError {
+    /// The data ends in the middle of a token.
+    Truncated,
+    /// A match refers to octets before the start of the output.
+    InvalidDistance,
+    /// The output would be larger than allowed.
+    TooLarge
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let n = ((data[i] as usize) << 16) | ((data[i + 1] as usize) << 8) | data[i + 2] as usize;
+    (n.wrapping_mul(2654435761) >> 8) % HASH_SIZE
+}
+
+fn flush_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend(chunk.iter().cloned());
+    }
+}
+
+/// Compresses data.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+    // The last position at which each hash of 3 octets was seen, plus one.
+    let mut table = vec![0usize; HASH_SIZE];
+    let mut literals_start = 0;
+    let mut i = 0;
+    while i + MIN_MATCH <= data.len() {
+        let h = hash(data, i);
+        let candidate = table[h];
+        table[h] = i + 1;
+        if candidate > 0 && i - (candidate - 1) <= MAX_DISTANCE {
+            let start = candidate - 1;
+            let mut len = 0;
+            while len < MAX_MATCH && i + len < data.len() && data[start + len] == data[i + len] {
+                len += 1;
+            }
+            if len >= MIN_MATCH {
+                flush_literals(&mut out, &data[literals_start .. i]);
+                let distance = i - start;
+                out.push(0x80 | (len - MIN_MATCH) as u8);
+                out.push((distance >> 8) as u8);
+                out.push(distance as u8);
+                i += len;
+                literals_start = i;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    flush_literals(&mut out, &data[literals_start ..]);
+    out
+}
+
+/// Decompresses data, failing if the output would exceed `max_size` octets.
+pub fn decompress(data: &[u8], max_size: usize) -> Result<Vec<u8>, DecompressError> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let token = data[i] as usize;
+        i += 1;
+        match token < 0x80 {
+            true => {
+                let len = token + 1;
+                if i + len > data.len() {
+                    return Err(DecompressError::Truncated);
+                }
+                if out.len() + len > max_size {
+                    return Err(DecompressError::TooLarge);
+                }
+                out.extend(data[i .. i + len].iter().cloned());
+                i += len;
+            },
+            false => {
+                let len = (token & 0x7f) + MIN_MATCH;
+                if i + 2 > data.len() {
+                    return Err(DecompressError::Truncated);
+                }
+                let distance = ((data[i] as usize) << 8) | data[i + 1] as usize;
+                i += 2;
+                if distance == 0 || distance > out.len() {
+                    return Err(DecompressError::InvalidDistance);
+                }
+                if out.len() + len > max_size {
+                    return Err(DecompressError::TooLarge);
+                }
+                // Matches may overlap the octets they produce, so copy one at a time.
+                let start = out.len() - distance;
+                for j in 0 .. len {
+                    let b = out[start + j];
+                    out.push(b);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[test]
+fn test_compress() {
+    for data in [
+        b"".to_vec(),
+        b"a".to_vec(),
+        b"abcabcabcabcabcabc".to_vec(),
+        b"Subject: Hello\r\n\r\nHello, hello, hello!\r\n".to_vec(),
+        repeat(b'x').take(1000).collect::<Vec<u8>>(),
+        (0 .. 70000).map(|i| (i * 7 % 251) as u8).collect::<Vec<u8>>()
+    ].iter() {
+        assert_eq!(Ok(data.clone()), decompress(compress(data.as_ref()).as_ref(), data.len()));
+    }
+
+    // Repetitive messages get smaller.
+    let data = repeat("Received: from rustastic.org\r\n").take(100).collect::<String>().into_bytes();
+    assert!(compress(data.as_ref()).len() < data.len() / 10);
+}
+
+#[test]
+fn test_decompress() {
+    assert_eq!(Ok(b"abcabca".to_vec()), decompress(&[2, b'a', b'b', b'c', 0x81, 0, 3], 100));
+    assert_eq!(Err(DecompressError::TooLarge), decompress(&[2, b'a', b'b', b'c', 0x81, 0, 3], 6));
+    assert_eq!(Err(DecompressError::Truncated), decompress(&[2, b'a', b'b'], 100));
+    assert_eq!(Err(DecompressError::Truncated), decompress(&[0, b'a', 0x80, 0], 100));
+    assert_eq!(Err(DecompressError::InvalidDistance), decompress(&[0, b'a', 0x80, 0, 2], 100));
+    assert_eq!(Err(DecompressError::InvalidDistance), decompress(&[0, b'a', 0x80, 0, 0], 100));
+}
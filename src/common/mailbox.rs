@@ -15,14 +15,27 @@
 //! Tools to parse and represent an email address in an SMTP transaction.
 
 use std::string::String;
+use std::fmt;
 use super::utils;
+use super::idna;
 use std::net::IpAddr;
-use std::ascii::OwnedAsciiExt;
 use std::ascii::AsciiExt;
 use std::borrow::ToOwned;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+#[cfg(feature = "serde")]
+use serde::de::{self, Visitor, Unexpected};
+#[cfg(all(test, feature = "serde"))]
+use serde::de::IntoDeserializer;
+#[cfg(all(test, feature = "serde"))]
+use serde::de::value::Error as ValueError;
 #[cfg(test)]
 use std::iter::{FromIterator, repeat};
 #[cfg(test)]
+use std::io::{BufRead, BufReader};
+#[cfg(test)]
+use std::fs::File;
+#[cfg(test)]
 use std::net::{Ipv4Addr, Ipv6Addr};
 
 /// Maximum length of the local part.
@@ -44,21 +57,27 @@ fn test_static_vars() {
     assert_eq!(255, MAX_DOMAIN_LEN);
 }
 
-fn get_mailbox_local_part(s: &str) -> Option<&str> {
-    utils::get_dot_string(s).or_else(|| utils::get_quoted_string(s))
+fn get_mailbox_local_part(s: &str, utf8: bool) -> Option<&str> {
+    match utf8 {
+        true => utils::get_utf8_dot_string(s).or_else(|| utils::get_utf8_quoted_string(s)),
+        false => utils::get_dot_string(s).or_else(|| utils::get_quoted_string(s))
+    }
 }
 
 #[test]
 fn test_local_part() {
-    assert_eq!(Some("rust.cool"), get_mailbox_local_part("rust.cool"));
-    assert_eq!(Some("\"rust \\a cool\""), get_mailbox_local_part("\"rust \\a cool\""));
-    assert_eq!(Some("\"rust.cool\""), get_mailbox_local_part("\"rust.cool\""));
-    assert_eq!(Some("\"rust.cool.\""), get_mailbox_local_part("\"rust.cool.\""));
-    assert_eq!(Some("\"rust\\\\\\b\\;.c\\\"ool\""), get_mailbox_local_part("\"rust\\\\\\b\\;.c\\\"ool\""));
+    assert_eq!(Some("rust.cool"), get_mailbox_local_part("rust.cool", false));
+    assert_eq!(Some("\"rust \\a cool\""), get_mailbox_local_part("\"rust \\a cool\"", false));
+    assert_eq!(Some("\"rust.cool\""), get_mailbox_local_part("\"rust.cool\"", false));
+    assert_eq!(Some("\"rust.cool.\""), get_mailbox_local_part("\"rust.cool.\"", false));
+    assert_eq!(Some("\"rust\\\\\\b\\;.c\\\"ool\""), get_mailbox_local_part("\"rust\\\\\\b\\;.c\\\"ool\"", false));
+    assert_eq!(Some("jos"), get_mailbox_local_part("josé", false));
+    assert_eq!(Some("josé"), get_mailbox_local_part("josé", true));
+    assert_eq!(Some("\"josé\""), get_mailbox_local_part("\"josé\"", true));
 }
 
 /// Represents the foreign part of an email address, aka the host.
-#[derive(PartialEq, Eq, Clone, Debug)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug)]
 pub enum MailboxForeignPart {
     /// The foreign part is a domain name.
     Domain(String),
@@ -66,6 +85,18 @@ pub enum MailboxForeignPart {
     IpAddr(IpAddr)
 }
 
+impl fmt::Display for MailboxForeignPart {
+    /// Writes the foreign part as it is sent in a path, ie `rustastic.org`, `[127.0.0.1]`
+    /// or `[IPv6:::1]`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MailboxForeignPart::Domain(ref domain) => f.write_str(domain),
+            MailboxForeignPart::IpAddr(IpAddr::V4(ref ip)) => write!(f, "[{}]", ip),
+            MailboxForeignPart::IpAddr(IpAddr::V6(ref ip)) => write!(f, "[IPv6:{}]", ip)
+        }
+    }
+}
+
 #[test]
 fn test_foreign_part() {
     let domain_text = "rustastic.org";
@@ -77,19 +108,55 @@ fn test_foreign_part() {
     assert!(domain != MailboxForeignPart::Domain(domain_text.to_owned() + "bullshit"));
     assert!(domain != ipv4);
     assert!(domain != ipv6);
+
+    assert_eq!("rustastic.org", domain.to_string());
+    assert_eq!("[127.0.0.1]", ipv4.to_string());
+    assert_eq!("[IPv6:1:1:1:1:1:1:1:1]", ipv6.to_string());
 }
 
 /// Represents an email address, aka "mailbox" in the SMTP spec.
 ///
-/// It is composed of a local part and a foreign part. If the address is sent to the `Postmaster`
-/// address for a domain, then the local part will always be converted `postmaster`, all lowercase.
-/// Since the `Postmaster` address must be handled without regard for case, this makes things simpler.
-#[derive(PartialEq, Eq, Clone, Debug)]
+/// It is composed of a local part and a foreign part. The local part is kept as the client
+/// wrote it, quotes and case included, so that relayed addresses are passed on unchanged.
+/// Use `is_postmaster` to recognize the `Postmaster` address, which is case insensitive.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug)]
 pub struct Mailbox {
     local_part: String,
     foreign_part: MailboxForeignPart
 }
 
+/// How `Mailbox::normalize` turns addresses into a canonical form.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct NormalizeOptions {
+    /// Whether to lowercase the domain, which is case insensitive.
+    pub lowercase_domain: bool,
+    /// Whether to lowercase the local part. The RFC leaves its case to the receiving
+    /// server, but most servers ignore it.
+    pub fold_local_part: bool,
+    /// Whether to remove the quotes of local parts that don't need them, ie `"rust"`.
+    pub unquote: bool,
+    /// The separator after which the detail is removed, ie `+` in
+    /// `rust+smtp@rustastic.org`, see `Mailbox::subaddress`.
+    pub subaddress_separator: Option<char>,
+    /// Whether to convert the U-labels of the domain to A-labels, so that both forms of
+    /// a domain give the same address, see `Mailbox::to_ascii_domain`.
+    pub ascii_domain: bool
+}
+
+impl NormalizeOptions {
+    /// Creates options that only make changes that keep the address the same, by
+    /// lowercasing the domain and removing needless quotes.
+    pub fn new() -> NormalizeOptions {
+        NormalizeOptions {
+            lowercase_domain: true,
+            fold_local_part: false,
+            unquote: true,
+            subaddress_separator: None,
+            ascii_domain: false
+        }
+    }
+}
+
 /// Represents an error that occured while trying to parse an email address.
 #[derive(PartialEq, Eq, Clone, Debug, Copy)]
 pub enum MailboxParseError {
@@ -116,15 +183,202 @@ impl Mailbox {
     /// This function does *not* expect anything to wrap the passed email
     /// address. For example, this will result in an error:
     /// `<hello@world.com>`
+    ///
+    /// Only ASCII is accepted, see `parse_utf8` for internationalized addresses.
     pub fn parse(s: &str) -> Result<Mailbox, MailboxParseError> {
-        let mut local_part: String;
-        let mut foreign_part: MailboxForeignPart;
+        Mailbox::parse_address(s, false)
+    }
+
+    /// Like `parse`, but also accepts UTF-8 local parts and U-label domains, as
+    /// described [in RFC 6531](http://tools.ietf.org/html/rfc6531#section-3.3).
+    ///
+    /// Domains are kept in the form they were given in, see `to_ascii_domain` and
+    /// `to_unicode_domain` to convert them. `parse` accepts A-labels, but not U-labels,
+    /// which can only be sent with `SMTPUTF8`.
+    ///
+    /// This is meant for transactions started with the `SMTPUTF8` parameter. Lengths are
+    /// still counted in octets.
+    pub fn parse_utf8(s: &str) -> Result<Mailbox, MailboxParseError> {
+        Mailbox::parse_address(s, true)
+    }
+
+    /// Returns the local part, ie `rust` in `rust@rustastic.org`.
+    ///
+    /// A quoted local part is returned with its quotes and escapes, see
+    /// `unquoted_local_part`.
+    pub fn local_part(&self) -> &str {
+        self.local_part.as_ref()
+    }
+
+    /// Returns the local part without quotes and escapes, ie `rust "smtp"` for
+    /// `"rust \"smtp\""@rustastic.org`.
+    ///
+    /// This is meant for display and lookups. Relays pass on the local part as it is.
+    pub fn unquoted_local_part(&self) -> String {
+        if !self.local_part.starts_with("\"") {
+            return self.local_part.clone();
+        }
+        let mut unquoted = String::with_capacity(self.local_part.len());
+        let mut escaped = false;
+        for c in self.local_part[1 .. self.local_part.len() - 1].chars() {
+            match (escaped, c) {
+                (false, '\\') => escaped = true,
+                _ => {
+                    unquoted.push(c);
+                    escaped = false;
+                }
+            }
+        }
+        unquoted
+    }
+
+    /// Tells whether this is the `Postmaster` address of its domain, which is case
+    /// insensitive [as per RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.5.1).
+    pub fn is_postmaster(&self) -> bool {
+        self.local_part.eq_ignore_ascii_case("postmaster")
+    }
+
+    /// Returns the address as it is sent in a path, ie `rust@[127.0.0.1]`.
+    ///
+    /// Parsing the result gives the same mailbox, with `parse_utf8` when the address has
+    /// UTF-8 in it. Addresses are written as they were parsed, except for address
+    /// literals, which are written in their usual form with an `IPv6:` tag, and source
+    /// routes, which are dropped.
+    ///
+    /// This is the same as `to_string`, see the `Display` implementation.
+    pub fn to_wire_string(&self) -> String {
+        format!("{}", self)
+    }
+
+    /// Returns the foreign part, ie `rustastic.org` in `rust@rustastic.org`.
+    pub fn foreign_part(&self) -> &MailboxForeignPart {
+        &self.foreign_part
+    }
+
+    /// Returns the domain, ie `rustastic.org` in `rust@rustastic.org`, or `None` when the
+    /// foreign part is an address literal.
+    pub fn domain(&self) -> Option<&str> {
+        match self.foreign_part {
+            MailboxForeignPart::Domain(ref domain) => Some(domain.as_ref()),
+            MailboxForeignPart::IpAddr(_) => None
+        }
+    }
+
+    /// Splits the local part into the base address and the detail, as described
+    /// [in RFC 5233](http://tools.ietf.org/html/rfc5233), ie `rust` and `smtp` in
+    /// `rust+smtp@rustastic.org` with `+` as separator.
+    ///
+    /// The detail is `None` if there is no separator. Quoted local parts and local parts
+    /// starting with the separator are never split.
+    pub fn subaddress(&self, separator: char) -> (&str, Option<&str>) {
+        if self.local_part.starts_with("\"") {
+            return (self.local_part.as_ref(), None);
+        }
+        match self.local_part.find(separator) {
+            Some(0) | None => (self.local_part.as_ref(), None),
+            Some(i) => (&self.local_part[.. i], Some(&self.local_part[i + separator.len_utf8() ..]))
+        }
+    }
+
+    /// Returns the same mailbox without the detail, ie `rust@rustastic.org` for
+    /// `rust+smtp@rustastic.org` with `+` as separator.
+    pub fn without_detail(&self, separator: char) -> Mailbox {
+        Mailbox {
+            local_part: self.subaddress(separator).0.to_owned(),
+            foreign_part: self.foreign_part.clone()
+        }
+    }
+
+    /// Returns the mailbox in a canonical form, for deduplication and lookup keys.
+    ///
+    /// Quotes are removed before the detail, so `"rust+smtp"@rustastic.org` becomes
+    /// `rust@rustastic.org` with `+` as separator.
+    pub fn normalize(&self, options: &NormalizeOptions) -> Mailbox {
+        let mut local_part = self.local_part.clone();
+        if options.unquote && local_part.starts_with("\"") {
+            let unquoted = self.unquoted_local_part();
+            if utils::get_utf8_dot_string(unquoted.as_ref()) == Some(unquoted.as_ref()) {
+                local_part = unquoted;
+            }
+        }
+        if let Some(separator) = options.subaddress_separator {
+            let mailbox = Mailbox {
+                local_part: local_part,
+                foreign_part: self.foreign_part.clone()
+            };
+            local_part = mailbox.subaddress(separator).0.to_owned();
+        }
+        if options.fold_local_part {
+            local_part = local_part.to_lowercase();
+        }
+        let foreign_part = match self.foreign_part {
+            MailboxForeignPart::Domain(ref domain) if options.lowercase_domain => {
+                MailboxForeignPart::Domain(domain.to_lowercase())
+            },
+            ref foreign_part => foreign_part.clone()
+        };
+        let mailbox = Mailbox {
+            local_part: local_part,
+            foreign_part: foreign_part
+        };
+        match options.ascii_domain {
+            true => mailbox.to_ascii_domain().unwrap_or(mailbox),
+            false => mailbox
+        }
+    }
+
+    /// Returns the mailbox with the U-labels of its domain converted to A-labels, ie
+    /// `rust@xn--bcher-kva.example` for `rust@bücher.example`, see `common::idna`.
+    ///
+    /// With an ASCII local part, this gives an address for servers that don't advertise
+    /// `SMTPUTF8`. Address literals are kept as they are.
+    pub fn to_ascii_domain(&self) -> Result<Mailbox, MailboxParseError> {
+        let domain = match self.foreign_part {
+            MailboxForeignPart::Domain(ref domain) => match idna::to_ascii(domain) {
+                Some(domain) => domain,
+                None => return Err(MailboxParseError::ForeignPartUnrecognized)
+            },
+            MailboxForeignPart::IpAddr(_) => return Ok(self.clone())
+        };
+        // The A-labels are longer than the U-labels.
+        if domain.len() > MAX_DOMAIN_LEN {
+            return Err(MailboxParseError::DomainTooLong);
+        }
+        if self.local_part.len() + 1 + domain.len() > MAX_MAILBOX_LEN {
+            return Err(MailboxParseError::TooLong);
+        }
+        Ok(Mailbox {
+            local_part: self.local_part.clone(),
+            foreign_part: MailboxForeignPart::Domain(domain)
+        })
+    }
+
+    /// Returns the mailbox with the A-labels of its domain converted to U-labels, ie
+    /// `rust@bücher.example` for `rust@xn--bcher-kva.example`, see `common::idna`.
+    ///
+    /// This is the form to show to users. Address literals are kept as they are.
+    pub fn to_unicode_domain(&self) -> Result<Mailbox, MailboxParseError> {
+        match self.foreign_part {
+            MailboxForeignPart::Domain(ref domain) => match idna::to_unicode(domain) {
+                Some(domain) => Ok(Mailbox {
+                    local_part: self.local_part.clone(),
+                    foreign_part: MailboxForeignPart::Domain(domain)
+                }),
+                None => Err(MailboxParseError::ForeignPartUnrecognized)
+            },
+            MailboxForeignPart::IpAddr(_) => Ok(self.clone())
+        }
+    }
+
+    fn parse_address(s: &str, utf8: bool) -> Result<Mailbox, MailboxParseError> {
+        let local_part: String;
+        let foreign_part: MailboxForeignPart;
 
         // Skip the source routes as specified in RFC 5321.
         let mut offset = utils::get_source_route(s).map_or(0, |s| s.len());
 
         // Get the local part.
-        match get_mailbox_local_part(&s[offset ..]) {
+        match get_mailbox_local_part(&s[offset ..], utf8) {
             Some(lp) => {
                 if lp.len() > MAX_MAILBOX_LOCAL_PART_LEN {
                     return Err(MailboxParseError::LocalPartTooLong);
@@ -148,7 +402,11 @@ impl Mailbox {
         }
         offset += 1;
 
-        match utils::get_domain(&s[offset ..]) {
+        let domain = match utf8 {
+            true => utils::get_utf8_domain(&s[offset ..]),
+            false => utils::get_domain(&s[offset ..])
+        };
+        match domain {
             Some(d) => {
                 // Is the domain is too long ?
                 if d.len() > MAX_DOMAIN_LEN {
@@ -185,29 +443,6 @@ impl Mailbox {
         } else if offset > MAX_MAILBOX_LEN {
             Err(MailboxParseError::TooLong)
         } else {
-            // The special "Postmaster" address must be handled differently.
-            // It is ASCII for sure, and since `into_ascii_lower` may panic for
-            // non ascii strings, we make this check first.
-            if local_part.as_str().is_ascii() {
-                // We make this special address lowercase so the server can
-                // avoid to check this again. Basically, we're saying that if
-                // the email is sent by or to Postmaster, we know that the email
-                // will be lowercase.
-                //
-                // We don't do this for other addresses though. Here's why:
-                // Imagine you want to build an email hosting service. You may
-                // want to allow your members to see the case that the person on
-                // the other end chose to give you. Also, handling low/up case
-                // with UTF8 strings is non trivial. Since SMTP allows non-ASCII
-                // mailboxes with RFC 5336, we'll let the case conversion up to
-                // the individual commands that a server wishes to implement.
-                //
-                // RFC 5336: https://tools.ietf.org/html/rfc5336
-                let local_part_c = local_part.clone().into_ascii_lowercase();
-                if local_part_c.as_str() == "postmaster" {
-                    local_part = "postmaster".to_owned();
-                }
-            }
             Ok(Mailbox {
                 local_part: local_part,
                 foreign_part: foreign_part
@@ -216,6 +451,13 @@ impl Mailbox {
     }
 }
 
+impl fmt::Display for Mailbox {
+    /// Writes the address as it is sent in a path, see `to_wire_string`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.local_part, self.foreign_part)
+    }
+}
+
 #[test]
 fn test_mailbox() {
     let mut s = String::from_iter(repeat('a').take(MAX_MAILBOX_LOCAL_PART_LEN));
@@ -282,10 +524,409 @@ fn test_mailbox() {
         IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0x0, 0x0, 0x0, 0xff00, 0x42, 0x8329))
     ));
 
-    // Make sure that the special postmaster address is always lowercase.
+    // The postmaster address keeps its case, but is recognized regardless of it.
     let path_7 = Mailbox::parse("PosTMAster@ok").unwrap();
-    assert_eq!("postmaster", path_7.local_part.as_str());
+    assert_eq!("PosTMAster", path_7.local_part.as_str());
+    assert!(path_7.is_postmaster());
+    assert!(Mailbox::parse("postmaster@ok").unwrap().is_postmaster());
+    assert!(!Mailbox::parse("\"postmaster\"@ok").unwrap().is_postmaster());
+    assert!(!Mailbox::parse("postmasters@ok").unwrap().is_postmaster());
+
+    // UTF-8 addresses need the UTF-8 mode.
+    assert_eq!(Err(MailboxParseError::LocalPartUnrecognized), Mailbox::parse("josé@example.com"));
+    assert_eq!(Err(MailboxParseError::ForeignPartUnrecognized), Mailbox::parse("jose@bücher.de"));
+    let path_9 = Mailbox::parse_utf8("josé@bücher.de").unwrap();
+    assert_eq!("josé", path_9.local_part.as_str());
+    assert_eq!(path_9.foreign_part, MailboxForeignPart::Domain("bücher.de".to_owned()));
+    assert_eq!(Mailbox::parse("rust@rustastic.org"), Mailbox::parse_utf8("rust@rustastic.org"));
+
+    // UTF-8 is also allowed in quoted local parts, but not escaped.
+    assert_eq!(Err(MailboxParseError::LocalPartUnrecognized), Mailbox::parse("\"jo sé\"@example.com"));
+    let path_10 = Mailbox::parse_utf8("\"jo sé\"@example.com").unwrap();
+    assert_eq!("\"jo sé\"", path_10.local_part());
+    assert_eq!("jo sé", path_10.unquoted_local_part());
+    assert!(Mailbox::parse_utf8("\"jo\\é\"@example.com").is_err());
+
+    // Lengths are in octets.
+    let mut s = String::from_iter(repeat('é').take(MAX_MAILBOX_LOCAL_PART_LEN / 2 + 1));
+    s.push_str("@t.com");
+    assert_eq!(Err(MailboxParseError::LocalPartTooLong), Mailbox::parse_utf8(s.as_str()));
+}
+
+#[test]
+fn test_subaddress() {
+    let mailbox = Mailbox::parse("rust+smtp+server@rustastic.org").unwrap();
+    assert_eq!(("rust", Some("smtp+server")), mailbox.subaddress('+'));
+    assert_eq!(("rust+smtp+server", None), mailbox.subaddress('-'));
+    assert_eq!(Mailbox::parse("rust@rustastic.org").unwrap(), mailbox.without_detail('+'));
+    assert_eq!(mailbox, mailbox.without_detail('-'));
+
+    assert_eq!(("rust", Some("")), Mailbox::parse("rust+@rustastic.org").unwrap().subaddress('+'));
+    assert_eq!(("+rust", None), Mailbox::parse("+rust@rustastic.org").unwrap().subaddress('+'));
+    assert_eq!(("\"rust+smtp\"", None), Mailbox::parse("\"rust+smtp\"@rustastic.org").unwrap().subaddress('+'));
+    assert_eq!(("josé", Some("ça")), Mailbox::parse_utf8("josé§ça@rustastic.org").unwrap().subaddress('§'));
+}
+
+#[test]
+fn test_normalize() {
+    let normalize = |address: &str, options: &NormalizeOptions| {
+        Mailbox::parse_utf8(address).unwrap().normalize(options).to_wire_string()
+    };
+    let mut options = NormalizeOptions::new();
+    assert_eq!("Rust+SMTP@rustastic.org", normalize("Rust+SMTP@RustAstic.ORG", &options));
+    assert_eq!("rust.smtp@rustastic.org", normalize("\"rust.smtp\"@rustastic.org", &options));
+    assert_eq!("\"rust smtp\"@rustastic.org", normalize("\"rust smtp\"@rustastic.org", &options));
+    assert_eq!("\"rust..smtp\"@rustastic.org", normalize("\"rust..smtp\"@rustastic.org", &options));
+    assert_eq!("rust@[127.0.0.1]", normalize("rust@[127.0.0.1]", &options));
+    assert_eq!("josé@bücher.de", normalize("josé@BÜCHER.de", &options));
+
+    options.fold_local_part = true;
+    options.subaddress_separator = Some('+');
+    assert_eq!("rust@rustastic.org", normalize("Rust+SMTP@RustAstic.ORG", &options));
+    assert_eq!("rust@rustastic.org", normalize("\"Rust+SMTP\"@rustastic.org", &options));
+    assert_eq!("\"rust +smtp\"@rustastic.org", normalize("\"Rust +SMTP\"@rustastic.org", &options));
+    assert_eq!("josé@bücher.de", normalize("JOSÉ+a@bücher.de", &options));
+
+    options.ascii_domain = true;
+    assert_eq!("jose@xn--bcher-kva.de", normalize("Jose@BÜCHER.de", &options));
+    assert_eq!("jose@xn--bcher-kva.de", normalize("jose@XN--BCHER-KVA.DE", &options));
+
+    options = NormalizeOptions {
+        lowercase_domain: false,
+        fold_local_part: false,
+        unquote: false,
+        subaddress_separator: None,
+        ascii_domain: false
+    };
+    assert_eq!("\"Rust\"@RustAstic.ORG", normalize("\"Rust\"@RustAstic.ORG", &options));
+}
+
+#[test]
+fn test_idna() {
+    let mailbox = Mailbox::parse_utf8("rust@bücher.example").unwrap();
+    let ascii = mailbox.to_ascii_domain().unwrap();
+    assert_eq!(Some("xn--bcher-kva.example"), ascii.domain());
+    assert_eq!(Ok(ascii.clone()), Mailbox::parse("rust@xn--bcher-kva.example"));
+    assert_eq!(Ok(mailbox.clone()), ascii.to_unicode_domain());
+    assert_eq!(Ok(ascii.clone()), ascii.to_ascii_domain());
+    assert_eq!(Ok(mailbox.clone()), mailbox.to_unicode_domain());
+
+    let literal = Mailbox::parse("rust@[127.0.0.1]").unwrap();
+    assert_eq!(Ok(literal.clone()), literal.to_ascii_domain());
+    assert_eq!(Ok(literal.clone()), literal.to_unicode_domain());
+
+    assert_eq!(Err(MailboxParseError::ForeignPartUnrecognized), Mailbox::parse("rust@xn--99999999999999").unwrap().to_unicode_domain());
+    let long = Mailbox::parse_utf8(format!("rust@{}.example", String::from_iter(repeat('ü').take(60))).as_ref()).unwrap();
+    assert_eq!(Err(MailboxParseError::ForeignPartUnrecognized), long.to_ascii_domain());
+}
 
-    let path_8 = Mailbox::parse("postmaster@ok").unwrap();
-    assert_eq!("postmaster", path_8.local_part.as_str());
+#[test]
+fn test_unquoted_local_part() {
+    assert_eq!("rust.cool", Mailbox::parse("rust.cool@rustastic.org").unwrap().unquoted_local_part());
+    assert_eq!("rust cool", Mailbox::parse("\"rust cool\"@rustastic.org").unwrap().unquoted_local_part());
+    assert_eq!("rust \"c\\ool", Mailbox::parse("\"rust \\\"c\\\\o\\ol\"@rustastic.org").unwrap().unquoted_local_part());
+    assert_eq!("", Mailbox::parse("\"\"@rustastic.org").unwrap().unquoted_local_part());
+}
+
+#[test]
+fn test_to_wire_string() {
+    assert_eq!("rust@rustastic.org", Mailbox::parse("rust@rustastic.org").unwrap().to_wire_string());
+    assert_eq!("rust@[127.0.0.1]", Mailbox::parse("rust@[127.0.0.1]").unwrap().to_wire_string());
+    assert_eq!("rust@[IPv6:::1]", Mailbox::parse("rust@[Ipv6:0::1]").unwrap().to_wire_string());
+    assert_eq!("rust@rustastic.org", Mailbox::parse("@a.org,@b.org:rust@rustastic.org").unwrap().to_wire_string());
+}
+
+#[test]
+fn test_display() {
+    let mailbox = Mailbox::parse("\"rust smtp\"@rustastic.org").unwrap();
+    assert_eq!("\"rust smtp\"", mailbox.local_part());
+    assert_eq!(Some("rustastic.org"), mailbox.domain());
+    assert_eq!("\"rust smtp\"@rustastic.org", mailbox.to_string());
+    assert_eq!("<\"rust smtp\"@rustastic.org>", format!("<{}>", mailbox));
+
+    let mailbox = Mailbox::parse("rust@[Ipv6:0::1]").unwrap();
+    assert_eq!(None, mailbox.domain());
+    assert_eq!(&MailboxForeignPart::IpAddr(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))), mailbox.foreign_part());
+    assert_eq!("rust@[IPv6:::1]", mailbox.to_string());
+    assert_eq!("josé@bücher.de", Mailbox::parse_utf8("josé@bücher.de").unwrap().to_string());
+}
+
+#[cfg(feature = "serde")]
+impl MailboxForeignPart {
+    // Parses a foreign part as it is written by `Display`, ie `rustastic.org` or
+    // `[127.0.0.1]`. U-labels are accepted.
+    fn parse(s: &str) -> Option<MailboxForeignPart> {
+        if utils::get_utf8_domain(s) == Some(s) && s.len() <= MAX_DOMAIN_LEN {
+            return Some(MailboxForeignPart::Domain(s.to_owned()));
+        }
+        match utils::get_mailbox_ip(s) {
+            Some((ip, addr)) if ip.len() == s.len() => Some(MailboxForeignPart::IpAddr(addr)),
+            _ => None
+        }
+    }
+}
+
+impl MailboxParseError {
+    // Every error, for deserialization.
+    #[cfg(feature = "serde")]
+    fn all() -> [MailboxParseError; 6] {
+        [
+            MailboxParseError::LocalPartTooLong,
+            MailboxParseError::LocalPartUnrecognized,
+            MailboxParseError::ForeignPartUnrecognized,
+            MailboxParseError::DomainTooLong,
+            MailboxParseError::TooLong,
+            MailboxParseError::AtNotFound
+        ]
+    }
+
+    /// Returns the name of the error, ie `AtNotFound`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MailboxParseError::LocalPartTooLong => "LocalPartTooLong",
+            MailboxParseError::LocalPartUnrecognized => "LocalPartUnrecognized",
+            MailboxParseError::ForeignPartUnrecognized => "ForeignPartUnrecognized",
+            MailboxParseError::DomainTooLong => "DomainTooLong",
+            MailboxParseError::TooLong => "TooLong",
+            MailboxParseError::AtNotFound => "AtNotFound"
+        }
+    }
+}
+
+// Deserializes values written as strings, with the given parser.
+#[cfg(feature = "serde")]
+struct ParseVisitor<T> {
+    parse: fn(&str) -> Option<T>,
+    expected: &'static str
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T> Visitor<'de> for ParseVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.expected)
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<T, E> {
+        match (self.parse)(s) {
+            Some(value) => Ok(value),
+            None => Err(E::invalid_value(Unexpected::Str(s), &self))
+        }
+    }
+}
+
+/// Mailboxes are serialized as strings, see `to_wire_string`. UTF-8 addresses are
+/// accepted when deserializing, like `parse_utf8` does.
+#[cfg(feature = "serde")]
+impl Serialize for Mailbox {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Mailbox {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Mailbox, D::Error> {
+        deserializer.deserialize_str(ParseVisitor {
+            parse: |s| Mailbox::parse_utf8(s).ok(),
+            expected: "an email address"
+        })
+    }
+}
+
+/// Foreign parts are serialized as strings, ie `rustastic.org` or `[127.0.0.1]`.
+#[cfg(feature = "serde")]
+impl Serialize for MailboxForeignPart {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for MailboxForeignPart {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MailboxForeignPart, D::Error> {
+        deserializer.deserialize_str(ParseVisitor {
+            parse: MailboxForeignPart::parse,
+            expected: "a domain or an address literal"
+        })
+    }
+}
+
+/// Errors are serialized as their name, see `name`.
+#[cfg(feature = "serde")]
+impl Serialize for MailboxParseError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for MailboxParseError {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MailboxParseError, D::Error> {
+        deserializer.deserialize_str(ParseVisitor {
+            parse: |s| MailboxParseError::all().iter().find(|err| err.name() == s).cloned(),
+            expected: "the name of a mailbox parse error"
+        })
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+fn deserialize<'de, T: Deserialize<'de>>(s: &'de str) -> Result<T, ValueError> {
+    T::deserialize(s.into_deserializer())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde() {
+    assert_eq!(Mailbox::parse("rust@rustastic.org"), Ok(deserialize("rust@rustastic.org").unwrap()));
+    assert_eq!(Mailbox::parse_utf8("josé@bücher.de"), Ok(deserialize("josé@bücher.de").unwrap()));
+    assert!(deserialize::<Mailbox>("rust").is_err());
+
+    assert_eq!(MailboxForeignPart::Domain("rustastic.org".to_owned()), deserialize("rustastic.org").unwrap());
+    let ip = MailboxForeignPart::IpAddr(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)));
+    assert_eq!(ip, deserialize(ip.to_string().as_ref()).unwrap());
+    assert!(deserialize::<MailboxForeignPart>("rustastic.org.").is_err());
+    assert!(deserialize::<MailboxForeignPart>("[127.0.0.1").is_err());
+
+    for err in MailboxParseError::all().iter() {
+        assert_eq!(*err, deserialize(err.name()).unwrap());
+    }
+    assert!(deserialize::<MailboxParseError>("NotAnError").is_err());
+}
+
+// Checks that the mailbox survives being written and parsed again.
+#[cfg(test)]
+fn check_round_trip(mailbox: &Mailbox, utf8: bool) {
+    let wire = mailbox.to_wire_string();
+    let parsed = match utf8 {
+        true => Mailbox::parse_utf8(wire.as_ref()),
+        false => Mailbox::parse(wire.as_ref())
+    };
+    assert_eq!(Ok(mailbox.clone()), parsed);
+}
+
+// Runs the check on every line of a corpus file, as `<mode> <address>` where the mode is
+// `ascii`, `utf8` or `invalid`.
+//
+// Valid addresses must come back byte for byte, so they are written in their usual form.
+#[test]
+fn test_corpus() {
+    let file = BufReader::new(File::open("tests/mailbox/corpus").unwrap());
+    for line in file.lines() {
+        let line = line.unwrap();
+        if line.len() == 0 || line.starts_with("#") {
+            continue;
+        }
+        let (mode, address) = line.split_at(line.find(' ').unwrap());
+        let address = &address[1 ..];
+        match mode {
+            "ascii" | "utf8" => {
+                let utf8 = mode == "utf8";
+                let mailbox = match utf8 {
+                    true => Mailbox::parse_utf8(address),
+                    false => Mailbox::parse(address)
+                };
+                let mailbox = mailbox.ok().expect(address);
+                assert_eq!(address, mailbox.to_wire_string().as_str());
+                check_round_trip(&mailbox, utf8);
+            },
+            "invalid" => {
+                assert!(Mailbox::parse(address).is_err(), "{}", address);
+                assert!(Mailbox::parse_utf8(address).is_err(), "{}", address);
+            },
+            _ => panic!("unknown mode in corpus: {}", mode)
+        }
+    }
+}
+
+// A xorshift generator, so that the generated addresses are the same on every run.
+#[cfg(test)]
+struct Generator(u64);
+
+#[cfg(test)]
+impl Generator {
+    fn next(&mut self, max: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % max as u64) as usize
+    }
+
+    fn pick(&mut self, chars: &[char]) -> char {
+        chars[self.next(chars.len())]
+    }
+
+    fn local_part(&mut self) -> String {
+        let atext: Vec<char> = "aZ09!#$%&'*+-/=?^_`{|}~é".chars().collect();
+        let qtext: Vec<char> = "a Z.@,;:()<>[]\\\"é".chars().collect();
+        let mut local_part = String::new();
+        match self.next(2) {
+            0 => {
+                for i in 0 .. self.next(12) + 1 {
+                    if i > 0 && self.next(4) == 0 {
+                        local_part.push('.');
+                    }
+                    local_part.push(self.pick(&atext));
+                }
+            },
+            _ => {
+                local_part.push('"');
+                for _ in 0 .. self.next(12) {
+                    let c = self.pick(&qtext);
+                    if c == '\\' || c == '"' {
+                        local_part.push('\\');
+                    }
+                    local_part.push(c);
+                }
+                local_part.push('"');
+            }
+        }
+        local_part
+    }
+
+    fn foreign_part(&mut self) -> String {
+        let ldh: Vec<char> = "aZ09-é".chars().collect();
+        match self.next(8) {
+            0 => format!("[{}.{}.{}.{}]", self.next(256), self.next(256), self.next(256), self.next(256)),
+            1 => format!("[IPv6:{:x}::{:x}]", self.next(65536), self.next(65536)),
+            _ => {
+                let mut labels = Vec::new();
+                for _ in 0 .. self.next(3) + 1 {
+                    let mut label = String::new();
+                    for _ in 0 .. self.next(10) + 1 {
+                        label.push(self.pick(&ldh));
+                    }
+                    labels.push(label);
+                }
+                labels.join(".")
+            }
+        }
+    }
+}
+
+// Generates addresses, valid or not, and checks that the valid ones round-trip byte for
+// byte and that parsing never panics.
+#[test]
+fn test_generated_round_trips() {
+    let mut generator = Generator(0x2545F4914F6CDD1D);
+    let mut valid = 0;
+    for _ in 0 .. 10000 {
+        let address = format!("{}@{}", generator.local_part(), generator.foreign_part());
+        for &utf8 in [false, true].iter() {
+            let parsed = match utf8 {
+                true => Mailbox::parse_utf8(address.as_ref()),
+                false => Mailbox::parse(address.as_ref())
+            };
+            if let Ok(mailbox) = parsed {
+                valid += 1;
+                check_round_trip(&mailbox, utf8);
+                if let MailboxForeignPart::Domain(_) = *mailbox.foreign_part() {
+                    assert_eq!(address, mailbox.to_wire_string());
+                }
+            }
+        }
+    }
+    // Make sure the generator doesn't only produce junk.
+    assert!(valid > 2000);
 }
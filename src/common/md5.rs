@@ -0,0 +1,141 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! MD5, as described [in RFC 1321](http://tools.ietf.org/html/rfc1321), and HMAC-MD5, as
+//! described [in RFC 2104](http://tools.ietf.org/html/rfc2104).
+//!
+//! These are only here for CRAM-MD5. MD5 is broken, don't use it for anything else.
+
+use std::vec::Vec;
+#[cfg(test)]
+use std::iter::repeat;
+
+static S: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+    5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20,
+    4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+    6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21
+];
+
+static K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391
+];
+
+/// Returns the MD5 digest of the data.
+pub fn digest(data: &[u8]) -> [u8; 16] {
+    // Pad the message to a multiple of 64 bytes, ending with its length in bits.
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    let bits = (data.len() as u64).wrapping_mul(8);
+    for i in 0 .. 8 {
+        message.push((bits >> (8 * i)) as u8);
+    }
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks(64) {
+        let mut words = [0u32; 16];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = (block[i * 4] as u32)
+                | ((block[i * 4 + 1] as u32) << 8)
+                | ((block[i * 4 + 2] as u32) << 16)
+                | ((block[i * 4 + 3] as u32) << 24);
+        }
+
+        let (mut a, mut b, mut c, mut d) = (state[0], state[1], state[2], state[3]);
+        for i in 0 .. 64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16)
+            };
+            let rotated = a.wrapping_add(f).wrapping_add(K[i]).wrapping_add(words[g]).rotate_left(S[i]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+
+        state[0] = state[0].wrapping_add(a);
+        state[1] = state[1].wrapping_add(b);
+        state[2] = state[2].wrapping_add(c);
+        state[3] = state[3].wrapping_add(d);
+    }
+
+    let mut out = [0u8; 16];
+    for (i, word) in state.iter().enumerate() {
+        for j in 0 .. 4 {
+            out[i * 4 + j] = (*word >> (8 * j)) as u8;
+        }
+    }
+    out
+}
+
+/// Returns the HMAC-MD5 of the data with the given key.
+pub fn hmac(key: &[u8], data: &[u8]) -> [u8; 16] {
+    let mut key = match key.len() > 64 {
+        true => digest(key).to_vec(),
+        false => key.to_vec()
+    };
+    key.resize(64, 0);
+
+    let mut inner: Vec<u8> = key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend(data.iter().cloned());
+    let mut outer: Vec<u8> = key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend(digest(inner.as_ref()).iter().cloned());
+    digest(outer.as_ref())
+}
+
+/// Formats a digest as lowercase hexadecimal.
+pub fn to_hex(digest: &[u8]) -> String {
+    let mut out = String::with_capacity(digest.len() * 2);
+    for b in digest.iter() {
+        out.push_str(format!("{:02x}", b).as_ref());
+    }
+    out
+}
+
+#[test]
+fn test_digest() {
+    assert_eq!("d41d8cd98f00b204e9800998ecf8427e", to_hex(&digest(b"")));
+    assert_eq!("0cc175b9c0f1b6a831c399e269772661", to_hex(&digest(b"a")));
+    assert_eq!("900150983cd24fb0d6963f7d28e17f72", to_hex(&digest(b"abc")));
+    assert_eq!("f96b697d7cb7938d525a2f31aaf161d0", to_hex(&digest(b"message digest")));
+    assert_eq!("57edf4a22be3c955ac49da2e2107b67a", to_hex(&digest(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890")));
+}
+
+#[test]
+fn test_hmac() {
+    let key: Vec<u8> = repeat(0x0b).take(16).collect();
+    assert_eq!("9294727a3638bb1c13f48ef8158bfc9d", to_hex(&hmac(key.as_ref(), b"Hi There")));
+    assert_eq!("750c783e6ab0b503eaa86e310a5db738", to_hex(&hmac(b"Jefe", b"what do ya want for nothing?")));
+
+    // Keys longer than a block are hashed first.
+    let key: Vec<u8> = repeat(0xaa).take(80).collect();
+    assert_eq!("6b1ab7fe4bd7bf8f0b62e6ce61b9d0cd", to_hex(&hmac(key.as_ref(), b"Test Using Larger Than Block-Size Key - Hash Key First")));
+
+    // The example of RFC 2195.
+    let challenge = b"<1896.697170952@postoffice.reston.mci.net>";
+    assert_eq!("b913a602c7eda7a495b4e6e7334d3890", to_hex(&hmac(b"tanstaaftanstaaf", challenge)));
+}
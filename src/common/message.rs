@@ -0,0 +1,180 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tools to inspect the content of a message, as described
+//! [in RFC 5322](http://tools.ietf.org/html/rfc5322).
+
+use std::cmp::min;
+use std::iter::Iterator;
+
+// Returns the position of the next `<CRLF>` starting at `from`, or the length of the
+// data if there is none.
+fn line_end(data: &[u8], from: usize) -> usize {
+    let mut i = from;
+    while i + 1 < data.len() {
+        if data[i] == b'\r' && data[i + 1] == b'\n' {
+            return i;
+        }
+        i += 1;
+    }
+    data.len()
+}
+
+/// An iterator over the header fields of a message.
+///
+/// Each item is a whole header field, including its folded lines but without the final
+/// `<CRLF>`. The iteration stops at the empty line that separates the header section from
+/// the body.
+pub struct HeaderFields<'a> {
+    data: &'a [u8],
+    pos: usize,
+    done: bool
+}
+
+/// Returns an iterator over the header fields of a message.
+pub fn header_fields(data: &[u8]) -> HeaderFields {
+    HeaderFields {
+        data: data,
+        pos: 0,
+        done: false
+    }
+}
+
+impl<'a> HeaderFields<'a> {
+    /// Returns the number of bytes of the message read so far.
+    ///
+    /// Once the iteration is over, this is the length of the header section, including the
+    /// empty line that ends it.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl<'a> Iterator for HeaderFields<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.done {
+            return None;
+        }
+
+        let data = self.data;
+        let start = self.pos;
+        let mut end = line_end(data, start);
+
+        // An empty line ends the header section.
+        if end == start {
+            self.done = true;
+            self.pos = min(start + 2, data.len());
+            return None;
+        }
+
+        // Lines starting with whitespace continue the current header field.
+        while end + 2 < data.len() && (data[end + 2] == b' ' || data[end + 2] == b'\t') {
+            end = line_end(data, end + 2);
+        }
+
+        if end == data.len() {
+            self.done = true;
+        }
+        self.pos = min(end + 2, data.len());
+        Some(&data[start .. end])
+    }
+}
+
+#[test]
+fn test_header_fields() {
+    let data = b"Subject: hi\r\nTo: a@b,\r\n\tc@d\r\n\r\nbody: no\r\n";
+    let mut fields = header_fields(data);
+    assert_eq!(Some(&b"Subject: hi"[..]), fields.next());
+    assert_eq!(Some(&b"To: a@b,\r\n\tc@d"[..]), fields.next());
+    assert_eq!(None, fields.next());
+    assert_eq!(None, fields.next());
+    assert_eq!(31, fields.position());
+
+    // No body and no final `<CRLF>`.
+    let mut fields = header_fields(b"Subject: hi");
+    assert_eq!(Some(&b"Subject: hi"[..]), fields.next());
+    assert_eq!(None, fields.next());
+    assert_eq!(11, fields.position());
+
+    let mut fields = header_fields(b"");
+    assert_eq!(None, fields.next());
+    assert_eq!(0, fields.position());
+}
+
+/// Limits on the header section of a message.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct HeaderLimits {
+    /// Maximum size of the header section in octets, including the empty line that ends it.
+    pub max_section_size: usize,
+    /// Maximum number of header fields.
+    pub max_count: usize,
+    /// Maximum size of a single header field in octets, including folded lines.
+    pub max_field_size: usize
+}
+
+/// Represents a limit exceeded by the header section of a message.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum HeaderLimitError {
+    /// The header section is too large.
+    SectionTooLong,
+    /// There are too many header fields.
+    TooManyFields,
+    /// A single header field is too large.
+    FieldTooLong
+}
+
+/// Checks that the header section of a message is within the given limits.
+pub fn check_header_limits(data: &[u8], limits: &HeaderLimits) -> Result<(), HeaderLimitError> {
+    let mut fields = header_fields(data);
+    let mut count = 0;
+
+    while let Some(field) = fields.next() {
+        count += 1;
+        if field.len() > limits.max_field_size {
+            return Err(HeaderLimitError::FieldTooLong);
+        }
+        if count > limits.max_count {
+            return Err(HeaderLimitError::TooManyFields);
+        }
+        // Check as we go to avoid iterating over a huge header section.
+        if fields.position() > limits.max_section_size {
+            return Err(HeaderLimitError::SectionTooLong);
+        }
+    }
+
+    match fields.position() > limits.max_section_size {
+        true => Err(HeaderLimitError::SectionTooLong),
+        false => Ok(())
+    }
+}
+
+#[test]
+fn test_check_header_limits() {
+    let limits = HeaderLimits {
+        max_section_size: 32,
+        max_count: 2,
+        max_field_size: 15
+    };
+    assert_eq!(Ok(()), check_header_limits(b"A: b\r\nC: d\r\n\r\nbody", &limits));
+    assert_eq!(Ok(()), check_header_limits(b"", &limits));
+    assert_eq!(Err(HeaderLimitError::TooManyFields), check_header_limits(b"A: b\r\nC: d\r\nE: f\r\n\r\n", &limits));
+    assert_eq!(Err(HeaderLimitError::FieldTooLong), check_header_limits(b"A: 0123456789abc\r\n\r\n", &limits));
+    assert_eq!(Ok(()), check_header_limits(b"A: 0123456789ab\r\n\r\n", &limits));
+    assert_eq!(Err(HeaderLimitError::SectionTooLong), check_header_limits(b"A: 0123456789ab\r\nB: 0123456789ab\r\n\r\n", &limits));
+
+    // The body is not limited.
+    assert_eq!(Ok(()), check_header_limits(b"A: b\r\n\r\n0123456789abcdef0123456789abcdef", &limits));
+}
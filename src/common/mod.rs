@@ -17,7 +17,30 @@
 pub mod stream;
 pub mod mailbox;
 pub mod utils;
+pub mod message;
+pub mod tls;
+pub mod base64;
+pub mod md5;
+pub mod sha256;
+pub mod status;
+pub mod dsn;
+pub mod delay;
+pub mod reply;
+pub mod parameters;
+pub mod lz;
+pub mod json;
+pub mod id;
+pub mod instrument;
+pub mod datetime;
+pub mod dns;
+pub mod idna;
+pub mod transcript;
+pub mod toml;
+
+pub use self::reply::Reply;
 
 pub static MIN_ALLOWED_MESSAGE_SIZE: usize = 65536;
 pub static MIN_ALLOWED_LINE_SIZE: usize = 1000;
 pub static MIN_ALLOWED_RECIPIENTS: usize = 100;
+// In milliseconds, see [RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.5.3.2.7).
+pub static MIN_ALLOWED_TIMEOUT: usize = 300000;
@@ -0,0 +1,123 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parameters of MAIL and RCPT, as described
+//! [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.1.2).
+//!
+//! They follow the path, ie `SIZE=1000 BODY=8BITMIME` in
+//! `MAIL FROM:<a@b> SIZE=1000 BODY=8BITMIME`.
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::vec::Vec;
+
+/// An error that occurs when parsing parameters.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum ParameterError {
+    /// A keyword isn't made of letters, digits and dashes, or starts with a dash.
+    InvalidKeyword,
+    /// A value is empty, or contains characters other than printable US-ASCII or `=`.
+    InvalidValue,
+    /// A keyword was given more than once.
+    Duplicate
+}
+
+/// Parameters, by keyword, in the order they were given.
+///
+/// Keywords are case insensitive, they are kept in uppercase.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct EsmtpParameters {
+    params: Vec<(String, Option<String>)>
+}
+
+fn is_keyword(s: &str) -> bool {
+    s.len() > 0 && !s.starts_with("-") && s.chars().all(|c| c.is_ascii() && (c.is_alphanumeric() || c == '-'))
+}
+
+fn is_value(s: &str) -> bool {
+    s.len() > 0 && s.chars().all(|c| c >= '!' && c <= '~' && c != '=')
+}
+
+impl EsmtpParameters {
+    /// Creates an empty set of parameters.
+    pub fn new() -> EsmtpParameters {
+        EsmtpParameters {
+            params: Vec::new()
+        }
+    }
+
+    /// Parses space separated parameters, ie `SIZE=1000 SMTPUTF8`.
+    pub fn parse(s: &str) -> Result<EsmtpParameters, ParameterError> {
+        let mut parameters = EsmtpParameters::new();
+        for param in s.split(' ').filter(|param| param.len() > 0) {
+            let mut parts = param.splitn(2, '=');
+            let keyword = parts.next().unwrap();
+            let value = parts.next();
+            if !is_keyword(keyword) {
+                return Err(ParameterError::InvalidKeyword);
+            }
+            if !value.map_or(true, is_value) {
+                return Err(ParameterError::InvalidValue);
+            }
+            if parameters.contains(keyword) {
+                return Err(ParameterError::Duplicate);
+            }
+            parameters.params.push((keyword.to_ascii_uppercase(), value.map(|value| value.to_owned())));
+        }
+        Ok(parameters)
+    }
+
+    /// Tells whether the parameter was given.
+    pub fn contains(&self, keyword: &str) -> bool {
+        self.params.iter().any(|&(ref k, _)| k.eq_ignore_ascii_case(keyword))
+    }
+
+    /// Returns the value of a parameter, `Some(None)` if it was given without a value.
+    pub fn get(&self, keyword: &str) -> Option<Option<&str>> {
+        self.params.iter().find(|&&(ref k, _)| k.eq_ignore_ascii_case(keyword)).map(|&(_, ref value)| {
+            value.as_ref().map(|value| value.as_ref())
+        })
+    }
+
+    /// Returns the parameters, as pairs of uppercase keyword and value.
+    pub fn as_slice(&self) -> &[(String, Option<String>)] {
+        self.params.as_ref()
+    }
+
+    /// Returns the number of parameters.
+    pub fn len(&self) -> usize {
+        self.params.len()
+    }
+}
+
+#[test]
+fn test_parse() {
+    let parameters = EsmtpParameters::parse("size=1000 SMTPUTF8  X-TAG=a+b").unwrap();
+    assert_eq!(3, parameters.len());
+    assert_eq!(Some(Some("1000")), parameters.get("SIZE"));
+    assert_eq!(Some(None), parameters.get("smtputf8"));
+    assert_eq!(Some(Some("a+b")), parameters.get("X-TAG"));
+    assert_eq!(None, parameters.get("BODY"));
+    assert_eq!("SIZE", parameters.as_slice()[0].0);
+    assert_eq!(0, EsmtpParameters::parse("").unwrap().len());
+
+    assert_eq!(Err(ParameterError::InvalidKeyword), EsmtpParameters::parse("=1000"));
+    assert_eq!(Err(ParameterError::InvalidKeyword), EsmtpParameters::parse("-SIZE=1000"));
+    assert_eq!(Err(ParameterError::InvalidKeyword), EsmtpParameters::parse("SI_ZE=1000"));
+    assert_eq!(Err(ParameterError::InvalidKeyword), EsmtpParameters::parse("<a@b>"));
+    assert_eq!(Err(ParameterError::InvalidValue), EsmtpParameters::parse("SIZE="));
+    assert_eq!(Err(ParameterError::InvalidValue), EsmtpParameters::parse("ENVID=a=b"));
+    assert_eq!(Err(ParameterError::InvalidValue), EsmtpParameters::parse("ENVID=caf\u{e9}"));
+    assert_eq!(Err(ParameterError::Duplicate), EsmtpParameters::parse("SIZE=1 size=2"));
+}
@@ -0,0 +1,311 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Replies, as described [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.2).
+//!
+//! A reply has a code, an optional enhanced status code and one or more lines of text.
+//! The server writes them with `OutputStream::write_reply`, and the client reads them with
+//! `client::read_reply`, which parses them with a `ReplyParser`.
+
+use std::borrow::ToOwned;
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::vec::Vec;
+use super::status::EnhancedStatusCode;
+
+/// A reply, possibly spanning several lines.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Reply {
+    code: u16,
+    status: Option<EnhancedStatusCode>,
+    lines: Vec<String>
+}
+
+/// An error that occurs when parsing a reply.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum ReplyError {
+    /// There was no line at all.
+    Empty,
+    /// A line doesn't start with a 3 digit code followed by a space or a dash.
+    InvalidLine,
+    /// The code doesn't start with a digit from `2` to `5`.
+    InvalidCode,
+    /// The lines don't all have the same code.
+    MixedCodes,
+    /// A line other than the last one doesn't have a dash after the code, or the last one
+    /// has one.
+    InvalidContinuation
+}
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ReplyError::Empty => "empty reply",
+            ReplyError::InvalidLine => "reply line without a code",
+            ReplyError::InvalidCode => "reply code out of range",
+            ReplyError::MixedCodes => "reply lines with different codes",
+            ReplyError::InvalidContinuation => "reply continued after its last line, or not ended"
+        })
+    }
+}
+
+impl Error for ReplyError {
+    fn description(&self) -> &str {
+        "invalid reply"
+    }
+}
+
+impl Reply {
+    /// Creates a reply without enhanced status code.
+    ///
+    /// The text is split into lines on `\n`.
+    pub fn new(code: u16, text: &str) -> Reply {
+        Reply {
+            code: code,
+            status: None,
+            lines: split_text(text)
+        }
+    }
+
+    /// Creates a reply with an enhanced status code, ie `250 2.1.0 OK`.
+    ///
+    /// The text is split into lines on `\n`, the status code is repeated on every line.
+    pub fn enhanced(code: u16, status: EnhancedStatusCode, text: &str) -> Reply {
+        let mut reply = Reply::new(code, text);
+        reply.status = Some(status);
+        reply
+    }
+
+    /// Creates a reply from its lines of text.
+    ///
+    /// An empty list gives a reply with a single empty line. Lines containing `\n` are
+    /// split, so a line of text never ends up as several lines on the wire.
+    pub fn multiline(code: u16, status: Option<EnhancedStatusCode>, lines: Vec<String>) -> Reply {
+        let mut split = Vec::with_capacity(lines.len());
+        for line in lines.iter() {
+            split.extend(split_text(line.as_ref()).into_iter());
+        }
+        Reply {
+            code: code,
+            status: status,
+            lines: match split.len() {
+                0 => vec![String::new()],
+                _ => split
+            }
+        }
+    }
+
+    /// Returns the code, ie `250`.
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+
+    /// Returns the enhanced status code, if any.
+    pub fn status(&self) -> Option<EnhancedStatusCode> {
+        self.status
+    }
+
+    /// Returns the lines of text, without the codes.
+    pub fn lines(&self) -> &[String] {
+        self.lines.as_ref()
+    }
+
+    /// Returns the lines as sent on the wire, without `<CRLF>`, ie `250-first` and
+    /// `250 last`.
+    pub fn to_lines(&self) -> Vec<String> {
+        let last = self.lines.len() - 1;
+        self.lines.iter().enumerate().map(|(i, text)| {
+            let sep = if i == last { " " } else { "-" };
+            let line = match self.status {
+                Some(status) => format!("{}{}{} {}", self.code, sep, status, text),
+                None => format!("{}{}{}", self.code, sep, text)
+            };
+            line.trim_right().to_owned()
+        }).collect()
+    }
+
+    /// Parses the lines of a reply, as received on the wire without `<CRLF>`.
+    ///
+    /// The enhanced status code is only recognized if every line has one, whose class
+    /// matches the code.
+    pub fn parse(lines: &[&str]) -> Result<Reply, ReplyError> {
+        if lines.len() == 0 {
+            return Err(ReplyError::Empty);
+        }
+        let mut parser = ReplyParser::new();
+        for (i, line) in lines.iter().enumerate() {
+            if let Some(reply) = try!(parser.push(line)) {
+                return match i == lines.len() - 1 {
+                    true => Ok(reply),
+                    false => Err(ReplyError::InvalidContinuation)
+                };
+            }
+        }
+        Err(ReplyError::InvalidContinuation)
+    }
+}
+
+/// Parses a reply one line at a time, as the lines are received.
+///
+/// Errors are found as soon as the line with the error is given, without waiting for the
+/// end of the reply.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ReplyParser {
+    code: Option<u16>,
+    lines: Vec<String>
+}
+
+impl ReplyParser {
+    /// Creates a parser waiting for the first line of a reply.
+    pub fn new() -> ReplyParser {
+        ReplyParser {
+            code: None,
+            lines: Vec::new()
+        }
+    }
+
+    /// Returns the number of lines given for the reply being parsed.
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Parses a line of a reply, without `<CRLF>`.
+    ///
+    /// Returns the reply once its last line is given, after which the parser waits for
+    /// the next reply. After an error, the parser must not be used anymore.
+    pub fn push(&mut self, line: &str) -> Result<Option<Reply>, ReplyError> {
+        let (code, last, _) = try!(parse_line(line));
+        if self.code.map_or(false, |expected| expected != code) {
+            return Err(ReplyError::MixedCodes);
+        }
+        self.code = Some(code);
+        self.lines.push(line.to_owned());
+        if !last {
+            return Ok(None);
+        }
+        let reply = build_reply(code, &self.lines);
+        self.code = None;
+        self.lines.clear();
+        Ok(Some(reply))
+    }
+}
+
+// Builds a reply from its lines, which are known to be valid and to have the code.
+fn build_reply(code: u16, lines: &[String]) -> Reply {
+    let statuses: Vec<Option<EnhancedStatusCode>> = lines.iter().map(|line| {
+        EnhancedStatusCode::from_reply(line)
+    }).collect();
+    let status = match statuses[0] {
+        Some(status) if statuses.iter().all(|s| *s == Some(status)) => Some(status),
+        _ => None
+    };
+    let texts = lines.iter().map(|line| {
+        let text = &line[cmp::min(line.len(), 4) ..];
+        match status {
+            Some(status) => {
+                let prefix = status.to_string();
+                text[prefix.len() ..].trim_left_matches(' ').to_owned()
+            },
+            None => text.to_owned()
+        }
+    }).collect();
+    Reply::multiline(code, status, texts)
+}
+
+// Splits text into lines on `\n`. `\r` is dropped, it can't appear alone on the wire.
+fn split_text(text: &str) -> Vec<String> {
+    text.split('\n').map(|line| line.replace("\r", "")).collect()
+}
+
+/// Parses a line of a reply into its code, whether it is the last line, and its text.
+pub fn parse_line(line: &str) -> Result<(u16, bool, &str), ReplyError> {
+    let bytes = line.as_bytes();
+    if bytes.len() < 3 || !bytes[.. 3].iter().all(|c| *c >= b'0' && *c <= b'9') {
+        return Err(ReplyError::InvalidLine);
+    }
+    if bytes[0] < b'2' || bytes[0] > b'5' {
+        return Err(ReplyError::InvalidCode);
+    }
+    let code = line[.. 3].parse().unwrap();
+    match bytes.get(3) {
+        None => Ok((code, true, "")),
+        Some(&b' ') => Ok((code, true, &line[4 ..])),
+        Some(&b'-') => Ok((code, false, &line[4 ..])),
+        Some(_) => Err(ReplyError::InvalidLine)
+    }
+}
+
+#[test]
+fn test_to_lines() {
+    assert_eq!(vec!["250 OK"], Reply::new(250, "OK").to_lines());
+    assert_eq!(vec!["250 2.1.0 OK"], Reply::enhanced(250, EnhancedStatusCode::new(2, 1, 0), "OK").to_lines());
+    assert_eq!(vec!["214-2.0.0 a", "214 2.0.0 b"], Reply::enhanced(214, EnhancedStatusCode::new(2, 0, 0), "a\nb").to_lines());
+    let lines = vec!["rustastic.org".to_owned(), "SIZE 1000".to_owned()];
+    assert_eq!(vec!["250-rustastic.org", "250 SIZE 1000"], Reply::multiline(250, None, lines).to_lines());
+    assert_eq!(vec!["250"], Reply::multiline(250, None, vec![]).to_lines());
+
+    // Line breaks in the text never break the framing of the reply.
+    assert_eq!(vec!["500-a", "500 b"], Reply::new(500, "a\r\nb").to_lines());
+    assert_eq!(vec!["500 ab"], Reply::new(500, "a\rb").to_lines());
+    let lines = vec!["a\nb".to_owned(), "c\r\n".to_owned()];
+    assert_eq!(vec!["250-a", "250-b", "250-c", "250"], Reply::multiline(250, None, lines).to_lines());
+    let lines = vec!["a".to_owned(), "".to_owned(), "b".to_owned()];
+    assert_eq!(vec!["214-2.0.0 a", "214-2.0.0", "214 2.0.0 b"], Reply::multiline(214, Some(EnhancedStatusCode::new(2, 0, 0)), lines).to_lines());
+}
+
+#[test]
+fn test_parse() {
+    let reply = Reply::parse(&["250-rustastic.org", "250 SIZE 1000"]).unwrap();
+    assert_eq!(250, reply.code());
+    assert_eq!(None, reply.status());
+    assert_eq!(&["rustastic.org".to_owned(), "SIZE 1000".to_owned()], reply.lines());
+
+    let reply = Reply::parse(&["550 5.1.1 Mailbox not taken"]).unwrap();
+    assert_eq!(Some(EnhancedStatusCode::new(5, 1, 1)), reply.status());
+    assert_eq!(&["Mailbox not taken".to_owned()], reply.lines());
+    assert_eq!(vec!["550 5.1.1 Mailbox not taken"], reply.to_lines());
+
+    // Status codes can't be trusted unless all lines have the same one.
+    let reply = Reply::parse(&["250-2.0.0 a", "250 b"]).unwrap();
+    assert_eq!(None, reply.status());
+    assert_eq!(&["2.0.0 a".to_owned(), "b".to_owned()], reply.lines());
+
+    assert_eq!(Ok(Reply::new(250, "")), Reply::parse(&["250"]));
+    assert_eq!(Err(ReplyError::Empty), Reply::parse(&[]));
+    assert_eq!(Err(ReplyError::InvalidLine), Reply::parse(&["25O OK"]));
+    assert_eq!(Err(ReplyError::InvalidLine), Reply::parse(&["250_OK"]));
+    assert_eq!(Err(ReplyError::MixedCodes), Reply::parse(&["250-a", "251 b"]));
+    assert_eq!(Err(ReplyError::InvalidContinuation), Reply::parse(&["250 a", "250 b"]));
+    assert_eq!(Err(ReplyError::InvalidContinuation), Reply::parse(&["250-a"]));
+    assert_eq!(Err(ReplyError::InvalidCode), Reply::parse(&["199 OK"]));
+    assert_eq!(Err(ReplyError::InvalidCode), Reply::parse(&["650 OK"]));
+}
+
+#[test]
+fn test_reply_parser() {
+    let mut parser = ReplyParser::new();
+    assert_eq!(Ok(None), parser.push("250-rustastic.org"));
+    assert_eq!(Ok(None), parser.push("250-SIZE 1000"));
+    assert_eq!(2, parser.len());
+    let reply = parser.push("250 8BITMIME").unwrap().unwrap();
+    assert_eq!(&["rustastic.org".to_owned(), "SIZE 1000".to_owned(), "8BITMIME".to_owned()], reply.lines());
+    assert_eq!(0, parser.len());
+    assert_eq!(Ok(Some(Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 1), "No"))), parser.push("550 5.1.1 No"));
+
+    // Mixed codes are found without waiting for the last line.
+    assert_eq!(Ok(None), parser.push("250-rustastic.org"));
+    assert_eq!(Err(ReplyError::MixedCodes), parser.push("251-SIZE 1000"));
+    assert_eq!(Err(ReplyError::InvalidLine), ReplyParser::new().push("Hello"));
+}
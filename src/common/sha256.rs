@@ -0,0 +1,120 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! SHA-256, as described [in RFC 6234](http://tools.ietf.org/html/rfc6234).
+//!
+//! This is used to compare certificate keys with pins, and to sign audit logs.
+
+use std::vec::Vec;
+#[cfg(test)]
+use super::md5::to_hex;
+
+static K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+/// Returns the SHA-256 digest of the data.
+pub fn digest(data: &[u8]) -> [u8; 32] {
+    // Pad the message to a multiple of 64 bytes, ending with its length in bits.
+    let mut message: Vec<u8> = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    let bits = (data.len() as u64).wrapping_mul(8);
+    for i in 0 .. 8 {
+        message.push((bits >> (8 * (7 - i))) as u8);
+    }
+
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+    ];
+    for block in message.chunks(64) {
+        let mut words = [0u32; 64];
+        for i in 0 .. 16 {
+            words[i] = ((block[i * 4] as u32) << 24)
+                | ((block[i * 4 + 1] as u32) << 16)
+                | ((block[i * 4 + 2] as u32) << 8)
+                | (block[i * 4 + 3] as u32);
+        }
+        for i in 16 .. 64 {
+            let s0 = words[i - 15].rotate_right(7) ^ words[i - 15].rotate_right(18) ^ (words[i - 15] >> 3);
+            let s1 = words[i - 2].rotate_right(17) ^ words[i - 2].rotate_right(19) ^ (words[i - 2] >> 10);
+            words[i] = words[i - 16].wrapping_add(s0).wrapping_add(words[i - 7]).wrapping_add(s1);
+        }
+
+        let mut v = state;
+        for i in 0 .. 64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(words[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [t1.wrapping_add(t2), v[0], v[1], v[2], v[3].wrapping_add(t1), v[4], v[5], v[6]];
+        }
+
+        for i in 0 .. 8 {
+            state[i] = state[i].wrapping_add(v[i]);
+        }
+    }
+
+    let mut out = [0u8; 32];
+    for (i, word) in state.iter().enumerate() {
+        for j in 0 .. 4 {
+            out[i * 4 + j] = (*word >> (8 * (3 - j))) as u8;
+        }
+    }
+    out
+}
+
+/// Returns the HMAC-SHA-256 of the data with the given key, as described
+/// [in RFC 2104](http://tools.ietf.org/html/rfc2104).
+pub fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut key = match key.len() > 64 {
+        true => digest(key).to_vec(),
+        false => key.to_vec()
+    };
+    key.resize(64, 0);
+
+    let mut inner: Vec<u8> = key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend(data.iter().cloned());
+    let mut outer: Vec<u8> = key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend(digest(inner.as_ref()).iter().cloned());
+    digest(outer.as_ref())
+}
+
+#[test]
+fn test_digest() {
+    assert_eq!("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", to_hex(&digest(b"")));
+    assert_eq!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad", to_hex(&digest(b"abc")));
+    assert_eq!("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1", to_hex(&digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")));
+    assert_eq!("cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1", to_hex(&digest(b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu")));
+}
+
+#[test]
+fn test_hmac() {
+    assert_eq!("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843", to_hex(&hmac(b"Jefe", b"what do ya want for nothing?")));
+
+    // Keys longer than a block are hashed first.
+    let key = [0xaau8; 131];
+    assert_eq!("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54", to_hex(&hmac(&key, b"Test Using Larger Than Block-Size Key - Hash Key First")));
+}
@@ -0,0 +1,113 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Enhanced status codes, as described [in RFC 3463](http://tools.ietf.org/html/rfc3463).
+//!
+//! Servers advertising `ENHANCEDSTATUSCODES`, as described
+//! [in RFC 2034](http://tools.ietf.org/html/rfc2034), put one after the reply code of
+//! their replies, ie `2.1.0` in `250 2.1.0 OK`.
+
+use std::fmt;
+
+/// An enhanced status code, of the form `class.subject.detail`.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct EnhancedStatusCode {
+    /// `2` for success, `4` for a temporary failure or `5` for a permanent one.
+    pub class: u8,
+    /// What the status is about, ie `1` for addressing or `5` for the protocol.
+    pub subject: u16,
+    /// The status itself, within the subject.
+    pub detail: u16
+}
+
+impl EnhancedStatusCode {
+    /// Creates an enhanced status code.
+    pub fn new(class: u8, subject: u16, detail: u16) -> EnhancedStatusCode {
+        EnhancedStatusCode {
+            class: class,
+            subject: subject,
+            detail: detail
+        }
+    }
+
+    /// Parses an enhanced status code, ie `5.1.1`.
+    ///
+    /// The class must be 2, 4 or 5, and the subject and detail have at most 3 digits.
+    pub fn parse(s: &str) -> Option<EnhancedStatusCode> {
+        let parts: Vec<&str> = s.split('.').collect();
+        if parts.len() != 3 {
+            return None;
+        }
+        let valid = parts.iter().enumerate().all(|(i, part)| {
+            let max = if i == 0 { 1 } else { 3 };
+            part.len() > 0 && part.len() <= max && part.chars().all(|c| c >= '0' && c <= '9')
+        });
+        if !valid {
+            return None;
+        }
+        let code = EnhancedStatusCode::new(
+            parts[0].parse().unwrap(),
+            parts[1].parse().unwrap(),
+            parts[2].parse().unwrap()
+        );
+        match code.class {
+            2 | 4 | 5 => Some(code),
+            _ => None
+        }
+    }
+
+    /// Returns the enhanced status code of a reply line, ie `250 2.1.0 OK`.
+    ///
+    /// There is none if its class doesn't match the first digit of the reply code.
+    pub fn from_reply(line: &str) -> Option<EnhancedStatusCode> {
+        if line.len() < 4 || !line.is_char_boundary(4) {
+            return None;
+        }
+        let code = line[4 ..].split(' ').next().and_then(EnhancedStatusCode::parse);
+        match code {
+            Some(code) if line.as_bytes()[0] == b'0' + code.class => Some(code),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for EnhancedStatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(Some(EnhancedStatusCode::new(2, 1, 0)), EnhancedStatusCode::parse("2.1.0"));
+    assert_eq!(Some(EnhancedStatusCode::new(5, 1, 10)), EnhancedStatusCode::parse("5.1.10"));
+    assert_eq!(Some(EnhancedStatusCode::new(4, 999, 999)), EnhancedStatusCode::parse("4.999.999"));
+    assert_eq!(None, EnhancedStatusCode::parse("3.1.0"));
+    assert_eq!(None, EnhancedStatusCode::parse("2.1"));
+    assert_eq!(None, EnhancedStatusCode::parse("2.1.0.0"));
+    assert_eq!(None, EnhancedStatusCode::parse("2..0"));
+    assert_eq!(None, EnhancedStatusCode::parse("2.1000.0"));
+    assert_eq!(None, EnhancedStatusCode::parse("22.1.0"));
+    assert_eq!(None, EnhancedStatusCode::parse("2.+1.0"));
+    assert_eq!("5.7.8", EnhancedStatusCode::new(5, 7, 8).to_string());
+}
+
+#[test]
+fn test_from_reply() {
+    assert_eq!(Some(EnhancedStatusCode::new(2, 1, 0)), EnhancedStatusCode::from_reply("250 2.1.0 OK"));
+    assert_eq!(Some(EnhancedStatusCode::new(5, 5, 1)), EnhancedStatusCode::from_reply("503-5.5.1 Bad sequence"));
+    assert_eq!(None, EnhancedStatusCode::from_reply("250 OK"));
+    assert_eq!(None, EnhancedStatusCode::from_reply("250 5.1.0 OK"));
+    assert_eq!(None, EnhancedStatusCode::from_reply("250"));
+}
@@ -18,9 +18,23 @@ use std::io::{BufRead, Read, Write, ErrorKind};
 use std::io::Result as IoResult;
 use std::io::Error as IoError;
 use std::vec::Vec;
+use std::mem;
+use std::usize;
 #[cfg(test)]
 use std::fs::File;
 use std::ops::{RangeFrom, IndexMut};
+use std::cmp::min;
+use std::net::{TcpStream, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::borrow::ToOwned;
+use super::tls::TlsStream;
+use super::instrument::{InstrumentedStream, StreamStats};
+use super::delay::DelayPolicy;
+use super::reply::Reply;
+use super::transcript::Transcript;
 #[cfg(test)]
 use std::error::Error;
 #[cfg(test)]
@@ -28,21 +42,184 @@ use std::fs::OpenOptions;
 #[cfg(test)]
 use super::{MIN_ALLOWED_LINE_SIZE};
 #[cfg(test)]
+use super::status::EnhancedStatusCode;
+#[cfg(test)]
 use std::iter::{FromIterator, repeat};
+#[cfg(test)]
+use std::net::TcpListener;
 
 pub static LINE_TOO_LONG: &'static str = "line too long";
 pub static DATA_TOO_LONG: &'static str = "message too long";
+pub static UNEXPECTED_EOF: &'static str = "unexpected end of stream";
+pub static DATA_REJECTED: &'static str = "message rejected";
 
 #[test]
 fn test_static_vars() {
     // Already tested in the limits test further down.
 }
 
+/// The transport of an SMTP session, which can be upgraded to TLS with STARTTLS.
+///
+/// Once upgraded, the input and output of a session share the same TLS stream. Either
+/// way, the traffic is counted, see `stats`.
+pub enum Transport {
+    /// A plain text TCP connection.
+    Tcp(InstrumentedStream<TcpStream>),
+    /// A connection encrypted with TLS, and the socket under it.
+    Tls(Arc<Mutex<InstrumentedStream<Box<TlsStream>>>>, Arc<TcpStream>)
+}
+
+impl Transport {
+    /// Tells whether the transport is encrypted with TLS.
+    pub fn is_tls(&self) -> bool {
+        match *self {
+            Transport::Tcp(_) => false,
+            Transport::Tls(..) => true
+        }
+    }
+
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> IoResult<SocketAddr> {
+        match *self {
+            Transport::Tcp(ref stream) => stream.peer_addr(),
+            Transport::Tls(_, ref socket) => socket.peer_addr()
+        }
+    }
+
+    /// Sets how long reads wait for the peer before failing, see `is_timeout`.
+    ///
+    /// This applies to the socket, so it also holds for the streams sharing it. A zero
+    /// duration is an error.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+        match *self {
+            Transport::Tcp(ref stream) => stream.set_read_timeout(timeout),
+            Transport::Tls(_, ref socket) => socket.set_read_timeout(timeout)
+        }
+    }
+
+    /// Returns the socket of the connection, ie to wait for it to be readable.
+    ///
+    /// Reading from the socket of a TLS connection bypasses TLS.
+    pub fn socket(&self) -> &TcpStream {
+        match *self {
+            Transport::Tcp(ref stream) => stream,
+            Transport::Tls(_, ref socket) => socket
+        }
+    }
+
+    /// Returns the traffic of the connection, shared by the streams of a session.
+    ///
+    /// Over TLS, this counts the octets of the SMTP dialogue, not the encrypted ones.
+    pub fn stats(&self) -> Arc<StreamStats> {
+        match *self {
+            Transport::Tcp(ref stream) => stream.stats().clone(),
+            Transport::Tls(ref stream, _) => stream.lock().unwrap().stats().clone()
+        }
+    }
+}
+
+/// A stream an SMTP session runs over.
+///
+/// Servers run sessions over a `Transport` by default, but commands only need to read and
+/// write, so they work with any stream, ie an in-memory one in tests. The defaults suit
+/// streams that are neither sockets nor encrypted.
+pub trait SessionStream: Read + Write {
+    /// Tells whether the stream is encrypted with TLS.
+    fn is_tls(&self) -> bool {
+        false
+    }
+
+    /// Returns the address of the peer.
+    fn peer_addr(&self) -> IoResult<SocketAddr> {
+        Err(IoError::new(ErrorKind::NotConnected, "stream has no peer address"))
+    }
+
+    /// Sets how long reads wait for the peer before failing, see `is_timeout`.
+    ///
+    /// Streams without timeouts ignore this.
+    #[allow(unused_variables)]
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+        Ok(())
+    }
+
+    /// Returns the traffic of the stream, if it is counted.
+    fn traffic(&self) -> Option<Arc<StreamStats>> {
+        None
+    }
+}
+
+impl SessionStream for Transport {
+    fn is_tls(&self) -> bool {
+        Transport::is_tls(self)
+    }
+
+    fn peer_addr(&self) -> IoResult<SocketAddr> {
+        Transport::peer_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+        Transport::set_read_timeout(self, timeout)
+    }
+
+    fn traffic(&self) -> Option<Arc<StreamStats>> {
+        Some(Transport::stats(self))
+    }
+}
+
+impl SessionStream for TcpStream {
+    fn peer_addr(&self) -> IoResult<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+// Unix sockets have no peer address, so rate limits and trusted networks don't apply to
+// their clients.
+impl SessionStream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> IoResult<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Tells whether a read failed because the peer sent nothing for longer than the read
+/// timeout.
+pub fn is_timeout(err: &IoError) -> bool {
+    err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match *self {
+            Transport::Tcp(ref mut stream) => stream.read(buf),
+            Transport::Tls(ref stream, _) => stream.lock().unwrap().read(buf)
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        match *self {
+            Transport::Tcp(ref mut stream) => stream.write(buf),
+            Transport::Tls(ref stream, _) => stream.lock().unwrap().write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match *self {
+            Transport::Tcp(ref mut stream) => stream.flush(),
+            Transport::Tls(ref stream, _) => stream.lock().unwrap().flush()
+        }
+    }
+}
+
 /// A stream that reads lines of input.
 ///
 /// # Example
 /// ```no_run
-/// use std::net::TcpStream;
+/// use std::net::{TcpStream, SocketAddr};
 /// use rsmtp::common::stream::InputStream;
 /// use rsmtp::common::{
 ///     MIN_ALLOWED_LINE_SIZE,
@@ -67,7 +244,11 @@ pub struct InputStream<S> {
     /// If `true`, will print debug messages of input and output to the console.
     debug: bool,
     /// The position of the `<CRLF>` found at the previous `read_line`.
-    last_crlf: Option<usize>
+    last_crlf: Option<usize>,
+    /// The address of the client, when it isn't the peer of the socket.
+    peer_addr: Option<SocketAddr>,
+    /// Records the lines read, if any.
+    transcript: Option<Transcript>
 }
 
 // The state of the `<CRLF>` search inside a buffer. See below.
@@ -114,10 +295,55 @@ impl<S: Read> InputStream<S> {
             // that the buffer is large enough.
             buf: Vec::with_capacity(max_line_size),
             debug: debug,
-            last_crlf: None
+            last_crlf: None,
+            peer_addr: None,
+            transcript: None
         }
     }
 
+    /// Sets the transcript recording the lines read, see `common::transcript`.
+    pub fn set_transcript(&mut self, transcript: Option<Transcript>) {
+        self.transcript = transcript;
+    }
+
+    /// Returns the transcript recording the lines read, if any.
+    pub fn transcript(&self) -> Option<&Transcript> {
+        self.transcript.as_ref()
+    }
+
+    /// Sets the address of the client when it isn't the peer of the socket, ie when it
+    /// was given by a PROXY header, see `server::proxy`.
+    pub fn set_peer_addr(&mut self, addr: Option<SocketAddr>) {
+        self.peer_addr = addr;
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Replaces the underlying stream, returning the previous one.
+    ///
+    /// Buffered input is discarded, as it must be when a connection is upgraded to TLS
+    /// [as per RFC 3207](http://tools.ietf.org/html/rfc3207#section-6).
+    pub fn replace_stream(&mut self, inner: S) -> S {
+        self.buf.clear();
+        self.last_crlf = None;
+        mem::replace(&mut self.stream, inner)
+    }
+
+    /// Tells whether the client sent anything after the current line, ie whether it
+    /// pipelined commands [as per RFC 2920](http://tools.ietf.org/html/rfc2920).
+    pub fn has_pending_input(&self) -> bool {
+        self.buf.len() > self.last_crlf.map_or(0, |p| p + 2)
+    }
+
+    /// Tells whether a whole line after the current one is buffered, so that it can be
+    /// read without waiting for the client.
+    pub fn has_pending_line(&self) -> bool {
+        position_crlf(&self.buf[self.last_crlf.map_or(0, |p| p + 2) ..]).is_some()
+    }
+
     /// Remove the previous line from the buffer when reading a new line.
     pub fn move_buf(&mut self) {
         // Remove the last line, since we've used it already by now.
@@ -162,6 +388,15 @@ impl<S: Read> InputStream<S> {
 
     /// Read an SMTP command. Ends with `<CRLF>`.
     pub fn read_line(&mut self) -> IoResult<&[u8]> {
+        let end = try!(self.next_line());
+        if let Some(ref transcript) = self.transcript {
+            transcript.record_client(String::from_utf8_lossy(&self.buf[.. end]).as_ref());
+        }
+        Ok(&self.buf[.. end])
+    }
+
+    // Reads a line, and returns where it ends in the buffer.
+    fn next_line(&mut self) -> IoResult<usize> {
         // Remove the previous line from the buffer before reading a new one.
         self.move_buf();
 
@@ -170,17 +405,20 @@ impl<S: Read> InputStream<S> {
             // reduces the number of syscalls.
             Some(last_crlf) => {
                 self.last_crlf = Some(last_crlf);
-                Ok(&self.buf[.. last_crlf])
+                Ok(last_crlf)
             },
             // If we don't have a line in the buffer, we'll read more input
             // and try again.
             None => {
                 match self.fill_buf() {
+                    Ok(0) => {
+                        Err(IoError::new(ErrorKind::UnexpectedEof, UNEXPECTED_EOF))
+                    },
                     Ok(_) => {
                         match position_crlf(self.buf.as_ref()) {
                             Some(last_crlf) => {
                                 self.last_crlf = Some(last_crlf);
-                                Ok(&self.buf[.. last_crlf])
+                                Ok(last_crlf)
                             },
                             None => {
                                 // If we didn't find a line, it means we had
@@ -198,14 +436,153 @@ impl<S: Read> InputStream<S> {
         };
 
         // If we read a line, we'll say so in the console, if debug mode is on.
-        if let Ok(bytes) = read_line {
+        if let Ok(end) = read_line {
             if self.debug {
-                println!("rsmtp: imsg: {}", String::from_utf8_lossy(bytes.as_ref()));
+                println!("rsmtp: imsg: {}", String::from_utf8_lossy(&self.buf[.. end]));
             }
         }
 
         read_line
     }
+
+    /// Read the content of a DATA command, up to the line containing a single `.`.
+    ///
+    /// Lines are joined with `<CRLF>` and the leading dot added by the client's
+    /// [transparency mechanism](http://tools.ietf.org/html/rfc5321#section-4.5.2) is removed.
+    /// If the content is larger than `max_size`, the rest of it is still read so that the stream
+    /// stays in sync with the client, but an error is returned.
+    pub fn read_data(&mut self, max_size: usize) -> IoResult<Vec<u8>> {
+        self.read_data_chunked(max_size, usize::MAX, |_, _| true)
+    }
+
+    /// Read the content of a DATA command like `read_data`, calling `on_chunk` every time
+    /// at least `chunk_size` more octets were received.
+    ///
+    /// `on_chunk` gets the content received so far and where the new octets start. If it
+    /// returns `false`, reading stops right away and an error is returned, so the stream is
+    /// no longer in sync with the client.
+    pub fn read_data_chunked<F: FnMut(&[u8], usize) -> bool>(&mut self, max_size: usize, chunk_size: usize, mut on_chunk: F) -> IoResult<Vec<u8>> {
+        let mut data = Vec::new();
+        try!(self.read_data_into(max_size, chunk_size, |chunk, last| {
+            let start = data.len();
+            data.extend(chunk.iter().cloned());
+            last || on_chunk(data.as_ref(), start)
+        }));
+        Ok(data)
+    }
+
+    /// Read the content of a DATA command like `read_data`, but hand it over to `on_chunk`
+    /// instead of keeping it, so a large message never has to fit in memory.
+    ///
+    /// `on_chunk` gets the new content every time at least `chunk_size` octets were
+    /// received, and what is left once the content is over, with `last` set. If it returns
+    /// `false` before the last chunk, reading stops right away and an error is returned, so
+    /// the stream is no longer in sync with the client. Once the content is too long, the
+    /// rest of it is read without calling `on_chunk`.
+    pub fn read_data_into<F: FnMut(&[u8], bool) -> bool>(&mut self, max_size: usize, chunk_size: usize, mut on_chunk: F) -> IoResult<()> {
+        let mut chunk = Vec::new();
+        let mut size = 0;
+        let mut too_long = false;
+
+        loop {
+            let end = try!(self.next_line());
+            let line = &self.buf[.. end];
+            if line == b"." {
+                break;
+            }
+            let line = if line.len() > 0 && line[0] == b'.' {
+                &line[1 ..]
+            } else {
+                line
+            };
+            if too_long || size + line.len() + 2 > max_size {
+                too_long = true;
+            } else {
+                chunk.extend(line.iter().cloned());
+                chunk.extend(b"\r\n".iter().cloned());
+                size += line.len() + 2;
+                if chunk.len() >= chunk_size {
+                    if !on_chunk(chunk.as_ref(), false) {
+                        return Err(IoError::new(ErrorKind::InvalidInput, DATA_REJECTED));
+                    }
+                    chunk.clear();
+                }
+            }
+        }
+
+        // The message is recorded by its size, it may be huge.
+        if let Some(ref transcript) = self.transcript {
+            transcript.record_octets(size);
+            transcript.record_client(".");
+        }
+
+        match too_long {
+            true => Err(IoError::new(ErrorKind::InvalidInput, DATA_TOO_LONG)),
+            false => match on_chunk(chunk.as_ref(), true) {
+                true => Ok(()),
+                false => Err(IoError::new(ErrorKind::InvalidInput, DATA_REJECTED))
+            }
+        }
+    }
+
+    /// Read exactly `len` octets, as sent after a BDAT command.
+    ///
+    /// If `len` is larger than `max_size`, the octets are still read so that the stream stays
+    /// in sync with the client, but an error is returned.
+    pub fn read_bytes(&mut self, len: usize, max_size: usize) -> IoResult<Vec<u8>> {
+        // The octets start right after the previous line.
+        self.move_buf();
+
+        let keep = len <= max_size;
+        let mut bytes = Vec::with_capacity(if keep { len } else { 0 });
+        let mut remaining = len;
+
+        // Use what is already in the buffer first.
+        let from_buf = min(remaining, self.buf.len());
+        if keep {
+            bytes.extend(self.buf[.. from_buf].iter().cloned());
+        }
+        self.buf = self.buf[from_buf ..].to_vec();
+        self.buf.reserve(self.max_line_size);
+        remaining -= from_buf;
+
+        // Then read the rest directly from the underlying stream.
+        let mut chunk = [0u8; 4096];
+        while remaining > 0 {
+            let size = min(remaining, chunk.len());
+            let num_bytes = try!(self.stream.read(&mut chunk[.. size]));
+            if num_bytes == 0 {
+                return Err(IoError::new(ErrorKind::UnexpectedEof, UNEXPECTED_EOF));
+            }
+            if keep {
+                bytes.extend(chunk[.. num_bytes].iter().cloned());
+            }
+            remaining -= num_bytes;
+        }
+
+        if self.debug {
+            println!("rsmtp: imsg: <{} octets>", len);
+        }
+        if let Some(ref transcript) = self.transcript {
+            transcript.record_octets(len);
+        }
+
+        match keep {
+            true => Ok(bytes),
+            false => Err(IoError::new(ErrorKind::InvalidInput, DATA_TOO_LONG))
+        }
+    }
+}
+
+impl<S: SessionStream> InputStream<S> {
+    /// Returns the address of the client, as given to `set_peer_addr`, or else the peer of
+    /// the stream.
+    pub fn peer_addr(&self) -> IoResult<SocketAddr> {
+        match self.peer_addr {
+            Some(addr) => Ok(addr),
+            None => SessionStream::peer_addr(&self.stream)
+        }
+    }
 }
 
 /// A stream that writes lines of output.
@@ -214,6 +591,16 @@ pub struct OutputStream<S> {
     stream: S,
     /// If `true`, will print debug messages of input and output to the console.
     debug: bool,
+    /// The reply code of the last line written, if it had one.
+    last_reply_code: Option<u16>,
+    /// Lines written since the last flush.
+    buf: Vec<u8>,
+    /// Decides how long to wait before replies.
+    delay_policy: Option<Arc<DelayPolicy>>,
+    /// The verb of the command being replied to.
+    command: Option<String>,
+    /// Records the lines written, if any.
+    transcript: Option<Transcript>
 }
 
 impl<S: Write> OutputStream<S> {
@@ -222,19 +609,131 @@ impl<S: Write> OutputStream<S> {
         OutputStream {
             stream: inner,
             debug: debug,
+            last_reply_code: None,
+            buf: Vec::new(),
+            delay_policy: None,
+            command: None,
+            transcript: None
         }
     }
 
+    /// Sets the transcript recording the lines written, see `common::transcript`.
+    pub fn set_transcript(&mut self, transcript: Option<Transcript>) {
+        self.transcript = transcript;
+    }
+
+    /// Sets the policy deciding how long to wait before replies.
+    pub fn set_delay_policy(&mut self, policy: Option<Arc<DelayPolicy>>) {
+        self.delay_policy = policy;
+    }
+
+    /// Sets the verb of the command the next replies are for, as given to the
+    /// `DelayPolicy`.
+    pub fn set_command(&mut self, command: Option<&str>) {
+        self.command = command.map(|command| command.to_owned());
+    }
+
+    /// Returns the verb of the command being replied to, as given to `set_command`.
+    pub fn command(&self) -> Option<&str> {
+        self.command.as_ref().map(|command| command.as_ref())
+    }
+
+    /// Returns the reply code of the last line written, ie `250` for `250 OK`.
+    pub fn last_reply_code(&self) -> Option<u16> {
+        self.last_reply_code
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Replaces the underlying stream, returning the previous one.
+    ///
+    /// Lines that were not flushed are written to the new stream.
+    pub fn replace_stream(&mut self, inner: S) -> S {
+        mem::replace(&mut self.stream, inner)
+    }
+
     /// Write a line ended with `<CRLF>`.
+    ///
+    /// The line is buffered until `flush` is called, so replies to pipelined commands are
+    /// sent together. Anything that waits for the client after a reply must flush first.
     pub fn write_line(&mut self, s: &str) -> IoResult<()> {
+        let code = s.as_bytes();
+        self.last_reply_code = match code.len() >= 3 && code[.. 3].iter().all(|c| *c >= b'0' && *c <= b'9') {
+            true => s[.. 3].parse().ok(),
+            false => None
+        };
+        // Only the last line of a reply is delayed, so multiline replies are delayed once.
+        let last_line = code.len() == 3 || (code.len() > 3 && code[3] == b' ');
+        if let (Some(code), true) = (self.last_reply_code, last_line) {
+            self.delay(code);
+        }
+        self.push_line(s);
+        Ok(())
+    }
+
+    /// Writes a reply, with `-` after the code of every line but the last, as described
+    /// [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.2.1).
+    ///
+    /// All the lines have the same code, and the reply is delayed once, before its last
+    /// line, if a `DelayPolicy` is set.
+    pub fn write_reply(&mut self, reply: &Reply) -> IoResult<()> {
+        let lines = reply.to_lines();
+        let last = lines.len() - 1;
+        for (i, line) in lines.iter().enumerate() {
+            if i == last {
+                self.delay(reply.code());
+            }
+            self.push_line(line.as_ref());
+        }
+        self.last_reply_code = Some(reply.code());
+        Ok(())
+    }
+
+    /// Writes octets as they are, ie a message sent by a client after DATA.
+    ///
+    /// Like lines, they are buffered until `flush` is called.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> IoResult<()> {
+        if self.debug {
+            println!("rsmtp: omsg: {} octets", bytes.len());
+        }
+        self.buf.extend(bytes.iter().cloned());
+        Ok(())
+    }
+
+    // Waits as long as the delay policy says before a reply with this code.
+    fn delay(&self, code: u16) {
+        if let Some(ref policy) = self.delay_policy {
+            if let Some(delay) = policy.delay(self.command.as_ref().map(|command| command.as_ref()), code) {
+                thread::sleep(delay);
+            }
+        }
+    }
+
+    // Buffers a line, adding `<CRLF>`.
+    fn push_line(&mut self, s: &str) {
         if self.debug {
             println!("rsmtp: omsg: {}", s);
         }
-        // We use `format!()` instead of 2 calls to `write_str()` to reduce
-        // the amount of syscalls and to send the string as a single packet.
-        // I'm not sure if this is the right way to go though. If you think
-        // this is wrong, please open a issue on Github.
-        write!(&mut self.stream, "{}\r\n", s)
+        if let Some(ref transcript) = self.transcript {
+            transcript.record_server(s);
+        }
+        self.buf.extend(s.as_bytes().iter().cloned());
+        self.buf.extend(b"\r\n".iter().cloned());
+    }
+
+    /// Sends the buffered lines to the client.
+    ///
+    /// They are written with a single call to reduce the amount of syscalls and to send
+    /// them as a single packet where possible.
+    pub fn flush(&mut self) -> IoResult<()> {
+        if self.buf.len() > 0 {
+            try!(self.stream.write_all(self.buf.as_ref()));
+            self.buf.clear();
+        }
+        self.stream.flush()
     }
 }
 
@@ -257,6 +756,7 @@ fn test_write_line() {
         stream = OutputStream::new(file_write, false);
         stream.write_line("HelloWorld").unwrap();
         stream.write_line("ByeBye").unwrap();
+        stream.flush().unwrap();
     }
     let mut file_read: File;
     let mut expected = String::new();
@@ -269,6 +769,70 @@ fn test_write_line() {
     assert_eq!("HelloWorld\r\nByeBye\r\n", expected.as_str());
 }
 
+#[test]
+fn test_flush() {
+    let mut stream = OutputStream::new(Vec::new(), false);
+    stream.write_line("250 OK").unwrap();
+    stream.write_line("354 Start mail input").unwrap();
+    assert_eq!(0, stream.get_ref().len());
+    stream.flush().unwrap();
+    assert_eq!(&b"250 OK\r\n354 Start mail input\r\n"[..], &stream.get_ref()[..]);
+    stream.flush().unwrap();
+    assert_eq!(30, stream.get_ref().len());
+}
+
+#[test]
+fn test_last_reply_code() {
+    let mut stream = OutputStream::new(Vec::new(), false);
+    assert_eq!(None, stream.last_reply_code());
+    stream.write_line("354 Start mail input").unwrap();
+    assert_eq!(Some(354), stream.last_reply_code());
+    stream.write_line("552-Too").unwrap();
+    assert_eq!(Some(552), stream.last_reply_code());
+    stream.write_line("HelloWorld").unwrap();
+    assert_eq!(None, stream.last_reply_code());
+}
+
+#[cfg(test)]
+struct RecordingPolicy {
+    calls: Mutex<Vec<(Option<String>, u16)>>
+}
+
+#[cfg(test)]
+impl DelayPolicy for RecordingPolicy {
+    fn delay(&self, command: Option<&str>, code: u16) -> Option<Duration> {
+        self.calls.lock().unwrap().push((command.map(|command| command.to_owned()), code));
+        Some(Duration::from_millis(1))
+    }
+}
+
+#[test]
+fn test_delay_policy() {
+    let policy = Arc::new(RecordingPolicy { calls: Mutex::new(Vec::new()) });
+    let mut stream = OutputStream::new(Vec::new(), false);
+    stream.set_delay_policy(Some(policy.clone()));
+    stream.write_line("220 Service ready").unwrap();
+    stream.set_command(Some("EHLO"));
+    stream.write_line("250-rustastic.org").unwrap();
+    stream.write_line("250 SIZE 1000").unwrap();
+    stream.write_line("HelloWorld").unwrap();
+    assert_eq!(vec![(None, 220), (Some("EHLO".to_owned()), 250)], *policy.calls.lock().unwrap());
+}
+
+#[test]
+fn test_write_reply() {
+    let policy = Arc::new(RecordingPolicy { calls: Mutex::new(Vec::new()) });
+    let mut stream = OutputStream::new(Vec::new(), false);
+    stream.set_delay_policy(Some(policy.clone()));
+    let lines = vec!["rustastic.org".to_owned(), "PIPELINING".to_owned(), "SIZE 1000".to_owned()];
+    stream.write_reply(&Reply::multiline(250, None, lines)).unwrap();
+    stream.write_reply(&Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 1), "Mailbox\nnot taken")).unwrap();
+    assert_eq!(Some(550), stream.last_reply_code());
+    stream.flush().unwrap();
+    assert_eq!(&b"250-rustastic.org\r\n250-PIPELINING\r\n250 SIZE 1000\r\n550-5.1.1 Mailbox\r\n550 5.1.1 not taken\r\n"[..], &stream.get_ref()[..]);
+    assert_eq!(vec![(None, 250), (None, 550)], *policy.calls.lock().unwrap());
+}
+
 #[test]
 fn test_limits() {
     let mut file: File;
@@ -333,3 +897,150 @@ fn test_read_line() {
     assert_eq!(String::from_utf8_lossy(stream.read_line().unwrap().as_ref()).to_owned(), expected);
     assert!(!stream.read_line().is_ok());
 }
+
+#[test]
+fn test_read_data() {
+    let mut file: File;
+    let mut stream: InputStream<File>;
+
+    file = OpenOptions::new().read(true).open("tests/stream/data1").unwrap();
+    stream = InputStream::new(file, MIN_ALLOWED_LINE_SIZE, false);
+    assert_eq!(b"Subject: hi\r\n\r\nhello\r\n.dotted\r\n".to_vec(), stream.read_data(1000).unwrap());
+    assert_eq!(String::from_utf8_lossy(stream.read_line().unwrap().as_ref()).to_owned().as_ref(), "QUIT");
+
+    // The whole message is read even if it is too long.
+    file = OpenOptions::new().read(true).open("tests/stream/data1").unwrap();
+    stream = InputStream::new(file, MIN_ALLOWED_LINE_SIZE, false);
+    match stream.read_data(10) {
+        Ok(_) => panic!(),
+        Err(err) => {
+            assert_eq!("message too long", err.description());
+            assert_eq!(ErrorKind::InvalidInput, err.kind());
+        }
+    }
+    assert_eq!(String::from_utf8_lossy(stream.read_line().unwrap().as_ref()).to_owned().as_ref(), "QUIT");
+
+    // No terminating dot.
+    file = OpenOptions::new().read(true).open("tests/stream/data2").unwrap();
+    stream = InputStream::new(file, MIN_ALLOWED_LINE_SIZE, false);
+    assert!(!stream.read_data(1000).is_ok());
+
+    // Chunks are reported at line boundaries, once they are large enough.
+    file = OpenOptions::new().read(true).open("tests/stream/data1").unwrap();
+    stream = InputStream::new(file, MIN_ALLOWED_LINE_SIZE, false);
+    let mut chunks = Vec::new();
+    stream.read_data_chunked(1000, 10, |data, start| {
+        chunks.push((start, data.len()));
+        true
+    }).unwrap();
+    assert_eq!(vec![(0, 13), (13, 31)], chunks);
+
+    file = OpenOptions::new().read(true).open("tests/stream/data1").unwrap();
+    stream = InputStream::new(file, MIN_ALLOWED_LINE_SIZE, false);
+    match stream.read_data_chunked(1000, 1, |data, _| !data.ends_with(b"hello\r\n")) {
+        Ok(_) => panic!(),
+        Err(err) => assert_eq!("message rejected", err.description())
+    }
+    assert_eq!(b"..dotted".to_vec(), stream.read_line().unwrap().to_vec());
+
+    // The content is handed over instead of being kept.
+    file = OpenOptions::new().read(true).open("tests/stream/data1").unwrap();
+    stream = InputStream::new(file, MIN_ALLOWED_LINE_SIZE, false);
+    let mut chunks = Vec::new();
+    stream.read_data_into(1000, 10, |chunk, last| {
+        chunks.push((chunk.to_vec(), last));
+        true
+    }).unwrap();
+    assert_eq!(vec![
+        (b"Subject: hi\r\n".to_vec(), false),
+        (b"\r\nhello\r\n.dotted\r\n".to_vec(), false),
+        (Vec::new(), true)
+    ], chunks);
+}
+
+#[test]
+fn test_read_bytes() {
+    let mut file: File;
+    let mut stream: InputStream<File>;
+
+    file = OpenOptions::new().read(true).open("tests/stream/bdat1").unwrap();
+    stream = InputStream::new(file, MIN_ALLOWED_LINE_SIZE, false);
+    assert_eq!(String::from_utf8_lossy(stream.read_line().unwrap().as_ref()).to_owned().as_ref(), "BDAT 13");
+    assert_eq!(b"hello\r\nworld!".to_vec(), stream.read_bytes(13, 1000).unwrap());
+    assert_eq!(String::from_utf8_lossy(stream.read_line().unwrap().as_ref()).to_owned().as_ref(), "QUIT");
+
+    // The octets are read even if there are too many of them.
+    file = OpenOptions::new().read(true).open("tests/stream/bdat1").unwrap();
+    stream = InputStream::new(file, MIN_ALLOWED_LINE_SIZE, false);
+    stream.read_line().unwrap();
+    match stream.read_bytes(13, 12) {
+        Ok(_) => panic!(),
+        Err(err) => {
+            assert_eq!("message too long", err.description());
+            assert_eq!(ErrorKind::InvalidInput, err.kind());
+        }
+    }
+    assert_eq!(String::from_utf8_lossy(stream.read_line().unwrap().as_ref()).to_owned().as_ref(), "QUIT");
+
+    // Reading past the end of the buffer.
+    file = OpenOptions::new().read(true).open("tests/stream/bdat1").unwrap();
+    stream = InputStream::new(file, 10, false);
+    stream.read_line().unwrap();
+    assert_eq!(b"hel".to_vec(), stream.read_bytes(3, 1000).unwrap());
+    assert_eq!(b"lo\r\nworld!QUIT\r\n".to_vec(), stream.read_bytes(16, 1000).unwrap());
+
+    // Not enough octets.
+    file = OpenOptions::new().read(true).open("tests/stream/bdat1").unwrap();
+    stream = InputStream::new(file, MIN_ALLOWED_LINE_SIZE, false);
+    stream.read_line().unwrap();
+    match stream.read_bytes(100, 1000) {
+        Ok(_) => panic!(),
+        Err(err) => {
+            assert_eq!(ErrorKind::UnexpectedEof, err.kind());
+        }
+    }
+}
+
+#[test]
+fn test_replace_stream() {
+    let mut file: File;
+    let mut stream: InputStream<File>;
+
+    file = OpenOptions::new().read(true).open("tests/stream/2lines1").unwrap();
+    stream = InputStream::new(file, MIN_ALLOWED_LINE_SIZE, false);
+    assert_eq!(String::from_utf8_lossy(stream.read_line().unwrap().as_ref()).to_owned().as_ref(), "hello world!");
+
+    // The second line was buffered but must not be visible from the new stream.
+    file = OpenOptions::new().read(true).open("tests/stream/1line1").unwrap();
+    stream.replace_stream(file);
+    assert_eq!(String::from_utf8_lossy(stream.read_line().unwrap().as_ref()).to_owned().as_ref(), "hello world!");
+    assert!(!stream.read_line().is_ok());
+}
+
+#[test]
+fn test_pending_input() {
+    let mut stream = InputStream::new(&b"MAIL FROM:<a@b>\r\nRCPT TO:<c@d>\r\nDATA"[..], MIN_ALLOWED_LINE_SIZE, false);
+    assert!(!stream.has_pending_input());
+    stream.read_line().unwrap();
+    assert!(stream.has_pending_input());
+    assert!(stream.has_pending_line());
+    stream.read_line().unwrap();
+    assert!(stream.has_pending_input());
+    assert!(!stream.has_pending_line());
+}
+
+#[test]
+fn test_read_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let mut input = InputStream::new(Transport::Tcp(InstrumentedStream::new(stream)), MIN_ALLOWED_LINE_SIZE, false);
+
+    input.get_ref().set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    assert!(is_timeout(&input.read_line().unwrap_err()));
+
+    // The session can go on once the peer sends something.
+    client.write_all(b"NOOP\r\n").unwrap();
+    assert_eq!(b"NOOP", input.read_line().unwrap());
+    assert!(input.get_ref().set_read_timeout(Some(Duration::from_millis(0))).is_err());
+}
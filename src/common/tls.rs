@@ -0,0 +1,124 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Abstractions over TLS implementations.
+//!
+//! This crate doesn't depend on a TLS library. Instead, you can plug in the one of your
+//! choice by implementing the traits below, usually with a few lines of code.
+
+use std::io::{Read, Write};
+use std::io::Result as IoResult;
+use std::net::TcpStream;
+use std::time::Duration;
+use std::vec::Vec;
+
+/// A stream encrypted with TLS.
+///
+/// This is implemented for every `Read + Write + Send` type, so the stream types of TLS
+/// libraries can be used as is.
+pub trait TlsStream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> TlsStream for T {}
+
+/// Performs the server side of TLS handshakes.
+pub trait TlsAcceptor: Send + Sync {
+    /// Performs a TLS handshake with a connected client.
+    fn accept(&self, stream: TcpStream) -> IoResult<Box<TlsStream>>;
+
+    /// Performs a TLS handshake with a connected client, applying the options, and
+    /// returns what was negotiated.
+    ///
+    /// This is what the server calls. By default, the options are ignored and nothing is
+    /// known about the handshake, so implement it to support the options.
+    #[allow(unused_variables)]
+    fn accept_with(&self, stream: TcpStream, options: &TlsOptions) -> IoResult<(Box<TlsStream>, ConnectionInfo)> {
+        self.accept(stream).map(|stream| (stream, ConnectionInfo::new()))
+    }
+}
+
+/// Performance and negotiation options of the server side of TLS handshakes.
+///
+/// See `Server::set_tls_options`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct TlsOptions {
+    /// Whether clients can resume earlier sessions, with tickets or session ids.
+    pub resumption: bool,
+    /// The ALPN protocols offered, in order of preference. Empty to not use ALPN.
+    pub alpn: Vec<String>,
+    /// The cipher suites allowed, in order of preference. Empty for the defaults of the
+    /// TLS library.
+    pub ciphers: Vec<String>,
+    /// How long the handshake can take before the session ends, `None` for no limit.
+    pub handshake_timeout: Option<Duration>
+}
+
+impl TlsOptions {
+    /// Creates options allowing resumption, without ALPN, with the default cipher suites
+    /// and a handshake timeout of one minute.
+    pub fn new() -> TlsOptions {
+        TlsOptions {
+            resumption: true,
+            alpn: Vec::new(),
+            ciphers: Vec::new(),
+            handshake_timeout: Some(Duration::from_secs(60))
+        }
+    }
+}
+
+/// What a TLS handshake negotiated with a client.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ConnectionInfo {
+    /// The protocol version, ie `TLSv1.3`, if known.
+    pub protocol: Option<String>,
+    /// The name of the cipher suite, if known.
+    pub cipher: Option<String>,
+    /// The ALPN protocol agreed on, if any.
+    pub alpn: Option<String>,
+    /// Whether an earlier session was resumed, skipping the full handshake.
+    pub resumed: bool
+}
+
+impl ConnectionInfo {
+    /// Creates the information of a handshake nothing is known about.
+    pub fn new() -> ConnectionInfo {
+        ConnectionInfo {
+            protocol: None,
+            cipher: None,
+            alpn: None,
+            resumed: false
+        }
+    }
+}
+
+/// What a TLS handshake negotiated with a server.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct TlsInfo {
+    /// The protocol version, ie `TLSv1.2`.
+    pub protocol: String,
+    /// The name of the cipher suite.
+    pub cipher: String,
+    /// The DER encoded SubjectPublicKeyInfo of the server certificate.
+    pub peer_spki: Vec<u8>,
+    /// Whether the server certificate chains up to a trusted root and matches the domain.
+    pub chain_verified: bool
+}
+
+/// Performs the client side of TLS handshakes.
+pub trait TlsConnector: Send + Sync {
+    /// Performs a TLS handshake with a server, expecting a certificate for the domain.
+    ///
+    /// The handshake must not fail because of the certificate. Whether it is acceptable is
+    /// decided by the client from the returned `TlsInfo`.
+    fn connect(&self, domain: &str, stream: TcpStream) -> IoResult<(Box<TlsStream>, TlsInfo)>;
+}
@@ -0,0 +1,491 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! A small parser for the subset of [TOML](https://toml.io/en/v0.4.0) used by
+//! configuration files.
+//!
+//! Supported are comments, `[table]` headers with dotted names, bare and quoted keys,
+//! basic and literal strings, integers, booleans and arrays, which may span several
+//! lines. Floats, dates, inline tables, arrays of tables and multi-line strings are not.
+
+use std::vec::Vec;
+use std::char;
+use std::borrow::ToOwned;
+
+/// A TOML value.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Toml {
+    /// `true` or `false`.
+    Bool(bool),
+    /// An integer.
+    Integer(i64),
+    /// A string.
+    String(String),
+    /// An array.
+    Array(Vec<Toml>),
+    /// A table, with its keys in order.
+    Table(Vec<(String, Toml)>)
+}
+
+/// Represents an error that occured while trying to parse TOML.
+///
+/// Each variant gives the line of the error, starting at 1.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum TomlError {
+    /// The text ended in the middle of a value.
+    UnexpectedEnd(usize),
+    /// A character isn't valid there.
+    UnexpectedChar(usize),
+    /// A key or a table is defined twice.
+    DuplicateKey(usize),
+    /// An integer doesn't fit in 64 bits.
+    NumberTooLarge(usize)
+}
+
+impl Toml {
+    /// Parses a TOML document, which is returned as a table.
+    pub fn parse(s: &str) -> Result<Toml, TomlError> {
+        let mut parser = Parser { s: s.as_bytes(), offset: 0, line: 1 };
+        let mut root = Vec::new();
+        let mut path: Vec<String> = Vec::new();
+        let mut headers: Vec<Vec<String>> = Vec::new();
+        loop {
+            parser.skip_blank_lines();
+            if parser.offset == parser.s.len() {
+                return Ok(Toml::Table(root));
+            }
+            if parser.s[parser.offset] == b'[' {
+                let line = parser.line;
+                path = try!(parser.header());
+                if headers.contains(&path) || !define_table(&mut root, &path) {
+                    return Err(TomlError::DuplicateKey(line));
+                }
+                headers.push(path.clone());
+            } else {
+                let line = parser.line;
+                let key = try!(parser.key());
+                parser.skip_whitespace();
+                try!(parser.expect(b'='));
+                parser.skip_whitespace();
+                let value = try!(parser.value());
+                let table = table_at(&mut root, &path).unwrap();
+                if table.iter().any(|&(ref existing, _)| *existing == key) {
+                    return Err(TomlError::DuplicateKey(line));
+                }
+                table.push((key, value));
+            }
+            try!(parser.end_of_line());
+        }
+    }
+
+    /// Returns the value of a key, if this is a table that has it.
+    pub fn get(&self, key: &str) -> Option<&Toml> {
+        match *self {
+            Toml::Table(ref entries) => {
+                entries.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref value)| value)
+            },
+            _ => None
+        }
+    }
+
+    /// Returns the string, if this is one.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Toml::String(ref s) => Some(s.as_ref()),
+            _ => None
+        }
+    }
+
+    /// Returns the integer, if this is one.
+    pub fn as_integer(&self) -> Option<i64> {
+        match *self {
+            Toml::Integer(n) => Some(n),
+            _ => None
+        }
+    }
+
+    /// Returns the boolean, if this is one.
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Toml::Bool(b) => Some(b),
+            _ => None
+        }
+    }
+
+    /// Returns the elements, if this is an array.
+    pub fn as_array(&self) -> Option<&[Toml]> {
+        match *self {
+            Toml::Array(ref elements) => Some(elements.as_ref()),
+            _ => None
+        }
+    }
+
+    /// Returns the keys and values, if this is a table.
+    pub fn as_table(&self) -> Option<&[(String, Toml)]> {
+        match *self {
+            Toml::Table(ref entries) => Some(entries.as_ref()),
+            _ => None
+        }
+    }
+}
+
+// Creates the tables on the path that don't exist yet. Fails if a value that isn't a
+// table is in the way.
+fn define_table(root: &mut Vec<(String, Toml)>, path: &[String]) -> bool {
+    let mut table = root;
+    for key in path.iter() {
+        let position = match table.iter().position(|&(ref existing, _)| existing == key) {
+            Some(position) => position,
+            None => {
+                table.push((key.clone(), Toml::Table(Vec::new())));
+                table.len() - 1
+            }
+        };
+        let current = table;
+        table = match current[position].1 {
+            Toml::Table(ref mut inner) => inner,
+            _ => return false
+        };
+    }
+    true
+}
+
+fn table_at<'a>(table: &'a mut Vec<(String, Toml)>, path: &[String]) -> Option<&'a mut Vec<(String, Toml)>> {
+    match path.split_first() {
+        None => Some(table),
+        Some((first, rest)) => {
+            match table.iter_mut().find(|&&mut (ref key, _)| key == first) {
+                Some(&mut (_, Toml::Table(ref mut inner))) => table_at(inner, rest),
+                _ => None
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    offset: usize,
+    line: usize
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.offset < self.s.len() && (self.s[self.offset] == b' ' || self.s[self.offset] == b'\t') {
+            self.offset += 1;
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.offset < self.s.len() && self.s[self.offset] == b'#' {
+            while self.offset < self.s.len() && self.s[self.offset] != b'\n' {
+                self.offset += 1;
+            }
+        }
+    }
+
+    // Skips a newline, `\n` or `\r\n`, if there is one.
+    fn skip_newline(&mut self) -> bool {
+        if self.s[self.offset ..].starts_with(b"\r\n") {
+            self.offset += 2;
+        } else if self.s[self.offset ..].starts_with(b"\n") {
+            self.offset += 1;
+        } else {
+            return false;
+        }
+        self.line += 1;
+        true
+    }
+
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_whitespace();
+            self.skip_comment();
+            if !self.skip_newline() {
+                return;
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), TomlError> {
+        self.skip_whitespace();
+        self.skip_comment();
+        match self.offset == self.s.len() || self.skip_newline() {
+            true => Ok(()),
+            false => Err(TomlError::UnexpectedChar(self.line))
+        }
+    }
+
+    fn peek(&self) -> Result<u8, TomlError> {
+        match self.s.get(self.offset) {
+            Some(&c) => Ok(c),
+            None => Err(TomlError::UnexpectedEnd(self.line))
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), TomlError> {
+        match try!(self.peek()) == c {
+            true => {
+                self.offset += 1;
+                Ok(())
+            },
+            false => Err(TomlError::UnexpectedChar(self.line))
+        }
+    }
+
+    fn header(&mut self) -> Result<Vec<String>, TomlError> {
+        try!(self.expect(b'['));
+        let mut path = Vec::new();
+        loop {
+            self.skip_whitespace();
+            path.push(try!(self.key()));
+            self.skip_whitespace();
+            match try!(self.peek()) {
+                b'.' => self.offset += 1,
+                b']' => {
+                    self.offset += 1;
+                    return Ok(path);
+                },
+                _ => return Err(TomlError::UnexpectedChar(self.line))
+            }
+        }
+    }
+
+    fn key(&mut self) -> Result<String, TomlError> {
+        match try!(self.peek()) {
+            b'"' => self.basic_string(),
+            b'\'' => self.literal_string(),
+            _ => {
+                let start = self.offset;
+                while self.offset < self.s.len() && is_bare_key_char(self.s[self.offset]) {
+                    self.offset += 1;
+                }
+                match self.offset > start {
+                    true => Ok(String::from_utf8(self.s[start .. self.offset].to_vec()).unwrap()),
+                    false => Err(TomlError::UnexpectedChar(self.line))
+                }
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Toml, TomlError> {
+        match try!(self.peek()) {
+            b'"' => self.basic_string().map(Toml::String),
+            b'\'' => self.literal_string().map(Toml::String),
+            b'[' => self.array(),
+            b't' => self.literal("true", Toml::Bool(true)),
+            b'f' => self.literal("false", Toml::Bool(false)),
+            b'+' | b'-' | b'0' ... b'9' => self.integer(),
+            _ => Err(TomlError::UnexpectedChar(self.line))
+        }
+    }
+
+    fn literal(&mut self, literal: &str, value: Toml) -> Result<Toml, TomlError> {
+        match self.s[self.offset ..].starts_with(literal.as_bytes()) {
+            true => {
+                self.offset += literal.len();
+                Ok(value)
+            },
+            false => Err(TomlError::UnexpectedChar(self.line))
+        }
+    }
+
+    fn integer(&mut self) -> Result<Toml, TomlError> {
+        let negative = match self.s[self.offset] {
+            b'-' => {
+                self.offset += 1;
+                true
+            },
+            b'+' => {
+                self.offset += 1;
+                false
+            },
+            _ => false
+        };
+        let start = self.offset;
+        let mut n: i64 = 0;
+        let mut last_digit = false;
+        while self.offset < self.s.len() {
+            match self.s[self.offset] {
+                c @ b'0' ... b'9' => {
+                    let digit = (c - b'0') as i64;
+                    n = match n.checked_mul(10).and_then(|n| match negative {
+                        true => n.checked_sub(digit),
+                        false => n.checked_add(digit)
+                    }) {
+                        Some(n) => n,
+                        None => return Err(TomlError::NumberTooLarge(self.line))
+                    };
+                    last_digit = true;
+                },
+                // Underscores may only separate digits.
+                b'_' if last_digit => last_digit = false,
+                _ => break
+            }
+            self.offset += 1;
+        }
+        // Leading zeros aren't allowed, and neither are trailing underscores.
+        let digits = &self.s[start .. self.offset];
+        if !last_digit || (digits.len() > 1 && digits[0] == b'0') {
+            return Err(TomlError::UnexpectedChar(self.line));
+        }
+        Ok(Toml::Integer(n))
+    }
+
+    fn basic_string(&mut self) -> Result<String, TomlError> {
+        try!(self.expect(b'"'));
+        let mut bytes = Vec::new();
+        loop {
+            match try!(self.peek()) {
+                b'"' => {
+                    self.offset += 1;
+                    // The input is a `str` and escapes are pushed as UTF-8, so this can't fail.
+                    return Ok(String::from_utf8(bytes).unwrap());
+                },
+                b'\\' => {
+                    self.offset += 1;
+                    let c = try!(self.peek());
+                    self.offset += 1;
+                    let c = match c {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'b' => '\x08',
+                        b't' => '\t',
+                        b'n' => '\n',
+                        b'f' => '\x0c',
+                        b'r' => '\r',
+                        b'u' => try!(self.unicode_escape(4)),
+                        b'U' => try!(self.unicode_escape(8)),
+                        _ => return Err(TomlError::UnexpectedChar(self.line))
+                    };
+                    let mut buf = String::new();
+                    buf.push(c);
+                    bytes.extend(buf.as_bytes().iter().cloned());
+                },
+                b'\n' | b'\r' => return Err(TomlError::UnexpectedChar(self.line)),
+                c => {
+                    bytes.push(c);
+                    self.offset += 1;
+                }
+            }
+        }
+    }
+
+    fn unicode_escape(&mut self, len: usize) -> Result<char, TomlError> {
+        if self.offset + len > self.s.len() {
+            return Err(TomlError::UnexpectedEnd(self.line));
+        }
+        let mut n = 0;
+        for &c in self.s[self.offset .. self.offset + len].iter() {
+            n = n * 16 + match c {
+                b'0' ... b'9' => (c - b'0') as u32,
+                b'a' ... b'f' => (c - b'a' + 10) as u32,
+                b'A' ... b'F' => (c - b'A' + 10) as u32,
+                _ => return Err(TomlError::UnexpectedChar(self.line))
+            };
+        }
+        self.offset += len;
+        char::from_u32(n).ok_or(TomlError::UnexpectedChar(self.line))
+    }
+
+    fn literal_string(&mut self) -> Result<String, TomlError> {
+        try!(self.expect(b'\''));
+        let start = self.offset;
+        loop {
+            match try!(self.peek()) {
+                b'\'' => {
+                    let s = String::from_utf8(self.s[start .. self.offset].to_vec()).unwrap();
+                    self.offset += 1;
+                    return Ok(s);
+                },
+                b'\n' | b'\r' => return Err(TomlError::UnexpectedChar(self.line)),
+                _ => self.offset += 1
+            }
+        }
+    }
+
+    // Arrays may span several lines, and have comments between their elements.
+    fn array(&mut self) -> Result<Toml, TomlError> {
+        try!(self.expect(b'['));
+        let mut elements = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            if try!(self.peek()) == b']' {
+                self.offset += 1;
+                return Ok(Toml::Array(elements));
+            }
+            elements.push(try!(self.value()));
+            self.skip_blank_lines();
+            match try!(self.peek()) {
+                b',' => self.offset += 1,
+                b']' => {
+                    self.offset += 1;
+                    return Ok(Toml::Array(elements));
+                },
+                _ => return Err(TomlError::UnexpectedChar(self.line))
+            }
+        }
+    }
+}
+
+fn is_bare_key_char(c: u8) -> bool {
+    match c {
+        b'A' ... b'Z' | b'a' ... b'z' | b'0' ... b'9' | b'_' | b'-' => true,
+        _ => false
+    }
+}
+
+#[test]
+fn test_parse() {
+    let toml = Toml::parse("# A server.\r\n\
+                            hostname = \"rustastic.org\" # The name.\r\n\
+                            ports = [25, 587,\r\n\
+                            \x20   465, # Implicit TLS.\r\n\
+                            ]\r\n\
+                            \r\n\
+                            [limits]\r\n\
+                            max_message_size = 10_485_760\r\n\
+                            'quoted key' = -1\r\n\
+                            [tls.files]\r\n\
+                            certificate = 'C:\\certs\\smtp.pem'\r\n\
+                            escaped = \"\\\"\\u00e9\\t\"\r\n\
+                            enabled = true").unwrap();
+    assert_eq!(Some("rustastic.org"), toml.get("hostname").and_then(|v| v.as_str()));
+    assert_eq!(Some(&[Toml::Integer(25), Toml::Integer(587), Toml::Integer(465)][..]),
+               toml.get("ports").and_then(|v| v.as_array()));
+    let limits = toml.get("limits").unwrap();
+    assert_eq!(Some(10485760), limits.get("max_message_size").and_then(|v| v.as_integer()));
+    assert_eq!(Some(-1), limits.get("quoted key").and_then(|v| v.as_integer()));
+    let files = toml.get("tls").and_then(|v| v.get("files")).unwrap();
+    assert_eq!(Some("C:\\certs\\smtp.pem"), files.get("certificate").and_then(|v| v.as_str()));
+    assert_eq!(Some("\"é\t"), files.get("escaped").and_then(|v| v.as_str()));
+    assert_eq!(Some(true), files.get("enabled").and_then(|v| v.as_bool()));
+    assert_eq!(Some(4), toml.as_table().map(|entries| entries.len()));
+
+    assert_eq!(Ok(Toml::Table(vec![])), Toml::parse(""));
+    assert_eq!(Ok(Toml::Table(vec![("a".to_owned(), Toml::Array(vec![]))])), Toml::parse("a = []"));
+    assert_eq!(Err(TomlError::DuplicateKey(2)), Toml::parse("a = 1\na = 2"));
+    assert_eq!(Err(TomlError::DuplicateKey(3)), Toml::parse("[a]\n[b]\n[a]"));
+    assert_eq!(Err(TomlError::DuplicateKey(2)), Toml::parse("a = 1\n[a.b]"));
+    assert_eq!(Err(TomlError::UnexpectedChar(1)), Toml::parse("a = 1 2"));
+    assert_eq!(Err(TomlError::UnexpectedChar(2)), Toml::parse("\na = 1.5"));
+    assert_eq!(Err(TomlError::UnexpectedChar(1)), Toml::parse("a = 01"));
+    assert_eq!(Err(TomlError::UnexpectedChar(1)), Toml::parse("a = 1_"));
+    assert_eq!(Err(TomlError::UnexpectedChar(1)), Toml::parse("a = \"b\nc\""));
+    assert_eq!(Err(TomlError::UnexpectedEnd(2)), Toml::parse("a = [1,\n"));
+    assert_eq!(Err(TomlError::UnexpectedEnd(1)), Toml::parse("a ="));
+    assert_eq!(Err(TomlError::NumberTooLarge(1)), Toml::parse("a = 9223372036854775808"));
+    assert_eq!(Ok(Toml::Table(vec![("a".to_owned(), Toml::Integer(-9223372036854775808))])),
+               Toml::parse("a = -9223372036854775808"));
+}
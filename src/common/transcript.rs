@@ -0,0 +1,185 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The lines exchanged during a session, for debugging clients that don't behave.
+//!
+//! A `Transcript` is given to both streams of a session, see `InputStream::set_transcript`
+//! and `OutputStream::set_transcript`, which record every line they read or write.
+//! Messages are recorded by their size only, and credentials are replaced with `***`:
+//! the initial response of `AUTH`, and every line sent after a `334` reply.
+
+use std::ascii::AsciiExt;
+use std::borrow::{Cow, ToOwned};
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+/// How many lines a `Transcript` keeps, the next ones are only counted.
+pub static MAX_LINES: usize = 10000;
+
+/// A line of a transcript.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum TranscriptLine {
+    /// A line sent by the client, without `<CRLF>`.
+    Client(String),
+    /// A line sent by the server, without `<CRLF>`.
+    Server(String)
+}
+
+struct Lines {
+    lines: Vec<TranscriptLine>,
+    omitted: usize
+}
+
+/// The lines of a session, see `common::transcript`.
+///
+/// Clones share their lines, so the input and the output of a session record to the
+/// same transcript.
+#[derive(Clone)]
+pub struct Transcript {
+    session: Option<String>,
+    lines: Arc<Mutex<Lines>>
+}
+
+/// Returns a command line as it can be logged.
+///
+/// The initial response of `AUTH`, which holds the credentials of the client, is
+/// replaced with `***`, ie `AUTH PLAIN ***`.
+pub fn redact(line: &str) -> Cow<str> {
+    if line.len() < 5 || !line.as_bytes()[.. 5].eq_ignore_ascii_case(b"AUTH ") {
+        return Cow::Borrowed(line);
+    }
+    let mut words = line[5 ..].split(' ').filter(|word| word.len() > 0);
+    match (words.next(), words.next()) {
+        (Some(mechanism), Some(_)) => Cow::Owned(format!("{} {} ***", &line[.. 4], mechanism)),
+        _ => Cow::Borrowed(line)
+    }
+}
+
+impl Transcript {
+    /// Creates an empty transcript for the session with the given id, if any.
+    pub fn new(session: Option<&str>) -> Transcript {
+        Transcript {
+            session: session.map(|session| session.to_owned()),
+            lines: Arc::new(Mutex::new(Lines {
+                lines: Vec::new(),
+                omitted: 0
+            }))
+        }
+    }
+
+    /// Returns the id of the session.
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_ref().map(|session| session.as_ref())
+    }
+
+    fn push(&self, line: TranscriptLine) {
+        let mut lines = self.lines.lock().unwrap();
+        match lines.lines.len() < MAX_LINES {
+            true => lines.lines.push(line),
+            false => lines.omitted += 1
+        }
+    }
+
+    /// Records a line sent by the client, redacting credentials.
+    pub fn record_client(&self, line: &str) {
+        let after_challenge = match self.lines.lock().unwrap().lines.last() {
+            Some(&TranscriptLine::Server(ref last)) => last.starts_with("334"),
+            _ => false
+        };
+        let line = match after_challenge {
+            true => "***".to_owned(),
+            false => redact(line).into_owned()
+        };
+        self.push(TranscriptLine::Client(line));
+    }
+
+    /// Records content sent by the client, ie a message, by its size.
+    pub fn record_octets(&self, len: usize) {
+        self.push(TranscriptLine::Client(format!("<{} octets>", len)));
+    }
+
+    /// Records a line sent by the server.
+    pub fn record_server(&self, line: &str) {
+        self.push(TranscriptLine::Server(line.to_owned()));
+    }
+
+    /// Returns the lines recorded so far, at most `MAX_LINES`.
+    pub fn lines(&self) -> Vec<TranscriptLine> {
+        self.lines.lock().unwrap().lines.clone()
+    }
+
+    /// Returns how many lines came after the first `MAX_LINES`, and weren't kept.
+    pub fn omitted(&self) -> usize {
+        self.lines.lock().unwrap().omitted
+    }
+
+    /// Returns the transcript as text, a line per line, starting with `C: ` for the
+    /// client and `S: ` for the server.
+    pub fn to_text(&self) -> String {
+        let lines = self.lines.lock().unwrap();
+        let mut text = String::new();
+        for line in lines.lines.iter() {
+            match *line {
+                TranscriptLine::Client(ref line) => text.push_str(format!("C: {}\n", line).as_ref()),
+                TranscriptLine::Server(ref line) => text.push_str(format!("S: {}\n", line).as_ref())
+            }
+        }
+        if lines.omitted > 0 {
+            text.push_str(format!("[{} lines omitted]\n", lines.omitted).as_ref());
+        }
+        text
+    }
+}
+
+#[test]
+fn test_redact() {
+    assert_eq!("MAIL FROM:<rust@rustastic.org>", redact("MAIL FROM:<rust@rustastic.org>"));
+    assert_eq!("AUTH PLAIN ***", redact("AUTH PLAIN AHJ1c3QAc2VjcmV0"));
+    assert_eq!("auth login ***", redact("auth login cnVzdA=="));
+    assert_eq!("AUTH LOGIN", redact("AUTH LOGIN"));
+    assert_eq!("AUTHORIZE", redact("AUTHORIZE"));
+    assert_eq!("AUTHé x", redact("AUTHé x"));
+}
+
+#[test]
+fn test_transcript() {
+    let transcript = Transcript::new(Some("s1"));
+    let clone = transcript.clone();
+    transcript.record_server("220 rustastic.org Service ready");
+    clone.record_client("AUTH LOGIN");
+    transcript.record_server("334 VXNlcm5hbWU6");
+    clone.record_client("cnVzdA==");
+    transcript.record_server("235 2.7.0 Authentication successful");
+    clone.record_client("AUTH PLAIN AHJ1c3QAc2VjcmV0");
+    clone.record_octets(42);
+    assert_eq!(Some("s1"), transcript.session());
+    assert_eq!(vec![
+        TranscriptLine::Server("220 rustastic.org Service ready".to_owned()),
+        TranscriptLine::Client("AUTH LOGIN".to_owned()),
+        TranscriptLine::Server("334 VXNlcm5hbWU6".to_owned()),
+        TranscriptLine::Client("***".to_owned()),
+        TranscriptLine::Server("235 2.7.0 Authentication successful".to_owned()),
+        TranscriptLine::Client("AUTH PLAIN ***".to_owned()),
+        TranscriptLine::Client("<42 octets>".to_owned())
+    ], transcript.lines());
+    assert!(transcript.to_text().starts_with("S: 220 rustastic.org Service ready\nC: AUTH LOGIN\n"));
+
+    let transcript = Transcript::new(None);
+    for _ in 0 .. MAX_LINES + 2 {
+        transcript.record_client("NOOP");
+    }
+    assert_eq!(MAX_LINES, transcript.lines().len());
+    assert_eq!(2, transcript.omitted());
+    assert!(transcript.to_text().ends_with("C: NOOP\n[2 lines omitted]\n"));
+}
@@ -17,6 +17,7 @@
 use std::net::IpAddr;
 use std::str::FromStr;
 use std::net::AddrParseError;
+use std::ascii::AsciiExt;
 #[cfg(test)]
 use std::net::{Ipv4Addr, Ipv6Addr};
 
@@ -26,14 +27,30 @@ use std::net::{Ipv4Addr, Ipv6Addr};
 /// A subdomain is as described
 /// [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.1.2).
 pub fn get_subdomain(s: &str) -> Option<&str> {
+    subdomain(s, false)
+}
+
+/// Like `get_subdomain`, but also accepts U-labels as described
+/// [in RFC 6531](http://tools.ietf.org/html/rfc6531#section-3.3).
+///
+/// Any non-ASCII character is accepted in a label, IDNA rules are not checked.
+pub fn get_utf8_subdomain(s: &str) -> Option<&str> {
+    subdomain(s, true)
+}
+
+fn is_label_char(c: char, utf8: bool) -> bool {
+    is_alnum(c) || (utf8 && is_utf8_non_ascii(c))
+}
+
+fn subdomain(s: &str, utf8: bool) -> Option<&str> {
     let mut i = 0;
     let mut len = 0;
-    if s.len() > 0 && is_alnum(s.char_at(0)) {
-        i += 1;
+    if s.len() > 0 && is_label_char(s.char_at(0), utf8) {
+        i += s.char_at(0).len_utf8();
         len = i;
         while i < s.len() {
-            if is_alnum(s.char_at(i)) {
-                i += 1;
+            if is_label_char(s.char_at(i), utf8) {
+                i += s.char_at(i).len_utf8();
                 len = i;
             } else if s.char_at(i) == '-' {
                 while i < s.len() && s.char_at(i) == '-' {
@@ -63,6 +80,12 @@ fn test_get_subdomain() {
     // Disallow dash at the end.
     assert_eq!(Some("heS1o"), get_subdomain("heS1o-&&&"));
     assert_eq!(None, get_subdomain("-hello-world"));
+
+    // U-labels are only allowed in UTF-8 mode.
+    assert_eq!(Some("b"), get_subdomain("büro"));
+    assert_eq!(None, get_subdomain("éa"));
+    assert_eq!(Some("büro-α"), get_utf8_subdomain("büro-α.de"));
+    assert_eq!(Some("éa"), get_utf8_subdomain("éa"));
 }
 
 /// Returns the length of the longest domain found at the beginning of
@@ -71,11 +94,20 @@ fn test_get_subdomain() {
 /// A domain is as described
 /// [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.1.2).
 pub fn get_domain(s: &str) -> Option<&str> {
-    match get_subdomain(s) {
+    domain(s, false)
+}
+
+/// Like `get_domain`, but also accepts U-labels, see `get_utf8_subdomain`.
+pub fn get_utf8_domain(s: &str) -> Option<&str> {
+    domain(s, true)
+}
+
+fn domain(s: &str, utf8: bool) -> Option<&str> {
+    match subdomain(s, utf8) {
         Some(sd1) => {
             let mut len = sd1.len();
             while len < s.len() && s.char_at(len) == '.' {
-                match get_subdomain(&s[len + 1 ..]) {
+                match subdomain(&s[len + 1 ..], utf8) {
                     Some(sdx) => {
                         len += 1 + sdx.len();
                     },
@@ -110,6 +142,10 @@ fn test_get_domain() {
 
     // Valid domains without dots.
     assert_eq!(Some("hello-bla"), get_domain("hello-bla."));
+
+    // Valid domains with U-labels.
+    assert_eq!(Some("hello.b"), get_domain("hello.bücher.de"));
+    assert_eq!(Some("hello.bücher.de"), get_utf8_domain("hello.bücher.de"));
 }
 
 /// Returns the length of the longest atom found at the beginning of
@@ -118,10 +154,21 @@ fn test_get_domain() {
 /// An atom is as described
 /// [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.1.2).
 pub fn get_atom(s: &str) -> Option<&str> {
+    atom(s, false)
+}
+
+/// Like `get_atom`, but also accepts `UTF8-non-ascii` as described
+/// [in RFC 6531](http://tools.ietf.org/html/rfc6531#section-3.3).
+pub fn get_utf8_atom(s: &str) -> Option<&str> {
+    atom(s, true)
+}
+
+fn atom(s: &str, utf8: bool) -> Option<&str> {
     let mut len = 0;
     while len < s.len() {
-        if is_atext(s.char_at(len)) {
-            len += 1
+        let c = s.char_at(len);
+        if is_atext(c) || (utf8 && is_utf8_non_ascii(c)) {
+            len += c.len_utf8();
         } else {
             break;
         }
@@ -138,6 +185,8 @@ fn test_get_atom() {
     assert_eq!(Some("!a{`"), get_atom("!a{`\\"));
     assert_eq!(Some("!a{`"), get_atom("!a{`"));
     assert_eq!(None, get_atom(""));
+    assert_eq!(None, get_atom("élan"));
+    assert_eq!(Some("élan"), get_utf8_atom("élan@"));
 }
 
 /// Returns the length of the longest dot-string found at the beginning
@@ -146,13 +195,22 @@ fn test_get_atom() {
 /// A dot-string is as described
 /// [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.1.2).
 pub fn get_dot_string(s: &str) -> Option<&str> {
+    dot_string(s, false)
+}
+
+/// Like `get_dot_string`, but also accepts `UTF8-non-ascii`, see `get_utf8_atom`.
+pub fn get_utf8_dot_string(s: &str) -> Option<&str> {
+    dot_string(s, true)
+}
+
+fn dot_string(s: &str, utf8: bool) -> Option<&str> {
     let mut len = 0;
 
-    match get_atom(s) {
+    match atom(s, utf8) {
         Some(a1) => {
             len += a1.len();
             while len < s.len() && s.char_at(len) == '.' {
-                match get_atom(&s[len + 1 ..]) {
+                match atom(&s[len + 1 ..], utf8) {
                     Some(a) => {
                         len += 1 + a.len();
                     },
@@ -176,6 +234,8 @@ fn test_get_dot_string() {
     assert_eq!(Some("-`-.bla.ok"), get_dot_string("-`-.bla.ok "));
     assert_eq!(Some("-`-.bla.ok"), get_dot_string("-`-.bla.ok"));
     assert_eq!(Some("-`-.bla.ok"), get_dot_string("-`-.bla.ok."));
+    assert_eq!(Some("jos"), get_dot_string("josé.ok"));
+    assert_eq!(Some("josé.ok"), get_utf8_dot_string("josé.ok"));
 }
 
 /// Checks whether a character is valid `atext` as described
@@ -248,6 +308,20 @@ fn test_is_atext() {
     assert!(!is_atext(127 as char));
 }
 
+/// Checks whether a character is `UTF8-non-ascii` as described
+/// [in RFC 6532](http://tools.ietf.org/html/rfc6532#section-3.1).
+pub fn is_utf8_non_ascii(c: char) -> bool {
+    c as u32 > 127
+}
+
+#[test]
+fn test_is_utf8_non_ascii() {
+    assert!(!is_utf8_non_ascii('a'));
+    assert!(!is_utf8_non_ascii(127 as char));
+    assert!(is_utf8_non_ascii('é'));
+    assert!(is_utf8_non_ascii('中'));
+}
+
 /// Checks if a character is alphanumeric 7 bit ASCII.
 pub fn is_alnum(c: char) -> bool {
     match c {
@@ -281,6 +355,16 @@ fn test_is_alnum() {
 /// A quoted-string is as described
 /// [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.1.2).
 pub fn get_quoted_string(s: &str) -> Option<&str> {
+    quoted_string(s, false)
+}
+
+/// Like `get_quoted_string`, but also accepts `UTF8-non-ascii` as described
+/// [in RFC 6531](http://tools.ietf.org/html/rfc6531#section-3.3).
+pub fn get_utf8_quoted_string(s: &str) -> Option<&str> {
+    quoted_string(s, true)
+}
+
+fn quoted_string(s: &str, utf8: bool) -> Option<&str> {
     let sl = s.len();
     // We need at least "".
     if sl >= 2 && s.char_at(0) == '"' {
@@ -288,10 +372,11 @@ pub fn get_quoted_string(s: &str) -> Option<&str> {
         let mut len = 1;
         loop {
             // Regular text.
-            if len < sl && is_qtext_smtp(s.char_at(len)) {
-                len += 1;
-            // Escaped text.
-            } else if len + 1 < sl &&
+            if len < sl && (is_qtext_smtp(s.char_at(len)) || (utf8 && is_utf8_non_ascii(s.char_at(len)))) {
+                len += s.char_at(len).len_utf8();
+            // Escaped text, the backslash is checked first as the next octet may not start
+            // a character.
+            } else if len + 1 < sl && s.char_at(len) == '\\' &&
                 is_quoted_pair_smtp(s.char_at(len), s.char_at(len + 1)) {
                 len += 2;
             } else {
@@ -323,6 +408,11 @@ fn test_get_quoted_string() {
     assert_eq!(Some("\"\""), get_quoted_string("\"\""));
     assert_eq!(Some("\"Rust{\\\\\\\"\\a}\\stic\""), get_quoted_string("\"Rust{\\\\\\\"\\a}\\stic\""));
     assert_eq!(Some("\"Rust{\\\\\\\"\\a}\\stic\""), get_quoted_string("\"Rust{\\\\\\\"\\a}\\stic\" "));
+
+    // Non-ASCII text is only allowed in UTF-8 mode.
+    assert_eq!(None, get_quoted_string("\"José\""));
+    assert_eq!(Some("\"José\""), get_utf8_quoted_string("\"José\""));
+    assert_eq!(None, get_utf8_quoted_string("\"José\\é\""));
 }
 
 /// Checks whether a character is valid `qtextSMTP` as described
@@ -449,7 +539,8 @@ fn test_get_source_route() {
 /// If the string starts with an ipv6 as present in email addresses, ie `[Ipv6:...]`, get its
 /// length. Else return `0`.
 fn get_possible_mailbox_ipv6(ip: &str) -> Option<&str> {
-    if ip.len() < 7 || &ip[.. 6] != "[Ipv6:" {
+    // The tag is case insensitive, like every string in the grammar.
+    if ip.len() < 7 || !ip.as_bytes()[.. 6].eq_ignore_ascii_case(b"[IPv6:") {
         None
     } else {
         let mut i = 6;
@@ -471,6 +562,7 @@ fn test_get_possible_mailbox_ipv6() {
     assert_eq!(Some("[Ipv6:]"), get_possible_mailbox_ipv6("[Ipv6:]"));
     assert_eq!(Some("[Ipv6:]"), get_possible_mailbox_ipv6("[Ipv6:]a"));
     assert_eq!(Some("[Ipv6:::1]"), get_possible_mailbox_ipv6("[Ipv6:::1]"));
+    assert_eq!(Some("[IPv6:::1]"), get_possible_mailbox_ipv6("[IPv6:::1]"));
     assert_eq!(None, get_possible_mailbox_ipv6("[Ipv6:434"));
     assert_eq!(None, get_possible_mailbox_ipv6("[Ipv"));
 }
@@ -520,7 +612,7 @@ pub fn get_mailbox_ip(s: &str) -> Option<(&str, IpAddr)> {
     }).or(get_possible_mailbox_ipv6(s).and_then(|ip| {
         // The IP without prefix / suffix.
         let stripped_ip = &s[
-            // Start after the prefix "[Ipv6:" as in "[Ipv6:::1]"
+            // Start after the prefix "[IPv6:" as in "[IPv6:::1]"
             6 ..
             // Go until before the suffix "]".
             ip.len() - 1
@@ -27,30 +27,17 @@
 //!
 //! use std::net::{IpAddr, Ipv4Addr};
 //! use rsmtp::server::Server;
-//! use rsmtp::server::commands::HeloSeen;
 //! use rsmtp::server::commands::HeloHandler;
 //! use rsmtp::server::commands::helo::get as get_helo_command;
 //!
+//! // The server keeps track of the state of the session, so the container only
+//! // holds what your handlers need.
 //! #[derive(Clone)]
-//! struct Container {
-//!     helo_seen: bool
-//! }
+//! struct Container;
 //!
 //! impl Container {
 //!     fn new() -> Container {
-//!         Container {
-//!             helo_seen: false
-//!         }
-//!     }
-//! }
-//!
-//! impl HeloSeen for Container {
-//!     fn helo_seen(&mut self) -> bool {
-//!         self.helo_seen
-//!     }
-//!
-//!     fn set_helo_seen(&mut self, helo_seen: bool) {
-//!         self.helo_seen = helo_seen;
+//!         Container
 //!     }
 //! }
 //!
@@ -69,9 +56,10 @@
 //!     // Look in `rsmtp::server::commands` for more commands.
 //!     server.add_command(get_helo_command());
 //!
-//!     // Hypothetical extension support.
-//!     server.add_extension("STARTTLS");
-//!     server.add_extension("BDAT");
+//!     // Extensions provided by commands, like CHUNKING for the BDAT command,
+//!     // are advertised automatically. Others can be added by hand, SIZE gets
+//!     // the maximum message size of the server.
+//!     server.add_extension("SIZE");
 //!
 //!     if let Err(_) = server.listen(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 2525) {
 //!         println!("Error.");
@@ -83,6 +71,9 @@
 // #![deny(unused_results)]
 #![feature(ip_addr, libc, convert, str_char, std_misc, owned_ascii_ext)]
 
+#[cfg(feature = "serde")]
+extern crate serde;
+
 pub mod client;
 pub mod common;
 pub mod server;
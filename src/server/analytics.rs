@@ -0,0 +1,260 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Aggregated measurements of a server, ready to be shown on a dashboard without a
+//! monitoring system.
+//!
+//! `Analytics` is a `Metrics` that counts the replies to each command, and the messages
+//! and refusals of each client and sender domain. Keep a clone of it to get reports
+//! while the server runs:
+//!
+//! ```ignore
+//! let analytics = Analytics::new();
+//! server.set_metrics(analytics.clone());
+//! ...
+//! let report = analytics.report(10);
+//! for &(ip, rejections) in report.top_clients_by_rejections.iter() {
+//!     println!("{} got {} refusals", ip, rejections);
+//! }
+//! ```
+//!
+//! The counts grow with the number of clients and domains seen, so long running servers
+//! should call `reset` once they exported a report, ie every hour.
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+use super::metrics::Metrics;
+use super::super::common::mailbox::{Mailbox, MailboxForeignPart};
+#[cfg(test)]
+use std::net::Ipv4Addr;
+#[cfg(test)]
+use super::Server;
+#[cfg(test)]
+use super::commands::{mail, rcpt, data};
+#[cfg(test)]
+use super::testing::{TestSession, TestContainer};
+
+/// The replies to a command, by reply code.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ReplyHistogram {
+    /// The verb of the command, ie `MAIL`, `None` for lines that matched no command.
+    pub command: Option<String>,
+    /// The number of replies with each code, by increasing code.
+    pub codes: Vec<(u16, u64)>
+}
+
+impl ReplyHistogram {
+    /// Returns the number of replies to the command.
+    pub fn total(&self) -> u64 {
+        self.codes.iter().fold(0, |total, &(_, count)| total + count)
+    }
+}
+
+/// What the server did since it started, or since the last `Analytics::reset`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AnalyticsReport {
+    /// The replies to each command, by verb.
+    pub replies: Vec<ReplyHistogram>,
+    /// The clients that sent the most messages, accepted or not, with their count.
+    pub top_clients_by_messages: Vec<(IpAddr, u64)>,
+    /// The clients that got the most `4xx` and `5xx` replies, with their count.
+    pub top_clients_by_rejections: Vec<(IpAddr, u64)>,
+    /// The domains of the senders of the most messages, accepted or not, with their
+    /// count. Messages from the null sender aren't counted.
+    pub top_sender_domains: Vec<(String, u64)>
+}
+
+struct Counts {
+    replies: HashMap<(Option<String>, u16), u64>,
+    messages: HashMap<IpAddr, u64>,
+    rejections: HashMap<IpAddr, u64>,
+    senders: HashMap<String, u64>
+}
+
+impl Counts {
+    fn new() -> Counts {
+        Counts {
+            replies: HashMap::new(),
+            messages: HashMap::new(),
+            rejections: HashMap::new(),
+            senders: HashMap::new()
+        }
+    }
+}
+
+// Returns the `top` keys with the highest counts. Ties are broken by key, so reports
+// don't change order from one call to the next.
+fn top<K: Hash + Eq + Ord + Clone>(counts: &HashMap<K, u64>, top: usize) -> Vec<(K, u64)> {
+    let mut sorted: Vec<(K, u64)> = counts.iter().map(|(key, &count)| (key.clone(), count)).collect();
+    sorted.sort_by(|a, b| match b.1.cmp(&a.1) {
+        Ordering::Equal => a.0.cmp(&b.0),
+        ordering => ordering
+    });
+    sorted.truncate(top);
+    sorted
+}
+
+// Returns the domain of a sender, as it is counted.
+fn sender_domain(sender: &Mailbox) -> String {
+    match *sender.foreign_part() {
+        MailboxForeignPart::Domain(ref domain) => domain.to_ascii_lowercase(),
+        MailboxForeignPart::IpAddr(ref ip) => format!("[{}]", ip)
+    }
+}
+
+/// Counts what a server does, see `server::analytics`.
+///
+/// Clones share their counts.
+#[derive(Clone)]
+pub struct Analytics {
+    counts: Arc<Mutex<Counts>>
+}
+
+impl Analytics {
+    /// Creates analytics that counted nothing yet.
+    pub fn new() -> Analytics {
+        Analytics {
+            counts: Arc::new(Mutex::new(Counts::new()))
+        }
+    }
+
+    /// Returns the reply histograms, and the `top` clients and sender domains.
+    pub fn report(&self, top_len: usize) -> AnalyticsReport {
+        let counts = self.counts.lock().unwrap();
+        let mut replies: Vec<ReplyHistogram> = Vec::new();
+        let mut keys: Vec<&(Option<String>, u16)> = counts.replies.keys().collect();
+        keys.sort();
+        for key in keys {
+            let count = counts.replies[key];
+            let new_command = replies.last().map_or(true, |histogram| histogram.command != key.0);
+            if new_command {
+                replies.push(ReplyHistogram {
+                    command: key.0.clone(),
+                    codes: Vec::new()
+                });
+            }
+            replies.last_mut().unwrap().codes.push((key.1, count));
+        }
+        AnalyticsReport {
+            replies: replies,
+            top_clients_by_messages: top(&counts.messages, top_len),
+            top_clients_by_rejections: top(&counts.rejections, top_len),
+            top_sender_domains: top(&counts.senders, top_len)
+        }
+    }
+
+    /// Forgets everything counted so far.
+    pub fn reset(&self) {
+        *self.counts.lock().unwrap() = Counts::new();
+    }
+}
+
+impl Metrics for Analytics {
+    fn reply(&self, command: Option<&str>, code: u16, client: Option<IpAddr>) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts.replies.entry((command.map(|command| command.to_owned()), code)).or_insert(0) += 1;
+        if let (Some(ip), true) = (client, code >= 400) {
+            *counts.rejections.entry(ip).or_insert(0) += 1;
+        }
+    }
+
+    fn message(&self, client: Option<IpAddr>, sender: Option<&Mailbox>, _: bool) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(ip) = client {
+            *counts.messages.entry(ip).or_insert(0) += 1;
+        }
+        if let Some(sender) = sender {
+            *counts.senders.entry(sender_domain(sender)).or_insert(0) += 1;
+        }
+    }
+}
+
+#[test]
+fn test_report() {
+    let analytics = Analytics::new();
+    let a = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    let b = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+    let c = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 3));
+    let rust = Mailbox::parse("rust@Rustastic.org").unwrap();
+    let ip = Mailbox::parse("rust@[127.0.0.1]").unwrap();
+
+    analytics.reply(Some("MAIL"), 250, Some(a));
+    analytics.reply(Some("MAIL"), 250, Some(b));
+    analytics.reply(Some("MAIL"), 550, Some(b));
+    analytics.reply(Some("DATA"), 250, Some(c));
+    analytics.reply(None, 500, Some(c));
+    analytics.reply(None, 500, None);
+    analytics.message(Some(a), Some(&rust), true);
+    analytics.message(Some(b), Some(&ip), false);
+    analytics.message(Some(b), Some(&rust), true);
+    analytics.message(Some(c), None, true);
+
+    let report = analytics.report(2);
+    assert_eq!(vec![
+        ReplyHistogram { command: None, codes: vec![(500, 2)] },
+        ReplyHistogram { command: Some("DATA".to_owned()), codes: vec![(250, 1)] },
+        ReplyHistogram { command: Some("MAIL".to_owned()), codes: vec![(250, 2), (550, 1)] }
+    ], report.replies);
+    assert_eq!(3, report.replies[2].total());
+    assert_eq!(vec![(b, 2), (a, 1)], report.top_clients_by_messages);
+    assert_eq!(vec![(b, 1), (c, 1)], report.top_clients_by_rejections);
+    assert_eq!(vec![("rustastic.org".to_owned(), 2), ("[127.0.0.1]".to_owned(), 1)], report.top_sender_domains);
+
+    analytics.reset();
+    assert_eq!(AnalyticsReport {
+        replies: vec![],
+        top_clients_by_messages: vec![],
+        top_clients_by_rejections: vec![],
+        top_sender_domains: vec![]
+    }, analytics.report(2));
+}
+
+#[test]
+fn test_server_analytics() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    let analytics = Analytics::new();
+    server.set_metrics(analytics.clone());
+    server.add_command(mail::get());
+    server.add_command(rcpt::get());
+    server.add_command(data::get());
+    let mut session = TestSession::new();
+
+    for line in ["RCPT TO:<b@rustastic.org>", "JUNK", "MAIL FROM:<a@rustastic.org>", "RCPT TO:<b@rustastic.org>"].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        session.reply();
+    }
+    session.send_bytes(b"Subject: Hi\r\n\r\nHi\r\n.\r\n");
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+    session.reply();
+    assert_eq!("250 2.0.0 OK", session.reply());
+
+    let localhost = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    let report = analytics.report(10);
+    assert_eq!(vec![
+        ReplyHistogram { command: None, codes: vec![(500, 1)] },
+        ReplyHistogram { command: Some("DATA".to_owned()), codes: vec![(250, 1)] },
+        ReplyHistogram { command: Some("MAIL".to_owned()), codes: vec![(250, 1)] },
+        ReplyHistogram { command: Some("RCPT".to_owned()), codes: vec![(250, 1), (503, 1)] }
+    ], report.replies);
+    assert_eq!(vec![(localhost, 1)], report.top_clients_by_messages);
+    assert_eq!(vec![(localhost, 2)], report.top_clients_by_rejections);
+    assert_eq!(vec![("rustastic.org".to_owned(), 1)], report.top_sender_domains);
+}
@@ -0,0 +1,286 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An append-only log of the messages a server handled, whose records are chained and
+//! signed so changes can be detected.
+//!
+//! Each record is a JSON line ending with `"mac"`, the HMAC-SHA-256 of the record
+//! without it, preceded by the MAC of the previous record. Changing, removing or
+//! reordering records breaks the chain from there on. Removing the last records can
+//! only be detected by comparing with a MAC kept elsewhere, see `AuditLog::head`.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let file = try!(OpenOptions::new().read(true).append(true).create(true).open("audit.log"));
+//! let log = try!(AuditLog::resume(key, &mut BufReader::new(try!(file.try_clone())), file));
+//! try!(log.append(&AuditRecord {
+//!     timestamp: now,
+//!     queue_id: queue_id,
+//!     sender: transaction.sender().cloned(),
+//!     recipients: transaction.recipients().to_vec(),
+//!     verdict: "accepted".to_owned()
+//! }));
+//! ```
+
+use std::borrow::ToOwned;
+use std::io::{BufRead, Write, Error as IoError};
+use std::io::Result as IoResult;
+use std::sync::Mutex;
+use std::vec::Vec;
+use super::super::common::json::{Json, JsonError};
+use super::super::common::mailbox::Mailbox;
+use super::super::common::md5::to_hex;
+use super::super::common::sha256;
+
+// What the first record is chained to.
+static GENESIS: &'static str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// What comes between a record and its MAC.
+static MAC_MEMBER: &'static str = ",\"mac\":\"";
+
+/// What happened to a message.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AuditRecord {
+    /// When it happened, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The id of the message in the queue.
+    pub queue_id: String,
+    /// The sender, `None` for the null sender `<>`.
+    pub sender: Option<Mailbox>,
+    /// The recipients.
+    pub recipients: Vec<Mailbox>,
+    /// What was decided, ie `accepted` or `quarantined`.
+    pub verdict: String
+}
+
+impl AuditRecord {
+    fn to_json(&self, seq: u64) -> Json {
+        Json::Object(vec![
+            ("seq".to_owned(), Json::Number(seq)),
+            ("timestamp".to_owned(), Json::Number(self.timestamp)),
+            ("queue_id".to_owned(), Json::String(self.queue_id.clone())),
+            ("sender".to_owned(), match self.sender {
+                Some(ref sender) => Json::String(sender.to_wire_string()),
+                None => Json::Null
+            }),
+            ("recipients".to_owned(), Json::Array(self.recipients.iter().map(|recipient| {
+                Json::String(recipient.to_wire_string())
+            }).collect())),
+            ("verdict".to_owned(), Json::String(self.verdict.clone()))
+        ])
+    }
+}
+
+/// Represents an error that occured while checking an audit log.
+#[derive(Debug)]
+pub enum AuditError {
+    /// Reading the log failed.
+    Io(IoError),
+    /// The line with the given number, starting at 1, isn't valid JSON.
+    Syntax(usize, JsonError),
+    /// The line with the given number, starting at 1, was changed, or a record before it
+    /// was removed or moved.
+    Tampered(usize)
+}
+
+// Returns the MAC of a record, without its MAC member, following the previous one.
+fn get_mac(key: &[u8], previous: &str, record: &str) -> String {
+    let mut data = Vec::with_capacity(previous.len() + 1 + record.len());
+    data.extend(previous.as_bytes().iter().cloned());
+    data.push(b'\n');
+    data.extend(record.as_bytes().iter().cloned());
+    to_hex(&sha256::hmac(key, data.as_ref()))
+}
+
+/// Checks the chain of records written by `AuditLog`, returning the number of records
+/// and the MAC of the last one.
+///
+/// Blank lines are skipped.
+pub fn verify<R: BufRead>(key: &[u8], input: &mut R) -> Result<(u64, String), AuditError> {
+    let mut count = 0;
+    let mut previous = GENESIS.to_owned();
+    for (i, line) in input.lines().enumerate() {
+        let line = try!(line.map_err(AuditError::Io));
+        if line.trim().len() == 0 {
+            continue;
+        }
+        let record = try!(Json::parse(line.as_ref()).map_err(|err| AuditError::Syntax(i + 1, err)));
+        let (body, mac) = match line.rfind(MAC_MEMBER) {
+            Some(index) if line.ends_with("\"}") && index + MAC_MEMBER.len() <= line.len() - 2 => {
+                (format!("{}}}", &line[.. index]), &line[index + MAC_MEMBER.len() .. line.len() - 2])
+            },
+            _ => return Err(AuditError::Tampered(i + 1))
+        };
+        let seq = record.get("seq").and_then(|seq| seq.as_u64());
+        if seq != Some(count + 1) || get_mac(key, previous.as_ref(), body.as_ref()) != mac {
+            return Err(AuditError::Tampered(i + 1));
+        }
+        previous = mac.to_owned();
+        count += 1;
+    }
+    Ok((count, previous))
+}
+
+struct AuditChain<W> {
+    output: W,
+    seq: u64,
+    previous: String
+}
+
+/// Appends records to a log, see `server::audit`.
+///
+/// The log can be shared by all connections.
+pub struct AuditLog<W> {
+    key: Vec<u8>,
+    chain: Mutex<AuditChain<W>>
+}
+
+impl<W: Write> AuditLog<W> {
+    /// Starts a new log, signed with the key.
+    pub fn new(key: &[u8], output: W) -> AuditLog<W> {
+        AuditLog {
+            key: key.to_vec(),
+            chain: Mutex::new(AuditChain {
+                output: output,
+                seq: 0,
+                previous: GENESIS.to_owned()
+            })
+        }
+    }
+
+    /// Goes on with a log after checking the records it already has, ie when the server
+    /// restarts.
+    ///
+    /// `input` reads the existing records, `output` appends to them.
+    pub fn resume<R: BufRead>(key: &[u8], input: &mut R, output: W) -> Result<AuditLog<W>, AuditError> {
+        let (seq, previous) = try!(verify(key, input));
+        Ok(AuditLog {
+            key: key.to_vec(),
+            chain: Mutex::new(AuditChain {
+                output: output,
+                seq: seq,
+                previous: previous
+            })
+        })
+    }
+
+    /// Signs and writes a record, and flushes the output.
+    ///
+    /// Records are numbered in the order they are appended, starting at 1.
+    pub fn append(&self, record: &AuditRecord) -> IoResult<()> {
+        let mut chain = self.chain.lock().unwrap();
+        let seq = chain.seq + 1;
+        let body = record.to_json(seq).to_string();
+        let mac = get_mac(self.key.as_ref(), chain.previous.as_ref(), body.as_ref());
+        try!(writeln!(chain.output, "{}{}{}\"}}", &body[.. body.len() - 1], MAC_MEMBER, mac));
+        try!(chain.output.flush());
+        chain.seq = seq;
+        chain.previous = mac;
+        Ok(())
+    }
+
+    /// Returns the number of records and the MAC of the last one.
+    ///
+    /// Keeping it elsewhere, ie in another system, allows detecting that the last
+    /// records were removed.
+    pub fn head(&self) -> (u64, String) {
+        let chain = self.chain.lock().unwrap();
+        (chain.seq, chain.previous.clone())
+    }
+}
+
+#[cfg(test)]
+fn get_record(queue_id: &str) -> AuditRecord {
+    AuditRecord {
+        timestamp: 1420070400,
+        queue_id: queue_id.to_owned(),
+        sender: Some(Mailbox::parse("rust@rustastic.org").unwrap()),
+        recipients: vec![Mailbox::parse("smtp@rustastic.org").unwrap()],
+        verdict: "accepted".to_owned()
+    }
+}
+
+#[test]
+fn test_audit_log() {
+    let log = AuditLog::new(b"key", Vec::new());
+    log.append(&get_record("A1")).unwrap();
+    log.append(&AuditRecord { sender: None, ..get_record("A2") }).unwrap();
+    let head = log.head();
+    assert_eq!(2, head.0);
+
+    let output = log.chain.into_inner().unwrap().output;
+    let text = String::from_utf8(output.clone()).unwrap();
+    assert!(text.starts_with("{\"seq\":1,\"timestamp\":1420070400,\"queue_id\":\"A1\",\"sender\":\"rust@rustastic.org\",\"recipients\":[\"smtp@rustastic.org\"],\"verdict\":\"accepted\",\"mac\":\""));
+    match verify(b"key", &mut &output[..]) {
+        Ok(result) => assert_eq!(head, result),
+        Err(err) => panic!("unexpected error: {:?}", err)
+    }
+
+    // Another key doesn't match.
+    match verify(b"other key", &mut &output[..]) {
+        Err(AuditError::Tampered(1)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    // The log goes on where it was.
+    let log = AuditLog::resume(b"key", &mut &output[..], output.clone()).unwrap();
+    assert_eq!(head, log.head());
+    log.append(&get_record("A3")).unwrap();
+    let output = log.chain.into_inner().unwrap().output;
+    assert_eq!(3, verify(b"key", &mut &output[..]).unwrap().0);
+}
+
+#[test]
+fn test_tampering() {
+    let log = AuditLog::new(b"key", Vec::new());
+    for queue_id in ["A1", "A2", "A3"].iter() {
+        log.append(&get_record(queue_id)).unwrap();
+    }
+    let text = String::from_utf8(log.chain.into_inner().unwrap().output).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+
+    // A changed verdict.
+    let changed = format!("{}\n{}\n", lines[0], lines[1].replace("accepted", "rejected"));
+    match verify(b"key", &mut changed.as_bytes()) {
+        Err(AuditError::Tampered(2)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    // A removed record.
+    let removed = format!("{}\n{}\n", lines[0], lines[2]);
+    match verify(b"key", &mut removed.as_bytes()) {
+        Err(AuditError::Tampered(2)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    // Swapped records.
+    let swapped = format!("{}\n{}\n{}\n", lines[1], lines[0], lines[2]);
+    match verify(b"key", &mut swapped.as_bytes()) {
+        Err(AuditError::Tampered(1)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    // A missing MAC.
+    let unsigned = format!("{}\n{{\"seq\":2}}\n", lines[0]);
+    match verify(b"key", &mut unsigned.as_bytes()) {
+        Err(AuditError::Tampered(2)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    match verify(b"key", &mut &b"{\"seq\":\n"[..]) {
+        Err(AuditError::Syntax(1, JsonError::UnexpectedEnd)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
@@ -0,0 +1,372 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use super::super::{ServerConfig, SessionError, AuthMechanism};
+use super::super::libc;
+use super::super::super::common::base64;
+use super::super::super::common::md5;
+use super::super::super::common::stream::InputStream;
+use super::super::super::common::stream::OutputStream;
+use super::super::super::common::stream::SessionStream;
+use super::super::super::common::Reply;
+use super::super::super::common::status::EnhancedStatusCode;
+use super::super::super::common::stream::{LINE_TOO_LONG, is_timeout};
+use super::super::NextMiddleware;
+use super::super::Command;
+use super::super::session::SessionState;
+use super::TransactionState;
+use super::AuthState;
+use super::AuthHandler;
+
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
+
+// Authorization identity, username and password.
+type Credentials = (Option<String>, String, String);
+
+/// Parses the argument of AUTH, ie `PLAIN` or `PLAIN AHJ1c3QAcGFzcw==`, into the
+/// mechanism and the initial response.
+fn parse_argument(line: &str) -> Option<(&str, Option<&str>)> {
+    let mut parts = line.split(' ');
+    let mechanism = match parts.next() {
+        Some(mechanism) if mechanism.len() > 0 && mechanism.len() <= 20 => mechanism,
+        _ => return None
+    };
+    let valid = mechanism.chars().all(|c| {
+        (c >= 'A' && c <= 'Z') || (c >= 'a' && c <= 'z') || (c >= '0' && c <= '9') || c == '-' || c == '_'
+    });
+    if !valid {
+        return None;
+    }
+    let initial = match parts.next() {
+        Some(initial) if initial.len() == 0 => return None,
+        initial => initial
+    };
+    match parts.next() {
+        None => Some((mechanism, initial)),
+        Some(_) => None
+    }
+}
+
+#[test]
+fn test_parse_argument() {
+    assert_eq!(Some(("PLAIN", None)), parse_argument("PLAIN"));
+    assert_eq!(Some(("login", None)), parse_argument("login"));
+    assert_eq!(Some(("CRAM-MD5", None)), parse_argument("CRAM-MD5"));
+    assert_eq!(Some(("PLAIN", Some("AHJ1c3QAcGFzcw=="))), parse_argument("PLAIN AHJ1c3QAcGFzcw=="));
+    assert_eq!(Some(("PLAIN", Some("="))), parse_argument("PLAIN ="));
+    assert_eq!(None, parse_argument(""));
+    assert_eq!(None, parse_argument("PLAIN "));
+    assert_eq!(None, parse_argument("PLAIN  abc"));
+    assert_eq!(None, parse_argument("PLAIN abc def"));
+    assert_eq!(None, parse_argument("PL@IN"));
+}
+
+/// Parses a PLAIN response, as described [in RFC 4616](http://tools.ietf.org/html/rfc4616#section-2).
+fn parse_plain(response: &[u8]) -> Option<Credentials> {
+    let parts: Vec<&[u8]> = response.split(|b| *b == 0).collect();
+    if parts.len() != 3 || parts[1].len() == 0 {
+        return None;
+    }
+    let authorization = match String::from_utf8(parts[0].to_vec()) {
+        Ok(ref authorization) if authorization.len() == 0 => None,
+        Ok(authorization) => Some(authorization),
+        Err(_) => return None
+    };
+    match (String::from_utf8(parts[1].to_vec()), String::from_utf8(parts[2].to_vec())) {
+        (Ok(username), Ok(password)) => Some((authorization, username, password)),
+        _ => None
+    }
+}
+
+#[test]
+fn test_parse_plain() {
+    assert_eq!(Some((None, "rust".to_string(), "pass".to_string())), parse_plain(b"\0rust\0pass"));
+    assert_eq!(Some((Some("admin".to_string()), "rust".to_string(), "pass".to_string())), parse_plain(b"admin\0rust\0pass"));
+    assert_eq!(Some((None, "rust".to_string(), "".to_string())), parse_plain(b"\0rust\0"));
+    assert_eq!(None, parse_plain(b"\0\0pass"));
+    assert_eq!(None, parse_plain(b"rust\0pass"));
+    assert_eq!(None, parse_plain(b"\0rust\0pa\0ss"));
+    assert_eq!(None, parse_plain(b"\0rust\0\xff"));
+    assert_eq!(None, parse_plain(b""));
+}
+
+// Decodes a response of the client, replying with an error if the client cancelled the
+// exchange or if the response is not valid base64.
+fn decode_response<ST: SessionStream>(output: &mut Output<ST>, response: &str) -> Option<Vec<u8>> {
+    if response == "*" {
+        output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 7, 0), "Authentication cancelled")).unwrap();
+        return None;
+    }
+    match base64::decode(response) {
+        Ok(response) => Some(response),
+        Err(_) => {
+            output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 2), "Cannot decode response")).unwrap();
+            None
+        }
+    }
+}
+
+// Decodes the initial response sent with the AUTH command, where `=` means empty.
+fn decode_initial_response<ST: SessionStream>(output: &mut Output<ST>, initial: &str) -> Option<Vec<u8>> {
+    match initial {
+        "=" => Some(Vec::new()),
+        _ => decode_response(output, initial)
+    }
+}
+
+// Sends a challenge to the client and reads its response.
+//
+// When the connection fails, the error is recorded in the state of the session.
+fn challenge<ST: SessionStream>(state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, challenge: &[u8]) -> Option<Vec<u8>> {
+    output.write_reply(&Reply::new(334, base64::encode(challenge).as_ref())).unwrap();
+    if let Err(err) = output.flush() {
+        state.set_failed(SessionError::Write(err));
+        return None;
+    }
+    let response = match input.read_line() {
+        Ok(line) => String::from_utf8_lossy(line).into_owned(),
+        Err(err) => {
+            if err.description() == LINE_TOO_LONG {
+                output.write_reply(&Reply::enhanced(500, EnhancedStatusCode::new(5, 5, 6), "Line too long")).unwrap();
+            } else if is_timeout(&err) {
+                state.set_timed_out();
+            } else {
+                state.set_failed(SessionError::Read(err));
+            }
+            return None;
+        }
+    };
+    decode_response(output, response.as_ref())
+}
+
+fn read_plain<ST: SessionStream>(state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, initial: Option<&str>) -> Option<Credentials> {
+    let response = match initial {
+        Some(initial) => decode_initial_response(output, initial),
+        None => challenge(state, input, output, b"")
+    };
+    match response {
+        Some(response) => {
+            let credentials = parse_plain(response.as_ref());
+            if credentials.is_none() {
+                output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 2), "Invalid PLAIN response")).unwrap();
+            }
+            credentials
+        },
+        None => None
+    }
+}
+
+fn read_login<ST: SessionStream>(state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, initial: Option<&str>) -> Option<Credentials> {
+    // Some clients send the username right away.
+    let username = match initial {
+        Some(initial) => decode_initial_response(output, initial),
+        None => challenge(state, input, output, b"Username:")
+    };
+    let username = match username {
+        Some(username) => username,
+        None => return None
+    };
+    let password = match challenge(state, input, output, b"Password:") {
+        Some(password) => password,
+        None => return None
+    };
+    if username.len() == 0 {
+        output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 2), "Invalid LOGIN response")).unwrap();
+        return None;
+    }
+    match (String::from_utf8(username), String::from_utf8(password)) {
+        (Ok(username), Ok(password)) => Some((None, username, password)),
+        _ => {
+            output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 2), "Invalid LOGIN response")).unwrap();
+            None
+        }
+    }
+}
+
+fn check_state<CT: TransactionState + AuthState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    if !state.is_greeted() {
+        output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, HELO/EHLO first")).unwrap();
+    } else if container.authenticated().is_some() {
+        output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, already authenticated")).unwrap();
+    } else if container.transaction().is_started() {
+        output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, AUTH not allowed during a mail transaction")).unwrap();
+    } else {
+        next.unwrap().call(config, container, state, input, output, line);
+    }
+}
+
+fn check_argument<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    match parse_argument(line) {
+        None => {
+            output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, format: 'AUTH <mechanism> [<initial-response>]'")).unwrap();
+        },
+        Some(_) => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+// Parses a CRAM-MD5 response, ie the username and the hexadecimal digest separated by a
+// space, as described [in RFC 2195](http://tools.ietf.org/html/rfc2195#section-2).
+fn parse_cram_md5(response: &[u8]) -> Option<(String, String)> {
+    let response = match String::from_utf8(response.to_vec()) {
+        Ok(response) => response,
+        Err(_) => return None
+    };
+    let pos = match response.rfind(' ') {
+        Some(pos) if pos > 0 => pos,
+        _ => return None
+    };
+    let digest = response[pos + 1 ..].to_ascii_lowercase();
+    match digest.len() == 32 && digest.chars().all(|c| (c >= '0' && c <= '9') || (c >= 'a' && c <= 'f')) {
+        true => Some((response[.. pos].to_owned(), digest)),
+        false => None
+    }
+}
+
+#[test]
+fn test_parse_cram_md5() {
+    assert_eq!(
+        Some(("tim".to_string(), "b913a602c7eda7a495b4e6e7334d3890".to_string())),
+        parse_cram_md5(b"tim b913a602c7eda7a495b4e6e7334d3890")
+    );
+    assert_eq!(
+        Some(("tim smith".to_string(), "b913a602c7eda7a495b4e6e7334d3890".to_string())),
+        parse_cram_md5(b"tim smith B913A602C7EDA7A495B4E6E7334D3890")
+    );
+    assert_eq!(None, parse_cram_md5(b"tim"));
+    assert_eq!(None, parse_cram_md5(b" b913a602c7eda7a495b4e6e7334d3890"));
+    assert_eq!(None, parse_cram_md5(b"tim b913a602c7eda7a495b4e6e7334d389"));
+    assert_eq!(None, parse_cram_md5(b"tim b913a602c7eda7a495b4e6e7334d389g"));
+}
+
+static CHALLENGE_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Returns a challenge that is never sent twice by this server, in the format suggested by
+// RFC 2195.
+fn get_cram_md5_challenge(hostname: &str) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let count = CHALLENGE_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let pid = unsafe { libc::getpid() };
+    format!("<{}.{}.{}{:09}@{}>", pid, count, now.as_secs(), now.subsec_nanos(), hostname)
+}
+
+// Compares in a time that doesn't depend on where the values differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn read_cram_md5<CT: AuthHandler, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, initial: Option<&str>) -> Option<String> {
+    // The server speaks first with CRAM-MD5.
+    if initial.is_some() {
+        output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "CRAM-MD5 doesn't allow an initial response")).unwrap();
+        return None;
+    }
+
+    let cram_challenge = get_cram_md5_challenge(config.hostname.as_ref());
+    let response = match challenge(state, input, output, cram_challenge.as_bytes()) {
+        Some(response) => response,
+        None => return None
+    };
+    let (username, digest) = match parse_cram_md5(response.as_ref()) {
+        Some(parsed) => parsed,
+        None => {
+            output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 2), "Invalid CRAM-MD5 response")).unwrap();
+            return None;
+        }
+    };
+
+    let expected = container.get_secret(username.as_ref()).map(|secret| {
+        md5::to_hex(&md5::hmac(secret.as_bytes(), cram_challenge.as_bytes()))
+    });
+    match expected {
+        Some(ref expected) if constant_time_eq(expected.as_bytes(), digest.as_bytes()) => {
+            Some(username)
+        },
+        _ => {
+            output.write_reply(&Reply::enhanced(535, EnhancedStatusCode::new(5, 7, 8), "Authentication credentials invalid")).unwrap();
+            None
+        }
+    }
+}
+
+// Validates the credentials with the container, returning the identity of the client.
+fn check_credentials<CT: AuthHandler, ST: SessionStream>(container: &mut CT, output: &mut Output<ST>, credentials: Credentials) -> Option<String> {
+    let (authorization, username, password) = credentials;
+    let res = container.handle_credentials(
+        authorization.as_ref().map(|a| a.as_ref()),
+        username.as_ref(),
+        password.as_ref()
+    );
+    match res {
+        Ok(_) => Some(authorization.unwrap_or(username)),
+        Err(_) => {
+            output.write_reply(&Reply::enhanced(535, EnhancedStatusCode::new(5, 7, 8), "Authentication credentials invalid")).unwrap();
+            None
+        }
+    }
+}
+
+fn handle_auth<CT: AuthState + AuthHandler, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, _: Next<CT, ST>) {
+    let (name, initial) = parse_argument(line).unwrap();
+    let mechanism = config.auth_mechanisms.iter().find(|mechanism| {
+        mechanism.name().eq_ignore_ascii_case(name)
+    }).cloned();
+
+    // If there is no identity, we have already replied.
+    let identity = match mechanism {
+        Some(AuthMechanism::Plain) => {
+            read_plain(state, input, output, initial).and_then(|c| check_credentials(container, output, c))
+        },
+        Some(AuthMechanism::Login) => {
+            read_login(state, input, output, initial).and_then(|c| check_credentials(container, output, c))
+        },
+        Some(AuthMechanism::CramMd5) => {
+            read_cram_md5(config, container, state, input, output, initial)
+        },
+        None => {
+            output.write_reply(&Reply::enhanced(504, EnhancedStatusCode::new(5, 5, 4), "Unrecognized authentication type")).unwrap();
+            return;
+        }
+    };
+    if let (Some(identity), Some(mechanism)) = (identity, mechanism) {
+        state.set_auth(mechanism, identity.as_ref());
+        container.set_authenticated(Some(identity));
+        output.write_reply(&Reply::enhanced(235, EnhancedStatusCode::new(2, 7, 0), "Authentication successful")).unwrap();
+    }
+}
+
+/// Returns the AUTH command, as described
+/// [in RFC 4954](http://tools.ietf.org/html/rfc4954).
+///
+/// The mechanisms are chosen with `Server::set_auth_mechanisms`. PLAIN and LOGIN send the
+/// password in clear text, so you will usually want to require TLS first, see
+/// `policy::is_tls`.
+pub fn get<CT: TransactionState + AuthState + AuthHandler + Clone + Send, ST: SessionStream>() -> Command<CT, ST> {
+    let mut command = Command::new();
+    command.starts_with("AUTH ");
+    command.help("AUTH <mechanism> [<initial-response>]\nAuthenticates the client with a SASL mechanism");
+    command.extension("AUTH");
+    command.middleware(check_state);
+    command.middleware(check_argument);
+    command.middleware(handle_auth);
+    command
+}
@@ -0,0 +1,170 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::ascii::AsciiExt;
+use super::super::{ServerConfig, SessionError};
+use super::super::super::common::stream::InputStream;
+use super::super::super::common::stream::OutputStream;
+use super::super::super::common::stream::SessionStream;
+use super::super::super::common::Reply;
+use super::super::super::common::status::EnhancedStatusCode;
+use super::super::super::common::stream::{DATA_TOO_LONG, is_timeout};
+use super::super::NextMiddleware;
+use super::super::transaction::abort_transaction;
+use super::super::Command;
+use super::super::session::{SessionState, Phase};
+use super::TransactionState;
+use super::DataHandler;
+use super::data::{spill_data, check_encoding, check_controls, check_headers, check_filters, check_duplicate, check_dkim, handle_data};
+use super::super::filter::{self, FilterVerdict};
+
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
+
+/// Parses the argument of BDAT, ie `1000` or `1000 LAST`, into the chunk size and
+/// whether this is the last chunk.
+fn parse_argument(line: &str) -> Option<(usize, bool)> {
+    let mut parts = line.split(' ');
+    let size = match parts.next() {
+        Some(size) if size.len() > 0 && size.chars().all(|c| c >= '0' && c <= '9') => {
+            match size.parse::<usize>() {
+                Ok(size) => size,
+                Err(_) => return None
+            }
+        },
+        _ => return None
+    };
+    let last = match parts.next() {
+        None => false,
+        Some(last) if last.eq_ignore_ascii_case("LAST") => true,
+        Some(_) => return None
+    };
+    match parts.next() {
+        None => Some((size, last)),
+        Some(_) => None
+    }
+}
+
+#[test]
+fn test_parse_argument() {
+    assert_eq!(Some((1000, false)), parse_argument("1000"));
+    assert_eq!(Some((0, true)), parse_argument("0 LAST"));
+    assert_eq!(Some((12, true)), parse_argument("12 last"));
+    assert_eq!(None, parse_argument(""));
+    assert_eq!(None, parse_argument("LAST"));
+    assert_eq!(None, parse_argument("-1"));
+    assert_eq!(None, parse_argument("+1"));
+    assert_eq!(None, parse_argument("12 LAST "));
+    assert_eq!(None, parse_argument("12  LAST"));
+    assert_eq!(None, parse_argument("12 FIRST"));
+    assert_eq!(None, parse_argument("99999999999999999999999"));
+}
+
+fn check_argument<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    match parse_argument(line) {
+        None => {
+            output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, format: 'BDAT <size> [LAST]'")).unwrap();
+        },
+        Some(_) => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+fn read_chunk<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let (size, last) = parse_argument(line).unwrap();
+
+    // The chunk must be read even if we reject it, otherwise we would interpret
+    // its content as commands.
+    let max_size = config.max_message_size - container.transaction().data().len();
+    if let Err(err) = input.get_ref().set_read_timeout(Some(config.data_timeout)) {
+        state.set_failed(SessionError::Setup(err));
+        return;
+    }
+    // A chunk that is too large was still read, the others leave the connection unusable.
+    let chunk = match input.read_bytes(size, max_size) {
+        Ok(chunk) => Some(chunk),
+        Err(ref err) if err.description() == DATA_TOO_LONG => None,
+        Err(ref err) if is_timeout(err) => {
+            state.set_timed_out();
+            return;
+        },
+        Err(err) => {
+            state.set_failed(SessionError::Read(err));
+            return;
+        }
+    };
+
+    if !state.is_greeted() {
+        output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, HELO/EHLO first")).unwrap();
+        return;
+    }
+    if container.transaction().recipients().len() == 0 {
+        output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, RCPT first")).unwrap();
+        return;
+    }
+
+    match chunk {
+        Some(chunk) => {
+            let start = container.transaction().data().len();
+            state.set_phase(Phase::Data);
+            if container.transaction().append_data(chunk.as_ref()).and_then(|_| spill_data(config, container)).is_err() {
+                output.write_reply(&Reply::enhanced(451, EnhancedStatusCode::new(4, 3, 0), "Requested action aborted: local error in processing")).unwrap();
+                return;
+            }
+            if let FilterVerdict::Reject(text) = filter::check_chunk(config.filters.as_ref(), container.transaction().data(), start) {
+                // The chunk was read in full, so the session can go on without the message.
+                output.write_reply(&Reply::enhanced(554, EnhancedStatusCode::new(5, 7, 1), text.as_ref())).unwrap();
+                return;
+            }
+            match last {
+                true => {
+                    next.unwrap().call(config, container, state, input, output, line);
+                },
+                false => {
+                    output.write_reply(&Reply::enhanced(250, EnhancedStatusCode::new(2, 0, 0), format!("OK, {} octets received", size).as_ref())).unwrap();
+                }
+            }
+        },
+        None => {
+            output.write_reply(&Reply::enhanced(552, EnhancedStatusCode::new(5, 3, 4), "Message exceeds fixed maximum message size")).unwrap();
+        }
+    }
+}
+
+/// Returns the BDAT command, as described
+/// [in RFC 3030](http://tools.ietf.org/html/rfc3030).
+///
+/// Chunks are accumulated in the current transaction, and the message is handed to the
+/// `DataHandler` when the chunk marked `LAST` is received. Every chunk goes through the
+/// `ContentFilter`s of the server as it arrives.
+pub fn get<CT: TransactionState + DataHandler + Clone + Send, ST: SessionStream>() -> Command<CT, ST> {
+    let mut command = Command::new();
+    command.starts_with("BDAT ");
+    command.help("BDAT <size> [LAST]\nSends a chunk of the message, of exactly <size> octets");
+    command.extension("CHUNKING");
+    command.middleware(check_argument);
+    command.middleware(read_chunk);
+    command.middleware(check_encoding);
+    command.middleware(check_controls);
+    command.middleware(check_headers);
+    command.middleware(check_filters);
+    command.middleware(check_duplicate);
+    command.middleware(check_dkim);
+    command.middleware(handle_data);
+    command.on_failure(abort_transaction);
+    command
+}
@@ -0,0 +1,517 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::str;
+use std::usize;
+use std::borrow::{Cow, ToOwned};
+use std::io::Result as IoResult;
+use super::super::{ServerConfig, SessionError, Utf8Policy, ControlPolicy};
+use super::super::transaction::BodyType;
+use super::super::dedup::{get_message_id, DuplicateAction};
+use super::super::filter::{self, FilterVerdict, FILTER_CHUNK_SIZE};
+use super::super::fanout::{self, FanoutPolicy};
+use super::super::super::common::message::{check_header_limits, HeaderLimitError};
+use super::super::super::common::stream::InputStream;
+use super::super::super::common::stream::OutputStream;
+use super::super::super::common::stream::SessionStream;
+use super::super::super::common::Reply;
+use super::super::super::common::status::EnhancedStatusCode;
+use super::super::super::common::stream::{LINE_TOO_LONG, DATA_TOO_LONG, DATA_REJECTED, is_timeout};
+use super::super::NextMiddleware;
+use super::super::transaction::abort_transaction;
+use super::super::Command;
+use super::super::session::{SessionState, Phase, check_greeted};
+use super::TransactionState;
+use super::DataHandler;
+#[cfg(test)]
+use super::{mail, rcpt};
+#[cfg(test)]
+use std::iter::repeat;
+#[cfg(test)]
+use std::env;
+#[cfg(test)]
+use super::super::Server;
+#[cfg(test)]
+use super::super::filter::PatternFilter;
+#[cfg(test)]
+use super::super::testing::{TestSession, TestContainer};
+
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
+
+fn check_argument<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    match line.len() == 0 {
+        false => {
+            output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, DATA takes no argument")).unwrap();
+        },
+        true => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+fn check_transaction<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let transaction = container.transaction();
+    if transaction.is_started() && transaction.recipients().len() == 0 {
+        // The client may have pipelined DATA after recipients that were all rejected, as
+        // described [in RFC 2920](http://tools.ietf.org/html/rfc2920#section-3.1).
+        output.write_reply(&Reply::enhanced(554, EnhancedStatusCode::new(5, 5, 1), "No valid recipients")).unwrap();
+    } else if transaction.recipients().len() == 0 {
+        output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, RCPT first")).unwrap();
+    } else if transaction.data().len() > 0 {
+        // DATA can't be mixed with BDAT in the same transaction.
+        output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, BDAT in progress")).unwrap();
+    } else {
+        next.unwrap().call(config, container, state, input, output, line);
+    }
+}
+
+fn read_data<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    output.write_reply(&Reply::new(354, "Start mail input; end with <CRLF>.<CRLF>")).unwrap();
+    state.set_phase(Phase::Data);
+    if let Err(err) = output.flush() {
+        state.set_failed(SessionError::Write(err));
+        return;
+    }
+    if let Err(err) = input.get_ref().set_read_timeout(Some(config.data_timeout)) {
+        state.set_failed(SessionError::Setup(err));
+        return;
+    }
+    // The content moves to the transaction as it arrives, so it can be spilled before the
+    // end of the message.
+    let mut rejection = None;
+    let mut failed = false;
+    let res = input.read_data_into(config.max_message_size, FILTER_CHUNK_SIZE, |chunk, last| {
+        // The rest of the message is still read, so the client can be told.
+        if failed {
+            return true;
+        }
+        let start = container.transaction().data().len();
+        if container.transaction().append_data(chunk).and_then(|_| spill_data(config, container)).is_err() {
+            failed = true;
+            return true;
+        }
+        // The whole message is checked once it is over.
+        if last {
+            return true;
+        }
+        match filter::check_chunk(config.filters.as_ref(), container.transaction().data(), start) {
+            FilterVerdict::Continue => true,
+            FilterVerdict::Reject(text) => {
+                rejection = Some(text);
+                false
+            }
+        }
+    });
+    match res {
+        Ok(_) if failed => {
+            container.transaction().set_data(Vec::new());
+            output.write_reply(&Reply::enhanced(451, EnhancedStatusCode::new(4, 3, 0), "Requested action aborted: local error in processing")).unwrap();
+        },
+        Ok(_) => {
+            next.unwrap().call(config, container, state, input, output, line);
+        },
+        Err(err) => {
+            // What was received so far is of no use.
+            container.transaction().set_data(Vec::new());
+            if is_timeout(&err) {
+                state.set_timed_out();
+            } else if err.description() == DATA_REJECTED {
+                // The rest of the message would be read as commands, so the session ends.
+                let text = rejection.unwrap_or("Transaction failed".to_owned());
+                output.write_reply(&Reply::enhanced(554, EnhancedStatusCode::new(5, 7, 1), text.as_ref())).unwrap();
+                state.set_closing();
+            } else if err.description() == DATA_TOO_LONG {
+                output.write_reply(&Reply::enhanced(552, EnhancedStatusCode::new(5, 3, 4), "Message exceeds fixed maximum message size")).unwrap();
+            } else if err.description() == LINE_TOO_LONG {
+                output.write_reply(&Reply::enhanced(500, EnhancedStatusCode::new(5, 5, 6), "Line too long")).unwrap();
+            } else {
+                state.set_failed(SessionError::Read(err));
+            }
+        }
+    }
+}
+
+/// Moves the message content of the transaction to the spool once it reaches the spill
+/// size, see `Server::set_spool`.
+///
+/// This runs whenever content is received, and is shared with BDAT.
+pub fn spill_data<CT: TransactionState, ST>(config: &ServerConfig<CT, ST>, container: &mut CT) -> IoResult<()> {
+    let transaction = container.transaction();
+    match config.temp_store() {
+        Some(store) if transaction.data().len() >= config.spill_size => transaction.spill(store),
+        _ => Ok(())
+    }
+}
+
+// The middlewares below run in this order once the whole message has been received.
+// They are shared with BDAT, and with XZDAT but for `check_dkim`.
+
+/// Applies the `Utf8Policy` of the server, unless the client declared `BODY=8BITMIME`.
+pub fn check_encoding<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    // 8-bit content is passed through untouched.
+    let valid = container.transaction().body() == Some(BodyType::EightBitMime)
+        || str::from_utf8(container.transaction().data()).is_ok();
+    match (valid, config.utf8_policy) {
+        (false, Utf8Policy::Reject) => {
+            output.write_reply(&Reply::enhanced(500, EnhancedStatusCode::new(5, 6, 0), "Message content must be valid UTF-8")).unwrap();
+        },
+        (false, Utf8Policy::Replace) => {
+            let data = String::from_utf8_lossy(container.transaction().data()).into_owned();
+            container.transaction().set_data(data.into_bytes());
+            next.unwrap().call(config, container, state, input, output, line);
+        },
+        _ => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+/// Applies the `ControlPolicy` of the server to the message content.
+pub fn check_controls<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let verdict = match config.control_policy.apply(container.transaction().data(), b"\t\r\n\x0c") {
+        None => Err(()),
+        Some(Cow::Borrowed(_)) => Ok(None),
+        Some(Cow::Owned(data)) => Ok(Some(data))
+    };
+    match verdict {
+        Err(_) => {
+            output.write_reply(&Reply::enhanced(554, EnhancedStatusCode::new(5, 6, 0), "Message content contains control characters")).unwrap();
+            return;
+        },
+        Ok(Some(data)) => container.transaction().set_data(data),
+        Ok(None) => {}
+    }
+    next.unwrap().call(config, container, state, input, output, line);
+}
+
+/// Rejects messages whose header section exceeds the configured limits.
+pub fn check_headers<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let res = check_header_limits(container.transaction().data(), &config.header_limits);
+    match res {
+        Ok(_) => {
+            next.unwrap().call(config, container, state, input, output, line);
+        },
+        Err(err) => {
+            let text = match err {
+                HeaderLimitError::SectionTooLong => "Header section exceeds maximum size",
+                HeaderLimitError::TooManyFields => "Too many header fields",
+                HeaderLimitError::FieldTooLong => "Header field exceeds maximum size"
+            };
+            output.write_reply(&Reply::enhanced(552, EnhancedStatusCode::new(5, 3, 4), text)).unwrap();
+        }
+    }
+}
+
+/// Rejects messages that one of the `ContentFilter`s of the server rejects.
+pub fn check_filters<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    match filter::check_message(config.filters.as_ref(), container.transaction().data()) {
+        FilterVerdict::Continue => {
+            next.unwrap().call(config, container, state, input, output, line);
+        },
+        FilterVerdict::Reject(text) => {
+            output.write_reply(&Reply::enhanced(554, EnhancedStatusCode::new(5, 7, 1), text.as_ref())).unwrap();
+        }
+    }
+}
+
+/// Applies the configured `DuplicateAction` to messages that were already accepted.
+pub fn check_duplicate<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let action = match config.duplicates {
+        Some(ref duplicates) => {
+            let transaction = container.transaction();
+            match get_message_id(transaction.data()) {
+                Some(id) if duplicates.is_duplicate(id, transaction.sender(), transaction.recipients()) => {
+                    Some(duplicates.action())
+                },
+                _ => None
+            }
+        },
+        None => None
+    };
+
+    match action {
+        Some(DuplicateAction::Drop) => {
+            container.transaction().reset();
+            state.end_transaction();
+            output.write_reply(&Reply::enhanced(250, EnhancedStatusCode::new(2, 0, 0), "OK")).unwrap();
+        },
+        Some(DuplicateAction::Reject) => {
+            output.write_reply(&Reply::enhanced(554, EnhancedStatusCode::new(5, 7, 1), "Transaction failed, duplicate message")).unwrap();
+        },
+        Some(DuplicateAction::Deliver) | None => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+/// Stores the outcomes of the `DkimValidator` of the server, if any, in the transaction.
+pub fn check_dkim<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    if let Some(ref validator) = config.dkim {
+        let outcomes = validator.verify(container.transaction().data());
+        container.transaction().set_dkim_results(outcomes);
+    }
+    next.unwrap().call(config, container, state, input, output, line);
+}
+
+/// Hands the message to the container, then to the consumers of the server, and ends the
+/// transaction.
+pub fn handle_data<CT: TransactionState + DataHandler, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, _: &mut Input<ST>, output: &mut Output<ST>, _: &str, _: Next<CT, ST>) {
+    let data = container.transaction().take_content();
+    let res = container.handle_data(&data).map(|_| {
+        fanout::run(config.consumers.as_ref(), config.fanout_policy, container.transaction(), &data)
+    });
+    if let (&Ok(ref failures), &Some(ref metrics)) = (&res, &config.metrics) {
+        for _ in failures.iter() {
+            metrics.increment("consumer_failures");
+        }
+    }
+    match res {
+        Ok(ref failures) if failures.len() > 0 && config.fanout_policy == FanoutPolicy::AllMustSucceed => {
+            output.write_reply(&Reply::enhanced(451, EnhancedStatusCode::new(4, 3, 0), "Requested action aborted: local error in processing")).unwrap();
+        },
+        Ok(_) => {
+            // Only accepted messages count, otherwise the client could never retry after
+            // a temporary failure.
+            if let Some(ref duplicates) = config.duplicates {
+                if let Some(id) = get_message_id(&data) {
+                    let transaction = container.transaction();
+                    duplicates.insert(id, transaction.sender(), transaction.recipients());
+                }
+            }
+            output.write_reply(&Reply::enhanced(250, EnhancedStatusCode::new(2, 0, 0), "OK")).unwrap();
+        },
+        Err(_) => {
+            output.write_reply(&Reply::enhanced(554, EnhancedStatusCode::new(5, 0, 0), "Transaction failed")).unwrap();
+        }
+    }
+    container.transaction().reset();
+    state.end_transaction();
+}
+
+/// Returns the DATA command
+pub fn get<CT: TransactionState + DataHandler + Clone + Send, ST: SessionStream>() -> Command<CT, ST> {
+    let mut command = Command::new();
+    command.starts_with("DATA");
+    command.help("DATA\nSends the content of the message, ended by a line with a single dot");
+    command.last_in_group();
+    command.middleware(check_greeted);
+    command.middleware(check_argument);
+    command.middleware(check_transaction);
+    command.middleware(read_data);
+    command.middleware(check_encoding);
+    command.middleware(check_controls);
+    command.middleware(check_headers);
+    command.middleware(check_filters);
+    command.middleware(check_duplicate);
+    command.middleware(check_dkim);
+    command.middleware(handle_data);
+    command.on_failure(abort_transaction);
+    command
+}
+
+#[test]
+fn test_8bitmime() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(mail::get());
+    server.add_command(rcpt::get());
+    server.add_command(get());
+    server.set_utf8_policy(Utf8Policy::Reject);
+    let mut session = TestSession::new();
+
+    // Without BODY=8BITMIME, the UTF-8 policy applies.
+    for &(line, reply) in [("MAIL FROM:<a@rustastic.org>", "250 2.1.0 OK"), ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK")].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+    session.send("caf\u{e9}");
+    session.send(".");
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+    session.reply();
+    assert_eq!("250 2.0.0 OK", session.reply());
+
+    // Bytes that aren't UTF-8 are rejected and end the transaction.
+    for &(line, reply) in [("MAIL FROM:<a@rustastic.org> BODY=7BIT", "250 2.1.0 OK"), ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK")].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+    session.send_bytes(b"caf\xe9\r\n.\r\n");
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+    session.reply();
+    assert_eq!("500 5.6.0 Message content must be valid UTF-8", session.reply());
+    assert!(!container.transaction.is_started());
+
+    // With BODY=8BITMIME, the content is passed through untouched.
+    for &(line, reply) in [("MAIL FROM:<a@rustastic.org> BODY=8BITMIME", "250 2.1.0 OK"), ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK")].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+    session.send_bytes(b"caf\xe9\r\n.\r\n");
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+    session.reply();
+    assert_eq!("250 2.0.0 OK", session.reply());
+    assert_eq!(Some(b"caf\xe9\r\n".to_vec()), container.data);
+}
+
+#[test]
+fn test_small_limits() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(mail::get());
+    server.add_command(rcpt::get());
+    server.add_command(get());
+    server.allow_non_conforming_limits();
+    server.set_max_recipients(1).unwrap();
+    server.set_max_message_size(16).unwrap();
+    let mut session = TestSession::new();
+
+    for &(line, reply) in [
+        ("MAIL FROM:<a@rustastic.org>", "250 2.1.0 OK"),
+        ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK"),
+        ("RCPT TO:<c@rustastic.org>", "452 4.5.3 Too many recipients")
+    ].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+    session.send("Subject: a message too long");
+    session.send(".");
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+    session.reply();
+    assert_eq!("552 5.3.4 Message exceeds fixed maximum message size", session.reply());
+    assert!(!container.transaction.is_started());
+    assert_eq!(None, container.data);
+
+    for &(line, reply) in [("MAIL FROM:<a@rustastic.org>", "250 2.1.0 OK"), ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK")].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+    session.send("Short.");
+    session.send(".");
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+    session.reply();
+    assert_eq!("250 2.0.0 OK", session.reply());
+    assert_eq!(Some(b"Short.\r\n".to_vec()), container.data);
+}
+
+#[test]
+fn test_filters() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(mail::get());
+    server.add_command(rcpt::get());
+    server.add_command(get());
+    server.add_content_filter(PatternFilter::new(b"spam", "Spam detected"));
+    let mut session = TestSession::new();
+
+    // Small messages are checked at the end.
+    for &(line, reply) in [("MAIL FROM:<a@rustastic.org>", "250 2.1.0 OK"), ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK")].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+    session.send("buy spam");
+    session.send(".");
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+    session.reply();
+    assert_eq!("554 5.7.1 Spam detected", session.reply());
+    assert!(!session.state.is_closing());
+    assert!(!container.transaction.is_started());
+
+    // Large messages are rejected as soon as the pattern is seen, before the end.
+    for &(line, reply) in [("MAIL FROM:<a@rustastic.org>", "250 2.1.0 OK"), ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK")].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+    let line: String = repeat('a').take(900).collect();
+    session.send("buy spam");
+    for _ in 0 .. 20 {
+        session.send(line.as_ref());
+    }
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+    session.reply();
+    assert_eq!("554 5.7.1 Spam detected", session.reply());
+    assert!(session.state.is_closing());
+    assert!(!container.transaction.is_started());
+}
+
+#[test]
+fn test_spool() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(mail::get());
+    server.add_command(rcpt::get());
+    server.add_command(get());
+    server.set_spool(env::temp_dir(), 4);
+    let mut session = TestSession::new();
+
+    for &(line, reply) in [("MAIL FROM:<a@rustastic.org>", "250 2.1.0 OK"), ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK")].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+    session.send("hello");
+    session.send(".");
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+    session.reply();
+    assert_eq!("250 2.0.0 OK", session.reply());
+    assert_eq!(Some(b"hello\r\n".to_vec()), container.data);
+    assert!(!container.transaction.is_spilled());
+
+    // Large messages are spilled while they are received, chunk after chunk.
+    for &(line, reply) in [("MAIL FROM:<a@rustastic.org>", "250 2.1.0 OK"), ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK")].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+    let line: String = repeat('a').take(900).collect();
+    for _ in 0 .. 40 {
+        session.send(line.as_ref());
+    }
+    session.send(".");
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+    session.reply();
+    assert_eq!("250 2.0.0 OK", session.reply());
+    let expected: Vec<u8> = repeat(format!("{}\r\n", line)).take(40).collect::<String>().into_bytes();
+    assert!(40 * 902 > FILTER_CHUNK_SIZE);
+    assert!(container.data == Some(expected));
+}
+
+#[test]
+fn test_control_policy() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(mail::get());
+    server.add_command(rcpt::get());
+    server.add_command(get());
+    let mut session = TestSession::new();
+    let message = b"Subject: Hi\r\n\r\nH\x00i\tthere\x0c\r\n.\r\n";
+
+    for &(policy, reply, data) in [
+        (ControlPolicy::Accept, "250 2.0.0 OK", &b"Subject: Hi\r\n\r\nH\x00i\tthere\x0c\r\n"[..]),
+        (ControlPolicy::Strip, "250 2.0.0 OK", &b"Subject: Hi\r\n\r\nHi\tthere\x0c\r\n"[..]),
+        (ControlPolicy::Reject, "554 5.6.0 Message content contains control characters", &b""[..])
+    ].iter() {
+        server.set_control_policy(policy);
+        container.data = None;
+        for line in ["MAIL FROM:<a@rustastic.org>", "RCPT TO:<b@rustastic.org>"].iter() {
+            Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+            session.reply();
+        }
+        session.send_bytes(message);
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+        session.reply();
+        assert_eq!(reply, session.reply());
+        assert_eq!(data, container.data.as_ref().map_or(&b""[..], |data| data.as_ref()));
+    }
+}
@@ -12,79 +12,135 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::net::TcpStream;
+use std::borrow::ToOwned;
 use super::super::ServerConfig;
+#[cfg(test)]
+use super::super::{Server, AuthMechanism};
 use super::super::super::common::stream::InputStream;
 use super::super::super::common::stream::OutputStream;
+use super::super::super::common::stream::SessionStream;
+use super::super::super::common::Reply;
+use super::super::super::common::status::EnhancedStatusCode;
 use super::super::super::common::utils;
 use super::super::NextMiddleware;
 use super::super::Command;
-use super::HeloSeen;
+use super::super::session::{SessionState, check_not_greeted};
 use super::HeloHandler;
 
-type Next<CT> = Option<NextMiddleware<CT, TcpStream>>;
-type Input = InputStream<TcpStream>;
-type Output = OutputStream<TcpStream>;
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
 
-fn check_state<CT: HeloSeen>(config: &ServerConfig<CT>, container: &mut CT, input: &mut Input, output: &mut Output, line: &str, next: Next<CT>) {
-    match container.helo_seen() {
-        true => {
-            output.write_line("503 Bad sequence of commands, HELO/EHLO already seen").unwrap();
-        },
-        false => {
-            next.unwrap().call(config, container, input, output, line);
-        }
-    }
-}
-
-fn check_domain<CT>(config: &ServerConfig<CT>, container: &mut CT, input: &mut Input, output: &mut Output, line: &str, next: Next<CT>) {
+fn check_domain<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
     match utils::get_domain(line) {
         None => {
-            output.write_line("501 Domain name is invalid").unwrap();
+            output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Domain name is invalid")).unwrap();
         },
         Some(domain) => {
             match domain.len() == line.len() {
                 false => {
-                    output.write_line("501 Domain name is invalid").unwrap();
+                    output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Domain name is invalid")).unwrap();
                 },
                 true => {
-                    next.unwrap().call(config, container, input, output, line);
+                    next.unwrap().call(config, container, state, input, output, line);
                 }
             }
         }
     }
 }
 
-fn handle_domain<CT: HeloSeen + HeloHandler>(config: &ServerConfig<CT>, container: &mut CT, _: &mut Input, output: &mut Output, line: &str, _: Next<CT>) {
+// Returns the EHLO line of an extension, filling in the parameters that depend on the
+// configuration when the extension was given without any. Returns `None` if the
+// extension must not be advertised.
+fn describe_extension<CT, ST>(config: &ServerConfig<CT, ST>, extension: &str, tls: bool) -> Option<String> {
+    match extension {
+        // STARTTLS can't be used again once the session is encrypted.
+        "STARTTLS" if tls => None,
+        "SIZE" => Some(format!("SIZE {}", config.max_message_size)),
+        "AUTH" => {
+            match config.auth_mechanisms.len() {
+                0 => None,
+                _ => {
+                    let names: Vec<&str> = config.auth_mechanisms.iter().map(|m| m.name()).collect();
+                    Some(format!("AUTH {}", names.join(" ")))
+                }
+            }
+        },
+        _ => Some(extension.to_owned())
+    }
+}
+
+// Returns the reply to EHLO, ie the hostname followed by the extensions.
+fn get_reply<CT, ST>(config: &ServerConfig<CT, ST>, tls: bool) -> Reply {
+    let mut lines = vec![config.hostname.clone()];
+    for extension in config.extensions.iter() {
+        if let Some(extension) = describe_extension(config, extension, tls) {
+            lines.push(extension);
+        }
+    }
+    Reply::multiline(250, None, lines)
+}
+
+#[test]
+fn test_get_reply() {
+    let mut server = Server::new(());
+    server.set_hostname("rustastic.org").unwrap();
+    assert_eq!(vec!["250 rustastic.org".to_string()], get_reply(&server.config, false).to_lines());
+
+    server.add_extension("STARTTLS");
+    server.add_extension("SIZE");
+    server.add_extension("AUTH");
+    server.add_extension("X-CUSTOM a b");
+    server.set_auth_mechanisms(&[AuthMechanism::CramMd5, AuthMechanism::Plain]);
+    assert_eq!(vec![
+        "250-rustastic.org".to_string(),
+        "250-STARTTLS".to_string(),
+        "250-SIZE 65536".to_string(),
+        "250-AUTH CRAM-MD5 PLAIN".to_string(),
+        "250 X-CUSTOM a b".to_string()
+    ], get_reply(&server.config, false).to_lines());
+
+    // STARTTLS is gone once TLS is active, and AUTH when there are no mechanisms.
+    server.set_auth_mechanisms(&[]);
+    assert_eq!(vec![
+        "250-rustastic.org".to_string(),
+        "250-SIZE 65536".to_string(),
+        "250 X-CUSTOM a b".to_string()
+    ], get_reply(&server.config, true).to_lines());
+
+    // Adding an extension again replaces it, so parameters can be set by hand.
+    server.add_extension("size 1000");
+    assert_eq!(vec![
+        "250-rustastic.org".to_string(),
+        "250-size 1000".to_string(),
+        "250 X-CUSTOM a b".to_string()
+    ], get_reply(&server.config, true).to_lines());
+}
+
+fn handle_domain<CT: HeloHandler, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, _: Next<CT, ST>) {
     match container.handle_domain(line) {
         Ok(_) => {
-            container.set_helo_seen(true);
-            let mut i = config.extensions.len();
-            let host = if i > 0 {
-                format!("250-{}", config.hostname)
-            } else {
-                format!("250 {}", config.hostname)
-            };
-            output.write_line(host.as_ref()).unwrap();
-            while i != 1 {
-                output.write_line(format!("250-{}", config.extensions[i - 1]).as_ref()).unwrap();
-                i -= 1;
-            }
-            if i == 1 {
-                output.write_line(format!("250 {}", config.extensions[i - 1]).as_ref()).unwrap();
-            }
+            state.greet(line);
+
+            output.write_reply(&get_reply(config, input.get_ref().is_tls())).unwrap();
         },
         Err(_) => {
-            output.write_line("550 Domain not taken").unwrap();
+            output.write_reply(&Reply::enhanced(550, EnhancedStatusCode::new(5, 7, 1), "Domain not taken")).unwrap();
         }
     }
 }
 
-/// Returns the MAIL command
-pub fn get<CT: HeloSeen + HeloHandler + Clone + Send>() -> Command<CT, TcpStream> {
+/// Returns the EHLO command
+///
+/// The reply lists the extensions of the server, see `Server::add_extension`.
+pub fn get<CT: HeloHandler + Clone + Send, ST: SessionStream>() -> Command<CT, ST> {
     let mut command = Command::new();
     command.starts_with("EHLO ");
-    command.middleware(check_state);
+    command.help("EHLO <domain>\nIdentifies the client to the server and lists the supported extensions");
+    command.extension("PIPELINING");
+    command.extension("ENHANCEDSTATUSCODES");
+    command.last_in_group();
+    command.middleware(check_not_greeted);
     command.middleware(check_domain);
     command.middleware(handle_domain);
     command
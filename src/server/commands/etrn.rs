@@ -0,0 +1,162 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::super::ServerConfig;
+use super::super::super::common::stream::InputStream;
+use super::super::super::common::stream::OutputStream;
+use super::super::super::common::stream::SessionStream;
+use super::super::super::common::Reply;
+use super::super::super::common::status::EnhancedStatusCode;
+use super::super::super::common::utils;
+use super::super::NextMiddleware;
+use super::super::Command;
+use super::super::session::SessionState;
+use super::TransactionState;
+use super::EtrnHandler;
+#[cfg(test)]
+use super::super::Server;
+#[cfg(test)]
+use super::super::testing::{TestSession, TestContainer};
+
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
+
+/// The node whose queue the client asks to flush.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum EtrnNode {
+    /// `ETRN example.com`, the messages for the domain.
+    Domain(String),
+    /// `ETRN @example.com`, the messages for the domain and its subdomains.
+    Subdomains(String),
+    /// `ETRN #queue`, the messages of a queue named by the server.
+    Queue(String)
+}
+
+/// What the `EtrnHandler` did with the request.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum EtrnOutcome {
+    /// Delivery of the queued messages was started, replies `250`.
+    Started,
+    /// Messages were already being delivered, replies `252`.
+    Pending,
+    /// The queue can't be flushed right now, replies `458`.
+    Unavailable,
+    /// The client isn't allowed to flush the queue of this node, for the given reason,
+    /// replies `459`.
+    NotAllowed(String)
+}
+
+/// Parses the argument of ETRN, as described
+/// [in RFC 1985](http://tools.ietf.org/html/rfc1985#section-5).
+pub fn parse_node(line: &str) -> Option<EtrnNode> {
+    let is_domain = |s: &str| utils::get_domain(s) == Some(s);
+    if line.starts_with("@") && is_domain(&line[1 ..]) {
+        Some(EtrnNode::Subdomains(line[1 ..].to_owned()))
+    } else if line.starts_with("#") && line.len() > 1 && line[1 ..].chars().all(|c| c > ' ' && c <= '~') {
+        Some(EtrnNode::Queue(line[1 ..].to_owned()))
+    } else if is_domain(line) {
+        Some(EtrnNode::Domain(line.to_owned()))
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_parse_node() {
+    assert_eq!(Some(EtrnNode::Domain("rustastic.org".to_owned())), parse_node("rustastic.org"));
+    assert_eq!(Some(EtrnNode::Subdomains("rustastic.org".to_owned())), parse_node("@rustastic.org"));
+    assert_eq!(Some(EtrnNode::Queue("slow-queue".to_owned())), parse_node("#slow-queue"));
+    assert_eq!(None, parse_node(""));
+    assert_eq!(None, parse_node("@"));
+    assert_eq!(None, parse_node("#"));
+    assert_eq!(None, parse_node("# queue"));
+    assert_eq!(None, parse_node("rustastic.org extra"));
+    assert_eq!(None, parse_node("@@rustastic.org"));
+}
+
+fn check_state<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    if !state.is_greeted() {
+        output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, HELO/EHLO first")).unwrap();
+    } else if container.transaction().is_started() {
+        output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, ETRN not allowed during a mail transaction")).unwrap();
+    } else {
+        next.unwrap().call(config, container, state, input, output, line);
+    }
+}
+
+fn check_argument<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    match parse_node(line) {
+        None => {
+            output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, format: 'ETRN <domain>', 'ETRN @<domain>' or 'ETRN #<queue>'")).unwrap();
+        },
+        Some(_) => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+fn handle_etrn<CT: EtrnHandler, ST: SessionStream>(_: &ServerConfig<CT, ST>, container: &mut CT, _: &mut SessionState, _: &mut Input<ST>, output: &mut Output<ST>, line: &str, _: Next<CT, ST>) {
+    let node = parse_node(line).unwrap();
+    let reply = match container.handle_etrn(&node) {
+        EtrnOutcome::Started => {
+            Reply::enhanced(250, EnhancedStatusCode::new(2, 0, 0), format!("OK, queuing for node {} started", line).as_ref())
+        },
+        EtrnOutcome::Pending => {
+            Reply::enhanced(252, EnhancedStatusCode::new(2, 0, 0), format!("OK, pending messages for node {} started", line).as_ref())
+        },
+        EtrnOutcome::Unavailable => {
+            Reply::enhanced(458, EnhancedStatusCode::new(4, 3, 0), format!("Unable to queue messages for node {}", line).as_ref())
+        },
+        EtrnOutcome::NotAllowed(reason) => {
+            Reply::enhanced(459, EnhancedStatusCode::new(4, 7, 1), format!("Node {} not allowed: {}", line, reason).as_ref())
+        }
+    };
+    output.write_reply(&reply).unwrap();
+}
+
+/// Returns the ETRN command, as described [in RFC 1985](http://tools.ietf.org/html/rfc1985).
+///
+/// The `EtrnHandler` decides whether the queue of the node is flushed, ie by a gateway
+/// holding mail for a client that connects intermittently.
+pub fn get<CT: TransactionState + EtrnHandler + Clone + Send, ST: SessionStream>() -> Command<CT, ST> {
+    let mut command = Command::new();
+    command.starts_with("ETRN ");
+    command.help("ETRN <domain>|@<domain>|#<queue>\nStarts the delivery of the messages queued for a node");
+    command.extension("ETRN");
+    command.middleware(check_state);
+    command.middleware(check_argument);
+    command.middleware(handle_etrn);
+    command
+}
+
+#[test]
+fn test_etrn() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(get());
+    let mut session = TestSession::new();
+
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "ETRN @rustastic.org");
+    assert_eq!("250 2.0.0 OK, queuing for node @rustastic.org started", session.reply());
+    assert_eq!(Some(EtrnNode::Subdomains("rustastic.org".to_owned())), container.etrn);
+
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "ETRN rustastic..org");
+    assert!(session.reply().starts_with("501 5.5.4"));
+
+    container.transaction.start(None);
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "ETRN rustastic.org");
+    assert!(session.reply().starts_with("503 5.5.1"));
+    assert_eq!(Some(EtrnNode::Subdomains("rustastic.org".to_owned())), container.etrn);
+}
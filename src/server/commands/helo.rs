@@ -12,66 +12,58 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::net::TcpStream;
 use super::super::ServerConfig;
 use super::super::super::common::stream::InputStream;
 use super::super::super::common::stream::OutputStream;
+use super::super::super::common::stream::SessionStream;
+use super::super::super::common::Reply;
+use super::super::super::common::status::EnhancedStatusCode;
 use super::super::super::common::utils;
 use super::super::NextMiddleware;
 use super::super::Command;
-use super::HeloSeen;
+use super::super::session::{SessionState, check_not_greeted};
 use super::HeloHandler;
 
-type Next<CT> = Option<NextMiddleware<CT, TcpStream>>;
-type Input = InputStream<TcpStream>;
-type Output = OutputStream<TcpStream>;
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
 
-fn check_state<CT: HeloSeen>(config: &ServerConfig<CT>, container: &mut CT, input: &mut Input, output: &mut Output, line: &str, next: Next<CT>) {
-    match container.helo_seen() {
-        true => {
-            output.write_line("503 Bad sequence of commands, HELO/EHLO already seen").unwrap();
-        },
-        false => {
-            next.unwrap().call(config, container, input, output, line);
-        }
-    }
-}
-
-fn check_domain<CT>(config: &ServerConfig<CT>, container: &mut CT, input: &mut Input, output: &mut Output, line: &str, next: Next<CT>) {
+fn check_domain<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
     match utils::get_domain(line) {
         None => {
-            output.write_line("501 Domain name is invalid").unwrap();
+            output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Domain name is invalid")).unwrap();
         },
         Some(domain) => {
             match domain.len() == line.len() {
                 false => {
-                    output.write_line("501 Domain name is invalid").unwrap();
+                    output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Domain name is invalid")).unwrap();
                 },
                 true => {
-                    next.unwrap().call(config, container, input, output, line);
+                    next.unwrap().call(config, container, state, input, output, line);
                 }
             }
         }
     }
 }
 
-fn handle_domain<CT: HeloSeen + HeloHandler>(config: &ServerConfig<CT>, container: &mut CT, _: &mut Input, output: &mut Output, line: &str, _: Next<CT>) {
+fn handle_domain<CT: HeloHandler, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, _: &mut Input<ST>, output: &mut Output<ST>, line: &str, _: Next<CT, ST>) {
     match container.handle_domain(line) {
         Ok(_) => {
-            container.set_helo_seen(true);
-            output.write_line(format!("250 {}", config.hostname).as_ref()).unwrap();
+            state.greet(line);
+            output.write_reply(&Reply::new(250, config.hostname.as_ref())).unwrap();
         },
         Err(_) => {
-            output.write_line("550 Domain not taken").unwrap();
+            output.write_reply(&Reply::enhanced(550, EnhancedStatusCode::new(5, 7, 1), "Domain not taken")).unwrap();
         }
     }
 }
 
 /// Returns the MAIL command
-pub fn get<CT: HeloSeen + HeloHandler + Clone + Send>() -> Command<CT, TcpStream> {
+pub fn get<CT: HeloHandler + Clone + Send, ST: SessionStream>() -> Command<CT, ST> {
     let mut command = Command::new();
     command.starts_with("HELO ");
-    command.middleware(check_state);
+    command.help("HELO <domain>\nIdentifies the client to the server");
+    command.middleware(check_not_greeted);
     command.middleware(check_domain);
     command.middleware(handle_domain);
     command
@@ -0,0 +1,84 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use super::super::ServerConfig;
+use super::super::super::common::stream::InputStream;
+use super::super::super::common::stream::OutputStream;
+use super::super::super::common::stream::SessionStream;
+use super::super::super::common::Reply;
+use super::super::super::common::status::EnhancedStatusCode;
+use super::super::NextMiddleware;
+use super::super::Command;
+use super::super::session::SessionState;
+
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
+
+fn check_argument<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    // Make sure we don't accept something like "HELPME".
+    match line.len() == 0 || line.starts_with(" ") {
+        false => {
+            output.write_reply(&Reply::enhanced(500, EnhancedStatusCode::new(5, 5, 1), "Command unrecognized")).unwrap();
+        },
+        true => {
+            next.unwrap().call(config, container, state, input, output, line.trim());
+        }
+    }
+}
+
+fn handle_help<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, _: &mut CT, _: &mut SessionState, _: &mut Input<ST>, output: &mut Output<ST>, line: &str, _: Next<CT, ST>) {
+    let mut lines = Vec::new();
+
+    if line.len() == 0 {
+        lines.push("Supported commands:".to_owned());
+        for command in config.commands.iter() {
+            if let Some(verb) = command.verb() {
+                lines.push(format!("    {}", verb));
+            }
+        }
+        lines.push("Use HELP <command> for more information".to_owned());
+    } else {
+        for command in config.commands.iter() {
+            match (command.verb(), command.help.as_ref()) {
+                (Some(verb), Some(help)) if verb.eq_ignore_ascii_case(line) => {
+                    for help_line in help.lines() {
+                        lines.push(help_line.to_owned());
+                    }
+                    break;
+                },
+                _ => {}
+            }
+        }
+    }
+
+    if lines.len() == 0 {
+        output.write_reply(&Reply::enhanced(504, EnhancedStatusCode::new(5, 5, 4), "HELP topic unknown")).unwrap();
+        return;
+    }
+
+    output.write_reply(&Reply::multiline(214, Some(EnhancedStatusCode::new(2, 0, 0)), lines)).unwrap();
+}
+
+/// Returns the HELP command
+pub fn get<CT: Clone + Send, ST: SessionStream>() -> Command<CT, ST> {
+    let mut command = Command::new();
+    command.starts_with("HELP");
+    command.help("HELP [<command>]\nShows the supported commands, or help about one command");
+    command.middleware(check_argument);
+    command.middleware(handle_help);
+    command
+}
@@ -12,72 +12,270 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::net::TcpStream;
+use std::ascii::AsciiExt;
 use super::super::ServerConfig;
+use super::super::transaction::BodyType;
 use super::super::super::common::mailbox::Mailbox;
+use super::super::super::common::dsn::{SenderDsn, parse_ret, parse_envid};
+use super::super::super::common::parameters::{EsmtpParameters, ParameterError};
 use super::super::super::common::stream::InputStream;
 use super::super::super::common::stream::OutputStream;
+use super::super::super::common::stream::SessionStream;
+use super::super::super::common::Reply;
+use super::super::super::common::status::EnhancedStatusCode;
 use super::super::NextMiddleware;
 use super::super::Command;
-use super::HeloSeen;
+use super::super::session::{SessionState, Phase, check_greeted};
+use super::TransactionState;
 use super::MailHandler;
+use super::split_argument;
+#[cfg(test)]
+use super::super::super::common::dsn::DsnReturn;
+#[cfg(test)]
+use super::rcpt;
+#[cfg(test)]
+use super::super::Server;
+#[cfg(test)]
+use super::super::testing::{TestSession, TestContainer};
 
-type Next<CT> = Option<NextMiddleware<CT, TcpStream>>;
-type Input = InputStream<TcpStream>;
-type Output = OutputStream<TcpStream>;
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
 
-fn check_state<CT: HeloSeen>(config: &ServerConfig<CT>, container: &mut CT, input: &mut Input, output: &mut Output, line: &str, next: Next<CT>) {
-    match container.helo_seen() {
-        false => {
-            output.write_line("503 Bad sequence of commands, HELO/EHLO first").unwrap();
+fn check_no_mail<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    match config.no_mail {
+        true => {
+            output.write_reply(&Reply::enhanced(521, EnhancedStatusCode::new(5, 3, 2), format!("{} does not accept mail", config.hostname).as_ref())).unwrap();
         },
+        false => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+fn check_transaction<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    match container.transaction().is_started() {
         true => {
-            next.unwrap().call(config, container, input, output, line);
+            output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, MAIL already seen")).unwrap();
+        },
+        false => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+fn get_path(line: &str) -> &str {
+    split_argument(line).0
+}
+
+/// The parameters given to MAIL.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct MailParameters {
+    /// The body type declared with `BODY`.
+    pub body: Option<BodyType>,
+    /// Whether `SMTPUTF8` was given.
+    pub smtputf8: bool,
+    /// The size declared with `SIZE`, as described
+    /// [in RFC 1870](http://tools.ietf.org/html/rfc1870).
+    pub size: Option<usize>,
+    /// The DSN parameters, `RET` and `ENVID`.
+    pub dsn: SenderDsn,
+    /// All the parameters, as given.
+    pub parameters: EsmtpParameters
+}
+
+/// Parses the parameters of MAIL, returning them or the reply to send.
+///
+/// `SMTPUTF8` and `SIZE` are only recognized if the server advertises them.
+fn parse_parameters<CT, ST>(config: &ServerConfig<CT, ST>, params: &str) -> Result<MailParameters, Reply> {
+    let parameters = match EsmtpParameters::parse(params) {
+        Ok(parameters) => parameters,
+        Err(ParameterError::Duplicate) => {
+            return Err(Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, parameter given more than once"));
+        },
+        Err(_) => {
+            return Err(Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error in parameters"));
+        }
+    };
+    let mut mail = MailParameters {
+        body: None,
+        smtputf8: false,
+        size: None,
+        dsn: SenderDsn {
+            ret: None,
+            envid: None
+        },
+        parameters: EsmtpParameters::new()
+    };
+    for &(ref keyword, ref value) in parameters.as_slice().iter() {
+        let value = value.as_ref().map(|value| value.as_ref());
+        match (keyword.as_ref(), value) {
+            ("SMTPUTF8", None) if config.has_extension("SMTPUTF8") => {
+                mail.smtputf8 = true;
+            },
+            ("SMTPUTF8", Some(_)) if config.has_extension("SMTPUTF8") => {
+                return Err(Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, format: 'SMTPUTF8'"));
+            },
+            // See [RFC 1870](http://tools.ietf.org/html/rfc1870#section-6).
+            ("SIZE", value) if config.has_extension("SIZE") => {
+                match value.and_then(|value| parse_size(value)) {
+                    Some(size) if size > config.max_message_size => {
+                        return Err(Reply::enhanced(552, EnhancedStatusCode::new(5, 3, 4), "Message size exceeds fixed maximum message size"));
+                    },
+                    Some(size) => mail.size = Some(size),
+                    None => return Err(Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, format: 'SIZE=<octets>'"))
+                }
+            },
+            ("BODY", Some(value)) if value.eq_ignore_ascii_case("7BIT") => {
+                mail.body = Some(BodyType::SevenBit);
+            },
+            ("BODY", Some(value)) if value.eq_ignore_ascii_case("8BITMIME") => {
+                mail.body = Some(BodyType::EightBitMime);
+            },
+            ("BODY", _) => {
+                return Err(Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, format: 'BODY=7BIT' or 'BODY=8BITMIME'"));
+            },
+            // See [RFC 3461](http://tools.ietf.org/html/rfc3461#section-4.3).
+            ("RET", value) => {
+                match value.and_then(parse_ret) {
+                    Some(ret) => mail.dsn.ret = Some(ret),
+                    None => return Err(Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, format: 'RET=FULL' or 'RET=HDRS'"))
+                }
+            },
+            // See [RFC 3461](http://tools.ietf.org/html/rfc3461#section-4.4).
+            ("ENVID", value) => {
+                match value.and_then(parse_envid) {
+                    Some(envid) => mail.dsn.envid = Some(envid),
+                    None => return Err(Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, format: 'ENVID=<xtext>'"))
+                }
+            },
+            _ => {
+                return Err(Reply::enhanced(555, EnhancedStatusCode::new(5, 5, 4), "MAIL FROM parameters not recognized or not implemented"));
+            }
         }
     }
+    mail.parameters = parameters;
+    Ok(mail)
+}
+
+// Parses the value of `SIZE`, which is made of digits only.
+fn parse_size(value: &str) -> Option<usize> {
+    match value.chars().all(|c| c >= '0' && c <= '9') {
+        true => value.parse().ok(),
+        false => None
+    }
+}
+
+#[test]
+fn test_parse_parameters() {
+    let mut server = Server::new(());
+    assert_eq!(Ok(None), parse_parameters(&server.config, "").map(|p| p.body));
+    assert_eq!(Ok(Some(BodyType::SevenBit)), parse_parameters(&server.config, "BODY=7BIT").map(|p| p.body));
+    assert_eq!(Ok(Some(BodyType::EightBitMime)), parse_parameters(&server.config, "body=8bitmime").map(|p| p.body));
+    assert!(parse_parameters(&server.config, "BODY").is_err());
+    assert!(parse_parameters(&server.config, "BODY=BINARYMIME").is_err());
+    assert!(parse_parameters(&server.config, "BODY=7BIT BODY=7BIT").is_err());
+    assert!(parse_parameters(&server.config, "X-UNKNOWN=1").is_err());
+
+    // SMTPUTF8 and SIZE are only recognized when advertised.
+    assert!(parse_parameters(&server.config, "SMTPUTF8").is_err());
+    assert!(parse_parameters(&server.config, "SIZE=1000").is_err());
+    server.add_extension("SMTPUTF8");
+    server.add_extension("SIZE");
+    let parameters = parse_parameters(&server.config, "SMTPUTF8 BODY=8BITMIME size=1000").unwrap();
+    assert_eq!(Some(BodyType::EightBitMime), parameters.body);
+    assert!(parameters.smtputf8);
+    assert_eq!(Some(1000), parameters.size);
+    assert_eq!(Some(Some("1000")), parameters.parameters.get("SIZE"));
+    assert_eq!(Ok(false), parse_parameters(&server.config, "BODY=7BIT").map(|p| p.smtputf8));
+    assert!(parse_parameters(&server.config, "SMTPUTF8=YES").is_err());
+    assert!(parse_parameters(&server.config, "SIZE=+1000").is_err());
+    assert_eq!(Err(552), parse_parameters(&server.config, "SIZE=65537").map_err(|reply| reply.code()));
+
+    // DSN parameters.
+    let dsn = SenderDsn { ret: Some(DsnReturn::Headers), envid: Some("QQ 314159".to_owned()) };
+    assert_eq!(Ok(dsn), parse_parameters(&server.config, "ret=HDRS ENVID=QQ+20314159").map(|p| p.dsn));
+    assert!(parse_parameters(&server.config, "RET").is_err());
+    assert!(parse_parameters(&server.config, "RET=BODY").is_err());
+    assert!(parse_parameters(&server.config, "RET=FULL RET=FULL").is_err());
+    assert!(parse_parameters(&server.config, "ENVID=").is_err());
+    assert!(parse_parameters(&server.config, "ENVID=a=b").is_err());
+    assert!(parse_parameters(&server.config, "ENVID=a ENVID=b").is_err());
 }
 
-fn check_mailbox_format<CT>(config: &ServerConfig<CT>, container: &mut CT, input: &mut Input, output: &mut Output, line: &str, next: Next<CT>) {
-    match line.len() < 2 || line.starts_with("<") || line.ends_with(">") {
+fn check_mailbox_format<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let (path, _) = split_argument(line);
+    match path.len() >= 2 && path.starts_with("<") && path.ends_with(">") {
         false => {
-            output.write_line("501 Invalid argument, format: '<email@example.com>'").unwrap();
+            output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Invalid argument, format: '<email@example.com>'")).unwrap();
         },
         true => {
-            next.unwrap().call(config, container, input, output, line);
+            next.unwrap().call(config, container, state, input, output, line);
         }
     }
 }
 
-fn handle_no_sender<CT: MailHandler>(config: &ServerConfig<CT>, container: &mut CT, input: &mut Input, output: &mut Output, line: &str, next: Next<CT>) {
-    match line == "<>" {
+fn check_parameters<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let (_, params) = split_argument(line);
+    match parse_parameters(config, params) {
+        Err(reply) => {
+            output.write_reply(&reply).unwrap();
+        },
+        Ok(_) => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+fn handle_no_sender<CT: TransactionState + MailHandler, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let (path, params) = split_argument(line);
+    match path == "<>" {
         true => {
-            match container.handle_sender_address(None) {
+            let parameters = parse_parameters(config, params).unwrap();
+            match container.handle_sender_address(None, &parameters) {
                 Ok(_) => {
-                    output.write_line("250 OK").unwrap();
+                    container.transaction().start(None);
+                    state.set_phase(Phase::Mail);
+                    state.set_sender(None);
+                    container.transaction().set_body(parameters.body);
+                    container.transaction().set_smtputf8(parameters.smtputf8);
+                    output.write_reply(&Reply::enhanced(250, EnhancedStatusCode::new(2, 1, 0), "OK")).unwrap();
                 },
                 Err(_) => {
-                    output.write_line("550 Mailbox not taken").unwrap();
+                    output.write_reply(&Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 8), "Mailbox not taken")).unwrap();
                 }
             }
         },
         false => {
-            next.unwrap().call(config, container, input, output, line);
+            next.unwrap().call(config, container, state, input, output, line);
         }
     }
 }
 
-fn handle_sender<CT: MailHandler>(_: &ServerConfig<CT>, container: &mut CT, _: &mut Input, output: &mut Output, line: &str, _: Next<CT>) {
-    match Mailbox::parse(&line[1 .. line.len() - 1]) {
+fn handle_sender<CT: TransactionState + MailHandler, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, _: &mut Input<ST>, output: &mut Output<ST>, line: &str, _: Next<CT, ST>) {
+    let (path, params) = split_argument(line);
+    let parameters = parse_parameters(config, params).unwrap();
+    let address = &path[1 .. path.len() - 1];
+    let mailbox = match parameters.smtputf8 {
+        true => Mailbox::parse_utf8(address),
+        false => Mailbox::parse(address)
+    };
+    match mailbox {
         Err(err) => {
-            output.write_line(format!("553 Email address invalid: {:?}", err).as_ref()).unwrap();
+            output.write_reply(&Reply::enhanced(553, EnhancedStatusCode::new(5, 1, 7), format!("Email address invalid: {:?}", err).as_ref())).unwrap();
         },
         Ok(mailbox) => {
-            match container.handle_sender_address(Some(mailbox)) {
+            match container.handle_sender_address(Some(mailbox.clone()), &parameters) {
                 Ok(_) => {
-                    output.write_line("250 OK").unwrap();
+                    container.transaction().start(Some(mailbox.clone()));
+                    state.set_phase(Phase::Mail);
+                    state.set_sender(Some(mailbox));
+                    container.transaction().set_body(parameters.body);
+                    container.transaction().set_smtputf8(parameters.smtputf8);
+                    output.write_reply(&Reply::enhanced(250, EnhancedStatusCode::new(2, 1, 0), "OK")).unwrap();
                 },
                 Err(_) => {
-                    output.write_line("550 Mailbox not taken").unwrap();
+                    output.write_reply(&Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 8), "Mailbox not taken")).unwrap();
                 }
             }
         }
@@ -85,12 +283,84 @@ fn handle_sender<CT: MailHandler>(_: &ServerConfig<CT>, container: &mut CT, _: &
 }
 
 /// Returns the MAIL command
-pub fn get<CT: HeloSeen + MailHandler + Clone + Send>() -> Command<CT, TcpStream> {
+///
+/// The `SMTPUTF8` parameter of [RFC 6531](http://tools.ietf.org/html/rfc6531) is accepted
+/// once the server advertises the extension with `Server::add_extension("SMTPUTF8")`. The
+/// `RET` and `ENVID` parameters of [RFC 3461](http://tools.ietf.org/html/rfc3461) and
+/// the `SIZE` parameter of [RFC 1870](http://tools.ietf.org/html/rfc1870) are passed to
+/// the `MailHandler` with the rest of the parameters.
+pub fn get<CT: TransactionState + MailHandler + Clone + Send, ST: SessionStream>() -> Command<CT, ST> {
     let mut command = Command::new();
     command.starts_with("MAIL FROM:");
-    command.middleware(check_state);
+    command.help("MAIL FROM:<address> [BODY=7BIT|BODY=8BITMIME] [SMTPUTF8] [SIZE=<octets>] [RET=FULL|RET=HDRS] [ENVID=<id>]\nStarts a mail transaction with the given sender");
+    command.extension("8BITMIME");
+    command.extension("DSN");
+    // See [RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.5.3.1.3) for the path,
+    // [RFC 6152](http://tools.ietf.org/html/rfc6152#section-2) for `BODY=8BITMIME` and
+    // [RFC 3461](http://tools.ietf.org/html/rfc3461#section-4) for DSN.
+    command.max_path_size(256, get_path);
+    command.increase_max_line_size("8BITMIME", 14);
+    command.increase_max_line_size("SMTPUTF8", 10);
+    command.increase_max_line_size("DSN", 110);
+    command.increase_max_line_size("SIZE", 26);
+    command.middleware(check_no_mail);
+    command.middleware(check_greeted);
+    command.middleware(check_transaction);
     command.middleware(check_mailbox_format);
+    command.middleware(check_parameters);
     command.middleware(handle_no_sender);
     command.middleware(handle_sender);
     command
 }
+
+#[test]
+fn test_smtputf8() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(get());
+    server.add_command(rcpt::get());
+    let mut session = TestSession::new();
+
+    // Not advertised, so not recognized.
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "MAIL FROM:<josé@bücher.de> SMTPUTF8");
+    assert_eq!("555 5.5.4 MAIL FROM parameters not recognized or not implemented", session.reply());
+
+    server.add_extension("SMTPUTF8");
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "MAIL FROM:<josé@bücher.de>");
+    assert!(session.reply().starts_with("553"));
+    assert!(!container.transaction.is_started());
+
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "MAIL FROM:<josé@bücher.de> SMTPUTF8");
+    assert_eq!("250 2.1.0 OK", session.reply());
+    assert!(container.transaction.is_smtputf8());
+    assert_eq!(Some(&Mailbox::parse_utf8("josé@bücher.de").unwrap()), container.transaction.sender());
+
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<用户@例子.广告>");
+    assert_eq!("250 2.1.5 OK", session.reply());
+    assert_eq!(1, container.transaction.recipients().len());
+}
+
+#[test]
+fn test_no_mail() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.set_hostname("rustastic.org").unwrap();
+    server.add_command(get());
+    server.add_command(rcpt::get());
+    server.add_no_mail_domain("example.com");
+    let mut session = TestSession::new();
+
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "MAIL FROM:<rust@rustastic.org>");
+    assert_eq!("250 2.1.0 OK", session.reply());
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<rust@EXAMPLE.com>");
+    assert_eq!("556 5.1.10 Domain does not accept mail", session.reply());
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<rust@rustastic.org>");
+    assert_eq!("250 2.1.5 OK", session.reply());
+    assert_eq!(1, container.transaction.recipients().len());
+
+    container.transaction.reset();
+    server.accept_no_mail();
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "MAIL FROM:<rust@rustastic.org>");
+    assert_eq!("521 5.3.2 rustastic.org does not accept mail", session.reply());
+    assert!(!container.transaction.is_started());
+}
@@ -13,6 +13,10 @@
 // limitations under the License.
 
 use super::super::common::mailbox::Mailbox;
+use super::transaction::Transaction;
+use self::mail::MailParameters;
+use self::rcpt::RcptParameters;
+use self::etrn::{EtrnNode, EtrnOutcome};
 
 /// The MAIL command.
 pub mod mail;
@@ -26,14 +30,47 @@ pub mod ehlo;
 /// The RCPT command.
 pub mod rcpt;
 
-/// Allows commands to get access to information about the state of the
-/// current transaction.
-pub trait HeloSeen {
-    /// Returns the state object for the current connection.
-    fn helo_seen(&mut self) -> bool;
+/// The DATA command.
+pub mod data;
 
-    /// Sets if we have HELO or not.
-    fn set_helo_seen(&mut self, helo_seen: bool);
+/// The HELP command.
+pub mod help;
+
+/// The BDAT command.
+pub mod bdat;
+
+/// The STARTTLS command.
+pub mod starttls;
+
+/// The AUTH command.
+pub mod auth;
+
+/// The ETRN command.
+pub mod etrn;
+
+/// The XZDAT command, a private extension for compressed messages.
+pub mod xzdat;
+
+/// The NOOP command.
+pub mod noop;
+
+/// The RSET command.
+pub mod rset;
+
+/// Allows commands to keep track of the mail transaction of the current
+/// connection.
+pub trait TransactionState {
+    /// Returns the transaction in progress.
+    fn transaction(&mut self) -> &mut Transaction;
+}
+
+/// Allows commands to keep track of the identity the client authenticated as.
+pub trait AuthState {
+    /// Returns the authenticated identity, if the client authenticated.
+    fn authenticated(&mut self) -> Option<&str>;
+
+    /// Sets the authenticated identity.
+    fn set_authenticated(&mut self, identity: Option<String>);
 }
 
 /// Methods needed by the MAIL/RCPT command to read the current state.
@@ -48,11 +85,66 @@ pub trait MailHandler {
     ///
     /// This will be `None` when the argument to MAIL is `<>`. This can happen
     /// when a server receives a delivery failure notification.
-    fn handle_sender_address(&mut self, mailbox: Option<Mailbox>) -> Result<(), ()>;
+    ///
+    /// `params` holds the parameters given after the address, such as `SIZE` or the
+    /// `RET` and `ENVID` parameters of DSN.
+    fn handle_sender_address(&mut self, mailbox: Option<Mailbox>, params: &MailParameters) -> Result<(), ()>;
 }
 
 /// Methods needed by the RCPT command to read the current state.
 pub trait RcptHandler {
     /// Handles the email address passed to the RCPT command.
-    fn handle_receiver_address(&mut self, mailbox: Mailbox) -> Result<(), ()>;
+    ///
+    /// `params` holds the parameters given after the address, such as the `NOTIFY` and
+    /// `ORCPT` parameters of DSN.
+    fn handle_receiver_address(&mut self, mailbox: Mailbox, params: &RcptParameters) -> Result<(), ()>;
+}
+
+/// Methods needed by the DATA command to read the current state.
+pub trait DataHandler {
+    /// Handles the message content passed to the DATA command.
+    ///
+    /// The sender and recipients can be read from the current `Transaction`.
+    fn handle_data(&mut self, data: &[u8]) -> Result<(), ()>;
+}
+
+/// Methods needed by the AUTH command to validate credentials.
+pub trait AuthHandler {
+    /// Checks the password of a user.
+    ///
+    /// `authorization` is the identity the client wants to act as, when it differs from
+    /// `username`. It is only set by mechanisms that support it, like PLAIN.
+    fn handle_credentials(&mut self, authorization: Option<&str>, username: &str, password: &str) -> Result<(), ()>;
+
+    /// Returns the shared secret of a user, for mechanisms that never see the password,
+    /// like CRAM-MD5.
+    ///
+    /// By default, there is no secret and these mechanisms always fail.
+    #[allow(unused_variables)]
+    fn get_secret(&mut self, username: &str) -> Option<String> {
+        None
+    }
+}
+
+/// Methods needed by the ETRN command to flush queues.
+pub trait EtrnHandler {
+    /// Starts the delivery of the messages queued for the node, and tells how it went.
+    fn handle_etrn(&mut self, node: &EtrnNode) -> EtrnOutcome;
+}
+
+/// Splits the argument of MAIL or RCPT into the path and the parameters, ie
+/// `<a@b> BODY=7BIT` into `<a@b>` and `BODY=7BIT`.
+pub fn split_argument(line: &str) -> (&str, &str) {
+    match line.find("> ") {
+        Some(pos) => (&line[.. pos + 1], &line[pos + 2 ..]),
+        None => (line, "")
+    }
+}
+
+#[test]
+fn test_split_argument() {
+    assert_eq!(("<a@b>", ""), split_argument("<a@b>"));
+    assert_eq!(("<>", "BODY=8BITMIME"), split_argument("<> BODY=8BITMIME"));
+    assert_eq!(("<a@b>", "BODY=7BIT X=Y"), split_argument("<a@b> BODY=7BIT X=Y"));
+    assert_eq!(("a@b", ""), split_argument("a@b"));
 }
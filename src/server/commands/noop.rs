@@ -0,0 +1,65 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use super::super::ServerConfig;
+use super::super::super::common::stream::InputStream;
+use super::super::super::common::stream::OutputStream;
+use super::super::super::common::stream::SessionStream;
+use super::super::super::common::Reply;
+use super::super::super::common::status::EnhancedStatusCode;
+use super::super::{NextMiddleware, ArgumentPolicy};
+use super::super::Command;
+use super::super::session::SessionState;
+#[cfg(test)]
+use super::super::Server;
+#[cfg(test)]
+use super::super::testing::{TestSession, TestContainer};
+
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
+
+fn handle_noop<CT, ST: SessionStream>(_: &ServerConfig<CT, ST>, _: &mut CT, _: &mut SessionState, _: &mut Input<ST>, output: &mut Output<ST>, _: &str, _: Next<CT, ST>) {
+    output.write_reply(&Reply::enhanced(250, EnhancedStatusCode::new(2, 0, 0), "OK")).unwrap();
+}
+
+/// Returns the NOOP command, as described
+/// [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.1.1.9).
+///
+/// The argument, if any, is ignored.
+pub fn get<CT: Clone + Send, ST: SessionStream>() -> Command<CT, ST> {
+    let mut command = Command::new();
+    command.starts_with("NOOP");
+    command.help("NOOP\nDoes nothing");
+    command.arguments(ArgumentPolicy::Ignored);
+    command.middleware(handle_noop);
+    command
+}
+
+#[test]
+fn test_noop() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(get());
+    server.set_strict_syntax(true);
+    let mut session = TestSession::new();
+
+    for line in ["NOOP", "noop ignored text", "NOOP "].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!("250 2.0.0 OK", session.reply());
+    }
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "NOOPS");
+    assert_eq!("500 5.5.1 Command unrecognized", session.reply());
+}
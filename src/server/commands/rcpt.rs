@@ -12,66 +12,423 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::net::TcpStream;
+use std::ascii::AsciiExt;
+use std::time::SystemTime;
 use super::super::ServerConfig;
-use super::super::super::common::mailbox::Mailbox;
+use super::super::super::common::mailbox::{Mailbox, MailboxForeignPart};
+use super::super::super::common::dsn::{RecipientDsn, parse_notify, parse_orcpt};
+use super::super::super::common::parameters::{EsmtpParameters, ParameterError};
 use super::super::super::common::stream::InputStream;
 use super::super::super::common::stream::OutputStream;
+use super::super::super::common::stream::SessionStream;
+use super::super::super::common::Reply;
+use super::super::super::common::status::EnhancedStatusCode;
 use super::super::NextMiddleware;
 use super::super::Command;
-use super::HeloSeen;
+use super::super::session::{SessionState, Phase, check_greeted};
+use super::super::greylist::{GreylistKey, GreylistDecision};
+use super::super::policy::is_trusted_network;
+use super::TransactionState;
 use super::RcptHandler;
+use super::split_argument;
+#[cfg(test)]
+use super::super::super::common::dsn::DsnNotify;
+#[cfg(test)]
+use super::data;
+#[cfg(test)]
+use super::super::Server;
+#[cfg(test)]
+use super::super::testing::{TestSession, TestContainer};
+#[cfg(test)]
+use super::super::subaddress::SubaddressPolicy;
+#[cfg(test)]
+use super::super::operator::OperatorAddresses;
+#[cfg(test)]
+use super::super::greylist::{Greylist, MemoryGreylistStore};
+#[cfg(test)]
+use std::time::Duration;
+#[cfg(test)]
+use std::borrow::ToOwned;
 
-type Next<CT> = Option<NextMiddleware<CT, TcpStream>>;
-type Input = InputStream<TcpStream>;
-type Output = OutputStream<TcpStream>;
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
 
-fn check_state<CT: HeloSeen>(config: &ServerConfig<CT>, container: &mut CT, input: &mut Input, output: &mut Output, line: &str, next: Next<CT>) {
-    match container.helo_seen() {
+fn check_transaction<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    match container.transaction().is_started() {
         false => {
-            output.write_line("503 Bad sequence of commands, HELO/EHLO first").unwrap();
+            output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, MAIL first")).unwrap();
         },
         true => {
-            next.unwrap().call(config, container, input, output, line);
+            next.unwrap().call(config, container, state, input, output, line);
         }
     }
 }
 
-fn check_mailbox_format<CT>(config: &ServerConfig<CT>, container: &mut CT, input: &mut Input, output: &mut Output, line: &str, next: Next<CT>) {
-    match line.len() < 2 || line.starts_with("<") || line.ends_with(">") {
+fn get_path(line: &str) -> &str {
+    split_argument(line).0
+}
+
+/// The parameters given to RCPT.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct RcptParameters {
+    /// The DSN parameters, `NOTIFY` and `ORCPT`.
+    pub dsn: RecipientDsn,
+    /// All the parameters, as given.
+    pub parameters: EsmtpParameters,
+    /// The detail of the recipient, ie `smtp` in `rust+smtp@rustastic.org`.
+    ///
+    /// This is only set with `Server::set_subaddress_policy`.
+    pub detail: Option<String>
+}
+
+/// Parses the parameters of RCPT, returning them or the reply to send.
+fn parse_parameters(params: &str) -> Result<RcptParameters, Reply> {
+    let parameters = match EsmtpParameters::parse(params) {
+        Ok(parameters) => parameters,
+        Err(ParameterError::Duplicate) => {
+            return Err(Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, parameter given more than once"));
+        },
+        Err(_) => {
+            return Err(Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error in parameters"));
+        }
+    };
+    let mut dsn = RecipientDsn {
+        notify: None,
+        orcpt: None
+    };
+    for &(ref keyword, ref value) in parameters.as_slice().iter() {
+        let value = value.as_ref().map(|value| value.as_ref());
+        match keyword.as_ref() {
+            // See [RFC 3461](http://tools.ietf.org/html/rfc3461#section-4.1).
+            "NOTIFY" => {
+                match value.and_then(parse_notify) {
+                    Some(notify) => dsn.notify = Some(notify),
+                    None => return Err(Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, format: 'NOTIFY=NEVER' or 'NOTIFY=SUCCESS,FAILURE,DELAY'"))
+                }
+            },
+            // See [RFC 3461](http://tools.ietf.org/html/rfc3461#section-4.2).
+            "ORCPT" => {
+                match value.and_then(parse_orcpt) {
+                    Some(orcpt) => dsn.orcpt = Some(orcpt),
+                    None => return Err(Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, format: 'ORCPT=<type>;<xtext>'"))
+                }
+            },
+            _ => {
+                return Err(Reply::enhanced(555, EnhancedStatusCode::new(5, 5, 4), "RCPT TO parameters not recognized or not implemented"));
+            }
+        }
+    }
+    Ok(RcptParameters {
+        dsn: dsn,
+        parameters: parameters,
+        detail: None
+    })
+}
+
+#[test]
+fn test_parse_parameters() {
+    assert_eq!(Ok(RecipientDsn { notify: None, orcpt: None }), parse_parameters("").map(|p| p.dsn));
+    let parameters = parse_parameters("notify=SUCCESS,DELAY ORCPT=rfc822;a+2Bb@rustastic.org").unwrap();
+    assert_eq!(Some(DsnNotify { success: true, failure: false, delay: true }), parameters.dsn.notify);
+    assert_eq!("rfc822", parameters.dsn.orcpt.as_ref().unwrap().address_type);
+    assert_eq!("a+b@rustastic.org", parameters.dsn.orcpt.as_ref().unwrap().address);
+    assert_eq!(Some(Some("SUCCESS,DELAY")), parameters.parameters.get("NOTIFY"));
+    assert_eq!(2, parameters.parameters.len());
+    assert!(parse_parameters("NOTIFY").is_err());
+    assert!(parse_parameters("NOTIFY=NEVER,DELAY").is_err());
+    assert!(parse_parameters("NOTIFY=NEVER NOTIFY=NEVER").is_err());
+    assert!(parse_parameters("ORCPT=a@rustastic.org").is_err());
+    assert!(parse_parameters("ORCPT=rfc822;a ORCPT=rfc822;b").is_err());
+    assert!(parse_parameters("BODY=8BITMIME").is_err());
+}
+
+fn check_mailbox_format<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let (path, _) = split_argument(line);
+    match path.len() >= 2 && path.starts_with("<") && path.ends_with(">") {
         false => {
-            output.write_line("501 Invalid argument, format: '<email@example.com>'").unwrap();
+            output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Invalid argument, format: '<email@example.com>'")).unwrap();
+        },
+        true => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+fn check_parameters<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let (_, params) = split_argument(line);
+    match parse_parameters(params) {
+        Err(reply) => {
+            output.write_reply(&reply).unwrap();
         },
+        Ok(_) => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+fn check_recipient_count<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    // The transaction goes on with the recipients accepted so far, as described
+    // [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.5.3.1.10).
+    match container.transaction().recipients().len() >= config.max_recipients {
         true => {
-            next.unwrap().call(config, container, input, output, line);
+            output.write_reply(&Reply::enhanced(452, EnhancedStatusCode::new(4, 5, 3), "Too many recipients")).unwrap();
+        },
+        false => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+// Defers recipients according to `Server::set_greylist`.
+fn check_greylist<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let decision = match (config.greylist.as_ref(), input.peer_addr()) {
+        (Some(greylist), Ok(addr)) if !is_trusted_network(config, container, input, line) => {
+            let (path, _) = split_argument(line);
+            match Mailbox::parse_utf8(&path[1 .. path.len() - 1]) {
+                // Operators must always be reachable.
+                Ok(ref recipient) if !config.operators.is_operator(recipient, config.hostname.as_ref()) => {
+                    greylist.check(&GreylistKey::new(addr.ip(), state.sender(), recipient), SystemTime::now())
+                },
+                _ => GreylistDecision::Pass
+            }
+        },
+        _ => GreylistDecision::Pass
+    };
+    match decision {
+        GreylistDecision::Defer(wait) => {
+            let text = format!("Greylisted, try again in {} seconds", wait.as_secs() + 1);
+            output.write_reply(&Reply::enhanced(450, EnhancedStatusCode::new(4, 7, 1), text.as_ref())).unwrap();
+        },
+        GreylistDecision::Pass => {
+            next.unwrap().call(config, container, state, input, output, line);
         }
     }
 }
 
-fn handle_receiver<CT: RcptHandler>(_: &ServerConfig<CT>, container: &mut CT, _: &mut Input, output: &mut Output, line: &str, _: Next<CT>) {
-    match Mailbox::parse(&line[1 .. line.len() - 1]) {
+// Tells whether the domain of the mailbox accepts no mail, see `Server::add_no_mail_domain`.
+fn accepts_no_mail<CT, ST>(config: &ServerConfig<CT, ST>, mailbox: &Mailbox) -> bool {
+    match *mailbox.foreign_part() {
+        MailboxForeignPart::Domain(ref domain) => {
+            config.no_mail_domains.iter().any(|d| d.eq_ignore_ascii_case(domain))
+        },
+        MailboxForeignPart::IpAddr(_) => false
+    }
+}
+
+fn handle_receiver<CT: TransactionState + RcptHandler, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, _: &mut Input<ST>, output: &mut Output<ST>, line: &str, _: Next<CT, ST>) {
+    let (path, params) = split_argument(line);
+    let mut parameters = parse_parameters(params).unwrap();
+    let address = &path[1 .. path.len() - 1];
+    let mailbox = match (address.eq_ignore_ascii_case("postmaster"), container.transaction().is_smtputf8()) {
+        // `<Postmaster>` has no domain, it is the postmaster of the server.
+        (true, _) => Mailbox::parse(format!("{}@{}", address, config.hostname).as_ref()),
+        (false, true) => Mailbox::parse_utf8(address),
+        (false, false) => Mailbox::parse(address)
+    };
+    match mailbox {
         Err(err) => {
-            output.write_line(format!("553 Email address invalid: {:?}", err).as_ref()).unwrap();
+            output.write_reply(&Reply::enhanced(553, EnhancedStatusCode::new(5, 1, 3), format!("Email address invalid: {:?}", err).as_ref())).unwrap();
+        },
+        Ok(ref mailbox) if config.operators.is_operator(mailbox, config.hostname.as_ref()) => {
+            let recipient = match container.handle_receiver_address(mailbox.clone(), &parameters) {
+                Ok(_) => mailbox.clone(),
+                Err(_) => config.operators.route(mailbox)
+            };
+            container.transaction().add_recipient(recipient);
+            state.set_phase(Phase::Rcpt);
+            output.write_reply(&Reply::enhanced(250, EnhancedStatusCode::new(2, 1, 5), "OK")).unwrap();
+        },
+        Ok(ref mailbox) if accepts_no_mail(config, mailbox) => {
+            output.write_reply(&Reply::enhanced(556, EnhancedStatusCode::new(5, 1, 10), "Domain does not accept mail")).unwrap();
         },
         Ok(mailbox) => {
-            match container.handle_receiver_address(mailbox) {
+            // The transaction keeps the full address, so the detail is still there for
+            // delivery.
+            let mut routed = mailbox.clone();
+            if let Some(ref policy) = config.subaddresses {
+                if let Some((base, detail)) = policy.split(&mailbox) {
+                    parameters.detail = Some(detail);
+                    if policy.routes_on_base() {
+                        routed = base;
+                    }
+                }
+            }
+            match container.handle_receiver_address(routed, &parameters) {
                 Ok(_) => {
-                    output.write_line("250 OK").unwrap();
+                    container.transaction().add_recipient(mailbox);
+                    state.set_phase(Phase::Rcpt);
+                    output.write_reply(&Reply::enhanced(250, EnhancedStatusCode::new(2, 1, 5), "OK")).unwrap();
                 },
                 Err(_) => {
-                    output.write_line("550 Mailbox not taken").unwrap();
+                    output.write_reply(&Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 1), "Mailbox not taken")).unwrap();
                 }
             }
         }
     }
 }
 
-/// Returns the MAIL command
-pub fn get<CT: HeloSeen + RcptHandler + Clone + Send>() -> Command<CT, TcpStream> {
+/// Returns the RCPT command
+///
+/// The `NOTIFY` and `ORCPT` parameters of [RFC 3461](http://tools.ietf.org/html/rfc3461)
+/// are passed to the `RcptHandler`.
+pub fn get<CT: TransactionState + RcptHandler + Clone + Send, ST: SessionStream>() -> Command<CT, ST> {
     let mut command = Command::new();
     command.starts_with("RCPT TO:");
-    command.middleware(check_state);
+    command.help("RCPT TO:<address> [NOTIFY=NEVER|NOTIFY=SUCCESS,FAILURE,DELAY] [ORCPT=<type>;<address>]\nAdds a recipient to the current mail transaction");
+    // See [RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.5.3.1.3) for the path
+    // and [RFC 3461](http://tools.ietf.org/html/rfc3461#section-4) for DSN.
+    command.max_path_size(256, get_path);
+    command.increase_max_line_size("DSN", 500);
+    command.middleware(check_greeted);
+    command.middleware(check_transaction);
     command.middleware(check_mailbox_format);
+    command.middleware(check_parameters);
+    command.middleware(check_recipient_count);
+    command.middleware(check_greylist);
     command.middleware(handle_receiver);
     command
 }
+
+#[test]
+fn test_max_recipients() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(get());
+    server.add_command(data::get());
+    let mut session = TestSession::new();
+
+    container.transaction.start(None);
+    for i in 0 .. 100 {
+        let line = format!("RCPT TO:<rcpt{}@rustastic.org>", i);
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line.as_ref());
+        assert_eq!("250 2.1.5 OK", session.reply());
+    }
+
+    // The client may keep trying, but the transaction is not affected.
+    for i in 100 .. 103 {
+        let line = format!("RCPT TO:<rcpt{}@rustastic.org>", i);
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line.as_ref());
+        assert_eq!("452 4.5.3 Too many recipients", session.reply());
+    }
+    assert!(container.transaction.is_started());
+    assert_eq!(100, container.transaction.recipients().len());
+
+    // The message is delivered to the recipients accepted before the 452.
+    session.send("Subject: hi");
+    session.send("");
+    session.send(".");
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+    assert_eq!("354 Start mail input; end with <CRLF>.<CRLF>", session.reply());
+    assert_eq!("250 2.0.0 OK", session.reply());
+    assert_eq!(Some(100), container.delivered_to);
+}
+
+#[test]
+fn test_dsn() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(get());
+    let mut session = TestSession::new();
+
+    container.transaction.start(None);
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<rust@rustastic.org> NOTIFY=FAILURE ORCPT=rfc822;rust@rustastic.org");
+    assert_eq!("250 2.1.5 OK", session.reply());
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<rust@rustastic.org> NOTIFY=ALWAYS");
+    assert!(session.reply().starts_with("501 5.5.4"));
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<rust@rustastic.org> X=Y");
+    assert_eq!("555 5.5.4 RCPT TO parameters not recognized or not implemented", session.reply());
+    assert_eq!(1, container.transaction.recipients().len());
+}
+
+#[test]
+fn test_subaddress() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(get());
+    let mut session = TestSession::new();
+    container.transaction.start(None);
+
+    // Without a policy, the separator is part of the local part.
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<rust+smtp@rustastic.org>");
+    assert_eq!("250 2.1.5 OK", session.reply());
+    assert_eq!(Some((Mailbox::parse("rust+smtp@rustastic.org").unwrap(), None)), container.receiver);
+
+    let mut policy = SubaddressPolicy::new('+');
+    server.set_subaddress_policy(policy.clone());
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<rust+smtp@rustastic.org>");
+    assert_eq!("250 2.1.5 OK", session.reply());
+    assert_eq!(Some((Mailbox::parse("rust+smtp@rustastic.org").unwrap(), Some("smtp".to_owned()))), container.receiver);
+
+    policy.route_on_base();
+    server.set_subaddress_policy(policy);
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<rust+smtp@rustastic.org>");
+    assert_eq!("250 2.1.5 OK", session.reply());
+    assert_eq!(Some((Mailbox::parse("rust@rustastic.org").unwrap(), Some("smtp".to_owned()))), container.receiver);
+
+    // The transaction keeps the full address for delivery.
+    assert_eq!(&Mailbox::parse("rust+smtp@rustastic.org").unwrap(), container.transaction.recipients().last().unwrap());
+}
+
+#[test]
+fn test_operator_addresses() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(get());
+    server.set_hostname("mx.rustastic.org").unwrap();
+    server.add_no_mail_domain("rustastic.org");
+    let mut session = TestSession::new();
+    container.transaction.start(None);
+    container.refuse_receivers = true;
+
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<Postmaster>");
+    assert_eq!("250 2.1.5 OK", session.reply());
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<postmaster@mx.rustastic.org>");
+    assert_eq!("250 2.1.5 OK", session.reply());
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<rust@mx.rustastic.org>");
+    assert_eq!("550 5.1.1 Mailbox not taken", session.reply());
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<postmaster@rustastic.org>");
+    assert_eq!("556 5.1.10 Domain does not accept mail", session.reply());
+    assert_eq!(vec![
+        Mailbox::parse("Postmaster@mx.rustastic.org").unwrap(),
+        Mailbox::parse("postmaster@mx.rustastic.org").unwrap()
+    ], container.transaction.recipients().to_vec());
+
+    // Refused operator addresses of the other domains go to the operator mailbox.
+    let mut operators = OperatorAddresses::new();
+    operators.add_domain("rustastic.org");
+    operators.accept_abuse();
+    operators.set_route(|_: &Mailbox| Mailbox::parse("ops@mx.rustastic.org").unwrap());
+    server.set_operator_addresses(operators);
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<postmaster@rustastic.org>");
+    assert_eq!("250 2.1.5 OK", session.reply());
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<abuse@rustastic.org>");
+    assert_eq!("250 2.1.5 OK", session.reply());
+    assert_eq!(&Mailbox::parse("ops@mx.rustastic.org").unwrap(), container.transaction.recipients().last().unwrap());
+    assert_eq!(4, container.transaction.recipients().len());
+}
+
+#[test]
+fn test_greylist() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(get());
+    server.set_hostname("mx.rustastic.org").unwrap();
+    let mut greylist = Greylist::new(MemoryGreylistStore::new());
+    greylist.set_delay(Duration::from_secs(3600));
+    server.set_greylist(greylist);
+    let mut session = TestSession::new();
+    container.transaction.start(None);
+
+    for _ in 0 .. 2 {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<rust@rustastic.org>");
+        assert!(session.reply().starts_with("450 4.7.1 Greylisted, try again in "));
+    }
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<postmaster@mx.rustastic.org>");
+    assert_eq!("250 2.1.5 OK", session.reply());
+
+    server.add_trusted_network("127.0.0.0".parse().unwrap(), 8);
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "RCPT TO:<rust@rustastic.org>");
+    assert_eq!("250 2.1.5 OK", session.reply());
+    assert_eq!(2, container.transaction.recipients().len());
+}
@@ -0,0 +1,79 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use super::super::ServerConfig;
+use super::super::super::common::stream::InputStream;
+use super::super::super::common::stream::OutputStream;
+use super::super::super::common::stream::SessionStream;
+use super::super::super::common::Reply;
+use super::super::super::common::status::EnhancedStatusCode;
+use super::super::{NextMiddleware, ArgumentPolicy};
+use super::super::Command;
+use super::super::session::SessionState;
+use super::TransactionState;
+#[cfg(test)]
+use super::{mail, rcpt};
+#[cfg(test)]
+use super::super::Server;
+#[cfg(test)]
+use super::super::testing::{TestSession, TestContainer};
+
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
+
+fn handle_reset<CT: TransactionState, ST: SessionStream>(_: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, _: &mut Input<ST>, output: &mut Output<ST>, _: &str, _: Next<CT, ST>) {
+    container.transaction().reset();
+    state.end_transaction();
+    output.write_reply(&Reply::enhanced(250, EnhancedStatusCode::new(2, 0, 0), "OK")).unwrap();
+}
+
+/// Returns the RSET command, as described
+/// [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.1.1.5).
+///
+/// It takes no argument, see `Server::set_strict_syntax` for what happens if the client
+/// gives one.
+pub fn get<CT: TransactionState + Clone + Send, ST: SessionStream>() -> Command<CT, ST> {
+    let mut command = Command::new();
+    command.starts_with("RSET");
+    command.help("RSET\nAborts the current mail transaction");
+    command.arguments(ArgumentPolicy::Forbidden);
+    command.middleware(handle_reset);
+    command
+}
+
+#[test]
+fn test_rset() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(mail::get());
+    server.add_command(rcpt::get());
+    server.add_command(get());
+    let mut session = TestSession::new();
+
+    for &(line, reply) in [("MAIL FROM:<a@rustastic.org>", "250 2.1.0 OK"), ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK"), ("RSET now", "250 2.0.0 OK")].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+    assert!(!container.transaction.is_started());
+    assert!(session.state.is_greeted());
+
+    // Strict servers reject arguments and trailing whitespace.
+    server.set_strict_syntax(true);
+    for &(line, reply) in [("RSET now", "501 5.5.4 Syntax error, RSET takes no argument"), ("RSET ", "501 5.5.4 Syntax error, RSET takes no argument"), ("RSET", "250 2.0.0 OK")].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+}
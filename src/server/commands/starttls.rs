@@ -0,0 +1,180 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use std::borrow::ToOwned;
+#[cfg(test)]
+use std::io::Result as IoResult;
+#[cfg(test)]
+use std::net::TcpStream;
+use super::super::{ServerConfig, SessionError};
+use super::super::super::common::stream::InputStream;
+use super::super::super::common::stream::OutputStream;
+use super::super::super::common::stream::{Transport, is_timeout};
+use super::super::super::common::instrument::InstrumentedStream;
+use super::super::super::common::Reply;
+use super::super::super::common::status::EnhancedStatusCode;
+use super::super::NextMiddleware;
+use super::super::Command;
+use super::super::session::SessionState;
+use super::TransactionState;
+#[cfg(test)]
+use super::super::Server;
+#[cfg(test)]
+use super::super::testing::{TestSession, TestContainer};
+#[cfg(test)]
+use super::super::super::common::tls::{TlsAcceptor, TlsOptions, TlsStream, ConnectionInfo};
+
+type Next<CT> = Option<NextMiddleware<CT, Transport>>;
+type Input = InputStream<Transport>;
+type Output = OutputStream<Transport>;
+
+fn check_argument<CT>(config: &ServerConfig<CT>, container: &mut CT, state: &mut SessionState, input: &mut Input, output: &mut Output, line: &str, next: Next<CT>) {
+    match line.len() == 0 {
+        false => {
+            output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, STARTTLS takes no argument")).unwrap();
+        },
+        true => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+fn check_state<CT>(config: &ServerConfig<CT>, container: &mut CT, state: &mut SessionState, input: &mut Input, output: &mut Output, line: &str, next: Next<CT>) {
+    if input.get_ref().is_tls() {
+        output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, TLS already active")).unwrap();
+    } else if config.tls.is_none() {
+        output.write_reply(&Reply::enhanced(454, EnhancedStatusCode::new(4, 7, 0), "TLS not available due to temporary reason")).unwrap();
+    } else {
+        next.unwrap().call(config, container, state, input, output, line);
+    }
+}
+
+fn start_tls<CT: TransactionState>(config: &ServerConfig<CT>, container: &mut CT, state: &mut SessionState, input: &mut Input, output: &mut Output, _: &str, _: Next<CT>) {
+    output.write_reply(&Reply::enhanced(220, EnhancedStatusCode::new(2, 0, 0), "Ready to start TLS")).unwrap();
+    if let Err(err) = output.flush() {
+        state.set_failed(SessionError::Write(err));
+        return;
+    }
+
+    let res = match *output.get_ref() {
+        Transport::Tcp(ref stream) => stream.try_clone().and_then(|socket| {
+            stream.try_clone().map(|stream| (stream, socket))
+        }),
+        Transport::Tls(..) => unreachable!()
+    };
+    let options = &config.tls_options;
+    let res = res.and_then(|(stream, socket)| {
+        try!(socket.set_read_timeout(options.handshake_timeout));
+        try!(socket.set_write_timeout(options.handshake_timeout));
+        let (tls, info) = try!(config.tls.as_ref().unwrap().accept_with(stream, options));
+        try!(socket.set_write_timeout(None));
+        Ok((tls, info, socket))
+    });
+    match res {
+        Ok((tls, info, socket)) => {
+            // Both halves of the session now go through the same TLS stream. The plain
+            // text streams are dropped, the socket stays open through the TLS stream.
+            let tls = Arc::new(Mutex::new(InstrumentedStream::with_stats(tls, output.get_ref().stats())));
+            let socket = Arc::new(socket);
+            input.replace_stream(Transport::Tls(tls.clone(), socket.clone()));
+            output.replace_stream(Transport::Tls(tls, socket));
+
+            if let Some(ref metrics) = config.metrics {
+                metrics.increment("tls_handshakes");
+                if info.resumed {
+                    metrics.increment("tls_resumptions");
+                }
+            }
+
+            // Forget everything we learned from the client before the handshake.
+            state.reset();
+            state.set_tls(info);
+            container.transaction().reset();
+        },
+        Err(ref err) if is_timeout(err) => {
+            // The client can't read a reply anymore, just end the session.
+            state.set_timed_out();
+        },
+        Err(err) => {
+            // We can't tell what state the connection is in, so give up on it.
+            state.set_failed(SessionError::Setup(err));
+        }
+    }
+}
+
+/// Returns the STARTTLS command, as described
+/// [in RFC 3207](http://tools.ietf.org/html/rfc3207).
+///
+/// The server needs a `TlsAcceptor` to perform the handshake, see
+/// `Server::set_tls_acceptor`, and its options, see `Server::set_tls_options`. Once the
+/// handshake is done, the session starts over and the client must send EHLO again. What
+/// the handshake negotiated is kept in `SessionState::tls`.
+pub fn get<CT: TransactionState + Clone + Send>() -> Command<CT, Transport> {
+    let mut command = Command::new();
+    command.starts_with("STARTTLS");
+    command.help("STARTTLS\nEncrypts the rest of the session with TLS");
+    command.extension("STARTTLS");
+    // Anything pipelined after STARTTLS would be read as if it came through TLS.
+    command.last_in_group();
+    command.middleware(check_argument);
+    command.middleware(check_state);
+    command.middleware(start_tls);
+    command
+}
+
+// Pretends to resume a session when the client asked for ALPN, keeping the stream as is.
+#[cfg(test)]
+struct PlainAcceptor;
+
+#[cfg(test)]
+impl TlsAcceptor for PlainAcceptor {
+    fn accept(&self, stream: TcpStream) -> IoResult<Box<TlsStream>> {
+        Ok(Box::new(stream))
+    }
+
+    fn accept_with(&self, stream: TcpStream, options: &TlsOptions) -> IoResult<(Box<TlsStream>, ConnectionInfo)> {
+        let mut info = ConnectionInfo::new();
+        info.alpn = options.alpn.first().cloned();
+        info.resumed = options.resumption;
+        Ok((Box::new(stream), info))
+    }
+}
+
+#[test]
+fn test_start_tls() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(get());
+    let mut session = TestSession::new();
+
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "STARTTLS");
+    assert_eq!("454 4.7.0 TLS not available due to temporary reason", session.reply());
+
+    server.set_tls_acceptor(PlainAcceptor);
+    let mut options = TlsOptions::new();
+    options.alpn.push("smtp".to_owned());
+    server.set_tls_options(options);
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "STARTTLS");
+    assert_eq!("220 2.0.0 Ready to start TLS", session.reply());
+    assert!(session.input.get_ref().is_tls());
+    assert!(!session.state.is_greeted());
+    let info = session.state.tls().unwrap();
+    assert!(info.resumed);
+    assert_eq!(Some("smtp".to_owned()), info.alpn);
+
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "STARTTLS");
+    assert_eq!("503 5.5.1 Bad sequence of commands, TLS already active", session.reply());
+}
@@ -0,0 +1,222 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use std::error::Error;
+use super::super::{ServerConfig, SessionError};
+use super::super::super::common::stream::InputStream;
+use super::super::super::common::stream::OutputStream;
+use super::super::super::common::stream::SessionStream;
+use super::super::super::common::Reply;
+use super::super::super::common::status::EnhancedStatusCode;
+use super::super::super::common::stream::{DATA_TOO_LONG, is_timeout};
+use super::super::super::common::lz::{decompress, DecompressError, EXTENSION};
+use super::super::NextMiddleware;
+use super::super::transaction::abort_transaction;
+use super::super::Command;
+use super::super::session::{SessionState, Phase};
+use super::TransactionState;
+use super::DataHandler;
+use super::data::{spill_data, check_encoding, check_controls, check_headers, check_filters, check_duplicate, handle_data};
+#[cfg(test)]
+use super::super::super::common::lz::compress;
+#[cfg(test)]
+use super::{mail, rcpt};
+#[cfg(test)]
+use super::super::Server;
+#[cfg(test)]
+use super::super::testing::{TestSession, TestContainer};
+
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
+
+// Compressed data can be a little larger than the message, when it doesn't compress.
+fn max_compressed_size(size: usize) -> usize {
+    size + size / 128 + 1
+}
+
+/// Parses the argument of XZDAT, ie `120 1000`, into the size of the compressed data and
+/// the size of the message.
+fn parse_argument(line: &str) -> Option<(usize, usize)> {
+    let parse_size = |s: &str| match s.len() > 0 && s.chars().all(|c| c >= '0' && c <= '9') {
+        true => s.parse::<usize>().ok(),
+        false => None
+    };
+    let mut parts = line.split(' ');
+    let compressed = match parts.next().and_then(&parse_size) {
+        Some(compressed) => compressed,
+        None => return None
+    };
+    let size = match parts.next().and_then(&parse_size) {
+        Some(size) => size,
+        None => return None
+    };
+    match parts.next() {
+        None => Some((compressed, size)),
+        Some(_) => None
+    }
+}
+
+#[test]
+fn test_parse_argument() {
+    assert_eq!(Some((120, 1000)), parse_argument("120 1000"));
+    assert_eq!(Some((0, 0)), parse_argument("0 0"));
+    assert_eq!(None, parse_argument(""));
+    assert_eq!(None, parse_argument("120"));
+    assert_eq!(None, parse_argument("120 1000 LAST"));
+    assert_eq!(None, parse_argument("120  1000"));
+    assert_eq!(None, parse_argument("+120 1000"));
+    assert_eq!(None, parse_argument("120 99999999999999999999999"));
+}
+
+fn check_argument<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    match parse_argument(line) {
+        None => {
+            output.write_reply(&Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Syntax error, format: 'XZDAT <compressed size> <size>'")).unwrap();
+        },
+        Some(_) => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+fn read_compressed<CT: TransactionState, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let (compressed, size) = parse_argument(line).unwrap();
+
+    // Like BDAT, the data must be read even if we reject it.
+    let max_size = config.max_message_size - container.transaction().data().len();
+    if let Err(err) = input.get_ref().set_read_timeout(Some(config.data_timeout)) {
+        state.set_failed(SessionError::Setup(err));
+        return;
+    }
+    let data = match input.read_bytes(compressed, max_compressed_size(max_size)) {
+        Ok(data) => Some(data),
+        Err(ref err) if err.description() == DATA_TOO_LONG => None,
+        Err(ref err) if is_timeout(err) => {
+            state.set_timed_out();
+            return;
+        },
+        Err(err) => {
+            state.set_failed(SessionError::Read(err));
+            return;
+        }
+    };
+
+    if !state.is_greeted() {
+        output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, HELO/EHLO first")).unwrap();
+        return;
+    }
+    if container.transaction().recipients().len() == 0 {
+        output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, RCPT first")).unwrap();
+        return;
+    }
+
+    let data = match data {
+        Some(data) => data,
+        None => {
+            output.write_reply(&Reply::enhanced(552, EnhancedStatusCode::new(5, 3, 4), "Message exceeds fixed maximum message size")).unwrap();
+            return;
+        }
+    };
+    if size > max_size {
+        output.write_reply(&Reply::enhanced(552, EnhancedStatusCode::new(5, 3, 4), "Message exceeds fixed maximum message size")).unwrap();
+        return;
+    }
+    match decompress(data.as_ref(), size) {
+        Ok(ref message) if message.len() == size => {
+            state.set_phase(Phase::Data);
+            if container.transaction().append_data(message.as_ref()).and_then(|_| spill_data(config, container)).is_err() {
+                output.write_reply(&Reply::enhanced(451, EnhancedStatusCode::new(4, 3, 0), "Requested action aborted: local error in processing")).unwrap();
+                return;
+            }
+            next.unwrap().call(config, container, state, input, output, line);
+        },
+        Ok(_) | Err(DecompressError::TooLarge) => {
+            output.write_reply(&Reply::enhanced(554, EnhancedStatusCode::new(5, 6, 0), "Message size does not match the declared size")).unwrap();
+        },
+        Err(_) => {
+            output.write_reply(&Reply::enhanced(554, EnhancedStatusCode::new(5, 6, 0), "Invalid compressed data")).unwrap();
+        }
+    }
+}
+
+/// Returns the XZDAT command, a private extension to send compressed messages between
+/// instances of this crate.
+///
+/// It works like `BDAT <size> LAST`, except that the client sends
+/// `XZDAT <compressed size> <size>` followed by the message compressed with
+/// `common::lz::compress`. The message then goes through the same checks as with DATA.
+///
+/// The extension is only advertised once this command is added to a server, and clients
+/// must not use it unless the reply to EHLO has `XZDAT`.
+pub fn get<CT: TransactionState + DataHandler + Clone + Send, ST: SessionStream>() -> Command<CT, ST> {
+    let mut command = Command::new();
+    command.starts_with("XZDAT ");
+    command.help("XZDAT <compressed size> <size>\nSends the whole message, compressed (private extension)");
+    command.extension(EXTENSION);
+    command.middleware(check_argument);
+    command.middleware(read_compressed);
+    command.middleware(check_encoding);
+    command.middleware(check_controls);
+    command.middleware(check_headers);
+    command.middleware(check_filters);
+    command.middleware(check_duplicate);
+    command.middleware(handle_data);
+    command.on_failure(abort_transaction);
+    command
+}
+
+#[test]
+fn test_xzdat() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(mail::get());
+    server.add_command(rcpt::get());
+    server.add_command(get());
+    let mut session = TestSession::new();
+    let message = b"Subject: Hello\r\n\r\nHello, hello, hello, hello!\r\n".to_vec();
+    let compressed = compress(message.as_ref());
+
+    for &(line, reply) in [("MAIL FROM:<a@rustastic.org>", "250 2.1.0 OK"), ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK")].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+    session.send_bytes(compressed.as_ref());
+    let line = format!("XZDAT {} {}", compressed.len(), message.len());
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line.as_ref());
+    assert_eq!("250 2.0.0 OK", session.reply());
+    assert_eq!(Some(message.clone()), container.data);
+
+    // The declared size must match.
+    for &(line, reply) in [("MAIL FROM:<a@rustastic.org>", "250 2.1.0 OK"), ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK")].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+    session.send_bytes(compressed.as_ref());
+    let line = format!("XZDAT {} {}", compressed.len(), message.len() - 1);
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line.as_ref());
+    assert_eq!("554 5.6.0 Message size does not match the declared size", session.reply());
+    assert!(!container.transaction.is_started());
+
+    // Invalid data is rejected, and doesn't desynchronize the session.
+    for &(line, reply) in [("MAIL FROM:<a@rustastic.org>", "250 2.1.0 OK"), ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK")].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+        assert_eq!(reply, session.reply());
+    }
+    session.send_bytes(&[0x80, 0, 1]);
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "XZDAT 3 3");
+    assert_eq!("554 5.6.0 Invalid compressed data", session.reply());
+    assert!(!container.transaction.is_started());
+}
@@ -0,0 +1,350 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server configurations written as TOML files, see `ServerConfig::from_toml_file`.
+//!
+//! Every setting is optional, and the ones that are missing keep their default:
+//!
+//! ```toml
+//! hostname = "mx.rustastic.org"
+//! listen = ["0.0.0.0:25", "[::]:25"]
+//! extensions = ["SIZE", "SMTPUTF8"]
+//!
+//! [limits]
+//! # Allows limits below the minimums required by the RFC.
+//! allow_non_conforming = false
+//! max_recipients = 100
+//! max_message_size = 10485760
+//! max_header_section_size = 65536
+//! max_header_count = 1000
+//! max_header_field_size = 16384
+//! max_errors_per_session = 10
+//! max_connections = 1000
+//! max_connections_per_ip = 20
+//!
+//! # In seconds.
+//! [timeouts]
+//! command = 300
+//! data = 600
+//!
+//! [tls]
+//! certificate = "/etc/ssl/certs/mx.rustastic.org.pem"
+//! key = "/etc/ssl/private/mx.rustastic.org.key"
+//! ```
+//!
+//! Commands are code, so they are still added to the server with `Server::add_command`,
+//! which also advertises the extensions they implement, ie `STARTTLS` or `CHUNKING`. The
+//! `extensions` setting only takes the ones the server implements without a command of
+//! their own, `SIZE` and `SMTPUTF8`, so EHLO never advertises a command that is missing.
+//! Unknown settings are errors, so that typos don't go unnoticed.
+
+use std::fs::File;
+use std::io::Read;
+use std::io::Error as IoError;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use std::vec::Vec;
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use super::{ServerConfig, ConfigError};
+use super::super::common::toml::{Toml, TomlError};
+#[cfg(test)]
+use std::env;
+#[cfg(test)]
+use std::fs;
+#[cfg(test)]
+use std::io::Write;
+#[cfg(test)]
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+#[cfg(test)]
+use super::Server;
+#[cfg(test)]
+use super::testing::TestContainer;
+
+/// An error that occured while loading a configuration file.
+#[derive(Debug)]
+pub enum ConfigFileError {
+    /// The file could not be read.
+    Io(IoError),
+    /// The file isn't valid TOML, or uses TOML that `common::toml` doesn't support.
+    Syntax(TomlError),
+    /// A setting is unknown, missing, or its value has the wrong type. Gives the setting,
+    /// ie `limits.max_recipients`.
+    Invalid(String),
+    /// A setting is out of range. Gives the setting and why.
+    Limit(String, ConfigError)
+}
+
+fn invalid<T>(name: &str) -> Result<T, ConfigFileError> {
+    Err(ConfigFileError::Invalid(name.to_owned()))
+}
+
+// Fails on the first key that isn't one of the known ones, giving it prefixed with the
+// name of its table.
+fn check_keys(toml: &Toml, prefix: &str, keys: &[&str]) -> Result<(), ConfigFileError> {
+    for &(ref key, _) in toml.as_table().unwrap_or(&[]).iter() {
+        if !keys.contains(&key.as_ref()) {
+            return invalid(format!("{}{}", prefix, key).as_ref());
+        }
+    }
+    Ok(())
+}
+
+// Returns the table with the given name, after checking that it only has known keys.
+fn table<'a>(toml: &'a Toml, name: &str, keys: &[&str]) -> Result<Option<&'a Toml>, ConfigFileError> {
+    match toml.get(name) {
+        Some(table @ &Toml::Table(_)) => {
+            try!(check_keys(table, format!("{}.", name).as_ref(), keys));
+            Ok(Some(table))
+        },
+        Some(_) => invalid(name),
+        None => Ok(None)
+    }
+}
+
+fn string<'a>(value: &'a Toml, name: &str) -> Result<&'a str, ConfigFileError> {
+    match value.as_str() {
+        Some(s) => Ok(s),
+        None => invalid(name)
+    }
+}
+
+fn strings<'a>(value: &'a Toml, name: &str) -> Result<Vec<&'a str>, ConfigFileError> {
+    let elements = match value.as_array() {
+        Some(elements) => elements,
+        None => return invalid(name)
+    };
+    let mut strings = Vec::with_capacity(elements.len());
+    for element in elements.iter() {
+        strings.push(try!(string(element, name)));
+    }
+    Ok(strings)
+}
+
+// The extensions the server implements without a command of their own, in `MAIL`.
+static CONFIGURABLE_EXTENSIONS: &'static [&'static str] = &["SIZE", "SMTPUTF8"];
+
+fn size(value: &Toml, name: &str) -> Result<usize, ConfigFileError> {
+    match value.as_integer() {
+        Some(n) if n >= 0 => Ok(n as usize),
+        _ => invalid(name)
+    }
+}
+
+impl<CT, ST> ServerConfig<CT, ST> {
+    /// Loads a configuration from a TOML file, see `server::config` for the settings.
+    ///
+    /// The configuration is then given to `Server::with_config`.
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<ServerConfig<CT, ST>, ConfigFileError> {
+        let mut text = String::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut text)).map_err(ConfigFileError::Io));
+        ServerConfig::from_toml(text.as_ref())
+    }
+
+    /// Loads a configuration from TOML text, see `from_toml_file`.
+    pub fn from_toml(text: &str) -> Result<ServerConfig<CT, ST>, ConfigFileError> {
+        let toml = try!(Toml::parse(text).map_err(ConfigFileError::Syntax));
+        try!(check_keys(&toml, "", &["hostname", "listen", "extensions", "limits", "timeouts", "tls"]));
+        let mut config = ServerConfig::new();
+
+        if let Some(hostname) = toml.get("hostname") {
+            try!(config.set_hostname(try!(string(hostname, "hostname"))).or_else(|_| invalid("hostname")));
+        }
+        if let Some(listen) = toml.get("listen") {
+            for address in try!(strings(listen, "listen")).iter() {
+                match address.parse::<SocketAddr>() {
+                    Ok(address) => config.add_address(address.ip(), address.port()),
+                    Err(_) => return invalid("listen")
+                }
+            }
+        }
+        if let Some(extensions) = toml.get("extensions") {
+            for extension in try!(strings(extensions, "extensions")).iter() {
+                let keyword = extension.split(' ').next().unwrap_or("");
+                if !CONFIGURABLE_EXTENSIONS.iter().any(|known| known.eq_ignore_ascii_case(keyword)) {
+                    return invalid("extensions");
+                }
+                config.add_extension(extension);
+            }
+        }
+        if let Some(limits) = try!(table(&toml, "limits", &[
+            "allow_non_conforming", "max_recipients", "max_message_size",
+            "max_header_section_size", "max_header_count", "max_header_field_size",
+            "max_errors_per_session", "max_connections", "max_connections_per_ip"
+        ])) {
+            try!(config.load_limits(limits));
+        }
+        if let Some(timeouts) = try!(table(&toml, "timeouts", &["command", "data"])) {
+            try!(config.load_timeouts(timeouts));
+        }
+        if let Some(tls) = try!(table(&toml, "tls", &["certificate", "key"])) {
+            // A certificate is no use without its key.
+            let certificate = match tls.get("certificate").and_then(|value| value.as_str()) {
+                Some(certificate) => certificate,
+                None => return invalid("tls.certificate")
+            };
+            let key = match tls.get("key").and_then(|value| value.as_str()) {
+                Some(key) => key,
+                None => return invalid("tls.key")
+            };
+            config.set_tls_files(certificate, key);
+        }
+        Ok(config)
+    }
+
+    fn load_limits(&mut self, limits: &Toml) -> Result<(), ConfigFileError> {
+        // This must come first, as it changes how the other limits are checked.
+        if let Some(allow) = limits.get("allow_non_conforming") {
+            match allow.as_bool() {
+                Some(true) => self.allow_non_conforming_limits(),
+                Some(false) => {},
+                None => return invalid("limits.allow_non_conforming")
+            }
+        }
+        try!(self.load_size(limits, "limits.max_recipients", ServerConfig::set_max_recipients));
+        try!(self.load_size(limits, "limits.max_message_size", ServerConfig::set_max_message_size));
+        try!(self.load_size(limits, "limits.max_header_section_size", ServerConfig::set_max_header_section_size));
+        try!(self.load_size(limits, "limits.max_header_count", ServerConfig::set_max_header_count));
+        try!(self.load_size(limits, "limits.max_header_field_size", ServerConfig::set_max_header_field_size));
+        try!(self.load_size(limits, "limits.max_errors_per_session", ServerConfig::set_max_errors_per_session));
+        try!(self.load_size(limits, "limits.max_connections", ServerConfig::set_max_connections));
+        self.load_size(limits, "limits.max_connections_per_ip", ServerConfig::set_max_connections_per_ip)
+    }
+
+    fn load_timeouts(&mut self, timeouts: &Toml) -> Result<(), ConfigFileError> {
+        try!(self.load_size(timeouts, "timeouts.command", |config, seconds| {
+            config.set_command_timeout(Duration::from_secs(seconds as u64))
+        }));
+        self.load_size(timeouts, "timeouts.data", |config, seconds| {
+            config.set_data_timeout(Duration::from_secs(seconds as u64))
+        })
+    }
+
+    // Gives the size named `name`, ie `limits.max_recipients`, to the setter, if the table
+    // has it.
+    fn load_size<F>(&mut self, table: &Toml, name: &str, set: F) -> Result<(), ConfigFileError>
+            where F: FnOnce(&mut ServerConfig<CT, ST>, usize) -> Result<(), ConfigError> {
+        let key = name.split('.').last().unwrap();
+        match table.get(key) {
+            Some(value) => {
+                let value = try!(size(value, name));
+                set(self, value).map_err(|err| ConfigFileError::Limit(name.to_owned(), err))
+            },
+            None => Ok(())
+        }
+    }
+}
+
+#[test]
+fn test_from_toml() {
+    let config: ServerConfig<TestContainer> = ServerConfig::from_toml("
+        hostname = 'rustastic.org'
+        listen = ['0.0.0.0:25', '[::1]:587']
+        extensions = ['SMTPUTF8', 'SIZE 1000']
+
+        [limits]
+        max_recipients = 10
+        allow_non_conforming = true
+        max_message_size = 1000
+        max_header_count = 50
+        max_connections_per_ip = 5
+
+        [timeouts]
+        data = 900
+
+        [tls]
+        certificate = '/etc/ssl/rustastic.pem'
+        key = '/etc/ssl/rustastic.key'
+    ").unwrap();
+    assert_eq!("rustastic.org", config.hostname);
+    assert_eq!(vec![
+        (IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 25),
+        (IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 587)
+    ], config.addresses);
+    assert_eq!(vec!["SMTPUTF8", "SIZE 1000"], config.extensions);
+    assert_eq!(10, config.max_recipients);
+    assert_eq!(1000, config.max_message_size);
+    assert_eq!(50, config.header_limits.max_count);
+    assert_eq!(16384, config.header_limits.max_field_size);
+    assert_eq!(Some(5), config.max_connections_per_ip);
+    assert_eq!(None, config.max_connections);
+    assert_eq!(Duration::from_secs(300), config.command_timeout);
+    assert_eq!(Duration::from_secs(900), config.data_timeout);
+    assert_eq!(Some((Path::new("/etc/ssl/rustastic.pem"), Path::new("/etc/ssl/rustastic.key"))), config.tls_files());
+
+    let server = Server::with_config(TestContainer::new(), config);
+    assert_eq!("rustastic.org", server.config.hostname);
+
+    let load = |text: &str| ServerConfig::<TestContainer>::from_toml(text).err().unwrap();
+    match load("hostname = 'a'\nhostname = 'b'") {
+        ConfigFileError::Syntax(TomlError::DuplicateKey(2)) => {},
+        err => panic!("{:?}", err)
+    }
+    match load("[limits]\nmax_recipient = 100") {
+        ConfigFileError::Invalid(ref name) if name == "limits.max_recipient" => {},
+        err => panic!("{:?}", err)
+    }
+    match load("hostnme = 'rustastic.org'") {
+        ConfigFileError::Invalid(ref name) if name == "hostnme" => {},
+        err => panic!("{:?}", err)
+    }
+    match load("hostname = 'rustastic org'") {
+        ConfigFileError::Invalid(ref name) if name == "hostname" => {},
+        err => panic!("{:?}", err)
+    }
+    // Commands advertise their own extensions.
+    match load("extensions = ['size', 'STARTTLS']") {
+        ConfigFileError::Invalid(ref name) if name == "extensions" => {},
+        err => panic!("{:?}", err)
+    }
+    match load("listen = ['rustastic.org:25']") {
+        ConfigFileError::Invalid(ref name) if name == "listen" => {},
+        err => panic!("{:?}", err)
+    }
+    match load("[limits]\nmax_message_size = '10M'") {
+        ConfigFileError::Invalid(ref name) if name == "limits.max_message_size" => {},
+        err => panic!("{:?}", err)
+    }
+    match load("[limits]\nmax_recipients = 10") {
+        ConfigFileError::Limit(ref name, ConfigError::BelowMinimum(100)) if name == "limits.max_recipients" => {},
+        err => panic!("{:?}", err)
+    }
+    match load("[limits]\nmax_connections = 0") {
+        ConfigFileError::Limit(ref name, ConfigError::Zero) if name == "limits.max_connections" => {},
+        err => panic!("{:?}", err)
+    }
+    match load("[timeouts]\ncommand = 60") {
+        ConfigFileError::Limit(ref name, ConfigError::BelowMinimum(_)) if name == "timeouts.command" => {},
+        err => panic!("{:?}", err)
+    }
+    match load("[tls]\ncertificate = '/etc/ssl/rustastic.pem'") {
+        ConfigFileError::Invalid(ref name) if name == "tls.key" => {},
+        err => panic!("{:?}", err)
+    }
+}
+
+#[test]
+fn test_from_toml_file() {
+    let path = env::temp_dir().join("rsmtp-test-config.toml");
+    fs::File::create(&path).unwrap().write_all(b"hostname = \"rustastic.org\"\n").unwrap();
+    let config = ServerConfig::<TestContainer>::from_toml_file(&path).unwrap();
+    assert_eq!("rustastic.org", config.hostname);
+    fs::remove_file(&path).unwrap();
+
+    match ServerConfig::<TestContainer>::from_toml_file(&path) {
+        Err(ConfigFileError::Io(_)) => {},
+        _ => panic!()
+    }
+}
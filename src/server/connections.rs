@@ -0,0 +1,144 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Counting of concurrent connections, to enforce `Server::set_max_connections` and
+//! `Server::set_max_connections_per_ip`.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use std::net::Ipv4Addr;
+
+/// Why a connection was refused.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum LimitExceeded {
+    /// The server has as many connections as it allows.
+    Global,
+    /// The client address has as many connections as it allows.
+    PerIp
+}
+
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>
+}
+
+/// The connections open on a server, shared by all of them.
+pub struct ConnectionCounter {
+    counts: Mutex<Counts>
+}
+
+impl ConnectionCounter {
+    /// Creates a counter with no connection.
+    pub fn new() -> ConnectionCounter {
+        ConnectionCounter {
+            counts: Mutex::new(Counts {
+                total: 0,
+                per_ip: HashMap::new()
+            })
+        }
+    }
+
+    /// Counts a new connection from the address, unless it would exceed a limit.
+    ///
+    /// A connection without an address, ie over a Unix socket, only counts towards `max`.
+    /// The connection is counted until the returned permit is dropped.
+    pub fn acquire(counter: &Arc<ConnectionCounter>, ip: Option<IpAddr>, max: Option<usize>, max_per_ip: Option<usize>) -> Result<ConnectionPermit, LimitExceeded> {
+        let mut counts = counter.counts.lock().unwrap();
+        if max.map_or(false, |max| counts.total >= max) {
+            return Err(LimitExceeded::Global);
+        }
+        if let Some(ip) = ip {
+            let from_ip = counts.per_ip.get(&ip).cloned().unwrap_or(0);
+            if max_per_ip.map_or(false, |max| from_ip >= max) {
+                return Err(LimitExceeded::PerIp);
+            }
+            counts.per_ip.insert(ip, from_ip + 1);
+        }
+        counts.total += 1;
+        Ok(ConnectionPermit {
+            counter: counter.clone(),
+            ip: ip
+        })
+    }
+
+    /// Returns the number of open connections.
+    pub fn total(&self) -> usize {
+        self.counts.lock().unwrap().total
+    }
+
+    /// Returns the number of open connections from an address.
+    pub fn from_ip(&self, ip: &IpAddr) -> usize {
+        self.counts.lock().unwrap().per_ip.get(ip).cloned().unwrap_or(0)
+    }
+}
+
+/// A connection counted by a `ConnectionCounter`, until it is dropped.
+pub struct ConnectionPermit {
+    counter: Arc<ConnectionCounter>,
+    ip: Option<IpAddr>
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.counter.counts.lock().unwrap();
+        counts.total -= 1;
+        let ip = match self.ip {
+            Some(ip) => ip,
+            None => return
+        };
+        let remove = match counts.per_ip.get_mut(&ip) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            },
+            None => false
+        };
+        // Forget addresses without connections, so the map doesn't grow forever.
+        if remove {
+            counts.per_ip.remove(&ip);
+        }
+    }
+}
+
+#[test]
+fn test_connection_counter() {
+    let counter = Arc::new(ConnectionCounter::new());
+    let a = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    let b = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    let first = ConnectionCounter::acquire(&counter, Some(a), Some(3), Some(2)).unwrap();
+    let second = ConnectionCounter::acquire(&counter, Some(a), Some(3), Some(2)).unwrap();
+    assert_eq!(Err(LimitExceeded::PerIp), ConnectionCounter::acquire(&counter, Some(a), Some(3), Some(2)).map(|_| ()));
+    let third = ConnectionCounter::acquire(&counter, Some(b), Some(3), Some(2)).unwrap();
+    assert_eq!(Err(LimitExceeded::Global), ConnectionCounter::acquire(&counter, Some(b), Some(3), Some(2)).map(|_| ()));
+    assert_eq!(3, counter.total());
+    assert_eq!(2, counter.from_ip(&a));
+
+    drop(first);
+    drop(third);
+    assert_eq!(1, counter.total());
+    assert_eq!(0, counter.from_ip(&b));
+    assert!(ConnectionCounter::acquire(&counter, Some(a), None, None).is_ok());
+
+    let local = ConnectionCounter::acquire(&counter, None, Some(2), Some(1)).unwrap();
+    assert_eq!(2, counter.total());
+    assert_eq!(1, counter.from_ip(&a));
+    assert_eq!(Err(LimitExceeded::Global), ConnectionCounter::acquire(&counter, None, Some(2), Some(1)).map(|_| ()));
+    drop(local);
+    drop(second);
+    assert_eq!(0, counter.total());
+}
@@ -0,0 +1,144 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Detection of messages submitted more than once, for example by clients that retry
+//! a transaction because they did not see our reply to the end of DATA.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::ascii::AsciiExt;
+use std::vec::Vec;
+use super::super::common::mailbox::Mailbox;
+use super::super::common::message::header_fields;
+
+/// What to do with a message that was already accepted within the window.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum DuplicateAction {
+    /// Reply with success, but don't hand the message to the container.
+    Drop,
+    /// Reply with `554`.
+    Reject,
+    /// Hand the message to the container as usual.
+    Deliver
+}
+
+// Message-ID, sender and sorted recipients.
+type DuplicateKey = (Vec<u8>, Option<Mailbox>, Vec<Mailbox>);
+
+/// Remembers recently accepted messages, shared by all connections of a server.
+///
+/// Two messages are duplicates if they have the same Message-ID, sender and recipients.
+/// Messages without a Message-ID are never considered duplicates.
+pub struct DuplicateWindow {
+    window: Duration,
+    action: DuplicateAction,
+    seen: Mutex<HashMap<DuplicateKey, Instant>>
+}
+
+fn get_key(message_id: &[u8], sender: Option<&Mailbox>, recipients: &[Mailbox]) -> DuplicateKey {
+    let mut recipients = recipients.to_vec();
+    recipients.sort();
+    (message_id.to_vec(), sender.cloned(), recipients)
+}
+
+impl DuplicateWindow {
+    /// Creates a window remembering messages for the given duration.
+    pub fn new(window: Duration, action: DuplicateAction) -> DuplicateWindow {
+        DuplicateWindow {
+            window: window,
+            action: action,
+            seen: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Returns the action to take for duplicates.
+    pub fn action(&self) -> DuplicateAction {
+        self.action
+    }
+
+    /// Tells whether a message was accepted within the window.
+    pub fn is_duplicate(&self, message_id: &[u8], sender: Option<&Mailbox>, recipients: &[Mailbox]) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        let window = self.window;
+        seen.retain(|_, accepted| accepted.elapsed() < window);
+        seen.contains_key(&get_key(message_id, sender, recipients))
+    }
+
+    /// Remembers a message that was accepted.
+    pub fn insert(&self, message_id: &[u8], sender: Option<&Mailbox>, recipients: &[Mailbox]) {
+        let mut seen = self.seen.lock().unwrap();
+        seen.insert(get_key(message_id, sender, recipients), Instant::now());
+    }
+}
+
+fn is_wsp(c: u8) -> bool {
+    c == b' ' || c == b'\t' || c == b'\r' || c == b'\n'
+}
+
+/// Returns the value of the Message-ID header of a message, without surrounding whitespace.
+///
+/// Only the header section is searched, and folded header fields are supported as described
+/// [in RFC 5322](http://tools.ietf.org/html/rfc5322#section-2.2.3).
+pub fn get_message_id(data: &[u8]) -> Option<&[u8]> {
+    for field in header_fields(data) {
+        if field.len() >= 11 && field[.. 11].eq_ignore_ascii_case(b"message-id:") {
+            let mut value = &field[11 ..];
+            while value.len() > 0 && is_wsp(value[0]) {
+                value = &value[1 ..];
+            }
+            while value.len() > 0 && is_wsp(value[value.len() - 1]) {
+                value = &value[.. value.len() - 1];
+            }
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[test]
+fn test_get_message_id() {
+    assert_eq!(Some(&b"<1@rust>"[..]), get_message_id(b"Message-ID: <1@rust>\r\n\r\nhello\r\n"));
+    assert_eq!(Some(&b"<1@rust>"[..]), get_message_id(b"Subject: hi\r\nmessage-id:<1@rust>  \r\n\r\n"));
+    assert_eq!(Some(&b"<1@rust>"[..]), get_message_id(b"Message-Id:\r\n <1@rust>\r\n"));
+    assert_eq!(Some(&b"<1@rust>"[..]), get_message_id(b"Message-Id: <1@rust>"));
+
+    // The body is not part of the headers.
+    assert_eq!(None, get_message_id(b"Subject: hi\r\n\r\nMessage-ID: <1@rust>\r\n"));
+    assert_eq!(None, get_message_id(b"Subject: hi\r\n X-Message-ID: <1@rust>\r\n"));
+    assert_eq!(None, get_message_id(b""));
+}
+
+#[test]
+fn test_duplicate_window() {
+    let sender = Mailbox::parse("rust@rustastic.org").unwrap();
+    let rcpt_1 = Mailbox::parse("one@rustastic.org").unwrap();
+    let rcpt_2 = Mailbox::parse("two@rustastic.org").unwrap();
+
+    let window = DuplicateWindow::new(Duration::from_secs(3600), DuplicateAction::Reject);
+    assert_eq!(DuplicateAction::Reject, window.action());
+    assert!(!window.is_duplicate(b"<1@rust>", Some(&sender), &[rcpt_1.clone(), rcpt_2.clone()]));
+    window.insert(b"<1@rust>", Some(&sender), &[rcpt_1.clone(), rcpt_2.clone()]);
+
+    // The order of the recipients doesn't matter.
+    assert!(window.is_duplicate(b"<1@rust>", Some(&sender), &[rcpt_2.clone(), rcpt_1.clone()]));
+    assert!(!window.is_duplicate(b"<2@rust>", Some(&sender), &[rcpt_1.clone(), rcpt_2.clone()]));
+    assert!(!window.is_duplicate(b"<1@rust>", None, &[rcpt_1.clone(), rcpt_2.clone()]));
+    assert!(!window.is_duplicate(b"<1@rust>", Some(&sender), &[rcpt_1.clone()]));
+
+    // Messages are forgotten once the window has passed.
+    let window = DuplicateWindow::new(Duration::from_secs(0), DuplicateAction::Drop);
+    window.insert(b"<1@rust>", Some(&sender), &[rcpt_1.clone()]);
+    assert!(!window.is_duplicate(b"<1@rust>", Some(&sender), &[rcpt_1.clone()]));
+}
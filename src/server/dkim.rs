@@ -0,0 +1,400 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of the DKIM signatures of received messages, as described
+//! [in RFC 6376](http://tools.ietf.org/html/rfc6376#section-6).
+//!
+//! Once DATA or the last BDAT chunk is received, the `DkimValidator` of the server checks
+//! every DKIM-Signature header field of the message and stores the outcomes in the
+//! transaction, before the `DataHandler` gets the message. The container decides what to
+//! do with them, ie refuse messages from a domain that always signs but failed:
+//!
+//! ```ignore
+//! fn handle_data(&mut self, data: &[u8]) -> Result<(), ()> {
+//!     let signed = self.transaction.dkim_results().iter().any(|outcome| {
+//!         outcome.domain == "rustastic.org" && outcome.result == DkimResult::Pass
+//!     });
+//!     ...
+//! }
+//! ```
+//!
+//! Public keys are looked up with a `DkimKeyLookup`, ie in DNS or from `StaticKeys`, and
+//! signatures are checked by a `DkimVerifier`, which wraps a crypto library.
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::vec::Vec;
+use super::super::client::dkim::{DkimAlgorithm, Canonicalization, canonicalize_header, canonicalize_body};
+use super::super::common::base64;
+use super::super::common::dns::DnsError;
+use super::super::common::message::header_fields;
+use super::super::common::sha256;
+#[cfg(test)]
+use super::super::client::dkim::{DkimKey, DkimSigner};
+
+/// The most signatures checked in a message, so a message can't make the server do
+/// many lookups.
+pub static MAX_SIGNATURES: usize = 5;
+
+/// The result of checking a signature, as named [in RFC 8601](http://tools.ietf.org/html/rfc8601#section-2.7.1).
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum DkimResult {
+    /// The signature is valid.
+    Pass,
+    /// The signature is well formed but doesn't match the message, for the given reason.
+    Fail(String),
+    /// The key couldn't be looked up, the check may succeed later.
+    TempError(String),
+    /// The signature or its key are unusable, for the given reason.
+    PermError(String)
+}
+
+/// The outcome of checking one signature.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct DkimOutcome {
+    /// The signing domain, the `d=` tag, empty if the signature doesn't give it.
+    pub domain: String,
+    /// The selector, the `s=` tag, empty if the signature doesn't give it.
+    pub selector: String,
+    /// Whether the signature is valid.
+    pub result: DkimResult
+}
+
+/// Looks up the public keys of signers.
+pub trait DkimKeyLookup: Send + Sync {
+    /// Returns the key record published at `<selector>._domainkey.<domain>`, ie
+    /// `v=DKIM1; k=rsa; p=MIGfMA0G...`.
+    fn lookup_key(&self, selector: &str, domain: &str) -> Result<String, DnsError>;
+}
+
+/// Keys given by the operator, ie for tests or for partners with known keys.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct StaticKeys {
+    keys: HashMap<(String, String), String>
+}
+
+impl StaticKeys {
+    /// Creates a set without keys.
+    pub fn new() -> StaticKeys {
+        StaticKeys {
+            keys: HashMap::new()
+        }
+    }
+
+    /// Adds the key record of a selector of a domain.
+    pub fn add(&mut self, selector: &str, domain: &str, record: &str) {
+        self.keys.insert((selector.to_ascii_lowercase(), domain.to_ascii_lowercase()), record.to_owned());
+    }
+}
+
+impl DkimKeyLookup for StaticKeys {
+    fn lookup_key(&self, selector: &str, domain: &str) -> Result<String, DnsError> {
+        match self.keys.get(&(selector.to_ascii_lowercase(), domain.to_ascii_lowercase())) {
+            Some(record) => Ok(record.clone()),
+            None => Err(DnsError::NotFound)
+        }
+    }
+}
+
+/// Checks signatures with public keys.
+pub trait DkimVerifier: Send + Sync {
+    /// Tells whether the signature of the SHA-256 digest of the signed header fields is
+    /// valid for the key, given as the decoded `p=` tag of its record.
+    fn verify(&self, algorithm: DkimAlgorithm, public_key: &[u8], digest: &[u8; 32], signature: &[u8]) -> bool;
+}
+
+// Returns the tags of a tag list, ie `v=1; a=rsa-sha256`, with whitespace removed from
+// their values, `None` if it is malformed.
+fn parse_tags(list: &str) -> Option<Vec<(String, String)>> {
+    let mut tags = Vec::new();
+    for tag in list.split(';') {
+        if tag.trim().len() == 0 {
+            continue;
+        }
+        let eq = match tag.find('=') {
+            Some(eq) => eq,
+            None => return None
+        };
+        let name = tag[.. eq].trim();
+        if name.len() == 0 || tags.iter().any(|&(ref existing, _): &(String, String)| existing == name) {
+            return None;
+        }
+        let value: String = tag[eq + 1 ..].chars().filter(|c| !c.is_whitespace()).collect();
+        tags.push((name.to_owned(), value));
+    }
+    Some(tags)
+}
+
+fn tag<'a>(tags: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    tags.iter().find(|&&(ref existing, _)| existing == name).map(|&(_, ref value)| value.as_ref())
+}
+
+// Returns the signature field without the value of its `b=` tag, as it was hashed.
+fn without_signature(field: &[u8]) -> Vec<u8> {
+    let colon = field.iter().position(|&byte| byte == b':').unwrap_or(field.len());
+    let mut start = colon + 1;
+    while start < field.len() {
+        let end = field[start ..].iter().position(|&byte| byte == b';').map_or(field.len(), |pos| start + pos);
+        if let Some(eq) = field[start .. end].iter().position(|&byte| byte == b'=') {
+            let name = String::from_utf8_lossy(&field[start .. start + eq]).trim().to_owned();
+            if name == "b" {
+                let mut stripped = field[.. start + eq + 1].to_vec();
+                stripped.extend_from_slice(&field[end ..]);
+                return stripped;
+            }
+        }
+        start = end + 1;
+    }
+    field.to_vec()
+}
+
+fn parse_canonicalization(name: &str) -> Option<Canonicalization> {
+    match name {
+        "simple" => Some(Canonicalization::Simple),
+        "relaxed" => Some(Canonicalization::Relaxed),
+        _ => None
+    }
+}
+
+/// Checks the DKIM signatures of messages, see `Server::set_dkim_validator`.
+#[derive(Clone)]
+pub struct DkimValidator {
+    keys: Arc<DkimKeyLookup>,
+    verifier: Arc<DkimVerifier>
+}
+
+impl DkimValidator {
+    /// Creates a validator looking up keys and checking signatures with the given
+    /// implementations.
+    pub fn new<K: 'static + DkimKeyLookup, V: 'static + DkimVerifier>(keys: K, verifier: V) -> DkimValidator {
+        DkimValidator {
+            keys: Arc::new(keys),
+            verifier: Arc::new(verifier)
+        }
+    }
+
+    /// Checks the first `MAX_SIGNATURES` signatures of the message, from the top.
+    ///
+    /// A message without signatures gives no outcome.
+    pub fn verify(&self, message: &[u8]) -> Vec<DkimOutcome> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        self.verify_at(message, now.as_secs())
+    }
+
+    // Checks the signatures of the message, at the given time.
+    fn verify_at(&self, message: &[u8], now: u64) -> Vec<DkimOutcome> {
+        let mut fields = header_fields(message);
+        let mut present: Vec<(String, &[u8])> = Vec::new();
+        for field in fields.by_ref() {
+            let colon = field.iter().position(|&byte| byte == b':').unwrap_or(field.len());
+            let name = String::from_utf8_lossy(&field[.. colon]).trim().to_ascii_lowercase();
+            present.push((name, field));
+        }
+        let body = &message[fields.position() ..];
+
+        present.iter().filter(|&&(ref name, _)| name == "dkim-signature").take(MAX_SIGNATURES).map(|&(_, field)| {
+            let colon = field.iter().position(|&byte| byte == b':').unwrap();
+            let tags = parse_tags(String::from_utf8_lossy(&field[colon + 1 ..]).as_ref()).unwrap_or(Vec::new());
+            DkimOutcome {
+                domain: tag(tags.as_ref(), "d").unwrap_or("").to_owned(),
+                selector: tag(tags.as_ref(), "s").unwrap_or("").to_owned(),
+                result: self.check(&present, field, body, now)
+            }
+        }).collect()
+    }
+
+    // Checks one signature field.
+    fn check(&self, present: &[(String, &[u8])], field: &[u8], body: &[u8], now: u64) -> DkimResult {
+        let perm = |reason: &str| DkimResult::PermError(reason.to_owned());
+        let colon = field.iter().position(|&byte| byte == b':').unwrap();
+        let tags = match parse_tags(String::from_utf8_lossy(&field[colon + 1 ..]).as_ref()) {
+            Some(tags) => tags,
+            None => return perm("malformed signature")
+        };
+        let tags = &tags[..];
+        let (algorithm, signature, body_hash, domain, signed, selector) = match (tag(tags, "a"), tag(tags, "b"), tag(tags, "bh"), tag(tags, "d"), tag(tags, "h"), tag(tags, "s")) {
+            (Some(a), Some(b), Some(bh), Some(d), Some(h), Some(s)) => (a, b, bh, d, h, s),
+            _ => return perm("missing tag")
+        };
+        if tag(tags, "v") != Some("1") {
+            return perm("unsupported version");
+        }
+        let algorithm = match algorithm {
+            "rsa-sha256" => DkimAlgorithm::RsaSha256,
+            "ed25519-sha256" => DkimAlgorithm::Ed25519Sha256,
+            _ => return perm("unsupported algorithm")
+        };
+        let canonicalization = tag(tags, "c").unwrap_or("simple");
+        let (header_canonicalization, body_canonicalization) = match canonicalization.find('/') {
+            Some(slash) => (parse_canonicalization(&canonicalization[.. slash]), parse_canonicalization(&canonicalization[slash + 1 ..])),
+            None => (parse_canonicalization(canonicalization), Some(Canonicalization::Simple))
+        };
+        let (header_canonicalization, body_canonicalization) = match (header_canonicalization, body_canonicalization) {
+            (Some(header), Some(body)) => (header, body),
+            _ => return perm("unsupported canonicalization")
+        };
+        let names: Vec<String> = signed.split(':').map(|name| name.to_ascii_lowercase()).collect();
+        if !names.iter().any(|name| name == "from") {
+            return perm("From not signed");
+        }
+        let (signature, body_hash) = match (base64::decode(signature), base64::decode(body_hash)) {
+            (Ok(signature), Ok(body_hash)) => (signature, body_hash),
+            _ => return perm("malformed base64")
+        };
+        if let Some(expiration) = tag(tags, "x") {
+            match expiration.parse::<u64>() {
+                Ok(expiration) if expiration < now => return DkimResult::Fail("signature expired".to_owned()),
+                Ok(_) => {},
+                Err(_) => return perm("malformed expiration")
+            }
+        }
+
+        let mut canonical_body = canonicalize_body(body, body_canonicalization);
+        if let Some(length) = tag(tags, "l") {
+            match length.parse::<usize>() {
+                Ok(length) if length <= canonical_body.len() => canonical_body.truncate(length),
+                _ => return perm("malformed body length")
+            }
+        }
+        if sha256::digest(canonical_body.as_ref())[..] != body_hash[..] {
+            return DkimResult::Fail("body hash mismatch".to_owned());
+        }
+
+        let record = match self.keys.lookup_key(selector, domain) {
+            Ok(record) => record,
+            Err(DnsError::Temporary(reason)) => return DkimResult::TempError(reason),
+            Err(_) => return perm("no key")
+        };
+        let record = match parse_tags(record.as_ref()) {
+            Some(record) => record,
+            None => return perm("malformed key")
+        };
+        let key_type = match algorithm {
+            DkimAlgorithm::RsaSha256 => "rsa",
+            DkimAlgorithm::Ed25519Sha256 => "ed25519"
+        };
+        if tag(record.as_ref(), "k").unwrap_or("rsa") != key_type {
+            return perm("key type mismatch");
+        }
+        let public_key = match tag(record.as_ref(), "p").map(base64::decode) {
+            Some(Ok(ref key)) if key.len() == 0 => return perm("key revoked"),
+            Some(Ok(key)) => key,
+            _ => return perm("malformed key")
+        };
+
+        // Fields with the same name are taken from the bottom up, and names listed more
+        // times than there are fields add nothing.
+        let mut used = vec![false; present.len()];
+        let mut hashed = Vec::new();
+        for name in names.iter() {
+            let found = present.iter().enumerate().rev().find(|&(i, &(ref existing, _))| !used[i] && existing == name).map(|(i, _)| i);
+            if let Some(i) = found {
+                used[i] = true;
+                hashed.extend(canonicalize_header(present[i].1, header_canonicalization));
+            }
+        }
+        let canonical = canonicalize_header(without_signature(field).as_ref(), header_canonicalization);
+        hashed.extend_from_slice(&canonical[.. canonical.len() - 2]);
+
+        match self.verifier.verify(algorithm, public_key.as_ref(), &sha256::digest(hashed.as_ref()), signature.as_ref()) {
+            true => DkimResult::Pass,
+            false => DkimResult::Fail("signature mismatch".to_owned())
+        }
+    }
+}
+
+// Pretends to check signatures made by the signer of the client tests, whose signature
+// is the digest itself.
+#[cfg(test)]
+struct DigestVerifier;
+
+#[cfg(test)]
+impl DkimVerifier for DigestVerifier {
+    fn verify(&self, _: DkimAlgorithm, public_key: &[u8], digest: &[u8; 32], signature: &[u8]) -> bool {
+        public_key == b"key" && &digest[..] == signature
+    }
+}
+
+#[cfg(test)]
+struct DigestSigner;
+
+#[cfg(test)]
+impl DkimSigner for DigestSigner {
+    fn algorithm(&self) -> DkimAlgorithm {
+        DkimAlgorithm::Ed25519Sha256
+    }
+
+    fn sign(&self, digest: &[u8; 32]) -> Result<Vec<u8>, String> {
+        Ok(digest.to_vec())
+    }
+}
+
+#[test]
+fn test_parse_tags() {
+    assert_eq!(Some(vec![("v".to_owned(), "1".to_owned()), ("b".to_owned(), "abcd".to_owned())]), parse_tags(" v=1;\r\n\tb=ab\r\n cd;"));
+    assert_eq!(None, parse_tags("v=1; v=2"));
+    assert_eq!(None, parse_tags("v"));
+    assert_eq!(b"DKIM-Signature: v=1; b=; bh=abc".to_vec(), without_signature(b"DKIM-Signature: v=1; b=xyz; bh=abc"));
+    assert_eq!(b"DKIM-Signature: v=1; bh=abc;\r\n\tb=".to_vec(), without_signature(b"DKIM-Signature: v=1; bh=abc;\r\n\tb=xy\r\n z"));
+}
+
+#[test]
+fn test_verify() {
+    let mut keys = StaticKeys::new();
+    keys.add("mail", "rustastic.org", &format!("v=DKIM1; k=ed25519; p={}", base64::encode(b"key")));
+    keys.add("revoked", "rustastic.org", "v=DKIM1; k=ed25519; p=");
+    keys.add("rsa", "rustastic.org", &format!("v=DKIM1; p={}", base64::encode(b"key")));
+    let validator = DkimValidator::new(keys, DigestVerifier);
+    let message = b"From: rust@rustastic.org\r\nSubject: Hi\r\n\r\nHi\r\n";
+    assert_eq!(Vec::<DkimOutcome>::new(), validator.verify(message));
+
+    let mut key = DkimKey::new("rustastic.org", "mail", DigestSigner);
+    let signed = key.sign(message).unwrap();
+    assert_eq!(vec![DkimOutcome {
+        domain: "rustastic.org".to_owned(),
+        selector: "mail".to_owned(),
+        result: DkimResult::Pass
+    }], validator.verify(signed.as_ref()));
+
+    // Relaxed canonicalization takes whitespace changes, not content changes.
+    let mut changed = String::from_utf8(signed.clone()).unwrap().replace("Subject: Hi", "subject:   Hi ").into_bytes();
+    assert_eq!(DkimResult::Pass, validator.verify(changed.as_ref())[0].result);
+    changed.extend_from_slice(b"More\r\n");
+    assert_eq!(DkimResult::Fail("body hash mismatch".to_owned()), validator.verify(changed.as_ref())[0].result);
+    let changed = String::from_utf8(signed.clone()).unwrap().replace("Subject: Hi", "Subject: Ho").into_bytes();
+    assert_eq!(DkimResult::Fail("signature mismatch".to_owned()), validator.verify(changed.as_ref())[0].result);
+
+    key.set_canonicalization(Canonicalization::Simple, Canonicalization::Simple);
+    let signed = key.sign(message).unwrap();
+    assert_eq!(DkimResult::Pass, validator.verify(signed.as_ref())[0].result);
+    let changed = String::from_utf8(signed).unwrap().replace("Subject: Hi", "Subject:  Hi").into_bytes();
+    assert_eq!(DkimResult::Fail("signature mismatch".to_owned()), validator.verify(changed.as_ref())[0].result);
+
+    for &(selector, ref result) in [
+        ("revoked", DkimResult::PermError("key revoked".to_owned())),
+        ("rsa", DkimResult::PermError("key type mismatch".to_owned())),
+        ("unknown", DkimResult::PermError("no key".to_owned()))
+    ].iter() {
+        let signed = DkimKey::new("rustastic.org", selector, DigestSigner).sign(message).unwrap();
+        assert_eq!(*result, validator.verify(signed.as_ref())[0].result);
+    }
+
+    let expired = b"DKIM-Signature: v=1; a=ed25519-sha256; d=rustastic.org; s=mail; h=from; bh=; b=; x=1\r\nFrom: rust@rustastic.org\r\n\r\n";
+    assert_eq!(DkimResult::Fail("signature expired".to_owned()), validator.verify(expired)[0].result);
+    let outcome = &validator.verify(b"DKIM-Signature: v=1; d=rustastic.org\r\nFrom: rust@rustastic.org\r\n\r\n")[0];
+    assert_eq!(("rustastic.org", DkimResult::PermError("missing tag".to_owned())), (outcome.domain.as_ref(), outcome.result.clone()));
+}
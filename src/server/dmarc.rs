@@ -0,0 +1,453 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! DMARC evaluation of received messages, as described
+//! [in RFC 7489](http://tools.ietf.org/html/rfc7489).
+//!
+//! A message passes DMARC when the domain of its From header field is aligned with a
+//! domain that passed SPF or DKIM. Otherwise, the policy published by the domain at
+//! `_dmarc.<domain>` tells what to do with it. This crate doesn't check SPF, so the
+//! domain that passed it, if any, is given by the caller. The DKIM outcomes are usually
+//! those of the transaction, see `server::dkim`:
+//!
+//! ```ignore
+//! fn handle_data(&mut self, data: &[u8]) -> Result<(), ()> {
+//!     let outcome = self.dmarc.evaluate(data, self.spf_pass.as_ref().map(|domain| domain.as_ref()), self.transaction.dkim_results());
+//!     match outcome.disposition {
+//!         Disposition::Reject => Err(()),
+//!         Disposition::Quarantine => self.store_in_junk(data),
+//!         Disposition::None => self.store(data)
+//!     }
+//! }
+//! ```
+//!
+//! Organizational domains are found without the public suffix list: they are the last two
+//! labels of a domain, or three under a suffix given to `add_public_suffix`, ie `co.uk`.
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::vec::Vec;
+use super::dkim::{DkimOutcome, DkimResult};
+use super::super::common::dns::DnsError;
+use super::super::common::message::header_fields;
+
+/// What the owner of a domain asks receivers to do with messages that fail DMARC.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum Disposition {
+    /// Deliver the message as usual.
+    None,
+    /// Deliver the message, but treat it as suspicious, ie as spam.
+    Quarantine,
+    /// Refuse the message.
+    Reject
+}
+
+/// How closely two domains must match to be aligned.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum Alignment {
+    /// The organizational domains must be the same, the default.
+    Relaxed,
+    /// The domains must be the same.
+    Strict
+}
+
+/// A DMARC policy record, ie `v=DMARC1; p=reject; adkim=s`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct DmarcRecord {
+    /// The policy of the domain, the `p=` tag.
+    pub policy: Disposition,
+    /// The policy of its subdomains, the `sp=` tag, the policy of the domain if `None`.
+    pub subdomain_policy: Option<Disposition>,
+    /// The alignment of DKIM domains, the `adkim=` tag.
+    pub dkim_alignment: Alignment,
+    /// The alignment of SPF domains, the `aspf=` tag.
+    pub spf_alignment: Alignment,
+    /// The percentage of failing messages the policy applies to, the `pct=` tag.
+    pub percent: u8
+}
+
+fn parse_disposition(value: &str) -> Option<Disposition> {
+    match value {
+        "none" => Some(Disposition::None),
+        "quarantine" => Some(Disposition::Quarantine),
+        "reject" => Some(Disposition::Reject),
+        _ => None
+    }
+}
+
+fn parse_alignment(value: &str) -> Option<Alignment> {
+    match value {
+        "r" => Some(Alignment::Relaxed),
+        "s" => Some(Alignment::Strict),
+        _ => None
+    }
+}
+
+impl DmarcRecord {
+    /// Parses a record, `None` if it isn't a valid DMARC record.
+    ///
+    /// Unknown tags are ignored, as are reporting tags, ie `rua=`.
+    pub fn parse(record: &str) -> Option<DmarcRecord> {
+        let mut tags = Vec::new();
+        for tag in record.split(';') {
+            if tag.trim().len() == 0 {
+                continue;
+            }
+            match tag.find('=') {
+                Some(eq) => tags.push((tag[.. eq].trim(), tag[eq + 1 ..].trim())),
+                None => return None
+            }
+        }
+        if tags.first() != Some(&("v", "DMARC1")) {
+            return None;
+        }
+        let mut parsed = DmarcRecord {
+            policy: Disposition::None,
+            subdomain_policy: None,
+            dkim_alignment: Alignment::Relaxed,
+            spf_alignment: Alignment::Relaxed,
+            percent: 100
+        };
+        let mut policy = None;
+        for &(name, value) in tags[1 ..].iter() {
+            let value = value.to_ascii_lowercase();
+            let valid = match name {
+                "p" => parse_disposition(value.as_ref()).map(|disposition| policy = Some(disposition)).is_some(),
+                "sp" => parse_disposition(value.as_ref()).map(|disposition| parsed.subdomain_policy = Some(disposition)).is_some(),
+                "adkim" => parse_alignment(value.as_ref()).map(|alignment| parsed.dkim_alignment = alignment).is_some(),
+                "aspf" => parse_alignment(value.as_ref()).map(|alignment| parsed.spf_alignment = alignment).is_some(),
+                "pct" => match value.parse::<u8>() {
+                    Ok(percent) if percent <= 100 => {
+                        parsed.percent = percent;
+                        true
+                    },
+                    _ => false
+                },
+                _ => true
+            };
+            if !valid {
+                return None;
+            }
+        }
+        policy.map(|policy| {
+            parsed.policy = policy;
+            parsed
+        })
+    }
+}
+
+/// Looks up DMARC records.
+pub trait DmarcLookup: Send + Sync {
+    /// Returns the TXT records published at `_dmarc.<domain>`.
+    ///
+    /// An existing name without TXT records has none, rather than `NotFound`.
+    fn lookup_dmarc(&self, domain: &str) -> Result<Vec<String>, DnsError>;
+}
+
+/// Whether a message passed DMARC.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum DmarcResult {
+    /// The From domain is aligned with a domain that passed SPF or DKIM.
+    Pass,
+    /// No aligned domain passed, and the From domain has a policy.
+    Fail,
+    /// The From domain has no policy.
+    None,
+    /// The policy couldn't be looked up, the evaluation may succeed later.
+    TempError(String),
+    /// The message has no From domain, or more than one.
+    PermError
+}
+
+/// The outcome of evaluating a message.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct DmarcOutcome {
+    /// Whether the message passed DMARC.
+    pub result: DmarcResult,
+    /// What to do with the message. This is `Disposition::None` unless it failed.
+    pub disposition: Disposition,
+    /// The domain of the From header field, if there is exactly one.
+    pub from_domain: Option<String>,
+    /// The policy that applies to the From domain, if any.
+    pub record: Option<DmarcRecord>
+}
+
+/// Returns the domain of the From header field of a message, `None` if there isn't
+/// exactly one address in exactly one From field.
+pub fn from_domain(message: &[u8]) -> Option<String> {
+    let mut domains = Vec::new();
+    for field in header_fields(message) {
+        let colon = match field.iter().position(|&byte| byte == b':') {
+            Some(colon) => colon,
+            None => continue
+        };
+        if !String::from_utf8_lossy(&field[.. colon]).trim().eq_ignore_ascii_case("From") {
+            continue;
+        }
+        let value = String::from_utf8_lossy(&field[colon + 1 ..]).into_owned();
+        // Display names may have commas and `@` in quotes, addresses don't.
+        let mut in_quotes = false;
+        let mut address = String::new();
+        let mut addresses = Vec::new();
+        for c in value.chars() {
+            match c {
+                '"' => in_quotes = !in_quotes,
+                ',' if !in_quotes => addresses.push(address.split_off(0)),
+                _ if !in_quotes => address.push(c),
+                _ => {}
+            }
+        }
+        addresses.push(address);
+        for address in addresses.iter() {
+            let address = match (address.rfind('<'), address.rfind('>')) {
+                (Some(start), Some(end)) if start < end => &address[start + 1 .. end],
+                _ => address.as_ref()
+            };
+            match address.rfind('@') {
+                Some(at) => domains.push(address[at + 1 ..].trim().trim_right_matches('.').to_ascii_lowercase()),
+                None => return None
+            }
+        }
+    }
+    match domains.len() {
+        1 => domains.pop(),
+        _ => None
+    }
+}
+
+/// Evaluates messages against the DMARC policies of their From domains.
+#[derive(Clone)]
+pub struct DmarcEvaluator {
+    lookup: Arc<DmarcLookup>,
+    public_suffixes: Vec<String>
+}
+
+impl DmarcEvaluator {
+    /// Creates an evaluator looking up policies with the given implementation.
+    pub fn new<L: 'static + DmarcLookup>(lookup: L) -> DmarcEvaluator {
+        DmarcEvaluator {
+            lookup: Arc::new(lookup),
+            public_suffixes: Vec::new()
+        }
+    }
+
+    /// Adds a public suffix of more than one label, ie `co.uk`, so organizational domains
+    /// under it get three labels.
+    pub fn add_public_suffix(&mut self, suffix: &str) {
+        self.public_suffixes.push(suffix.trim_matches('.').to_ascii_lowercase());
+    }
+
+    /// Returns the organizational domain of a domain, ie `rustastic.org` for
+    /// `mx.rustastic.org`.
+    pub fn organizational_domain(&self, domain: &str) -> String {
+        let domain = domain.trim_right_matches('.').to_ascii_lowercase();
+        let labels: Vec<&str> = domain.split('.').collect();
+        let suffix_len = self.public_suffixes.iter().filter(|suffix| {
+            domain.ends_with(&format!(".{}", suffix)[..])
+        }).map(|suffix| suffix.split('.').count()).max().unwrap_or(1);
+        match labels.len() > suffix_len {
+            true => labels[labels.len() - suffix_len - 1 ..].join("."),
+            false => domain.clone()
+        }
+    }
+
+    /// Tells whether two domains are aligned.
+    pub fn is_aligned(&self, a: &str, b: &str, alignment: Alignment) -> bool {
+        match alignment {
+            Alignment::Strict => a.trim_right_matches('.').eq_ignore_ascii_case(b.trim_right_matches('.')),
+            Alignment::Relaxed => self.organizational_domain(a) == self.organizational_domain(b)
+        }
+    }
+
+    // Returns the record published for a domain, `None` if there isn't exactly one.
+    fn record(&self, domain: &str) -> Result<Option<DmarcRecord>, String> {
+        let records = match self.lookup.lookup_dmarc(domain) {
+            Ok(records) => records,
+            Err(DnsError::Temporary(reason)) => return Err(reason),
+            Err(_) => return Ok(None)
+        };
+        let mut parsed: Vec<DmarcRecord> = records.iter().filter_map(|record| DmarcRecord::parse(record)).collect();
+        match parsed.len() {
+            1 => Ok(parsed.pop()),
+            _ => Ok(None)
+        }
+    }
+
+    /// Evaluates a message.
+    ///
+    /// `spf_domain` is the domain that passed SPF, if any, and `dkim` the outcomes of the
+    /// DKIM signatures of the message. When the policy applies to less than all messages,
+    /// a random share of the failing ones gets the next weaker disposition.
+    pub fn evaluate(&self, message: &[u8], spf_domain: Option<&str>, dkim: &[DkimOutcome]) -> DmarcOutcome {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        self.evaluate_sampled(message, spf_domain, dkim, (now.subsec_nanos() / 1000 % 100) as u8)
+    }
+
+    // Evaluates a message, whose policy applies if `sample` is lower than its `pct=`.
+    fn evaluate_sampled(&self, message: &[u8], spf_domain: Option<&str>, dkim: &[DkimOutcome], sample: u8) -> DmarcOutcome {
+        let mut outcome = DmarcOutcome {
+            result: DmarcResult::PermError,
+            disposition: Disposition::None,
+            from_domain: from_domain(message),
+            record: None
+        };
+        let domain = match outcome.from_domain {
+            Some(ref domain) => domain.clone(),
+            None => return outcome
+        };
+
+        // Subdomains without their own record get the policy of their organization.
+        let organizational = self.organizational_domain(domain.as_ref());
+        let (record, inherited) = match self.record(domain.as_ref()) {
+            Ok(Some(record)) => (record, false),
+            Ok(None) if organizational != domain => match self.record(organizational.as_ref()) {
+                Ok(Some(record)) => (record, true),
+                Ok(None) => {
+                    outcome.result = DmarcResult::None;
+                    return outcome;
+                },
+                Err(reason) => {
+                    outcome.result = DmarcResult::TempError(reason);
+                    return outcome;
+                }
+            },
+            Ok(None) => {
+                outcome.result = DmarcResult::None;
+                return outcome;
+            },
+            Err(reason) => {
+                outcome.result = DmarcResult::TempError(reason);
+                return outcome;
+            }
+        };
+
+        let dkim_aligned = dkim.iter().any(|signature| {
+            signature.result == DkimResult::Pass && self.is_aligned(signature.domain.as_ref(), domain.as_ref(), record.dkim_alignment)
+        });
+        let spf_aligned = spf_domain.map_or(false, |spf_domain| self.is_aligned(spf_domain, domain.as_ref(), record.spf_alignment));
+        match dkim_aligned || spf_aligned {
+            true => outcome.result = DmarcResult::Pass,
+            false => {
+                outcome.result = DmarcResult::Fail;
+                let policy = match inherited {
+                    true => record.subdomain_policy.unwrap_or(record.policy),
+                    false => record.policy
+                };
+                outcome.disposition = match (sample < record.percent, policy) {
+                    (true, policy) => policy,
+                    (false, Disposition::Reject) => Disposition::Quarantine,
+                    (false, _) => Disposition::None
+                };
+            }
+        }
+        outcome.record = Some(record);
+        outcome
+    }
+}
+
+#[cfg(test)]
+struct TestLookup;
+
+#[cfg(test)]
+impl DmarcLookup for TestLookup {
+    fn lookup_dmarc(&self, domain: &str) -> Result<Vec<String>, DnsError> {
+        match domain {
+            "rustastic.org" => Ok(vec!["v=spf1 -all".to_owned(), "v=DMARC1; p=reject; sp=quarantine; adkim=s; pct=50; rua=mailto:d@rustastic.org".to_owned()]),
+            "rustastic.co.uk" => Ok(vec!["v=DMARC1; p=quarantine".to_owned()]),
+            "down.example" => Err(DnsError::Temporary("timeout".to_owned())),
+            _ => Err(DnsError::NotFound)
+        }
+    }
+}
+
+#[cfg(test)]
+fn dkim_pass(domain: &str) -> DkimOutcome {
+    DkimOutcome {
+        domain: domain.to_owned(),
+        selector: "mail".to_owned(),
+        result: DkimResult::Pass
+    }
+}
+
+#[test]
+fn test_parse_record() {
+    assert_eq!(Some(DmarcRecord {
+        policy: Disposition::Reject,
+        subdomain_policy: Some(Disposition::None),
+        dkim_alignment: Alignment::Strict,
+        spf_alignment: Alignment::Relaxed,
+        percent: 20
+    }), DmarcRecord::parse("v=DMARC1; p=Reject; sp=none; adkim=s; aspf=r; pct=20; fo=1;"));
+    assert_eq!(None, DmarcRecord::parse("p=reject; v=DMARC1"));
+    assert_eq!(None, DmarcRecord::parse("v=DMARC1; adkim=s"));
+    assert_eq!(None, DmarcRecord::parse("v=DMARC1; p=drop"));
+    assert_eq!(None, DmarcRecord::parse("v=DMARC1; p=none; pct=101"));
+}
+
+#[test]
+fn test_from_domain() {
+    assert_eq!(Some("rustastic.org".to_owned()), from_domain(b"From: \"Rust, @home\" <rust@Rustastic.org>\r\n\r\n"));
+    assert_eq!(Some("rustastic.org".to_owned()), from_domain(b"Subject: Hi\r\nfrom:rust@rustastic.org\r\n\r\n"));
+    assert_eq!(None, from_domain(b"From: a@rustastic.org, b@rustastic.org\r\n\r\n"));
+    assert_eq!(None, from_domain(b"From: a@rustastic.org\r\nFrom: b@rustastic.org\r\n\r\n"));
+    assert_eq!(None, from_domain(b"Subject: Hi\r\n\r\nFrom: a@rustastic.org\r\n"));
+}
+
+#[test]
+fn test_alignment() {
+    let mut evaluator = DmarcEvaluator::new(TestLookup);
+    assert_eq!("rustastic.org", evaluator.organizational_domain("mx.mail.Rustastic.org."));
+    assert_eq!("org", evaluator.organizational_domain("org"));
+    assert_eq!("co.uk", evaluator.organizational_domain("rustastic.co.uk"));
+    evaluator.add_public_suffix("co.uk");
+    assert_eq!("rustastic.co.uk", evaluator.organizational_domain("mx.rustastic.co.uk"));
+    assert!(evaluator.is_aligned("mx.rustastic.org", "rustastic.org", Alignment::Relaxed));
+    assert!(!evaluator.is_aligned("mx.rustastic.org", "rustastic.org", Alignment::Strict));
+    assert!(!evaluator.is_aligned("a.co.uk", "b.co.uk", Alignment::Relaxed));
+}
+
+#[test]
+fn test_evaluate() {
+    let mut evaluator = DmarcEvaluator::new(TestLookup);
+    evaluator.add_public_suffix("co.uk");
+    let message = b"From: rust@rustastic.org\r\n\r\nHi\r\n";
+
+    // DKIM alignment is strict, SPF alignment is relaxed.
+    let outcome = evaluator.evaluate_sampled(message, None, &[dkim_pass("rustastic.org")], 0);
+    assert_eq!((DmarcResult::Pass, Disposition::None), (outcome.result, outcome.disposition));
+    assert_eq!(Some(Disposition::Quarantine), outcome.record.unwrap().subdomain_policy);
+    let outcome = evaluator.evaluate_sampled(message, Some("bounces.rustastic.org"), &[], 0);
+    assert_eq!(DmarcResult::Pass, outcome.result);
+    let mut failed = dkim_pass("rustastic.org");
+    failed.result = DkimResult::Fail("body hash mismatch".to_owned());
+    for dkim in [dkim_pass("mail.rustastic.org"), failed].iter() {
+        let outcome = evaluator.evaluate_sampled(message, Some("example.org"), &[dkim.clone()], 0);
+        assert_eq!((DmarcResult::Fail, Disposition::Reject), (outcome.result, outcome.disposition));
+    }
+
+    // Half the failing messages get the policy, the others the next weaker one.
+    assert_eq!(Disposition::Quarantine, evaluator.evaluate_sampled(message, None, &[], 50).disposition);
+    // Subdomains inherit the policy for subdomains.
+    let outcome = evaluator.evaluate_sampled(b"From: rust@mx.rustastic.org\r\n\r\n", None, &[], 0);
+    assert_eq!((Some("mx.rustastic.org".to_owned()), Disposition::Quarantine), (outcome.from_domain, outcome.disposition));
+    let outcome = evaluator.evaluate_sampled(b"From: rust@mx.rustastic.co.uk\r\n\r\n", None, &[dkim_pass("rustastic.co.uk")], 0);
+    assert_eq!(DmarcResult::Pass, outcome.result);
+
+    assert_eq!(DmarcResult::None, evaluator.evaluate_sampled(b"From: rust@example.org\r\n\r\n", None, &[], 0).result);
+    assert_eq!(DmarcResult::TempError("timeout".to_owned()), evaluator.evaluate_sampled(b"From: rust@down.example\r\n\r\n", None, &[], 0).result);
+    let outcome = evaluator.evaluate_sampled(b"Subject: Hi\r\n\r\n", None, &[], 0);
+    assert_eq!((DmarcResult::PermError, Disposition::None), (outcome.result, outcome.disposition));
+}
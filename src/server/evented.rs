@@ -0,0 +1,447 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! A runtime serving many sessions with a few threads, see `Server::listen_evented`.
+//!
+//! With `Server::listen`, every session has a thread of its own, even while the client
+//! sends nothing. Here, an event loop waits for all the idle sessions at once with `poll`,
+//! and hands a session to a worker only once the client sent something. The worker runs
+//! the commands it got, exactly as `Server::listen` does, and gives the session back. This
+//! way, commands and middleware are the same whatever the runtime.
+//!
+//! A worker stays with a session until its command is done, so a client sending a line or
+//! a message slowly keeps a worker busy, up to the timeouts.
+//!
+//! New connections are set up and greeted on a worker too, since looking up the reverse
+//! DNS of the client can take a while. With the PROXY protocol, the loop first waits for
+//! the header like it waits for a command, so clients that send nothing don't hold a
+//! worker.
+
+use std::io::ErrorKind;
+use std::io::Error as IoError;
+use std::io::Result as IoResult;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+#[cfg(test)]
+use std::borrow::ToOwned;
+#[cfg(test)]
+use std::io::{BufRead, BufReader, Write};
+#[cfg(test)]
+use std::net::Shutdown;
+use super::libc;
+use super::{Server, ServerConfig, SessionError, SessionResult};
+use super::pool::WorkerPool;
+use super::connections::ConnectionPermit;
+use super::session::SessionState;
+use super::transaction::TransactionGuard;
+use super::super::common::stream::{InputStream, OutputStream, Transport};
+#[cfg(test)]
+use super::commands::helo;
+#[cfg(test)]
+use super::testing::TestContainer;
+#[cfg(test)]
+use super::rdns::{ReverseDns, TestResolver};
+
+// How long the event loop waits for events before taking back the sessions that workers
+// are done with, in milliseconds.
+static TICK: i32 = 20;
+
+// The sessions waiting for a worker, per worker.
+static QUEUE_PER_WORKER: usize = 16;
+
+// A connection whose session didn't start yet.
+struct Pending {
+    stream: TcpStream,
+    accepted: Instant,
+    // Counts the connection, and then its session.
+    permit: ConnectionPermit
+}
+
+// What the workers are given.
+enum Job<CT> {
+    // Starts the session of a new connection.
+    Open(Pending),
+    // Runs the commands a client sent.
+    Step(Session<CT>)
+}
+
+// A session between two commands.
+struct Session<CT> {
+    input: InputStream<Transport>,
+    output: OutputStream<Transport>,
+    container: TransactionGuard<CT>,
+    state: SessionState,
+    started: Instant,
+    // Counts the session until it ends.
+    _permit: ConnectionPermit
+}
+
+// Waits for the listener and the idle sessions, and hands them to workers.
+pub struct EventLoop<CT> {
+    config: Arc<ServerConfig<CT>>,
+    listener: TcpListener,
+    // The connections waiting for their PROXY header.
+    waiting: Vec<Pending>,
+    idle: Vec<Session<CT>>,
+    pool: WorkerPool<Job<CT>>,
+    done: Receiver<Session<CT>>
+}
+
+impl<CT: 'static + Send + Sync + Clone> EventLoop<CT> {
+    // Creates a loop accepting connections from the listener, with the given number of
+    // workers.
+    pub fn new(config: Arc<ServerConfig<CT>>, container: CT, listener: TcpListener, workers: usize) -> IoResult<EventLoop<CT>> {
+        try!(listener.set_nonblocking(true));
+        let (sender, done) = channel();
+        let sender = Mutex::new(sender);
+        let worker_config = config.clone();
+        let pool = WorkerPool::new(workers, workers * QUEUE_PER_WORKER, move |job| {
+            let session = match job {
+                Job::Open(pending) => EventLoop::start(worker_config.as_ref(), container.clone(), pending),
+                Job::Step(session) => EventLoop::step(worker_config.as_ref(), session)
+            };
+            if let Some(session) = session {
+                // The loop only goes away with the pool, once the workers are done.
+                let _ = sender.lock().unwrap().send(session);
+            }
+        });
+        Ok(EventLoop {
+            config: config,
+            listener: listener,
+            waiting: Vec::new(),
+            idle: Vec::new(),
+            pool: pool,
+            done: done
+        })
+    }
+
+    // Runs the commands a client sent, on a worker. Returns the session unless it is over.
+    fn step(config: &ServerConfig<CT>, mut session: Session<CT>) -> Option<Session<CT>> {
+        let res = {
+            let Session { ref mut input, ref mut output, ref mut container, ref mut state, .. } = session;
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                try!(Server::<CT>::handle_next_command(config, input, output, &mut **container, state));
+                // Pipelined commands may already be buffered, without the socket being
+                // readable anymore.
+                while input.has_pending_line() {
+                    try!(Server::<CT>::handle_next_command(config, input, output, &mut **container, state));
+                }
+                Ok(())
+            }))
+        };
+        // The connection is closed when the session is dropped.
+        match res {
+            Ok(Ok(_)) => Some(session),
+            res => {
+                EventLoop::finish(config, &mut session, &res);
+                None
+            }
+        }
+    }
+
+    // Ends a session that is over, see `Server::finish_session`.
+    fn finish(config: &ServerConfig<CT>, session: &mut Session<CT>, res: &SessionResult) {
+        let Session { ref input, ref mut output, ref mut container, ref state, started, .. } = *session;
+        Server::<CT>::finish_session(config, input, output, &mut **container, state, started, res);
+    }
+
+    // Sets up the session of a new connection and greets the client, on a worker. Returns
+    // the session unless it is over.
+    fn start(config: &ServerConfig<CT>, container: CT, pending: Pending) -> Option<Session<CT>> {
+        let Pending { stream, permit, .. } = pending;
+        let (mut input, mut output) = match Server::<CT>::open_streams(config, stream) {
+            Ok(streams) => streams,
+            Err((stream, err)) => {
+                EventLoop::<CT>::abandon(config, stream, &err);
+                return None;
+            }
+        };
+        let mut state = SessionState::new();
+        state.set_id(config.generate_id().as_ref());
+        Server::<CT>::report_opened(config, &input, &state);
+        Server::<CT>::start_transcript(config, &mut input, &mut output, &state);
+        let mut session = Session {
+            input: input,
+            output: output,
+            container: TransactionGuard::new(container, config.abort),
+            state: state,
+            started: Instant::now(),
+            _permit: permit
+        };
+
+        Server::<CT>::look_up_client(config, &session.input, &mut session.state);
+        let res = session.output.write_reply(&Server::<CT>::greeting(config)).and_then(|_| session.output.flush());
+        match res {
+            Ok(_) => Some(session),
+            Err(err) => {
+                EventLoop::finish(config, &mut session, &Ok(Err(SessionError::Write(err))));
+                None
+            }
+        }
+    }
+
+    // Closes a connection whose session couldn't start.
+    fn abandon(config: &ServerConfig<CT>, stream: TcpStream, err: &SessionError) {
+        let mut output = OutputStream::new(stream, false);
+        Server::<CT>::close_session(config, None, &mut output, err);
+    }
+
+    // Takes a new connection, whose session starts on a worker once its PROXY header, if
+    // any, arrived.
+    fn open(&mut self, stream: TcpStream) {
+        let config = self.config.as_ref();
+        let (stream, permit) = match Server::<CT>::admit(config, stream) {
+            Some(admitted) => admitted,
+            None => return
+        };
+        // Sessions run on blocking sockets, whatever the listener is.
+        if let Err(err) = stream.set_nonblocking(false) {
+            Server::<CT>::report_error(config, None, &SessionError::Setup(err));
+            return;
+        }
+        let pending = Pending {
+            stream: stream,
+            accepted: Instant::now(),
+            permit: permit
+        };
+        match config.proxy_protocol {
+            // Reading the header right away would block the loop until the client sends it.
+            true => self.waiting.push(pending),
+            false => {
+                if let Err(Job::Open(pending)) = self.pool.submit(Job::Open(pending)) {
+                    Server::<CT>::reject_session(config, pending.stream, &SessionError::TooBusy);
+                }
+            }
+        }
+    }
+
+    // Accepts new connections, ends the sessions that timed out, then waits for events
+    // and hands the sessions that got something to the workers.
+    pub fn turn(&mut self) -> IoResult<()> {
+        while let Ok(session) = self.done.try_recv() {
+            self.idle.push(session);
+        }
+
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => self.open(stream),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    Server::<CT>::report_error(self.config.as_ref(), None, &SessionError::Accept(err));
+                    break;
+                }
+            }
+        }
+
+        // The PROXY header gets the same time as a command.
+        let timeout = self.config.command_timeout;
+        let mut i = 0;
+        while i < self.waiting.len() {
+            match self.waiting[i].accepted.elapsed() >= timeout {
+                true => {
+                    let pending = self.waiting.swap_remove(i);
+                    let err = SessionError::Proxy(IoError::new(ErrorKind::TimedOut, "no PROXY header"));
+                    EventLoop::<CT>::abandon(self.config.as_ref(), pending.stream, &err);
+                },
+                false => i += 1
+            }
+        }
+
+        let mut i = 0;
+        while i < self.idle.len() {
+            match self.idle[i].input.get_ref().stats().idle_time() >= timeout {
+                true => {
+                    let mut session = self.idle.swap_remove(i);
+                    EventLoop::finish(self.config.as_ref(), &mut session, &Ok(Err(SessionError::Timeout)));
+                },
+                false => i += 1
+            }
+        }
+
+        let mut fds = Vec::with_capacity(self.waiting.len() + self.idle.len() + 1);
+        fds.push(pollfd(&self.listener));
+        for pending in self.waiting.iter() {
+            fds.push(pollfd(&pending.stream));
+        }
+        for session in self.idle.iter() {
+            fds.push(pollfd(session.input.get_ref().socket()));
+        }
+        let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, TICK) };
+        if res < 0 {
+            let err = IoError::last_os_error();
+            return match err.kind() {
+                ErrorKind::Interrupted => Ok(()),
+                _ => Err(err)
+            };
+        }
+
+        // Going backwards keeps the indexes of the sessions not looked at yet.
+        let mut busy = false;
+        let first_idle = self.waiting.len() + 1;
+        for i in (0 .. self.idle.len()).rev() {
+            if fds[first_idle + i].revents != 0 {
+                let session = self.idle.swap_remove(i);
+                if let Err(Job::Step(session)) = self.pool.submit(Job::Step(session)) {
+                    self.idle.push(session);
+                    busy = true;
+                }
+            }
+        }
+        for i in (0 .. self.waiting.len()).rev() {
+            if fds[i + 1].revents != 0 {
+                let pending = self.waiting.swap_remove(i);
+                if let Err(Job::Open(pending)) = self.pool.submit(Job::Open(pending)) {
+                    self.waiting.push(pending);
+                    busy = true;
+                }
+            }
+        }
+        if busy {
+            // The sessions are still readable, don't spin until a worker is free.
+            thread::sleep(Duration::from_millis(TICK as u64));
+        }
+        Ok(())
+    }
+
+    // Returns the number of sessions waiting for their client.
+    #[cfg(test)]
+    fn idle_sessions(&self) -> usize {
+        self.idle.len()
+    }
+}
+
+// Waits for the socket to be readable, or for a connection on a listener.
+pub fn pollfd<S: AsRawFd>(socket: &S) -> libc::pollfd {
+    libc::pollfd {
+        fd: socket.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0
+    }
+}
+
+#[test]
+fn test_event_loop() {
+    let container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.set_hostname("rustastic.org").unwrap();
+    server.add_command(helo::get());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut event_loop = EventLoop::new(Arc::new(server.config.clone()), container, listener, 1).unwrap();
+
+    // A single worker serves both sessions, which are idle most of the time.
+    let mut clients: Vec<BufReader<TcpStream>> = (0 .. 2).map(|_| {
+        let client = TcpStream::connect(address).unwrap();
+        client.set_nonblocking(true).unwrap();
+        BufReader::new(client)
+    }).collect();
+    for &expected in ["220 rustastic.org Service ready\r\n", "250 rustastic.org\r\n"].iter() {
+        for client in clients.iter_mut() {
+            let mut reply = String::new();
+            while !reply.ends_with("\r\n") {
+                let _ = client.read_line(&mut reply);
+                event_loop.turn().unwrap();
+            }
+            assert_eq!(expected, reply);
+            client.get_mut().write_all(b"HELO rustastic.org\r\n").unwrap();
+        }
+    }
+    while event_loop.idle_sessions() < 2 {
+        event_loop.turn().unwrap();
+    }
+}
+
+#[test]
+fn test_event_loop_proxy() {
+    let container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.set_hostname("rustastic.org").unwrap();
+    server.set_proxy_protocol(true);
+    server.add_command(helo::get());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut event_loop = EventLoop::new(Arc::new(server.config.clone()), container, listener, 1).unwrap();
+
+    // A client that sends nothing doesn't hold the only worker.
+    let _silent = TcpStream::connect(address).unwrap();
+    while event_loop.waiting.len() < 1 {
+        event_loop.turn().unwrap();
+    }
+    let mut client = TcpStream::connect(address).unwrap();
+    client.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 25\r\n").unwrap();
+    client.set_nonblocking(true).unwrap();
+    let mut client = BufReader::new(client);
+    let mut reply = String::new();
+    while !reply.ends_with("\r\n") {
+        let _ = client.read_line(&mut reply);
+        event_loop.turn().unwrap();
+    }
+    assert_eq!("220 rustastic.org Service ready\r\n", reply);
+    assert_eq!(1, event_loop.waiting.len());
+}
+
+#[test]
+fn test_event_loop_summary() {
+    let summaries = Arc::new(Mutex::new(Vec::new()));
+    let container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.set_hostname("rustastic.org").unwrap();
+    server.add_command(helo::get());
+    let copy = summaries.clone();
+    server.set_on_summary(move |summary| copy.lock().unwrap().push(summary.clone()));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut event_loop = EventLoop::new(Arc::new(server.config.clone()), container, listener, 1).unwrap();
+
+    let mut client = TcpStream::connect(address).unwrap();
+    client.write_all(b"HELO rustastic.org\r\n").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    while summaries.lock().unwrap().len() < 1 {
+        event_loop.turn().unwrap();
+    }
+    let summaries = summaries.lock().unwrap();
+    assert_eq!(Some("rustastic.org"), summaries[0].domain.as_ref().map(|domain| domain.as_ref()));
+    assert_eq!(1, summaries[0].commands);
+    assert_eq!(Some(client.local_addr().unwrap()), summaries[0].peer);
+    assert_eq!("client_closed", summaries[0].close_reason);
+}
+
+#[test]
+fn test_event_loop_reverse_dns() {
+    let summaries = Arc::new(Mutex::new(Vec::new()));
+    let container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.set_hostname("rustastic.org").unwrap();
+    server.set_reverse_dns(TestResolver, Duration::from_secs(5));
+    let copy = summaries.clone();
+    server.set_on_summary(move |summary| copy.lock().unwrap().push(summary.clone()));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut event_loop = EventLoop::new(Arc::new(server.config.clone()), container, listener, 1).unwrap();
+
+    let client = TcpStream::connect(address).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    while summaries.lock().unwrap().len() < 1 {
+        event_loop.turn().unwrap();
+    }
+    let expected = ReverseDns::Verified("localhost.rustastic.org".to_owned());
+    assert_eq!(Some(&expected), summaries.lock().unwrap()[0].reverse_dns.as_ref());
+}
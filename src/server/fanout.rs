@@ -0,0 +1,137 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Handing accepted messages to several consumers, ie storing them in a maildir, notifying
+//! a webhook and indexing them for search.
+//!
+//! Consumers run in the order they were added, after the `DataHandler` of the container
+//! accepted the message. They get the content where it is, in memory or in the spool, so
+//! nothing is copied. See `Server::add_message_consumer` and `Server::set_fanout_policy`.
+
+use std::sync::Arc;
+use std::vec::Vec;
+#[cfg(test)]
+use std::borrow::ToOwned;
+#[cfg(test)]
+use std::sync::Mutex;
+use super::transaction::{Transaction, MessageData};
+#[cfg(test)]
+use super::Server;
+#[cfg(test)]
+use super::commands::{mail, rcpt, data};
+#[cfg(test)]
+use super::testing::{TestSession, TestContainer};
+#[cfg(test)]
+use super::super::common::mailbox::Mailbox;
+
+/// Receives the messages accepted by the server.
+pub trait MessageConsumer: Send + Sync {
+    /// Handles a message, whose envelope is in the transaction.
+    ///
+    /// Returns why the message couldn't be handled, if it couldn't.
+    fn consume(&self, transaction: &Transaction, message: &MessageData) -> Result<(), String>;
+}
+
+/// What happens when a consumer fails.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum FanoutPolicy {
+    /// The message is refused with a temporary failure, and the next consumers don't get
+    /// it. The client will try again, so the consumers before may get it twice.
+    AllMustSucceed,
+    /// The failure is only counted, and the message is accepted anyway.
+    BestEffort
+}
+
+/// Hands a message to the consumers, in order.
+///
+/// Returns the failures, by index of the consumer. With `AllMustSucceed`, this stops at the
+/// first failure.
+pub fn run(consumers: &[Arc<MessageConsumer>], policy: FanoutPolicy, transaction: &Transaction, message: &MessageData) -> Vec<(usize, String)> {
+    let mut failures = Vec::new();
+    for (i, consumer) in consumers.iter().enumerate() {
+        if let Err(reason) = consumer.consume(transaction, message) {
+            failures.push((i, reason));
+            if policy == FanoutPolicy::AllMustSucceed {
+                break;
+            }
+        }
+    }
+    failures
+}
+
+#[cfg(test)]
+struct Recorder {
+    name: &'static str,
+    fail: bool,
+    log: Arc<Mutex<Vec<String>>>
+}
+
+#[cfg(test)]
+impl MessageConsumer for Recorder {
+    fn consume(&self, transaction: &Transaction, message: &MessageData) -> Result<(), String> {
+        self.log.lock().unwrap().push(format!("{} {} {}", self.name, transaction.recipients().len(), message.len()));
+        match self.fail {
+            true => Err("down".to_owned()),
+            false => Ok(())
+        }
+    }
+}
+
+#[test]
+fn test_run() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let consumers: Vec<Arc<MessageConsumer>> = vec![
+        Arc::new(Recorder { name: "maildir", fail: false, log: log.clone() }),
+        Arc::new(Recorder { name: "webhook", fail: true, log: log.clone() }),
+        Arc::new(Recorder { name: "index", fail: false, log: log.clone() })
+    ];
+    let mut transaction = Transaction::new();
+    transaction.add_recipient(Mailbox::parse("b@rustastic.org").unwrap());
+    let message = MessageData::Memory(b"Hello".to_vec());
+
+    assert_eq!(vec![(1, "down".to_owned())], run(consumers.as_ref(), FanoutPolicy::AllMustSucceed, &transaction, &message));
+    assert_eq!(vec!["maildir 1 5", "webhook 1 5"], *log.lock().unwrap());
+    log.lock().unwrap().clear();
+    assert_eq!(vec![(1, "down".to_owned())], run(consumers.as_ref(), FanoutPolicy::BestEffort, &transaction, &message));
+    assert_eq!(vec!["maildir 1 5", "webhook 1 5", "index 1 5"], *log.lock().unwrap());
+}
+
+#[test]
+fn test_fanout() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(mail::get());
+    server.add_command(rcpt::get());
+    server.add_command(data::get());
+    server.add_message_consumer(Recorder { name: "maildir", fail: false, log: log.clone() });
+    server.add_message_consumer(Recorder { name: "webhook", fail: true, log: log.clone() });
+    let mut session = TestSession::new();
+
+    for &(policy, reply) in [(FanoutPolicy::BestEffort, "250 2.0.0 OK"), (FanoutPolicy::AllMustSucceed, "451 4.3.0 Requested action aborted: local error in processing")].iter() {
+        server.set_fanout_policy(policy);
+        for &(line, reply) in [("MAIL FROM:<a@rustastic.org>", "250 2.1.0 OK"), ("RCPT TO:<b@rustastic.org>", "250 2.1.5 OK")].iter() {
+            Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+            assert_eq!(reply, session.reply());
+        }
+        session.send("Hello");
+        session.send(".");
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+        session.reply();
+        assert_eq!(reply, session.reply());
+        assert!(!container.transaction.is_started());
+    }
+    assert_eq!(4, log.lock().unwrap().len());
+}
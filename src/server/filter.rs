@@ -0,0 +1,139 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Filters that decide whether to accept a message from its content.
+//!
+//! Filters see the message as it arrives, so they can reject obviously bad messages
+//! before the client is done sending them, and once it is complete. A message rejected
+//! while DATA is in progress gets `554` right away and the connection is closed, which
+//! saves receiving the rest of a large payload.
+
+use std::borrow::ToOwned;
+use std::sync::Arc;
+use std::vec::Vec;
+
+/// The number of octets of message content received between two calls to
+/// `ContentFilter::check_chunk` during DATA.
+///
+/// With BDAT, filters are called with every chunk instead.
+pub static FILTER_CHUNK_SIZE: usize = 16384;
+
+/// The outcome of a filter.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum FilterVerdict {
+    /// Keep going, the message may be accepted.
+    Continue,
+    /// Reject the message with `554`, and the given text.
+    Reject(String)
+}
+
+/// Checks the content of messages.
+///
+/// Every method accepts by default, so you only need to implement the ones you use.
+pub trait ContentFilter: Send + Sync {
+    /// Called while the message is received, with the content received so far. `start`
+    /// is where the content that wasn't checked yet starts.
+    ///
+    /// With DATA, the content is already unstuffed but may end in the middle of a line.
+    #[allow(unused_variables)]
+    fn check_chunk(&self, received: &[u8], start: usize) -> FilterVerdict {
+        FilterVerdict::Continue
+    }
+
+    /// Called with the whole message, once it is received.
+    #[allow(unused_variables)]
+    fn check_message(&self, message: &[u8]) -> FilterVerdict {
+        FilterVerdict::Continue
+    }
+}
+
+/// Runs the filters on new content, until one of them rejects it.
+pub fn check_chunk(filters: &[Arc<ContentFilter>], received: &[u8], start: usize) -> FilterVerdict {
+    for filter in filters.iter() {
+        if let FilterVerdict::Reject(text) = filter.check_chunk(received, start) {
+            return FilterVerdict::Reject(text);
+        }
+    }
+    FilterVerdict::Continue
+}
+
+/// Runs the filters on a whole message, until one of them rejects it.
+pub fn check_message(filters: &[Arc<ContentFilter>], message: &[u8]) -> FilterVerdict {
+    for filter in filters.iter() {
+        if let FilterVerdict::Reject(text) = filter.check_message(message) {
+            return FilterVerdict::Reject(text);
+        }
+    }
+    FilterVerdict::Continue
+}
+
+/// Rejects messages containing a pattern, ie a known spam payload, as soon as it is seen.
+pub struct PatternFilter {
+    pattern: Vec<u8>,
+    text: String
+}
+
+impl PatternFilter {
+    /// Creates a filter rejecting messages containing the pattern, with the given text.
+    pub fn new(pattern: &[u8], text: &str) -> PatternFilter {
+        PatternFilter {
+            pattern: pattern.to_vec(),
+            text: text.to_owned()
+        }
+    }
+}
+
+impl ContentFilter for PatternFilter {
+    fn check_chunk(&self, received: &[u8], start: usize) -> FilterVerdict {
+        // The pattern may straddle the previous chunk.
+        let from = start.saturating_sub(self.pattern.len().saturating_sub(1));
+        match self.pattern.len() > 0 && received[from ..].windows(self.pattern.len()).any(|w| w == &self.pattern[..]) {
+            true => FilterVerdict::Reject(self.text.clone()),
+            false => FilterVerdict::Continue
+        }
+    }
+
+    fn check_message(&self, message: &[u8]) -> FilterVerdict {
+        // Content at the end of DATA may not have been seen in a chunk.
+        self.check_chunk(message, 0)
+    }
+}
+
+#[cfg(test)]
+struct SizeFilter;
+
+#[cfg(test)]
+impl ContentFilter for SizeFilter {
+    fn check_message(&self, message: &[u8]) -> FilterVerdict {
+        match message.len() > 10 {
+            true => FilterVerdict::Reject("Too big".to_owned()),
+            false => FilterVerdict::Continue
+        }
+    }
+}
+
+#[test]
+fn test_filters() {
+    let filters: Vec<Arc<ContentFilter>> = vec![
+        Arc::new(SizeFilter),
+        Arc::new(PatternFilter::new(b"spam", "Spam detected"))
+    ];
+    assert_eq!(FilterVerdict::Continue, check_chunk(&filters, b"hello sp", 0));
+    assert_eq!(FilterVerdict::Reject("Spam detected".to_owned()), check_chunk(&filters, b"hello spam", 8));
+    assert_eq!(FilterVerdict::Continue, check_chunk(&filters, b"spam, hello", 4));
+    assert_eq!(FilterVerdict::Continue, check_message(&filters, b"hello"));
+    assert_eq!(FilterVerdict::Reject("Spam detected".to_owned()), check_message(&filters, b"spam"));
+    assert_eq!(FilterVerdict::Reject("Too big".to_owned()), check_message(&filters, b"hello, world"));
+}
@@ -0,0 +1,217 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Greylisting, as described [in RFC 6647](http://tools.ietf.org/html/rfc6647).
+//!
+//! The first time a client sends from a sender to a recipient, RCPT gets `450`. Real
+//! mail servers try again later, and once the initial delay has passed, the recipient is
+//! accepted. Entries not seen for longer than the expiry are forgotten, so a triplet
+//! that keeps sending mail is only delayed once.
+//!
+//! Entries are kept by a `GreylistStore`, in memory by default. Servers sharing a
+//! store greylist together.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut greylist = Greylist::new(MemoryGreylistStore::new());
+//! greylist.set_delay(Duration::from_secs(300));
+//! server.set_greylist(greylist);
+//! ```
+
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use super::super::common::mailbox::Mailbox;
+#[cfg(test)]
+use std::net::Ipv4Addr;
+
+/// What a greylist is keyed on: the address of the client, the sender and the recipient.
+#[derive(PartialEq, Eq, Clone, Debug, Hash)]
+pub struct GreylistKey {
+    /// The address of the client.
+    pub ip: IpAddr,
+    /// The sender, lowercased, empty for the null sender.
+    pub sender: String,
+    /// The recipient, lowercased.
+    pub recipient: String
+}
+
+impl GreylistKey {
+    /// Creates the key of a recipient of a transaction.
+    pub fn new(ip: IpAddr, sender: Option<&Mailbox>, recipient: &Mailbox) -> GreylistKey {
+        GreylistKey {
+            ip: ip,
+            sender: sender.map_or(String::new(), |sender| sender.to_wire_string().to_ascii_lowercase()),
+            recipient: recipient.to_wire_string().to_ascii_lowercase()
+        }
+    }
+}
+
+/// What a greylist knows about a key.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct GreylistEntry {
+    /// When the key was first seen.
+    pub first_seen: SystemTime,
+    /// When the key was last seen.
+    pub last_seen: SystemTime,
+    /// Whether the key was let through once.
+    pub passed: bool
+}
+
+/// Keeps the entries of a greylist.
+pub trait GreylistStore: Send + Sync {
+    /// Returns the entry of a key, if any.
+    fn get(&self, key: &GreylistKey) -> Option<GreylistEntry>;
+
+    /// Sets the entry of a key.
+    fn put(&self, key: &GreylistKey, entry: GreylistEntry);
+
+    /// Forgets the entries last seen before the given time.
+    ///
+    /// Stores that expire entries on their own can ignore this.
+    fn expire(&self, before: SystemTime);
+}
+
+/// A `GreylistStore` in memory, which is lost when the server stops.
+pub struct MemoryGreylistStore {
+    entries: Mutex<HashMap<GreylistKey, GreylistEntry>>
+}
+
+impl MemoryGreylistStore {
+    /// Creates an empty store.
+    pub fn new() -> MemoryGreylistStore {
+        MemoryGreylistStore {
+            entries: Mutex::new(HashMap::new())
+        }
+    }
+}
+
+impl GreylistStore for MemoryGreylistStore {
+    fn get(&self, key: &GreylistKey) -> Option<GreylistEntry> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    fn put(&self, key: &GreylistKey, entry: GreylistEntry) {
+        self.entries.lock().unwrap().insert(key.clone(), entry);
+    }
+
+    fn expire(&self, before: SystemTime) {
+        self.entries.lock().unwrap().retain(|_, entry| entry.last_seen >= before);
+    }
+}
+
+/// What a `Greylist` decided for a recipient.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum GreylistDecision {
+    /// The recipient can be accepted.
+    Pass,
+    /// The recipient must be deferred. Gives how long before a retry passes.
+    Defer(Duration)
+}
+
+/// Defers the first attempts of new triplets, see `server::greylist`.
+#[derive(Clone)]
+pub struct Greylist {
+    store: Arc<GreylistStore>,
+    delay: Duration,
+    expiry: Duration,
+    checks: Arc<Mutex<usize>>
+}
+
+impl Greylist {
+    /// Creates a greylist keeping its entries in the store, with an initial delay of 5
+    /// minutes and an expiry of 36 days.
+    pub fn new<S: 'static + GreylistStore>(store: S) -> Greylist {
+        Greylist {
+            store: Arc::new(store),
+            delay: Duration::from_secs(5 * 60),
+            expiry: Duration::from_secs(36 * 24 * 3600),
+            checks: Arc::new(Mutex::new(0))
+        }
+    }
+
+    /// Sets how long after the first attempt a retry is accepted.
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    /// Sets how long an entry is kept after it was last seen.
+    pub fn set_expiry(&mut self, expiry: Duration) {
+        self.expiry = expiry;
+    }
+
+    /// Records an attempt for the key at the given time, and decides whether it passes.
+    pub fn check(&self, key: &GreylistKey, now: SystemTime) -> GreylistDecision {
+        // Forget the expired entries once in a while, so the store doesn't grow forever.
+        {
+            let mut checks = self.checks.lock().unwrap();
+            *checks += 1;
+            if *checks % 1024 == 0 {
+                self.store.expire(now - self.expiry);
+            }
+        }
+
+        let expired = |entry: &GreylistEntry| {
+            now.duration_since(entry.last_seen).map(|elapsed| elapsed > self.expiry).unwrap_or(false)
+        };
+        let mut entry = match self.store.get(key) {
+            Some(ref entry) if !expired(entry) => *entry,
+            _ => GreylistEntry { first_seen: now, last_seen: now, passed: false }
+        };
+        entry.last_seen = now;
+        let waited = now.duration_since(entry.first_seen).unwrap_or(Duration::from_secs(0));
+        let decision = match entry.passed || waited >= self.delay {
+            true => {
+                entry.passed = true;
+                GreylistDecision::Pass
+            },
+            false => GreylistDecision::Defer(self.delay - waited)
+        };
+        self.store.put(key, entry);
+        decision
+    }
+}
+
+#[test]
+fn test_greylist() {
+    let sender = Mailbox::parse("Rust@rustastic.org").unwrap();
+    let recipient = Mailbox::parse("smtp@rustastic.org").unwrap();
+    let key = GreylistKey::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), Some(&sender), &recipient);
+    assert_eq!("rust@rustastic.org", key.sender);
+    let other = GreylistKey::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)), None, &recipient);
+    assert_eq!("", other.sender);
+
+    let start = SystemTime::now();
+    let minute = Duration::from_secs(60);
+    let mut greylist = Greylist::new(MemoryGreylistStore::new());
+    greylist.set_delay(minute * 5);
+    greylist.set_expiry(minute * 60);
+
+    assert_eq!(GreylistDecision::Defer(minute * 5), greylist.check(&key, start));
+    assert_eq!(GreylistDecision::Defer(minute * 3), greylist.check(&key, start + minute * 2));
+    assert_eq!(GreylistDecision::Defer(minute * 5), greylist.check(&other, start + minute * 2));
+    assert_eq!(GreylistDecision::Pass, greylist.check(&key, start + minute * 5));
+    assert_eq!(GreylistDecision::Pass, greylist.check(&key, start + minute * 50));
+
+    // Once forgotten, a key is delayed again.
+    assert_eq!(GreylistDecision::Defer(minute * 5), greylist.check(&key, start + minute * 120));
+
+    let store = MemoryGreylistStore::new();
+    store.put(&key, GreylistEntry { first_seen: start, last_seen: start, passed: true });
+    store.expire(start + minute);
+    assert_eq!(None, store.get(&key));
+}
@@ -0,0 +1,184 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What a server does, as events that can be written to any log.
+//!
+//! Every event of a session carries the id of the session, see `Server::set_id_generator`,
+//! so the lines of concurrent sessions can be told apart. A server logs nothing until it
+//! is given a `Logger`:
+//!
+//! ```ignore
+//! server.set_logger(StdoutLogger);
+//! ```
+
+use std::net::SocketAddr;
+use super::SessionError;
+pub use super::super::common::transcript::redact;
+#[cfg(test)]
+use std::borrow::ToOwned;
+#[cfg(test)]
+use std::io::Write;
+#[cfg(test)]
+use std::net::{Shutdown, TcpListener, TcpStream};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use std::vec::Vec;
+#[cfg(test)]
+use super::Server;
+#[cfg(test)]
+use super::commands::mail;
+#[cfg(test)]
+use super::session::close_reason;
+#[cfg(test)]
+use super::testing::TestContainer;
+#[cfg(test)]
+use super::super::common::instrument::InstrumentedStream;
+#[cfg(test)]
+use super::super::common::stream::Transport;
+
+/// Receives the events of a server.
+///
+/// Every method does nothing by default, so you only need to implement the ones you use.
+pub trait Logger: Send + Sync {
+    /// Called when the server starts listening on an address.
+    #[allow(unused_variables)]
+    fn listening(&self, hostname: &str, address: &str) {}
+
+    /// Called when a session starts, before the greeting. `peer` is the address of the
+    /// client, when it is known.
+    #[allow(unused_variables)]
+    fn session_opened(&self, session: &str, peer: Option<SocketAddr>) {}
+
+    /// Called with each command line a client sent, with the credentials of `AUTH`
+    /// removed, see `redact`.
+    #[allow(unused_variables)]
+    fn command(&self, session: &str, line: &str) {}
+
+    /// Called after each command line, with the code of the last reply to it.
+    ///
+    /// `command` is the verb of the command, ie `MAIL`, or `None` when the line matched no
+    /// command.
+    #[allow(unused_variables)]
+    fn reply(&self, session: &str, command: Option<&str>, code: u16) {}
+
+    /// Called with the errors that end sessions, or prevent them from starting.
+    ///
+    /// `session` is `None` when the error happened before the session had an id, ie when
+    /// accepting the connection failed.
+    #[allow(unused_variables)]
+    fn error(&self, session: Option<&str>, err: &SessionError) {}
+
+    /// Called when a session is over, with why it ended, see `session::close_reason`.
+    #[allow(unused_variables)]
+    fn session_closed(&self, session: &str, reason: &str) {}
+}
+
+/// A `Logger` writing a line per event to the standard output.
+pub struct StdoutLogger;
+
+impl Logger for StdoutLogger {
+    fn listening(&self, hostname: &str, address: &str) {
+        println!("Server '{}' listening on {}...", hostname, address);
+    }
+
+    fn session_opened(&self, session: &str, peer: Option<SocketAddr>) {
+        match peer {
+            Some(peer) => println!("[{}] connection from {}", session, peer),
+            None => println!("[{}] connection", session)
+        }
+    }
+
+    fn command(&self, session: &str, line: &str) {
+        println!("[{}] > {}", session, line);
+    }
+
+    fn reply(&self, session: &str, _: Option<&str>, code: u16) {
+        println!("[{}] < {}", session, code);
+    }
+
+    fn error(&self, session: Option<&str>, err: &SessionError) {
+        println!("[{}] error: {:?}", session.unwrap_or("-"), err);
+    }
+
+    fn session_closed(&self, session: &str, reason: &str) {
+        println!("[{}] closed: {}", session, reason);
+    }
+}
+
+#[cfg(test)]
+struct TestLogger {
+    events: Arc<Mutex<Vec<(String, String)>>>
+}
+
+#[cfg(test)]
+impl TestLogger {
+    fn push(&self, session: &str, event: String) {
+        self.events.lock().unwrap().push((session.to_owned(), event));
+    }
+}
+
+#[cfg(test)]
+impl Logger for TestLogger {
+    fn session_opened(&self, session: &str, peer: Option<SocketAddr>) {
+        self.push(session, format!("opened {}", peer.unwrap().ip()));
+    }
+
+    fn command(&self, session: &str, line: &str) {
+        self.push(session, format!("> {}", line));
+    }
+
+    fn reply(&self, session: &str, command: Option<&str>, code: u16) {
+        self.push(session, format!("< {} {}", command.unwrap_or("-"), code));
+    }
+
+    fn error(&self, session: Option<&str>, err: &SessionError) {
+        self.push(session.unwrap(), format!("error {}", close_reason(err)));
+    }
+
+    fn session_closed(&self, session: &str, reason: &str) {
+        self.push(session, format!("closed {}", reason));
+    }
+}
+
+#[test]
+fn test_server_logger() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut server = Server::new(TestContainer::new());
+    server.set_hostname("rustastic.org").unwrap();
+    server.add_command(mail::get());
+    server.set_logger(TestLogger { events: events.clone() });
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    client.write_all(b"MAIL FROM:<rust@rustastic.org>\r\nAUTH PLAIN c2VjcmV0\r\n").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let input = Transport::Tcp(InstrumentedStream::new(stream.try_clone().unwrap()));
+    assert!(server.serve(input, Transport::Tcp(InstrumentedStream::new(stream))).is_err());
+
+    let events = events.lock().unwrap();
+    let session = events[0].0.clone();
+    assert!(session.len() > 0);
+    assert!(events.iter().all(|&(ref id, _)| *id == session));
+    assert_eq!(vec![
+        "opened 127.0.0.1",
+        "> MAIL FROM:<rust@rustastic.org>",
+        "< MAIL 503",
+        "> AUTH PLAIN ***",
+        "< - 500",
+        "error client_closed",
+        "closed client_closed"
+    ], events.iter().map(|&(_, ref event)| event.as_ref()).collect::<Vec<&str>>());
+}
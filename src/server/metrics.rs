@@ -0,0 +1,181 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Measurements reported by a server, so they can be exported to a monitoring system.
+
+use std::net::IpAddr;
+use std::time::Duration;
+use super::super::common::mailbox::Mailbox;
+#[cfg(test)]
+use std::borrow::ToOwned;
+#[cfg(test)]
+use std::collections::HashMap;
+#[cfg(test)]
+use std::io::{BufRead, BufReader, Write};
+#[cfg(test)]
+use std::net::{Shutdown, TcpListener, TcpStream};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use std::thread;
+#[cfg(test)]
+use std::vec::Vec;
+#[cfg(test)]
+use super::Server;
+#[cfg(test)]
+use super::commands::{helo, mail, rcpt, data};
+#[cfg(test)]
+use super::testing::TestContainer;
+#[cfg(test)]
+use super::super::common::instrument::InstrumentedStream;
+#[cfg(test)]
+use super::super::common::stream::Transport;
+
+/// Receives measurements from a server.
+///
+/// Every method does nothing by default, so you only need to implement the ones you use.
+pub trait Metrics: Send + Sync {
+    /// Called with the time spent in a middleware of a command, not counting the time spent
+    /// in the middleware that come after it.
+    ///
+    /// `command` is the verb of the command, ie `MAIL`, and `index` is the position of the
+    /// middleware in the command, starting at `0`. This is only called when the crate is
+    /// built with the `profiling` feature.
+    #[allow(unused_variables)]
+    fn middleware_timing(&self, command: &str, index: usize, duration: Duration) {}
+
+    /// Called when an event happens, to increment the counter with the given name.
+    ///
+    /// The counters are:
+    ///
+    /// * `sessions_opened`: a session started, before the greeting.
+    /// * `session_panics`: a session was torn down because of a panic.
+    /// * `sessions_rejected`: a client was turned away because all the workers were busy,
+    ///   or because of the connection limits.
+    /// * `tls_handshakes`: a client completed a STARTTLS handshake.
+    /// * `tls_resumptions`: a client completed a STARTTLS handshake resuming a session.
+    /// * `consumer_failures`: a `fanout::MessageConsumer` failed to handle a message.
+    /// * `commands_rejected`: a command line got a `4xx` or `5xx` reply.
+    /// * `messages_accepted`: a message was accepted at the end of its content.
+    /// * `messages_refused`: a message was refused at the end of its content.
+    #[allow(unused_variables)]
+    fn increment(&self, counter: &str) {}
+
+    /// Called with how long something took.
+    ///
+    /// The timings are:
+    ///
+    /// * `session_duration`: from the start of a session to its end, whatever ended it.
+    #[allow(unused_variables)]
+    fn timing(&self, name: &str, duration: Duration) {}
+
+    /// Called to add an amount to the counter with the given name.
+    ///
+    /// The counters, reported when a session ends, are:
+    ///
+    /// * `bytes_received`: the octets read from the client.
+    /// * `bytes_sent`: the octets written to the client.
+    #[allow(unused_variables)]
+    fn add(&self, counter: &str, amount: u64) {}
+
+    /// Called when an event happens to a host, to increment the counter of that host with
+    /// the given name.
+    ///
+    /// The counters, reported by `client::smarthost::SmarthostPool`, are:
+    ///
+    /// * `smarthost_selected`: the host was picked to deliver a message.
+    /// * `smarthost_failures`: a delivery or a probe failed.
+    /// * `smarthost_ejections`: the host was ejected after failing.
+    /// * `smarthost_recoveries`: the host got its full weight back after an ejection.
+    #[allow(unused_variables)]
+    fn increment_host(&self, counter: &str, host: &str) {}
+
+    /// Called after each command line, with the code of the last reply to it.
+    ///
+    /// `command` is the verb of the command, ie `MAIL`, or `None` when the line matched no
+    /// command. `client` is the address of the client, when it is known.
+    #[allow(unused_variables)]
+    fn reply(&self, command: Option<&str>, code: u16, client: Option<IpAddr>) {}
+
+    /// Called when a message was accepted or refused at the end of its content.
+    ///
+    /// `sender` is the reverse path given with MAIL, `None` for the null sender.
+    #[allow(unused_variables)]
+    fn message(&self, client: Option<IpAddr>, sender: Option<&Mailbox>, accepted: bool) {}
+}
+
+#[cfg(test)]
+struct TestMetrics {
+    counters: Arc<Mutex<HashMap<String, u64>>>,
+    timings: Arc<Mutex<Vec<(String, Duration)>>>
+}
+
+#[cfg(test)]
+impl Metrics for TestMetrics {
+    fn increment(&self, counter: &str) {
+        self.add(counter, 1);
+    }
+
+    fn add(&self, counter: &str, amount: u64) {
+        *self.counters.lock().unwrap().entry(counter.to_owned()).or_insert(0) += amount;
+    }
+
+    fn timing(&self, name: &str, duration: Duration) {
+        self.timings.lock().unwrap().push((name.to_owned(), duration));
+    }
+}
+
+#[test]
+fn test_session_metrics() {
+    let counters = Arc::new(Mutex::new(HashMap::new()));
+    let timings = Arc::new(Mutex::new(Vec::new()));
+    let mut server = Server::new(TestContainer::new());
+    server.set_hostname("rustastic.org").unwrap();
+    server.add_command(helo::get());
+    server.add_command(mail::get());
+    server.add_command(rcpt::get());
+    server.add_command(data::get());
+    server.set_metrics(TestMetrics { counters: counters.clone(), timings: timings.clone() });
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let commands = b"HELO rustastic.org\r\nJUNK\r\nMAIL FROM:<a@rustastic.org>\r\nRCPT TO:<b@rustastic.org>\r\nDATA\r\n";
+    client.write_all(commands).unwrap();
+    // The message is only sent after the 354 reply, so the server runs in another thread.
+    let server_thread = thread::spawn(move || {
+        let input = Transport::Tcp(InstrumentedStream::new(stream.try_clone().unwrap()));
+        server.serve(input, Transport::Tcp(InstrumentedStream::new(stream))).is_err()
+    });
+    let mut replies = BufReader::new(client.try_clone().unwrap());
+    let mut line = String::new();
+    while !line.starts_with("354") {
+        line.clear();
+        replies.read_line(&mut line).unwrap();
+    }
+    client.write_all(b"Subject: Hi\r\n\r\nHi\r\n.\r\n").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    assert!(server_thread.join().unwrap());
+
+    let counters = counters.lock().unwrap();
+    assert_eq!(Some(&1), counters.get("sessions_opened"));
+    assert_eq!(Some(&1), counters.get("commands_rejected"));
+    assert_eq!(Some(&1), counters.get("messages_accepted"));
+    assert_eq!(None, counters.get("messages_refused"));
+    assert_eq!(Some(&(commands.len() as u64 + 22)), counters.get("bytes_received"));
+    assert!(counters.get("bytes_sent").unwrap() > &0);
+    let timings = timings.lock().unwrap();
+    assert_eq!(1, timings.len());
+    assert_eq!("session_duration", timings[0].0);
+}
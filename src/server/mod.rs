@@ -17,24 +17,212 @@
 
 extern crate libc;
 
-use super::common::stream::{InputStream, OutputStream};
+use super::common::stream::{InputStream, OutputStream, Transport, SessionStream, is_timeout};
+use super::common::tls::{TlsAcceptor, TlsOptions};
+use super::common::message::HeaderLimits;
+use super::common::delay::DelayPolicy;
+use super::common::id::{IdGenerator, Ulid, message_id};
+use super::common::instrument::{InstrumentedStream, StreamStats};
+use super::common::Reply;
+use super::common::dns::Resolver;
+use super::common::status::EnhancedStatusCode;
+use super::common::{MIN_ALLOWED_RECIPIENTS, MIN_ALLOWED_MESSAGE_SIZE, MIN_ALLOWED_TIMEOUT};
 use std::net::{TcpListener, TcpStream};
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::io::{Write, ErrorKind};
 use std::io::Result as IoResult;
+use std::io::Error as IoError;
 use std::thread;
-use std::borrow::ToOwned;
+use std::cmp;
+use std::panic::{self, AssertUnwindSafe};
+use std::any::Any;
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::borrow::{Cow, ToOwned};
+use std::ascii::AsciiExt;
 use std::sync::Arc;
 use std::ops::Deref;
 use std::clone::Clone;
-use std::os::unix::io::{FromRawFd, AsRawFd};
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use self::dedup::{DuplicateWindow, DuplicateAction};
+use self::metrics::Metrics;
+use self::logger::{Logger, redact};
+use super::common::transcript::Transcript;
+use self::policy::Condition;
+use self::transaction::{AbortFn, TransactionGuard, is_aborting_reply};
+use self::session::{SessionState, SessionSummary, Phase, close_reason};
+use self::subaddress::SubaddressPolicy;
+use self::operator::OperatorAddresses;
+use self::dkim::DkimValidator;
+use self::rdns::lookup_timeout;
+use self::greylist::Greylist;
+use self::pool::WorkerPool;
+use self::filter::ContentFilter;
+use self::evented::EventLoop;
+use self::connections::{ConnectionCounter, ConnectionPermit};
+use self::ratelimit::RateLimiter;
+use self::tempstore::TempStore;
+use self::fanout::{MessageConsumer, FanoutPolicy};
+#[cfg(test)]
+use self::testing::{TestSession, TestContainer, MemoryStream};
+#[cfg(test)]
+use std::iter::repeat;
+use super::common::mailbox::Mailbox;
+use super::common::utils::get_domain;
+#[cfg(test)]
+use super::common::id::Snowflake;
+#[cfg(test)]
+use std::io::{BufRead, BufReader};
+#[cfg(test)]
+use std::net::Shutdown;
+#[cfg(test)]
+use std::net::Ipv6Addr;
+#[cfg(test)]
+use std::{env, fs};
+#[cfg(test)]
+use super::client::SmtpClient;
 
 /// Core SMTP commands
 pub mod commands;
 
+/// Mail transaction state
+pub mod transaction;
+
+/// Duplicate submission detection
+pub mod dedup;
+
+/// Server measurements
+pub mod metrics;
+
+/// Logging of server events
+pub mod logger;
+
+/// Reply histograms and top talkers
+pub mod analytics;
+
+/// Conditions for middleware and commands
+pub mod policy;
+
+/// Accept-and-discard server preset
+pub mod sink;
+
+/// Policy state shared by several instances
+pub mod replication;
+
+/// Session state and ordering checks
+pub mod session;
+
+/// Subaddress routing
+pub mod subaddress;
+
+/// Always accepted operator addresses
+pub mod operator;
+
+/// Verification of DKIM signatures
+pub mod dkim;
+
+/// DMARC policies of sender domains
+pub mod dmarc;
+
+/// Reverse DNS of clients
+pub mod rdns;
+
+/// Greylisting of new senders
+pub mod greylist;
+
+/// Worker threads for connections
+pub mod pool;
+
+/// Content filters
+pub mod filter;
+
+/// Event loop runtime
+mod evented;
+
+/// Concurrent connection counting
+pub mod connections;
+
+/// Rate limiting of clients
+pub mod ratelimit;
+
+/// Temporary files of transactions
+pub mod tempstore;
+
+/// The PROXY protocol of load balancers
+pub mod proxy;
+
+/// Handing accepted messages to several consumers
+pub mod fanout;
+
+/// Tamper-evident logs of handled messages
+pub mod audit;
+
+/// Publishing accepted messages to message queues
+pub mod publish;
+
+/// Store-and-forward queue of accepted messages
+pub mod queue;
+
+/// Relaying accepted messages to smarthosts or mail exchangers
+pub mod relay;
+
+/// Loading the configuration from TOML files
+pub mod config;
+
+#[cfg(feature = "profiling")]
+mod profiling;
+
+#[cfg(test)]
+mod testing;
+
 extern {
     fn gethostname(name: *mut libc::c_char, size: libc::size_t) -> libc::c_int;
 }
 
+// Holds new connections in the kernel until the client sends data, or the timeout expires.
+#[cfg(target_os = "linux")]
+fn set_accept_filter(listener: &TcpListener, timeout: Duration) -> IoResult<()> {
+    let seconds = cmp::max(timeout.as_secs(), 1) as libc::c_int;
+    let res = unsafe {
+        libc::setsockopt(listener.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT,
+            &seconds as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(IoError::last_os_error())
+    }
+}
+
+// The `dataready` filter has no timeout, connections are held until the client speaks.
+#[cfg(target_os = "freebsd")]
+fn set_accept_filter(listener: &TcpListener, _: Duration) -> IoResult<()> {
+    let mut arg: libc::accept_filter_arg = unsafe { ::std::mem::zeroed() };
+    for (i, byte) in b"dataready".iter().enumerate() {
+        arg.af_name[i] = *byte as libc::c_char;
+    }
+    let res = unsafe {
+        libc::setsockopt(listener.as_raw_fd(), libc::SOL_SOCKET, libc::SO_ACCEPTFILTER,
+            &arg as *const libc::accept_filter_arg as *const libc::c_void,
+            size_of::<libc::accept_filter_arg>() as libc::socklen_t)
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(IoError::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+fn set_accept_filter(_: &TcpListener, _: Duration) -> IoResult<()> {
+    Ok(())
+}
+
 fn rust_gethostname() -> Result<String, ()> {
     let len = 255;
     let mut buf = Vec::<u8>::with_capacity(len);
@@ -70,45 +258,155 @@ fn rust_gethostname() -> Result<String, ()> {
     }
 }
 
+// Decides whether a middleware runs.
+enum Guard<CT, ST> {
+    // Skip the middleware unless the condition holds.
+    When(Condition<CT, ST>),
+    // Stop the command with the reply unless the condition holds.
+    Require(Condition<CT, ST>, Reply)
+}
+
+impl<CT, ST> Clone for Guard<CT, ST> {
+    fn clone(&self) -> Guard<CT, ST> {
+        match *self {
+            Guard::When(ref condition) => Guard::When(condition.clone()),
+            Guard::Require(ref condition, ref reply) => Guard::Require(condition.clone(), reply.clone())
+        }
+    }
+}
+
 /// Gives access to the next middleware for a command.
 pub struct NextMiddleware<CT, ST> {
     callback: MiddlewareFn<CT, ST>,
-    next: Box<Option<NextMiddleware<CT, ST>>>
+    next: Box<Option<NextMiddleware<CT, ST>>>,
+    index: usize,
+    guard: Option<Guard<CT, ST>>
 }
 
 impl<CT, ST> Clone for NextMiddleware<CT, ST> {
     fn clone(&self) -> NextMiddleware<CT, ST> {
         NextMiddleware {
             callback: self.callback,
-            next: self.next.clone()
+            next: self.next.clone(),
+            index: self.index,
+            guard: self.guard.clone()
         }
     }
 }
 
-impl<CT, ST> NextMiddleware<CT, ST> {
+impl<CT, ST: Write> NextMiddleware<CT, ST> {
+    /// Call a command middleware.
+    #[cfg(not(feature = "profiling"))]
+    pub fn call(&self, config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, i: &mut InputStream<ST>, o: &mut OutputStream<ST>, l: &str) {
+        self.call_callback(config, container, state, i, o, l);
+    }
+
     /// Call a command middleware.
-    pub fn call(&self, config: &ServerConfig<CT>, container: &mut CT, i: &mut InputStream<ST>, o: &mut OutputStream<ST>, l: &str) {
-        match *self.next {
-            Some(ref next) => {
-                (self.callback)(config, container, i, o, l, Some(next.clone()));
+    #[cfg(feature = "profiling")]
+    pub fn call(&self, config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, i: &mut InputStream<ST>, o: &mut OutputStream<ST>, l: &str) {
+        profiling::measure(self.index, || self.call_callback(config, container, state, i, o, l));
+    }
+
+    fn call_callback(&self, config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, i: &mut InputStream<ST>, o: &mut OutputStream<ST>, l: &str) {
+        let next = match *self.next {
+            Some(ref next) => Some(next.clone()),
+            None => None
+        };
+        match self.guard {
+            Some(Guard::When(ref condition)) if !condition.holds(config, container, i, l) => {
+                if let Some(next) = next {
+                    next.call(config, container, state, i, o, l);
+                }
             },
-            None => {
-                (self.callback)(config, container, i, o, l, None);
+            Some(Guard::Require(ref condition, ref reply)) if !condition.holds(config, container, i, l) => {
+                o.write_reply(reply).unwrap();
+            },
+            _ => {
+                (self.callback)(config, container, state, i, o, l, next);
             }
         }
     }
 }
 
+// The middleware behind `Command::require`, which only calls the next one.
+fn pass<CT, ST: Write>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, i: &mut InputStream<ST>, o: &mut OutputStream<ST>, l: &str, next: Option<NextMiddleware<CT, ST>>) {
+    if let Some(next) = next {
+        next.call(config, container, state, i, o, l);
+    }
+}
+
+// Returns the number of whole milliseconds in the duration.
+fn to_millis(duration: Duration) -> usize {
+    duration.as_secs() as usize * 1000 + duration.subsec_nanos() as usize / 1000000
+}
+
+// Tells whether the line starts with `start`, ignoring ASCII case.
+//
+// Non-ASCII bytes only match themselves, so the line can be sliced after `start`.
+fn starts_with_ignore_case(line: &str, start: &str) -> bool {
+    line.len() >= start.len() && line.as_bytes()[.. start.len()].eq_ignore_ascii_case(start.as_bytes())
+}
+
+// Tells whether the line starts with the start string of a command, as a whole word, so
+// that `DATAX` isn't taken for `DATA`.
+fn matches_start(line: &str, start: &str) -> bool {
+    if !starts_with_ignore_case(line, start) {
+        return false;
+    }
+    let whole_word = match start.chars().last() {
+        Some(c) => !c.is_alphanumeric(),
+        None => true
+    };
+    whole_word || line.len() == start.len() || line.as_bytes()[start.len()] == b' '
+}
+
+#[test]
+fn test_matches_start() {
+    assert!(matches_start("DATA", "DATA"));
+    assert!(matches_start("data x", "DATA"));
+    assert!(!matches_start("DATAX", "DATA"));
+    assert!(matches_start("MAIL FROM:<a@rustastic.org>", "MAIL FROM:"));
+    assert!(matches_start("HELO rustastic.org", "HELO "));
+}
+
+#[test]
+fn test_starts_with_ignore_case() {
+    assert!(starts_with_ignore_case("MAIL FROM:<a@rustastic.org>", "MAIL FROM:"));
+    assert!(starts_with_ignore_case("mail from:<a@rustastic.org>", "MAIL FROM:"));
+    assert!(starts_with_ignore_case("Ehlo", "EHLO"));
+    assert!(!starts_with_ignore_case("EHL", "EHLO"));
+    assert!(!starts_with_ignore_case("HELO", "EHLO"));
+    assert!(!starts_with_ignore_case("MAİL FROM:", "MAIL FROM:"));
+}
+
 /// A command middleware callback.
 pub type MiddlewareFn<CT, ST> = fn(
-    &ServerConfig<CT>,
+    &ServerConfig<CT, ST>,
     &mut CT,
+    &mut SessionState,
     &mut InputStream<ST>,
     &mut OutputStream<ST>,
     &str,
     Option<NextMiddleware<CT, ST>>
 ) -> ();
 
+/// Returns the path in the argument of a command, ie `<a@b>` in `<a@b> BODY=8BITMIME`.
+pub type PathFn = fn(&str) -> &str;
+
+/// What a command accepts after its verb.
+///
+/// See `Server::set_strict_syntax` for how arguments to commands that take none are
+/// treated.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum ArgumentPolicy {
+    /// Anything, passed to the middleware, which checks it.
+    Any,
+    /// Nothing, ie for RSET.
+    Forbidden,
+    /// Anything, but the middleware never sees it, ie for NOOP.
+    Ignored
+}
+
 /// An email server command.
 ///
 /// It is defined by the string you find at the start of the command, for
@@ -117,31 +415,133 @@ pub type MiddlewareFn<CT, ST> = fn(
 pub struct Command<CT, ST> {
     start: Option<String>,
     front_middleware: Option<NextMiddleware<CT, ST>>,
+    help: Option<String>,
+    extensions: Vec<String>,
+    on_failure: Option<AbortFn<CT>>,
+    max_line_size: Option<usize>,
+    line_size_increases: Vec<(String, usize)>,
+    max_path_size: Option<(usize, PathFn)>,
+    arguments: ArgumentPolicy,
+    last_in_group: bool
 }
 
 impl<CT, ST> Clone for Command<CT, ST> {
     fn clone(&self) -> Command<CT, ST> {
         Command {
             start: self.start.clone(),
-            front_middleware: self.front_middleware.clone()
+            front_middleware: self.front_middleware.clone(),
+            help: self.help.clone(),
+            extensions: self.extensions.clone(),
+            on_failure: self.on_failure,
+            max_line_size: self.max_line_size,
+            line_size_increases: self.line_size_increases.clone(),
+            max_path_size: self.max_path_size,
+            arguments: self.arguments,
+            last_in_group: self.last_in_group
         }
     }
 }
 
-impl<CT, ST> Command<CT, ST> {
+impl<CT, ST: Write> Command<CT, ST> {
     /// Creates a new command
     pub fn new() -> Command<CT, ST> {
         Command {
             start: None,
-            front_middleware: None
+            front_middleware: None,
+            help: None,
+            extensions: Vec::new(),
+            on_failure: None,
+            max_line_size: None,
+            line_size_increases: Vec::new(),
+            max_path_size: None,
+            arguments: ArgumentPolicy::Any,
+            last_in_group: false
         }
     }
 
     /// Describes the start of the command line for this command.
+    ///
+    /// Command lines are matched regardless of case, as described
+    /// [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-2.4), but the rest of the
+    /// line is passed to the middleware untouched.
     pub fn starts_with(&mut self, start: &str) {
         self.start = Some(start.to_owned());
     }
 
+    /// Sets the text returned by `HELP <verb>` for this command.
+    ///
+    /// The text may span multiple lines, separated by `\n`.
+    pub fn help(&mut self, text: &str) {
+        self.help = Some(text.to_owned());
+    }
+
+    /// Marks an SMTP extension as provided by this command.
+    ///
+    /// The extension is advertised in the output of the EHLO command once the command is
+    /// added to a server.
+    pub fn extension(&mut self, extension: &str) {
+        self.extensions.push(extension.to_owned());
+    }
+
+    /// Sets the maximum size of the command line, including `<CRLF>`.
+    ///
+    /// Defaults to the maximum command line size of the server, which is 512 octets
+    /// [as per RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.5.3.1.4). Longer
+    /// lines are rejected before any middleware runs.
+    pub fn max_line_size(&mut self, size: usize) {
+        self.max_line_size = Some(size);
+    }
+
+    /// Allows command lines longer by the given number of octets when the server
+    /// advertises the extension, ie for the parameters it adds to this command.
+    pub fn increase_max_line_size(&mut self, extension: &str, bytes: usize) {
+        self.line_size_increases.push((extension.to_owned(), bytes));
+    }
+
+    /// Sets the maximum size of the path in the argument of this command, including the
+    /// angle brackets.
+    ///
+    /// Longer paths are rejected before any middleware runs.
+    pub fn max_path_size(&mut self, size: usize, path: PathFn) {
+        self.max_path_size = Some((size, path));
+    }
+
+    /// Sets what the command accepts after its verb.
+    ///
+    /// Defaults to `ArgumentPolicy::Any`.
+    pub fn arguments(&mut self, policy: ArgumentPolicy) {
+        self.arguments = policy;
+    }
+
+    /// Marks this command as one that must end a group of pipelined commands, as
+    /// described [in RFC 2920](http://tools.ietf.org/html/rfc2920#section-3.1).
+    ///
+    /// The command is rejected if the client sent anything after it without waiting for
+    /// the reply.
+    pub fn last_in_group(&mut self) {
+        self.last_in_group = true;
+    }
+
+    /// Sets the function that aborts the transaction when this command fails.
+    ///
+    /// The session loop calls it when the command replies with a failure, see
+    /// `transaction::is_aborting_reply`. It is also called when the session ends, even
+    /// because of a connection error or a panic.
+    pub fn on_failure(&mut self, abort: AbortFn<CT>) {
+        self.on_failure = Some(abort);
+    }
+
+    /// Returns the verb of this command, ie `MAIL` for a command starting with `MAIL FROM:`.
+    fn verb(&self) -> Option<&str> {
+        self.start.as_ref().map(|start| {
+            let s = start.as_str();
+            match s.find(|c: char| c == ' ' || c == ':') {
+                Some(pos) => &s[.. pos],
+                None => s
+            }
+        })
+    }
+
     fn last_middleware<'a>(prev: &'a mut NextMiddleware<CT, ST>) -> &'a mut NextMiddleware<CT, ST> {
         match *prev.next {
             None => prev,
@@ -151,19 +551,61 @@ impl<CT, ST> Command<CT, ST> {
 
     /// Add a middleware to call for this command.
     pub fn middleware(&mut self, callback: MiddlewareFn<CT, ST>) {
-        // The upcoming item in the middleware chain.
-        let next = Some(NextMiddleware {
+        self.push_middleware(callback, None);
+    }
+
+    /// Add a middleware to call before all the others, ie to throttle a built in command.
+    pub fn middleware_first(&mut self, callback: MiddlewareFn<CT, ST>) {
+        let mut next = self.front_middleware.take();
+        {
+            let mut middleware = next.as_mut();
+            while let Some(current) = middleware {
+                current.index += 1;
+                middleware = (*current.next).as_mut();
+            }
+        }
+        self.front_middleware = Some(NextMiddleware {
             callback: callback,
-            next: Box::new(None)
+            next: Box::new(next),
+            index: 0,
+            guard: None
         });
+    }
+
+    /// Add a middleware that is only called when the condition holds.
+    ///
+    /// When it doesn't, the middleware is skipped and the next one is called instead.
+    pub fn when(&mut self, condition: Condition<CT, ST>, callback: MiddlewareFn<CT, ST>) {
+        self.push_middleware(callback, Some(Guard::When(condition)));
+    }
 
+    /// Stop the command with the given reply unless the condition holds.
+    ///
+    /// The condition is checked at this point of the middleware chain, so it can rely on
+    /// the checks of the previous middleware.
+    pub fn require(&mut self, condition: Condition<CT, ST>, reply: Reply) {
+        self.push_middleware(pass, Some(Guard::Require(condition, reply)));
+    }
+
+    fn push_middleware(&mut self, callback: MiddlewareFn<CT, ST>, guard: Option<Guard<CT, ST>>) {
         // Get the current last item, so we can append the new item.
         match self.front_middleware {
             None => {
-                self.front_middleware = next;
+                self.front_middleware = Some(NextMiddleware {
+                    callback: callback,
+                    next: Box::new(None),
+                    index: 0,
+                    guard: guard
+                });
             },
             Some(_) => {
-                Command::last_middleware(self.front_middleware.as_mut().unwrap()).next = Box::new(next);
+                let last = Command::last_middleware(self.front_middleware.as_mut().unwrap());
+                last.next = Box::new(Some(NextMiddleware {
+                    callback: callback,
+                    next: Box::new(None),
+                    index: last.index + 1,
+                    guard: guard
+                }));
             }
         }
     }
@@ -175,20 +617,66 @@ impl<CT, ST> Command<CT, ST> {
 }
 
 /// An SMTP server configuration.
-pub struct ServerConfig<CT> {
+///
+/// Sessions run over streams of type `ST`, see `Server::with_stream`.
+pub struct ServerConfig<CT, ST = Transport> {
     hostname: String,
     max_recipients: usize,
     max_message_size: usize,
     max_command_line_size: usize,
     max_text_line_size: usize,
-    commands: Vec<Command<CT, TcpStream>>,
-    extensions: Vec<String>
+    command_timeout: Duration,
+    data_timeout: Duration,
+    header_limits: HeaderLimits,
+    utf8_policy: Utf8Policy,
+    control_policy: ControlPolicy,
+    auth_mechanisms: Vec<AuthMechanism>,
+    commands: Vec<Command<CT, ST>>,
+    extensions: Vec<String>,
+    duplicates: Option<Arc<DuplicateWindow>>,
+    filters: Vec<Arc<ContentFilter>>,
+    consumers: Vec<Arc<MessageConsumer>>,
+    fanout_policy: FanoutPolicy,
+    metrics: Option<Arc<Metrics>>,
+    logger: Option<Arc<Logger>>,
+    delay_policy: Option<Arc<DelayPolicy>>,
+    tls: Option<Arc<TlsAcceptor>>,
+    tls_options: TlsOptions,
+    trusted_networks: Vec<(IpAddr, u8)>,
+    no_mail: bool,
+    strict_syntax: bool,
+    max_errors: Option<usize>,
+    workers: Option<(usize, usize)>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    connections: Arc<ConnectionCounter>,
+    command_rate: Option<Arc<RateLimiter>>,
+    message_rate: Option<Arc<RateLimiter>>,
+    temp_store: Option<Arc<TempStore>>,
+    spill_size: usize,
+    proxy_protocol: bool,
+    accept_filter: Option<Duration>,
+    no_mail_domains: Vec<String>,
+    subaddresses: Option<SubaddressPolicy>,
+    operators: OperatorAddresses,
+    dkim: Option<DkimValidator>,
+    reverse_dns: Option<(Arc<Resolver>, Duration)>,
+    greylist: Option<Greylist>,
+    ids: Arc<IdGenerator>,
+    abort: Option<AbortFn<CT>>,
+    on_panic: Option<Arc<PanicHook>>,
+    on_error: Option<Arc<ErrorHook>>,
+    on_summary: Option<Arc<SummaryHook>>,
+    on_transcript: Option<TranscriptFn<CT>>,
+    non_conforming_limits: bool,
+    addresses: Vec<(IpAddr, u16)>,
+    tls_files: Option<(PathBuf, PathBuf)>
 }
 
-impl<CT> Clone for ServerConfig<CT> {
-    fn clone(&self) -> ServerConfig<CT> {
-        // TcpStream is non clonable, which seems to disturb the compiler, so we clone
-        // the commands vector (which is made of commands that take a TcpStream) manually.
+impl<CT, ST> Clone for ServerConfig<CT, ST> {
+    fn clone(&self) -> ServerConfig<CT, ST> {
+        // Streams are non clonable, which seems to disturb the compiler, so we clone
+        // the commands vector (which is made of commands that take a stream) manually.
         let mut cloned_commands = Vec::with_capacity(self.commands.len());
         for c in self.commands.iter() {
             cloned_commands.push(c.clone());
@@ -200,215 +688,2386 @@ impl<CT> Clone for ServerConfig<CT> {
             max_message_size: self.max_message_size,
             max_command_line_size: self.max_command_line_size,
             max_text_line_size: self.max_text_line_size,
+            command_timeout: self.command_timeout,
+            data_timeout: self.data_timeout,
+            header_limits: self.header_limits,
+            utf8_policy: self.utf8_policy,
+            control_policy: self.control_policy,
+            auth_mechanisms: self.auth_mechanisms.clone(),
             commands: cloned_commands,
-            extensions: self.extensions.clone()
+            extensions: self.extensions.clone(),
+            duplicates: self.duplicates.clone(),
+            filters: self.filters.clone(),
+            consumers: self.consumers.clone(),
+            fanout_policy: self.fanout_policy,
+            metrics: self.metrics.clone(),
+            logger: self.logger.clone(),
+            delay_policy: self.delay_policy.clone(),
+            tls: self.tls.clone(),
+            tls_options: self.tls_options.clone(),
+            trusted_networks: self.trusted_networks.clone(),
+            no_mail: self.no_mail,
+            strict_syntax: self.strict_syntax,
+            max_errors: self.max_errors,
+            workers: self.workers,
+            max_connections: self.max_connections,
+            max_connections_per_ip: self.max_connections_per_ip,
+            connections: self.connections.clone(),
+            command_rate: self.command_rate.clone(),
+            message_rate: self.message_rate.clone(),
+            temp_store: self.temp_store.clone(),
+            spill_size: self.spill_size,
+            proxy_protocol: self.proxy_protocol,
+            accept_filter: self.accept_filter,
+            no_mail_domains: self.no_mail_domains.clone(),
+            subaddresses: self.subaddresses.clone(),
+            operators: self.operators.clone(),
+            dkim: self.dkim.clone(),
+            reverse_dns: self.reverse_dns.clone(),
+            greylist: self.greylist.clone(),
+            ids: self.ids.clone(),
+            abort: self.abort,
+            on_panic: self.on_panic.clone(),
+            on_error: self.on_error.clone(),
+            on_summary: self.on_summary.clone(),
+            on_transcript: self.on_transcript,
+            non_conforming_limits: self.non_conforming_limits,
+            addresses: self.addresses.clone(),
+            tls_files: self.tls_files.clone()
         }
     }
 }
 
-/// An SMTP server, with no commands by default.
-pub struct Server<CT> {
-    config: ServerConfig<CT>,
-    container: CT
-}
+impl<CT, ST> ServerConfig<CT, ST> {
+    /// Creates a configuration with the defaults, and no commands.
+    pub fn new() -> ServerConfig<CT, ST> {
+        ServerConfig {
+            hostname: String::new(),
+            max_recipients: 100,
+            max_message_size: 65536,
+            max_command_line_size: 512,
+            max_text_line_size: 1000,
+            command_timeout: Duration::from_secs(300),
+            data_timeout: Duration::from_secs(600),
+            header_limits: HeaderLimits {
+                max_section_size: 65536,
+                max_count: 1000,
+                max_field_size: 16384
+            },
+            utf8_policy: Utf8Policy::Accept,
+            control_policy: ControlPolicy::Accept,
+            auth_mechanisms: vec![AuthMechanism::Plain, AuthMechanism::Login],
+            commands: Vec::with_capacity(16),
+            extensions: Vec::with_capacity(16),
+            duplicates: None,
+            filters: Vec::new(),
+            consumers: Vec::new(),
+            fanout_policy: FanoutPolicy::AllMustSucceed,
+            metrics: None,
+            logger: None,
+            delay_policy: None,
+            tls: None,
+            tls_options: TlsOptions::new(),
+            trusted_networks: Vec::new(),
+            no_mail: false,
+            strict_syntax: false,
+            max_errors: None,
+            workers: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            connections: Arc::new(ConnectionCounter::new()),
+            command_rate: None,
+            message_rate: None,
+            temp_store: None,
+            spill_size: 0,
+            proxy_protocol: false,
+            accept_filter: None,
+            no_mail_domains: Vec::new(),
+            subaddresses: None,
+            operators: OperatorAddresses::new(),
+            dkim: None,
+            reverse_dns: None,
+            greylist: None,
+            ids: Arc::new(Ulid::new()),
+            abort: None,
+            on_panic: None,
+            on_error: None,
+            on_summary: None,
+            on_transcript: None,
+            non_conforming_limits: false,
+            addresses: Vec::new(),
+            tls_files: None
+        }
+    }
 
-/// An error that occures when a server starts up
-#[derive(PartialEq, Eq, Clone, Debug, Copy)]
-pub enum ServerError {
-    /// The hostname could not be retrieved from the system
-    Hostname,
-    /// Could not bind the socket
-    Bind,
-    /// Could not listen on the socket
-    Listen
-}
+    /// Sets the name of the server, used in the greeting and in replies to EHLO.
+    ///
+    /// The name must be a domain, ie `mx.rustastic.org`. When it is empty, servers that
+    /// listen use the hostname of the system.
+    pub fn set_hostname(&mut self, hostname: &str) -> Result<(), ConfigError> {
+        if hostname.len() > 0 && get_domain(hostname) != Some(hostname) {
+            return Err(ConfigError::Malformed);
+        }
+        self.hostname = hostname.to_owned();
+        Ok(())
+    }
 
-/// Tells whether an error occured during server setup.
-pub type ServerResult<T> = Result<T, ServerError>;
+    /// Returns the name of the server, which is empty until it listens if none was set.
+    pub fn hostname(&self) -> &str {
+        self.hostname.as_ref()
+    }
 
-// TODO: logging, via a Trait on the container?
-// TODO: fatal error handling
+    /// Allows limits below the minimums required by the RFC.
+    ///
+    /// This is meant for constrained deployments that know their clients, and for tests.
+    /// It only applies to the limits set after it.
+    pub fn allow_non_conforming_limits(&mut self) {
+        self.non_conforming_limits = true;
+    }
 
-impl<CT: 'static + Send + Sync + Clone> Server<CT> {
-    /// Creates a new SMTP server.
+    /// Tells whether limits below the minimums required by the RFC are allowed.
+    pub fn allows_non_conforming_limits(&self) -> bool {
+        self.non_conforming_limits
+    }
+
+    /// Sets the maximum number of recipients of a transaction.
     ///
-    /// The container can be of any type and can be used to get access to a
-    /// bunch of things inside your commands, like database connections,
-    /// a logger and more.
-    pub fn new(container: CT) -> Server<CT> {
-        Server {
-            config: ServerConfig {
-                hostname: String::new(),
-                max_recipients: 100,
-                max_message_size: 65536,
-                max_command_line_size: 512,
-                max_text_line_size: 1000,
-                commands: Vec::with_capacity(16),
-                extensions: Vec::with_capacity(16)
-            },
-            container: container
-        }
+    /// Defaults to 100, which is also the minimum required by the RFC.
+    pub fn set_max_recipients(&mut self, max: usize) -> Result<(), ConfigError> {
+        try!(self.check_limit(max, MIN_ALLOWED_RECIPIENTS));
+        self.max_recipients = max;
+        Ok(())
     }
 
-    fn set_hostname(&mut self, hostname: &str) {
-        self.config.hostname = hostname.to_owned();
+    /// Returns the maximum number of recipients of a transaction.
+    pub fn max_recipients(&self) -> usize {
+        self.max_recipients
     }
 
-    fn set_max_recipients(&mut self, max: usize) {
-        if max < 100 {
-            panic!("Maximum number of recipients must be >= 100.");
-        }
-        self.config.max_recipients = max;
+    /// Sets the maximum size of a message, in octets.
+    ///
+    /// Defaults to 65536, which is also the minimum required by the RFC.
+    pub fn set_max_message_size(&mut self, max: usize) -> Result<(), ConfigError> {
+        try!(self.check_limit(max, MIN_ALLOWED_MESSAGE_SIZE));
+        self.max_message_size = max;
+        Ok(())
     }
 
-    fn set_max_message_size(&mut self, max: usize) {
-        if max < 65536 {
-            panic!("Maximum message size must be >= 65536.");
-        }
-        self.config.max_message_size = max;
+    /// Returns the maximum size of a message, in octets.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
     }
 
-    /// Adds a command to the server.
-    pub fn add_command(&mut self, command: Command<CT, TcpStream>) {
-        self.config.commands.push(command);
+    /// Returns the maximum size of a command line, including the room made by commands
+    /// for the extensions they implement.
+    pub fn max_command_line_size(&self) -> usize {
+        self.max_command_line_size
     }
 
-    // TODO: allow saying which extensions are supported by this server
-    // for use in EHLO response.
+    /// Returns the maximum size of a line of a message.
+    pub fn max_text_line_size(&self) -> usize {
+        self.max_text_line_size
+    }
 
-    fn increase_max_command_line_size(&mut self, bytes: usize) {
-        self.config.max_command_line_size += bytes;
+    /// Sets how long the server waits for the next command.
+    ///
+    /// Defaults to 5 minutes, which is also the minimum required by the RFC.
+    pub fn set_command_timeout(&mut self, timeout: Duration) -> Result<(), ConfigError> {
+        try!(self.check_limit(to_millis(timeout), MIN_ALLOWED_TIMEOUT));
+        self.command_timeout = timeout;
+        Ok(())
     }
 
-    fn increase_max_text_line_size(&mut self, bytes: usize) {
-        self.config.max_text_line_size += bytes;
+    /// Returns how long the server waits for the next command.
+    pub fn command_timeout(&self) -> Duration {
+        self.command_timeout
     }
 
-    /// Marks an SMTP extension as "supported" by the server.
+    /// Sets how long the server waits for more of a message.
     ///
-    /// This is used in the output of the EHLO command.
-    pub fn add_extension(&mut self, extension: &str) {
-        self.config.extensions.push(extension.to_owned());
+    /// Defaults to 10 minutes. The minimum is the same as for `set_command_timeout`.
+    pub fn set_data_timeout(&mut self, timeout: Duration) -> Result<(), ConfigError> {
+        try!(self.check_limit(to_millis(timeout), MIN_ALLOWED_TIMEOUT));
+        self.data_timeout = timeout;
+        Ok(())
     }
 
-    fn get_hostname_from_system(&mut self) -> ServerResult<String> {
-        match rust_gethostname() {
-            Ok(s) => {
-                Ok(s)
+    /// Returns how long the server waits for more of a message.
+    pub fn data_timeout(&self) -> Duration {
+        self.data_timeout
+    }
+
+    /// Sets the maximum size of the header section of a message, in octets.
+    pub fn set_max_header_section_size(&mut self, max: usize) -> Result<(), ConfigError> {
+        try!(self.check_limit(max, 1));
+        self.header_limits.max_section_size = max;
+        Ok(())
+    }
+
+    /// Sets the maximum number of header fields in a message.
+    pub fn set_max_header_count(&mut self, max: usize) -> Result<(), ConfigError> {
+        try!(self.check_limit(max, 1));
+        self.header_limits.max_count = max;
+        Ok(())
+    }
+
+    /// Sets the maximum size of a single header field, including folded lines, in octets.
+    pub fn set_max_header_field_size(&mut self, max: usize) -> Result<(), ConfigError> {
+        try!(self.check_limit(max, 1));
+        self.header_limits.max_field_size = max;
+        Ok(())
+    }
+
+    /// Returns the limits on the header section of messages.
+    pub fn header_limits(&self) -> HeaderLimits {
+        self.header_limits
+    }
+
+    /// Sets how many replies that are `500` or `503` a client can get in a row, see
+    /// `Server::set_max_errors_per_session`.
+    pub fn set_max_errors_per_session(&mut self, max: usize) -> Result<(), ConfigError> {
+        try!(self.check_limit(max, 1));
+        self.max_errors = Some(max);
+        Ok(())
+    }
+
+    /// Returns how many errors a client can get in a row, if there is a limit.
+    pub fn max_errors_per_session(&self) -> Option<usize> {
+        self.max_errors
+    }
+
+    /// Sets the maximum number of connections the server handles at once.
+    pub fn set_max_connections(&mut self, max: usize) -> Result<(), ConfigError> {
+        try!(self.check_limit(max, 1));
+        self.max_connections = Some(max);
+        Ok(())
+    }
+
+    /// Returns the maximum number of connections, if there is a limit.
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    /// Sets the maximum number of connections the server handles at once from the same
+    /// address.
+    pub fn set_max_connections_per_ip(&mut self, max: usize) -> Result<(), ConfigError> {
+        try!(self.check_limit(max, 1));
+        self.max_connections_per_ip = Some(max);
+        Ok(())
+    }
+
+    /// Returns the maximum number of connections from the same address, if there is a
+    /// limit.
+    pub fn max_connections_per_ip(&self) -> Option<usize> {
+        self.max_connections_per_ip
+    }
+
+    /// Marks an SMTP extension as "supported", see `Server::add_extension`.
+    pub fn add_extension(&mut self, extension: &str) {
+        let keyword = extension.split(' ').next().unwrap_or("").to_ascii_uppercase();
+        let position = self.extensions.iter().position(|existing| {
+            existing.split(' ').next().unwrap_or("").to_ascii_uppercase() == keyword
+        });
+        match position {
+            Some(position) => {
+                self.extensions[position] = extension.to_owned();
             },
-            Err(_) => {
-                Err(ServerError::Hostname)
+            None => {
+                self.extensions.push(extension.to_owned());
             }
         }
     }
 
-    fn get_listener_for_address(&mut self, address: (IpAddr, u16)) -> ServerResult<TcpListener> {
-        match TcpListener::bind(address) {
-            Ok(listener) => Ok(listener),
-            Err(_) => Err(ServerError::Bind)
-        }
-    }
-
-    fn handle_commands(config: &ServerConfig<CT>, input: &mut InputStream<TcpStream>, output: &mut OutputStream<TcpStream>, container: &mut CT) {
-        'main: loop {
-            let line = match input.read_line() {
-                Ok(buffer) => {
-                    // The commands expect a regular human readable string.
-                    // Also, we need to make this an owned string because
-                    // the stream uses the same buffer for command lines and
-                    // text lines.
-                    //
-                    // TODO: use a different buffer for text lines and command
-                    // lines?
-                    String::from_utf8_lossy(buffer).into_owned()
-                },
-                Err(err) => {
-                    panic!("Could not read command: {}", err);
-                }
-            };
+    /// Returns the extensions advertised in replies to EHLO, as their EHLO lines.
+    pub fn extensions(&self) -> &[String] {
+        self.extensions.as_ref()
+    }
 
-            // Find the right handler for this command line.
-            for command in config.commands.iter() {
-                // The right command starts with whatever we have set
-                // when we created the command. We use unwrap here, but
-                // the commands are checked before the server starts
-                // so this is always OK.
-                match command.start {
-                    Some(ref start) => {
-                        let ls = line.as_str();
-                        // TODO: make this case insensitive
-                        if ls.starts_with(start.as_str()) {
-                            match command.front_middleware {
-                                Some(ref next) => {
-                                    next.call(config, container, input, output, &ls[start.len() ..]);
-                                },
-                                None => {
-                                    // TODO: improve error message
-                                    panic!("Found a command with no middleware");
-                                }
-                            }
-                            continue 'main;
-                        }
-                    },
-                    None => {
-                        // TODO: improve error message
-                        panic!("Found a command with no start string");
-                    }
-                }
-            }
+    /// Adds an address to listen on, see `Server::listen_all`.
+    pub fn add_address(&mut self, ip: IpAddr, port: u16) {
+        self.addresses.push((ip, port));
+    }
 
-            // If we get here, it means that no command matched.
-            output.write_line("500 Command unrecognized").unwrap();
-        }
-    }
-
-    fn handle_connection(&self, stream_res: IoResult<TcpStream>, config: &Arc<ServerConfig<CT>>) {
-        let config = config.clone();
-        let mut container = self.container.clone();
-        let thread_handle = thread::spawn(move || {
-            match stream_res {
-                Ok(stream) => {
-                    // Clone the stream. This uses "unsafe" but is safe because we use this
-                    // stream only for reading and the other one only for writing.
-                    let mut input = InputStream::new(unsafe {
-                        TcpStream::from_raw_fd(stream.as_raw_fd())
-                    }, 1000, false);
-                    let mut output = OutputStream::new(stream, false);
-
-                    Server::<CT>::handle_commands(
-                        config.deref(),
-                        &mut input,
-                        &mut output,
-                        &mut container
-                    );
-                },
-                Err(err) => {
-                    panic!("Could not accept client: {}", err);
-                }
-            }
-        });
-        println!("Connection being handled in thread: {:?}", thread_handle.thread().name());
+    /// Returns the addresses to listen on.
+    pub fn addresses(&self) -> &[(IpAddr, u16)] {
+        self.addresses.as_ref()
     }
 
-    /// Start the SMTP server on the given address and port.
-    pub fn listen(&mut self, ip: IpAddr, port: u16) -> ServerResult<()> {
-        // TODO: check that commands all are valid, meaning they have at least
-        // a key word (ie HELO) and at least 1 middleware.
+    /// Sets the paths of the TLS certificate chain and private key, see `tls_files`.
+    pub fn set_tls_files<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, certificate: P, key: Q) {
+        self.tls_files = Some((certificate.as_ref().to_path_buf(), key.as_ref().to_path_buf()));
+    }
 
-        if self.config.hostname.len() == 0 {
-            self.config.hostname = try!(self.get_hostname_from_system());
+    fn check_limit(&self, value: usize, min: usize) -> Result<(), ConfigError> {
+        if value == 0 {
+            return Err(ConfigError::Zero);
+        }
+        match value < min && !self.non_conforming_limits {
+            true => Err(ConfigError::BelowMinimum(min)),
+            false => Ok(())
         }
+    }
 
-        let listener = try!(self.get_listener_for_address((ip, port)));
+    /// Returns the paths of the TLS certificate chain and private key, if any.
+    ///
+    /// The server doesn't read them, they are meant for building the `TlsAcceptor` given
+    /// to `Server::set_tls_acceptor`.
+    pub fn tls_files(&self) -> Option<(&Path, &Path)> {
+        self.tls_files.as_ref().map(|&(ref certificate, ref key)| (certificate.as_ref(), key.as_ref()))
+    }
 
-        println!("Server '{}' listening on {}:{}...", self.config.hostname, ip, port);
+    // Tells whether the server advertises the extension with the given keyword.
+    fn has_extension(&self, keyword: &str) -> bool {
+        self.extensions.iter().any(|existing| {
+            existing.split(' ').next().unwrap_or("").eq_ignore_ascii_case(keyword)
+        })
+    }
 
-        let config = Arc::new(self.config.clone());
+    /// Returns a new id from the generator of the server.
+    ///
+    /// See `Server::set_id_generator`.
+    pub fn generate_id(&self) -> String {
+        self.ids.generate()
+    }
 
-        for conn in listener.incoming() {
-            self.handle_connection(conn, &config);
-        }
+    /// Returns a new Message-ID in the domain of the server, ie
+    /// `<01ARZ3NDEKTSV4RRFFQ69G5FAV@rustastic.org>`.
+    pub fn message_id(&self) -> String {
+        message_id(&*self.ids, self.hostname.as_ref())
+    }
 
-        Ok(())
+    /// Returns the store for temporary files under the spool, if any.
+    ///
+    /// See `Server::set_spool`.
+    pub fn temp_store(&self) -> Option<&TempStore> {
+        self.temp_store.as_ref().map(|store| &**store)
     }
 }
+
+/// An SMTP server, with no commands by default.
+///
+/// Sessions run over a `Transport` unless the server is created with `with_stream`.
+pub struct Server<CT, ST = Transport> {
+    config: ServerConfig<CT, ST>,
+    container: CT
+}
+
+/// An error that occures when a server starts up
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum ServerError {
+    /// The hostname could not be retrieved from the system
+    Hostname,
+    /// Could not bind the socket
+    Bind,
+    /// Could not listen on the socket
+    Listen
+}
+
+/// An error that ends a session, or prevents it from starting.
+#[derive(Debug)]
+pub enum SessionError {
+    /// A connection could not be accepted.
+    Accept(IoError),
+    /// The streams of an accepted connection could not be set up.
+    Setup(IoError),
+    /// Reading from the client failed, because the client closed the connection, the line
+    /// was too long, or there was another network error.
+    Read(IoError),
+    /// Writing to the client failed.
+    Write(IoError),
+    /// A command has no middleware, and can't handle anything.
+    InvalidCommand(String),
+    /// The client sent nothing for longer than the timeout, see
+    /// `Server::set_command_timeout` and `Server::set_data_timeout`.
+    Timeout,
+    /// The client got too many `500` or `503` replies in a row, see
+    /// `Server::set_max_errors_per_session`.
+    TooManyErrors,
+    /// All the workers were busy and their queue was full, see `Server::set_worker_pool`.
+    TooBusy,
+    /// The server or the client address had too many connections, see
+    /// `Server::set_max_connections` and `Server::set_max_connections_per_ip`.
+    TooManyConnections,
+    /// A `ContentFilter` rejected a message during DATA, before the client finished
+    /// sending it.
+    Rejected,
+    /// The connection didn't start with a valid PROXY header, see
+    /// `Server::set_proxy_protocol`.
+    Proxy(IoError)
+}
+
+/// An error that occurs when a server setting is invalid
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum ConfigError {
+    /// The value is below the minimum required by the RFC, which is given
+    ///
+    /// See `Server::allow_non_conforming_limits`.
+    BelowMinimum(usize),
+    /// The value is zero, which would reject every transaction
+    Zero,
+    /// The value isn't well formed, ie a hostname that isn't a domain
+    Malformed
+}
+
+/// How to treat message content that isn't valid UTF-8.
+///
+/// This applies to DATA and BDAT on sessions that didn't declare 8-bit content.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum Utf8Policy {
+    /// Reject the message with `500`.
+    Reject,
+    /// Accept the message as is.
+    Accept,
+    /// Accept the message, replacing invalid sequences with U+FFFD.
+    Replace
+}
+
+/// How to treat NUL and other control characters in command lines and message content.
+///
+/// In command lines, all the control characters of US-ASCII count, including DEL. In
+/// message content, tabs, line breaks and form feeds are allowed.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum ControlPolicy {
+    /// Reject the command with `500`, or the message with `554`.
+    Reject,
+    /// Remove the control characters.
+    Strip,
+    /// Accept the control characters as they are.
+    Accept
+}
+
+impl ControlPolicy {
+    /// Applies the policy to some text, where the `allowed` control characters don't
+    /// count. Returns `None` if the text must be rejected.
+    pub fn apply<'a>(&self, text: &'a [u8], allowed: &[u8]) -> Option<Cow<'a, [u8]>> {
+        let is_control = |byte: &u8| (*byte < 0x20 || *byte == 0x7f) && !allowed.contains(byte);
+        if *self == ControlPolicy::Accept || !text.iter().any(&is_control) {
+            return Some(Cow::Borrowed(text));
+        }
+        match *self {
+            ControlPolicy::Strip => Some(Cow::Owned(text.iter().filter(|byte| !is_control(byte)).cloned().collect())),
+            _ => None
+        }
+    }
+}
+
+#[test]
+fn test_control_policy() {
+    assert_eq!(Some(&b"NO\x00OP\t"[..]), ControlPolicy::Accept.apply(b"NO\x00OP\t", b"").as_ref().map(|text| text.as_ref()));
+    assert_eq!(Some(&b"NOOP"[..]), ControlPolicy::Strip.apply(b"NO\x00OP\t\x7f", b"").as_ref().map(|text| text.as_ref()));
+    assert_eq!(Some(&b"a\tb\r\n"[..]), ControlPolicy::Strip.apply(b"a\x1b\tb\r\n", b"\t\r\n").as_ref().map(|text| text.as_ref()));
+    assert_eq!(None, ControlPolicy::Reject.apply(b"NO\x00OP", b""));
+    assert_eq!(Some(&b"NOOP"[..]), ControlPolicy::Reject.apply(b"NOOP", b"").as_ref().map(|text| text.as_ref()));
+}
+
+/// A SASL mechanism supported by the AUTH command.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum AuthMechanism {
+    /// The PLAIN mechanism, as described [in RFC 4616](http://tools.ietf.org/html/rfc4616).
+    Plain,
+    /// The LOGIN mechanism, which is not standardized but widely used.
+    Login,
+    /// The CRAM-MD5 mechanism, as described [in RFC 2195](http://tools.ietf.org/html/rfc2195).
+    CramMd5
+}
+
+impl AuthMechanism {
+    /// Returns the name of the mechanism, as used in the AUTH command.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AuthMechanism::Plain => "PLAIN",
+            AuthMechanism::Login => "LOGIN",
+            AuthMechanism::CramMd5 => "CRAM-MD5"
+        }
+    }
+}
+
+// How the commands of a session ended, by an error or a panic.
+type SessionResult = thread::Result<Result<(), SessionError>>;
+
+/// A function called with the payload of a panic that tore down a session.
+pub type PanicHook = Fn(&(Any + Send)) + Send + Sync;
+
+/// A function called with the errors that end sessions, see `Server::set_on_error`.
+pub type ErrorHook = Fn(&SessionError) + Send + Sync;
+
+/// A function called with the summary of every session, see `Server::set_on_summary`.
+pub type SummaryHook = Fn(&SessionSummary) + Send + Sync;
+
+/// A function handing the transcript of a session to the container, see
+/// `Server::set_transcript_handler`.
+pub type TranscriptFn<CT> = fn(&mut CT, &Transcript);
+
+/// Tells whether an error occured during server setup.
+pub type ServerResult<T> = Result<T, ServerError>;
+
+impl<CT: 'static + Send + Sync + Clone, ST: SessionStream> Server<CT, ST> {
+    /// Creates a new SMTP server, for sessions over streams of type `ST`.
+    ///
+    /// Such a server can't listen, sessions are run with `serve`, ie over in-memory
+    /// streams in tests or over connections accepted elsewhere. See `new` for servers
+    /// that accept connections themselves.
+    pub fn with_stream(container: CT) -> Server<CT, ST> {
+        Server::with_config(container, ServerConfig::new())
+    }
+
+    /// Creates a new SMTP server with the given configuration, ie one loaded with
+    /// `ServerConfig::from_toml_file`.
+    pub fn with_config(container: CT, config: ServerConfig<CT, ST>) -> Server<CT, ST> {
+        Server {
+            config: config,
+            container: container
+        }
+    }
+
+    /// Returns the configuration of the server.
+    pub fn config(&self) -> &ServerConfig<CT, ST> {
+        &self.config
+    }
+
+    /// Sets the name of the server, used in the greeting and in replies to EHLO.
+    ///
+    /// By default, servers that listen use the hostname of the system, see
+    /// `ServerConfig::set_hostname`.
+    pub fn set_hostname(&mut self, hostname: &str) -> Result<(), ConfigError> {
+        self.config.set_hostname(hostname)
+    }
+
+    /// Allows limits below the minimums required by the RFC.
+    ///
+    /// This is meant for constrained deployments that know their clients, and for tests
+    /// that exercise the limits without sending hundreds of recipients. Clients that rely
+    /// on the minimums may fail to deliver mail. It only applies to the limits set after
+    /// it.
+    pub fn allow_non_conforming_limits(&mut self) {
+        self.config.allow_non_conforming_limits();
+    }
+
+    /// Sets the maximum number of recipients of a transaction.
+    ///
+    /// The RFC requires at least 100, see `allow_non_conforming_limits` to go below.
+    pub fn set_max_recipients(&mut self, max: usize) -> Result<(), ConfigError> {
+        self.config.set_max_recipients(max)
+    }
+
+    /// Sets the maximum size of a message, in octets.
+    ///
+    /// The RFC requires at least 65536, see `allow_non_conforming_limits` to go below.
+    pub fn set_max_message_size(&mut self, max: usize) -> Result<(), ConfigError> {
+        self.config.set_max_message_size(max)
+    }
+
+    /// Sets how long the server waits for the next command.
+    ///
+    /// Defaults to 5 minutes, which is also the minimum required by the RFC, see
+    /// `allow_non_conforming_limits` to go below. `ConfigError::BelowMinimum` gives the
+    /// minimum in milliseconds.
+    pub fn set_command_timeout(&mut self, timeout: Duration) -> Result<(), ConfigError> {
+        self.config.set_command_timeout(timeout)
+    }
+
+    /// Sets how long the server waits for more of a message, with DATA, BDAT or XZDAT.
+    ///
+    /// Defaults to 10 minutes. The minimum is the same as for `set_command_timeout`.
+    pub fn set_data_timeout(&mut self, timeout: Duration) -> Result<(), ConfigError> {
+        self.config.set_data_timeout(timeout)
+    }
+
+    /// Sets the maximum size of the header section of a message, in octets.
+    ///
+    /// Messages with a larger header section are rejected with `552` at the end of DATA.
+    pub fn set_max_header_section_size(&mut self, max: usize) -> Result<(), ConfigError> {
+        self.config.set_max_header_section_size(max)
+    }
+
+    /// Sets the maximum number of header fields in a message.
+    pub fn set_max_header_count(&mut self, max: usize) -> Result<(), ConfigError> {
+        self.config.set_max_header_count(max)
+    }
+
+    /// Sets the maximum size of a single header field, including folded lines, in octets.
+    pub fn set_max_header_field_size(&mut self, max: usize) -> Result<(), ConfigError> {
+        self.config.set_max_header_field_size(max)
+    }
+
+    /// Sets how to treat message content that isn't valid UTF-8.
+    ///
+    /// Defaults to `Utf8Policy::Accept`.
+    pub fn set_utf8_policy(&mut self, policy: Utf8Policy) {
+        self.config.utf8_policy = policy;
+    }
+
+    /// Sets how to treat control characters, ie NUL, in command lines and message content.
+    ///
+    /// Defaults to `ControlPolicy::Accept`.
+    pub fn set_control_policy(&mut self, policy: ControlPolicy) {
+        self.config.control_policy = policy;
+    }
+
+    /// Sets the mechanisms offered by the AUTH command, in order of preference.
+    ///
+    /// Defaults to PLAIN and LOGIN. CRAM-MD5 requires `AuthHandler::get_secret`.
+    pub fn set_auth_mechanisms(&mut self, mechanisms: &[AuthMechanism]) {
+        self.config.auth_mechanisms = mechanisms.to_vec();
+    }
+
+    /// Sets a function called with the payload of panics that tear down a session.
+    ///
+    /// A panic, usually in a handler of the container, only ends the session it happened
+    /// in. The client gets a `421` reply if possible.
+    pub fn set_on_panic<F: 'static + Fn(&(Any + Send)) + Send + Sync>(&mut self, hook: F) {
+        self.config.on_panic = Some(Arc::new(hook));
+    }
+
+    /// Sets a function called with the errors that end sessions, or prevent them from
+    /// starting.
+    ///
+    /// The client gets a `421` reply first when the connection is still usable. A client
+    /// closing the connection is reported too, as `SessionError::Read`.
+    pub fn set_on_error<F: 'static + Fn(&SessionError) + Send + Sync>(&mut self, hook: F) {
+        self.config.on_error = Some(Arc::new(hook));
+    }
+
+    /// Sets a function called once every session is over, with a summary of what it did,
+    /// ie for billing or capacity planning.
+    ///
+    /// It is called after the hooks for errors and panics.
+    pub fn set_on_summary<F: 'static + Fn(&SessionSummary) + Send + Sync>(&mut self, hook: F) {
+        self.config.on_summary = Some(Arc::new(hook));
+    }
+
+    /// Records the transcript of every session, and hands it to the container once the
+    /// session is over, see `common::transcript`.
+    ///
+    /// This is meant for debugging clients, since the lines of a session are kept in
+    /// memory until it ends. Messages are only recorded by their size.
+    pub fn set_transcript_handler(&mut self, handler: TranscriptFn<CT>) {
+        self.config.on_transcript = Some(handler);
+    }
+
+    /// Sets where the server reports its measurements.
+    pub fn set_metrics<M: 'static + Metrics>(&mut self, metrics: M) {
+        self.config.metrics = Some(Arc::new(metrics));
+    }
+
+    /// Sets where the server logs its events, see `server::logger`. Nothing is logged by
+    /// default.
+    pub fn set_logger<L: 'static + Logger>(&mut self, logger: L) {
+        self.config.logger = Some(Arc::new(logger));
+    }
+
+    /// Sets the policy deciding how long to wait before each reply, see `common::delay`.
+    ///
+    /// This is meant for honeypots and anti-spam research, legitimate clients give up
+    /// after the timeouts of [RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.5.3.2).
+    pub fn set_delay_policy<P: 'static + DelayPolicy>(&mut self, policy: P) {
+        self.config.delay_policy = Some(Arc::new(policy));
+    }
+
+    /// Sets how the server performs TLS handshakes, which enables STARTTLS.
+    pub fn set_tls_acceptor<A: 'static + TlsAcceptor>(&mut self, acceptor: A) {
+        self.config.tls = Some(Arc::new(acceptor));
+    }
+
+    /// Sets the options given to the `TlsAcceptor` for every handshake.
+    ///
+    /// Defaults to `TlsOptions::new()`.
+    pub fn set_tls_options(&mut self, options: TlsOptions) {
+        self.config.tls_options = options;
+    }
+
+    /// Marks a network as trusted, for use with `policy::is_trusted_network`.
+    ///
+    /// The network is given by an address and a prefix length, ie `192.168.1.0` and `24`.
+    pub fn add_trusted_network(&mut self, network: IpAddr, prefix: u8) {
+        self.config.trusted_networks.push((network, prefix));
+    }
+
+    /// Ends sessions with `421` once a client got `max` replies in a row that are `500` or
+    /// `503`, which is what clients sending junk get.
+    ///
+    /// There is no limit by default.
+    pub fn set_max_errors_per_session(&mut self, max: usize) -> Result<(), ConfigError> {
+        self.config.set_max_errors_per_session(max)
+    }
+
+    /// Operates the server in "this host accepts no mail" mode, as described
+    /// [in RFC 7504](http://tools.ietf.org/html/rfc7504#section-3).
+    ///
+    /// The greeting and every MAIL command get a `521` reply.
+    pub fn accept_no_mail(&mut self) {
+        self.config.no_mail = true;
+    }
+
+    /// Marks a domain as accepting no mail, ie with a null MX record as described
+    /// [in RFC 7505](http://tools.ietf.org/html/rfc7505).
+    ///
+    /// Recipients in that domain get a `556` reply.
+    pub fn add_no_mail_domain(&mut self, domain: &str) {
+        self.config.no_mail_domains.push(domain.to_owned());
+    }
+
+    /// Sets how recipients are split into a base address and a detail, ie
+    /// `rust+smtp@rustastic.org`.
+    ///
+    /// The detail is given to the `RcptHandler` in `RcptParameters`.
+    pub fn set_subaddress_policy(&mut self, policy: SubaddressPolicy) {
+        self.config.subaddresses = Some(policy);
+    }
+
+    /// Sets the operator addresses that are always accepted as recipients, see
+    /// `server::operator`.
+    ///
+    /// By default, only `Postmaster` at the hostname of the server is.
+    pub fn set_operator_addresses(&mut self, operators: OperatorAddresses) {
+        self.config.operators = operators;
+    }
+
+    /// Checks the DKIM signatures of every message before it is handed to the
+    /// `DataHandler`, which finds the outcomes in the transaction, see `server::dkim`.
+    pub fn set_dkim_validator(&mut self, validator: DkimValidator) {
+        self.config.dkim = Some(validator);
+    }
+
+    /// Looks up the name of every client before the greeting, giving up after the
+    /// timeout. Middleware find the outcome in the session state, see `server::rdns`.
+    pub fn set_reverse_dns<R: 'static + Resolver>(&mut self, resolver: R, timeout: Duration) {
+        self.config.reverse_dns = Some((Arc::new(resolver), timeout));
+    }
+
+    /// Defers the recipients of new triplets of client, sender and recipient with `450`,
+    /// see `server::greylist`.
+    ///
+    /// Operator addresses and clients from trusted networks are never greylisted.
+    pub fn set_greylist(&mut self, greylist: Greylist) {
+        self.config.greylist = Some(greylist);
+    }
+
+    /// Sets how ids are minted, for sessions and Message-ID headers.
+    ///
+    /// Defaults to ULIDs, see the `id` module for other generators.
+    pub fn set_id_generator<G: 'static + IdGenerator>(&mut self, ids: G) {
+        self.config.ids = Arc::new(ids);
+    }
+
+    /// Handles connections with a fixed number of worker threads, instead of a thread per
+    /// connection.
+    ///
+    /// At most `queue` connections wait for a worker. When they are all busy and the queue
+    /// is full, new clients get `421` right away.
+    pub fn set_worker_pool(&mut self, workers: usize, queue: usize) -> Result<(), ConfigError> {
+        if workers == 0 {
+            return Err(ConfigError::Zero);
+        }
+        self.config.workers = Some((workers, queue));
+        Ok(())
+    }
+
+    /// Sets the maximum number of connections the server handles at once.
+    ///
+    /// Clients beyond that get `421` right away.
+    pub fn set_max_connections(&mut self, max: usize) -> Result<(), ConfigError> {
+        self.config.set_max_connections(max)
+    }
+
+    /// Sets the maximum number of connections the server handles at once from the same
+    /// address.
+    ///
+    /// Clients beyond that get `421` right away.
+    pub fn set_max_connections_per_ip(&mut self, max: usize) -> Result<(), ConfigError> {
+        self.config.set_max_connections_per_ip(max)
+    }
+
+    /// Sets the limiter used by `ratelimit::throttle_commands`.
+    pub fn set_command_rate_limit(&mut self, limiter: RateLimiter) {
+        self.config.command_rate = Some(Arc::new(limiter));
+    }
+
+    /// Sets the limiter used by `ratelimit::throttle_messages`.
+    pub fn set_message_rate_limit(&mut self, limiter: RateLimiter) {
+        self.config.message_rate = Some(Arc::new(limiter));
+    }
+
+    /// Spills messages of at least `spill_size` octets to temporary files in the directory,
+    /// instead of keeping them in memory.
+    pub fn set_spool<P: AsRef<Path>>(&mut self, dir: P, spill_size: usize) {
+        self.config.temp_store = Some(Arc::new(TempStore::new(dir)));
+        self.config.spill_size = spill_size;
+    }
+
+    /// Asks the system to only hand connections to the server once the client sent data,
+    /// or once the timeout expired, where this is supported. This keeps scanners that
+    /// connect and never speak away from the workers.
+    ///
+    /// In SMTP the server speaks first, so this only helps on ports where clients speak
+    /// first, ie behind a proxy sending a PROXY header, see `set_proxy_protocol`. Elsewhere
+    /// it delays every session by the timeout. For plain SMTP, `listen_evented` sends the
+    /// greeting and only wakes a worker once the client answers.
+    pub fn set_accept_filter(&mut self, timeout: Duration) -> Result<(), ConfigError> {
+        if timeout == Duration::from_secs(0) {
+            return Err(ConfigError::Zero);
+        }
+        self.config.accept_filter = Some(timeout);
+        Ok(())
+    }
+
+    /// Expects every connection to start with a PROXY header, and uses the client address
+    /// it gives instead of the address of the proxy, see `proxy`.
+    ///
+    /// Only enable this when all connections come through the proxy, since anyone can
+    /// send a header. Connection limits still apply to the address of the proxy, because
+    /// they are checked before the header is read.
+    pub fn set_proxy_protocol(&mut self, proxy_protocol: bool) {
+        self.config.proxy_protocol = proxy_protocol;
+    }
+
+    /// Sets how strictly command lines are parsed.
+    ///
+    /// By default, whitespace at the end of command lines is ignored, and so are arguments
+    /// to commands that take none, ie `RSET now`. Strict servers pass the whitespace to
+    /// the commands and reply `501` to arguments given to commands that take none.
+    pub fn set_strict_syntax(&mut self, strict: bool) {
+        self.config.strict_syntax = strict;
+    }
+
+    /// Adds a command to the server.
+    pub fn add_command(&mut self, command: Command<CT, ST>) {
+        for extension in command.extensions.iter() {
+            self.add_extension(extension);
+        }
+        if command.on_failure.is_some() {
+            self.config.abort = command.on_failure;
+        }
+        self.config.commands.push(command);
+    }
+
+    fn increase_max_command_line_size(&mut self, bytes: usize) {
+        self.config.max_command_line_size += bytes;
+    }
+
+    fn increase_max_text_line_size(&mut self, bytes: usize) {
+        self.config.max_text_line_size += bytes;
+    }
+
+    /// Marks an SMTP extension as "supported" by the server.
+    ///
+    /// This is used in the output of the EHLO command. The extension is given as its EHLO
+    /// line, ie `SIZE 10485760`. For `SIZE` and `AUTH` without parameters, the parameters
+    /// are taken from the configuration. Adding an extension with the same keyword again
+    /// replaces it.
+    pub fn add_extension(&mut self, extension: &str) {
+        self.config.add_extension(extension);
+    }
+
+    /// Detects messages that are submitted more than once within the given window.
+    ///
+    /// A message is a duplicate when a message with the same Message-ID, sender and
+    /// recipients was accepted less than `window` ago, on any connection. This protects
+    /// downstream systems from clients that retry every transaction.
+    pub fn set_duplicate_window(&mut self, window: Duration, action: DuplicateAction) {
+        self.config.duplicates = Some(Arc::new(DuplicateWindow::new(window, action)));
+    }
+
+    /// Adds a filter checking the content of messages, see `filter::ContentFilter`.
+    ///
+    /// Filters run in the order they were added, until one of them rejects the message.
+    pub fn add_content_filter<F: 'static + ContentFilter>(&mut self, filter: F) {
+        self.config.filters.push(Arc::new(filter));
+    }
+
+    /// Adds a consumer of the accepted messages, see `fanout::MessageConsumer`.
+    ///
+    /// Consumers run in the order they were added, after the `DataHandler`.
+    pub fn add_message_consumer<C: 'static + MessageConsumer>(&mut self, consumer: C) {
+        self.config.consumers.push(Arc::new(consumer));
+    }
+
+    /// Sets what happens when a consumer fails, by default `FanoutPolicy::AllMustSucceed`.
+    pub fn set_fanout_policy(&mut self, policy: FanoutPolicy) {
+        self.config.fanout_policy = policy;
+    }
+
+    /// Runs a session over the given streams, until an error ends it.
+    ///
+    /// `input` and `output` are usually two handles to the same connection. The client
+    /// is told why the session ended like with `listen`, but connection limits and PROXY
+    /// headers are left to the caller. Panics end the session like with `listen`, then go
+    /// on to the caller.
+    pub fn serve(&self, input: ST, output: ST) -> Result<(), SessionError> {
+        let (input, output) = Server::<CT, ST>::new_streams(&self.config, input, output);
+        Server::<CT, ST>::serve_session(&self.config, self.container.clone(), input, output)
+    }
+
+    // Wraps the streams of a session, with the buffer and the delays of the server.
+    fn new_streams(config: &ServerConfig<CT, ST>, input: ST, output: ST) -> (InputStream<ST>, OutputStream<ST>) {
+        let input = InputStream::new(input, Server::<CT, ST>::max_line_size(config), false);
+        let mut output = OutputStream::new(output, false);
+        output.set_delay_policy(config.delay_policy.clone());
+        (input, output)
+    }
+
+    // Runs a session over streams that are set up, see `serve`.
+    fn serve_session(config: &ServerConfig<CT, ST>, container: CT, mut input: InputStream<ST>, mut output: OutputStream<ST>) -> Result<(), SessionError> {
+        // Makes sure the transaction is cleaned up however the session ends.
+        let mut container = TransactionGuard::new(container, config.abort);
+        let mut state = SessionState::new();
+        state.set_id(config.generate_id().as_ref());
+        Server::<CT, ST>::report_opened(config, &input, &state);
+        Server::<CT, ST>::start_transcript(config, &mut input, &mut output, &state);
+
+        let started = Instant::now();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            Server::<CT, ST>::handle_commands(config, &mut input, &mut output, &mut *container, &mut state)
+        }));
+        Server::<CT, ST>::finish_session(config, &input, &mut output, &mut *container, &state, started, &res);
+        match res {
+            Ok(res) => res,
+            Err(payload) => panic::resume_unwind(payload)
+        }
+    }
+
+    // Ends a session: tells the client why, when it can still be told, and reports the
+    // error or the panic, then hands over the transcript and reports the traffic and the
+    // summary.
+    fn finish_session(config: &ServerConfig<CT, ST>, input: &InputStream<ST>, output: &mut OutputStream<ST>, container: &mut CT, state: &SessionState, started: Instant, res: &SessionResult) {
+        let reason = match *res {
+            Ok(Ok(_)) => "closed_by_server",
+            Ok(Err(ref err)) => {
+                Server::<CT, ST>::close_session(config, state.id(), output, err);
+                close_reason(err)
+            },
+            Err(ref payload) => {
+                Server::<CT, ST>::handle_panic(config, output, &**payload);
+                "panic"
+            }
+        };
+        Server::<CT, ST>::end_transcript(config, input, container);
+        Server::<CT, ST>::report_traffic(config, input);
+        Server::<CT, ST>::report_summary(config, input, state, started, reason);
+    }
+
+    // Returns the size of the input buffer, which must hold the longest line any command
+    // accepts.
+    fn max_line_size(config: &ServerConfig<CT, ST>) -> usize {
+        config.commands.iter().fold(config.max_text_line_size, |max, command| {
+            cmp::max(max, Server::<CT, ST>::line_size_limit(config, command))
+        })
+    }
+
+    // Runs a session until an error ends it.
+    fn handle_commands(config: &ServerConfig<CT, ST>, input: &mut InputStream<ST>, output: &mut OutputStream<ST>, container: &mut CT, state: &mut SessionState) -> Result<(), SessionError> {
+        Server::<CT, ST>::look_up_client(config, input, state);
+        try!(output.write_reply(&Server::<CT, ST>::greeting(config)).map_err(SessionError::Write));
+        try!(output.flush().map_err(SessionError::Write));
+        loop {
+            try!(Server::<CT, ST>::handle_next_command(config, input, output, container, state));
+        }
+    }
+
+    // Records the reverse DNS of the client, if the server looks it up and the address of
+    // the client is known.
+    fn look_up_client(config: &ServerConfig<CT, ST>, input: &InputStream<ST>, state: &mut SessionState) {
+        if let (Some(&(ref resolver, timeout)), Ok(addr)) = (config.reverse_dns.as_ref(), input.peer_addr()) {
+            state.set_reverse_dns(lookup_timeout(resolver.clone(), addr.ip(), timeout));
+        }
+    }
+
+    // Returns what the middleware of a command get from the rest of the command line,
+    // according to the argument policy of the command and the strictness of the server.
+    fn get_argument<'a>(config: &ServerConfig<CT, ST>, command: &Command<CT, ST>, rest: &'a str) -> Result<&'a str, Reply> {
+        let rest = match config.strict_syntax {
+            true => rest,
+            false => rest.trim_right_matches(|c| c == ' ' || c == '\t')
+        };
+        match (command.arguments, rest.len() == 0 || !config.strict_syntax) {
+            (ArgumentPolicy::Any, _) => Ok(rest),
+            (ArgumentPolicy::Ignored, _) | (ArgumentPolicy::Forbidden, true) => Ok(""),
+            (ArgumentPolicy::Forbidden, false) => {
+                let text = format!("Syntax error, {} takes no argument", command.verb().unwrap_or(""));
+                Err(Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), text.as_ref()))
+            }
+        }
+    }
+
+    // Returns the first line sent to clients.
+    fn greeting(config: &ServerConfig<CT, ST>) -> Reply {
+        match config.no_mail {
+            true => Reply::enhanced(521, EnhancedStatusCode::new(5, 3, 2), format!("{} does not accept mail", config.hostname).as_ref()),
+            false => Reply::new(220, format!("{} Service ready", config.hostname).as_ref())
+        }
+    }
+
+    // Reads and runs a command.
+    //
+    // Replies are only sent once the client has no more pipelined commands for us, so
+    // replies to a group of commands are sent together.
+    fn handle_next_command(config: &ServerConfig<CT, ST>, input: &mut InputStream<ST>, output: &mut OutputStream<ST>, container: &mut CT, state: &mut SessionState) -> Result<(), SessionError> {
+        // Commands reading a message use their own timeout.
+        try!(input.get_ref().set_read_timeout(Some(config.command_timeout)).map_err(SessionError::Setup));
+        let line = match input.read_line() {
+            Ok(buffer) => {
+                // The commands expect a regular human readable string.
+                // Also, we need to make this an owned string because
+                // the stream uses the same buffer for command lines and
+                // text lines.
+                //
+                // TODO: use a different buffer for text lines and command
+                // lines?
+                config.control_policy.apply(buffer, b"").map(|line| String::from_utf8_lossy(line.as_ref()).into_owned())
+            },
+            Err(ref err) if is_timeout(err) => {
+                return Err(SessionError::Timeout);
+            },
+            Err(err) => {
+                return Err(SessionError::Read(err));
+            }
+        };
+
+        if let (Some(ref logger), Some(ref line)) = (config.logger.as_ref(), line.as_ref()) {
+            logger.command(state.id().unwrap_or(""), redact(line).as_ref());
+        }
+        match line {
+            Some(line) => Server::<CT, ST>::handle_command(config, input, output, container, state, line.as_ref()),
+            None => {
+                output.set_command(None);
+                state.record_command();
+                output.write_reply(&Reply::enhanced(500, EnhancedStatusCode::new(5, 5, 2), "Syntax error, control characters not allowed")).unwrap();
+                Server::<CT, ST>::report_reply(config, input, output, state);
+            }
+        }
+        if let Some(err) = state.take_failure() {
+            return Err(err);
+        }
+        if state.is_timed_out() {
+            return Err(SessionError::Timeout);
+        }
+        if state.is_closing() {
+            try!(output.flush().map_err(SessionError::Write));
+            return Err(SessionError::Rejected);
+        }
+        if let (Some(max), Some(code)) = (config.max_errors, output.last_reply_code()) {
+            if state.record_reply(code) >= max {
+                return Err(SessionError::TooManyErrors);
+            }
+        }
+        if !input.has_pending_line() {
+            try!(output.flush().map_err(SessionError::Write));
+        }
+        Ok(())
+    }
+
+    // Runs the command matching a command line, and reports the reply to it.
+    fn handle_command(config: &ServerConfig<CT, ST>, input: &mut InputStream<ST>, output: &mut OutputStream<ST>, container: &mut CT, state: &mut SessionState, line: &str) {
+        Server::<CT, ST>::run_command(config, input, output, container, state, line);
+        Server::<CT, ST>::report_reply(config, input, output, state);
+    }
+
+    // Reports the last reply to a command line to the metrics and the logger.
+    fn report_reply(config: &ServerConfig<CT, ST>, input: &InputStream<ST>, output: &OutputStream<ST>, state: &SessionState) {
+        if let (Some(ref metrics), Some(code)) = (config.metrics.as_ref(), output.last_reply_code()) {
+            metrics.reply(output.command(), code, input.peer_addr().ok().map(|addr| addr.ip()));
+            if code >= 400 {
+                metrics.increment("commands_rejected");
+            }
+        }
+        if let (Some(ref logger), Some(code)) = (config.logger.as_ref(), output.last_reply_code()) {
+            logger.reply(state.id().unwrap_or(""), output.command(), code);
+        }
+    }
+
+    // Runs the command matching a command line.
+    fn run_command(config: &ServerConfig<CT, ST>, input: &mut InputStream<ST>, output: &mut OutputStream<ST>, container: &mut CT, state: &mut SessionState, line: &str) {
+        output.set_command(None);
+        state.record_command();
+        // Find the right handler for this command line.
+        for command in config.commands.iter() {
+            // The right command starts with whatever we have set
+            // when we created the command.
+            match command.start {
+                Some(ref start) => {
+                    let ls = line;
+                    if matches_start(ls, start.as_str()) {
+                        output.set_command(command.verb());
+                        if let Some(reply) = Server::<CT, ST>::check_limits(config, command, ls, &ls[start.len() ..]) {
+                            output.write_reply(&reply).unwrap();
+                            return;
+                        }
+                        if command.last_in_group && input.has_pending_input() {
+                            output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 0), "Improper use of SMTP command pipelining")).unwrap();
+                            return;
+                        }
+                        let argument = match Server::<CT, ST>::get_argument(config, command, &ls[start.len() ..]) {
+                            Ok(argument) => argument,
+                            Err(reply) => {
+                                output.write_reply(&reply).unwrap();
+                                return;
+                            }
+                        };
+                        match command.front_middleware {
+                            Some(ref next) => {
+                                // The transaction is over once the message is handled, so
+                                // its sender is kept for the metrics beforehand.
+                                let sender = match (config.metrics.is_some(), command.on_failure.is_some()) {
+                                    (true, true) => state.sender().cloned(),
+                                    _ => None
+                                };
+                                let receiving = state.phase() == Phase::Data;
+                                next.call(config, container, state, input, output, argument);
+                                Server::<CT, ST>::report_timings(config, command);
+                                if let Some(abort) = command.on_failure {
+                                    // Replies refusing the command before any content, ie
+                                    // DATA before RCPT, are no message.
+                                    let received = state.take_received() || receiving;
+                                    if output.last_reply_code().map_or(false, is_aborting_reply) {
+                                        abort(container);
+                                        state.end_transaction();
+                                    }
+                                    // Only the commands sending messages abort, and a
+                                    // message is over once out of the DATA phase.
+                                    if let (Some(code), true, false) = (output.last_reply_code(), received, state.phase() == Phase::Data) {
+                                        state.record_message(code < 400);
+                                        if let Some(ref metrics) = config.metrics {
+                                            metrics.message(input.peer_addr().ok().map(|addr| addr.ip()), sender.as_ref(), code < 400);
+                                            metrics.increment(match code < 400 {
+                                                true => "messages_accepted",
+                                                false => "messages_refused"
+                                            });
+                                        }
+                                    }
+                                }
+                            },
+                            None => {
+                                // This is a bug in the command, the session can go on.
+                                output.write_reply(&Reply::enhanced(451, EnhancedStatusCode::new(4, 3, 0), "Requested action aborted: local error in processing")).unwrap();
+                                Server::<CT, ST>::report_error(config, state.id(), &SessionError::InvalidCommand(start.clone()));
+                            }
+                        }
+                        return;
+                    }
+                },
+                // A command without start string can't match any line.
+                None => {}
+            }
+        }
+
+        // If we get here, it means that no command matched.
+        output.write_reply(&Reply::enhanced(500, EnhancedStatusCode::new(5, 5, 1), "Command unrecognized")).unwrap();
+    }
+
+    // Returns the maximum size of a command line for the command, including `<CRLF>`.
+    fn line_size_limit(config: &ServerConfig<CT, ST>, command: &Command<CT, ST>) -> usize {
+        let mut limit = command.max_line_size.unwrap_or(config.max_command_line_size);
+        for &(ref extension, bytes) in command.line_size_increases.iter() {
+            if config.has_extension(extension) {
+                limit += bytes;
+            }
+        }
+        limit
+    }
+
+    // Returns the reply to send when the command line exceeds the limits of the command.
+    fn check_limits(config: &ServerConfig<CT, ST>, command: &Command<CT, ST>, line: &str, argument: &str) -> Option<Reply> {
+        if line.len() + 2 > Server::<CT, ST>::line_size_limit(config, command) {
+            return Some(Reply::enhanced(500, EnhancedStatusCode::new(5, 5, 6), "Line too long"));
+        }
+        match command.max_path_size {
+            Some((max, path)) if path(argument).len() > max => Some(Reply::enhanced(501, EnhancedStatusCode::new(5, 5, 4), "Path too long")),
+            _ => None
+        }
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    fn report_timings(_: &ServerConfig<CT, ST>, _: &Command<CT, ST>) {}
+
+    #[cfg(feature = "profiling")]
+    fn report_timings(config: &ServerConfig<CT, ST>, command: &Command<CT, ST>) {
+        // Always report, so the timings don't pile up when no metrics are configured.
+        let metrics = config.metrics.as_ref().map(|metrics| metrics.deref());
+        profiling::report(metrics, command.verb().unwrap_or(""));
+    }
+
+    // Returns the `421` reply that ends a session.
+    fn closing_reply(config: &ServerConfig<CT, ST>) -> Reply {
+        let text = format!("{} Service not available, closing transmission channel", config.hostname);
+        Reply::enhanced(421, EnhancedStatusCode::new(4, 3, 0), text.as_ref())
+    }
+
+    // Sends the reply that ends a session.
+    fn write_closing<S: Write>(output: &mut OutputStream<S>, reply: &Reply) {
+        // The stream may already be closed, in which case there is no one to tell.
+        let _ = output.write_reply(reply);
+        let _ = output.flush();
+    }
+
+    fn handle_panic<S: Write>(config: &ServerConfig<CT, ST>, output: &mut OutputStream<S>, payload: &(Any + Send)) {
+        Server::<CT, ST>::write_closing(output, &Server::<CT, ST>::closing_reply(config));
+
+        if let Some(ref metrics) = config.metrics {
+            metrics.increment("session_panics");
+        }
+        if let Some(ref hook) = config.on_panic {
+            hook(payload);
+        }
+    }
+
+    fn report_error(config: &ServerConfig<CT, ST>, session: Option<&str>, err: &SessionError) {
+        if let Some(ref logger) = config.logger {
+            logger.error(session, err);
+        }
+        if let Some(ref hook) = config.on_error {
+            hook(err);
+        }
+    }
+
+    // Reports what a session did, once it is over.
+    fn report_summary(config: &ServerConfig<CT, ST>, input: &InputStream<ST>, state: &SessionState, started: Instant, reason: &'static str) {
+        if let Some(ref hook) = config.on_summary {
+            let mut summary = SessionSummary::new(state, started.elapsed(), reason);
+            summary.peer = input.peer_addr().ok();
+            if let Some(stats) = input.get_ref().traffic() {
+                summary.bytes_received = stats.bytes_in() as u64;
+                summary.bytes_sent = stats.bytes_out() as u64;
+            }
+            hook(&summary);
+        }
+        Server::<CT, ST>::report_closed(config, state, started.elapsed(), reason);
+    }
+
+    // Reports the end of a session to the metrics and the logger, with how long it lasted
+    // and why it ended.
+    fn report_closed(config: &ServerConfig<CT, ST>, state: &SessionState, duration: Duration, reason: &str) {
+        if let Some(ref metrics) = config.metrics {
+            metrics.timing("session_duration", duration);
+        }
+        if let Some(ref logger) = config.logger {
+            logger.session_closed(state.id().unwrap_or(""), reason);
+        }
+    }
+
+    // Reports the start of a session to the metrics and the logger.
+    fn report_opened(config: &ServerConfig<CT, ST>, input: &InputStream<ST>, state: &SessionState) {
+        if let Some(ref metrics) = config.metrics {
+            metrics.increment("sessions_opened");
+        }
+        if let Some(ref logger) = config.logger {
+            logger.session_opened(state.id().unwrap_or(""), input.peer_addr().ok());
+        }
+    }
+
+    // Gives a transcript to the streams of a session, if transcripts are handled.
+    fn start_transcript(config: &ServerConfig<CT, ST>, input: &mut InputStream<ST>, output: &mut OutputStream<ST>, state: &SessionState) {
+        if config.on_transcript.is_some() {
+            let transcript = Transcript::new(state.id());
+            input.set_transcript(Some(transcript.clone()));
+            output.set_transcript(Some(transcript));
+        }
+    }
+
+    // Hands the transcript of a session that is over to the container.
+    fn end_transcript(config: &ServerConfig<CT, ST>, input: &InputStream<ST>, container: &mut CT) {
+        if let (Some(handler), Some(transcript)) = (config.on_transcript, input.transcript()) {
+            handler(container, transcript);
+        }
+    }
+
+    // Reports the traffic of a session that is over, when the stream counts it.
+    fn report_traffic(config: &ServerConfig<CT, ST>, input: &InputStream<ST>) {
+        if let (Some(ref metrics), Some(stats)) = (config.metrics.as_ref(), input.get_ref().traffic()) {
+            metrics.add("bytes_received", stats.bytes_in() as u64);
+            metrics.add("bytes_sent", stats.bytes_out() as u64);
+        }
+    }
+
+    // Logs an address the server listens on.
+    fn log_listening(config: &ServerConfig<CT, ST>, address: &str) {
+        if let Some(ref logger) = config.logger {
+            logger.listening(config.hostname.as_ref(), address);
+        }
+    }
+
+    // Tells the client the session is over, when the connection is still usable, and
+    // reports the error.
+    //
+    // Lines that are too long can't be read, but the client can still be told.
+    fn close_session<S: Write>(config: &ServerConfig<CT, ST>, session: Option<&str>, output: &mut OutputStream<S>, err: &SessionError) {
+        let reply = match *err {
+            SessionError::Read(ref err) if err.kind() != ErrorKind::InvalidInput => None,
+            SessionError::Write(_) => None,
+            // The client already got a reply.
+            SessionError::Rejected => None,
+            // The peer may not even speak SMTP.
+            SessionError::Proxy(_) => None,
+            SessionError::Timeout => {
+                let text = format!("{} Timeout, closing transmission channel", config.hostname);
+                Some(Reply::enhanced(421, EnhancedStatusCode::new(4, 4, 2), text.as_ref()))
+            },
+            SessionError::TooManyErrors => {
+                let text = format!("{} Too many errors, closing transmission channel", config.hostname);
+                Some(Reply::enhanced(421, EnhancedStatusCode::new(4, 7, 0), text.as_ref()))
+            },
+            SessionError::TooManyConnections => {
+                let text = format!("{} Too many connections, closing transmission channel", config.hostname);
+                Some(Reply::enhanced(421, EnhancedStatusCode::new(4, 7, 0), text.as_ref()))
+            },
+            SessionError::TooBusy => {
+                let text = format!("{} Too busy, closing transmission channel", config.hostname);
+                Some(Reply::enhanced(421, EnhancedStatusCode::new(4, 3, 2), text.as_ref()))
+            },
+            _ => Some(Server::<CT, ST>::closing_reply(config))
+        };
+        if let Some(reply) = reply {
+            Server::<CT, ST>::write_closing(output, &reply);
+        }
+        Server::<CT, ST>::report_error(config, session, err);
+    }
+
+    // Turns a client away before the session starts.
+    fn reject_session<S: Write>(config: &ServerConfig<CT, ST>, stream: S, err: &SessionError) {
+        if let Some(ref metrics) = config.metrics {
+            metrics.increment("sessions_rejected");
+        }
+        let mut output = OutputStream::new(stream, false);
+        Server::<CT, ST>::close_session(config, None, &mut output, err);
+    }
+}
+
+impl<CT: 'static + Send + Sync + Clone> Server<CT> {
+    /// Creates a new SMTP server.
+    ///
+    /// The container can be of any type and can be used to get access to a
+    /// bunch of things inside your commands, like database connections,
+    /// a logger and more.
+    pub fn new(container: CT) -> Server<CT> {
+        Server::with_stream(container)
+    }
+
+    fn get_hostname_from_system(&mut self) -> ServerResult<String> {
+        match rust_gethostname() {
+            Ok(s) => {
+                Ok(s)
+            },
+            Err(_) => {
+                Err(ServerError::Hostname)
+            }
+        }
+    }
+
+    fn get_listener_for_address(&mut self, address: (IpAddr, u16)) -> ServerResult<TcpListener> {
+        let listener = match TcpListener::bind(address) {
+            Ok(listener) => listener,
+            Err(_) => return Err(ServerError::Bind)
+        };
+        try!(Server::<CT>::prepare_listener(&self.config, &listener));
+        Ok(listener)
+    }
+
+    // Applies the settings of the server to a bound listener.
+    fn prepare_listener(config: &ServerConfig<CT>, listener: &TcpListener) -> ServerResult<()> {
+        match config.accept_filter {
+            Some(timeout) => set_accept_filter(listener, timeout).map_err(|_| ServerError::Listen),
+            None => Ok(())
+        }
+    }
+
+    fn handle_connection(&self, stream_res: IoResult<TcpStream>, config: &Arc<ServerConfig<CT>>, pool: Option<&WorkerPool<(TcpStream, ConnectionPermit)>>) {
+        let stream = match stream_res {
+            Ok(stream) => stream,
+            Err(err) => {
+                Server::<CT>::report_error(config.deref(), None, &SessionError::Accept(err));
+                return;
+            }
+        };
+        let (stream, permit) = match Server::<CT>::admit(config.deref(), stream) {
+            Some(admitted) => admitted,
+            None => return
+        };
+        match pool {
+            Some(pool) => {
+                if let Err((stream, _)) = pool.submit((stream, permit)) {
+                    Server::<CT>::reject_session(config.deref(), stream, &SessionError::TooBusy);
+                }
+            },
+            None => {
+                let config = config.clone();
+                let container = self.container.clone();
+                thread::spawn(move || {
+                    Server::<CT>::handle_session(config.deref(), container, stream, permit);
+                });
+            }
+        }
+    }
+
+    // Counts a new connection, or turns the client away if there are too many.
+    fn admit(config: &ServerConfig<CT>, stream: TcpStream) -> Option<(TcpStream, ConnectionPermit)> {
+        let ip = match stream.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(err) => {
+                Server::<CT>::report_error(config, None, &SessionError::Setup(err));
+                return None;
+            }
+        };
+        let max = config.max_connections;
+        let max_per_ip = config.max_connections_per_ip;
+        match ConnectionCounter::acquire(&config.connections, Some(ip), max, max_per_ip) {
+            Ok(permit) => Some((stream, permit)),
+            Err(_) => {
+                Server::<CT>::reject_session(config, stream, &SessionError::TooManyConnections);
+                None
+            }
+        }
+    }
+
+    // Sets up the streams of a new connection.
+    fn open_streams(config: &ServerConfig<CT>, stream: TcpStream) -> Result<(InputStream<Transport>, OutputStream<Transport>), (TcpStream, SessionError)> {
+        // The header comes before the greeting, so it gets the same time as a command.
+        let peer_addr = match config.proxy_protocol {
+            true => {
+                let res = stream.set_read_timeout(Some(config.command_timeout)).and_then(|_| {
+                    proxy::read_header(&mut &stream)
+                });
+                match res {
+                    Ok(header) => header.source,
+                    Err(err) => return Err((stream, SessionError::Proxy(err)))
+                }
+            },
+            false => None
+        };
+
+        // Clone the stream. We use this stream only for reading and the other
+        // one only for writing, until they are both replaced by STARTTLS.
+        let input_stream = match stream.try_clone() {
+            Ok(input_stream) => input_stream,
+            Err(err) => return Err((stream, SessionError::Setup(err)))
+        };
+
+        let max_line_size = Server::<CT>::max_line_size(config);
+        let stats = Arc::new(StreamStats::new());
+        let mut input = InputStream::new(Transport::Tcp(InstrumentedStream::with_stats(input_stream, stats.clone())), max_line_size, false);
+        input.set_peer_addr(peer_addr);
+        let mut output = OutputStream::new(Transport::Tcp(InstrumentedStream::with_stats(stream, stats)), false);
+        output.set_delay_policy(config.delay_policy.clone());
+        Ok((input, output))
+    }
+
+    // Runs a session, counted until it ends by the permit.
+    fn handle_session(config: &ServerConfig<CT>, container: CT, stream: TcpStream, _: ConnectionPermit) {
+        let (input, output) = match Server::<CT>::open_streams(config, stream) {
+            Ok(streams) => streams,
+            Err((stream, err)) => {
+                let mut output = OutputStream::new(stream, false);
+                Server::<CT>::close_session(config, None, &mut output, &err);
+                return;
+            }
+        };
+        // The session is over and reported either way, and the connection is closed when
+        // the streams are dropped.
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            Server::<CT>::serve_session(config, container, input, output)
+        }));
+    }
+
+    /// Start the SMTP server on the given address and port.
+    pub fn listen(&mut self, ip: IpAddr, port: u16) -> ServerResult<()> {
+        // TODO: check that commands all are valid, meaning they have at least
+        // a key word (ie HELO) and at least 1 middleware.
+
+        if self.config.hostname.len() == 0 {
+            self.config.hostname = try!(self.get_hostname_from_system());
+        }
+
+        let listener = try!(self.get_listener_for_address((ip, port)));
+
+        Server::<CT>::log_listening(&self.config, SocketAddr::new(ip, port).to_string().as_ref());
+
+        self.accept(vec![listener])
+    }
+
+    /// Start the SMTP server on a listener that is already bound, ie one passed by systemd
+    /// socket activation, or bound before dropping privileges.
+    pub fn listen_on(&mut self, listener: TcpListener) -> ServerResult<()> {
+        if self.config.hostname.len() == 0 {
+            self.config.hostname = try!(self.get_hostname_from_system());
+        }
+
+        let address = match listener.local_addr() {
+            Ok(address) => address,
+            Err(_) => return Err(ServerError::Listen)
+        };
+        // The listener may have been set to non-blocking by whoever bound it.
+        if let Err(_) = listener.set_nonblocking(false) {
+            return Err(ServerError::Listen);
+        }
+        try!(Server::<CT>::prepare_listener(&self.config, &listener));
+
+        Server::<CT>::log_listening(&self.config, address.to_string().as_ref());
+
+        self.accept(vec![listener])
+    }
+
+    /// Adds an address to listen on with `listen_all`, ie `0.0.0.0:25` or `[::]:25`.
+    pub fn add_address(&mut self, ip: IpAddr, port: u16) {
+        self.config.add_address(ip, port);
+    }
+
+    /// Start the SMTP server on all the addresses given to `add_address` at once.
+    ///
+    /// The sessions of all the addresses share the configuration, the container and the
+    /// worker pool, if any. This fails if there is no address, or if any of them can't be
+    /// bound.
+    pub fn listen_all(&mut self) -> ServerResult<()> {
+        if self.config.addresses.len() == 0 {
+            return Err(ServerError::Bind);
+        }
+        if self.config.hostname.len() == 0 {
+            self.config.hostname = try!(self.get_hostname_from_system());
+        }
+
+        let mut listeners = Vec::with_capacity(self.config.addresses.len());
+        for &(ip, port) in self.config.addresses.clone().iter() {
+            listeners.push(try!(self.get_listener_for_address((ip, port))));
+            Server::<CT>::log_listening(&self.config, SocketAddr::new(ip, port).to_string().as_ref());
+        }
+
+        self.accept(listeners)
+    }
+
+    // Accepts connections from all the listeners, until one of them fails.
+    fn accept(&mut self, listeners: Vec<TcpListener>) -> ServerResult<()> {
+        let config = Arc::new(self.config.clone());
+        let pool = self.config.workers.map(|(workers, queue)| {
+            let config = config.clone();
+            let container = self.container.clone();
+            WorkerPool::new(workers, queue, move |(stream, permit)| {
+                Server::<CT>::handle_session(config.deref(), container.clone(), stream, permit);
+            })
+        });
+
+        if listeners.len() == 1 {
+            for conn in listeners[0].incoming() {
+                self.handle_connection(conn, &config, pool.as_ref());
+            }
+            return Ok(());
+        }
+
+        for listener in listeners.iter() {
+            if let Err(_) = listener.set_nonblocking(true) {
+                return Err(ServerError::Listen);
+            }
+        }
+        let mut fds: Vec<libc::pollfd> = listeners.iter().map(|listener| evented::pollfd(listener)).collect();
+        loop {
+            let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if res < 0 {
+                match IoError::last_os_error().kind() {
+                    ErrorKind::Interrupted => continue,
+                    _ => return Err(ServerError::Listen)
+                }
+            }
+            for (fd, listener) in fds.iter_mut().zip(listeners.iter()) {
+                if fd.revents == 0 {
+                    continue;
+                }
+                fd.revents = 0;
+                self.accept_ready(|| {
+                    // Sessions run on blocking sockets, whatever the listener is.
+                    listener.accept().and_then(|(stream, _)| {
+                        try!(stream.set_nonblocking(false));
+                        Ok(stream)
+                    })
+                }, &config, pool.as_ref());
+            }
+        }
+    }
+
+    // Accepts the connections waiting on a non-blocking listener.
+    //
+    // An error stops until the next `poll`, so a listener that keeps failing doesn't spin.
+    // Running out of file descriptors doesn't go away by itself, so that also waits a bit
+    // for sessions to end.
+    fn accept_ready<F: FnMut() -> IoResult<TcpStream>>(&self, mut accept: F, config: &Arc<ServerConfig<CT>>, pool: Option<&WorkerPool<(TcpStream, ConnectionPermit)>>) {
+        loop {
+            match accept() {
+                Ok(stream) => self.handle_connection(Ok(stream), config, pool),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    let exhausted = match err.raw_os_error() {
+                        Some(libc::EMFILE) | Some(libc::ENFILE) => true,
+                        _ => false
+                    };
+                    self.handle_connection(Err(err), config, pool);
+                    if exhausted {
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Start the SMTP server on the given address and port, serving all the sessions with
+    /// an event loop and the given number of worker threads, at least one.
+    ///
+    /// Sessions only hold a worker while they run a command, so a few workers can serve
+    /// thousands of mostly idle sessions. A client sending a line or a message slowly
+    /// still keeps a worker busy, up to the timeouts. Commands work the same as with
+    /// `listen`.
+    pub fn listen_evented(&mut self, ip: IpAddr, port: u16, workers: usize) -> ServerResult<()> {
+        if self.config.hostname.len() == 0 {
+            self.config.hostname = try!(self.get_hostname_from_system());
+        }
+
+        let listener = try!(self.get_listener_for_address((ip, port)));
+
+        Server::<CT>::log_listening(&self.config, SocketAddr::new(ip, port).to_string().as_ref());
+
+        let config = Arc::new(self.config.clone());
+        let mut event_loop = match EventLoop::new(config, self.container.clone(), listener, cmp::max(workers, 1)) {
+            Ok(event_loop) => event_loop,
+            Err(_) => return Err(ServerError::Listen)
+        };
+        loop {
+            if let Err(_) = event_loop.turn() {
+                return Err(ServerError::Listen);
+            }
+        }
+    }
+}
+
+impl<CT: 'static + Send + Sync + Clone> Server<CT, UnixStream> {
+    /// Start the SMTP server on a Unix socket at the given path, ie for local delivery
+    /// agents and filters. Nothing must exist at the path yet.
+    ///
+    /// Clients of a Unix socket have no address, so only `set_max_connections` limits
+    /// them, and rate limits and trusted networks don't apply to them. Sessions run on the
+    /// worker pool, if any, and panics end them like with `listen`.
+    pub fn listen_unix<P: AsRef<Path>>(&mut self, path: P) -> ServerResult<()> {
+        let listener = match UnixListener::bind(path.as_ref()) {
+            Ok(listener) => listener,
+            Err(_) => return Err(ServerError::Bind)
+        };
+        self.listen_on_unix(listener)
+    }
+
+    /// Start the SMTP server on a Unix socket that is already bound, see `listen_unix`.
+    pub fn listen_on_unix(&mut self, listener: UnixListener) -> ServerResult<()> {
+        if self.config.hostname.len() == 0 {
+            self.config.hostname = try!(rust_gethostname().map_err(|_| ServerError::Hostname));
+        }
+        let address = match listener.local_addr() {
+            Ok(address) => address,
+            Err(_) => return Err(ServerError::Listen)
+        };
+
+        Server::<CT, UnixStream>::log_listening(&self.config, format!("{:?}", address).as_ref());
+
+        let config = Arc::new(self.config.clone());
+        let pool = self.config.workers.map(|(workers, queue)| {
+            let config = config.clone();
+            let container = self.container.clone();
+            WorkerPool::new(workers, queue, move |(stream, permit)| {
+                Server::<CT, UnixStream>::handle_unix_session(config.deref(), container.clone(), stream, permit);
+            })
+        });
+
+        for conn in listener.incoming() {
+            let stream = match conn {
+                Ok(stream) => stream,
+                Err(err) => {
+                    Server::<CT, UnixStream>::report_error(config.deref(), None, &SessionError::Accept(err));
+                    continue;
+                }
+            };
+            let permit = match ConnectionCounter::acquire(&config.connections, None, config.max_connections, None) {
+                Ok(permit) => permit,
+                Err(_) => {
+                    Server::<CT, UnixStream>::reject_session(config.deref(), stream, &SessionError::TooManyConnections);
+                    continue;
+                }
+            };
+            match pool.as_ref() {
+                Some(pool) => {
+                    if let Err((stream, _)) = pool.submit((stream, permit)) {
+                        Server::<CT, UnixStream>::reject_session(config.deref(), stream, &SessionError::TooBusy);
+                    }
+                },
+                None => {
+                    let config = config.clone();
+                    let container = self.container.clone();
+                    thread::spawn(move || {
+                        Server::<CT, UnixStream>::handle_unix_session(config.deref(), container, stream, permit);
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Runs a session over a Unix socket, counted until it ends by the permit.
+    fn handle_unix_session(config: &ServerConfig<CT, UnixStream>, container: CT, stream: UnixStream, _: ConnectionPermit) {
+        let input = match stream.try_clone() {
+            Ok(input) => input,
+            Err(err) => {
+                let mut output = OutputStream::new(stream, false);
+                Server::<CT, UnixStream>::close_session(config, None, &mut output, &SessionError::Setup(err));
+                return;
+            }
+        };
+        let (input, output) = Server::<CT, UnixStream>::new_streams(config, input, stream);
+        // The session is over and reported either way, like with `handle_session`.
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            Server::<CT, UnixStream>::serve_session(config, container, input, output)
+        }));
+    }
+}
+
+#[cfg(test)]
+struct PanicMetrics {
+    panics: Arc<AtomicUsize>
+}
+
+#[cfg(test)]
+impl Metrics for PanicMetrics {
+    fn increment(&self, counter: &str) {
+        assert_eq!("session_panics", counter);
+        self.panics.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_handle_panic() {
+    let panics = Arc::new(AtomicUsize::new(0));
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let hook_messages = messages.clone();
+
+    let mut server = Server::new(());
+    server.set_hostname("rustastic.org").unwrap();
+    server.set_metrics(PanicMetrics { panics: panics.clone() });
+    server.set_on_panic(move |payload| {
+        let message = payload.downcast_ref::<&str>().map(|m| m.to_string());
+        hook_messages.lock().unwrap().push(message);
+    });
+
+    let payload = panic::catch_unwind(|| {
+        panic!("handler failed");
+    }).unwrap_err();
+    let mut output = OutputStream::new(Vec::new(), false);
+    Server::<()>::handle_panic(&server.config, &mut output, &*payload);
+
+    assert_eq!(&b"421 4.3.0 rustastic.org Service not available, closing transmission channel\r\n"[..], &output.get_ref()[..]);
+    assert_eq!(1, panics.load(Ordering::SeqCst));
+    assert_eq!(vec![Some("handler failed".to_string())], *messages.lock().unwrap());
+}
+
+#[test]
+fn test_limits() {
+    let mut server = Server::new(());
+    assert_eq!(Ok(()), server.set_max_recipients(100));
+    assert_eq!(Err(ConfigError::BelowMinimum(100)), server.set_max_recipients(99));
+    assert_eq!(100, server.config.max_recipients);
+    assert_eq!(Ok(()), server.set_max_message_size(1 << 20));
+    assert_eq!(Err(ConfigError::BelowMinimum(65536)), server.set_max_message_size(65535));
+    assert_eq!(1 << 20, server.config.max_message_size);
+
+    server.allow_non_conforming_limits();
+    assert_eq!(Ok(()), server.set_max_recipients(1));
+    assert_eq!(1, server.config.max_recipients);
+    assert_eq!(Ok(()), server.set_max_message_size(1024));
+    assert_eq!(1024, server.config.max_message_size);
+    assert_eq!(Err(ConfigError::Zero), server.set_max_recipients(0));
+    assert_eq!(Err(ConfigError::Zero), server.set_max_message_size(0));
+}
+
+#[test]
+fn test_config() {
+    let mut config: ServerConfig<TestContainer> = ServerConfig::new();
+    assert_eq!(Ok(()), config.set_hostname("mx.rustastic.org"));
+    assert_eq!(Err(ConfigError::Malformed), config.set_hostname("mx rustastic.org"));
+    assert_eq!(Err(ConfigError::Malformed), config.set_hostname("mx.rustastic.org."));
+    assert_eq!("mx.rustastic.org", config.hostname());
+    assert_eq!(Err(ConfigError::BelowMinimum(300000)), config.set_command_timeout(Duration::from_secs(60)));
+    assert_eq!(Ok(()), config.set_data_timeout(Duration::from_secs(900)));
+    assert_eq!(Err(ConfigError::Zero), config.set_max_connections(0));
+    assert_eq!(Ok(()), config.set_max_connections_per_ip(10));
+    assert_eq!(Err(ConfigError::Zero), config.set_max_header_count(0));
+    assert_eq!(Ok(()), config.set_max_header_count(10));
+    config.add_extension("SIZE");
+    config.add_address(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 25);
+    assert!(!config.allows_non_conforming_limits());
+    config.allow_non_conforming_limits();
+    assert_eq!(Ok(()), config.set_max_recipients(1));
+
+    let server = Server::with_config(TestContainer::new(), config);
+    let config = server.config();
+    assert_eq!("mx.rustastic.org", config.hostname());
+    assert_eq!(1, config.max_recipients());
+    assert_eq!(65536, config.max_message_size());
+    assert_eq!(Duration::from_secs(300), config.command_timeout());
+    assert_eq!(Duration::from_secs(900), config.data_timeout());
+    assert_eq!(10, config.header_limits().max_count);
+    assert_eq!(None, config.max_connections());
+    assert_eq!(Some(10), config.max_connections_per_ip());
+    assert_eq!(None, config.max_errors_per_session());
+    assert_eq!(&["SIZE".to_owned()][..], config.extensions());
+    assert_eq!(&[(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 25)][..], config.addresses());
+    assert_eq!(None, config.tls_files());
+}
+
+#[test]
+fn test_check_limits() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(commands::mail::get());
+    server.add_command(commands::rcpt::get());
+    let mut session = TestSession::new();
+
+    // 254 octets for the mailbox, 256 for the path.
+    let local_part: String = repeat('a').take(64).collect();
+    let domain: String = repeat("abcdefghi.").take(17).collect::<String>() + "abcde.rustastic.org";
+    let line = format!("RCPT TO:<{}@{}>", local_part, domain);
+    container.transaction.start(None);
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line.as_ref());
+    assert_eq!("250 2.1.5 OK", session.reply());
+
+    let line = format!("RCPT TO:<{}@x{}>", local_part, domain);
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line.as_ref());
+    assert_eq!("501 5.5.4 Path too long", session.reply());
+    assert_eq!(1, container.transaction.recipients().len());
+
+    // 512 octets are allowed, plus 500 for DSN on RCPT and 14 for `BODY=8BITMIME` and 110
+    // for DSN on MAIL.
+    let line = format!("RCPT TO:<a@rustastic.org>{}", repeat(' ').take(512 + 500 - 2 - 25).collect::<String>());
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line.as_ref());
+    assert!(!session.reply().starts_with("500"));
+    let line = line + " ";
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line.as_ref());
+    assert_eq!("500 5.5.6 Line too long", session.reply());
+
+    container.transaction.reset();
+    let line = format!("MAIL FROM:<a@rustastic.org>{}", repeat(' ').take(512 + 14 + 110 - 2 - 27).collect::<String>());
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line.as_ref());
+    assert!(!session.reply().starts_with("500"));
+    let line = line + " ";
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line.as_ref());
+    assert_eq!("500 5.5.6 Line too long", session.reply());
+}
+
+#[test]
+fn test_pipelining() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(commands::mail::get());
+    server.add_command(commands::rcpt::get());
+    server.add_command(commands::data::get());
+    let mut session = TestSession::new();
+
+    // DATA pipelined after recipients that were all rejected.
+    session.send_bytes(b"MAIL FROM:<a@rustastic.org>\r\nRCPT TO:<b@>\r\nDATA\r\n");
+    for _ in 0 .. 3 {
+        Server::handle_next_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state).unwrap();
+    }
+    assert_eq!("250 2.1.0 OK", session.reply());
+    assert!(session.reply().starts_with("553"));
+    assert_eq!("554 5.5.1 No valid recipients", session.reply());
+    assert!(!container.transaction.is_started());
+
+    // The message can't be sent before the reply to DATA.
+    session.send_bytes(b"MAIL FROM:<a@rustastic.org>\r\nRCPT TO:<b@rustastic.org>\r\nDATA\r\nSubject: hi\r\n");
+    for _ in 0 .. 4 {
+        Server::handle_next_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state).unwrap();
+    }
+    assert_eq!("250 2.1.0 OK", session.reply());
+    assert_eq!("250 2.1.5 OK", session.reply());
+    assert_eq!("503 5.5.0 Improper use of SMTP command pipelining", session.reply());
+    assert_eq!("500 5.5.1 Command unrecognized", session.reply());
+    assert_eq!(None, container.data);
+}
+
+#[test]
+fn test_greeting() {
+    let mut server = Server::new(());
+    server.set_hostname("rustastic.org").unwrap();
+    assert_eq!(vec!["220 rustastic.org Service ready"], Server::greeting(&server.config).to_lines());
+    server.accept_no_mail();
+    assert_eq!(vec!["521 5.3.2 rustastic.org does not accept mail"], Server::greeting(&server.config).to_lines());
+}
+
+#[test]
+fn test_mixed_case() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.add_command(commands::mail::get());
+    server.add_command(commands::rcpt::get());
+    let mut session = TestSession::new();
+
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "mail from:<Rust@Rustastic.org> body=8bitmime");
+    assert_eq!("250 2.1.0 OK", session.reply());
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "Rcpt To:<Alice@rustastic.org>");
+    assert_eq!("250 2.1.5 OK", session.reply());
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "rcpt\tto:<b@rustastic.org>");
+    assert_eq!("500 5.5.1 Command unrecognized", session.reply());
+
+    // The arguments keep their case.
+    assert_eq!("Rust", container.transaction.sender().unwrap().local_part());
+    assert_eq!("Alice", container.transaction.recipients()[0].local_part());
+}
+
+#[test]
+fn test_session_errors() {
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let hook_errors = errors.clone();
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.set_hostname("rustastic.org").unwrap();
+    server.set_on_error(move |err| {
+        hook_errors.lock().unwrap().push(format!("{:?}", err));
+    });
+    let mut command = Command::new();
+    command.starts_with("NOOP");
+    server.add_command(command);
+    let mut session = TestSession::new();
+
+    // A command without middleware fails, but the session goes on.
+    Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "NOOP");
+    assert_eq!("451 4.3.0 Requested action aborted: local error in processing", session.reply());
+    assert_eq!(vec!["InvalidCommand(\"NOOP\")".to_owned()], *errors.lock().unwrap());
+
+    // Lines that can't be read end the session with a 421.
+    let line: String = repeat('a').take(1000).collect();
+    session.send(line.as_ref());
+    let err = Server::handle_next_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state).unwrap_err();
+    Server::close_session(&server.config, session.state.id(), &mut session.output, &err);
+    assert_eq!("421 4.3.0 rustastic.org Service not available, closing transmission channel", session.reply());
+    assert_eq!(2, errors.lock().unwrap().len());
+
+    // There is no one to tell when the client is gone.
+    let mut output = OutputStream::new(Vec::new(), false);
+    let err = SessionError::Read(IoError::new(ErrorKind::UnexpectedEof, "unexpected end of stream"));
+    Server::close_session(&server.config, None, &mut output, &err);
+    assert_eq!(0, output.get_ref().len());
+    assert_eq!(3, errors.lock().unwrap().len());
+}
+
+#[cfg(test)]
+fn keep_transcript(container: &mut TestContainer, transcript: &Transcript) {
+    container.transcripts.lock().unwrap().push(transcript.to_text());
+}
+
+#[test]
+fn test_transcript() {
+    let container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.set_hostname("rustastic.org").unwrap();
+    server.add_command(commands::helo::get());
+    server.add_command(commands::mail::get());
+    server.set_transcript_handler(keep_transcript);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    client.write_all(b"HELO rustastic.org\r\nAUTH PLAIN c2VjcmV0\r\nMAIL FROM:<rust@rustastic.org>\r\n").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let input = Transport::Tcp(InstrumentedStream::new(stream.try_clone().unwrap()));
+    assert!(server.serve(input, Transport::Tcp(InstrumentedStream::new(stream))).is_err());
+
+    assert_eq!(vec![[
+        "S: 220 rustastic.org Service ready",
+        "C: HELO rustastic.org",
+        "S: 250 rustastic.org",
+        "C: AUTH PLAIN ***",
+        "S: 500 5.5.1 Command unrecognized",
+        "C: MAIL FROM:<rust@rustastic.org>",
+        "S: 250 2.1.0 OK\n"
+    ].join("\n")], *container.transcripts.lock().unwrap());
+}
+
+#[test]
+fn test_timeouts() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.set_hostname("rustastic.org").unwrap();
+    server.add_command(commands::data::get());
+    assert_eq!(Err(ConfigError::BelowMinimum(300000)), server.set_command_timeout(Duration::from_millis(50)));
+    assert_eq!(Err(ConfigError::Zero), server.set_data_timeout(Duration::from_millis(0)));
+    server.allow_non_conforming_limits();
+    server.set_command_timeout(Duration::from_millis(50)).unwrap();
+    server.set_data_timeout(Duration::from_millis(50)).unwrap();
+    let mut session = TestSession::new();
+
+    // A client that sends no command.
+    let err = Server::handle_next_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state).unwrap_err();
+    Server::close_session(&server.config, session.state.id(), &mut session.output, &err);
+    assert_eq!("421 4.4.2 rustastic.org Timeout, closing transmission channel", session.reply());
+
+    // A client that stops in the middle of a message.
+    container.transaction.start(None);
+    container.transaction.add_recipient(Mailbox::parse("rust@rustastic.org").unwrap());
+    session.send("DATA");
+    let err = Server::handle_next_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state).unwrap_err();
+    assert!(session.state.is_timed_out());
+    Server::close_session(&server.config, session.state.id(), &mut session.output, &err);
+    assert_eq!("354 Start mail input; end with <CRLF>.<CRLF>", session.reply());
+    assert_eq!("421 4.4.2 rustastic.org Timeout, closing transmission channel", session.reply());
+}
+
+#[test]
+fn test_id_generator() {
+    let mut server = Server::new(0usize);
+    server.set_hostname("rustastic.org").unwrap();
+    server.set_id_generator(Snowflake::new(7, 0).unwrap());
+    let id: u64 = server.config.generate_id().parse().unwrap();
+    assert_eq!(7, (id >> 12) & 0x3ff);
+    let message_id = server.config.message_id();
+    assert!(message_id.starts_with("<") && message_id.ends_with("@rustastic.org>"));
+}
+
+#[test]
+fn test_worker_pool() {
+    let mut server = Server::new(());
+    server.set_hostname("rustastic.org").unwrap();
+    assert_eq!(Err(ConfigError::Zero), server.set_worker_pool(0, 10));
+    server.set_worker_pool(4, 0).unwrap();
+    assert_eq!(Some((4, 0)), server.config.workers);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    Server::reject_session(&server.config, stream, &SessionError::TooBusy);
+    let mut reply = String::new();
+    BufReader::new(client).read_line(&mut reply).unwrap();
+    assert_eq!("421 4.3.2 rustastic.org Too busy, closing transmission channel\r\n", reply);
+}
+
+#[cfg(test)]
+struct PanicLogger;
+
+#[cfg(test)]
+impl Logger for PanicLogger {
+    fn command(&self, _: &str, _: &str) {
+        panic!("logger failed");
+    }
+}
+
+#[test]
+fn test_worker_pool_panics() {
+    let mut server = Server::new(());
+    server.set_hostname("rustastic.org").unwrap();
+    server.set_worker_pool(1, 1).unwrap();
+    server.set_logger(PanicLogger);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let _ = server.listen_on(listener);
+    });
+
+    // The hook panics for every session, and the only worker still serves the next one.
+    for _ in 0 .. 2 {
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut reply = String::new();
+        reader.read_line(&mut reply).unwrap();
+        assert!(reply.starts_with("220 "));
+        client.write_all(b"NOOP\r\n").unwrap();
+        reply.clear();
+        reader.read_line(&mut reply).unwrap();
+        assert_eq!("421 4.3.0 rustastic.org Service not available, closing transmission channel\r\n", reply);
+    }
+}
+
+#[test]
+fn test_connection_limits() {
+    let mut server = Server::new(());
+    server.set_hostname("rustastic.org").unwrap();
+    assert_eq!(Err(ConfigError::Zero), server.set_max_connections(0));
+    assert_eq!(Err(ConfigError::Zero), server.set_max_connections_per_ip(0));
+    server.set_max_connections(10).unwrap();
+    server.set_max_connections_per_ip(1).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut admitted = Vec::new();
+    for expected in [true, false, true].iter() {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        match Server::admit(&server.config, stream) {
+            Some(permit) => {
+                assert!(*expected);
+                admitted.push(permit);
+            },
+            None => {
+                assert!(!*expected);
+                let mut reply = String::new();
+                BufReader::new(client).read_line(&mut reply).unwrap();
+                assert_eq!("421 4.7.0 rustastic.org Too many connections, closing transmission channel\r\n", reply);
+                // The counter goes down when a session ends.
+                admitted.clear();
+            }
+        }
+    }
+    assert_eq!(1, server.config.connections.total());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_accept_filter() {
+    let mut server = Server::new(());
+    assert_eq!(Err(ConfigError::Zero), server.set_accept_filter(Duration::from_secs(0)));
+    server.set_accept_filter(Duration::from_secs(5)).unwrap();
+    let listener = server.get_listener_for_address(("127.0.0.1".parse().unwrap(), 0)).unwrap();
+
+    let mut seconds: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(listener.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT,
+            &mut seconds as *mut libc::c_int as *mut libc::c_void, &mut len)
+    };
+    assert_eq!(0, res);
+    assert!(seconds > 0);
+}
+
+#[test]
+fn test_listen_on() {
+    let mut server = Server::new(());
+    server.set_hostname("rustastic.org").unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let _ = server.listen_on(listener);
+    });
+    let mut reply = String::new();
+    BufReader::new(TcpStream::connect(address).unwrap()).read_line(&mut reply).unwrap();
+    assert_eq!("220 rustastic.org Service ready\r\n", reply);
+}
+
+#[test]
+fn test_listen_unix() {
+    let path = env::temp_dir().join(format!("rsmtp-test-{}.sock", Ulid::new().generate()));
+    let mut server: Server<(), UnixStream> = Server::with_stream(());
+    server.set_hostname("rustastic.org").unwrap();
+    server.add_command(commands::noop::get());
+    server.set_max_connections(1).unwrap();
+    let connections = server.config.connections.clone();
+    let listener = UnixListener::bind(&path).unwrap();
+    thread::spawn(move || {
+        let _ = server.listen_on_unix(listener);
+    });
+
+    let mut client = SmtpClient::connect_unix(&path).unwrap();
+    assert_eq!(220, client.banner().code());
+    assert_eq!(250, client.command("NOOP").unwrap().code());
+    assert_eq!(1, connections.total());
+
+    let mut reply = String::new();
+    BufReader::new(UnixStream::connect(&path).unwrap()).read_line(&mut reply).unwrap();
+    assert_eq!("421 4.7.0 rustastic.org Too many connections, closing transmission channel\r\n", reply);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_listen_unix_panics() {
+    let path = env::temp_dir().join(format!("rsmtp-test-{}.sock", Ulid::new().generate()));
+    let mut server: Server<(), UnixStream> = Server::with_stream(());
+    server.set_hostname("rustastic.org").unwrap();
+    server.set_logger(PanicLogger);
+    let listener = UnixListener::bind(&path).unwrap();
+    thread::spawn(move || {
+        let _ = server.listen_on_unix(listener);
+    });
+
+    let mut client = SmtpClient::connect_unix(&path).unwrap();
+    assert_eq!(220, client.banner().code());
+    assert_eq!(421, client.command("NOOP").unwrap().code());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_listen_all() {
+    let mut server = Server::new(());
+    server.set_hostname("rustastic.org").unwrap();
+    assert_eq!(Err(ServerError::Bind), server.listen_all());
+
+    let listeners = vec![TcpListener::bind("127.0.0.1:0").unwrap(), TcpListener::bind("127.0.0.1:0").unwrap()];
+    let addresses: Vec<SocketAddr> = listeners.iter().map(|listener| listener.local_addr().unwrap()).collect();
+    thread::spawn(move || {
+        let _ = server.accept(listeners);
+    });
+    for address in addresses.iter().chain(addresses.iter()) {
+        let mut reply = String::new();
+        BufReader::new(TcpStream::connect(address).unwrap()).read_line(&mut reply).unwrap();
+        assert!(reply.starts_with("220 rustastic.org"));
+    }
+}
+
+#[test]
+fn test_accept_ready() {
+    let errors = Arc::new(AtomicUsize::new(0));
+    let hook_errors = errors.clone();
+    let mut server = Server::new(());
+    server.set_hostname("rustastic.org").unwrap();
+    server.set_on_error(move |err| {
+        match *err {
+            SessionError::Accept(_) => hook_errors.fetch_add(1, Ordering::SeqCst),
+            _ => panic!("unexpected error: {:?}", err)
+        };
+    });
+    let config = Arc::new(server.config.clone());
+
+    // A listener out of file descriptors fails every time until a session ends.
+    let mut accepts = 0;
+    server.accept_ready(|| {
+        accepts += 1;
+        Err(IoError::from_raw_os_error(libc::EMFILE))
+    }, &config, None);
+    assert_eq!(1, accepts);
+    assert_eq!(1, errors.load(Ordering::SeqCst));
+
+    let mut accepts = 0;
+    server.accept_ready(|| {
+        accepts += 1;
+        Err(IoError::new(ErrorKind::WouldBlock, "no connection"))
+    }, &config, None);
+    assert_eq!(1, accepts);
+    assert_eq!(1, errors.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_proxy_protocol() {
+    let mut server = Server::new(());
+    server.set_proxy_protocol(true);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    client.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 25\r\nEHLO rustastic.org\r\n").unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let (mut input, _) = Server::open_streams(&server.config, stream).ok().unwrap();
+    assert_eq!("192.0.2.1:56324".parse::<SocketAddr>().unwrap(), input.peer_addr().unwrap());
+    assert_eq!(b"EHLO rustastic.org", input.read_line().unwrap());
+
+    // The connection is closed without a reply if the header is missing.
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    client.write_all(b"EHLO rustastic.org\r\n").unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    match Server::open_streams(&server.config, stream) {
+        Err((_, SessionError::Proxy(_))) => {},
+        _ => panic!("expected a PROXY error")
+    }
+}
+
+#[test]
+fn test_max_errors() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.set_hostname("rustastic.org").unwrap();
+    server.add_command(commands::data::get());
+    assert_eq!(Err(ConfigError::Zero), server.set_max_errors_per_session(0));
+    server.set_max_errors_per_session(3).unwrap();
+    let mut session = TestSession::new();
+
+    // Other replies start the count over.
+    for line in ["JUNK", "DATA", "DATA x", "JUNK", "JUNK"].iter() {
+        session.send(line);
+        Server::handle_next_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state).unwrap();
+    }
+    session.send("JUNK");
+    let err = Server::handle_next_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state).unwrap_err();
+    Server::close_session(&server.config, session.state.id(), &mut session.output, &err);
+    for _ in 0 .. 6 {
+        session.reply();
+    }
+    assert_eq!("421 4.7.0 rustastic.org Too many errors, closing transmission channel", session.reply());
+}
+
+#[test]
+fn test_serve() {
+    let mut server = Server::with_stream(TestContainer::new());
+    server.set_hostname("rustastic.org").unwrap();
+    server.add_command(commands::helo::get());
+    server.add_command(commands::mail::get());
+    server.add_command(commands::noop::get());
+    let stream = MemoryStream::new(&["HELO rustastic.org", "MAIL FROM:<rust@rustastic.org>", "noop"]);
+
+    // The session ends when the client has nothing more to say.
+    match server.serve(stream.clone(), stream.clone()) {
+        Err(SessionError::Read(ref err)) => assert_eq!(ErrorKind::UnexpectedEof, err.kind()),
+        res => panic!("unexpected result: {:?}", res)
+    }
+    assert_eq!(vec![
+        "220 rustastic.org Service ready".to_owned(),
+        "250 rustastic.org".to_owned(),
+        "250 2.1.0 OK".to_owned(),
+        "250 2.0.0 OK".to_owned()
+    ], stream.written());
+}
+
+#[test]
+fn test_read_errors() {
+    let mut server = Server::with_stream(TestContainer::new());
+    server.set_hostname("rustastic.org").unwrap();
+    server.add_command(commands::helo::get());
+    server.add_command(commands::mail::get());
+    server.add_command(commands::rcpt::get());
+    server.add_command(commands::data::get());
+    server.add_command(commands::bdat::get());
+
+    // The client goes away in the middle of a message, which ends the session without
+    // unwinding.
+    for &(command, content) in [("DATA", "Hello"), ("BDAT 100 LAST", "Hello")].iter() {
+        let stream = MemoryStream::new(&["HELO rustastic.org", "MAIL FROM:<rust@rustastic.org>", "RCPT TO:<smtp@rustastic.org>", command, content]);
+        match server.serve(stream.clone(), stream.clone()) {
+            Err(SessionError::Read(ref err)) => assert_eq!(ErrorKind::UnexpectedEof, err.kind()),
+            res => panic!("unexpected result: {:?}", res)
+        }
+        assert_eq!("250 2.1.5 OK", stream.written()[3]);
+    }
+}
+
+#[test]
+fn test_session_summary() {
+    let summaries = Arc::new(Mutex::new(Vec::new()));
+    let mut server = Server::with_stream(TestContainer::new());
+    server.set_hostname("rustastic.org").unwrap();
+    server.add_command(commands::helo::get());
+    server.add_command(commands::mail::get());
+    server.add_command(commands::rcpt::get());
+    server.add_command(commands::data::get());
+    let copy = summaries.clone();
+    server.set_on_summary(move |summary| copy.lock().unwrap().push(summary.clone()));
+    let stream = MemoryStream::new(&[
+        "HELO rustastic.org", "DATA",
+        "MAIL FROM:<rust@rustastic.org>", "RCPT TO:<smtp@rustastic.org>", "DATA", "Hello", ".",
+        "VRFY rust"
+    ]);
+
+    assert!(server.serve(stream.clone(), stream).is_err());
+    let summaries = summaries.lock().unwrap();
+    assert_eq!(1, summaries.len());
+    assert_eq!(Some("rustastic.org"), summaries[0].domain.as_ref().map(|domain| domain.as_ref()));
+    assert_eq!(6, summaries[0].commands);
+    assert_eq!(1, summaries[0].messages_accepted);
+    // DATA before RCPT refuses no message.
+    assert_eq!(0, summaries[0].messages_rejected);
+    assert_eq!(None, summaries[0].tls);
+    assert_eq!("client_closed", summaries[0].close_reason);
+}
+
+#[test]
+fn test_control_policy_commands() {
+    let mut server = Server::with_stream(TestContainer::new());
+    server.set_hostname("rustastic.org").unwrap();
+    server.add_command(commands::helo::get());
+    server.add_command(commands::noop::get());
+    let stream = MemoryStream::new(&["HELO rustastic.org", "NO\u{0}OP", "NOOP\u{7}"]);
+    server.set_control_policy(ControlPolicy::Reject);
+    assert!(server.serve(stream.clone(), stream.clone()).is_err());
+    assert_eq!(vec![
+        "220 rustastic.org Service ready".to_owned(),
+        "250 rustastic.org".to_owned(),
+        "500 5.5.2 Syntax error, control characters not allowed".to_owned(),
+        "500 5.5.2 Syntax error, control characters not allowed".to_owned()
+    ], stream.written());
+
+    let stream = MemoryStream::new(&["HELO rustastic.org", "NO\u{0}OP"]);
+    server.set_control_policy(ControlPolicy::Strip);
+    assert!(server.serve(stream.clone(), stream.clone()).is_err());
+    assert_eq!("250 2.0.0 OK", stream.written()[2]);
+}
@@ -0,0 +1,131 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The addresses of the people running the server, which take mail whatever the other
+//! policies say.
+//!
+//! `Postmaster` at the domains of the server, and `<Postmaster>` without a domain, must
+//! always be accepted [as per RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.5.1).
+//! `abuse`, described [in RFC 2142](http://tools.ietf.org/html/rfc2142#section-4), can be
+//! accepted the same way.
+//!
+//! Such recipients skip the no mail domains and the subaddress policy. They are still
+//! given to the `RcptHandler`, and when it refuses them, the route of the operators picks
+//! the mailbox that gets their mail:
+//!
+//! ```ignore
+//! let mut operators = OperatorAddresses::new();
+//! operators.add_domain("rustastic.org");
+//! operators.accept_abuse();
+//! operators.set_route(|_: &Mailbox| Mailbox::parse("ops@rustastic.org").unwrap());
+//! server.set_operator_addresses(operators);
+//! ```
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::sync::Arc;
+use std::vec::Vec;
+use super::super::common::mailbox::{Mailbox, MailboxForeignPart};
+
+/// Picks the mailbox that gets mail to an operator address, when the `RcptHandler`
+/// refuses it.
+pub trait OperatorRoute: Send + Sync {
+    /// Returns the mailbox that gets the mail of the recipient.
+    fn route(&self, recipient: &Mailbox) -> Mailbox;
+}
+
+impl<F: Fn(&Mailbox) -> Mailbox + Send + Sync> OperatorRoute for F {
+    fn route(&self, recipient: &Mailbox) -> Mailbox {
+        self(recipient)
+    }
+}
+
+/// The operator addresses a server always accepts.
+///
+/// See `Server::set_operator_addresses`.
+#[derive(Clone)]
+pub struct OperatorAddresses {
+    domains: Vec<String>,
+    abuse: bool,
+    route: Option<Arc<OperatorRoute>>
+}
+
+impl OperatorAddresses {
+    /// Accepts `Postmaster` at the hostname of the server only.
+    pub fn new() -> OperatorAddresses {
+        OperatorAddresses {
+            domains: Vec::new(),
+            abuse: false,
+            route: None
+        }
+    }
+
+    /// Accepts the operator addresses of another domain of the server.
+    pub fn add_domain(&mut self, domain: &str) {
+        self.domains.push(domain.to_owned());
+    }
+
+    /// Accepts `abuse` too.
+    pub fn accept_abuse(&mut self) {
+        self.abuse = true;
+    }
+
+    /// Sets the route used for operator addresses the `RcptHandler` refuses.
+    ///
+    /// Without a route, they are added to the transaction as they are.
+    pub fn set_route<R: 'static + OperatorRoute>(&mut self, route: R) {
+        self.route = Some(Arc::new(route));
+    }
+
+    /// Tells whether the mailbox is an operator address of a server with this hostname.
+    pub fn is_operator(&self, mailbox: &Mailbox, hostname: &str) -> bool {
+        let local = mailbox.is_postmaster() || (self.abuse && mailbox.local_part().eq_ignore_ascii_case("abuse"));
+        local && match *mailbox.foreign_part() {
+            MailboxForeignPart::Domain(ref domain) => {
+                domain.eq_ignore_ascii_case(hostname) || self.domains.iter().any(|d| d.eq_ignore_ascii_case(domain))
+            },
+            MailboxForeignPart::IpAddr(_) => false
+        }
+    }
+
+    /// Returns the mailbox that gets the mail of a refused operator address.
+    pub fn route(&self, recipient: &Mailbox) -> Mailbox {
+        match self.route {
+            Some(ref route) => route.route(recipient),
+            None => recipient.clone()
+        }
+    }
+}
+
+#[test]
+fn test_is_operator() {
+    let mut operators = OperatorAddresses::new();
+    let postmaster = Mailbox::parse("PostMaster@rustastic.org").unwrap();
+    let abuse = Mailbox::parse("abuse@rustastic.org").unwrap();
+    assert!(operators.is_operator(&postmaster, "Rustastic.org"));
+    assert!(!operators.is_operator(&postmaster, "mx.rustastic.org"));
+    assert!(!operators.is_operator(&abuse, "rustastic.org"));
+    assert!(!operators.is_operator(&Mailbox::parse("postmaster@[127.0.0.1]").unwrap(), "rustastic.org"));
+
+    operators.add_domain("rustastic.org");
+    operators.accept_abuse();
+    assert!(operators.is_operator(&postmaster, "mx.rustastic.org"));
+    assert!(operators.is_operator(&abuse, "mx.rustastic.org"));
+    assert!(!operators.is_operator(&Mailbox::parse("rust@rustastic.org").unwrap(), "mx.rustastic.org"));
+    assert!(!operators.is_operator(&Mailbox::parse("abuse@example.org").unwrap(), "mx.rustastic.org"));
+
+    assert_eq!(abuse, operators.route(&abuse));
+    operators.set_route(|_: &Mailbox| Mailbox::parse("ops@rustastic.org").unwrap());
+    assert_eq!(Mailbox::parse("ops@rustastic.org").unwrap(), operators.route(&abuse));
+}
@@ -0,0 +1,216 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conditions that can be combined to decide whether a middleware runs, or whether a
+//! command is allowed at all.
+//!
+//! # Example
+//!
+//! ```ignore
+//! // Only relay from trusted networks or for authenticated clients, over TLS.
+//! command.require(all_of(vec![
+//!     condition(is_tls),
+//!     any_of(vec![condition(is_trusted_network), condition(is_authenticated)])
+//! ]), Reply::enhanced(530, EnhancedStatusCode::new(5, 7, 0), "Authentication required"));
+//! ```
+
+use std::net::IpAddr;
+use std::vec::Vec;
+#[cfg(test)]
+use std::net::{Ipv4Addr, Ipv6Addr};
+use super::ServerConfig;
+#[cfg(test)]
+use super::Server;
+#[cfg(test)]
+use super::testing::MemoryStream;
+use super::commands::AuthState;
+use super::super::common::stream::{InputStream, SessionStream};
+
+/// A condition on the state of a session, given the current command line.
+pub type ConditionFn<CT, ST> = fn(&ServerConfig<CT, ST>, &mut CT, &InputStream<ST>, &str) -> bool;
+
+/// A condition built from functions and combinators.
+pub enum Condition<CT, ST> {
+    /// Holds when the function returns `true`.
+    Fn(ConditionFn<CT, ST>),
+    /// Holds when all the conditions hold.
+    AllOf(Vec<Condition<CT, ST>>),
+    /// Holds when at least one of the conditions holds.
+    AnyOf(Vec<Condition<CT, ST>>),
+    /// Holds when the condition doesn't hold.
+    Not(Box<Condition<CT, ST>>)
+}
+
+impl<CT, ST> Clone for Condition<CT, ST> {
+    fn clone(&self) -> Condition<CT, ST> {
+        match *self {
+            Condition::Fn(f) => Condition::Fn(f),
+            Condition::AllOf(ref conditions) => Condition::AllOf(conditions.clone()),
+            Condition::AnyOf(ref conditions) => Condition::AnyOf(conditions.clone()),
+            Condition::Not(ref condition) => Condition::Not(condition.clone())
+        }
+    }
+}
+
+impl<CT, ST> Condition<CT, ST> {
+    /// Tells whether the condition holds.
+    ///
+    /// Conditions are evaluated from left to right, and only until the result is known.
+    pub fn holds(&self, config: &ServerConfig<CT, ST>, container: &mut CT, input: &InputStream<ST>, line: &str) -> bool {
+        match *self {
+            Condition::Fn(f) => f(config, container, input, line),
+            Condition::AllOf(ref conditions) => {
+                conditions.iter().all(|c| c.holds(config, container, input, line))
+            },
+            Condition::AnyOf(ref conditions) => {
+                conditions.iter().any(|c| c.holds(config, container, input, line))
+            },
+            Condition::Not(ref condition) => !condition.holds(config, container, input, line)
+        }
+    }
+}
+
+/// Returns a condition that holds when the function returns `true`.
+pub fn condition<CT, ST>(f: ConditionFn<CT, ST>) -> Condition<CT, ST> {
+    Condition::Fn(f)
+}
+
+/// Returns a condition that holds when all the conditions hold.
+pub fn all_of<CT, ST>(conditions: Vec<Condition<CT, ST>>) -> Condition<CT, ST> {
+    Condition::AllOf(conditions)
+}
+
+/// Returns a condition that holds when at least one of the conditions holds.
+pub fn any_of<CT, ST>(conditions: Vec<Condition<CT, ST>>) -> Condition<CT, ST> {
+    Condition::AnyOf(conditions)
+}
+
+/// Returns a condition that holds when the condition doesn't hold.
+pub fn not<CT, ST>(condition: Condition<CT, ST>) -> Condition<CT, ST> {
+    Condition::Not(Box::new(condition))
+}
+
+/// Holds when the client authenticated.
+pub fn is_authenticated<CT: AuthState, ST>(_: &ServerConfig<CT, ST>, container: &mut CT, _: &InputStream<ST>, _: &str) -> bool {
+    container.authenticated().is_some()
+}
+
+/// Holds when the session is encrypted with TLS.
+pub fn is_tls<CT, ST: SessionStream>(_: &ServerConfig<CT, ST>, _: &mut CT, input: &InputStream<ST>, _: &str) -> bool {
+    input.get_ref().is_tls()
+}
+
+/// Holds when the client connected from one of the trusted networks of the server.
+///
+/// See `Server::add_trusted_network`.
+pub fn is_trusted_network<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, _: &mut CT, input: &InputStream<ST>, _: &str) -> bool {
+    match input.peer_addr() {
+        Ok(addr) => {
+            let ip = addr.ip();
+            config.trusted_networks.iter().any(|&(ref network, prefix)| {
+                in_network(&ip, network, prefix)
+            })
+        },
+        Err(_) => false
+    }
+}
+
+fn prefix_matches(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let prefix = prefix as usize;
+    if prefix > a.len() * 8 {
+        return false;
+    }
+    let bytes = prefix / 8;
+    let bits = prefix % 8;
+    if a[.. bytes] != b[.. bytes] {
+        return false;
+    }
+    match bits {
+        0 => true,
+        _ => {
+            let mask = 0xffu8 << (8 - bits);
+            a[bytes] & mask == b[bytes] & mask
+        }
+    }
+}
+
+/// Tells whether an address is in the network with the given prefix length.
+///
+/// IPv4 and IPv6 addresses never match each other.
+pub fn in_network(ip: &IpAddr, network: &IpAddr, prefix: u8) -> bool {
+    match (*ip, *network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            prefix_matches(&ip.octets(), &network.octets(), prefix)
+        },
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mut ip_bytes = Vec::with_capacity(16);
+            let mut network_bytes = Vec::with_capacity(16);
+            for (a, b) in ip.segments().iter().zip(network.segments().iter()) {
+                ip_bytes.push((*a >> 8) as u8);
+                ip_bytes.push(*a as u8);
+                network_bytes.push((*b >> 8) as u8);
+                network_bytes.push(*b as u8);
+            }
+            prefix_matches(ip_bytes.as_ref(), network_bytes.as_ref(), prefix)
+        },
+        _ => false
+    }
+}
+
+#[test]
+fn test_in_network() {
+    let network = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0));
+    assert!(in_network(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 42)), &network, 24));
+    assert!(in_network(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 42)), &network, 0));
+    assert!(!in_network(&IpAddr::V4(Ipv4Addr::new(192, 168, 2, 42)), &network, 24));
+    assert!(in_network(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 127)), &network, 25));
+    assert!(!in_network(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 128)), &network, 25));
+    assert!(in_network(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)), &network, 32));
+    assert!(!in_network(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)), &network, 33));
+
+    let network = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0));
+    assert!(in_network(&IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)), &network, 32));
+    assert!(!in_network(&IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb9, 0, 0, 0, 0, 0, 1)), &network, 32));
+    assert!(!in_network(&IpAddr::V4(Ipv4Addr::new(32, 1, 13, 184)), &network, 16));
+}
+
+#[cfg(test)]
+fn is_even(_: &ServerConfig<usize, MemoryStream>, container: &mut usize, _: &InputStream<MemoryStream>, _: &str) -> bool {
+    *container % 2 == 0
+}
+
+#[cfg(test)]
+fn is_small(_: &ServerConfig<usize, MemoryStream>, container: &mut usize, _: &InputStream<MemoryStream>, _: &str) -> bool {
+    *container < 10
+}
+
+#[test]
+fn test_condition() {
+    let server = Server::<usize, MemoryStream>::with_stream(0usize);
+    let input = InputStream::new(MemoryStream::new(&[]), 1000, false);
+
+    let even_and_small = all_of(vec![condition(is_even), condition(is_small)]);
+    let even_or_small = any_of(vec![condition(is_even), condition(is_small)]);
+    let odd = not(condition(is_even));
+
+    for &(mut n, and, or, odd_expected) in [(4, true, true, false), (5, false, true, true), (12, false, true, false), (13, false, false, true)].iter() {
+        assert_eq!(and, even_and_small.holds(&server.config, &mut n, &input, ""));
+        assert_eq!(or, even_or_small.holds(&server.config, &mut n, &input, ""));
+        assert_eq!(odd_expected, odd.clone().holds(&server.config, &mut n, &input, ""));
+    }
+
+    // Empty combinations.
+    assert!(all_of::<usize, MemoryStream>(vec![]).holds(&server.config, &mut 0, &input, ""));
+    assert!(!any_of::<usize, MemoryStream>(vec![]).holds(&server.config, &mut 0, &input, ""));
+}
@@ -0,0 +1,145 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! A fixed number of worker threads handling jobs from a bounded queue.
+//!
+//! The server uses this to handle connections without spawning a thread per client, see
+//! `Server::set_worker_pool`.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+#[cfg(test)]
+use std::sync::mpsc::channel;
+#[cfg(test)]
+use std::sync::Barrier;
+
+/// Worker threads handling jobs of type `T`.
+///
+/// The workers stop when the pool is dropped, after the jobs already in the queue.
+pub struct WorkerPool<T> {
+    sender: Option<SyncSender<T>>,
+    workers: Vec<thread::JoinHandle<()>>
+}
+
+impl<T: 'static + Send> WorkerPool<T> {
+    /// Starts `workers` threads, each calling the handler with the jobs it gets.
+    ///
+    /// At most `queue` jobs wait for a worker, `submit` fails beyond that. A queue of `0`
+    /// only accepts jobs when a worker is idle. A worker whose job panics goes on with the
+    /// next one.
+    pub fn new<F: 'static + Fn(T) + Send + Sync>(workers: usize, queue: usize, handler: F) -> WorkerPool<T> {
+        let (sender, receiver) = sync_channel(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+        let workers = (0 .. workers).map(|i| {
+            let receiver = receiver.clone();
+            let handler = handler.clone();
+            thread::Builder::new().name(format!("smtp-worker-{}", i)).spawn(move || {
+                WorkerPool::work(receiver, handler)
+            }).unwrap()
+        }).collect();
+        WorkerPool {
+            sender: Some(sender),
+            workers: workers
+        }
+    }
+
+    fn work<F: Fn(T)>(receiver: Arc<Mutex<Receiver<T>>>, handler: Arc<F>) {
+        loop {
+            // Only hold the lock while waiting, so other workers can run jobs meanwhile.
+            let job = receiver.lock().unwrap().recv();
+            match job {
+                // Otherwise the pool would lose a worker for good.
+                Ok(job) => {
+                    let _ = panic::catch_unwind(AssertUnwindSafe(|| handler(job)));
+                },
+                Err(_) => return
+            }
+        }
+    }
+
+    /// Queues a job, giving it back if all the workers are busy and the queue is full.
+    pub fn submit(&self, job: T) -> Result<(), T> {
+        match self.sender.as_ref().unwrap().try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(job)) => Err(job),
+            Err(TrySendError::Disconnected(job)) => Err(job)
+        }
+    }
+
+    /// Returns the number of worker threads.
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+}
+
+impl<T> Drop for WorkerPool<T> {
+    fn drop(&mut self) {
+        // Closing the queue stops the workers once they are done.
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[test]
+fn test_worker_pool() {
+    let (done_sender, done) = channel();
+    let done_sender = Mutex::new(done_sender);
+    let barrier = Arc::new(Barrier::new(3));
+    let worker_barrier = barrier.clone();
+    let pool = WorkerPool::new(2, 1, move |job: usize| {
+        if job < 2 {
+            worker_barrier.wait();
+        }
+        done_sender.lock().unwrap().send(job).unwrap();
+    });
+    assert_eq!(2, pool.size());
+
+    // Both workers are stuck on the barrier, so the queue fills up.
+    for job in 0 .. 3 {
+        while pool.submit(job).is_err() {
+            thread::yield_now();
+        }
+    }
+    assert_eq!(Err(3), pool.submit(3));
+
+    barrier.wait();
+    let mut jobs: Vec<usize> = (0 .. 3).map(|_| done.recv().unwrap()).collect();
+    jobs.sort();
+    assert_eq!(vec![0, 1, 2], jobs);
+}
+
+#[test]
+fn test_worker_panic() {
+    let (done_sender, done) = channel();
+    let done_sender = Mutex::new(done_sender);
+    let pool = WorkerPool::new(1, 2, move |job: usize| {
+        if job == 0 {
+            panic!("job failed");
+        }
+        done_sender.lock().unwrap().send(job).unwrap();
+    });
+
+    // The only worker survives the panic of the first job.
+    assert_eq!(Ok(()), pool.submit(0));
+    assert_eq!(Ok(()), pool.submit(1));
+    assert_eq!(1, done.recv().unwrap());
+    assert_eq!(Ok(()), pool.submit(2));
+    assert_eq!(2, done.recv().unwrap());
+}
@@ -0,0 +1,93 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Timing of the middleware of a command.
+//!
+//! Middleware call each other, so the time spent in a middleware includes the time spent in
+//! the ones after it. We keep track of the time spent in nested calls to report only the
+//! time spent in each middleware itself. A connection is handled by a single thread, so the
+//! bookkeeping is thread local.
+
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+use super::metrics::Metrics;
+
+thread_local!(static NESTED: Cell<Duration> = Cell::new(Duration::new(0, 0)));
+thread_local!(static TIMINGS: RefCell<Vec<(usize, Duration)>> = RefCell::new(Vec::new()));
+
+/// Calls a middleware and records the time spent in it.
+pub fn measure<F: FnOnce()>(index: usize, f: F) {
+    let outer = NESTED.with(|nested| nested.replace(Duration::new(0, 0)));
+    let start = Instant::now();
+
+    f();
+
+    let total = start.elapsed();
+    let inner = NESTED.with(|nested| nested.replace(outer + total));
+    let own = if inner < total { total - inner } else { Duration::new(0, 0) };
+    TIMINGS.with(|timings| timings.borrow_mut().push((index, own)));
+}
+
+/// Reports the timings recorded since the last report for the given command, and forgets
+/// about them.
+pub fn report(metrics: Option<&Metrics>, command: &str) {
+    NESTED.with(|nested| nested.set(Duration::new(0, 0)));
+    TIMINGS.with(|timings| {
+        if let Some(metrics) = metrics {
+            for &(index, duration) in timings.borrow().iter() {
+                metrics.middleware_timing(command, index, duration);
+            }
+        }
+        timings.borrow_mut().clear();
+    });
+}
+
+#[cfg(test)]
+struct TestMetrics {
+    timings: ::std::sync::Mutex<Vec<(String, usize, Duration)>>
+}
+
+#[cfg(test)]
+impl Metrics for TestMetrics {
+    fn middleware_timing(&self, command: &str, index: usize, duration: Duration) {
+        self.timings.lock().unwrap().push((command.to_owned(), index, duration));
+    }
+}
+
+#[test]
+fn test_measure() {
+    let metrics = TestMetrics {
+        timings: ::std::sync::Mutex::new(Vec::new())
+    };
+
+    measure(0, || {
+        measure(1, || {
+            ::std::thread::sleep(Duration::from_millis(20));
+        });
+    });
+    report(Some(&metrics), "MAIL");
+
+    let timings = metrics.timings.lock().unwrap();
+    assert_eq!(2, timings.len());
+    // The innermost middleware is done first.
+    assert_eq!(("MAIL".to_owned(), 1), (timings[0].0.clone(), timings[0].1));
+    assert_eq!(("MAIL".to_owned(), 0), (timings[1].0.clone(), timings[1].1));
+    assert!(timings[0].2 >= Duration::from_millis(20));
+    assert!(timings[1].2 < Duration::from_millis(20));
+
+    // Reported timings are forgotten.
+    report(None, "MAIL");
+    TIMINGS.with(|timings| assert_eq!(0, timings.borrow().len()));
+}
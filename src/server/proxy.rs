@@ -0,0 +1,230 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! The PROXY protocol, versions 1 and 2, as described
+//! [by HAProxy](http://www.haproxy.org/download/1.8/doc/proxy-protocol.txt).
+//!
+//! A proxy or a load balancer sends this header before anything else, to tell the server
+//! which client it connected for. See `Server::set_proxy_protocol`.
+
+use std::io::{Read, Error as IoError, ErrorKind};
+use std::io::Result as IoResult;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+use std::vec::Vec;
+
+/// The first octets of a version 2 header.
+pub static V2_SIGNATURE: [u8; 12] = [0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a];
+
+/// The maximum length of a version 1 header, including the `<CRLF>`.
+pub static V1_MAX_LENGTH: usize = 107;
+
+/// The addresses of the connection the proxy relays.
+///
+/// They are `None` when the proxy doesn't know them, or connected on its own behalf, ie
+/// for health checks.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct ProxyHeader {
+    /// The address of the client.
+    pub source: Option<SocketAddr>,
+    /// The address the client connected to.
+    pub destination: Option<SocketAddr>
+}
+
+fn invalid(message: &str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, format!("invalid PROXY header: {}", message))
+}
+
+/// Reads a header of either version from the start of a connection.
+///
+/// Nothing after the header is read, so the SMTP session can start on the same stream.
+pub fn read_header<R: Read>(input: &mut R) -> IoResult<ProxyHeader> {
+    // The shortest header of version 1, `PROXY UNKNOWN<CRLF>`, is longer than the signature.
+    let mut start = [0u8; 12];
+    try!(input.read_exact(&mut start));
+    match start == V2_SIGNATURE {
+        true => {
+            let mut fixed = [0u8; 4];
+            try!(input.read_exact(&mut fixed));
+            let len = ((fixed[2] as usize) << 8) | fixed[3] as usize;
+            let mut body = vec![0u8; len];
+            try!(input.read_exact(&mut body));
+            parse_v2(fixed[0], fixed[1], body.as_ref()).ok_or(invalid("bad version 2 header"))
+        },
+        false => {
+            if &start[.. 6] != b"PROXY " {
+                return Err(invalid("no header"));
+            }
+            // Read one octet at a time, to stop right after the `<CRLF>`.
+            let mut line = start.to_vec();
+            while !line.ends_with(b"\r\n") {
+                if line.len() >= V1_MAX_LENGTH {
+                    return Err(invalid("line too long"));
+                }
+                let mut byte = [0u8; 1];
+                try!(input.read_exact(&mut byte));
+                line.push(byte[0]);
+            }
+            let len = line.len() - 2;
+            match str::from_utf8(&line[.. len]) {
+                Ok(line) => parse_v1(line).ok_or(invalid("bad version 1 header")),
+                Err(_) => Err(invalid("bad version 1 header"))
+            }
+        }
+    }
+}
+
+fn parse_port(port: &str) -> Option<u16> {
+    match port.len() > 0 && port.chars().all(|c| c >= '0' && c <= '9') {
+        true => port.parse().ok(),
+        false => None
+    }
+}
+
+// Parses a version 1 header, without the `<CRLF>`.
+fn parse_v1(line: &str) -> Option<ProxyHeader> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    if parts.len() < 2 || parts[0] != "PROXY" {
+        return None;
+    }
+    if parts[1] == "UNKNOWN" {
+        // The rest of the line must be ignored.
+        return Some(ProxyHeader {
+            source: None,
+            destination: None
+        });
+    }
+    if parts.len() != 6 {
+        return None;
+    }
+    let (source, destination) = match parts[1] {
+        "TCP4" => match (parts[2].parse::<Ipv4Addr>(), parts[3].parse::<Ipv4Addr>()) {
+            (Ok(source), Ok(destination)) => (IpAddr::V4(source), IpAddr::V4(destination)),
+            _ => return None
+        },
+        "TCP6" => match (parts[2].parse::<Ipv6Addr>(), parts[3].parse::<Ipv6Addr>()) {
+            (Ok(source), Ok(destination)) => (IpAddr::V6(source), IpAddr::V6(destination)),
+            _ => return None
+        },
+        _ => return None
+    };
+    match (parse_port(parts[4]), parse_port(parts[5])) {
+        (Some(source_port), Some(destination_port)) => Some(ProxyHeader {
+            source: Some(SocketAddr::new(source, source_port)),
+            destination: Some(SocketAddr::new(destination, destination_port))
+        }),
+        _ => None
+    }
+}
+
+#[test]
+fn test_parse_v1() {
+    let header = parse_v1("PROXY TCP4 192.0.2.1 198.51.100.2 56324 25").unwrap();
+    assert_eq!(Some("192.0.2.1:56324".parse().unwrap()), header.source);
+    assert_eq!(Some("198.51.100.2:25".parse().unwrap()), header.destination);
+    let header = parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 56324 25").unwrap();
+    assert_eq!(Some("[2001:db8::1]:56324".parse().unwrap()), header.source);
+    assert_eq!(None, parse_v1("PROXY UNKNOWN ff:: ff:: 1 2").unwrap().source);
+
+    assert_eq!(None, parse_v1("PROXY TCP4 2001:db8::1 2001:db8::2 56324 25"));
+    assert_eq!(None, parse_v1("PROXY TCP4 192.0.2.1 198.51.100.2 56324"));
+    assert_eq!(None, parse_v1("PROXY TCP4 192.0.2.1 198.51.100.2 +1 25"));
+    assert_eq!(None, parse_v1("PROXY TCP4 192.0.2.1 198.51.100.2 65536 25"));
+    assert_eq!(None, parse_v1("PROXY UDP4 192.0.2.1 198.51.100.2 56324 25"));
+}
+
+// Parses a version 2 header, given its version and command, its family and the rest.
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> Option<ProxyHeader> {
+    if version_command >> 4 != 2 {
+        return None;
+    }
+    match version_command & 0x0f {
+        // LOCAL, the proxy connected on its own behalf.
+        0 => return Some(ProxyHeader {
+            source: None,
+            destination: None
+        }),
+        // PROXY.
+        1 => {},
+        _ => return None
+    }
+    let port = |i: usize| ((body[i] as u16) << 8) | body[i + 1] as u16;
+    match family >> 4 {
+        // AF_INET.
+        1 if body.len() >= 12 => {
+            let source = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let destination = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            Some(ProxyHeader {
+                source: Some(SocketAddr::new(IpAddr::V4(source), port(8))),
+                destination: Some(SocketAddr::new(IpAddr::V4(destination), port(10)))
+            })
+        },
+        // AF_INET6.
+        2 if body.len() >= 36 => {
+            let mut segments = [0u16; 16];
+            for i in 0 .. 16 {
+                segments[i] = port(i * 2);
+            }
+            let source = Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], segments[4], segments[5], segments[6], segments[7]);
+            let destination = Ipv6Addr::new(segments[8], segments[9], segments[10], segments[11], segments[12], segments[13], segments[14], segments[15]);
+            Some(ProxyHeader {
+                source: Some(SocketAddr::new(IpAddr::V6(source), port(32))),
+                destination: Some(SocketAddr::new(IpAddr::V6(destination), port(34)))
+            })
+        },
+        1 | 2 => None,
+        // AF_UNSPEC and AF_UNIX, the addresses are unknown or not IP addresses.
+        _ => Some(ProxyHeader {
+            source: None,
+            destination: None
+        })
+    }
+}
+
+#[test]
+fn test_read_header() {
+    let mut input = &b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 25\r\nEHLO rustastic.org\r\n"[..];
+    let header = read_header(&mut input).unwrap();
+    assert_eq!(Some("192.0.2.1:56324".parse().unwrap()), header.source);
+    assert_eq!(b"EHLO rustastic.org\r\n", input);
+
+    let mut v2 = V2_SIGNATURE.to_vec();
+    v2.extend([0x21, 0x11, 0, 15, 192, 0, 2, 1, 198, 51, 100, 2, 0xdc, 0x04, 0, 25, 0xff, 0xff, 0xff].iter().cloned());
+    v2.extend(b"EHLO".iter().cloned());
+    let mut input = &v2[..];
+    let header = read_header(&mut input).unwrap();
+    assert_eq!(Some("192.0.2.1:56324".parse().unwrap()), header.source);
+    assert_eq!(Some("198.51.100.2:25".parse().unwrap()), header.destination);
+    assert_eq!(b"EHLO", input);
+
+    // LOCAL.
+    let mut v2 = V2_SIGNATURE.to_vec();
+    v2.extend([0x20, 0x00, 0, 0].iter().cloned());
+    assert_eq!(None, read_header(&mut &v2[..]).unwrap().source);
+
+    // IPv6.
+    let mut v2 = V2_SIGNATURE.to_vec();
+    v2.extend([0x21, 0x21, 0, 36].iter().cloned());
+    v2.extend([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1].iter().cloned());
+    v2.extend([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0xdc, 0x04, 0, 25].iter().cloned());
+    assert_eq!(Some("[2001:db8::1]:56324".parse().unwrap()), read_header(&mut &v2[..]).unwrap().source);
+
+    assert_eq!(ErrorKind::InvalidData, read_header(&mut &b"EHLO rustastic.org\r\n"[..]).err().unwrap().kind());
+    let long: Vec<u8> = b"PROXY UNKNOWN ".iter().cloned().chain(vec![b'a'; 200].into_iter()).collect();
+    assert_eq!(ErrorKind::InvalidData, read_header(&mut &long[..]).err().unwrap().kind());
+    let mut v2 = V2_SIGNATURE.to_vec();
+    v2.extend([0x11, 0x11, 0, 0].iter().cloned());
+    assert_eq!(ErrorKind::InvalidData, read_header(&mut &v2[..]).err().unwrap().kind());
+}
@@ -0,0 +1,335 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Publishing accepted messages to a message queue, ie Kafka, NATS or AMQP, through a
+//! `Publisher` backed by the client library of the broker.
+//!
+//! `QueueAdapter` is a `MessageConsumer`, so a message is only accepted once the broker
+//! has it. Messages of concurrent sessions are published in batches, and every session
+//! of a batch waits for it. When the broker is down, messages are refused with `451`
+//! right away for a while, so clients try again later instead of piling up.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut adapter = QueueAdapter::new(KafkaPublisher::new(producer, "mail"));
+//! adapter.set_batch_limits(100, 10 * 1024 * 1024, Duration::from_millis(5));
+//! adapter.set_max_message_size(1024 * 1024);
+//! server.add_message_consumer(adapter);
+//! ```
+
+use std::borrow::ToOwned;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+#[cfg(test)]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(test)]
+use std::thread;
+use super::fanout::MessageConsumer;
+use super::transaction::{Transaction, MessageData, TagValue};
+use super::super::common::mailbox::Mailbox;
+
+/// An accepted message, as published.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Publication {
+    /// The sender, `None` for the null sender `<>`.
+    pub sender: Option<Mailbox>,
+    /// The recipients.
+    pub recipients: Vec<Mailbox>,
+    /// The content of the message.
+    pub body: Vec<u8>,
+    /// The tags of the transaction, see `Transaction::set_tag`.
+    pub tags: BTreeMap<String, TagValue>
+}
+
+/// Publishes messages to a broker.
+pub trait Publisher: Send + Sync {
+    /// Publishes a batch of messages, in order, returning once the broker has them all.
+    ///
+    /// Returns why the batch couldn't be published, if it couldn't. The whole batch is
+    /// refused then, so some messages may be published twice.
+    fn publish(&self, batch: &[Publication]) -> Result<(), String>;
+}
+
+// What the sessions of a batch wait for.
+struct Outcome {
+    result: Mutex<Option<Result<(), String>>>,
+    done: Condvar
+}
+
+struct Batch {
+    publications: Vec<Publication>,
+    size: usize,
+    outcome: Arc<Outcome>
+}
+
+struct AdapterState {
+    // The batch messages are added to, published by the session that opened it.
+    open: Option<Batch>,
+    // Until when messages are refused without trying the broker.
+    down_until: Option<Instant>
+}
+
+/// A `MessageConsumer` publishing messages with a `Publisher`, see `server::publish`.
+pub struct QueueAdapter<P> {
+    publisher: P,
+    max_batch_messages: usize,
+    max_batch_size: usize,
+    linger: Duration,
+    max_message_size: Option<usize>,
+    retry_after: Duration,
+    state: Mutex<AdapterState>,
+    // Tells the session publishing a batch that it is full.
+    full: Condvar
+}
+
+impl<P: Publisher> QueueAdapter<P> {
+    /// Creates an adapter publishing with the publisher.
+    ///
+    /// By default, each message is published as soon as it is accepted, there is no size
+    /// limit, and messages are refused for 30 seconds after the broker fails.
+    pub fn new(publisher: P) -> QueueAdapter<P> {
+        QueueAdapter {
+            publisher: publisher,
+            max_batch_messages: 1,
+            max_batch_size: 0,
+            linger: Duration::from_secs(0),
+            max_message_size: None,
+            retry_after: Duration::from_secs(30),
+            state: Mutex::new(AdapterState {
+                open: None,
+                down_until: None
+            }),
+            full: Condvar::new()
+        }
+    }
+
+    /// Sets how messages are batched.
+    ///
+    /// A batch is published once it has `max_messages` messages, or `max_size` octets of
+    /// content if not `0`, or `linger` after its first message, whichever comes first.
+    /// The longer the linger, the longer clients wait for their reply.
+    pub fn set_batch_limits(&mut self, max_messages: usize, max_size: usize, linger: Duration) {
+        self.max_batch_messages = if max_messages == 0 { 1 } else { max_messages };
+        self.max_batch_size = max_size;
+        self.linger = linger;
+    }
+
+    /// Sets the largest message the broker takes, in octets. Larger messages are refused.
+    ///
+    /// The refusal is temporary, so the maximum size of the server should be lower, see
+    /// `Server::set_max_message_size`.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = Some(size);
+    }
+
+    /// Sets for how long messages are refused without trying the broker once it failed.
+    pub fn set_retry_after(&mut self, duration: Duration) {
+        self.retry_after = duration;
+    }
+
+    /// Tells whether messages are being refused because the broker failed.
+    pub fn is_down(&self) -> bool {
+        match self.state.lock().unwrap().down_until {
+            Some(until) => Instant::now() < until,
+            None => false
+        }
+    }
+
+    fn is_full(&self, batch: &Batch) -> bool {
+        batch.publications.len() >= self.max_batch_messages ||
+            (self.max_batch_size > 0 && batch.size >= self.max_batch_size)
+    }
+
+    // Waits for the batch opened by this session to be full or to linger long enough,
+    // then publishes it.
+    fn publish_open_batch(&self) -> Result<(), String> {
+        let started = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let elapsed = started.elapsed();
+            let full = state.open.as_ref().map_or(true, |batch| self.is_full(batch));
+            if full || elapsed >= self.linger {
+                break;
+            }
+            state = self.full.wait_timeout(state, self.linger - elapsed).unwrap().0;
+        }
+        let batch = state.open.take().unwrap();
+        drop(state);
+
+        let result = self.publisher.publish(batch.publications.as_ref());
+        if result.is_err() {
+            self.state.lock().unwrap().down_until = Some(Instant::now() + self.retry_after);
+        }
+        *batch.outcome.result.lock().unwrap() = Some(result.clone());
+        batch.outcome.done.notify_all();
+        result
+    }
+}
+
+impl<P: Publisher> MessageConsumer for QueueAdapter<P> {
+    fn consume(&self, transaction: &Transaction, message: &MessageData) -> Result<(), String> {
+        if let Some(max) = self.max_message_size {
+            if message.len() > max {
+                return Err(format!("message of {} octets too large for the queue", message.len()));
+            }
+        }
+        let publication = Publication {
+            sender: transaction.sender().cloned(),
+            recipients: transaction.recipients().to_vec(),
+            body: message.to_vec(),
+            tags: transaction.tags().clone()
+        };
+
+        let outcome = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(until) = state.down_until {
+                if Instant::now() < until {
+                    return Err("message queue unavailable".to_owned());
+                }
+                state.down_until = None;
+            }
+            match state.open {
+                Some(ref mut batch) => {
+                    batch.size += publication.body.len();
+                    batch.publications.push(publication);
+                    if self.is_full(batch) {
+                        self.full.notify_all();
+                    }
+                    Some(batch.outcome.clone())
+                },
+                None => {
+                    state.open = Some(Batch {
+                        size: publication.body.len(),
+                        publications: vec![publication],
+                        outcome: Arc::new(Outcome {
+                            result: Mutex::new(None),
+                            done: Condvar::new()
+                        })
+                    });
+                    None
+                }
+            }
+        };
+
+        match outcome {
+            // Another session opened the batch, and publishes it.
+            Some(outcome) => {
+                let mut result = outcome.result.lock().unwrap();
+                while result.is_none() {
+                    result = outcome.done.wait(result).unwrap();
+                }
+                result.clone().unwrap()
+            },
+            None => self.publish_open_batch()
+        }
+    }
+}
+
+#[cfg(test)]
+struct TestPublisher {
+    batches: Mutex<Vec<Vec<Publication>>>,
+    fail: AtomicBool
+}
+
+#[cfg(test)]
+impl Publisher for TestPublisher {
+    fn publish(&self, batch: &[Publication]) -> Result<(), String> {
+        self.batches.lock().unwrap().push(batch.to_vec());
+        match self.fail.load(Ordering::SeqCst) {
+            true => Err("broker down".to_owned()),
+            false => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+fn get_adapter() -> QueueAdapter<TestPublisher> {
+    QueueAdapter::new(TestPublisher {
+        batches: Mutex::new(Vec::new()),
+        fail: AtomicBool::new(false)
+    })
+}
+
+#[cfg(test)]
+fn get_transaction() -> Transaction {
+    let mut transaction = Transaction::new();
+    transaction.start(Some(Mailbox::parse("a@rustastic.org").unwrap()));
+    transaction.add_recipient(Mailbox::parse("b@rustastic.org").unwrap());
+    transaction.set_tag("class", "bulk");
+    transaction
+}
+
+#[test]
+fn test_publish() {
+    let mut adapter = get_adapter();
+    adapter.set_max_message_size(5);
+    let transaction = get_transaction();
+
+    assert_eq!(Ok(()), adapter.consume(&transaction, &MessageData::Memory(b"Hello".to_vec())));
+    assert!(adapter.consume(&transaction, &MessageData::Memory(b"Hello!".to_vec())).is_err());
+    let batches = adapter.publisher.batches.lock().unwrap().clone();
+    assert_eq!(1, batches.len());
+    let mut tags = BTreeMap::new();
+    tags.insert("class".to_owned(), TagValue::Text("bulk".to_owned()));
+    assert_eq!(vec![Publication {
+        sender: Some(Mailbox::parse("a@rustastic.org").unwrap()),
+        recipients: vec![Mailbox::parse("b@rustastic.org").unwrap()],
+        body: b"Hello".to_vec(),
+        tags: tags
+    }], batches[0]);
+}
+
+#[test]
+fn test_backpressure() {
+    let adapter = get_adapter();
+    let transaction = get_transaction();
+    let message = MessageData::Memory(b"Hello".to_vec());
+    adapter.publisher.fail.store(true, Ordering::SeqCst);
+
+    assert_eq!(Err("broker down".to_owned()), adapter.consume(&transaction, &message));
+    assert!(adapter.is_down());
+    // The broker isn't tried again for a while.
+    assert_eq!(Err("message queue unavailable".to_owned()), adapter.consume(&transaction, &message));
+    assert_eq!(1, adapter.publisher.batches.lock().unwrap().len());
+
+    adapter.state.lock().unwrap().down_until = Some(Instant::now());
+    adapter.publisher.fail.store(false, Ordering::SeqCst);
+    assert_eq!(Ok(()), adapter.consume(&transaction, &message));
+    assert!(!adapter.is_down());
+    assert_eq!(2, adapter.publisher.batches.lock().unwrap().len());
+}
+
+#[test]
+fn test_batching() {
+    let mut adapter = get_adapter();
+    // Full batches don't wait for the linger.
+    adapter.set_batch_limits(3, 0, Duration::from_secs(60));
+    let adapter = Arc::new(adapter);
+
+    let handles: Vec<_> = (0 .. 3).map(|_| {
+        let adapter = adapter.clone();
+        thread::spawn(move || {
+            adapter.consume(&get_transaction(), &MessageData::Memory(b"Hello".to_vec()))
+        })
+    }).collect();
+    for handle in handles {
+        assert_eq!(Ok(()), handle.join().unwrap());
+    }
+    let batches = adapter.publisher.batches.lock().unwrap().clone();
+    assert_eq!(1, batches.len());
+    assert_eq!(3, batches[0].len());
+}
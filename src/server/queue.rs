@@ -0,0 +1,792 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A queue of accepted messages on disk, delivered later by a background scheduler, which
+//! makes a store-and-forward MTA out of a server.
+//!
+//! `MessageQueue` is a `MessageConsumer`, so a message is only accepted once it is on
+//! disk. Each message is kept in two files of the queue directory: `<id>.msg` holds the
+//! content, and `<id>.env` the envelope as JSON, ie the sender, the recipients still to
+//! deliver, the tags of the transaction and when to try next. Envelopes are replaced by
+//! renaming, so a crash never leaves a half written one, and content goes through a
+//! `TempStore` in the same directory, so it only gets its name once it is complete. The
+//! queue picks up where it left off after a restart.
+//!
+//! Deliveries that fail temporarily are retried with the backoff of a `RetryPolicy`,
+//! until the message has been queued for longer than the policy allows. Recipients that
+//! were refused, or still failing when the message expires, are reported to the sender
+//! with a bounce, as described [in RFC 3464](http://tools.ietf.org/html/rfc3464), which
+//! goes through the queue too. Messages from the null sender never bounce.
+//!
+//! The queue can be moved to another instance with `export` and `import`.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let queue = try!(MessageQueue::new("/var/spool/rsmtp", "mx.rustastic.org"));
+//! server.add_message_consumer(queue.clone());
+//! queue.start(|sender: Option<&Mailbox>, recipients: &[Mailbox], message: &[u8]| {
+//!     ...
+//! }, Duration::from_secs(30));
+//! ```
+
+use std::borrow::ToOwned;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, Read, Write};
+use std::io::{Error as IoError, Result as IoResult};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::vec::Vec;
+use super::fanout::MessageConsumer;
+use super::tempstore::TempStore;
+use super::transaction::{Transaction, MessageData, TagValue};
+use super::super::client::retry::{RetryPolicy, RecipientOutcome, TemporaryFailure};
+use super::super::common::Reply;
+use super::super::common::datetime::DateTime;
+use super::super::common::id::{IdGenerator, Ulid, message_id};
+use super::super::common::base64;
+use super::super::common::json::{Json, ImportError, export_lines, import_lines};
+use super::super::common::mailbox::Mailbox;
+use super::super::common::message::header_fields;
+#[cfg(test)]
+use std::cmp;
+#[cfg(test)]
+use std::env;
+#[cfg(test)]
+use super::super::common::status::EnhancedStatusCode;
+
+/// A message in the queue, without its content.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct QueueEntry {
+    /// The id of the message in the queue.
+    pub id: String,
+    /// The sender, `None` for the null sender `<>`.
+    pub sender: Option<Mailbox>,
+    /// The recipients the message wasn't delivered to yet.
+    pub recipients: Vec<Mailbox>,
+    /// The tags of the transaction, see `Transaction::set_tag`.
+    pub tags: BTreeMap<String, TagValue>,
+    /// When the message was queued, in seconds since the Unix epoch.
+    pub queued_at: u64,
+    /// How many deliveries were attempted so far.
+    pub attempts: u32,
+    /// When to attempt the next delivery, in seconds since the Unix epoch.
+    pub next_attempt: u64,
+    /// Why the last attempt failed temporarily, if it did.
+    pub last_error: Option<String>
+}
+
+fn tag_to_json(value: &TagValue) -> Json {
+    // JSON numbers of `common::json` can't be negative, so numbers are kept as text.
+    let (kind, value) = match *value {
+        TagValue::Bool(value) => ("bool", Json::Bool(value)),
+        TagValue::Int(value) => ("int", Json::String(value.to_string())),
+        TagValue::Text(ref value) => ("text", Json::String(value.clone()))
+    };
+    Json::Object(vec![(kind.to_owned(), value)])
+}
+
+fn tag_from_json(json: &Json) -> Option<TagValue> {
+    if let Some(value) = json.get("bool").and_then(|value| value.as_bool()) {
+        return Some(TagValue::Bool(value));
+    }
+    if let Some(value) = json.get("int").and_then(|value| value.as_str()) {
+        return value.parse().ok().map(TagValue::Int);
+    }
+    json.get("text").and_then(|value| value.as_str()).map(|value| TagValue::Text(value.to_owned()))
+}
+
+impl QueueEntry {
+    /// Returns the envelope as stored in the queue.
+    pub fn to_json(&self) -> Json {
+        Json::Object(vec![
+            ("id".to_owned(), Json::String(self.id.clone())),
+            ("sender".to_owned(), match self.sender {
+                Some(ref sender) => Json::String(sender.to_wire_string()),
+                None => Json::Null
+            }),
+            ("recipients".to_owned(), Json::Array(self.recipients.iter().map(|recipient| {
+                Json::String(recipient.to_wire_string())
+            }).collect())),
+            ("tags".to_owned(), Json::Object(self.tags.iter().map(|(key, value)| {
+                (key.clone(), tag_to_json(value))
+            }).collect())),
+            ("queued_at".to_owned(), Json::Number(self.queued_at)),
+            ("attempts".to_owned(), Json::Number(self.attempts as u64)),
+            ("next_attempt".to_owned(), Json::Number(self.next_attempt)),
+            ("last_error".to_owned(), match self.last_error {
+                Some(ref err) => Json::String(err.clone()),
+                None => Json::Null
+            })
+        ])
+    }
+
+    /// Reads an envelope stored in the queue, `None` if it is invalid.
+    pub fn from_json(json: &Json) -> Option<QueueEntry> {
+        let sender = match json.get("sender") {
+            Some(&Json::Null) => None,
+            Some(sender) => match sender.as_str().and_then(|sender| Mailbox::parse_utf8(sender).ok()) {
+                Some(sender) => Some(sender),
+                None => return None
+            },
+            None => return None
+        };
+        let mut recipients = Vec::new();
+        for recipient in json.get("recipients").and_then(|recipients| recipients.as_array()).unwrap_or(&[]) {
+            match recipient.as_str().and_then(|recipient| Mailbox::parse_utf8(recipient).ok()) {
+                Some(recipient) => recipients.push(recipient),
+                None => return None
+            }
+        }
+        let mut tags = BTreeMap::new();
+        if let Some(&Json::Object(ref members)) = json.get("tags") {
+            for &(ref key, ref value) in members.iter() {
+                match tag_from_json(value) {
+                    Some(value) => tags.insert(key.clone(), value),
+                    None => return None
+                };
+            }
+        }
+        let number = |key: &str| json.get(key).and_then(|value| value.as_u64());
+        match (json.get("id").and_then(|id| id.as_str()), number("queued_at"), number("attempts"), number("next_attempt")) {
+            (Some(id), Some(queued_at), Some(attempts), Some(next_attempt)) => Some(QueueEntry {
+                id: id.to_owned(),
+                sender: sender,
+                recipients: recipients,
+                tags: tags,
+                queued_at: queued_at,
+                attempts: attempts as u32,
+                next_attempt: next_attempt,
+                last_error: json.get("last_error").and_then(|err| err.as_str()).map(|err| err.to_owned())
+            }),
+            _ => None
+        }
+    }
+}
+
+/// What happened to a message for a recipient, in one delivery attempt.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum AttemptOutcome {
+    /// The message was delivered.
+    Delivered,
+    /// The message was refused for good, with the given reply.
+    Refused(Reply),
+    /// The message failed temporarily, and will be tried again.
+    Deferred(TemporaryFailure)
+}
+
+/// Delivers the messages of a queue, ie to the mail exchangers of the recipients, see
+/// `client::mx`.
+pub trait Deliverer: Send + Sync {
+    /// Attempts to deliver a message, returning what happened for each recipient, in the
+    /// order they were given.
+    ///
+    /// Recipients without an outcome are deferred.
+    fn deliver(&self, sender: Option<&Mailbox>, recipients: &[Mailbox], message: &[u8]) -> Vec<AttemptOutcome>;
+}
+
+impl<F> Deliverer for F where F: Fn(Option<&Mailbox>, &[Mailbox], &[u8]) -> Vec<AttemptOutcome> + Send + Sync {
+    fn deliver(&self, sender: Option<&Mailbox>, recipients: &[Mailbox], message: &[u8]) -> Vec<AttemptOutcome> {
+        self(sender, recipients, message)
+    }
+}
+
+/// A function called with the errors a queue runs into, see `MessageQueue::set_on_error`.
+pub type QueueErrorHook = Fn(Option<&str>, &IoError) + Send + Sync;
+
+// Seconds since the Unix epoch. A clock before the epoch counts as the epoch.
+fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+/// Returns a bounce for the recipients a message couldn't be delivered to, as described
+/// [in RFC 3464](http://tools.ietf.org/html/rfc3464).
+///
+/// The bounce is addressed to the sender of the message, and comes with its header.
+pub fn bounce_message(hostname: &str, ids: &IdGenerator, entry: &QueueEntry, failures: &[(Mailbox, RecipientOutcome)], message: &[u8], now: SystemTime) -> Vec<u8> {
+    let boundary = ids.generate();
+    let mut bounce = String::new();
+    bounce.push_str(format!("From: Mail Delivery System <MAILER-DAEMON@{}>\r\n", hostname).as_ref());
+    if let Some(ref sender) = entry.sender {
+        bounce.push_str(format!("To: <{}>\r\n", sender.to_wire_string()).as_ref());
+    }
+    bounce.push_str("Subject: Undelivered Mail Returned to Sender\r\n");
+    bounce.push_str(format!("Date: {}\r\n", DateTime::from_timestamp(timestamp(now) as i64, 0)).as_ref());
+    bounce.push_str(format!("Message-ID: {}\r\n", message_id(ids, hostname)).as_ref());
+    bounce.push_str("Auto-Submitted: auto-replied\r\n");
+    bounce.push_str("MIME-Version: 1.0\r\n");
+    bounce.push_str(format!("Content-Type: multipart/report; report-type=delivery-status; boundary=\"{}\"\r\n\r\n", boundary).as_ref());
+
+    bounce.push_str(format!("--{}\r\nContent-Type: text/plain; charset=us-ascii\r\n\r\n", boundary).as_ref());
+    bounce.push_str(format!("This is the mail system at {}.\r\n\r\n", hostname).as_ref());
+    bounce.push_str("Your message could not be delivered to the following recipients:\r\n\r\n");
+    for &(ref recipient, ref outcome) in failures.iter() {
+        let reason = match *outcome {
+            RecipientOutcome::Refused(ref reply) => reply.to_lines().join(" "),
+            RecipientOutcome::Expired(TemporaryFailure::Reply(ref reply)) => {
+                format!("delivery time expired, last reply: {}", reply.to_lines().join(" "))
+            },
+            RecipientOutcome::Expired(TemporaryFailure::Connection(ref err)) => {
+                format!("delivery time expired, last error: {}", err)
+            },
+            RecipientOutcome::Delivered(_) => continue
+        };
+        bounce.push_str(format!("<{}>: {}\r\n", recipient.to_wire_string(), reason).as_ref());
+    }
+
+    bounce.push_str(format!("\r\n--{}\r\nContent-Type: message/delivery-status\r\n\r\n", boundary).as_ref());
+    bounce.push_str(format!("Reporting-MTA: dns; {}\r\n", hostname).as_ref());
+    bounce.push_str(format!("Arrival-Date: {}\r\n", DateTime::from_timestamp(entry.queued_at as i64, 0)).as_ref());
+    for &(ref recipient, ref outcome) in failures.iter() {
+        let (status, reply) = match *outcome {
+            RecipientOutcome::Refused(ref reply) => {
+                (reply.status().map_or("5.0.0".to_owned(), |status| status.to_string()), Some(reply))
+            },
+            RecipientOutcome::Expired(TemporaryFailure::Reply(ref reply)) => ("4.4.7".to_owned(), Some(reply)),
+            RecipientOutcome::Expired(TemporaryFailure::Connection(_)) => ("4.4.7".to_owned(), None),
+            RecipientOutcome::Delivered(_) => continue
+        };
+        bounce.push_str(format!("\r\nFinal-Recipient: rfc822; {}\r\nAction: failed\r\nStatus: {}\r\n", recipient.to_wire_string(), status).as_ref());
+        if let Some(reply) = reply {
+            bounce.push_str(format!("Diagnostic-Code: smtp; {}\r\n", reply.to_lines().join(" ")).as_ref());
+        }
+    }
+
+    bounce.push_str(format!("\r\n--{}\r\nContent-Type: text/rfc822-headers\r\n\r\n", boundary).as_ref());
+    let mut bytes = bounce.into_bytes();
+    let mut fields = header_fields(message);
+    while let Some(_) = fields.next() {}
+    bytes.extend(message[.. fields.position()].iter().cloned());
+    bytes.extend(format!("\r\n--{}--\r\n", boundary).into_bytes().into_iter());
+    bytes
+}
+
+/// A queue of messages on disk, see `server::queue`.
+///
+/// Clones share the same directory and scheduler lock.
+#[derive(Clone)]
+pub struct MessageQueue {
+    dir: PathBuf,
+    hostname: String,
+    policy: RetryPolicy,
+    ids: Arc<IdGenerator>,
+    store: Arc<TempStore>,
+    running: Arc<Mutex<()>>,
+    on_error: Option<Arc<QueueErrorHook>>
+}
+
+impl MessageQueue {
+    /// Opens the queue in a directory, creating it if needed. Bounces are sent from the
+    /// mail system of `hostname`.
+    ///
+    /// By default, the first retry comes after 5 minutes, each retry waits twice as long
+    /// as the previous one, up to 4 hours, and messages expire after 5 days.
+    pub fn new<P: AsRef<Path>>(dir: P, hostname: &str) -> IoResult<MessageQueue> {
+        try!(fs::create_dir_all(dir.as_ref()));
+        let mut policy = RetryPolicy::new(Duration::from_secs(5 * 24 * 3600));
+        policy.set_delays(Duration::from_secs(5 * 60), Duration::from_secs(4 * 3600), 2);
+        Ok(MessageQueue {
+            dir: dir.as_ref().to_path_buf(),
+            hostname: hostname.to_owned(),
+            policy: policy,
+            ids: Arc::new(Ulid::new()),
+            store: Arc::new(TempStore::new(dir.as_ref())),
+            running: Arc::new(Mutex::new(())),
+            on_error: None
+        })
+    }
+
+    /// Sets when to retry, and how long messages stay in the queue.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.policy = policy;
+    }
+
+    /// Sets how the ids of messages and bounces are minted, ULIDs by default.
+    pub fn set_id_generator<G: 'static + IdGenerator>(&mut self, ids: G) {
+        self.ids = Arc::new(ids);
+    }
+
+    /// Sets a function called with the errors that make the queue skip a message, ie an
+    /// envelope that can't be read or rewritten, along with the id of the message when it
+    /// is known. Those messages are tried again at the next run.
+    pub fn set_on_error<F: 'static + Fn(Option<&str>, &IoError) + Send + Sync>(&mut self, hook: F) {
+        self.on_error = Some(Arc::new(hook));
+    }
+
+    fn report(&self, id: Option<&str>, err: &IoError) {
+        if let Some(ref hook) = self.on_error {
+            hook(id, err);
+        }
+    }
+
+    fn path(&self, id: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", id, extension))
+    }
+
+    // Writes an envelope, replacing the previous one at once.
+    fn write_entry(&self, entry: &QueueEntry) -> IoResult<()> {
+        let tmp = self.path(entry.id.as_ref(), "env.tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write_all(entry.to_json().to_string().as_bytes()));
+            try!(file.sync_all());
+        }
+        fs::rename(&tmp, self.path(entry.id.as_ref(), "env"))
+    }
+
+    // Writes the content of a message, which only gets its name once it is on disk.
+    fn write_message(&self, id: &str, message: &[u8]) -> IoResult<()> {
+        let mut file = try!(self.store.create());
+        try!(file.write_all(message));
+        try!(file.sync_all());
+        file.persist(self.path(id, "msg"))
+    }
+
+    /// Adds a message to the queue, to be delivered at the next run. Returns its id.
+    ///
+    /// The message is on disk once this returns.
+    pub fn enqueue(&self, sender: Option<&Mailbox>, recipients: &[Mailbox], tags: &BTreeMap<String, TagValue>, message: &[u8], now: SystemTime) -> IoResult<String> {
+        let entry = QueueEntry {
+            id: self.ids.generate(),
+            sender: sender.cloned(),
+            recipients: recipients.to_vec(),
+            tags: tags.clone(),
+            queued_at: timestamp(now),
+            attempts: 0,
+            next_attempt: timestamp(now),
+            last_error: None
+        };
+        try!(self.write_message(entry.id.as_ref(), message));
+        try!(self.write_entry(&entry));
+        Ok(entry.id)
+    }
+
+    /// Returns the messages in the queue, in no particular order.
+    ///
+    /// Invalid envelopes are skipped, and so are the ones that can't be read, which are
+    /// reported to the error hook, see `set_on_error`. Only failing to list the directory
+    /// is an error.
+    pub fn entries(&self) -> IoResult<Vec<QueueEntry>> {
+        let mut entries = Vec::new();
+        for file in try!(fs::read_dir(&self.dir)) {
+            let path = match file {
+                Ok(file) => file.path(),
+                Err(err) => {
+                    self.report(None, &err);
+                    continue;
+                }
+            };
+            if path.extension().map_or(true, |extension| extension != "env") {
+                continue;
+            }
+            let mut json = String::new();
+            if let Err(err) = File::open(&path).and_then(|mut file| file.read_to_string(&mut json)) {
+                self.report(path.file_stem().and_then(|id| id.to_str()), &err);
+                continue;
+            }
+            if let Some(entry) = Json::parse(json.as_ref()).ok().as_ref().and_then(QueueEntry::from_json) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Returns the content of a message in the queue.
+    pub fn message(&self, id: &str) -> IoResult<Vec<u8>> {
+        let mut message = Vec::new();
+        try!(try!(File::open(self.path(id, "msg"))).read_to_end(&mut message));
+        Ok(message)
+    }
+
+    /// Writes every message as JSON lines, sorted by id, returning the number of messages.
+    ///
+    /// Lines are envelopes, see `QueueEntry::to_json`, with the content of the message in
+    /// base64 as `message`, ie `{"id":"...","sender":"rust@rustastic.org",...,"message":"SGVsbG8NCg=="}`.
+    /// Messages whose content can't be read are reported to the error hook and left out.
+    pub fn export<W: Write>(&self, output: &mut W) -> IoResult<usize> {
+        let mut entries = try!(self.entries());
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut records = Vec::new();
+        for entry in entries.iter() {
+            let message = match self.message(entry.id.as_ref()) {
+                Ok(message) => message,
+                Err(err) => {
+                    self.report(Some(entry.id.as_ref()), &err);
+                    continue;
+                }
+            };
+            let mut record = entry.to_json();
+            if let Json::Object(ref mut members) = record {
+                members.push(("message".to_owned(), Json::String(base64::encode(message.as_ref()))));
+            }
+            records.push(record);
+        }
+        export_lines(output, records.as_ref())
+    }
+
+    /// Adds the messages written by `export`, ie by another instance, returning the number
+    /// of messages read.
+    ///
+    /// Messages keep their id, their envelope and when they are due, and messages whose id
+    /// is already in the queue are left as they are. Messages before an invalid line are
+    /// kept.
+    pub fn import<R: BufRead>(&self, input: &mut R) -> Result<usize, ImportError> {
+        let mut failure = None;
+        let res = import_lines(input, |record| {
+            let entry = match QueueEntry::from_json(record) {
+                // The id names the files of the message.
+                Some(ref entry) if entry.id.len() == 0 || !entry.id.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') => return false,
+                Some(entry) => entry,
+                None => return false
+            };
+            let message = match record.get("message").and_then(|message| message.as_str()).map(base64::decode) {
+                Some(Ok(message)) => message,
+                _ => return false
+            };
+            if self.path(entry.id.as_ref(), "env").exists() {
+                return true;
+            }
+            match self.write_message(entry.id.as_ref(), message.as_ref()).and_then(|_| self.write_entry(&entry)) {
+                Ok(()) => true,
+                Err(err) => {
+                    failure = Some(err);
+                    false
+                }
+            }
+        });
+        match failure {
+            Some(err) => Err(ImportError::Io(err)),
+            None => res
+        }
+    }
+
+    /// Attempts to deliver the messages whose next attempt is due, and returns how many
+    /// there were.
+    ///
+    /// Only one run happens at a time, the others wait for it. A message whose content
+    /// can't be read, or whose envelope can't be updated after the attempt, is reported to
+    /// the error hook and skipped, see `set_on_error`.
+    pub fn run_once(&self, deliverer: &Deliverer, now: SystemTime) -> IoResult<usize> {
+        let _running = self.running.lock().unwrap();
+        let now_secs = timestamp(now);
+        let mut attempted = 0;
+        for mut entry in try!(self.entries()) {
+            if entry.next_attempt > now_secs {
+                continue;
+            }
+            let message = match self.message(entry.id.as_ref()) {
+                Ok(message) => message,
+                // The message is corrupt, or was removed by hand.
+                Err(err) => {
+                    self.report(Some(entry.id.as_ref()), &err);
+                    continue;
+                }
+            };
+            let outcomes = deliverer.deliver(entry.sender.as_ref(), entry.recipients.as_ref(), message.as_ref());
+            attempted += 1;
+            entry.attempts += 1;
+
+            let delay = self.policy.delay(entry.attempts - 1);
+            let expired = now_secs + delay.as_secs() > entry.queued_at + self.policy.give_up_after().as_secs();
+            let mut pending = Vec::new();
+            let mut failures = Vec::new();
+            for (i, recipient) in entry.recipients.iter().enumerate() {
+                let failure = match outcomes.get(i) {
+                    Some(&AttemptOutcome::Delivered) => continue,
+                    Some(&AttemptOutcome::Refused(ref reply)) => {
+                        failures.push((recipient.clone(), RecipientOutcome::Refused(reply.clone())));
+                        continue;
+                    },
+                    Some(&AttemptOutcome::Deferred(ref failure)) => failure.clone(),
+                    None => TemporaryFailure::Connection("no outcome".to_owned())
+                };
+                entry.last_error = Some(match failure {
+                    TemporaryFailure::Reply(ref reply) => reply.to_lines().join(" "),
+                    TemporaryFailure::Connection(ref err) => err.clone()
+                });
+                match expired {
+                    true => failures.push((recipient.clone(), RecipientOutcome::Expired(failure))),
+                    false => pending.push(recipient.clone())
+                }
+            }
+
+            // The bounce is built from the entry as it was attempted, but only queued once
+            // the new state of the entry is on disk, so a failure here can't make the same
+            // recipients bounce again at the next run.
+            let bounce = match failures.len() > 0 && entry.sender.is_some() {
+                true => Some(bounce_message(self.hostname.as_ref(), &*self.ids, &entry, failures.as_ref(), message.as_ref(), now)),
+                false => None
+            };
+            let id = entry.id.clone();
+            let done = pending.len() == 0;
+            // Removing the envelope takes the message out of the queue, its content is only
+            // left over if removing it fails.
+            let persisted = match done {
+                true => fs::remove_file(self.path(id.as_ref(), "env")),
+                false => {
+                    entry.recipients = pending;
+                    entry.next_attempt = now_secs + delay.as_secs();
+                    self.write_entry(&entry)
+                }
+            };
+            if let Err(err) = persisted {
+                self.report(Some(id.as_ref()), &err);
+                continue;
+            }
+            if let (Some(bounce), Some(sender)) = (bounce, entry.sender.as_ref()) {
+                if let Err(err) = self.enqueue(None, &[sender.clone()], &BTreeMap::new(), bounce.as_ref(), now) {
+                    self.report(Some(id.as_ref()), &err);
+                }
+            }
+            if done {
+                if let Err(err) = fs::remove_file(self.path(id.as_ref(), "msg")) {
+                    self.report(Some(id.as_ref()), &err);
+                }
+            }
+        }
+        Ok(attempted)
+    }
+
+    /// Starts a thread running the queue every `interval`, for as long as the process
+    /// runs.
+    pub fn start<D: 'static + Deliverer>(&self, deliverer: D, interval: Duration) -> JoinHandle<()> {
+        let queue = self.clone();
+        thread::spawn(move || {
+            loop {
+                // The spool may be unreadable for a moment, ie while the disk is full.
+                let _ = queue.run_once(&deliverer, SystemTime::now());
+                thread::sleep(interval);
+            }
+        })
+    }
+}
+
+impl MessageConsumer for MessageQueue {
+    fn consume(&self, transaction: &Transaction, message: &MessageData) -> Result<(), String> {
+        self.enqueue(transaction.sender(), transaction.recipients(), transaction.tags(), message, SystemTime::now())
+            .map(|_| ())
+            .map_err(|err| format!("queue: {}", err))
+    }
+}
+
+#[cfg(test)]
+fn get_queue(name: &str) -> MessageQueue {
+    let dir = env::temp_dir().join(format!("rsmtp-test-queue-{}-{}", name, Ulid::new().generate()));
+    let mut queue = MessageQueue::new(dir, "mx.rustastic.org").unwrap();
+    let mut policy = RetryPolicy::new(Duration::from_secs(3600));
+    policy.set_delays(Duration::from_secs(60), Duration::from_secs(600), 2);
+    queue.set_retry_policy(policy);
+    queue
+}
+
+#[test]
+fn test_entry_json() {
+    let mut tags = BTreeMap::new();
+    tags.insert("bulk".to_owned(), TagValue::Bool(true));
+    tags.insert("score".to_owned(), TagValue::Int(-250));
+    tags.insert("class".to_owned(), TagValue::from("newsletters"));
+    let entry = QueueEntry {
+        id: "01ARZ3NDEKTSV4RRFFQ69G5FAV".to_owned(),
+        sender: Some(Mailbox::parse("rust@rustastic.org").unwrap()),
+        recipients: vec![Mailbox::parse("a@rustastic.org").unwrap(), Mailbox::parse_utf8("δοκιμή@rustastic.org").unwrap()],
+        tags: tags,
+        queued_at: 1420070400,
+        attempts: 2,
+        next_attempt: 1420071000,
+        last_error: Some("451 4.3.0 Try again".to_owned())
+    };
+    let json = Json::parse(entry.to_json().to_string().as_ref()).unwrap();
+    assert_eq!(Some(entry.clone()), QueueEntry::from_json(&json));
+
+    let bounce = QueueEntry { sender: None, last_error: None, tags: BTreeMap::new(), .. entry };
+    assert_eq!(Some(bounce.clone()), QueueEntry::from_json(&bounce.to_json()));
+    assert_eq!(None, QueueEntry::from_json(&Json::parse("{\"id\":\"x\"}").unwrap()));
+}
+
+#[test]
+fn test_run() {
+    let queue = get_queue("run");
+    let start = UNIX_EPOCH + Duration::from_secs(1420070400);
+    let minute = Duration::from_secs(60);
+    let sender = Mailbox::parse("rust@rustastic.org").unwrap();
+    let a = Mailbox::parse("a@rustastic.org").unwrap();
+    let b = Mailbox::parse("b@rustastic.org").unwrap();
+    let mut tags = BTreeMap::new();
+    tags.insert("class".to_owned(), TagValue::from("bulk"));
+    let id = queue.enqueue(Some(&sender), &[a.clone(), b.clone()], &tags, b"Subject: Hi\r\n\r\nHello\r\n", start).unwrap();
+
+    // `a` gets the message at the first attempt, `b` keeps failing.
+    let attempts = Arc::new(Mutex::new(Vec::new()));
+    let log = attempts.clone();
+    let deliverer = move |_: Option<&Mailbox>, recipients: &[Mailbox], _: &[u8]| {
+        log.lock().unwrap().push(recipients.len());
+        recipients.iter().map(|recipient| match recipient.local_part() {
+            "a" => AttemptOutcome::Delivered,
+            _ => AttemptOutcome::Deferred(TemporaryFailure::Reply(Reply::enhanced(451, EnhancedStatusCode::new(4, 3, 0), "Try again")))
+        }).collect()
+    };
+    assert_eq!(1, queue.run_once(&deliverer, start).unwrap());
+    let entries = queue.entries().unwrap();
+    assert_eq!(1, entries.len());
+    assert_eq!(id, entries[0].id);
+    assert_eq!(vec![b.clone()], entries[0].recipients);
+    assert_eq!(tags, entries[0].tags);
+    assert_eq!(timestamp(start + minute), entries[0].next_attempt);
+    assert_eq!(Some("451 4.3.0 Try again".to_owned()), entries[0].last_error);
+
+    // Nothing is due before the delay.
+    assert_eq!(0, queue.run_once(&deliverer, start + minute / 2).unwrap());
+    assert_eq!(1, queue.run_once(&deliverer, start + minute).unwrap());
+    assert_eq!(timestamp(start + minute * 3), queue.entries().unwrap()[0].next_attempt);
+    assert_eq!(vec![2, 1, 1], *attempts.lock().unwrap());
+
+    // Once the next attempt would come too late, the message expires and bounces.
+    assert_eq!(1, queue.run_once(&deliverer, start + minute * 58).unwrap());
+    let entries = queue.entries().unwrap();
+    assert_eq!(1, entries.len());
+    assert!(entries[0].id != id);
+    assert_eq!(None, entries[0].sender);
+    assert_eq!(vec![sender], entries[0].recipients);
+    let bounce = String::from_utf8(queue.message(entries[0].id.as_ref()).unwrap()).unwrap();
+    assert!(bounce.contains("To: <rust@rustastic.org>\r\n"));
+    assert!(bounce.contains("Content-Type: multipart/report; report-type=delivery-status;"));
+    assert!(bounce.contains("\r\nFinal-Recipient: rfc822; b@rustastic.org\r\nAction: failed\r\nStatus: 4.4.7\r\nDiagnostic-Code: smtp; 451 4.3.0 Try again\r\n"));
+    assert!(bounce.contains("Content-Type: text/rfc822-headers\r\n\r\nSubject: Hi\r\n\r\n--"));
+    assert!(!bounce.contains("a@rustastic.org"));
+
+    // Bounces don't bounce.
+    let refuse = |_: Option<&Mailbox>, _: &[Mailbox], _: &[u8]| {
+        vec![AttemptOutcome::Refused(Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 1), "No such user"))]
+    };
+    assert_eq!(1, queue.run_once(&refuse, start + minute * 58).unwrap());
+    assert_eq!(0, queue.entries().unwrap().len());
+    fs::remove_dir_all(&queue.dir).unwrap();
+}
+
+#[test]
+fn test_refused() {
+    let queue = get_queue("refused");
+    let start = UNIX_EPOCH + Duration::from_secs(1420070400);
+    let sender = Mailbox::parse("rust@rustastic.org").unwrap();
+    let a = Mailbox::parse("a@rustastic.org").unwrap();
+    queue.enqueue(Some(&sender), &[a], &BTreeMap::new(), b"Hello\r\n", start).unwrap();
+
+    let refuse = |_: Option<&Mailbox>, _: &[Mailbox], _: &[u8]| {
+        vec![AttemptOutcome::Refused(Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 1), "No such user"))]
+    };
+    assert_eq!(1, queue.run_once(&refuse, start).unwrap());
+    let entries = queue.entries().unwrap();
+    assert_eq!(1, entries.len());
+    let bounce = String::from_utf8(queue.message(entries[0].id.as_ref()).unwrap()).unwrap();
+    assert!(bounce.contains("\r\nFinal-Recipient: rfc822; a@rustastic.org\r\nAction: failed\r\nStatus: 5.1.1\r\nDiagnostic-Code: smtp; 550 5.1.1 No such user\r\n"));
+    fs::remove_dir_all(&queue.dir).unwrap();
+}
+
+#[test]
+fn test_errors() {
+    let mut queue = get_queue("errors");
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let log = errors.clone();
+    queue.set_on_error(move |id, _| log.lock().unwrap().push(id.map(|id| id.to_owned())));
+    let start = UNIX_EPOCH + Duration::from_secs(1420070400);
+    let sender = Mailbox::parse("rust@rustastic.org").unwrap();
+    let a = Mailbox::parse("a@rustastic.org").unwrap();
+    let b = Mailbox::parse("b@rustastic.org").unwrap();
+
+    // An envelope that can't be read doesn't keep the others from being listed.
+    fs::create_dir(queue.path("broken", "env")).unwrap();
+    let id = queue.enqueue(Some(&sender), &[a, b], &BTreeMap::new(), b"Hello\r\n", start).unwrap();
+    assert_eq!(1, queue.entries().unwrap().len());
+    assert_eq!(vec![Some("broken".to_owned())], *errors.lock().unwrap());
+    errors.lock().unwrap().clear();
+
+    // `a` is refused and `b` deferred, but the envelope can't be rewritten, so nothing
+    // bounces until it can.
+    fs::create_dir(queue.path(id.as_ref(), "env.tmp")).unwrap();
+    let deliverer = |_: Option<&Mailbox>, _: &[Mailbox], _: &[u8]| vec![
+        AttemptOutcome::Refused(Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 1), "No such user")),
+        AttemptOutcome::Deferred(TemporaryFailure::Connection("timed out".to_owned()))
+    ];
+    assert_eq!(1, queue.run_once(&deliverer, start).unwrap());
+    assert_eq!(vec![Some("broken".to_owned()), Some(id.clone())], *errors.lock().unwrap());
+    let entries = queue.entries().unwrap();
+    assert_eq!(1, entries.len());
+    assert_eq!(0, entries[0].attempts);
+
+    fs::remove_dir(queue.path(id.as_ref(), "env.tmp")).unwrap();
+    assert_eq!(1, queue.run_once(&deliverer, start).unwrap());
+    let entries = queue.entries().unwrap();
+    assert_eq!(2, entries.len());
+    assert!(entries.iter().any(|entry| entry.id == id && entry.attempts == 1));
+    assert!(entries.iter().any(|entry| entry.sender.is_none()));
+    fs::remove_dir_all(&queue.dir).unwrap();
+}
+
+#[test]
+fn test_import_export() {
+    let queue = get_queue("export");
+    let start = UNIX_EPOCH + Duration::from_secs(1420070400);
+    let mut tags = BTreeMap::new();
+    tags.insert("class".to_owned(), TagValue::from("bulk"));
+    let a = Mailbox::parse("a@rustastic.org").unwrap();
+    let first = queue.enqueue(Some(&Mailbox::parse("rust@rustastic.org").unwrap()), &[a.clone()], &tags, b"Hello\r\n", start).unwrap();
+    let second = queue.enqueue(None, &[a], &BTreeMap::new(), b"Bounce\r\n", start).unwrap();
+
+    let mut output = Vec::new();
+    assert_eq!(2, queue.export(&mut output).unwrap());
+    let text = String::from_utf8(output.clone()).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(2, lines.len());
+    assert!(lines[0].starts_with(&format!("{{\"id\":\"{}\"", cmp::min(&first, &second))[..]));
+    assert!(text.contains("\"message\":\"SGVsbG8NCg==\""));
+
+    // Importing twice doesn't queue the messages twice.
+    let other = get_queue("import");
+    assert_eq!(2, other.import(&mut &output[..]).unwrap());
+    assert_eq!(2, other.import(&mut &output[..]).unwrap());
+    let mut entries = other.entries().unwrap();
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut expected = queue.entries().unwrap();
+    expected.sort_by(|a, b| a.id.cmp(&b.id));
+    assert_eq!(expected, entries);
+    assert_eq!(b"Hello\r\n".to_vec(), other.message(first.as_ref()).unwrap());
+
+    let input = b"{\"id\":\"../x\",\"sender\":null,\"recipients\":[],\"queued_at\":1,\"attempts\":0,\"next_attempt\":1,\"message\":\"\"}\n";
+    match other.import(&mut &input[..]) {
+        Err(ImportError::Invalid(1)) => {},
+        res => panic!("unexpected result: {:?}", res)
+    }
+    assert_eq!(2, other.entries().unwrap().len());
+    fs::remove_dir_all(&queue.dir).unwrap();
+    fs::remove_dir_all(&other.dir).unwrap();
+}
+
+#[test]
+fn test_consume() {
+    let queue = get_queue("consume");
+    let mut transaction = Transaction::new();
+    transaction.start(Some(Mailbox::parse("rust@rustastic.org").unwrap()));
+    transaction.add_recipient(Mailbox::parse("a@rustastic.org").unwrap());
+    transaction.set_tag("score", 120i64);
+    assert_eq!(Ok(()), queue.consume(&transaction, &MessageData::Memory(b"Hello\r\n".to_vec())));
+
+    let entries = queue.entries().unwrap();
+    assert_eq!(1, entries.len());
+    assert_eq!(Some(&TagValue::Int(120)), entries[0].tags.get("score"));
+    assert_eq!(b"Hello\r\n".to_vec(), queue.message(entries[0].id.as_ref()).unwrap());
+    fs::remove_dir_all(&queue.dir).unwrap();
+}
@@ -0,0 +1,202 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Throttling of clients sending too many commands or messages, keyed by client address.
+//!
+//! # Example
+//!
+//! ```ignore
+//! // At most 10 messages a minute per client, then 450.
+//! server.set_message_rate_limit(RateLimiter::new(10, Duration::from_secs(60), RateAction::Reject));
+//! let mut mail = commands::mail::get();
+//! mail.middleware_first(ratelimit::throttle_messages);
+//! server.add_command(mail);
+//! ```
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(test)]
+use std::net::Ipv4Addr;
+use super::ServerConfig;
+use super::NextMiddleware;
+use super::session::SessionState;
+use super::super::common::stream::{InputStream, OutputStream, SessionStream};
+use super::super::common::Reply;
+use super::super::common::status::EnhancedStatusCode;
+#[cfg(test)]
+use super::{Server, Command};
+#[cfg(test)]
+use super::testing::{TestSession, TestContainer};
+
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
+
+/// What to do with a client over its rate.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum RateAction {
+    /// Wait before handling the command, which delays the reply (tarpitting).
+    Delay(Duration),
+    /// Reply `450`, so the client tries again later.
+    Reject
+}
+
+/// What a `RateLimiter` decided for an event.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum RateDecision {
+    /// The client is within its rate.
+    Allow,
+    /// The client is over its rate and must wait.
+    Delay(Duration),
+    /// The client is over its rate and must be rejected.
+    Reject
+}
+
+/// Counts events per client address over fixed windows, shared by all connections.
+pub struct RateLimiter {
+    max: usize,
+    window: Duration,
+    action: RateAction,
+    // The start of the current window of each address, and its events in it.
+    counts: Mutex<HashMap<IpAddr, (Instant, usize)>>
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `max` events per `window` from each address, applying the
+    /// action beyond that.
+    pub fn new(max: usize, window: Duration, action: RateAction) -> RateLimiter {
+        RateLimiter {
+            max: max,
+            window: window,
+            action: action,
+            counts: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Counts an event from the address at the given time, and decides what to do with it.
+    ///
+    /// Rejected events don't count, so the client isn't locked out for retrying.
+    pub fn check(&self, ip: IpAddr, now: Instant) -> RateDecision {
+        let mut counts = self.counts.lock().unwrap();
+        // Forget the expired windows once in a while, so the map doesn't grow forever.
+        if counts.len() > 1024 {
+            let window = self.window;
+            let expired: Vec<IpAddr> = counts.iter().filter(|&(_, &(start, _))| {
+                now.duration_since(start) >= window
+            }).map(|(ip, _)| *ip).collect();
+            for ip in expired.iter() {
+                counts.remove(ip);
+            }
+        }
+
+        let entry = counts.entry(ip).or_insert((now, 0));
+        if now.duration_since(entry.0) >= self.window {
+            *entry = (now, 0);
+        }
+        match (entry.1 < self.max, self.action) {
+            (true, _) => {
+                entry.1 += 1;
+                RateDecision::Allow
+            },
+            (false, RateAction::Delay(delay)) => {
+                entry.1 += 1;
+                RateDecision::Delay(delay)
+            },
+            (false, RateAction::Reject) => RateDecision::Reject
+        }
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    let a = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    let b = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+    let start = Instant::now();
+    let second = Duration::from_secs(1);
+
+    let limiter = RateLimiter::new(2, Duration::from_secs(10), RateAction::Reject);
+    assert_eq!(RateDecision::Allow, limiter.check(a, start));
+    assert_eq!(RateDecision::Allow, limiter.check(a, start + second));
+    assert_eq!(RateDecision::Reject, limiter.check(a, start + second * 2));
+    assert_eq!(RateDecision::Allow, limiter.check(b, start + second * 2));
+    assert_eq!(RateDecision::Allow, limiter.check(a, start + second * 10));
+
+    let limiter = RateLimiter::new(1, Duration::from_secs(10), RateAction::Delay(second));
+    assert_eq!(RateDecision::Allow, limiter.check(a, start));
+    assert_eq!(RateDecision::Delay(second), limiter.check(a, start));
+}
+
+// Applies the decision of the limiter to the client of the session.
+fn throttle<CT, ST: SessionStream>(limiter: Option<&RateLimiter>, text: &str, config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let decision = match (limiter, input.peer_addr()) {
+        (Some(limiter), Ok(addr)) => limiter.check(addr.ip(), Instant::now()),
+        _ => RateDecision::Allow
+    };
+    match decision {
+        RateDecision::Reject => {
+            output.write_reply(&Reply::enhanced(450, EnhancedStatusCode::new(4, 7, 1), text)).unwrap();
+        },
+        RateDecision::Delay(delay) => {
+            thread::sleep(delay);
+            next.unwrap().call(config, container, state, input, output, line);
+        },
+        RateDecision::Allow => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+/// Throttles the command according to `Server::set_command_rate_limit`.
+///
+/// This counts every command it is added to, so put it first, see
+/// `Command::middleware_first`.
+pub fn throttle_commands<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let limiter = config.command_rate.as_ref().map(|limiter| &**limiter);
+    throttle(limiter, "Too many commands, try again later", config, container, state, input, output, line, next);
+}
+
+/// Throttles the command according to `Server::set_message_rate_limit`.
+///
+/// This is meant for MAIL, to count transactions.
+pub fn throttle_messages<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    let limiter = config.message_rate.as_ref().map(|limiter| &**limiter);
+    throttle(limiter, "Too many messages, try again later", config, container, state, input, output, line, next);
+}
+
+#[cfg(test)]
+fn ok<CT, ST: SessionStream>(_: &ServerConfig<CT, ST>, _: &mut CT, _: &mut SessionState, _: &mut Input<ST>, output: &mut Output<ST>, _: &str, _: Next<CT, ST>) {
+    output.write_reply(&Reply::new(250, "OK")).unwrap();
+}
+
+#[test]
+fn test_throttle() {
+    let mut container = TestContainer::new();
+    let mut server = Server::new(container.clone());
+    server.set_command_rate_limit(RateLimiter::new(2, Duration::from_secs(3600), RateAction::Reject));
+    let mut command = Command::new();
+    command.starts_with("PING");
+    command.middleware(ok);
+    command.middleware_first(throttle_commands);
+    server.add_command(command);
+    let mut session = TestSession::new();
+
+    for reply in ["250 OK", "250 OK", "450 4.7.1 Too many commands, try again later"].iter() {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "PING");
+        assert_eq!(*reply, session.reply());
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The names of clients, from the PTR records of their address.
+//!
+//! Anyone can give their address any name, so a name is only verified when it resolves
+//! back to the address of the client, which is known as forward-confirmed reverse DNS
+//! (FCrDNS). Only a verified name belongs in the `Received` header, see
+//! `received_from`.
+//!
+//! Lookups are done once per connection, before the greeting, see
+//! `Server::set_reverse_dns`. Middleware find the outcome in the session state:
+//!
+//! ```ignore
+//! match state.reverse_dns() {
+//!     Some(&ReverseDns::Verified(_)) => next_middleware.call(...),
+//!     _ => output.write_reply(&Reply::enhanced(550, EnhancedStatusCode::new(5, 7, 25), "Reverse DNS required"))
+//! }
+//! ```
+
+use std::borrow::ToOwned;
+use std::ascii::AsciiExt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use super::super::common::dns::{Resolver, DnsError};
+#[cfg(test)]
+use std::net::Ipv4Addr;
+#[cfg(test)]
+use std::vec::Vec;
+#[cfg(test)]
+use super::Server;
+#[cfg(test)]
+use super::testing::{TestSession, TestContainer};
+#[cfg(test)]
+use super::super::common::dns::MxRecord;
+
+/// The outcome of the reverse DNS lookup of a client.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum ReverseDns {
+    /// The address has a name that resolves back to it.
+    Verified(String),
+    /// The address has names, but none of them resolves back to it. The first one is
+    /// given.
+    Unverified(String),
+    /// The address has no name.
+    NotFound,
+    /// The lookup failed or timed out, and may succeed later.
+    TempError(String)
+}
+
+impl ReverseDns {
+    /// Returns the name of the client, verified or not.
+    pub fn hostname(&self) -> Option<&str> {
+        match *self {
+            ReverseDns::Verified(ref name) | ReverseDns::Unverified(ref name) => Some(name.as_ref()),
+            _ => None
+        }
+    }
+
+    /// Returns the name of the client if it resolves back to its address.
+    pub fn verified_hostname(&self) -> Option<&str> {
+        match *self {
+            ReverseDns::Verified(ref name) => Some(name.as_ref()),
+            _ => None
+        }
+    }
+}
+
+/// Looks up the names of an address, and verifies them with forward lookups.
+///
+/// Names are tried in order until one of them resolves back to the address.
+pub fn lookup(resolver: &Resolver, ip: &IpAddr) -> ReverseDns {
+    let names = match resolver.lookup_ptr(ip) {
+        Ok(ref names) if names.len() == 0 => return ReverseDns::NotFound,
+        Ok(names) => names,
+        Err(DnsError::Temporary(err)) => return ReverseDns::TempError(err),
+        Err(_) => return ReverseDns::NotFound
+    };
+    let mut last_err = None;
+    for name in names.iter() {
+        match resolver.lookup_ip(name) {
+            Ok(ref ips) if ips.contains(ip) => return ReverseDns::Verified(name.to_ascii_lowercase()),
+            Err(DnsError::Temporary(err)) => last_err = Some(err),
+            _ => {}
+        }
+    }
+    match last_err {
+        // A name we couldn't check may have been the right one.
+        Some(err) => ReverseDns::TempError(err),
+        None => ReverseDns::Unverified(names[0].to_ascii_lowercase())
+    }
+}
+
+/// Looks up the names of an address like `lookup`, giving up after the timeout.
+///
+/// Resolvers can't be interrupted, so the lookup runs in its own thread, which is left
+/// behind if it is too slow.
+pub fn lookup_timeout(resolver: Arc<Resolver>, ip: IpAddr, timeout: Duration) -> ReverseDns {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(lookup(&*resolver, &ip));
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(res) => res,
+        Err(_) => ReverseDns::TempError("timeout".to_owned())
+    }
+}
+
+/// Returns the `from` clause of a `Received` header, as described
+/// [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.4), ie
+/// `from mx.rustastic.org (mx.rustastic.org [192.0.2.1])`.
+///
+/// `domain` is what the client gave with HELO or EHLO. Only a verified name is shown
+/// next to the address.
+pub fn received_from(domain: &str, ip: &IpAddr, reverse_dns: Option<&ReverseDns>) -> String {
+    let literal = match *ip {
+        IpAddr::V4(ip) => format!("[{}]", ip),
+        IpAddr::V6(ip) => format!("[IPv6:{}]", ip)
+    };
+    match reverse_dns.and_then(|reverse_dns| reverse_dns.verified_hostname()) {
+        Some(name) => format!("from {} ({} {})", domain, name, literal),
+        None => format!("from {} ({})", domain, literal)
+    }
+}
+
+/// A resolver for the addresses of the tests, `127.0.0.1` being
+/// `localhost.rustastic.org`.
+#[cfg(test)]
+pub struct TestResolver;
+
+#[cfg(test)]
+impl Resolver for TestResolver {
+    fn lookup_mx(&self, _: &str) -> Result<Vec<MxRecord>, DnsError> {
+        Err(DnsError::NotFound)
+    }
+
+    fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>, DnsError> {
+        match host {
+            "mx.rustastic.org" => Ok(vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]),
+            "localhost.rustastic.org" => Ok(vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]),
+            "down.rustastic.org" => Err(DnsError::Temporary("server failure".to_owned())),
+            _ => Ok(vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 99))])
+        }
+    }
+
+    fn lookup_ptr(&self, ip: &IpAddr) -> Result<Vec<String>, DnsError> {
+        match *ip {
+            IpAddr::V4(ip) if ip.octets()[0] == 127 => Ok(vec!["localhost.rustastic.org".to_owned()]),
+            IpAddr::V4(ip) => match ip.octets()[3] {
+                1 => Ok(vec!["spoofed.rustastic.org".to_owned(), "MX.rustastic.org".to_owned()]),
+                2 => Ok(vec!["spoofed.rustastic.org".to_owned()]),
+                3 => Ok(vec!["down.rustastic.org".to_owned()]),
+                4 => Err(DnsError::Temporary("timeout".to_owned())),
+                5 => {
+                    thread::sleep(Duration::from_millis(500));
+                    Ok(vec![])
+                },
+                _ => Err(DnsError::NotFound)
+            },
+            _ => Ok(vec![])
+        }
+    }
+}
+
+#[test]
+fn test_lookup() {
+    let ip = |last| IpAddr::V4(Ipv4Addr::new(192, 0, 2, last));
+    assert_eq!(ReverseDns::Verified("mx.rustastic.org".to_owned()), lookup(&TestResolver, &ip(1)));
+    assert_eq!(ReverseDns::Unverified("spoofed.rustastic.org".to_owned()), lookup(&TestResolver, &ip(2)));
+    assert_eq!(ReverseDns::TempError("server failure".to_owned()), lookup(&TestResolver, &ip(3)));
+    assert_eq!(ReverseDns::TempError("timeout".to_owned()), lookup(&TestResolver, &ip(4)));
+    assert_eq!(ReverseDns::NotFound, lookup(&TestResolver, &ip(6)));
+    assert_eq!(ReverseDns::NotFound, lookup(&TestResolver, &"2001:db8::1".parse().unwrap()));
+
+    assert_eq!(Some("spoofed.rustastic.org"), lookup(&TestResolver, &ip(2)).hostname());
+    assert_eq!(None, lookup(&TestResolver, &ip(2)).verified_hostname());
+
+    let resolver: Arc<Resolver> = Arc::new(TestResolver);
+    assert_eq!(ReverseDns::Verified("mx.rustastic.org".to_owned()), lookup_timeout(resolver.clone(), ip(1), Duration::from_secs(5)));
+    assert_eq!(ReverseDns::TempError("timeout".to_owned()), lookup_timeout(resolver, ip(5), Duration::from_millis(10)));
+}
+
+#[test]
+fn test_received_from() {
+    let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    let verified = ReverseDns::Verified("mx.rustastic.org".to_owned());
+    let unverified = ReverseDns::Unverified("mx.rustastic.org".to_owned());
+    assert_eq!("from mx (mx.rustastic.org [192.0.2.1])", received_from("mx", &ip, Some(&verified)));
+    assert_eq!("from mx ([192.0.2.1])", received_from("mx", &ip, Some(&unverified)));
+    assert_eq!("from mx ([192.0.2.1])", received_from("mx", &ip, None));
+    assert_eq!("from mx ([IPv6:2001:db8::1])", received_from("mx", &"2001:db8::1".parse().unwrap(), None));
+}
+
+#[test]
+fn test_server_reverse_dns() {
+    let container = TestContainer::new();
+    let mut server = Server::new(container);
+    let mut session = TestSession::new();
+    Server::look_up_client(&server.config, &session.input, &mut session.state);
+    assert_eq!(None, session.state.reverse_dns());
+
+    server.set_reverse_dns(TestResolver, Duration::from_secs(5));
+    Server::look_up_client(&server.config, &session.input, &mut session.state);
+    assert_eq!(Some(&ReverseDns::Verified("localhost.rustastic.org".to_owned())), session.state.reverse_dns());
+
+    // The name is known for the whole connection.
+    session.state.reset();
+    let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    assert_eq!("from rustastic.org (localhost.rustastic.org [127.0.0.1])", received_from("rustastic.org", &ip, session.state.reverse_dns()));
+}
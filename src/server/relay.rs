@@ -0,0 +1,511 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A forwarding gateway: messages accepted by the server are delivered onward by the
+//! client, to smarthosts or to the mail exchangers of the recipients.
+//!
+//! Accepted messages go to a `MessageQueue`, which retries and bounces them. A `Relay`
+//! is what the queue delivers with. Envelopes can be rewritten on the way, ie to
+//! replace internal domains with public ones. Rewriting happens at delivery, so the
+//! queue keeps the envelopes as they were received.
+//!
+//! `Server::relay` gives a server that takes mail from its trusted networks only.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let queue = try!(MessageQueue::new("/var/spool/rsmtp", "gw.rustastic.org"));
+//! let mut pool = SmarthostPool::new();
+//! pool.add_host("smtp.rustastic.org:25", 1);
+//! let mut relay = Relay::smarthosts(pool, "gw.rustastic.org");
+//! relay.set_sender_rewrite(|sender| sender.map(|sender| {
+//!     Mailbox::parse(format!("{}@rustastic.org", sender.local_part()).as_ref()).unwrap()
+//! }));
+//! queue.start(relay, Duration::from_secs(30));
+//!
+//! let mut server = Server::relay(queue);
+//! server.add_trusted_network(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8);
+//! try!(server.listen(ip, 25));
+//! ```
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use std::vec::Vec;
+use super::{Server, ServerConfig, NextMiddleware};
+use super::commands::{self, HeloHandler, TransactionState, MailHandler, RcptHandler, DataHandler};
+use super::commands::mail::MailParameters;
+use super::commands::rcpt::RcptParameters;
+use super::policy::is_trusted_network;
+use super::queue::{MessageQueue, Deliverer, AttemptOutcome};
+use super::session::SessionState;
+use super::transaction::Transaction;
+use super::super::client::SmtpClient;
+use super::super::client::mx::{MxRouter, MxError};
+use super::super::client::retry::{send_once, RecipientOutcome, TemporaryFailure};
+use super::super::client::smarthost::SmarthostPool;
+use super::super::common::Reply;
+use super::super::common::dns::{Resolver, DnsError};
+use super::super::common::mailbox::{Mailbox, MailboxForeignPart};
+use super::super::common::status::EnhancedStatusCode;
+use super::super::common::stream::{InputStream, OutputStream, SessionStream};
+#[cfg(test)]
+use std::env;
+#[cfg(test)]
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+#[cfg(test)]
+use std::sync::mpsc;
+#[cfg(test)]
+use std::thread;
+#[cfg(test)]
+use std::time::SystemTime;
+#[cfg(test)]
+use super::sink::SinkLog;
+#[cfg(test)]
+use super::testing::TestSession;
+#[cfg(test)]
+use super::super::common::dns::MxRecord;
+#[cfg(test)]
+use super::super::common::id::{IdGenerator, Ulid};
+#[cfg(test)]
+use super::super::common::instrument::InstrumentedStream;
+#[cfg(test)]
+use super::super::common::stream::Transport;
+
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
+
+/// Rewrites the sender of a message, `None` being the null sender.
+pub type SenderRewrite = Fn(Option<&Mailbox>) -> Option<Mailbox> + Send + Sync;
+
+/// Rewrites a recipient of a message.
+pub type RecipientRewrite = Fn(&Mailbox) -> Mailbox + Send + Sync;
+
+// Where a relay sends messages.
+enum Route {
+    Smarthosts(Mutex<SmarthostPool>),
+    Mx(MxRouter<Arc<Resolver>>)
+}
+
+/// Delivers the messages of a `MessageQueue` onward, see `server::relay`.
+pub struct Relay {
+    route: Route,
+    hello: String,
+    sender_rewrite: Option<Box<SenderRewrite>>,
+    recipient_rewrite: Option<Box<RecipientRewrite>>
+}
+
+// Converts the outcome of a delivery for a recipient to what the queue expects.
+fn to_attempt_outcome(result: Result<RecipientOutcome, TemporaryFailure>) -> AttemptOutcome {
+    match result {
+        Ok(RecipientOutcome::Delivered(_)) => AttemptOutcome::Delivered,
+        Ok(RecipientOutcome::Refused(reply)) => AttemptOutcome::Refused(reply),
+        Ok(RecipientOutcome::Expired(failure)) | Err(failure) => AttemptOutcome::Deferred(failure)
+    }
+}
+
+// Returns the domain the mail exchangers of a recipient are looked up for.
+fn recipient_domain(recipient: &Mailbox) -> Option<String> {
+    match *recipient.foreign_part() {
+        MailboxForeignPart::Domain(ref domain) => Some(domain.to_ascii_lowercase()),
+        MailboxForeignPart::IpAddr(_) => None
+    }
+}
+
+impl Relay {
+    /// Creates a relay sending every message to a smarthost of the pool, identifying
+    /// itself with `hello` in EHLO.
+    pub fn smarthosts(pool: SmarthostPool, hello: &str) -> Relay {
+        Relay::with_route(Route::Smarthosts(Mutex::new(pool)), hello)
+    }
+
+    /// Creates a relay sending messages to the mail exchangers of the recipients, found
+    /// with the resolver, and identifying itself with `hello` in EHLO.
+    ///
+    /// Recipients with an address literal, ie `rust@[192.0.2.1]`, are refused.
+    pub fn mx<R: 'static + Resolver>(resolver: R, hello: &str) -> Relay {
+        let resolver: Arc<Resolver> = Arc::new(resolver);
+        Relay::with_route(Route::Mx(MxRouter::new(resolver)), hello)
+    }
+
+    fn with_route(route: Route, hello: &str) -> Relay {
+        Relay {
+            route: route,
+            hello: hello.to_owned(),
+            sender_rewrite: None,
+            recipient_rewrite: None
+        }
+    }
+
+    /// Sets the port of the mail exchangers, 25 by default.
+    ///
+    /// Smarthosts have their port in their address, so this only applies to relays
+    /// created with `Relay::mx`.
+    pub fn set_mx_port(&mut self, port: u16) {
+        if let Route::Mx(ref mut router) = self.route {
+            router.set_port(port);
+        }
+    }
+
+    /// Sets how senders are rewritten before delivery.
+    pub fn set_sender_rewrite<F>(&mut self, rewrite: F)
+        where F: 'static + Fn(Option<&Mailbox>) -> Option<Mailbox> + Send + Sync {
+        self.sender_rewrite = Some(Box::new(rewrite));
+    }
+
+    /// Sets how recipients are rewritten before delivery.
+    ///
+    /// Outcomes are still reported for the recipients as they were queued.
+    pub fn set_recipient_rewrite<F>(&mut self, rewrite: F)
+        where F: 'static + Fn(&Mailbox) -> Mailbox + Send + Sync {
+        self.recipient_rewrite = Some(Box::new(rewrite));
+    }
+
+    /// Returns the envelope a message is delivered with.
+    pub fn rewrite(&self, sender: Option<&Mailbox>, recipients: &[Mailbox]) -> (Option<Mailbox>, Vec<Mailbox>) {
+        let sender = match self.sender_rewrite {
+            Some(ref rewrite) => rewrite(sender),
+            None => sender.cloned()
+        };
+        let recipients = match self.recipient_rewrite {
+            Some(ref rewrite) => recipients.iter().map(|recipient| rewrite(recipient)).collect(),
+            None => recipients.to_vec()
+        };
+        (sender, recipients)
+    }
+
+    fn via_smarthost(&self, pool: &Mutex<SmarthostPool>, sender: Option<&Mailbox>, recipients: &[Mailbox], message: &[u8]) -> Vec<AttemptOutcome> {
+        let address = match pool.lock().unwrap().select(Instant::now()) {
+            Some(address) => address,
+            None => {
+                let failure = TemporaryFailure::Connection("no smarthost available".to_owned());
+                return recipients.iter().map(|_| AttemptOutcome::Deferred(failure.clone())).collect();
+            }
+        };
+        let results = match SmtpClient::connect(address.as_ref() as &str) {
+            Ok(mut client) => {
+                let results = send_once(&mut client, self.hello.as_ref(), sender, recipients, message);
+                let _ = client.quit();
+                results
+            },
+            Err(err) => recipients.iter().map(|_| Err(TemporaryFailure::Connection(err.to_string()))).collect()
+        };
+        // A smarthost that took no recipient at all may be down, let others get a turn.
+        {
+            let mut pool = pool.lock().unwrap();
+            match results.iter().all(|result| result.is_err()) {
+                true => pool.report_failure(address.as_ref(), Instant::now()),
+                false => pool.report_success(address.as_ref())
+            }
+        }
+        results.into_iter().map(to_attempt_outcome).collect()
+    }
+
+    fn via_mx(&self, router: &MxRouter<Arc<Resolver>>, sender: Option<&Mailbox>, recipients: &[Mailbox], message: &[u8]) -> Vec<AttemptOutcome> {
+        let mut outcomes: Vec<Option<AttemptOutcome>> = recipients.iter().map(|_| None).collect();
+        let mut domains: Vec<String> = Vec::new();
+        for (index, recipient) in recipients.iter().enumerate() {
+            match recipient_domain(recipient) {
+                Some(domain) => if !domains.contains(&domain) {
+                    domains.push(domain);
+                },
+                None => {
+                    let reply = Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 2), "Address literals are not relayed");
+                    outcomes[index] = Some(AttemptOutcome::Refused(reply));
+                }
+            }
+        }
+
+        // Each domain gets one session, with all its recipients.
+        for domain in domains.iter() {
+            let indexes: Vec<usize> = (0 .. recipients.len()).filter(|&index| {
+                recipient_domain(&recipients[index]).as_ref() == Some(domain)
+            }).collect();
+            let group: Vec<Mailbox> = indexes.iter().map(|&index| recipients[index].clone()).collect();
+            let mut results = Vec::new();
+            let delivered = router.deliver(domain.as_ref(), |client| {
+                let attempt = send_once(client, self.hello.as_ref(), sender, group.as_ref(), message);
+                // Other hosts are only tried while every recipient failed temporarily.
+                let reply = match attempt.iter().any(|result| result.is_ok()) {
+                    true => Ok(Reply::new(250, "OK")),
+                    false => match attempt[0] {
+                        Err(TemporaryFailure::Reply(ref reply)) => Ok(reply.clone()),
+                        Err(TemporaryFailure::Connection(ref err)) => Err(IoError::new(ErrorKind::Other, err.clone())),
+                        Ok(_) => unreachable!()
+                    }
+                };
+                results = attempt;
+                reply
+            });
+            let group_outcomes: Vec<AttemptOutcome> = match delivered {
+                Err(MxError::NullMx) => {
+                    let reply = Reply::enhanced(556, EnhancedStatusCode::new(5, 1, 10), "Recipient domain does not accept mail");
+                    group.iter().map(|_| AttemptOutcome::Refused(reply.clone())).collect()
+                },
+                Err(MxError::Dns(DnsError::Temporary(err))) => {
+                    group.iter().map(|_| AttemptOutcome::Deferred(TemporaryFailure::Connection(err.clone()))).collect()
+                },
+                Err(MxError::Dns(_)) => {
+                    let reply = Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 2), "Recipient domain not found");
+                    group.iter().map(|_| AttemptOutcome::Refused(reply.clone())).collect()
+                },
+                // No host could be reached, so nobody said anything about the recipients.
+                Err(MxError::Exhausted(ref failures)) if results.len() == 0 => {
+                    let err = failures.iter().map(|&(ref host, ref err)| format!("{}: {}", host, err)).collect::<Vec<String>>().join("; ");
+                    group.iter().map(|_| AttemptOutcome::Deferred(TemporaryFailure::Connection(err.clone()))).collect()
+                },
+                Ok(_) | Err(MxError::Exhausted(_)) => results.into_iter().map(to_attempt_outcome).collect()
+            };
+            for (&index, outcome) in indexes.iter().zip(group_outcomes.into_iter()) {
+                outcomes[index] = Some(outcome);
+            }
+        }
+        outcomes.into_iter().map(|outcome| outcome.unwrap()).collect()
+    }
+}
+
+impl Deliverer for Relay {
+    fn deliver(&self, sender: Option<&Mailbox>, recipients: &[Mailbox], message: &[u8]) -> Vec<AttemptOutcome> {
+        let (sender, recipients) = self.rewrite(sender, recipients);
+        match self.route {
+            Route::Smarthosts(ref pool) => self.via_smarthost(pool, sender.as_ref(), recipients.as_ref(), message),
+            Route::Mx(ref router) => self.via_mx(router, sender.as_ref(), recipients.as_ref(), message)
+        }
+    }
+}
+
+/// A container accepting every domain, sender, recipient and message, for
+/// `Server::relay`.
+#[derive(Clone)]
+pub struct RelayContainer {
+    transaction: Transaction
+}
+
+impl RelayContainer {
+    /// Creates a container.
+    pub fn new() -> RelayContainer {
+        RelayContainer {
+            transaction: Transaction::new()
+        }
+    }
+}
+
+impl HeloHandler for RelayContainer {
+    fn handle_domain(&mut self, _: &str) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl TransactionState for RelayContainer {
+    fn transaction(&mut self) -> &mut Transaction {
+        &mut self.transaction
+    }
+}
+
+impl MailHandler for RelayContainer {
+    fn handle_sender_address(&mut self, _: Option<Mailbox>, _: &MailParameters) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl RcptHandler for RelayContainer {
+    fn handle_receiver_address(&mut self, _: Mailbox, _: &RcptParameters) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl DataHandler for RelayContainer {
+    fn handle_data(&mut self, _: &[u8]) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+/// Refuses recipients unless the client is in a trusted network, so the relay isn't open
+/// to everyone.
+pub fn check_relay_access<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    match is_trusted_network(config, container, input, line) {
+        true => next.unwrap().call(config, container, state, input, output, line),
+        false => output.write_reply(&Reply::enhanced(554, EnhancedStatusCode::new(5, 7, 1), "Relay access denied")).unwrap()
+    }
+}
+
+impl Server<RelayContainer> {
+    /// Creates a server queueing the mail of its trusted networks, see `server::relay`.
+    ///
+    /// It has the HELO, EHLO, HELP, MAIL, RCPT, DATA and BDAT commands, and advertises
+    /// `SIZE`. RCPT is refused to clients outside the trusted networks, see
+    /// `Server::add_trusted_network`. The rest of the configuration can be changed as
+    /// usual.
+    pub fn relay(queue: MessageQueue) -> Server<RelayContainer> {
+        let mut server = Server::new(RelayContainer::new());
+        let mut rcpt = commands::rcpt::get();
+        rcpt.middleware_first(check_relay_access);
+        server.add_command(commands::helo::get());
+        server.add_command(commands::ehlo::get());
+        server.add_command(commands::help::get());
+        server.add_command(commands::mail::get());
+        server.add_command(rcpt);
+        server.add_command(commands::data::get());
+        server.add_command(commands::bdat::get());
+        server.add_extension("SIZE");
+        server.add_message_consumer(queue);
+        server
+    }
+}
+
+#[cfg(test)]
+struct TestResolver;
+
+#[cfg(test)]
+impl Resolver for TestResolver {
+    fn lookup_mx(&self, domain: &str) -> Result<Vec<MxRecord>, DnsError> {
+        let mx = |preference, exchange: &str| MxRecord { preference: preference, exchange: exchange.to_owned() };
+        match domain {
+            "rustastic.org" => Ok(vec![mx(10, "mx.rustastic.org")]),
+            "null.rustastic.org" => Ok(vec![mx(0, "")]),
+            "down.rustastic.org" => Err(DnsError::Temporary("timeout".to_owned())),
+            _ => Err(DnsError::NotFound)
+        }
+    }
+
+    fn lookup_ip(&self, _: &str) -> Result<Vec<IpAddr>, DnsError> {
+        Ok(vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))])
+    }
+}
+
+// Starts a sink serving connections one at a time, and returns its address and log.
+#[cfg(test)]
+fn start_sink() -> (SocketAddr, Arc<SinkLog>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let server = Server::sink();
+        tx.send(server.sink_log()).unwrap();
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let input = Transport::Tcp(InstrumentedStream::new(stream.try_clone().unwrap()));
+            let _ = server.serve(input, Transport::Tcp(InstrumentedStream::new(stream)));
+        }
+    });
+    (addr, rx.recv().unwrap())
+}
+
+#[test]
+fn test_rewrite() {
+    let mut relay = Relay::smarthosts(SmarthostPool::new(), "gw.rustastic.org");
+    let sender = Mailbox::parse("rust@internal.rustastic.org").unwrap();
+    let recipients = vec![Mailbox::parse("smtp@rustastic.org").unwrap()];
+    assert_eq!((Some(sender.clone()), recipients.clone()), relay.rewrite(Some(&sender), recipients.as_ref()));
+
+    relay.set_sender_rewrite(|sender| sender.map(|_| Mailbox::parse("rust@rustastic.org").unwrap()));
+    relay.set_recipient_rewrite(|recipient| Mailbox::parse(format!("{}@archive.rustastic.org", recipient.local_part()).as_ref()).unwrap());
+    assert_eq!(
+        (Some(Mailbox::parse("rust@rustastic.org").unwrap()), vec![Mailbox::parse("smtp@archive.rustastic.org").unwrap()]),
+        relay.rewrite(Some(&sender), recipients.as_ref())
+    );
+    assert_eq!(None, relay.rewrite(None, recipients.as_ref()).0);
+}
+
+#[test]
+fn test_smarthost() {
+    let (addr, log) = start_sink();
+    let mut pool = SmarthostPool::new();
+    pool.add_host(addr.to_string().as_ref(), 1);
+    let mut relay = Relay::smarthosts(pool, "gw.rustastic.org");
+    relay.set_sender_rewrite(|_| Some(Mailbox::parse("gw@rustastic.org").unwrap()));
+
+    let sender = Mailbox::parse("rust@internal.rustastic.org").unwrap();
+    let recipients = vec![Mailbox::parse("a@rustastic.org").unwrap(), Mailbox::parse("b@example.org").unwrap()];
+    let outcomes = relay.deliver(Some(&sender), recipients.as_ref(), b"Subject: Hi\r\n\r\nHi\r\n");
+    assert_eq!(vec![AttemptOutcome::Delivered, AttemptOutcome::Delivered], outcomes);
+
+    let envelopes = log.envelopes();
+    assert_eq!(1, envelopes.len());
+    assert_eq!(Some(Mailbox::parse("gw@rustastic.org").unwrap()), envelopes[0].sender);
+    assert_eq!(recipients, envelopes[0].recipients);
+
+    // Without a smarthost, messages wait.
+    let relay = Relay::smarthosts(SmarthostPool::new(), "gw.rustastic.org");
+    match relay.deliver(None, recipients.as_ref(), b"Hi\r\n")[0] {
+        AttemptOutcome::Deferred(_) => {},
+        ref outcome => panic!("{:?}", outcome)
+    }
+}
+
+#[test]
+fn test_mx() {
+    let (addr, log) = start_sink();
+    let mut relay = Relay::mx(TestResolver, "gw.rustastic.org");
+    relay.set_mx_port(addr.port());
+
+    let recipients: Vec<Mailbox> = [
+        "a@rustastic.org",
+        "b@null.rustastic.org",
+        "c@unknown.rustastic.org",
+        "d@down.rustastic.org",
+        "e@Rustastic.org",
+        "f@[192.0.2.1]"
+    ].iter().map(|recipient| Mailbox::parse(recipient).unwrap()).collect();
+    let outcomes = relay.deliver(None, recipients.as_ref(), b"Subject: Hi\r\n\r\nHi\r\n");
+    assert_eq!(AttemptOutcome::Delivered, outcomes[0]);
+    assert_eq!(AttemptOutcome::Refused(Reply::enhanced(556, EnhancedStatusCode::new(5, 1, 10), "Recipient domain does not accept mail")), outcomes[1]);
+    assert_eq!(AttemptOutcome::Refused(Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 2), "Recipient domain not found")), outcomes[2]);
+    assert_eq!(AttemptOutcome::Deferred(TemporaryFailure::Connection("timeout".to_owned())), outcomes[3]);
+    assert_eq!(AttemptOutcome::Delivered, outcomes[4]);
+    assert_eq!(AttemptOutcome::Refused(Reply::enhanced(550, EnhancedStatusCode::new(5, 1, 2), "Address literals are not relayed")), outcomes[5]);
+
+    // The recipients of a domain share a session.
+    let envelopes = log.envelopes();
+    assert_eq!(1, envelopes.len());
+    assert_eq!(vec![recipients[0].clone(), recipients[4].clone()], envelopes[0].recipients);
+}
+
+#[test]
+fn test_server_relay() {
+    let dir = env::temp_dir().join(format!("rsmtp-test-relay-{}", Ulid::new().generate()));
+    let queue = MessageQueue::new(dir, "gw.rustastic.org").unwrap();
+    let mut server = Server::relay(queue.clone());
+    let mut container = server.container.clone();
+    let mut session = TestSession::new();
+
+    let send = |server: &Server<RelayContainer>, session: &mut TestSession, container: &mut RelayContainer, line: &str| {
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, container, &mut session.state, line);
+        session.reply()
+    };
+    assert!(send(&server, &mut session, &mut container, "MAIL FROM:<rust@rustastic.org>").starts_with("250"));
+    assert_eq!("554 5.7.1 Relay access denied", send(&server, &mut session, &mut container, "RCPT TO:<smtp@example.org>"));
+
+    server.add_trusted_network(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), 8);
+    assert!(send(&server, &mut session, &mut container, "RCPT TO:<smtp@example.org>").starts_with("250"));
+    session.send_bytes(b"Subject: Hi\r\n\r\nHi\r\n.\r\n");
+    assert!(send(&server, &mut session, &mut container, "DATA").starts_with("354"));
+    assert_eq!("250 2.0.0 OK", session.reply());
+
+    let entries = queue.entries().unwrap();
+    assert_eq!(1, entries.len());
+    assert_eq!(vec![Mailbox::parse("smtp@example.org").unwrap()], entries[0].recipients);
+
+    // The queue hands the message to the relay.
+    let (addr, log) = start_sink();
+    let mut pool = SmarthostPool::new();
+    pool.add_host(addr.to_string().as_ref(), 1);
+    let relay = Relay::smarthosts(pool, "gw.rustastic.org");
+    assert_eq!(1, queue.run_once(&relay, SystemTime::now()).unwrap());
+    assert_eq!(0, queue.entries().unwrap().len());
+    assert_eq!(1, log.stats().messages);
+}
@@ -0,0 +1,523 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Policy state shared by several server instances, like greylist tuples and rate
+//! counters.
+//!
+//! Each instance keeps its own `KvStore`, and sends the new value of a key to the others
+//! through a `ReplicationBackend` whenever it changes it. Values are merged rather than
+//! overwritten, so updates can arrive late, twice or in any order, and all the nodes
+//! end up with the same state once they have seen the same updates:
+//!
+//! - for a greylist tuple, the earliest first sighting wins, and the tuple has passed
+//!   once it passed on any node;
+//! - for a rate counter, each node only counts its own increments and the total is the
+//!   sum over the nodes. A newer window replaces an older one.
+//!
+//! Between two syncs a node only knows its own updates, so limits can be exceeded by up
+//! to one window of increments per node, and a client may be greylisted again on
+//! another node. Keys must not be used for both kinds of values, a value of the other
+//! kind is ignored.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let network = MemoryNetwork::new();
+//! let mut first = KvStore::new(1);
+//! first.set_backend(network.backend());
+//! let mut second = KvStore::new(2);
+//! second.set_backend(network.backend());
+//!
+//! first.increment("rate:192.0.2.1", window);
+//! second.sync();
+//! assert_eq!(Some(1), second.count("rate:192.0.2.1", window));
+//! ```
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+use std::io::{BufRead, Write};
+use std::io::Result as IoResult;
+use std::borrow::ToOwned;
+use super::super::common::json::{Json, ImportError, export_lines, import_lines};
+
+/// Identifies a server instance, it must be unique among the nodes sharing state.
+pub type NodeId = u32;
+
+/// A value of shared policy state.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum StateValue {
+    /// A greylist tuple, with the time it was first seen in seconds, and whether a retry
+    /// came late enough.
+    Greylist {
+        /// When the tuple was first seen.
+        first_seen: u64,
+        /// Whether the tuple was accepted once.
+        passed: bool
+    },
+    /// A rate counter, with the count of each node in the window.
+    Counter {
+        /// The window the counts are for, ie the current time divided by its length.
+        window: u64,
+        /// The count of each node, sorted by node.
+        counts: Vec<(NodeId, u64)>
+    }
+}
+
+impl StateValue {
+    /// Merges another value of the same key into this one.
+    ///
+    /// Merging is commutative, associative and idempotent.
+    pub fn merge(&mut self, other: &StateValue) {
+        match (self, other) {
+            (&mut StateValue::Greylist { ref mut first_seen, ref mut passed },
+             &StateValue::Greylist { first_seen: other_first_seen, passed: other_passed }) => {
+                *first_seen = cmp::min(*first_seen, other_first_seen);
+                *passed = *passed || other_passed;
+            },
+            (&mut StateValue::Counter { ref mut window, ref mut counts },
+             &StateValue::Counter { window: other_window, counts: ref other_counts }) => {
+                if other_window > *window {
+                    *window = other_window;
+                    *counts = other_counts.clone();
+                } else if other_window == *window {
+                    for &(node, count) in other_counts.iter() {
+                        match counts.binary_search_by(|&(n, _)| n.cmp(&node)) {
+                            Ok(i) => counts[i].1 = cmp::max(counts[i].1, count),
+                            Err(i) => counts.insert(i, (node, count))
+                        }
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn test_merge() {
+    let mut a = StateValue::Greylist { first_seen: 100, passed: false };
+    a.merge(&StateValue::Greylist { first_seen: 90, passed: false });
+    a.merge(&StateValue::Greylist { first_seen: 120, passed: true });
+    assert_eq!(StateValue::Greylist { first_seen: 90, passed: true }, a);
+
+    let mut a = StateValue::Counter { window: 5, counts: vec![(1, 3), (3, 1)] };
+    let b = StateValue::Counter { window: 5, counts: vec![(1, 2), (2, 4), (3, 2)] };
+    a.merge(&b);
+    a.merge(&b);
+    assert_eq!(StateValue::Counter { window: 5, counts: vec![(1, 3), (2, 4), (3, 2)] }, a);
+    a.merge(&StateValue::Counter { window: 4, counts: vec![(1, 100)] });
+    assert_eq!(StateValue::Counter { window: 5, counts: vec![(1, 3), (2, 4), (3, 2)] }, a);
+    a.merge(&StateValue::Counter { window: 6, counts: vec![(2, 1)] });
+    assert_eq!(StateValue::Counter { window: 6, counts: vec![(2, 1)] }, a);
+
+    // Values of the other kind are ignored.
+    a.merge(&StateValue::Greylist { first_seen: 0, passed: true });
+    assert_eq!(StateValue::Counter { window: 6, counts: vec![(2, 1)] }, a);
+}
+
+/// Carries updates between nodes, ie over a message bus or a shared database.
+pub trait ReplicationBackend: Send + Sync {
+    /// Sends the new value of a key to the other nodes.
+    ///
+    /// This must not block for long, it is called while handling commands. Updates may
+    /// be lost, the next update of the key carries the whole value again.
+    fn publish(&self, key: &str, value: &StateValue);
+
+    /// Returns the updates received from the other nodes since the last call.
+    fn poll(&self) -> Vec<(String, StateValue)>;
+}
+
+/// The policy state of a node.
+pub struct KvStore {
+    node: NodeId,
+    entries: Mutex<HashMap<String, StateValue>>,
+    backend: Option<Box<ReplicationBackend>>
+}
+
+impl KvStore {
+    /// Creates an empty store for the given node, which doesn't share its state.
+    pub fn new(node: NodeId) -> KvStore {
+        KvStore {
+            node: node,
+            entries: Mutex::new(HashMap::new()),
+            backend: None
+        }
+    }
+
+    /// Sets the backend sharing the state with other nodes.
+    pub fn set_backend<B: 'static + ReplicationBackend>(&mut self, backend: B) {
+        self.backend = Some(Box::new(backend));
+    }
+
+    /// Returns the value of a key.
+    pub fn get(&self, key: &str) -> Option<StateValue> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    /// Merges a value into a key, without publishing it.
+    pub fn merge(&self, key: &str, value: &StateValue) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.contains_key(key) {
+            entries.get_mut(key).unwrap().merge(value);
+        } else {
+            entries.insert(key.to_owned(), value.clone());
+        }
+    }
+
+    /// Applies the updates received from the other nodes.
+    pub fn sync(&self) {
+        if let Some(ref backend) = self.backend {
+            for (key, value) in backend.poll().into_iter() {
+                self.merge(key.as_ref(), &value);
+            }
+        }
+    }
+
+    // Merges a local change and publishes the resulting value.
+    fn update(&self, key: &str, value: StateValue) -> StateValue {
+        self.merge(key, &value);
+        let value = self.get(key).unwrap();
+        if let Some(ref backend) = self.backend {
+            backend.publish(key, &value);
+        }
+        value
+    }
+
+    /// Tells whether a greylisted tuple is accepted at `now`, in seconds.
+    ///
+    /// A tuple is accepted once `delay` seconds have passed since it was first seen, on
+    /// any node, and from then on.
+    pub fn greylist(&self, key: &str, now: u64, delay: u64) -> bool {
+        match self.get(key) {
+            Some(StateValue::Greylist { passed: true, .. }) => true,
+            Some(StateValue::Greylist { first_seen, .. }) => {
+                let passed = now >= first_seen + delay;
+                if passed {
+                    self.update(key, StateValue::Greylist { first_seen: first_seen, passed: true });
+                }
+                passed
+            },
+            _ => {
+                self.update(key, StateValue::Greylist { first_seen: now, passed: false });
+                false
+            }
+        }
+    }
+
+    /// Counts an event in a window, returning the count of all the nodes so far.
+    pub fn increment(&self, key: &str, window: u64) -> u64 {
+        let own = match self.get(key) {
+            Some(StateValue::Counter { window: w, ref counts }) if w == window => {
+                counts.iter().find(|&&(node, _)| node == self.node).map_or(0, |&(_, count)| count)
+            },
+            _ => 0
+        };
+        let value = self.update(key, StateValue::Counter { window: window, counts: vec![(self.node, own + 1)] });
+        total(&value, window).unwrap_or(0)
+    }
+
+    /// Returns the count of all the nodes in a window, if the key is a counter for it.
+    pub fn count(&self, key: &str, window: u64) -> Option<u64> {
+        self.get(key).and_then(|value| total(&value, window))
+    }
+
+    /// Writes every key as JSON lines, sorted by key, returning the number of keys.
+    ///
+    /// Greylist tuples look like
+    /// `{"key":"...","type":"greylist","first_seen":100,"passed":true}` and counters like
+    /// `{"key":"...","type":"counter","window":5,"counts":[[1,3],[2,4]]}`, with the count
+    /// of each node.
+    pub fn export<W: Write>(&self, output: &mut W) -> IoResult<usize> {
+        let records = {
+            let entries = self.entries.lock().unwrap();
+            let mut keys: Vec<&String> = entries.keys().collect();
+            keys.sort();
+            keys.iter().map(|key| entries[*key].to_json(key.as_ref())).collect::<Vec<Json>>()
+        };
+        export_lines(output, records.as_ref())
+    }
+
+    /// Merges keys written by `export`, ie by another instance, returning the number of
+    /// keys read.
+    ///
+    /// Values are merged like updates from other nodes, so importing into a running store
+    /// is safe. Imported values are not published. Keys before an invalid line are kept.
+    pub fn import<R: BufRead>(&self, input: &mut R) -> Result<usize, ImportError> {
+        import_lines(input, |record| {
+            match StateValue::from_json(record) {
+                Some((key, value)) => {
+                    self.merge(key.as_ref(), &value);
+                    true
+                },
+                None => false
+            }
+        })
+    }
+}
+
+impl StateValue {
+    // The JSON record of the value of a key.
+    fn to_json(&self, key: &str) -> Json {
+        let mut members = vec![("key".to_owned(), Json::String(key.to_owned()))];
+        match *self {
+            StateValue::Greylist { first_seen, passed } => {
+                members.push(("type".to_owned(), Json::String("greylist".to_owned())));
+                members.push(("first_seen".to_owned(), Json::Number(first_seen)));
+                members.push(("passed".to_owned(), Json::Bool(passed)));
+            },
+            StateValue::Counter { window, ref counts } => {
+                members.push(("type".to_owned(), Json::String("counter".to_owned())));
+                members.push(("window".to_owned(), Json::Number(window)));
+                members.push(("counts".to_owned(), Json::Array(counts.iter().map(|&(node, count)| {
+                    Json::Array(vec![Json::Number(node as u64), Json::Number(count)])
+                }).collect())));
+            }
+        }
+        Json::Object(members)
+    }
+
+    // Returns the key and the value of a JSON record.
+    fn from_json(record: &Json) -> Option<(String, StateValue)> {
+        let key = match record.get("key").and_then(|key| key.as_str()) {
+            Some(key) => key.to_owned(),
+            None => return None
+        };
+        let value = match record.get("type").and_then(|t| t.as_str()) {
+            Some("greylist") => {
+                match (record.get("first_seen").and_then(|v| v.as_u64()), record.get("passed").and_then(|v| v.as_bool())) {
+                    (Some(first_seen), Some(passed)) => StateValue::Greylist { first_seen: first_seen, passed: passed },
+                    _ => return None
+                }
+            },
+            Some("counter") => {
+                let window = match record.get("window").and_then(|v| v.as_u64()) {
+                    Some(window) => window,
+                    None => return None
+                };
+                let mut counts = Vec::new();
+                for count in record.get("counts").and_then(|v| v.as_array()).unwrap_or(&[]).iter() {
+                    match count.as_array() {
+                        Some(pair) if pair.len() == 2 => {
+                            match (pair[0].as_u64(), pair[1].as_u64()) {
+                                (Some(node), Some(count)) if node <= NodeId::max_value() as u64 => counts.push((node as NodeId, count)),
+                                _ => return None
+                            }
+                        },
+                        _ => return None
+                    }
+                }
+                counts.sort();
+                StateValue::Counter { window: window, counts: counts }
+            },
+            _ => return None
+        };
+        Some((key, value))
+    }
+}
+
+fn total(value: &StateValue, window: u64) -> Option<u64> {
+    match *value {
+        StateValue::Counter { window: w, ref counts } if w == window => {
+            Some(counts.iter().fold(0, |sum, &(_, count)| sum + count))
+        },
+        _ => None
+    }
+}
+
+// The updates waiting for each node of a `MemoryNetwork`.
+struct Mailboxes {
+    inboxes: Vec<VecDeque<(String, StateValue)>>,
+    outboxes: Vec<Vec<(String, StateValue)>>,
+    connected: Vec<bool>
+}
+
+impl Mailboxes {
+    fn deliver(&mut self, from: usize, key: &str, value: &StateValue) {
+        for (i, inbox) in self.inboxes.iter_mut().enumerate() {
+            if i != from {
+                inbox.push_back((key.to_owned(), value.clone()));
+            }
+        }
+    }
+}
+
+/// Connects nodes in memory, to simulate replication in tests.
+///
+/// Nodes can be disconnected to simulate a network partition: their updates are held
+/// until they are connected again, and they receive nothing in the meantime.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    mailboxes: Arc<Mutex<Mailboxes>>
+}
+
+impl MemoryNetwork {
+    /// Creates a network without nodes.
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork {
+            mailboxes: Arc::new(Mutex::new(Mailboxes {
+                inboxes: Vec::new(),
+                outboxes: Vec::new(),
+                connected: Vec::new()
+            }))
+        }
+    }
+
+    /// Returns the backend of a new node, nodes are numbered from 0 in order.
+    pub fn backend(&self) -> MemoryBackend {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        mailboxes.inboxes.push(VecDeque::new());
+        mailboxes.outboxes.push(Vec::new());
+        mailboxes.connected.push(true);
+        MemoryBackend {
+            index: mailboxes.inboxes.len() - 1,
+            mailboxes: self.mailboxes.clone()
+        }
+    }
+
+    /// Connects or disconnects a node.
+    pub fn set_connected(&self, index: usize, connected: bool) {
+        self.mailboxes.lock().unwrap().connected[index] = connected;
+    }
+}
+
+/// The backend of a node of a `MemoryNetwork`.
+pub struct MemoryBackend {
+    index: usize,
+    mailboxes: Arc<Mutex<Mailboxes>>
+}
+
+impl ReplicationBackend for MemoryBackend {
+    fn publish(&self, key: &str, value: &StateValue) {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        match mailboxes.connected[self.index] {
+            true => mailboxes.deliver(self.index, key, value),
+            false => mailboxes.outboxes[self.index].push((key.to_owned(), value.clone()))
+        }
+    }
+
+    fn poll(&self) -> Vec<(String, StateValue)> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        if !mailboxes.connected[self.index] {
+            return Vec::new();
+        }
+        let held: Vec<(String, StateValue)> = mailboxes.outboxes[self.index].drain(..).collect();
+        for &(ref key, ref value) in held.iter() {
+            mailboxes.deliver(self.index, key.as_ref(), value);
+        }
+        mailboxes.inboxes[self.index].drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+fn get_nodes(network: &MemoryNetwork, n: usize) -> Vec<KvStore> {
+    (0 .. n).map(|i| {
+        let mut store = KvStore::new(i as NodeId);
+        store.set_backend(network.backend());
+        store
+    }).collect()
+}
+
+#[test]
+fn test_greylist() {
+    let network = MemoryNetwork::new();
+    let nodes = get_nodes(&network, 3);
+
+    // The retry goes to another node, which knows about the first attempt.
+    assert!(!nodes[0].greylist("192.0.2.1,a@rustastic.org,b@rustastic.org", 1000, 300));
+    nodes[1].sync();
+    assert!(!nodes[1].greylist("192.0.2.1,a@rustastic.org,b@rustastic.org", 1100, 300));
+    nodes[2].sync();
+    assert!(nodes[2].greylist("192.0.2.1,a@rustastic.org,b@rustastic.org", 1300, 300));
+
+    // Once passed, the tuple passes everywhere.
+    nodes[0].sync();
+    assert!(nodes[0].greylist("192.0.2.1,a@rustastic.org,b@rustastic.org", 1301, 300));
+    for node in nodes.iter() {
+        node.sync();
+    }
+    for node in nodes.iter() {
+        assert_eq!(Some(StateValue::Greylist { first_seen: 1000, passed: true }), node.get("192.0.2.1,a@rustastic.org,b@rustastic.org"));
+    }
+}
+
+#[test]
+fn test_counters() {
+    let network = MemoryNetwork::new();
+    let nodes = get_nodes(&network, 3);
+
+    assert_eq!(1, nodes[0].increment("rate:192.0.2.1", 7));
+    assert_eq!(2, nodes[0].increment("rate:192.0.2.1", 7));
+    assert_eq!(1, nodes[1].increment("rate:192.0.2.1", 7));
+    nodes[1].sync();
+    assert_eq!(Some(3), nodes[1].count("rate:192.0.2.1", 7));
+    assert_eq!(4, nodes[1].increment("rate:192.0.2.1", 7));
+
+    // During a partition, each side only sees its own increments.
+    network.set_connected(2, false);
+    assert_eq!(1, nodes[2].increment("rate:192.0.2.1", 7));
+    nodes[0].sync();
+    assert_eq!(Some(4), nodes[0].count("rate:192.0.2.1", 7));
+    nodes[2].sync();
+    assert_eq!(Some(1), nodes[2].count("rate:192.0.2.1", 7));
+
+    // After it, everything converges.
+    network.set_connected(2, true);
+    for node in nodes.iter() {
+        node.sync();
+    }
+    for node in nodes.iter() {
+        node.sync();
+        assert_eq!(Some(5), node.count("rate:192.0.2.1", 7));
+    }
+
+    // A new window starts from zero.
+    assert_eq!(1, nodes[0].increment("rate:192.0.2.1", 8));
+    nodes[1].sync();
+    assert_eq!(None, nodes[1].count("rate:192.0.2.1", 7));
+    assert_eq!(Some(1), nodes[1].count("rate:192.0.2.1", 8));
+}
+
+#[test]
+fn test_import_export() {
+    let store = KvStore::new(1);
+    store.greylist("b", 100, 60);
+    store.greylist("b", 200, 60);
+    store.increment("a", 5);
+    store.merge("a", &StateValue::Counter { window: 5, counts: vec![(2, 4)] });
+
+    let mut output = Vec::new();
+    assert_eq!(2, store.export(&mut output).unwrap());
+    assert_eq!(
+        "{\"key\":\"a\",\"type\":\"counter\",\"window\":5,\"counts\":[[1,1],[2,4]]}\n{\"key\":\"b\",\"type\":\"greylist\",\"first_seen\":100,\"passed\":true}\n",
+        String::from_utf8(output.clone()).unwrap()
+    );
+
+    // Importing merges with what the store already has.
+    let other = KvStore::new(2);
+    other.merge("b", &StateValue::Greylist { first_seen: 50, passed: false });
+    assert_eq!(2, other.import(&mut &output[..]).unwrap());
+    assert_eq!(Some(5), other.count("a", 5));
+    assert_eq!(Some(StateValue::Greylist { first_seen: 50, passed: true }), other.get("b"));
+
+    let input = b"{\"key\":\"c\",\"type\":\"greylist\",\"first_seen\":1,\"passed\":false}\n{\"key\":\"d\",\"type\":\"counter\",\"window\":1,\"counts\":[[1]]}\n";
+    match other.import(&mut &input[..]) {
+        Err(ImportError::Invalid(2)) => {},
+        res => panic!("unexpected result: {:?}", res)
+    }
+    assert!(other.get("c").is_some());
+    assert!(other.get("d").is_none());
+}
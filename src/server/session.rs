@@ -0,0 +1,455 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! The state of a session, as described
+//! [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-4.1.4).
+//!
+//! The server keeps a `SessionState` for every connection and passes it to middleware
+//! alongside the container, so commands check their ordering the same way whatever the
+//! container is.
+
+use std::borrow::ToOwned;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+use super::{ServerConfig, SessionError, AuthMechanism};
+use super::NextMiddleware;
+use super::super::common::stream::{InputStream, OutputStream, SessionStream};
+use super::super::common::Reply;
+use super::super::common::mailbox::Mailbox;
+use super::super::common::status::EnhancedStatusCode;
+use super::super::common::tls::ConnectionInfo;
+use super::rdns::ReverseDns;
+
+type Next<CT, ST> = Option<NextMiddleware<CT, ST>>;
+type Input<ST> = InputStream<ST>;
+type Output<ST> = OutputStream<ST>;
+
+/// Where a session is, from connection to the end of a message.
+#[derive(PartialEq, Eq, Clone, Debug, Copy, PartialOrd, Ord)]
+pub enum Phase {
+    /// The client connected, but didn't send HELO or EHLO yet.
+    Connected,
+    /// The client sent HELO or EHLO, and no mail transaction is in progress.
+    Greeted,
+    /// The server accepted the sender of a mail transaction.
+    Mail,
+    /// The server accepted at least one recipient.
+    Rcpt,
+    /// The client is sending the message.
+    Data
+}
+
+// An error of the connection recorded by a command, kept by kind and message so the
+// state can still be compared.
+#[derive(PartialEq, Eq, Clone, Debug)]
+enum Failure {
+    Setup(ErrorKind, String),
+    Read(ErrorKind, String),
+    Write(ErrorKind, String)
+}
+
+/// The state of a session.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct SessionState {
+    id: Option<String>,
+    phase: Phase,
+    domain: Option<String>,
+    sender: Option<Mailbox>,
+    received: bool,
+    timed_out: bool,
+    closing: bool,
+    failure: Option<Failure>,
+    errors: usize,
+    tls: Option<ConnectionInfo>,
+    commands: u64,
+    accepted: u64,
+    rejected: u64,
+    auth: Option<(AuthMechanism, String)>,
+    reverse_dns: Option<ReverseDns>
+}
+
+impl SessionState {
+    /// Creates the state of a session that just started.
+    pub fn new() -> SessionState {
+        SessionState {
+            id: None,
+            phase: Phase::Connected,
+            domain: None,
+            sender: None,
+            received: false,
+            timed_out: false,
+            closing: false,
+            failure: None,
+            errors: 0,
+            tls: None,
+            commands: 0,
+            accepted: 0,
+            rejected: 0,
+            auth: None,
+            reverse_dns: None
+        }
+    }
+
+    /// Returns the id of the session, if one was minted for it.
+    ///
+    /// See `Server::set_id_generator`.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_ref().map(|id| id.as_ref())
+    }
+
+    /// Sets the id of the session, which stays the same after `reset`.
+    pub fn set_id(&mut self, id: &str) {
+        self.id = Some(id.to_owned());
+    }
+
+    /// Returns the current phase.
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Returns the domain given with HELO or EHLO.
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_ref().map(|domain| domain.as_ref())
+    }
+
+    /// Tells whether the client sent HELO or EHLO.
+    pub fn is_greeted(&self) -> bool {
+        self.phase != Phase::Connected
+    }
+
+    /// Records a successful HELO or EHLO, which also ends any mail transaction.
+    pub fn greet(&mut self, domain: &str) {
+        self.phase = Phase::Greeted;
+        self.domain = Some(domain.to_owned());
+    }
+
+    /// Moves to a phase of the mail transaction.
+    ///
+    /// Nothing happens before HELO or EHLO, there can't be a transaction yet. Moving to
+    /// `Phase::Data` records that message content is arriving, see `take_received`.
+    pub fn set_phase(&mut self, phase: Phase) {
+        if self.is_greeted() && phase != Phase::Connected {
+            self.phase = phase;
+            self.received |= phase == Phase::Data;
+        }
+    }
+
+    /// Tells whether the session moved to `Phase::Data` since the last call, ie whether
+    /// DATA or BDAT received content, as opposed to being refused before any.
+    pub fn take_received(&mut self) -> bool {
+        let received = self.received;
+        self.received = false;
+        received
+    }
+
+    /// Returns the sender of the mail transaction, `None` for the null sender or when no
+    /// transaction is in progress.
+    pub fn sender(&self) -> Option<&Mailbox> {
+        self.sender.as_ref()
+    }
+
+    /// Records the sender accepted by MAIL.
+    pub fn set_sender(&mut self, sender: Option<Mailbox>) {
+        self.sender = sender;
+    }
+
+    /// Ends the mail transaction, if any.
+    pub fn end_transaction(&mut self) {
+        if self.is_greeted() {
+            self.phase = Phase::Greeted;
+        }
+        self.sender = None;
+    }
+
+    /// Records that the client sent nothing for too long during a command, which ends
+    /// the session once the command returns.
+    pub fn set_timed_out(&mut self) {
+        self.timed_out = true;
+    }
+
+    /// Tells whether the client sent nothing for too long during a command.
+    pub fn is_timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// Records that the connection must be closed once the command returns, after a reply
+    /// that ends the session.
+    pub fn set_closing(&mut self) {
+        self.closing = true;
+    }
+
+    /// Tells whether the connection must be closed once the command returns.
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    /// Records that the connection failed during a command, which ends the session with
+    /// the error once the command returns.
+    ///
+    /// Middleware use this instead of panicking when reading, writing or setting up the
+    /// streams fails. A `Timeout` is the same as `set_timed_out`, and the other errors
+    /// close the session like `set_closing`.
+    pub fn set_failed(&mut self, err: SessionError) {
+        match err {
+            SessionError::Setup(err) => self.failure = Some(Failure::Setup(err.kind(), err.to_string())),
+            SessionError::Read(err) => self.failure = Some(Failure::Read(err.kind(), err.to_string())),
+            SessionError::Write(err) => self.failure = Some(Failure::Write(err.kind(), err.to_string())),
+            SessionError::Timeout => self.timed_out = true,
+            _ => self.closing = true
+        }
+    }
+
+    /// Returns the error recorded by `set_failed`, if any, and forgets it.
+    pub fn take_failure(&mut self) -> Option<SessionError> {
+        self.failure.take().map(|failure| match failure {
+            Failure::Setup(kind, text) => SessionError::Setup(IoError::new(kind, text)),
+            Failure::Read(kind, text) => SessionError::Read(IoError::new(kind, text)),
+            Failure::Write(kind, text) => SessionError::Write(IoError::new(kind, text))
+        })
+    }
+
+    /// Records the reply to a command, returning how many commands in a row got `500` or
+    /// `503`.
+    pub fn record_reply(&mut self, code: u16) -> usize {
+        match code {
+            500 | 503 => self.errors += 1,
+            _ => self.errors = 0
+        }
+        self.errors
+    }
+
+    /// Returns what the TLS handshake negotiated, if the session is encrypted.
+    pub fn tls(&self) -> Option<&ConnectionInfo> {
+        self.tls.as_ref()
+    }
+
+    /// Records what the TLS handshake negotiated.
+    pub fn set_tls(&mut self, info: ConnectionInfo) {
+        self.tls = Some(info);
+    }
+
+    /// Records a command line, recognized or not.
+    pub fn record_command(&mut self) {
+        self.commands += 1;
+    }
+
+    /// Records the end of a message, accepted or not.
+    pub fn record_message(&mut self, accepted: bool) {
+        match accepted {
+            true => self.accepted += 1,
+            false => self.rejected += 1
+        }
+    }
+
+    /// Returns what the client authenticated with, and as whom.
+    pub fn auth(&self) -> Option<(AuthMechanism, &str)> {
+        self.auth.as_ref().map(|&(mechanism, ref identity)| (mechanism, identity.as_ref()))
+    }
+
+    /// Records a successful AUTH.
+    pub fn set_auth(&mut self, mechanism: AuthMechanism, identity: &str) {
+        self.auth = Some((mechanism, identity.to_owned()));
+    }
+
+    /// Returns the reverse DNS of the client, if the server looked it up.
+    ///
+    /// See `Server::set_reverse_dns`.
+    pub fn reverse_dns(&self) -> Option<&ReverseDns> {
+        self.reverse_dns.as_ref()
+    }
+
+    /// Records the reverse DNS of the client, which stays the same after `reset`.
+    pub fn set_reverse_dns(&mut self, reverse_dns: ReverseDns) {
+        self.reverse_dns = Some(reverse_dns);
+    }
+
+    /// Forgets everything about the client, as needed after STARTTLS.
+    ///
+    /// What the session did so far is kept for its summary.
+    pub fn reset(&mut self) {
+        self.phase = Phase::Connected;
+        self.domain = None;
+        self.sender = None;
+    }
+}
+
+#[test]
+fn test_session_state() {
+    let mut state = SessionState::new();
+    assert_eq!(Phase::Connected, state.phase());
+    state.set_phase(Phase::Mail);
+    assert_eq!(Phase::Connected, state.phase());
+    state.end_transaction();
+    assert!(!state.is_greeted());
+
+    state.greet("rustastic.org");
+    assert!(state.is_greeted());
+    assert_eq!(Some("rustastic.org"), state.domain());
+    state.set_phase(Phase::Mail);
+    state.set_phase(Phase::Rcpt);
+    assert!(state.phase() > Phase::Mail);
+    state.end_transaction();
+    assert_eq!(Phase::Greeted, state.phase());
+
+    assert!(!state.take_received());
+    state.set_phase(Phase::Data);
+    assert!(state.take_received());
+    assert!(!state.take_received());
+    state.greet("example.com");
+    assert_eq!(Phase::Greeted, state.phase());
+    assert_eq!(Some("example.com"), state.domain());
+
+    state.reset();
+    assert_eq!(SessionState::new(), state);
+
+    state.set_id("01ARZ3NDEKTSV4RRFFQ69G5FAV");
+    state.greet("rustastic.org");
+    state.reset();
+    assert_eq!(Some("01ARZ3NDEKTSV4RRFFQ69G5FAV"), state.id());
+    assert!(!state.is_greeted());
+
+    assert_eq!(1, state.record_reply(500));
+    assert_eq!(2, state.record_reply(503));
+    assert_eq!(0, state.record_reply(501));
+    assert_eq!(1, state.record_reply(500));
+
+    state.set_failed(SessionError::Read(IoError::new(ErrorKind::ConnectionReset, "reset")));
+    match state.take_failure() {
+        Some(SessionError::Read(ref err)) => assert_eq!(ErrorKind::ConnectionReset, err.kind()),
+        _ => panic!()
+    }
+    assert!(state.take_failure().is_none());
+    state.set_failed(SessionError::Timeout);
+    assert!(state.is_timed_out());
+}
+
+/// What a session did, reported once it is over, see `Server::set_on_summary`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct SessionSummary {
+    /// The id of the session, see `Server::set_id_generator`.
+    pub id: Option<String>,
+    /// The address of the client, if known.
+    pub peer: Option<SocketAddr>,
+    /// The domain the client gave with HELO or EHLO, the last one if several.
+    pub domain: Option<String>,
+    /// How long the session lasted.
+    pub duration: Duration,
+    /// The number of command lines, recognized or not.
+    pub commands: u64,
+    /// The number of messages accepted.
+    pub messages_accepted: u64,
+    /// The number of messages rejected, including the refused DATA and BDAT commands.
+    pub messages_rejected: u64,
+    /// The octets received, `0` if the stream doesn't count them.
+    pub bytes_received: u64,
+    /// The octets sent, `0` if the stream doesn't count them.
+    pub bytes_sent: u64,
+    /// What the TLS handshake negotiated, if the session was encrypted.
+    pub tls: Option<ConnectionInfo>,
+    /// What the client authenticated with, and as whom.
+    pub auth: Option<(AuthMechanism, String)>,
+    /// The reverse DNS of the client, if the server looked it up.
+    pub reverse_dns: Option<ReverseDns>,
+    /// Why the session ended, see `close_reason`.
+    pub close_reason: &'static str
+}
+
+impl SessionSummary {
+    /// Summarizes a session from its state at the end.
+    pub fn new(state: &SessionState, duration: Duration, close_reason: &'static str) -> SessionSummary {
+        SessionSummary {
+            id: state.id.clone(),
+            peer: None,
+            domain: state.domain.clone(),
+            duration: duration,
+            commands: state.commands,
+            messages_accepted: state.accepted,
+            messages_rejected: state.rejected,
+            bytes_received: 0,
+            bytes_sent: 0,
+            tls: state.tls.clone(),
+            auth: state.auth.clone(),
+            reverse_dns: state.reverse_dns.clone(),
+            close_reason: close_reason
+        }
+    }
+}
+
+/// Returns a short name for why a session ended, meant for grouping in queries, ie
+/// `client_closed` or `timeout`.
+pub fn close_reason(err: &SessionError) -> &'static str {
+    match *err {
+        SessionError::Accept(_) => "accept_failed",
+        SessionError::Setup(_) => "setup_failed",
+        SessionError::Read(ref err) if err.kind() == ErrorKind::UnexpectedEof => "client_closed",
+        SessionError::Read(ref err) if err.kind() == ErrorKind::InvalidInput => "line_too_long",
+        SessionError::Read(_) => "read_failed",
+        SessionError::Write(_) => "write_failed",
+        SessionError::InvalidCommand(_) => "invalid_command",
+        SessionError::Timeout => "timeout",
+        SessionError::TooManyErrors => "too_many_errors",
+        SessionError::TooBusy => "too_busy",
+        SessionError::TooManyConnections => "too_many_connections",
+        SessionError::Rejected => "closed_by_server",
+        SessionError::Proxy(_) => "invalid_proxy_header"
+    }
+}
+
+#[test]
+fn test_session_summary() {
+    let mut state = SessionState::new();
+    state.set_id("01ARZ3NDEKTSV4RRFFQ69G5FAV");
+    state.record_command();
+    state.greet("rustastic.org");
+    state.record_command();
+    state.record_message(true);
+    state.record_message(false);
+    state.set_auth(AuthMechanism::Plain, "rust");
+    state.reset();
+    state.record_command();
+
+    let summary = SessionSummary::new(&state, Duration::from_secs(2), close_reason(&SessionError::Timeout));
+    assert_eq!(Some("01ARZ3NDEKTSV4RRFFQ69G5FAV".to_owned()), summary.id);
+    assert_eq!(None, summary.domain);
+    assert_eq!(3, summary.commands);
+    assert_eq!((1, 1), (summary.messages_accepted, summary.messages_rejected));
+    assert_eq!(Some((AuthMechanism::Plain, "rust".to_owned())), summary.auth);
+    assert_eq!("timeout", summary.close_reason);
+}
+
+/// Replies `503` unless the client sent HELO or EHLO.
+pub fn check_greeted<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    match state.is_greeted() {
+        false => {
+            output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, HELO/EHLO first")).unwrap();
+        },
+        true => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
+
+/// Replies `503` if the client already sent HELO or EHLO.
+pub fn check_not_greeted<CT, ST: SessionStream>(config: &ServerConfig<CT, ST>, container: &mut CT, state: &mut SessionState, input: &mut Input<ST>, output: &mut Output<ST>, line: &str, next: Next<CT, ST>) {
+    match state.is_greeted() {
+        true => {
+            output.write_reply(&Reply::enhanced(503, EnhancedStatusCode::new(5, 5, 1), "Bad sequence of commands, HELO/EHLO already seen")).unwrap();
+        },
+        false => {
+            next.unwrap().call(config, container, state, input, output, line);
+        }
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A server that accepts all mail and discards it, like `smtp-sink`.
+//!
+//! This is meant for load tests, and for integration environments that need an MTA
+//! that never bounces anything. Only the envelopes are kept.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let server = Server::sink();
+//! let log = server.sink_log();
+//! thread::spawn(move || server.listen(ip, 2525));
+//! // ... send mail ...
+//! println!("{} messages received", log.stats().messages);
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+use super::Server;
+use super::commands::{self, HeloHandler, TransactionState, MailHandler, RcptHandler, DataHandler};
+use super::commands::mail::MailParameters;
+use super::commands::rcpt::RcptParameters;
+use super::transaction::{Transaction, TagValue};
+use super::super::common::mailbox::Mailbox;
+#[cfg(test)]
+use super::testing::TestSession;
+
+/// How many envelopes a `SinkLog` keeps, the oldest ones are dropped first.
+pub static MAX_ENVELOPES: usize = 1000;
+
+/// The envelope of a discarded message.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Envelope {
+    /// The sender, `None` for the null sender `<>`.
+    pub sender: Option<Mailbox>,
+    /// The recipients.
+    pub recipients: Vec<Mailbox>,
+    /// The size of the message, in octets.
+    pub size: usize,
+    /// The tags of the transaction, see `Transaction::set_tag`.
+    pub tags: BTreeMap<String, TagValue>
+}
+
+/// Counters of what a sink received.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub struct SinkStats {
+    /// The number of messages.
+    pub messages: u64,
+    /// The number of recipients, over all messages.
+    pub recipients: u64,
+    /// The number of octets, over all messages.
+    pub bytes: u64
+}
+
+/// What a sink received, shared by all its connections.
+pub struct SinkLog {
+    envelopes: Mutex<VecDeque<Envelope>>,
+    stats: Mutex<SinkStats>
+}
+
+impl SinkLog {
+    /// Creates an empty log.
+    pub fn new() -> SinkLog {
+        SinkLog {
+            envelopes: Mutex::new(VecDeque::new()),
+            stats: Mutex::new(SinkStats {
+                messages: 0,
+                recipients: 0,
+                bytes: 0
+            })
+        }
+    }
+
+    fn record(&self, envelope: Envelope) {
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.messages += 1;
+            stats.recipients += envelope.recipients.len() as u64;
+            stats.bytes += envelope.size as u64;
+        }
+        let mut envelopes = self.envelopes.lock().unwrap();
+        if envelopes.len() == MAX_ENVELOPES {
+            envelopes.pop_front();
+        }
+        envelopes.push_back(envelope);
+    }
+
+    /// Returns the envelopes of the last messages, the oldest first.
+    ///
+    /// At most `MAX_ENVELOPES` are kept.
+    pub fn envelopes(&self) -> Vec<Envelope> {
+        self.envelopes.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Returns the counters of everything received so far.
+    pub fn stats(&self) -> SinkStats {
+        *self.stats.lock().unwrap()
+    }
+}
+
+/// A container accepting every domain, sender, recipient and message.
+#[derive(Clone)]
+pub struct SinkContainer {
+    transaction: Transaction,
+    log: Arc<SinkLog>
+}
+
+impl SinkContainer {
+    /// Creates a container recording envelopes to the log.
+    pub fn new(log: Arc<SinkLog>) -> SinkContainer {
+        SinkContainer {
+            transaction: Transaction::new(),
+            log: log
+        }
+    }
+}
+
+impl HeloHandler for SinkContainer {
+    fn handle_domain(&mut self, _: &str) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl TransactionState for SinkContainer {
+    fn transaction(&mut self) -> &mut Transaction {
+        &mut self.transaction
+    }
+}
+
+impl MailHandler for SinkContainer {
+    fn handle_sender_address(&mut self, _: Option<Mailbox>, _: &MailParameters) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl RcptHandler for SinkContainer {
+    fn handle_receiver_address(&mut self, _: Mailbox, _: &RcptParameters) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl DataHandler for SinkContainer {
+    fn handle_data(&mut self, data: &[u8]) -> Result<(), ()> {
+        self.log.record(Envelope {
+            sender: self.transaction.sender().cloned(),
+            recipients: self.transaction.recipients().to_vec(),
+            size: data.len(),
+            tags: self.transaction.tags().clone()
+        });
+        Ok(())
+    }
+}
+
+impl Server<SinkContainer> {
+    /// Creates a server that accepts all mail and discards it, see `server::sink`.
+    ///
+    /// It has the HELO, EHLO, HELP, MAIL, RCPT, DATA and BDAT commands, and advertises
+    /// `SIZE`. The rest of the configuration can be changed as usual.
+    pub fn sink() -> Server<SinkContainer> {
+        let mut server = Server::new(SinkContainer::new(Arc::new(SinkLog::new())));
+        server.add_command(commands::helo::get());
+        server.add_command(commands::ehlo::get());
+        server.add_command(commands::help::get());
+        server.add_command(commands::mail::get());
+        server.add_command(commands::rcpt::get());
+        server.add_command(commands::data::get());
+        server.add_command(commands::bdat::get());
+        server.add_extension("SIZE");
+        server
+    }
+
+    /// Returns the log of the sink, shared by all its connections.
+    pub fn sink_log(&self) -> Arc<SinkLog> {
+        self.container.log.clone()
+    }
+}
+
+#[test]
+fn test_sink() {
+    let server = Server::sink();
+    let log = server.sink_log();
+    let mut container = server.container.clone();
+    let mut session = TestSession::new();
+
+    for _ in 0 .. 2 {
+        session.state.reset();
+        for line in ["HELO rustastic.org", "MAIL FROM:<a@rustastic.org>", "RCPT TO:<b@rustastic.org>", "RCPT TO:<c@rustastic.org>"].iter() {
+            Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, line);
+            assert!(session.reply().starts_with("250"));
+        }
+        session.send("Subject: hi");
+        session.send(".");
+        Server::handle_command(&server.config, &mut session.input, &mut session.output, &mut container, &mut session.state, "DATA");
+        assert!(session.reply().starts_with("354"));
+        assert_eq!("250 2.0.0 OK", session.reply());
+    }
+
+    assert_eq!(SinkStats { messages: 2, recipients: 4, bytes: 26 }, log.stats());
+    let envelopes = log.envelopes();
+    assert_eq!(2, envelopes.len());
+    assert_eq!(Some(&Mailbox::parse("a@rustastic.org").unwrap()), envelopes[0].sender.as_ref());
+    assert_eq!(vec![Mailbox::parse("b@rustastic.org").unwrap(), Mailbox::parse("c@rustastic.org").unwrap()], envelopes[1].recipients);
+}
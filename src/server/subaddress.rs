@@ -0,0 +1,107 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Subaddresses, as described [in RFC 5233](http://tools.ietf.org/html/rfc5233), ie
+//! `rust+smtp@rustastic.org` delivered to the mailbox of `rust@rustastic.org`.
+
+use std::ascii::AsciiExt;
+use std::borrow::ToOwned;
+use std::vec::Vec;
+use super::super::common::mailbox::{Mailbox, MailboxForeignPart};
+
+/// How the server splits recipients into a base address and a detail.
+///
+/// See `Server::set_subaddress_policy`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct SubaddressPolicy {
+    separator: Option<char>,
+    domains: Vec<(String, Option<char>)>,
+    route_on_base: bool
+}
+
+impl SubaddressPolicy {
+    /// Creates a policy using the separator for every domain.
+    pub fn new(separator: char) -> SubaddressPolicy {
+        SubaddressPolicy {
+            separator: Some(separator),
+            domains: Vec::new(),
+            route_on_base: false
+        }
+    }
+
+    /// Sets the separator for a domain, or disables subaddresses for it with `None`.
+    pub fn set_domain_separator(&mut self, domain: &str, separator: Option<char>) {
+        self.domains.retain(|&(ref existing, _)| !existing.eq_ignore_ascii_case(domain));
+        self.domains.push((domain.to_owned(), separator));
+    }
+
+    /// Makes the `RcptHandler` see the base address instead of the full address.
+    ///
+    /// The transaction still holds the full address, so the detail is kept for delivery.
+    pub fn route_on_base(&mut self) {
+        self.route_on_base = true;
+    }
+
+    /// Tells whether the `RcptHandler` sees the base address.
+    pub fn routes_on_base(&self) -> bool {
+        self.route_on_base
+    }
+
+    /// Returns the separator used for the domain of the mailbox.
+    pub fn separator(&self, mailbox: &Mailbox) -> Option<char> {
+        if let MailboxForeignPart::Domain(ref domain) = *mailbox.foreign_part() {
+            for &(ref existing, separator) in self.domains.iter() {
+                if existing.eq_ignore_ascii_case(domain) {
+                    return separator;
+                }
+            }
+        }
+        self.separator
+    }
+
+    /// Returns the base address and the detail of the mailbox, if it has a detail.
+    pub fn split(&self, mailbox: &Mailbox) -> Option<(Mailbox, String)> {
+        self.separator(mailbox).and_then(|separator| {
+            match mailbox.subaddress(separator) {
+                (_, Some(detail)) => Some((mailbox.without_detail(separator), detail.to_owned())),
+                (_, None) => None
+            }
+        })
+    }
+}
+
+#[test]
+fn test_subaddress_policy() {
+    let mut policy = SubaddressPolicy::new('+');
+    policy.set_domain_separator("Example.com", Some('-'));
+    policy.set_domain_separator("rustastic.net", None);
+
+    let mailbox = Mailbox::parse("rust+smtp@rustastic.org").unwrap();
+    assert_eq!(Some('+'), policy.separator(&mailbox));
+    assert_eq!(Some((Mailbox::parse("rust@rustastic.org").unwrap(), "smtp".to_owned())), policy.split(&mailbox));
+    assert_eq!(None, policy.split(&Mailbox::parse("rust@rustastic.org").unwrap()));
+
+    let mailbox = Mailbox::parse("rust-smtp+server@example.COM").unwrap();
+    assert_eq!(Some('-'), policy.separator(&mailbox));
+    assert_eq!(Some((Mailbox::parse("rust@example.COM").unwrap(), "smtp+server".to_owned())), policy.split(&mailbox));
+
+    assert_eq!(None, policy.split(&Mailbox::parse("rust+smtp@rustastic.net").unwrap()));
+    assert_eq!(Some('+'), policy.separator(&Mailbox::parse("rust@[127.0.0.1]").unwrap()));
+
+    // The last separator given for a domain wins.
+    policy.set_domain_separator("rustastic.net", Some('+'));
+    assert!(policy.split(&Mailbox::parse("rust+smtp@rustastic.net").unwrap()).is_some());
+    assert!(!policy.routes_on_base());
+}
@@ -0,0 +1,317 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Temporary files under the spool directory, removed as soon as they are dropped.
+//!
+//! A transaction owns the temporary files holding its message, so they go away when the
+//! transaction is reset or aborted, including when a handler panics, see
+//! `TransactionGuard`.
+
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Write, Seek, SeekFrom};
+use std::io::Result as IoResult;
+use std::io::Error as IoError;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(test)]
+use std::env;
+#[cfg(test)]
+use std::io::Read;
+use super::libc;
+
+/// Allocates temporary files in a directory.
+pub struct TempStore {
+    dir: PathBuf,
+    counter: AtomicUsize
+}
+
+impl TempStore {
+    /// Creates a store putting its files in the directory, which must exist.
+    pub fn new<P: AsRef<Path>>(dir: P) -> TempStore {
+        TempStore {
+            dir: dir.as_ref().to_path_buf(),
+            counter: AtomicUsize::new(0)
+        }
+    }
+
+    /// Returns the directory of the store.
+    pub fn dir(&self) -> &Path {
+        self.dir.as_ref()
+    }
+
+    /// Creates an empty temporary file.
+    ///
+    /// Where the system supports it, the file has no name, so it can't be left behind even
+    /// if the process is killed. Otherwise it gets a unique name, removed on drop.
+    pub fn create(&self) -> IoResult<TempFile> {
+        match self.create_anonymous() {
+            Ok(file) => Ok(file),
+            Err(_) => self.create_named()
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create_anonymous(&self) -> IoResult<TempFile> {
+        let dir = match CString::new(self.dir.as_os_str().as_bytes()) {
+            Ok(dir) => dir,
+            Err(err) => return Err(IoError::from(err))
+        };
+        let fd = unsafe { libc::open(dir.as_ptr(), libc::O_TMPFILE | libc::O_RDWR | libc::O_CLOEXEC, 0o600) };
+        match fd < 0 {
+            true => Err(IoError::last_os_error()),
+            false => Ok(TempFile {
+                file: unsafe { File::from_raw_fd(fd) },
+                path: None
+            })
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn create_anonymous(&self) -> IoResult<TempFile> {
+        Err(IoError::from_raw_os_error(libc::ENOTSUP))
+    }
+
+    fn create_named(&self) -> IoResult<TempFile> {
+        let pid = unsafe { libc::getpid() };
+        let n = self.counter.fetch_add(1, Ordering::SeqCst);
+        let path = self.dir.join(format!("rsmtp-{}-{}.tmp", pid, n));
+        let file = try!(OpenOptions::new().read(true).write(true).create_new(true).mode(0o600).open(&path));
+        Ok(TempFile {
+            file: file,
+            path: Some(path)
+        })
+    }
+}
+
+/// A temporary file, removed when dropped.
+pub struct TempFile {
+    file: File,
+    path: Option<PathBuf>
+}
+
+impl TempFile {
+    /// Returns the path of the file, or `None` if it has no name.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_ref().map(|path| path.as_ref())
+    }
+
+    /// Gives the file a name, so it is kept once dropped, ie when its content is complete.
+    ///
+    /// The path must be on the file system of the store. A file that has no name yet
+    /// fails if the path already exists.
+    pub fn persist<P: AsRef<Path>>(mut self, path: P) -> IoResult<()> {
+        match self.path.take() {
+            Some(tmp) => match fs::rename(&tmp, path.as_ref()) {
+                Ok(()) => Ok(()),
+                Err(err) => {
+                    self.path = Some(tmp);
+                    Err(err)
+                }
+            },
+            None => self.link(path.as_ref())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn link(&self, path: &Path) -> IoResult<()> {
+        let (from, to) = match (CString::new(format!("/proc/self/fd/{}", self.file.as_raw_fd())), CString::new(path.as_os_str().as_bytes())) {
+            (Ok(from), Ok(to)) => (from, to),
+            (Err(err), _) | (_, Err(err)) => return Err(IoError::from(err))
+        };
+        let res = unsafe { libc::linkat(libc::AT_FDCWD, from.as_ptr(), libc::AT_FDCWD, to.as_ptr(), libc::AT_SYMLINK_FOLLOW) };
+        match res < 0 {
+            true => Err(IoError::last_os_error()),
+            false => Ok(())
+        }
+    }
+
+    // Files without a name are only created on Linux.
+    #[cfg(not(target_os = "linux"))]
+    fn link(&self, _: &Path) -> IoResult<()> {
+        Err(IoError::from_raw_os_error(libc::ENOTSUP))
+    }
+}
+
+impl Deref for TempFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl DerefMut for TempFile {
+    fn deref_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+}
+
+impl fmt::Debug for TempFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.path {
+            Some(ref path) => write!(f, "TempFile({})", path.display()),
+            None => write!(f, "TempFile(anonymous)")
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[test]
+fn test_temp_file() {
+    let store = TempStore::new(env::temp_dir());
+
+    let mut file = store.create().unwrap();
+    file.write_all(b"hello").unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut content = String::new();
+    file.read_to_string(&mut content).unwrap();
+    assert_eq!("hello", content);
+
+    // Named files are removed on drop.
+    let file = store.create_named().unwrap();
+    let path = file.path().unwrap().to_path_buf();
+    assert!(path.exists());
+    assert!(store.create_named().unwrap().path() != Some(path.as_ref()));
+    drop(file);
+    assert!(!path.exists());
+
+    // Persisted files are kept, whether they had a name or not.
+    for file in vec![store.create().unwrap(), store.create_named().unwrap()] {
+        let mut file = file;
+        file.write_all(b"kept").unwrap();
+        let path = env::temp_dir().join(format!("rsmtp-test-persist-{}-{}", unsafe { libc::getpid() }, store.counter.fetch_add(1, Ordering::SeqCst)));
+        file.persist(&path).unwrap();
+        let mut content = String::new();
+        File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!("kept", content);
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+/// Message content kept in a temporary file and mapped in memory, so it can be read as a
+/// slice without using the heap.
+pub struct SpillFile {
+    file: TempFile,
+    map: *mut u8,
+    len: usize
+}
+
+// The mapping is only written through `&mut self`.
+unsafe impl Send for SpillFile {}
+unsafe impl Sync for SpillFile {}
+
+impl SpillFile {
+    /// Creates a spill file with the given content.
+    pub fn new(store: &TempStore, data: &[u8]) -> IoResult<SpillFile> {
+        let mut spill = SpillFile {
+            file: try!(store.create()),
+            map: ptr::null_mut(),
+            len: 0
+        };
+        try!(spill.append(data));
+        Ok(spill)
+    }
+
+    /// Appends data at the end of the file.
+    ///
+    /// If this fails, the file is cut back and the content stays as it was, so later
+    /// appends still follow it.
+    pub fn append(&mut self, data: &[u8]) -> IoResult<()> {
+        if data.len() == 0 {
+            return Ok(());
+        }
+        try!(self.file.seek(SeekFrom::Start(self.len as u64)));
+        if let Err(err) = self.file.write_all(data) {
+            let _ = self.file.set_len(self.len as u64);
+            return Err(err);
+        }
+        // The old mapping is only replaced once the new one exists.
+        let len = self.len + data.len();
+        let map = unsafe {
+            libc::mmap(ptr::null_mut(), len as libc::size_t, libc::PROT_READ, libc::MAP_SHARED, self.file.as_raw_fd(), 0)
+        };
+        if map == libc::MAP_FAILED {
+            let err = IoError::last_os_error();
+            let _ = self.file.set_len(self.len as u64);
+            return Err(err);
+        }
+        self.unmap();
+        self.map = map as *mut u8;
+        self.len = len;
+        Ok(())
+    }
+
+    /// Returns the temporary file.
+    pub fn file(&self) -> &TempFile {
+        &self.file
+    }
+
+    fn unmap(&mut self) {
+        if !self.map.is_null() {
+            unsafe { libc::munmap(self.map as *mut libc::c_void, self.len as libc::size_t) };
+            self.map = ptr::null_mut();
+            self.len = 0;
+        }
+    }
+}
+
+impl Deref for SpillFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.map.is_null() {
+            true => &[],
+            false => unsafe { slice::from_raw_parts(self.map, self.len) }
+        }
+    }
+}
+
+impl fmt::Debug for SpillFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SpillFile({:?}, {} octets)", self.file, self.len)
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        self.unmap();
+    }
+}
+
+#[test]
+fn test_spill_file() {
+    let store = TempStore::new(env::temp_dir());
+    let mut spill = SpillFile::new(&store, b"").unwrap();
+    assert_eq!(b"", &spill[..]);
+    spill.append(b"hello").unwrap();
+    spill.append(b" world").unwrap();
+    assert_eq!(b"hello world", &spill[..]);
+    assert_eq!(11, spill.file().metadata().unwrap().len());
+}
@@ -0,0 +1,207 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tools to test commands over a real connection on the loopback interface, or over
+//! memory.
+
+use std::io::{BufRead, BufReader, Read, Write, Cursor};
+use std::io::Result as IoResult;
+use std::borrow::ToOwned;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use super::super::common::mailbox::Mailbox;
+use super::super::common::stream::{InputStream, OutputStream, Transport, SessionStream};
+use super::super::common::instrument::{InstrumentedStream, StreamStats};
+use super::commands::{TransactionState, HeloHandler, MailHandler, RcptHandler, DataHandler, EtrnHandler};
+use super::commands::etrn::{EtrnNode, EtrnOutcome};
+use super::commands::mail::MailParameters;
+use super::commands::rcpt::RcptParameters;
+use super::transaction::Transaction;
+use super::session::SessionState;
+
+/// The server streams of a session, and the client end of the connection.
+pub struct TestSession {
+    /// What the server reads from the client.
+    pub input: InputStream<Transport>,
+    /// What the server writes to the client.
+    pub output: OutputStream<Transport>,
+    /// The state of the session, which already saw HELO.
+    pub state: SessionState,
+    client: BufReader<TcpStream>
+}
+
+impl TestSession {
+    /// Connects a client to a new session.
+    pub fn new() -> TestSession {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut state = SessionState::new();
+        state.greet("rustastic.org");
+        let stats = Arc::new(StreamStats::new());
+        TestSession {
+            input: InputStream::new(Transport::Tcp(InstrumentedStream::with_stats(stream.try_clone().unwrap(), stats.clone())), 1000, false),
+            output: OutputStream::new(Transport::Tcp(InstrumentedStream::with_stats(stream, stats)), false),
+            state: state,
+            client: BufReader::new(client)
+        }
+    }
+
+    /// Sends a line from the client, adding `<CRLF>`.
+    ///
+    /// The line is written at once, since the server reads a line with a single read.
+    pub fn send(&mut self, line: &str) {
+        self.client.get_mut().write_all(format!("{}\r\n", line).as_bytes()).unwrap();
+    }
+
+    /// Sends raw bytes from the client.
+    pub fn send_bytes(&mut self, bytes: &[u8]) {
+        self.client.get_mut().write_all(bytes).unwrap();
+    }
+
+    /// Reads a line of reply from the server, without `<CRLF>`.
+    ///
+    /// Replies buffered by the server are flushed first.
+    pub fn reply(&mut self) -> String {
+        self.output.flush().unwrap();
+        let mut line = String::new();
+        self.client.read_line(&mut line).unwrap();
+        line.trim_right_matches("\r\n").to_owned()
+    }
+}
+
+/// A stream in memory, which reads what the client sent and keeps what the server wrote.
+///
+/// Clones share the same buffers, so one can be read from while the other is written to.
+#[derive(Clone)]
+pub struct MemoryStream {
+    input: Arc<Mutex<Cursor<Vec<u8>>>>,
+    output: Arc<Mutex<Vec<u8>>>
+}
+
+impl MemoryStream {
+    /// Creates a stream reading the given lines, each followed by `<CRLF>`.
+    pub fn new(lines: &[&str]) -> MemoryStream {
+        let mut input = Vec::new();
+        for line in lines.iter() {
+            input.extend(line.as_bytes().iter().cloned());
+            input.extend(b"\r\n".iter().cloned());
+        }
+        MemoryStream {
+            input: Arc::new(Mutex::new(Cursor::new(input))),
+            output: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+
+    /// Returns the lines written so far, without `<CRLF>`.
+    pub fn written(&self) -> Vec<String> {
+        let output = self.output.lock().unwrap();
+        String::from_utf8_lossy(&output[..]).split("\r\n").filter(|line| line.len() > 0).map(|line| line.to_owned()).collect()
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.input.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.output.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl SessionStream for MemoryStream {}
+
+/// A container accepting everything, which remembers the last message it got.
+#[derive(Clone)]
+pub struct TestContainer {
+    /// The transaction of the session.
+    pub transaction: Transaction,
+    /// The content of the last message.
+    pub data: Option<Vec<u8>>,
+    /// The number of recipients of the last message.
+    pub delivered_to: Option<usize>,
+    /// The node of the last ETRN command.
+    pub etrn: Option<EtrnNode>,
+    /// The last recipient given to the `RcptHandler`, with its detail.
+    pub receiver: Option<(Mailbox, Option<String>)>,
+    /// Whether the `RcptHandler` refuses every recipient.
+    pub refuse_receivers: bool,
+    /// The transcripts of the sessions, as text, shared by clones.
+    pub transcripts: Arc<Mutex<Vec<String>>>
+}
+
+impl TestContainer {
+    /// Creates a container with no transaction in progress.
+    pub fn new() -> TestContainer {
+        TestContainer {
+            transaction: Transaction::new(),
+            data: None,
+            delivered_to: None,
+            etrn: None,
+            receiver: None,
+            refuse_receivers: false,
+            transcripts: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+}
+
+impl TransactionState for TestContainer {
+    fn transaction(&mut self) -> &mut Transaction {
+        &mut self.transaction
+    }
+}
+
+impl HeloHandler for TestContainer {
+    fn handle_domain(&mut self, _: &str) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl MailHandler for TestContainer {
+    fn handle_sender_address(&mut self, _: Option<Mailbox>, _: &MailParameters) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl RcptHandler for TestContainer {
+    fn handle_receiver_address(&mut self, mailbox: Mailbox, parameters: &RcptParameters) -> Result<(), ()> {
+        self.receiver = Some((mailbox, parameters.detail.clone()));
+        match self.refuse_receivers {
+            true => Err(()),
+            false => Ok(())
+        }
+    }
+}
+
+impl DataHandler for TestContainer {
+    fn handle_data(&mut self, data: &[u8]) -> Result<(), ()> {
+        self.data = Some(data.to_vec());
+        self.delivered_to = Some(self.transaction.recipients().len());
+        Ok(())
+    }
+}
+
+impl EtrnHandler for TestContainer {
+    fn handle_etrn(&mut self, node: &EtrnNode) -> EtrnOutcome {
+        self.etrn = Some(node.clone());
+        EtrnOutcome::Started
+    }
+}
@@ -0,0 +1,557 @@
+// Copyright 2014 The Rustastic SMTP Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The state of a mail transaction, from the MAIL command to the end of DATA.
+
+use std::mem;
+use std::borrow::ToOwned;
+use std::collections::BTreeMap;
+use std::vec::Vec;
+use std::ops::{Deref, DerefMut};
+use std::io::Result as IoResult;
+#[cfg(test)]
+use std::env;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use std::thread;
+use super::commands::TransactionState;
+use super::tempstore::{TempStore, SpillFile};
+use super::super::common::mailbox::Mailbox;
+use super::dkim::DkimOutcome;
+
+/// The body type declared with the `BODY` parameter of MAIL, as described
+/// [in RFC 6152](http://tools.ietf.org/html/rfc6152).
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+pub enum BodyType {
+    /// `BODY=7BIT`, the content is US-ASCII.
+    SevenBit,
+    /// `BODY=8BITMIME`, the content may contain octets above 127.
+    EightBitMime
+}
+
+/// The content of a message, in memory or spilled to a temporary file.
+#[derive(Debug)]
+pub enum MessageData {
+    /// The content is on the heap.
+    Memory(Vec<u8>),
+    /// The content is in a temporary file, removed when this is dropped.
+    Spilled(SpillFile)
+}
+
+impl Deref for MessageData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            MessageData::Memory(ref data) => data.as_ref(),
+            MessageData::Spilled(ref spill) => spill
+        }
+    }
+}
+
+impl Clone for MessageData {
+    /// Clones the content, copying spilled content to memory.
+    fn clone(&self) -> MessageData {
+        MessageData::Memory(self.to_vec())
+    }
+}
+
+/// The value of a tag of a transaction, see `Transaction::set_tag`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum TagValue {
+    /// A flag, ie whether the message is bulk mail.
+    Bool(bool),
+    /// A number, ie a spam score in hundredths.
+    Int(i64),
+    /// A text, ie the name of a routing class.
+    Text(String)
+}
+
+impl TagValue {
+    /// Returns the flag, `None` if the value isn't one.
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            TagValue::Bool(value) => Some(value),
+            _ => None
+        }
+    }
+
+    /// Returns the number, `None` if the value isn't one.
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            TagValue::Int(value) => Some(value),
+            _ => None
+        }
+    }
+
+    /// Returns the text, `None` if the value isn't one.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            TagValue::Text(ref value) => Some(value.as_ref()),
+            _ => None
+        }
+    }
+}
+
+impl From<bool> for TagValue {
+    fn from(value: bool) -> TagValue {
+        TagValue::Bool(value)
+    }
+}
+
+impl From<i64> for TagValue {
+    fn from(value: i64) -> TagValue {
+        TagValue::Int(value)
+    }
+}
+
+impl<'a> From<&'a str> for TagValue {
+    fn from(value: &'a str) -> TagValue {
+        TagValue::Text(value.to_owned())
+    }
+}
+
+impl From<String> for TagValue {
+    fn from(value: String) -> TagValue {
+        TagValue::Text(value)
+    }
+}
+
+/// A mail transaction as described
+/// [in RFC 5321](http://tools.ietf.org/html/rfc5321#section-3.3).
+#[derive(Clone, Debug)]
+pub struct Transaction {
+    /// Whether a MAIL command has been accepted.
+    started: bool,
+    /// The reverse path, `None` for the null sender `<>`.
+    sender: Option<Mailbox>,
+    /// The body type declared with MAIL, if any.
+    body: Option<BodyType>,
+    /// Whether MAIL had the `SMTPUTF8` parameter.
+    smtputf8: bool,
+    /// The forward paths accepted so far.
+    recipients: Vec<Mailbox>,
+    /// The message content received via DATA.
+    data: MessageData,
+    /// What policies found out about the message, for later routing.
+    tags: BTreeMap<String, TagValue>,
+    /// The outcomes of the DKIM signatures of the message.
+    dkim: Vec<DkimOutcome>
+}
+
+impl Transaction {
+    /// Creates an empty transaction.
+    pub fn new() -> Transaction {
+        Transaction {
+            started: false,
+            sender: None,
+            body: None,
+            smtputf8: false,
+            recipients: Vec::new(),
+            data: MessageData::Memory(Vec::new()),
+            tags: BTreeMap::new(),
+            dkim: Vec::new()
+        }
+    }
+
+    /// Starts the transaction with the sender given to the MAIL command.
+    ///
+    /// The tags set since the last reset are kept, so policies of MAIL can tag the
+    /// transaction before it starts.
+    pub fn start(&mut self, sender: Option<Mailbox>) {
+        let tags = mem::replace(&mut self.tags, BTreeMap::new());
+        self.reset();
+        self.tags = tags;
+        self.started = true;
+        self.sender = sender;
+    }
+
+    /// Tells whether a MAIL command has been accepted for this transaction.
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    /// Returns the sender, or `None` for the null sender `<>`.
+    pub fn sender(&self) -> Option<&Mailbox> {
+        self.sender.as_ref()
+    }
+
+    /// Sets the body type declared with MAIL.
+    pub fn set_body(&mut self, body: Option<BodyType>) {
+        self.body = body;
+    }
+
+    /// Returns the body type declared with MAIL, if any.
+    pub fn body(&self) -> Option<BodyType> {
+        self.body
+    }
+
+    /// Marks the transaction as using UTF-8 addresses and headers, as described
+    /// [in RFC 6531](http://tools.ietf.org/html/rfc6531).
+    pub fn set_smtputf8(&mut self, smtputf8: bool) {
+        self.smtputf8 = smtputf8;
+    }
+
+    /// Tells whether MAIL had the `SMTPUTF8` parameter.
+    pub fn is_smtputf8(&self) -> bool {
+        self.smtputf8
+    }
+
+    /// Tags the transaction, replacing the previous value of the tag.
+    ///
+    /// Tags carry what policies found out about the message, ie that it is bulk mail or
+    /// comes from an internal host, to the consumers of the message, so they can route it
+    /// without looking at it again. They are cleared with the transaction.
+    pub fn set_tag<V: Into<TagValue>>(&mut self, key: &str, value: V) {
+        self.tags.insert(key.to_owned(), value.into());
+    }
+
+    /// Returns the value of a tag, `None` if the transaction doesn't have it.
+    pub fn tag(&self, key: &str) -> Option<&TagValue> {
+        self.tags.get(key)
+    }
+
+    /// Removes a tag, returning its value.
+    pub fn remove_tag(&mut self, key: &str) -> Option<TagValue> {
+        self.tags.remove(key)
+    }
+
+    /// Returns all the tags, by key.
+    pub fn tags(&self) -> &BTreeMap<String, TagValue> {
+        &self.tags
+    }
+
+    /// Sets the outcomes of the DKIM signatures of the message.
+    pub fn set_dkim_results(&mut self, outcomes: Vec<DkimOutcome>) {
+        self.dkim = outcomes;
+    }
+
+    /// Returns the outcomes of the DKIM signatures of the message, from the top, once it
+    /// is received. This is empty if the server has no `DkimValidator`, see
+    /// `server::dkim`.
+    pub fn dkim_results(&self) -> &[DkimOutcome] {
+        self.dkim.as_ref()
+    }
+
+    /// Adds a recipient accepted by the RCPT command.
+    pub fn add_recipient(&mut self, recipient: Mailbox) {
+        self.recipients.push(recipient);
+    }
+
+    /// Returns the recipients accepted so far.
+    pub fn recipients(&self) -> &[Mailbox] {
+        self.recipients.as_ref()
+    }
+
+    /// Sets the message content received via DATA.
+    pub fn set_data(&mut self, data: Vec<u8>) {
+        self.data = MessageData::Memory(data);
+    }
+
+    /// Appends a chunk of message content, as received via BDAT.
+    ///
+    /// This fails only when the content is spilled and the file can't be written.
+    pub fn append_data(&mut self, chunk: &[u8]) -> IoResult<()> {
+        match self.data {
+            MessageData::Memory(ref mut data) => {
+                data.extend(chunk.iter().cloned());
+                Ok(())
+            },
+            MessageData::Spilled(ref mut spill) => spill.append(chunk)
+        }
+    }
+
+    /// Returns the message content received via DATA.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Moves the message content out of the transaction, in memory.
+    pub fn take_data(&mut self) -> Vec<u8> {
+        match mem::replace(&mut self.data, MessageData::Memory(Vec::new())) {
+            MessageData::Memory(data) => data,
+            MessageData::Spilled(spill) => spill.to_vec()
+        }
+    }
+
+    /// Moves the message content out of the transaction, wherever it is.
+    pub fn take_content(&mut self) -> MessageData {
+        mem::replace(&mut self.data, MessageData::Memory(Vec::new()))
+    }
+
+    /// Moves the message content to a temporary file of the store, freeing its memory.
+    ///
+    /// The content received afterwards is appended to the file, which is removed when the
+    /// transaction is reset or aborted.
+    pub fn spill(&mut self, store: &TempStore) -> IoResult<()> {
+        let spill = match self.data {
+            MessageData::Memory(ref data) => try!(SpillFile::new(store, data.as_ref())),
+            MessageData::Spilled(_) => return Ok(())
+        };
+        self.data = MessageData::Spilled(spill);
+        Ok(())
+    }
+
+    /// Tells whether the message content is in a temporary file.
+    pub fn is_spilled(&self) -> bool {
+        match self.data {
+            MessageData::Memory(_) => false,
+            MessageData::Spilled(_) => true
+        }
+    }
+
+    /// Clears the transaction, as required after RSET or at the end of DATA.
+    pub fn reset(&mut self) {
+        self.started = false;
+        self.sender = None;
+        self.body = None;
+        self.smtputf8 = false;
+        self.recipients.clear();
+        self.tags.clear();
+        self.dkim.clear();
+        match self.data {
+            MessageData::Memory(ref mut data) => data.clear(),
+            MessageData::Spilled(_) => self.data = MessageData::Memory(Vec::new())
+        }
+    }
+
+    /// Ends the transaction after a failure, releasing everything it holds.
+    ///
+    /// Unlike `reset`, this also frees the memory used by the message content, which may
+    /// be large. The session loop calls this when the transaction can't go on, see
+    /// `Command::on_failure`.
+    pub fn abort(&mut self) {
+        self.started = false;
+        self.sender = None;
+        self.body = None;
+        self.smtputf8 = false;
+        self.recipients = Vec::new();
+        self.data = MessageData::Memory(Vec::new());
+        self.tags = BTreeMap::new();
+        self.dkim = Vec::new();
+    }
+}
+
+#[test]
+fn test_transaction() {
+    let mut transaction = Transaction::new();
+    assert!(!transaction.is_started());
+
+    transaction.start(Some(Mailbox::parse("rust@rustastic.org").unwrap()));
+    transaction.add_recipient(Mailbox::parse("hello@rustastic.org").unwrap());
+    transaction.set_data(b"hello".to_vec());
+    assert!(transaction.is_started());
+    assert_eq!(Some(&Mailbox::parse("rust@rustastic.org").unwrap()), transaction.sender());
+    assert_eq!(1, transaction.recipients().len());
+    transaction.append_data(b" world").unwrap();
+    assert_eq!(b"hello world".to_vec(), transaction.take_data());
+    assert_eq!(0, transaction.data().len());
+
+    // Starting again forgets about the previous recipients.
+    transaction.set_body(Some(BodyType::EightBitMime));
+    transaction.set_smtputf8(true);
+    assert_eq!(Some(BodyType::EightBitMime), transaction.body());
+    assert!(transaction.is_smtputf8());
+    transaction.start(None);
+    assert_eq!(None, transaction.body());
+    assert!(!transaction.is_smtputf8());
+    assert!(transaction.is_started());
+    assert_eq!(None, transaction.sender());
+    assert_eq!(0, transaction.recipients().len());
+
+    transaction.reset();
+    assert!(!transaction.is_started());
+}
+
+#[test]
+fn test_tags() {
+    let mut transaction = Transaction::new();
+    transaction.set_tag("internal", true);
+    transaction.start(Some(Mailbox::parse("rust@rustastic.org").unwrap()));
+    transaction.set_tag("class", "bulk");
+    transaction.set_tag("score", 250i64);
+    transaction.set_tag("score", 300i64);
+    assert_eq!(Some(true), transaction.tag("internal").and_then(TagValue::as_bool));
+    assert_eq!(Some("bulk"), transaction.tag("class").and_then(TagValue::as_str));
+    assert_eq!(Some(300), transaction.tag("score").and_then(TagValue::as_int));
+    assert_eq!(None, transaction.tag("class").and_then(TagValue::as_int));
+    assert_eq!(vec!["class", "internal", "score"], transaction.tags().keys().map(|key| key.as_ref()).collect::<Vec<&str>>());
+    assert_eq!(Some(TagValue::Int(300)), transaction.remove_tag("score"));
+    assert_eq!(None, transaction.tag("score"));
+
+    transaction.reset();
+    assert!(transaction.tags().is_empty());
+    transaction.set_tag("internal", true);
+    transaction.abort();
+    assert!(transaction.tags().is_empty());
+}
+
+#[test]
+fn test_spill() {
+    let store = TempStore::new(env::temp_dir());
+    let mut transaction = Transaction::new();
+    transaction.start(None);
+    transaction.set_data(b"hello".to_vec());
+    transaction.spill(&store).unwrap();
+    assert!(transaction.is_spilled());
+    transaction.append_data(b" world").unwrap();
+    assert_eq!(b"hello world", transaction.data());
+    assert_eq!(b"hello world".to_vec(), transaction.clone().take_data());
+    assert!(transaction.take_content().to_vec() == b"hello world".to_vec());
+    assert!(!transaction.is_spilled());
+
+    // The file goes away with the transaction.
+    transaction.set_data(b"hello".to_vec());
+    transaction.spill(&store).unwrap();
+    transaction.reset();
+    assert!(!transaction.is_spilled());
+    assert_eq!(0, transaction.data().len());
+}
+
+/// A function that aborts the transaction held by a container.
+pub type AbortFn<CT> = fn(&mut CT);
+
+/// Aborts the transaction of a container, for use with `Command::on_failure`.
+pub fn abort_transaction<CT: TransactionState>(container: &mut CT) {
+    container.transaction().abort();
+}
+
+/// Tells whether a reply code means that the transaction must be aborted.
+///
+/// This is the case for every failure, except the ones saying the command was rejected
+/// before it had any effect, ie syntax errors and bad sequences of commands.
+pub fn is_aborting_reply(code: u16) -> bool {
+    code >= 400 && !(code >= 501 && code <= 504)
+}
+
+#[test]
+fn test_is_aborting_reply() {
+    assert!(!is_aborting_reply(250));
+    assert!(!is_aborting_reply(354));
+    assert!(is_aborting_reply(421));
+    assert!(is_aborting_reply(451));
+    assert!(is_aborting_reply(500));
+    assert!(!is_aborting_reply(501));
+    assert!(!is_aborting_reply(503));
+    assert!(!is_aborting_reply(504));
+    assert!(is_aborting_reply(552));
+    assert!(is_aborting_reply(554));
+}
+
+/// Owns the container of a session and aborts its transaction when dropped.
+///
+/// Since this also happens while unwinding, the transaction is cleaned up when the
+/// connection fails or a handler panics, not only when the session ends normally.
+pub struct TransactionGuard<CT> {
+    container: CT,
+    abort: Option<AbortFn<CT>>
+}
+
+impl<CT> TransactionGuard<CT> {
+    /// Creates a guard calling `abort` on the container when dropped, if any.
+    pub fn new(container: CT, abort: Option<AbortFn<CT>>) -> TransactionGuard<CT> {
+        TransactionGuard {
+            container: container,
+            abort: abort
+        }
+    }
+}
+
+impl<CT> Deref for TransactionGuard<CT> {
+    type Target = CT;
+
+    fn deref(&self) -> &CT {
+        &self.container
+    }
+}
+
+impl<CT> DerefMut for TransactionGuard<CT> {
+    fn deref_mut(&mut self) -> &mut CT {
+        &mut self.container
+    }
+}
+
+impl<CT> Drop for TransactionGuard<CT> {
+    fn drop(&mut self) {
+        if let Some(abort) = self.abort {
+            abort(&mut self.container);
+        }
+    }
+}
+
+#[test]
+fn test_abort() {
+    let mut transaction = Transaction::new();
+    transaction.start(None);
+    transaction.add_recipient(Mailbox::parse("hello@rustastic.org").unwrap());
+    transaction.set_data(b"hello".to_vec());
+    transaction.abort();
+    assert!(!transaction.is_started());
+    assert_eq!(0, transaction.recipients().len());
+    assert_eq!(0, transaction.data().len());
+}
+
+#[cfg(test)]
+struct SharedTransaction {
+    transaction: Arc<Mutex<Transaction>>,
+    local: Transaction
+}
+
+#[cfg(test)]
+impl TransactionState for SharedTransaction {
+    fn transaction(&mut self) -> &mut Transaction {
+        &mut self.local
+    }
+}
+
+#[cfg(test)]
+fn abort_shared(container: &mut SharedTransaction) {
+    abort_transaction(container);
+    *container.transaction.lock().unwrap() = container.local.clone();
+}
+
+#[test]
+fn test_transaction_guard() {
+    let shared = Arc::new(Mutex::new(Transaction::new()));
+
+    // The transaction is aborted when the session ends.
+    {
+        let mut guard = TransactionGuard::new(SharedTransaction {
+            transaction: shared.clone(),
+            local: Transaction::new()
+        }, Some(abort_shared));
+        guard.transaction().start(None);
+        *shared.lock().unwrap() = guard.local.clone();
+        assert!(shared.lock().unwrap().is_started());
+    }
+    assert!(!shared.lock().unwrap().is_started());
+
+    // And when a handler panics.
+    let thread_shared = shared.clone();
+    let res = thread::spawn(move || {
+        let mut guard = TransactionGuard::new(SharedTransaction {
+            transaction: thread_shared.clone(),
+            local: Transaction::new()
+        }, Some(abort_shared));
+        guard.transaction().start(None);
+        guard.transaction().set_data(b"hello".to_vec());
+        *thread_shared.lock().unwrap() = guard.local.clone();
+        panic!("handler failed");
+    }).join();
+    assert!(res.is_err());
+    let transaction = shared.lock().unwrap();
+    assert!(!transaction.is_started());
+    assert_eq!(0, transaction.data().len());
+}